@@ -0,0 +1,129 @@
+use std::fs;
+use std::io;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use flat_bril::fusion::{fuse_instrs, with_instrs};
+use flat_bril::interp::{InterpContext, Limits, interp_program};
+use flat_bril::json_interp;
+use flat_bril::memfile::{self, FlatProgram};
+use flat_bril::types::FlatInstr;
+
+/// Programs in `test/` to benchmark, along with the args passed to `main`
+/// (the args are chosen so that each run takes a few milliseconds)
+const BENCHMARKS: &[(&str, &[&str])] = &[
+    ("ackermann", &["3", "4"]),
+    ("check-primes", &["300"]),
+    ("fib_recursive", &["18"]),
+    ("fizz-buzz", &["2000"]),
+    ("sum-check", &["20000"]),
+];
+
+/// Loop-heavy programs in `test/` (& their args) used to measure the effect
+/// of fusing instructions into superinstructions
+const LOOP_KERNELS: &[(&str, &[&str])] = &[
+    ("check-primes", &["300"]),
+    ("fizz-buzz", &["2000"]),
+    ("pythagorean_triple", &["100"]),
+    ("sum-check", &["20000"]),
+];
+
+/// Loads & interprets the `.fbril` file at `path` (writing its output to `out`)
+fn run_flat<W: io::Write>(path: &str, args: &[&str], out: W) {
+    let mmap = memfile::mmap_existing_file(path).unwrap();
+    let program = memfile::get_flat_program(&mmap).unwrap();
+    let mut ctx = InterpContext::new(out, Limits::default());
+    interp_program(&program, args.to_vec(), &mut ctx).unwrap();
+}
+
+/// Parses & interprets the JSON file at `path` (writing its output to `out`)
+fn run_json<W: io::Write>(path: &str, args: &[&str], mut out: W) {
+    let json_str = fs::read_to_string(path).unwrap();
+    let program: serde_json::Value = serde_json::from_str(&json_str).unwrap();
+    json_interp::interp_program(&program, args, &mut out);
+}
+
+/// Compares the end-to-end time of loading + interpreting a flat Bril file
+/// against parsing + walking the JSON representation of the same program
+fn flat_vs_json(c: &mut Criterion) {
+    for (name, args) in BENCHMARKS {
+        // Flatten the program once up front
+        let json_path = format!("test/{name}.json");
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json_path).unwrap())
+                .unwrap();
+        let fbril_path = std::env::temp_dir()
+            .join(format!("flat-bril-bench-{name}.fbril"))
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(&fbril_path, memfile::json_to_fbril_bytes(&json)).unwrap();
+
+        // Sanity check: both interpreters produce the same output
+        let (mut flat_out, mut json_out) = (vec![], vec![]);
+        run_flat(&fbril_path, args, &mut flat_out);
+        run_json(&json_path, args, &mut json_out);
+        assert_eq!(flat_out, json_out, "outputs of `{name}` differ");
+
+        let mut group = c.benchmark_group(*name);
+        group.bench_function("flat", |b| {
+            b.iter(|| run_flat(&fbril_path, args, io::sink()))
+        });
+        group.bench_function("json", |b| {
+            b.iter(|| run_json(&json_path, args, io::sink()))
+        });
+        group.finish();
+    }
+}
+
+/// Loads & interprets the `.fbril` file at `path` after fusing its
+/// instructions into superinstructions (writing its output to `out`)
+fn run_fused<W: io::Write>(path: &str, args: &[&str], out: W) {
+    let mmap = memfile::mmap_existing_file(path).unwrap();
+    let program = memfile::get_program_views(&mmap).unwrap();
+    let fused: Vec<Vec<FlatInstr>> = program.iter().map(fuse_instrs).collect();
+    let fused_program = FlatProgram::new(
+        program
+            .iter()
+            .zip(&fused)
+            .map(|(view, instrs)| with_instrs(view, instrs))
+            .collect(),
+    );
+    let mut ctx = InterpContext::new(out, Limits::default());
+    interp_program(&fused_program, args.to_vec(), &mut ctx).unwrap();
+}
+
+/// Compares interpreting loop kernels with & without superinstructions
+/// (the time taken by the fusion pass is included, since it has to run
+/// every time a program is loaded)
+fn fused_vs_unfused(c: &mut Criterion) {
+    for (name, args) in LOOP_KERNELS {
+        let json: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(format!("test/{name}.json")).unwrap(),
+        )
+        .unwrap();
+        let fbril_path = std::env::temp_dir()
+            .join(format!("flat-bril-bench-{name}.fbril"))
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(&fbril_path, memfile::json_to_fbril_bytes(&json)).unwrap();
+
+        // Sanity check: fusing doesn't change the program's output
+        let (mut flat_out, mut fused_out) = (vec![], vec![]);
+        run_flat(&fbril_path, args, &mut flat_out);
+        run_fused(&fbril_path, args, &mut fused_out);
+        assert_eq!(flat_out, fused_out, "outputs of `{name}` differ");
+
+        let mut group = c.benchmark_group(format!("{name} (fusion)"));
+        group.bench_function("unfused", |b| {
+            b.iter(|| run_flat(&fbril_path, args, io::sink()))
+        });
+        group.bench_function("fused", |b| {
+            b.iter(|| run_fused(&fbril_path, args, io::sink()))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, flat_vs_json, fused_vs_unfused);
+criterion_main!(benches);
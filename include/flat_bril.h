@@ -0,0 +1,96 @@
+#ifndef FLAT_BRIL_H
+#define FLAT_BRIL_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of a call to the C API
+// (the variants after `FBRIL_STATUS_PANIC` mirror `ErrorCode`)
+typedef enum FbrilStatus {
+  FBRIL_STATUS_OK = 0,
+  // A required pointer argument was `NULL`
+  FBRIL_STATUS_NULL_ARGUMENT,
+  // A path or argument string wasn't valid UTF-8
+  FBRIL_STATUS_INVALID_UTF8,
+  // The interpreter panicked
+  FBRIL_STATUS_PANIC,
+  FBRIL_STATUS_MALFORMED_FILE,
+  FBRIL_STATUS_MALFORMED_INSTR,
+  FBRIL_STATUS_UNDEFINED_VARIABLE,
+  FBRIL_STATUS_UNDEFINED_FUNCTION,
+  FBRIL_STATUS_UNDEFINED_LABEL,
+  FBRIL_STATUS_TYPE_ERROR,
+  FBRIL_STATUS_MISSING_RETURN_VALUE,
+  FBRIL_STATUS_DIVISION_BY_ZERO,
+  FBRIL_STATUS_BAD_ARGUMENT,
+  FBRIL_STATUS_STEP_LIMIT,
+  FBRIL_STATUS_CALL_DEPTH_LIMIT,
+  FBRIL_STATUS_TIMEOUT,
+  FBRIL_STATUS_IO,
+  FBRIL_STATUS_REPLAY_MISMATCH,
+  FBRIL_STATUS_UNSUPPORTED_INSTR,
+  FBRIL_STATUS_HOST_ERROR,
+} FbrilStatus;
+
+// A flat Bril program loaded by `fbril_load`
+// (opaque to C, and must be released with `fbril_free`)
+typedef struct FbrilProgram FbrilProgram;
+
+// Called with each chunk of output produced by the program
+// (`data` is only valid for the duration of the call, and is not
+// NUL-terminated). `user_data` is passed through from `fbril_run`.
+typedef void (*FbrilOutputCallback)(const uint8_t *data, size_t len, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Loads the `.fbril` file at `path`, storing a handle to it in `*out`
+// (which is set to `NULL` if the file can't be loaded)
+//
+// # Safety
+// `path` must be a valid, NUL-terminated C string, and `out` must be
+// valid for writes
+enum FbrilStatus fbril_load(const char *path, struct FbrilProgram **out);
+
+// Interprets `program`, passing the `num_args` strings in `args` to `main`.
+// The program's output is passed to `callback` (along with `user_data`)
+// as it is produced; `callback` may be `NULL`, in which case the output
+// is discarded.
+// - A program can be run any number of times (including from several
+//   threads at once)
+//
+// # Safety
+// - `program` must have been returned by `fbril_load` (and not yet freed)
+// - `args` must point to `num_args` valid, NUL-terminated C strings
+//   (it may be `NULL` if `num_args` is 0)
+// - `callback` must be safe to call with `user_data`
+enum FbrilStatus fbril_run(const struct FbrilProgram *program,
+                           const char *const *args,
+                           size_t num_args,
+                           FbrilOutputCallback callback,
+                           void *user_data);
+
+// Releases a program returned by `fbril_load` (does nothing if `program`
+// is `NULL`)
+//
+// # Safety
+// `program` must have been returned by `fbril_load`, and must not be used
+// after this call
+void fbril_free(struct FbrilProgram *program);
+
+// Returns a message describing the most recent error on the current thread,
+// or `NULL` if there hasn't been one. The string is owned by flat-bril, and
+// is only valid until the next call to the C API on this thread.
+const char *fbril_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FLAT_BRIL_H */
@@ -0,0 +1,43 @@
+#!/bin/bash
+
+# Benchmarks the flat interpreter on a few long-running programs.
+# Usage: ./interp_bench.sh [BASELINE]
+# - If a `BASELINE` binary (e.g. a release build of an older commit) is given,
+#   each program is also run with it, so that the two can be compared
+# - Each program is also run with `--fuse`, to measure the effect of
+#   superinstructions, & with `--no-predecode`, to measure the effect of
+#   pre-decoding
+# - Results are written to interp_bench.md
+
+BIN="./target/release/flat-bril"
+BASELINE="$1"
+FBRIL_DIR=$(mktemp -d)
+
+# Create a hyperfine command with all benchmarks
+# (program output is piped rather than discarded, since writing to /dev/null
+# hides the cost of output-bound programs)
+CMD="hyperfine -w3 --shell=none --output=pipe --export-markdown interp_bench.md"
+
+# Each benchmark is a program in test/ along with the args passed to `main`
+# (the args are larger than the ones in the .bril files so that
+# the runtime is dominated by interpretation rather than process startup)
+benchmarks=("ackermann 3 6" "check-primes 2000" "fib_recursive 25" "fizz-buzz 100000" "pythagorean_triple 600" "sum-check 1000000")
+
+for benchmark in "${benchmarks[@]}"; do
+  read -r name args <<< "$benchmark"
+  fbril="$FBRIL_DIR/$name.fbril"
+  "$BIN" --fbril --filename "$fbril" < "test/$name.json" > /dev/null
+
+  # Add to hyperfine command
+  CMD+=" --command-name \"$name\" \"$BIN --filename $fbril --interp $args\""
+  CMD+=" --command-name \"$name (fused)\" \"$BIN --fuse --filename $fbril --interp $args\""
+  CMD+=" --command-name \"$name (no predecode)\" \"$BIN --no-predecode --filename $fbril --interp $args\""
+  if [ -n "$BASELINE" ]; then
+    CMD+=" --command-name \"$name (baseline)\" \"$BASELINE --filename $fbril --interp $args\""
+  fi
+done
+
+# Execute the command
+eval "$CMD"
+
+rm -rf "$FBRIL_DIR"
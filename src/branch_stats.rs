@@ -0,0 +1,184 @@
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+use crate::debugger::format_instr;
+use crate::interp::get_func_name;
+use crate::profile::Profile;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A `br` which appears in a `BranchReport`
+/// - `num_true` & `num_false` are the no. of times its condition was true
+///   & false
+/// - `bias` is the fraction of its executions which went the more common
+///   way (so it's always at least 0.5)
+/// - `instr` is the `br` in Bril's text format
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchStat {
+    pub func: String,
+    pub pc: usize,
+    pub num_true: u64,
+    pub num_false: u64,
+    pub bias: f64,
+    pub instr: String,
+}
+
+/// The heavily biased branches of a run, i.e. the `br`s which went the same
+/// way at least `min_bias` of the time (e.g. loop conditions & error
+/// checks), sorted by how often they were executed (most often first)
+/// - `num_executed` is the no. of distinct `br`s which were executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchReport {
+    pub biased: Vec<BranchStat>,
+    pub num_executed: usize,
+    pub min_bias: f64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl BranchStat {
+    /// The no. of times the `br` was executed
+    pub fn executions(&self) -> u64 {
+        self.num_true + self.num_false
+    }
+}
+
+impl BranchReport {
+    /// Finds the `br`s of `program` whose outcomes during the run recorded
+    /// in `profile` were at least `min_bias` (between 0.5 & 1) one way
+    /// (`program` must be the program that was profiled)
+    pub fn new<P: IndexPair>(
+        program: &[InstrView<P>],
+        profile: &Profile,
+        min_bias: f64,
+    ) -> Self {
+        let mut biased = vec![];
+        let mut num_executed = 0;
+        for view in program {
+            let name = get_func_name(view);
+            let Some(func_profile) = profile.functions.get(name) else {
+                continue;
+            };
+            for &(pc, num_true, num_false) in &func_profile.branches {
+                let Some(instr) = view.instrs.get(pc) else {
+                    continue;
+                };
+                num_executed += 1;
+                let bias = num_true.max(num_false) as f64
+                    / (num_true + num_false) as f64;
+                if bias >= min_bias {
+                    biased.push(BranchStat {
+                        func: name.to_string(),
+                        pc,
+                        num_true,
+                        num_false,
+                        bias,
+                        instr: format_instr(view, instr),
+                    });
+                }
+            }
+        }
+        // (ties are broken by program order, since the sort is stable)
+        biased.sort_by_key(|branch| Reverse(branch.executions()));
+        Self {
+            biased,
+            num_executed,
+            min_bias,
+        }
+    }
+
+    /// Renders the report as a table
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "branch stats ({} of {} branches executed are >= {:.1}% \
+            biased):\n",
+            self.biased.len(),
+            self.num_executed,
+            self.min_bias * 100.0
+        );
+        writeln!(
+            text,
+            "  {:>10}  {:>10}  {:>6}  branch",
+            "true", "false", "bias"
+        )
+        .unwrap();
+        for branch in &self.biased {
+            writeln!(
+                text,
+                "  {:>10}  {:>10}  {:>5.1}%  @{} {:>4}  {}",
+                branch.num_true,
+                branch.num_false,
+                branch.bias * 100.0,
+                branch.func,
+                branch.pc,
+                branch.instr
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod branch_stats_tests {
+    use serde_json::json;
+
+    use crate::branch_stats::BranchReport;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::profile::Profiler;
+
+    /// A loop condition is true on every iteration but the last, so it's
+    /// reported as biased, unlike a branch which alternates
+    #[test]
+    fn test_branch_report() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "n", "type": "int", "value": 9 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "const", "dest": "odd", "type": "bool",
+                  "value": false },
+                { "label": "loop" },
+                { "op": "lt", "dest": "c", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["c"] },
+                { "label": "body" },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "not", "dest": "odd", "type": "bool",
+                  "args": ["odd"] },
+                { "op": "br", "labels": ["loop", "loop"], "args": ["odd"] },
+                { "label": "done" },
+                { "op": "print", "args": ["i"] }
+            ]
+        }]});
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let mut profiler = Profiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec![], &mut ctx).expect("program runs");
+        let profile = profiler.profile();
+        // (both labels of the 2nd `br` are the same, so only the outcomes
+        // tell its true & false executions apart)
+        assert_eq!(profile.functions["main"].branches, [(5, 9, 1), (8, 5, 4)]);
+
+        let report = BranchReport::new(program.views(), &profile, 0.9);
+        assert_eq!(report.num_executed, 2);
+        assert_eq!(report.biased.len(), 1);
+        assert_eq!(report.biased[0].pc, 5);
+        assert_eq!(report.biased[0].bias, 0.9);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::flatten;
+use crate::types::InstrStore;
+use crate::unflatten;
+
+/* -------------------------------------------------------------------------- */
+/*                                 Conversions                                */
+/* -------------------------------------------------------------------------- */
+
+// Conversions between flat Bril functions (`InstrStore`s) and the types in
+// the `bril-rs` crate, so that existing Rust Bril tooling can use `.fbril`
+// files as an on-disk representation without rewriting its front end.
+// - These go through Bril's JSON representation (using `flatten.rs` and
+//   `unflatten.rs`), so like the rest of flat-bril, they only support
+//   Core Bril (they panic on instructions from other extensions)
+
+/// Flattens a `bril-rs` function
+impl From<bril_rs::Function> for InstrStore {
+    fn from(func: bril_rs::Function) -> Self {
+        let func_json = serde_json::to_value(&func)
+            .expect("unable to serialize `bril_rs::Function`");
+        flatten::flatten_instrs(&func_json)
+    }
+}
+
+/// Converts a flattened function back to a `bril-rs` function
+impl From<&InstrStore> for bril_rs::Function {
+    fn from(instr_store: &InstrStore) -> Self {
+        let func_json = unflatten::unflatten_instrs(instr_store);
+        serde_json::from_value(func_json)
+            .expect("unflattened function is not a valid `bril_rs::Function`")
+    }
+}
+
+/// Flattens every function in a `bril-rs` program
+pub fn program_to_instr_stores(program: bril_rs::Program) -> Vec<InstrStore> {
+    program
+        .functions
+        .into_iter()
+        .map(InstrStore::from)
+        .collect()
+}
+
+/// Converts a list of flattened functions back to a `bril-rs` program
+pub fn instr_stores_to_program(
+    instr_stores: &[InstrStore],
+) -> bril_rs::Program {
+    let functions: Vec<bril_rs::Function> =
+        instr_stores.iter().map(bril_rs::Function::from).collect();
+    // (going through JSON means we don't have to care about which of
+    // `bril-rs`'s features, e.g. `import`, are enabled)
+    serde_json::from_value(serde_json::json!({ "functions": functions }))
+        .expect("unable to construct `bril_rs::Program`")
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod bril_rs_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::bril_rs::{instr_stores_to_program, program_to_instr_stores};
+
+    /// Converting a `bril-rs` program to the flat format and back
+    /// gives the original program
+    #[test]
+    fn test_bril_rs_roundtrip() {
+        for path in ["test/call-with-args.json", "test/gcd.json"] {
+            let file = File::open(path).expect("Unable to open file");
+            let program: bril_rs::Program =
+                serde_json::from_reader(BufReader::new(file))
+                    .expect("Unable to parse program");
+            let instr_stores = program_to_instr_stores(program.clone());
+            assert_eq!(instr_stores_to_program(&instr_stores), program);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use crate::interp::{get_func, get_func_name};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The call graph of a program, whose nodes are the program's functions
+/// (in the order they appear in the file)
+/// - `calls[f]` maps the index of each function called by `functions[f]`
+///   to the no. of `call` instructions in `functions[f]` which call it
+/// - `call` instructions whose callee isn't defined in the program are
+///   ignored (the interpreter reports them when they're executed)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallGraph {
+    pub functions: Vec<String>,
+    pub calls: Vec<BTreeMap<usize, usize>>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Quotes `s` as a Graphviz ID (escaping `"` & `\`), where each newline
+/// becomes a left-justified line break (so a multi-line label should
+/// end with a newline)
+pub fn dot_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\l"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl CallGraph {
+    /// Builds the call graph of the program whose functions are `views`,
+    /// using the callee names that `call` instructions refer to in
+    /// each function's `funcs_store`
+    pub fn new<P: IndexPair>(views: &[InstrView<P>]) -> Self {
+        let functions: Vec<String> = views
+            .iter()
+            .map(|view| get_func_name(view).to_string())
+            .collect();
+        let func_idxes: HashMap<&str, usize> = functions
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.as_str(), idx))
+            .collect();
+        let calls = views
+            .iter()
+            .map(|view| {
+                let mut callees = BTreeMap::new();
+                for instr in view.instrs {
+                    if Opcode::u32_to_opcode(instr.op()) != Some(Opcode::Call) {
+                        continue;
+                    }
+                    let Some((offset, len)) = instr.funcs.get() else {
+                        continue;
+                    };
+                    if let Some(&callee) =
+                        func_idxes.get(get_func(view, offset, len))
+                    {
+                        *callees.entry(callee).or_default() += 1;
+                    }
+                }
+                callees
+            })
+            .collect();
+        Self { functions, calls }
+    }
+
+    /// The index of the function named `name` (without the leading `@`)
+    pub fn function_index(&self, name: &str) -> Option<usize> {
+        self.functions.iter().position(|func| func == name)
+    }
+
+    /// The indexes of the functions called by function `f`, in ascending order
+    pub fn callees(&self, f: usize) -> impl Iterator<Item = usize> + '_ {
+        self.calls[f].keys().copied()
+    }
+
+    /// The indexes of the functions which call function `f`,
+    /// in ascending order
+    pub fn callers(&self, f: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..self.functions.len())
+            .filter(move |&caller| self.calls[caller].contains_key(&f))
+    }
+
+    /// Determines which functions can be reached from function `root`
+    /// (e.g. `main`) by following calls: `reachable[f]` is `false` iff
+    /// function `f` is dead
+    pub fn reachable_from(&self, root: usize) -> Vec<bool> {
+        let mut reachable = vec![false; self.functions.len()];
+        let mut worklist = vec![root];
+        reachable[root] = true;
+        while let Some(f) = worklist.pop() {
+            for callee in self.callees(f) {
+                if !reachable[callee] {
+                    reachable[callee] = true;
+                    worklist.push(callee);
+                }
+            }
+        }
+        reachable
+    }
+
+    /// Renders the call graph in Graphviz's DOT language, with one node per
+    /// function & one edge per caller/callee pair (labelled with the no. of
+    /// call sites if there's more than one)
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph callgraph {\n");
+        for func in &self.functions {
+            writeln!(dot, "  {};", dot_quote(func)).unwrap();
+        }
+        for (caller, callees) in self.calls.iter().enumerate() {
+            for (&callee, &num_calls) in callees {
+                write!(
+                    dot,
+                    "  {} -> {}",
+                    dot_quote(&self.functions[caller]),
+                    dot_quote(&self.functions[callee])
+                )
+                .unwrap();
+                if num_calls > 1 {
+                    write!(dot, " [label=\"{num_calls}\"]").unwrap();
+                }
+                dot.push_str(";\n");
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the call graph as plain text, with one line per function
+    /// listing the functions it calls
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (caller, func) in self.functions.iter().enumerate() {
+            let callees: Vec<&str> = self
+                .callees(caller)
+                .map(|callee| self.functions[callee].as_str())
+                .collect();
+            writeln!(text, "@{func} -> [{}]", callees.join(", ")).unwrap();
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod callgraph_tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use crate::callgraph::CallGraph;
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+
+    /// Calls are counted per call site, recursion is a self-edge,
+    /// & functions that are never called from `main` are dead
+    #[test]
+    fn test_call_graph() {
+        let json = json!({ "functions": [
+            {
+                "name": "main",
+                "instrs": [
+                    { "op": "const", "dest": "n", "type": "int", "value": 5 },
+                    { "op": "call", "dest": "a", "type": "int",
+                      "funcs": ["fact"], "args": ["n"] },
+                    { "op": "call", "dest": "b", "type": "int",
+                      "funcs": ["fact"], "args": ["a"] },
+                    { "op": "print", "args": ["b"] }
+                ]
+            },
+            {
+                "name": "fact",
+                "args": [{ "name": "n", "type": "int" }],
+                "type": "int",
+                "instrs": [
+                    { "op": "const", "dest": "one", "type": "int",
+                      "value": 1 },
+                    { "op": "le", "dest": "done", "type": "bool",
+                      "args": ["n", "one"] },
+                    { "op": "br", "labels": ["base", "rec"],
+                      "args": ["done"] },
+                    { "label": "base" },
+                    { "op": "ret", "args": ["one"] },
+                    { "label": "rec" },
+                    { "op": "sub", "dest": "m", "type": "int",
+                      "args": ["n", "one"] },
+                    { "op": "call", "dest": "r", "type": "int",
+                      "funcs": ["fact"], "args": ["m"] },
+                    { "op": "mul", "dest": "r", "type": "int",
+                      "args": ["n", "r"] },
+                    { "op": "ret", "args": ["r"] }
+                ]
+            },
+            {
+                "name": "unused",
+                "instrs": [{ "op": "call", "funcs": ["main"] }]
+            }
+        ]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+
+        let graph = CallGraph::new(&views);
+        assert_eq!(graph.functions, ["main", "fact", "unused"]);
+        assert_eq!(
+            graph.calls,
+            [
+                BTreeMap::from([(1, 2)]),
+                BTreeMap::from([(1, 1)]),
+                BTreeMap::from([(0, 1)])
+            ]
+        );
+        assert_eq!(graph.callers(1).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(graph.reachable_from(0), [true, true, false]);
+        assert_eq!(
+            graph.to_dot(),
+            "digraph callgraph {\n  \"main\";\n  \"fact\";\n  \"unused\";\n  \
+             \"main\" -> \"fact\" [label=\"2\"];\n  \"fact\" -> \"fact\";\n  \
+             \"unused\" -> \"main\";\n}\n"
+        );
+    }
+}
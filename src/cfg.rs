@@ -0,0 +1,719 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Write};
+
+use crate::callgraph::dot_quote;
+use crate::flatten;
+use crate::program::{Function, InstructionRef, OpRef};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The maximum no. of instructions shown in each block by `Cfg::to_dot`
+/// (the rest of the block is elided)
+pub const DOT_PREVIEW_LEN: usize = 8;
+
+/// An owned (non-label) instruction, i.e. the owned counterpart of `OpRef`,
+/// which optimization passes are free to modify
+#[derive(Debug, Clone, PartialEq)]
+pub struct Operation {
+    pub op: Opcode,
+    pub dest: Option<String>,
+    pub ty: Option<Type>,
+    pub value: Option<BrilValue>,
+    pub args: Vec<String>,
+    pub labels: Vec<String>,
+    pub func: Option<String>,
+}
+
+/// A basic block: a maximal sequence of instructions which is only entered
+/// at the top (via `label`, if it has one) & only exited at the bottom
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub label: Option<String>,
+    pub instrs: Vec<Operation>,
+}
+
+/// The control-flow graph of a Bril function, whose blocks are kept in
+/// program order (so a block without a terminator falls through to the
+/// next block). The entry block is `blocks[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub name: String,
+    pub args: Vec<(String, Type)>,
+    pub ret_ty: Option<Type>,
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// The dominator tree of a `Cfg`
+/// - `idom[b]` is the immediate dominator of block `b`
+///   (`None` for the entry block & for unreachable blocks)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dominators {
+    pub idom: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+}
+
+/// A natural loop: the blocks which can reach one of the loop's back edges
+/// (an edge whose target dominates its source) without going through
+/// `header`. Loops which share a header are merged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub header: usize,
+    pub body: HashSet<usize>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Operation {
+    /// Determines if the instruction ends a basic block
+    pub fn is_terminator(&self) -> bool {
+        matches!(self.op, Opcode::Jmp | Opcode::Br | Opcode::Ret)
+    }
+
+    /// Converts the instruction to Bril's JSON representation
+    /// (empty `args` / `labels` lists are omitted)
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({ "op": self.op.as_str() });
+        if let Some(dest) = &self.dest {
+            json["dest"] = dest.as_str().into();
+        }
+        if let Some(ty) = self.ty {
+            json["type"] = ty.to_json();
+        }
+        match self.value {
+            Some(BrilValue::IntVal(i)) => json["value"] = i.into(),
+            Some(BrilValue::BoolVal(b)) => json["value"] = bool::from(b).into(),
+            #[cfg(feature = "ext-values")]
+            Some(BrilValue::Extern(_)) => (),
+            None => (),
+        }
+        if !self.args.is_empty() {
+            json["args"] = self.args.clone().into();
+        }
+        if !self.labels.is_empty() {
+            json["labels"] = self.labels.clone().into();
+        }
+        if let Some(func) = &self.func {
+            json["funcs"] = vec![func.clone()].into();
+        }
+        json
+    }
+}
+
+/// Renders the instruction in Bril's text format
+/// (the same format as `debugger::format_instr`)
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(dest) = &self.dest {
+            write!(f, "{dest}")?;
+            if let Some(ty) = self.ty {
+                write!(f, ": {ty}")?;
+            }
+            write!(f, " = ")?;
+        }
+        write!(f, "{}", self.op)?;
+        if let Some(value) = self.value {
+            write!(f, " {value}")?;
+        }
+        if let Some(func) = &self.func {
+            write!(f, " @{func}")?;
+        }
+        for arg in &self.args {
+            write!(f, " {arg}")?;
+        }
+        for label in &self.labels {
+            write!(f, " .{label}")?;
+        }
+        write!(f, ";")
+    }
+}
+
+impl From<&OpRef<'_>> for Operation {
+    fn from(op_ref: &OpRef<'_>) -> Self {
+        Self {
+            op: op_ref.op,
+            dest: op_ref.dest.map(str::to_string),
+            ty: op_ref.ty,
+            value: op_ref.value,
+            args: op_ref.args.iter().map(|s| s.to_string()).collect(),
+            labels: op_ref.labels.iter().map(|s| s.to_string()).collect(),
+            func: op_ref.func.map(str::to_string),
+        }
+    }
+}
+
+impl Cfg {
+    /// Splits a function into basic blocks
+    /// (panics if the function contains an extension instruction, since its
+    /// effect on control flow is unknown)
+    pub fn new<P: IndexPair>(func: Function<'_, P>) -> Self {
+        let mut blocks = vec![];
+        let mut current = BasicBlock {
+            label: None,
+            instrs: vec![],
+        };
+        for instr in func.instructions() {
+            match instr {
+                InstructionRef::Label(label) => {
+                    // A label starts a new block (the current one is
+                    // dropped if it's empty & unlabeled, which happens if
+                    // the function starts with a label, or after a terminator)
+                    if current.label.is_some() || !current.instrs.is_empty() {
+                        blocks.push(current);
+                    }
+                    current = BasicBlock {
+                        label: Some(label.to_string()),
+                        instrs: vec![],
+                    };
+                }
+                InstructionRef::Extension(json) => {
+                    panic!(
+                        "@{}: can't build the CFG of a function with the \
+                        extension instruction {json}",
+                        func.name()
+                    )
+                }
+                InstructionRef::Op(op_ref) => {
+                    let operation = Operation::from(&op_ref);
+                    let is_terminator = operation.is_terminator();
+                    current.instrs.push(operation);
+                    if is_terminator {
+                        blocks.push(current);
+                        current = BasicBlock {
+                            label: None,
+                            instrs: vec![],
+                        };
+                    }
+                }
+            }
+        }
+        if current.label.is_some() || !current.instrs.is_empty() {
+            blocks.push(current);
+        }
+
+        Self {
+            name: func.name().to_string(),
+            args: func
+                .params()
+                .map(|(name, ty)| {
+                    (name.to_string(), ty.expect("parameter has no type"))
+                })
+                .collect(),
+            ret_ty: func.return_type(),
+            blocks,
+        }
+    }
+
+    /// Finds the block whose label is `label`
+    pub fn block_index(&self, label: &str) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| block.label.as_deref() == Some(label))
+    }
+
+    /// The successors of block `b` (jumps to undefined labels are ignored)
+    pub fn successors(&self, b: usize) -> Vec<usize> {
+        match self.blocks[b].instrs.last() {
+            Some(instr) if matches!(instr.op, Opcode::Jmp | Opcode::Br) => {
+                let mut succs = vec![];
+                for label in &instr.labels {
+                    if let Some(succ) = self.block_index(label)
+                        && !succs.contains(&succ)
+                    {
+                        succs.push(succ);
+                    }
+                }
+                succs
+            }
+            Some(instr) if instr.op == Opcode::Ret => vec![],
+            _ if b + 1 < self.blocks.len() => vec![b + 1],
+            _ => vec![],
+        }
+    }
+
+    /// The predecessors of every block
+    pub fn predecessors(&self) -> Vec<Vec<usize>> {
+        let mut preds = vec![vec![]; self.blocks.len()];
+        for b in 0..self.blocks.len() {
+            for succ in self.successors(b) {
+                preds[succ].push(b);
+            }
+        }
+        preds
+    }
+
+    /// The blocks reachable from the entry, in reverse postorder
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = vec![];
+        // Iterative DFS, where each stack entry is a block along with
+        // the successors that haven't been visited yet
+        let mut stack: Vec<(usize, Vec<usize>)> = vec![];
+        if !self.blocks.is_empty() {
+            visited[0] = true;
+            stack.push((0, self.successors(0)));
+        }
+        while let Some((b, succs)) = stack.last_mut() {
+            if let Some(succ) = succs.pop() {
+                if !visited[succ] {
+                    visited[succ] = true;
+                    let succ_succs = self.successors(succ);
+                    stack.push((succ, succ_succs));
+                }
+            } else {
+                postorder.push(*b);
+                stack.pop();
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+
+    /// Removes the blocks which can't be reached from the entry block
+    /// (since control never falls through into an unreachable block,
+    /// the remaining blocks don't need to be reordered)
+    pub fn remove_unreachable_blocks(&mut self) {
+        let reachable: HashSet<usize> =
+            self.reverse_postorder().into_iter().collect();
+        let mut b = 0;
+        self.blocks.retain(|_| {
+            b += 1;
+            reachable.contains(&(b - 1))
+        });
+    }
+
+    /// The PC of the first instruction of each block (labels aren't
+    /// instructions, so an empty block starts at the same PC as the next one).
+    /// The CFG must not have been modified since it was built, so that its
+    /// blocks hold the function's instructions in order.
+    pub fn block_start_pcs(&self) -> Vec<usize> {
+        let mut pc = 0;
+        self.blocks
+            .iter()
+            .map(|block| {
+                let start = pc;
+                pc += block.instrs.len();
+                start
+            })
+            .collect()
+    }
+
+    /// Returns a label that isn't used by any block, starting with `prefix`
+    pub fn fresh_label(&self, prefix: &str) -> String {
+        let labels: HashSet<&str> = self
+            .blocks
+            .iter()
+            .filter_map(|block| block.label.as_deref())
+            .collect();
+        (0..)
+            .map(|i| format!("{prefix}.{i}"))
+            .find(|label| !labels.contains(label.as_str()))
+            .expect("ran out of labels")
+    }
+
+    /// Computes the dominator tree of the CFG, using the iterative algorithm
+    /// from Cooper, Harvey & Kennedy's "A Simple, Fast Dominance Algorithm"
+    pub fn dominators(&self) -> Dominators {
+        let rpo = self.reverse_postorder();
+        let mut rpo_idx = vec![usize::MAX; self.blocks.len()];
+        for (i, &b) in rpo.iter().enumerate() {
+            rpo_idx[b] = i;
+        }
+        let preds = self.predecessors();
+
+        let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        if let Some(&entry) = rpo.first() {
+            idom[entry] = Some(entry);
+        }
+        let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+            while a != b {
+                while rpo_idx[a] > rpo_idx[b] {
+                    a = idom[a].expect("processed blocks have an idom");
+                }
+                while rpo_idx[b] > rpo_idx[a] {
+                    b = idom[b].expect("processed blocks have an idom");
+                }
+            }
+            a
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &pred in &preds[b] {
+                    if idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => intersect(&idom, pred, other),
+                    });
+                }
+                if new_idom.is_some() && idom[b] != new_idom {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut reachable = vec![false; self.blocks.len()];
+        for &b in &rpo {
+            reachable[b] = true;
+        }
+        // The entry block has no immediate dominator
+        if let Some(&entry) = rpo.first() {
+            idom[entry] = None;
+        }
+        Dominators { idom, reachable }
+    }
+
+    /// Finds the natural loops in the CFG, with inner loops before the loops
+    /// that contain them
+    pub fn natural_loops(&self, dominators: &Dominators) -> Vec<Loop> {
+        let preds = self.predecessors();
+        let mut loops: Vec<Loop> = vec![];
+        for b in 0..self.blocks.len() {
+            for header in self.successors(b) {
+                if !dominators.dominates(header, b) {
+                    continue;
+                }
+                let idx = match loops.iter().position(|l| l.header == header) {
+                    Some(idx) => idx,
+                    None => {
+                        loops.push(Loop {
+                            header,
+                            body: HashSet::from([header]),
+                        });
+                        loops.len() - 1
+                    }
+                };
+                // Walk backwards from the source of the back edge
+                let mut stack = vec![b];
+                while let Some(block) = stack.pop() {
+                    if loops[idx].body.insert(block) {
+                        stack.extend(&preds[block]);
+                    }
+                }
+            }
+        }
+        loops.sort_by_key(|l| l.body.len());
+        loops
+    }
+
+    /// The variables which are live on entry to every block
+    /// (standard backwards dataflow analysis)
+    pub fn live_in(&self) -> Vec<HashSet<String>> {
+        // The variables used before being defined in each block,
+        // and the variables defined in each block
+        let mut uses = vec![HashSet::new(); self.blocks.len()];
+        let mut defs = vec![HashSet::new(); self.blocks.len()];
+        for (b, block) in self.blocks.iter().enumerate() {
+            for instr in &block.instrs {
+                for arg in &instr.args {
+                    if !defs[b].contains(arg) {
+                        uses[b].insert(arg.clone());
+                    }
+                }
+                if let Some(dest) = &instr.dest {
+                    defs[b].insert(dest.clone());
+                }
+            }
+        }
+
+        let succs: Vec<Vec<usize>> =
+            (0..self.blocks.len()).map(|b| self.successors(b)).collect();
+        let mut live_in: Vec<HashSet<String>> = uses.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for b in (0..self.blocks.len()).rev() {
+                for &succ in &succs[b] {
+                    let new_vars: Vec<String> = live_in[succ]
+                        .iter()
+                        .filter(|var| {
+                            !defs[b].contains(*var)
+                                && !live_in[b].contains(*var)
+                        })
+                        .cloned()
+                        .collect();
+                    changed |= !new_vars.is_empty();
+                    live_in[b].extend(new_vars);
+                }
+            }
+        }
+        live_in
+    }
+
+    /// The name of block `b` when printing the CFG: its label, or
+    /// `<entry>` / `<bN>` if it doesn't have one (which can't clash with a
+    /// label, since `<` & `>` aren't allowed in Bril identifiers)
+    pub fn block_name(&self, b: usize) -> String {
+        match &self.blocks[b].label {
+            Some(label) => format!(".{label}"),
+            None if b == 0 => "<entry>".to_string(),
+            None => format!("<b{b}>"),
+        }
+    }
+
+    /// The outgoing edges of block `b`, as `(successor, label)` pairs, where
+    /// the label is `"true"` / `"false"` for the edges of a `br` (unlike
+    /// `successors`, an edge is listed twice if both of a `br`'s labels
+    /// lead to the same block)
+    fn labelled_successors(&self, b: usize) -> Vec<(usize, Option<&str>)> {
+        match self.blocks[b].instrs.last() {
+            Some(instr) if instr.op == Opcode::Br => instr
+                .labels
+                .iter()
+                .zip(["true", "false"])
+                .filter_map(|(label, edge)| {
+                    Some((self.block_index(label)?, Some(edge)))
+                })
+                .collect(),
+            _ => self
+                .successors(b)
+                .into_iter()
+                .map(|succ| (succ, None))
+                .collect(),
+        }
+    }
+
+    /// Renders the CFG in Graphviz's DOT language, with one box per block
+    /// showing its name & (up to `DOT_PREVIEW_LEN` of) its instructions
+    pub fn to_dot(&self) -> String {
+        let mut dot = format!("digraph {} {{\n", dot_quote(&self.name));
+        dot.push_str("  node [shape=box, fontname=monospace];\n");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut text = format!("{}:\n", self.block_name(b));
+            for instr in block.instrs.iter().take(DOT_PREVIEW_LEN) {
+                writeln!(text, "  {instr}").unwrap();
+            }
+            if block.instrs.len() > DOT_PREVIEW_LEN {
+                let num_elided = block.instrs.len() - DOT_PREVIEW_LEN;
+                writeln!(text, "  ... ({num_elided} more)").unwrap();
+            }
+            writeln!(dot, "  b{b} [label={}];", dot_quote(&text)).unwrap();
+        }
+        for b in 0..self.blocks.len() {
+            for (succ, label) in self.labelled_successors(b) {
+                write!(dot, "  b{b} -> b{succ}").unwrap();
+                if let Some(label) = label {
+                    write!(dot, " [label={label}]").unwrap();
+                }
+                dot.push_str(";\n");
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the CFG as plain text: each block's instructions,
+    /// followed by the blocks it can jump or fall through to
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (b, block) in self.blocks.iter().enumerate() {
+            writeln!(text, "{}:", self.block_name(b)).unwrap();
+            for instr in &block.instrs {
+                writeln!(text, "  {instr}").unwrap();
+            }
+            let succs: Vec<String> = self
+                .successors(b)
+                .into_iter()
+                .map(|succ| self.block_name(succ))
+                .collect();
+            writeln!(text, "  -> [{}]", succs.join(", ")).unwrap();
+        }
+        text
+    }
+
+    /// Converts the CFG back to Bril's JSON representation of a function
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut instrs = vec![];
+        for block in &self.blocks {
+            if let Some(label) = &block.label {
+                instrs.push(serde_json::json!({ "label": label }));
+            }
+            instrs.extend(block.instrs.iter().map(Operation::to_json));
+        }
+        let args: Vec<serde_json::Value> = self
+            .args
+            .iter()
+            .map(|(name, ty)| serde_json::json!({ "name": name, "type": ty }))
+            .collect();
+        let mut json = serde_json::json!({
+            "name": self.name,
+            "instrs": instrs,
+        });
+        if !args.is_empty() {
+            json["args"] = args.into();
+        }
+        if let Some(ret_ty) = self.ret_ty {
+            json["type"] = ret_ty.to_json();
+        }
+        json
+    }
+
+    /// Converts the CFG back to a flat function
+    pub fn to_instr_store(&self) -> InstrStore {
+        flatten::flatten_instrs(&self.to_json())
+    }
+
+    /// The names & types of all the variables in the function
+    /// (if a variable is assigned values of different types,
+    /// the type of its first definition is used)
+    pub fn var_types(&self) -> HashMap<String, Type> {
+        let mut types: HashMap<String, Type> = HashMap::new();
+        for (name, ty) in &self.args {
+            types.entry(name.clone()).or_insert(*ty);
+        }
+        for instr in self.blocks.iter().flat_map(|block| &block.instrs) {
+            if let (Some(dest), Some(ty)) = (&instr.dest, instr.ty) {
+                types.entry(dest.clone()).or_insert(ty);
+            }
+        }
+        types
+    }
+}
+
+impl Dominators {
+    /// Determines if block `a` dominates block `b`
+    /// (every block dominates itself)
+    pub fn dominates(&self, a: usize, mut b: usize) -> bool {
+        if !self.reachable[b] {
+            return false;
+        }
+        loop {
+            if a == b {
+                return true;
+            }
+            match self.idom[b] {
+                Some(parent) => b = parent,
+                None => return false,
+            }
+        }
+    }
+
+    /// The children of every block in the dominator tree
+    pub fn children(&self) -> Vec<Vec<usize>> {
+        let mut children = vec![vec![]; self.idom.len()];
+        for (b, idom) in self.idom.iter().enumerate() {
+            if let Some(parent) = idom {
+                children[*parent].push(b);
+            }
+        }
+        children
+    }
+
+    /// The dominance frontier of every block, i.e. the blocks where
+    /// its dominance ends (Cooper, Harvey & Kennedy's algorithm)
+    pub fn frontiers(&self, cfg: &Cfg) -> Vec<HashSet<usize>> {
+        let mut frontiers = vec![HashSet::new(); self.idom.len()];
+        for (b, preds) in cfg.predecessors().iter().enumerate() {
+            if !self.reachable[b] || preds.len() < 2 {
+                continue;
+            }
+            for &pred in preds {
+                let mut runner = pred;
+                while self.reachable[runner] && Some(runner) != self.idom[b] {
+                    frontiers[runner].insert(b);
+                    match self.idom[runner] {
+                        Some(parent) => runner = parent,
+                        None => break,
+                    }
+                }
+            }
+        }
+        frontiers
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod cfg_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use serde_json::json;
+
+    use crate::cfg::Cfg;
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+    use crate::program::Program;
+
+    /// The CFG of a function with a loop has the expected edges &
+    /// dominator tree, and converting it back gives the original function
+    #[test]
+    fn test_cfg_of_loop() {
+        let file = File::open("test/gcd.json").expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+        let program = Program::new(&views);
+
+        for func in program.functions() {
+            let cfg = Cfg::new(func);
+            let dominators = cfg.dominators();
+            for b in cfg.reverse_postorder() {
+                // The entry dominates every reachable block, and every
+                // block's predecessors are dominated by its idom
+                assert!(dominators.dominates(0, b));
+                for pred in &cfg.predecessors()[b] {
+                    if let Some(idom) = dominators.idom[b] {
+                        assert!(dominators.dominates(idom, *pred));
+                    }
+                }
+            }
+            let func_json = json["functions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|f| f["name"] == func.name())
+                .unwrap();
+            assert_eq!(
+                cfg.to_instr_store(),
+                crate::flatten::flatten_instrs(func_json)
+            );
+        }
+    }
+
+    /// The DOT rendering of a CFG labels the edges of `br`s, & escapes
+    /// the text of each block
+    #[test]
+    fn test_cfg_to_dot() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "c", "type": "bool",
+                  "value": true },
+                { "op": "br", "labels": ["then", "then"], "args": ["c"] },
+                { "label": "then" },
+                { "op": "print", "args": ["c"] }
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+        let cfg = Cfg::new(Program::new(&views).function("main").unwrap());
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph \"main\" {\n  \
+             node [shape=box, fontname=monospace];\n  \
+             b0 [label=\"<entry>:\\l  c: bool = const true;\\l  \
+             br c .then .then;\\l\"];\n  \
+             b1 [label=\".then:\\l  print c;\\l\"];\n  \
+             b0 -> b1 [label=true];\n  \
+             b0 -> b1 [label=false];\n}\n"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::interp::{Environment, get_func_name, get_table_label_name};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The maximum no. of events in a trace: once it's reached, no new spans
+/// are started (so that long runs don't produce unusably large traces)
+pub const MAX_TRACE_EVENTS: usize = 1_000_000;
+
+/// A single event in Chrome's trace-event format
+/// - `ph` is the phase: `B` / `E` begin & end a function call, while `X`
+///   is a complete event (a block), which has a duration
+/// - `ts` & `dur` are in microseconds since the start of the run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TraceEvent {
+    pub name: String,
+    pub cat: &'static str,
+    pub ph: &'static str,
+    pub ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dur: Option<f64>,
+    pub pid: u32,
+    pub tid: u32,
+}
+
+/// A call which is currently on the stack
+/// - `recorded` is false if the call started after the trace was full,
+///   in which case it has no `B` event to end
+/// - `block` is the PC & start time of the block currently executing
+///   (only tracked if blocks are being recorded)
+#[derive(Debug)]
+struct Frame {
+    recorded: bool,
+    block: Option<(usize, f64)>,
+}
+
+/// An `Observer` which records a timeline of the run in Chrome's trace-event
+/// format (see `to_json`), which can be viewed in `chrome://tracing` or
+/// Perfetto
+/// - Every function call is a span, named after the function
+/// - If `blocks` is true, each block executed is a span nested inside its
+///   function's span, named after the label it starts at. A block runs from
+///   the label that control jumped to until the next `jmp`, `br`, call or
+///   return (so falling through into the next label doesn't start a new
+///   block).
+pub struct ChromeTracer {
+    blocks: bool,
+    start: Instant,
+    stack: Vec<Frame>,
+    pub events: Vec<TraceEvent>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl ChromeTracer {
+    /// Creates a tracer whose timestamps are relative to now (which also
+    /// records a span for each block executed if `blocks` is true)
+    pub fn new(blocks: bool) -> Self {
+        Self {
+            blocks,
+            start: Instant::now(),
+            stack: vec![],
+            events: vec![],
+        }
+    }
+
+    /// The no. of microseconds since the tracer was created
+    fn now(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * 1_000_000.0
+    }
+
+    fn is_full(&self) -> bool {
+        self.events.len() >= MAX_TRACE_EVENTS
+    }
+
+    fn push_event(&mut self, name: String, ph: &'static str, ts: f64) {
+        self.events.push(TraceEvent {
+            name,
+            cat: if ph == "X" { "block" } else { "call" },
+            ph,
+            ts,
+            dur: None,
+            pid: 1,
+            tid: 1,
+        });
+    }
+
+    /// Ends the block currently executing in the innermost call (if any),
+    /// recording a span for it
+    fn end_block<P: IndexPair>(&mut self, instr_view: &InstrView<P>) {
+        let Some((pc, start)) =
+            self.stack.last_mut().and_then(|frame| frame.block.take())
+        else {
+            return;
+        };
+        if self.is_full() {
+            return;
+        }
+        let label = (0..instr_view.label_table.len())
+            .find(|&idx| instr_view.label_table[idx].pc == pc)
+            .map(|idx| get_table_label_name(instr_view, idx));
+        let name = match label {
+            Some(label) => format!("@{} .{label}", get_func_name(instr_view)),
+            None if pc == 0 => format!("@{} entry", get_func_name(instr_view)),
+            None => format!("@{} pc {pc}", get_func_name(instr_view)),
+        };
+        let now = self.now();
+        self.push_event(name, "X", start);
+        self.events.last_mut().unwrap().dur = Some(now - start);
+    }
+
+    /// Starts a new block at `pc` in the innermost call
+    fn start_block(&mut self, pc: usize) {
+        let now = self.now();
+        if self.blocks
+            && let Some(frame) = self.stack.last_mut()
+        {
+            frame.block = Some((pc, now));
+        }
+    }
+
+    /// Ends the innermost call
+    fn end_call<P: IndexPair>(&mut self, instr_view: &InstrView<P>) {
+        self.end_block(instr_view);
+        if let Some(frame) = self.stack.pop()
+            && frame.recorded
+        {
+            let now = self.now();
+            let name = format!("@{}", get_func_name(instr_view));
+            self.push_event(name, "E", now);
+        }
+    }
+
+    /// Ends the calls which are still on the stack (e.g. because the program
+    /// failed part-way through), so that every `B` event has a matching `E`
+    /// event (their blocks are dropped, since their names aren't known)
+    pub fn finish(&mut self) {
+        while let Some(frame) = self.stack.pop() {
+            if frame.recorded {
+                let now = self.now();
+                self.push_event(String::new(), "E", now);
+            }
+        }
+    }
+
+    /// Serializes the trace as a JSON object with a `traceEvents` array
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&serde_json::json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ns",
+        }))
+        .expect("unable to serialize trace")
+    }
+}
+
+impl Observer for ChromeTracer {
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        let recorded = !self.is_full();
+        if recorded {
+            let now = self.now();
+            self.push_event(format!("@{}", get_func_name(callee)), "B", now);
+        }
+        self.stack.push(Frame {
+            recorded,
+            block: None,
+        });
+        self.start_block(0);
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _value: Option<BrilValue>,
+    ) {
+        self.end_call(callee);
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        _pc: usize,
+        _taken: bool,
+        target_pc: usize,
+    ) {
+        if self.blocks {
+            self.end_block(instr_view);
+            self.start_block(target_pc);
+        }
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        _pc: usize,
+        target_pc: usize,
+    ) {
+        if self.blocks {
+            self.end_block(instr_view);
+            self.start_block(target_pc);
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod chrome_trace_tests {
+    use crate::chrome_trace::ChromeTracer;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+
+    /// Calls are nested spans, and blocks (if recorded) are spans inside
+    /// their function's span
+    #[test]
+    fn test_chrome_trace() {
+        let json_str = std::fs::read_to_string("test/gcd.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let run = |blocks: bool| {
+            let mut tracer = ChromeTracer::new(blocks);
+            let mut ctx = InterpContext::new(vec![], Limits::default())
+                .with_observer(&mut tracer);
+            interp_program(&program, vec!["4", "6"], &mut ctx)
+                .expect("program should run");
+            tracer.finish();
+            tracer
+        };
+
+        let tracer = run(false);
+        let phases: Vec<&str> = tracer.events.iter().map(|e| e.ph).collect();
+        assert_eq!(phases.first(), Some(&"B"));
+        assert_eq!(phases.last(), Some(&"E"));
+        assert!(!phases.contains(&"X"));
+        let begins = phases.iter().filter(|ph| **ph == "B").count();
+        let ends = phases.iter().filter(|ph| **ph == "E").count();
+        assert_eq!(begins, ends);
+
+        let tracer = run(true);
+        let blocks: Vec<&str> = tracer
+            .events
+            .iter()
+            .filter(|e| e.ph == "X")
+            .map(|e| e.name.as_str())
+            .collect();
+        assert!(!blocks.is_empty());
+        assert!(blocks.iter().all(|name| name.starts_with('@')));
+        assert!(tracer.events.iter().all(|e| e.dur.is_none_or(|d| d >= 0.0)));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&tracer.to_json()).unwrap();
+        assert_eq!(json["traceEvents"][0]["ph"], "B");
+    }
+}
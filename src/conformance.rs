@@ -0,0 +1,271 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::diagnostic::ErrorCode;
+use crate::flatten::{self, Ingest, read_bril_source};
+use crate::interp::interp_from_bytes;
+use crate::memfile::try_json_to_fbril_bytes;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The outcome of a single conformance test
+/// - `Unsupported`: the program uses an extension (e.g. floats or chars)
+///   which flat-bril doesn't implement, so it doesn't count towards the
+///   compliance percentage
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Error,
+    Unsupported,
+}
+
+/// The result of running a single test program
+/// (`message` explains why the test didn't pass)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TestResult {
+    pub path: PathBuf,
+    pub status: Status,
+    pub message: Option<String>,
+}
+
+/// The results of running every test in a corpus (see `run_conformance`),
+/// in the order given by `find_tests`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConformanceReport {
+    pub results: Vec<TestResult>,
+    pub passed: usize,
+    pub supported: usize,
+    pub compliance: f64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Collects the `.bril` files in `dir` & its subdirectories which have an
+/// expected output (i.e. a `.out` file with the same name), sorted by path
+fn find_tests(dir: &Path, tests: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("unable to read `{}`: {e}", dir.display()))?;
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(|e| format!("unable to read `{}`: {e}", dir.display()))?;
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            find_tests(&path, tests)?;
+        } else if path.extension().is_some_and(|ext| ext == "bril")
+            && path.with_extension("out").is_file()
+        {
+            tests.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Extracts the args passed to `main` from the `# ARGS: ...` comment in the
+/// Bril source `source` (the convention used by Bril's `turnt` tests),
+/// or no args if there's no such comment
+pub fn parse_args(source: &str) -> Vec<String> {
+    source
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix('#'))
+        .find_map(|comment| comment.trim().strip_prefix("ARGS:"))
+        .map(|args| args.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+/// Runs the test program at `path` (a `.bril` file) & compares its output
+/// with the `.out` file next to it.
+/// - If there's a `.json` file next to it, that's used as the JSON form of
+///   the program, otherwise the program is converted with `bril2json`
+///   (see `read_bril_source`)
+/// - Programs which `--strict` flattening rejects are assumed to use an
+///   extension that flat-bril doesn't implement (the upstream corpus only
+///   contains well-formed programs), as are programs which reach an
+///   extension instruction
+fn run_test(path: &Path) -> TestResult {
+    let result = |status, message| TestResult {
+        path: path.to_path_buf(),
+        status,
+        message,
+    };
+    let error = |msg: String| result(Status::Error, Some(msg));
+
+    let (source, expected) = match (
+        fs::read_to_string(path),
+        fs::read_to_string(path.with_extension("out")),
+    ) {
+        (Ok(source), Ok(expected)) => (source, expected),
+        (Err(e), _) | (_, Err(e)) => {
+            return error(format!("unable to read test: {e}"));
+        }
+    };
+    let json_path = path.with_extension("json");
+    let json = if json_path.is_file() {
+        read_bril_source(&json_path)
+    } else {
+        read_bril_source(path)
+    };
+    let json = match json {
+        Ok(json) => json,
+        Err(msg) => return error(msg),
+    };
+    let functions = json["functions"].as_array().map(Vec::as_slice);
+    for func in functions.unwrap_or_default() {
+        if let Err(msg) = flatten::check_func_json(func, Ingest::Strict) {
+            return result(Status::Unsupported, Some(msg));
+        }
+    }
+    let bytes = match try_json_to_fbril_bytes(&json) {
+        Ok(bytes) => bytes,
+        Err(msg) => return error(msg),
+    };
+
+    let args = parse_args(&source);
+    let arg_strs: Vec<&str> = args.iter().map(String::as_str).collect();
+    match interp_from_bytes(&bytes, arg_strs) {
+        Ok(output) if output.lines.iter().eq(expected.lines()) => {
+            result(Status::Pass, None)
+        }
+        Ok(output) => result(
+            Status::Fail,
+            Some(format!(
+                "expected {:?}, got {:?}",
+                expected,
+                output.lines.join("\n") + "\n"
+            )),
+        ),
+        Err(diagnostic) if diagnostic.code == ErrorCode::UnsupportedInstr => {
+            result(Status::Unsupported, Some(diagnostic.to_string()))
+        }
+        Err(diagnostic) => error(diagnostic.to_string()),
+    }
+}
+
+/// Runs every test in the Bril test corpus `dir` (e.g. `bril/test/interp`),
+/// i.e. every `.bril` file with a `.out` file next to it, passing the args
+/// given by its `# ARGS:` comment (see `run_test`)
+pub fn run_conformance(dir: &Path) -> Result<ConformanceReport, String> {
+    let mut tests = vec![];
+    find_tests(dir, &mut tests)?;
+    let results: Vec<TestResult> =
+        tests.iter().map(|path| run_test(path)).collect();
+
+    let count = |status| results.iter().filter(|r| r.status == status).count();
+    let passed = count(Status::Pass);
+    let supported = results.len() - count(Status::Unsupported);
+    let compliance = if supported == 0 {
+        0.0
+    } else {
+        passed as f64 / supported as f64 * 100.0
+    };
+    Ok(ConformanceReport {
+        results,
+        passed,
+        supported,
+        compliance,
+    })
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl ConformanceReport {
+    /// Lists the tests which failed (or couldn't be run), followed by a
+    /// summary with the compliance percentage
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for result in &self.results {
+            let status = match result.status {
+                Status::Fail => "FAIL",
+                Status::Error => "ERROR",
+                Status::Pass | Status::Unsupported => continue,
+            };
+            writeln!(text, "{status}  {}", result.path.display()).unwrap();
+            if let Some(message) = &result.message {
+                writeln!(text, "    {message}").unwrap();
+            }
+        }
+        write!(
+            text,
+            "{}/{} supported tests passed ({:.1}% compliance), \
+            {} unsupported",
+            self.passed,
+            self.supported,
+            self.compliance,
+            self.results.len() - self.supported
+        )
+        .unwrap();
+        text
+    }
+
+    /// Serializes the report as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("unable to serialize conformance report")
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod conformance_tests {
+    use std::fs;
+
+    use crate::conformance::{Status, parse_args, run_conformance};
+
+    /// Tests are found recursively & run with their `# ARGS`, and programs
+    /// using unimplemented extensions don't count towards the compliance
+    #[test]
+    fn test_run_conformance() {
+        let dir = std::env::temp_dir().join("flat-bril-conformance-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("core")).unwrap();
+        for ext in ["bril", "json", "out"] {
+            fs::copy(
+                format!("test/armstrong.{ext}"),
+                dir.join(format!("core/armstrong.{ext}")),
+            )
+            .unwrap();
+            fs::copy(
+                format!("test/add.{ext}"),
+                dir.join(format!("wrong.{ext}")),
+            )
+            .unwrap();
+        }
+        fs::write(dir.join("wrong.out"), "43\n").unwrap();
+        fs::write(dir.join("float.bril"), "# ARGS: 1.5\n").unwrap();
+        fs::write(dir.join("float.out"), "1.5\n").unwrap();
+        fs::write(
+            dir.join("float.json"),
+            r#"{"functions": [{"name": "main", "instrs": [
+                {"op": "const", "dest": "x", "type": "float", "value": 1.5},
+                {"op": "print", "args": ["x"]}
+            ]}]}"#,
+        )
+        .unwrap();
+
+        let report = run_conformance(&dir).unwrap();
+        let statuses: Vec<Status> =
+            report.results.iter().map(|r| r.status).collect();
+        assert_eq!(statuses, [Status::Pass, Status::Unsupported, Status::Fail]);
+        assert_eq!((report.passed, report.supported), (1, 2));
+        assert_eq!(report.compliance, 50.0);
+        assert!(report.to_text().contains("FAIL"));
+
+        assert_eq!(parse_args("# ARGS: 3 6\n@main {}"), ["3", "6"]);
+        assert!(parse_args("@main {}").is_empty());
+    }
+}
@@ -0,0 +1,238 @@
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::debugger::format_instr;
+use crate::interp::get_func_name;
+use crate::profile::Profile;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of instructions listed as hot spots in a `CoverageReport`
+pub const NUM_HOT_SPOTS: usize = 10;
+
+/// An instruction which appears in a `CoverageReport`
+/// - `count` is the no. of times it was executed
+/// - `share` is the fraction of all executed instructions that it accounts
+///   for (only meaningful for hot spots)
+/// - `instr` is the instruction in Bril's text format
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoveredInstr {
+    pub func: String,
+    pub pc: usize,
+    pub count: u64,
+    pub share: f64,
+    pub instr: String,
+}
+
+/// The coverage of a single function (labels aren't counted as instructions)
+/// - `never_executed` lists the instructions which were never executed
+///   (which is all of them if the function was never called)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FuncCoverage {
+    pub name: String,
+    pub executed: usize,
+    pub total: usize,
+    pub never_executed: Vec<CoveredInstr>,
+}
+
+/// A per-instruction coverage report of a run, computed from the counts in
+/// its `Profile`: which instructions were never executed, and the (up to
+/// `NUM_HOT_SPOTS`) instructions which were executed most often
+/// - `steps` is the dynamic instruction count (excluding labels)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CoverageReport {
+    pub functions: Vec<FuncCoverage>,
+    pub hot_spots: Vec<CoveredInstr>,
+    pub steps: u64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl CoverageReport {
+    /// Computes the coverage of `program` during the run recorded in
+    /// `profile` (`program` must be the program that was profiled,
+    /// before any superinstructions were fused)
+    pub fn new<P: IndexPair>(
+        program: &[InstrView<P>],
+        profile: &Profile,
+    ) -> Self {
+        let mut functions = vec![];
+        let mut executed_instrs = vec![];
+        for view in program {
+            let name = get_func_name(view);
+            let counts = profile.functions.get(name).map(|f| &f.counts);
+            let mut coverage = FuncCoverage {
+                name: name.to_string(),
+                executed: 0,
+                total: 0,
+                never_executed: vec![],
+            };
+            for (pc, instr) in view.instrs.iter().enumerate() {
+                let count = counts
+                    .and_then(|counts| counts.get(pc))
+                    .copied()
+                    .unwrap_or(0);
+                let covered = CoveredInstr {
+                    func: name.to_string(),
+                    pc,
+                    count,
+                    share: 0.0,
+                    instr: format_instr(view, instr),
+                };
+                coverage.total += 1;
+                if count == 0 {
+                    coverage.never_executed.push(covered);
+                } else {
+                    coverage.executed += 1;
+                    executed_instrs.push(covered);
+                }
+            }
+            functions.push(coverage);
+        }
+
+        let steps: u64 = executed_instrs.iter().map(|instr| instr.count).sum();
+        // (ties are broken by program order, since the sort is stable)
+        executed_instrs.sort_by_key(|instr| std::cmp::Reverse(instr.count));
+        executed_instrs.truncate(NUM_HOT_SPOTS);
+        for instr in &mut executed_instrs {
+            instr.share = instr.count as f64 / steps as f64;
+        }
+        Self {
+            functions,
+            hot_spots: executed_instrs,
+            steps,
+        }
+    }
+
+    /// Serializes the report as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("unable to serialize coverage report")
+    }
+
+    /// Renders the report as plain text
+    pub fn to_text(&self) -> String {
+        let percent = |executed: usize, total: usize| {
+            if total == 0 {
+                100.0
+            } else {
+                executed as f64 / total as f64 * 100.0
+            }
+        };
+        let executed = self.functions.iter().map(|f| f.executed).sum();
+        let total = self.functions.iter().map(|f| f.total).sum();
+        let mut text = format!(
+            "coverage: {executed}/{total} instructions executed ({:.1}%)\n",
+            percent(executed, total)
+        );
+        for func in &self.functions {
+            writeln!(
+                text,
+                "@{}: {}/{} ({:.1}%)",
+                func.name,
+                func.executed,
+                func.total,
+                percent(func.executed, func.total)
+            )
+            .unwrap();
+            for instr in &func.never_executed {
+                writeln!(
+                    text,
+                    "  never executed: {:>4}  {}",
+                    instr.pc, instr.instr
+                )
+                .unwrap();
+            }
+        }
+        writeln!(text, "hot spots ({} instructions executed):", self.steps)
+            .unwrap();
+        for instr in &self.hot_spots {
+            writeln!(
+                text,
+                "  {:>10} {:>5.1}%  @{} {:>4}  {}",
+                instr.count,
+                instr.share * 100.0,
+                instr.func,
+                instr.pc,
+                instr.instr
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod coverage_tests {
+    use serde_json::json;
+
+    use crate::coverage::CoverageReport;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::profile::Profiler;
+
+    /// Instructions on the branch that isn't taken & functions that aren't
+    /// called are never executed, & the loop body is the hottest code
+    #[test]
+    fn test_coverage_report() {
+        let json = json!({ "functions": [
+            {
+                "name": "main",
+                "instrs": [
+                    { "op": "const", "dest": "i", "type": "int", "value": 3 },
+                    { "op": "const", "dest": "one", "type": "int",
+                      "value": 1 },
+                    { "op": "const", "dest": "zero", "type": "int",
+                      "value": 0 },
+                    { "label": "loop" },
+                    { "op": "sub", "dest": "i", "type": "int",
+                      "args": ["i", "one"] },
+                    { "op": "gt", "dest": "c", "type": "bool",
+                      "args": ["i", "zero"] },
+                    { "op": "br", "labels": ["loop", "done"], "args": ["c"] },
+                    { "label": "done" },
+                    { "op": "lt", "dest": "c", "type": "bool",
+                      "args": ["i", "zero"] },
+                    { "op": "br", "labels": ["neg", "end"], "args": ["c"] },
+                    { "label": "neg" },
+                    { "op": "call", "funcs": ["unused"] },
+                    { "label": "end" },
+                    { "op": "print", "args": ["i"] }
+                ]
+            },
+            { "name": "unused", "instrs": [{ "op": "nop" }] }
+        ]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let mut profiler = Profiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec![], &mut ctx).expect("program runs");
+        let report = CoverageReport::new(program.views(), &profiler.profile());
+
+        let main = &report.functions[0];
+        assert_eq!((main.executed, main.total), (9, 10));
+        assert_eq!(main.never_executed[0].pc, 8);
+        assert_eq!(main.never_executed[0].instr, "call @unused;");
+        let unused = &report.functions[1];
+        assert_eq!((unused.executed, unused.total), (0, 1));
+        // 3 iterations of the loop + the other 6 instructions
+        assert_eq!(report.steps, 15);
+        let hot_pcs: Vec<usize> =
+            report.hot_spots.iter().map(|instr| instr.pc).collect();
+        assert_eq!(hot_pcs, [3, 4, 5, 0, 1, 2, 6, 7, 9]);
+        assert_eq!(report.hot_spots[0].share, 0.2);
+    }
+}
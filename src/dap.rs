@@ -0,0 +1,685 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use serde_json::{Value, json};
+
+use crate::debugger::{BreakTarget, Breakpoint};
+use crate::diagnostic::Diagnostic;
+use crate::flatten::read_bril_source;
+use crate::interp::{FuncTable, Limits};
+use crate::memfile::{AlignedBytes, get_flat_program, try_json_to_fbril_bytes};
+use crate::reverse_debugger::{DEFAULT_CHECKPOINT_INTERVAL, ReverseDebugger};
+use crate::scheduler::TaskStatus;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The ID of the only thread a Bril program has
+const THREAD_ID: u64 = 1;
+
+/// Maps the instructions of a flattened program to the lines of its source
+/// file, using the `pos` of each instruction in the JSON (i.e. the output of
+/// `bril2json -p`)
+/// - `lines[func][pc]` is the line of the instruction at `pc` in `func`
+/// - `locations[line]` is the first instruction on `line` (a label's line
+///   maps to the first instruction after it)
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    lines: HashMap<String, Vec<Option<u64>>>,
+    locations: BTreeMap<u64, (String, usize)>,
+}
+
+/// A connection to a debugger client (e.g. an editor), which exchanges
+/// Debug Adapter Protocol messages, i.e. JSON objects which are each
+/// preceded by a `Content-Length` header
+/// - `seq` is the sequence no. of the last message sent
+struct Connection<R, W> {
+    input: R,
+    output: W,
+    seq: u64,
+}
+
+/// The state of a debugging session, once a program has been launched
+/// - `source` is the DAP `Source` that the program's lines are in
+/// - `printed` is the no. of bytes of the program's output which have been
+///   sent to the client
+struct Session<'a, P: IndexPair> {
+    debugger: ReverseDebugger<'a, P>,
+    map: SourceMap,
+    source: Value,
+    stop_on_entry: bool,
+    printed: usize,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl SourceMap {
+    /// Builds the map for a JSON Bril program (instructions without a `pos`
+    /// have no line)
+    pub fn from_json(json: &Value) -> Self {
+        let mut map = Self::default();
+        let functions = json["functions"].as_array().into_iter().flatten();
+        for func in functions {
+            let name = func["name"].as_str().unwrap_or_default();
+            let mut lines = vec![];
+            let mut label_lines = vec![];
+            for instr in func["instrs"].as_array().into_iter().flatten() {
+                let line = instr["pos"]["row"].as_u64();
+                if instr.get("label").is_some() {
+                    label_lines.extend(line);
+                    continue;
+                }
+                for line in label_lines.drain(..).chain(line) {
+                    map.locations
+                        .entry(line)
+                        .or_insert_with(|| (name.to_string(), lines.len()));
+                }
+                lines.push(line);
+            }
+            map.lines.insert(name.to_string(), lines);
+        }
+        map
+    }
+
+    /// The line of the instruction at `pc` in `func` (if it has one)
+    pub fn line(&self, func: &str, pc: usize) -> Option<u64> {
+        self.lines.get(func)?.get(pc).copied().flatten()
+    }
+
+    /// Records the source line of the instruction at which `diagnostic`
+    /// occurred (if the map has one)
+    pub fn locate(&self, mut diagnostic: Diagnostic) -> Diagnostic {
+        if let (Some(func), Some(pc)) = (&diagnostic.func, diagnostic.pc) {
+            diagnostic.line = self.line(func, pc);
+        }
+        diagnostic
+    }
+
+    /// The function & PC of the first instruction on `line`
+    pub fn location(&self, line: u64) -> Option<(&str, usize)> {
+        self.locations
+            .get(&line)
+            .map(|(func, pc)| (func.as_str(), *pc))
+    }
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    /// Reads the next message (returns `None` once the input has ended)
+    fn read_message(&mut self) -> Result<Option<Value>, String> {
+        let mut len = None;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = self
+                .input
+                .read_line(&mut line)
+                .map_err(|e| format!("unable to read message: {e}"))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(n) = header.strip_prefix("Content-Length:") {
+                len = Some(n.trim().parse::<usize>().map_err(|_| {
+                    format!("invalid Content-Length `{}`", n.trim())
+                })?);
+            }
+        }
+        let len = len.ok_or("message has no Content-Length header")?;
+        let mut body = vec![0; len];
+        self.input
+            .read_exact(&mut body)
+            .map_err(|e| format!("unable to read message: {e}"))?;
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(|e| format!("invalid message: {e}"))
+    }
+
+    /// Sends `msg`, numbering it with the next sequence no.
+    fn send(&mut self, mut msg: Value) -> Result<(), String> {
+        self.seq += 1;
+        msg["seq"] = self.seq.into();
+        let body = msg.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{body}", body.len())
+            .and_then(|()| self.output.flush())
+            .map_err(|e| format!("unable to send message: {e}"))
+    }
+
+    /// Responds to `request`, successfully with `body` or with an error
+    fn respond(
+        &mut self,
+        request: &Value,
+        body: Result<Value, String>,
+    ) -> Result<(), String> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(msg) => response["message"] = msg.into(),
+        }
+        self.send(response)
+    }
+
+    /// Sends the event called `event`
+    fn event(&mut self, event: &str, body: Value) -> Result<(), String> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+/// The features of the protocol which are supported
+fn capabilities() -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsStepBack": true,
+        "supportsEvaluateForHovers": true,
+    })
+}
+
+/// Runs a Debug Adapter Protocol server which reads requests from `input` &
+/// writes responses & events to `output`, until the client disconnects.
+/// The program is loaded by the `launch` request, whose arguments are
+/// - `program`: a `.json` or `.bril` file (see `read_bril_source`), which
+///   is flattened in memory
+/// - `args` (optional): the arguments passed to the entry function
+/// - `entry` (optional): the function to call instead of `main`
+/// - `stopOnEntry` (optional): whether to stop before the first instruction
+/// - `source` (optional): the file the program's lines refer to, if it isn't
+///   `program` (e.g. the `.bril` file that a `.json` file came from)
+pub fn serve<R: BufRead, W: Write>(input: R, output: W) -> Result<(), String> {
+    let mut conn = Connection {
+        input,
+        output,
+        seq: 0,
+    };
+    while let Some(request) = conn.read_message()? {
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => conn.respond(&request, Ok(capabilities()))?,
+            "launch" => return launch(&mut conn, &request),
+            "disconnect" => return conn.respond(&request, Ok(json!({}))),
+            cmd => conn.respond(
+                &request,
+                Err(format!("`{cmd}` isn't supported before `launch`")),
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// Loads the program named in the `launch` request & debugs it until the
+/// client disconnects (or responds with an error if it can't be loaded)
+fn launch<R: BufRead, W: Write>(
+    conn: &mut Connection<R, W>,
+    request: &Value,
+) -> Result<(), String> {
+    let launch_args = &request["arguments"];
+    let Some(program_path) = launch_args["program"].as_str() else {
+        return conn.respond(request, Err("missing `program`".to_string()));
+    };
+    let entry = launch_args["entry"].as_str().unwrap_or("main");
+    let args: Vec<String> = launch_args["args"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|arg| arg.as_str().map_or_else(|| arg.to_string(), str::to_string))
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let loaded = read_bril_source(Path::new(program_path)).and_then(|json| {
+        Ok((SourceMap::from_json(&json), try_json_to_fbril_bytes(&json)?))
+    });
+    let (map, bytes) = match loaded {
+        Ok(loaded) => loaded,
+        Err(msg) => return conn.respond(request, Err(msg)),
+    };
+    let data = AlignedBytes::new(&bytes);
+    let program = match get_flat_program(&data) {
+        Ok(program) => program,
+        Err(msg) => return conn.respond(request, Err(msg)),
+    };
+    let funcs = FuncTable::new(program.views());
+    let debugger = match ReverseDebugger::new(
+        &funcs,
+        entry,
+        &args,
+        Limits::default(),
+        DEFAULT_CHECKPOINT_INTERVAL,
+    ) {
+        Ok(debugger) => debugger,
+        Err(e) => return conn.respond(request, Err(e.to_string())),
+    };
+
+    let source_path = launch_args["source"].as_str().unwrap_or(program_path);
+    let source_name = Path::new(source_path)
+        .file_name()
+        .map_or(source_path.into(), |name| name.to_string_lossy());
+    let mut session = Session {
+        debugger,
+        map,
+        source: json!({ "name": source_name, "path": source_path }),
+        stop_on_entry: launch_args["stopOnEntry"].as_bool().unwrap_or(false),
+        printed: 0,
+    };
+    conn.respond(request, Ok(json!({})))?;
+    conn.event("initialized", json!({}))?;
+    while let Some(request) = conn.read_message()? {
+        if !session.handle(conn, &request)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<P: IndexPair> Session<'_, P> {
+    /// Executes instructions until the call depth is at most `depth`,
+    /// stopping early at breakpoints (always executes at least one)
+    fn step_until_depth(&mut self, depth: usize) {
+        self.debugger.step();
+        while !self.debugger.task().is_finished()
+            && self.debugger.task().depth() > depth
+            && !self.debugger.at_breakpoint()
+        {
+            self.debugger.step();
+        }
+    }
+
+    /// Sends the program's new output to the client, followed by a
+    /// `stopped` event with `reason`, or the events which end the session if
+    /// the program has finished
+    fn report_stop<R: BufRead, W: Write>(
+        &mut self,
+        conn: &mut Connection<R, W>,
+        reason: &str,
+    ) -> Result<(), String> {
+        let new_out = &self.debugger.output()[self.printed..];
+        if !new_out.is_empty() {
+            let output = String::from_utf8_lossy(new_out).to_string();
+            self.printed += new_out.len();
+            conn.event(
+                "output",
+                json!({ "category": "stdout", "output": output }),
+            )?;
+        }
+        let (category, output, exit_code) = match &self.debugger.task().status {
+            TaskStatus::Runnable => {
+                let reason = if self.debugger.at_breakpoint() {
+                    "breakpoint"
+                } else {
+                    reason
+                };
+                return conn.event(
+                    "stopped",
+                    json!({
+                        "reason": reason,
+                        "threadId": THREAD_ID,
+                        "allThreadsStopped": true,
+                    }),
+                );
+            }
+            TaskStatus::Returned(value) => {
+                let output = value.map_or(String::new(), |v| format!("{v}\n"));
+                ("stdout", output, 0)
+            }
+            TaskStatus::Failed(e) => {
+                let e = self.map.locate(e.clone());
+                ("stderr", format!("error: {e}\n"), 1)
+            }
+        };
+        if !output.is_empty() {
+            conn.event(
+                "output",
+                json!({ "category": category, "output": output }),
+            )?;
+        }
+        conn.event("exited", json!({ "exitCode": exit_code }))?;
+        conn.event("terminated", json!({}))
+    }
+
+    /// Maps the lines in a `setBreakpoints` request to instructions,
+    /// replacing all of the existing breakpoints
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        let lines = args["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|bp| bp["line"].as_u64());
+        let mut breakpoints = vec![];
+        let mut results = vec![];
+        for line in lines {
+            match self.map.location(line) {
+                Some((func, pc)) => {
+                    breakpoints.push(Breakpoint {
+                        func: func.to_string(),
+                        target: BreakTarget::Pc(pc),
+                    });
+                    results.push(json!({ "verified": true, "line": line }));
+                }
+                None => results.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": "no instruction on this line",
+                })),
+            }
+        }
+        self.debugger.set_breakpoints(breakpoints);
+        json!({ "breakpoints": results })
+    }
+
+    /// The variables in the frame `frame_idx` of the call stack (outermost
+    /// first), or in the innermost frame if it's `None`
+    fn frame_env(
+        &self,
+        frame_idx: Option<u64>,
+    ) -> Result<BTreeMap<String, BrilValue>, String> {
+        let snapshot = self
+            .debugger
+            .task()
+            .snapshot()
+            .ok_or("the program has finished")?;
+        let frame = match frame_idx {
+            Some(idx) => snapshot.frames.get(idx as usize),
+            None => snapshot.frames.last(),
+        };
+        frame
+            .map(|frame| frame.env.clone())
+            .ok_or_else(|| "no such frame".to_string())
+    }
+
+    /// Handles a request from the client, returning false if the session
+    /// should end
+    fn handle<R: BufRead, W: Write>(
+        &mut self,
+        conn: &mut Connection<R, W>,
+        request: &Value,
+    ) -> Result<bool, String> {
+        let args = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or_default();
+        let finished = self.debugger.task().is_finished();
+        let body = match command {
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": "main" }]
+            })),
+            "stackTrace" => {
+                let frames = self
+                    .debugger
+                    .task()
+                    .snapshot()
+                    .map_or(vec![], |snapshot| snapshot.frames);
+                let stack_frames: Vec<Value> = frames
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(idx, frame)| {
+                        json!({
+                            "id": idx,
+                            "name": format!("@{}", frame.func),
+                            "source": self.source,
+                            "line": self.map.line(&frame.func, frame.pc)
+                                .unwrap_or(0),
+                            "column": 1,
+                        })
+                    })
+                    .collect();
+                Ok(json!({
+                    "stackFrames": stack_frames,
+                    "totalFrames": frames.len(),
+                }))
+            }
+            "scopes" => Ok(json!({ "scopes": [{
+                "name": "Locals",
+                "variablesReference": args["frameId"].as_u64().unwrap_or(0) + 1,
+                "expensive": false,
+            }]})),
+            "variables" => {
+                let reference = args["variablesReference"].as_u64();
+                self.frame_env(reference.map(|r| r.saturating_sub(1))).map(
+                    |env| {
+                        let variables: Vec<Value> = env
+                            .iter()
+                            .map(|(name, value)| {
+                                json!({
+                                    "name": name,
+                                    "value": value.to_string(),
+                                    "type": value.get_type().to_string(),
+                                    "variablesReference": 0,
+                                })
+                            })
+                            .collect();
+                        json!({ "variables": variables })
+                    },
+                )
+            }
+            "evaluate" => {
+                let expr = args["expression"].as_str().unwrap_or_default();
+                self.frame_env(args["frameId"].as_u64()).and_then(|env| {
+                    env.get(expr.trim())
+                        .map(|value| {
+                            json!({
+                                "result": value.to_string(),
+                                "variablesReference": 0,
+                            })
+                        })
+                        .ok_or_else(|| format!("`{expr}` is undefined"))
+                })
+            }
+            "configurationDone" | "pause" => Ok(json!({})),
+            "continue" if !finished => {
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue"
+                if !finished || command == "stepBack" =>
+            {
+                Ok(json!({}))
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                Err("the program has finished".to_string())
+            }
+            "disconnect" | "terminate" => {
+                conn.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            _ => Err(format!("`{command}` isn't supported")),
+        };
+        let ok = body.is_ok();
+        conn.respond(request, body)?;
+        if !ok {
+            return Ok(true);
+        }
+
+        // Requests which move to another state report where they stopped
+        // once they've been responded to
+        let depth = self.debugger.task().depth();
+        let reason = match command {
+            "configurationDone" => {
+                if !self.stop_on_entry && !self.debugger.at_breakpoint() {
+                    self.debugger.continue_to_breakpoint();
+                }
+                "entry"
+            }
+            "pause" => "pause",
+            "continue" => {
+                self.debugger.continue_to_breakpoint();
+                "breakpoint"
+            }
+            "next" => {
+                self.step_until_depth(depth);
+                "step"
+            }
+            "stepIn" => {
+                self.debugger.step();
+                "step"
+            }
+            "stepOut" => {
+                self.step_until_depth(depth.saturating_sub(1));
+                "step"
+            }
+            "stepBack" => {
+                let steps = self.debugger.steps();
+                self.debugger.goto(steps.saturating_sub(1));
+                "step"
+            }
+            "reverseContinue" => {
+                self.debugger.reverse_to_breakpoint();
+                "step"
+            }
+            _ => return Ok(true),
+        };
+        self.report_stop(conn, reason)?;
+        Ok(true)
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod dap_tests {
+    use std::io::Cursor;
+
+    use serde_json::{Value, json};
+
+    use crate::dap::{SourceMap, serve};
+    use crate::diagnostic::{Diagnostic, ErrorCode};
+
+    /// A client can launch a program, stop at a breakpoint on a source
+    /// line, inspect variables, step backwards & run to the end
+    #[test]
+    fn test_dap() {
+        let pos = |row: u64| json!({ "row": row, "col": 3 });
+        let program = json!({ "functions": [
+            {
+                "name": "main",
+                "pos": pos(1),
+                "instrs": [
+                    { "op": "const", "dest": "a", "type": "int",
+                      "value": 4, "pos": pos(2) },
+                    { "op": "call", "dest": "b", "type": "int",
+                      "funcs": ["double"], "args": ["a"], "pos": pos(3) },
+                    { "op": "print", "args": ["b"], "pos": pos(4) }
+                ]
+            },
+            {
+                "name": "double",
+                "args": [{ "name": "x", "type": "int" }],
+                "type": "int",
+                "pos": pos(6),
+                "instrs": [
+                    { "label": "start", "pos": pos(7) },
+                    { "op": "add", "dest": "y", "type": "int",
+                      "args": ["x", "x"], "pos": pos(8) },
+                    { "op": "ret", "args": ["y"], "pos": pos(9) }
+                ]
+            }
+        ]});
+        let path = std::env::temp_dir().join("flat-bril-dap-test.json");
+        std::fs::write(&path, program.to_string()).unwrap();
+
+        let requests = [
+            ("initialize", json!({})),
+            ("launch", json!({ "program": path })),
+            (
+                "setBreakpoints",
+                json!({ "breakpoints": [
+                    { "line": 7 }, { "line": 5 }
+                ]}),
+            ),
+            ("configurationDone", json!({})),
+            ("stackTrace", json!({ "threadId": 1 })),
+            ("variables", json!({ "variablesReference": 2 })),
+            ("stepBack", json!({ "threadId": 1 })),
+            ("evaluate", json!({ "expression": "a", "frameId": 0 })),
+            ("continue", json!({ "threadId": 1 })),
+            ("continue", json!({ "threadId": 1 })),
+        ];
+        let mut input = String::new();
+        for (seq, (command, arguments)) in requests.iter().enumerate() {
+            let body = json!({
+                "seq": seq + 1,
+                "type": "request",
+                "command": command,
+                "arguments": arguments,
+            })
+            .to_string();
+            input += &format!("Content-Length: {}\r\n\r\n{body}", body.len());
+        }
+        let mut output = vec![];
+        serve(Cursor::new(input), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let messages: Vec<Value> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|msg| {
+                let (_, body) = msg.split_once("\r\n\r\n").unwrap();
+                serde_json::from_str(body).unwrap()
+            })
+            .collect();
+        let response = |command: &str| -> Vec<&Value> {
+            messages
+                .iter()
+                .filter(|msg| msg["command"] == command)
+                .collect()
+        };
+        let events: Vec<&str> = messages
+            .iter()
+            .filter_map(|msg| msg["event"].as_str())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "initialized",
+                "stopped",
+                "stopped",
+                "stopped",
+                "output",
+                "exited",
+                "terminated"
+            ]
+        );
+
+        // (the label on line 7 maps to the `add` after it)
+        let breakpoints = &response("setBreakpoints")[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+        let frames = &response("stackTrace")[0]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "@double");
+        assert_eq!(frames[0]["line"], 8);
+        assert_eq!(frames[1]["line"], 3);
+        let variables = &response("variables")[0]["body"]["variables"];
+        assert_eq!(variables[0]["name"], "x");
+        assert_eq!(variables[0]["value"], "4");
+        assert_eq!(response("evaluate")[0]["body"]["result"], "4");
+
+        // (stepping back from the breakpoint goes back to the `call`, so
+        // the next `continue` stops at the breakpoint again)
+        let output = messages.iter().find(|msg| msg["event"] == "output");
+        assert_eq!(output.unwrap()["body"]["output"], "8\n");
+        assert!(
+            response("continue")
+                .iter()
+                .all(|msg| msg["success"] == true)
+        );
+
+        // (errors are located at the line of the instruction they occur at)
+        let error = Diagnostic::new(ErrorCode::TypeError, "oops");
+        let map = SourceMap::from_json(&program);
+        assert_eq!(
+            map.locate(error.clone().located("double", 0)).line,
+            Some(8)
+        );
+        assert_eq!(map.locate(error).line, None);
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::interp::{
+    Environment, FuncTable, get_args, get_ext_json, get_func, get_func_name,
+    get_label_name, get_labels_vec, get_var,
+};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The location a breakpoint refers to within a function
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakTarget {
+    Pc(usize),
+    Label(String),
+}
+
+/// A breakpoint at `target` in the Bril function `func`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub func: String,
+    pub target: BreakTarget,
+}
+
+/// The breakpoints of a debugger, numbered in the order they're set (a
+/// breakpoint keeps its number when others are deleted)
+/// - `locations` maps each function of the program being debugged to its
+///   no. of instructions & the labels which are followed by an instruction,
+///   i.e. the targets which a breakpoint in it can stop at
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Breakpoints {
+    locations: HashMap<String, (usize, HashSet<String>)>,
+    set: BTreeMap<usize, Breakpoint>,
+    next_id: usize,
+}
+
+/// What the debugger should do when it is next consulted
+/// - `Step`: stop before the next instruction (in any function)
+/// - `Next`: stop before the next instruction whose call depth is
+///   at most `depth` (i.e. step over calls)
+/// - `Continue`: only stop at breakpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Step,
+    Next { depth: usize },
+    Continue,
+}
+
+/// An interactive debugger, which is an `Observer` that the interpreter
+/// consults before every instruction (see `Debugger::on_instr`).
+/// - Commands are read from `stdin`, and all debugger output goes to `stderr`
+///   (so that `stdout` only contains the program's output)
+/// - `frames` is the call stack, i.e. the function name and PC of each
+///   active call (the innermost call is at the end)
+pub struct Debugger {
+    breakpoints: Breakpoints,
+    mode: Mode,
+    frames: Vec<(String, usize)>,
+}
+
+/// Help text displayed by the `help` command
+const HELP: &str = "\
+commands:
+  s, step             execute one instruction (stepping into calls)
+  n, next             execute one instruction (stepping over calls)
+  c, continue         run until the next breakpoint
+  b, break [@f] TGT   set a breakpoint, where TGT is a PC or a `.label`
+                      (in function `@f`, or the current function)
+  d, delete N         delete breakpoint no. N
+  i, info             list all breakpoints
+  p, print VAR        print the value of a variable
+  e, env              print the entire environment
+  l, list [N]         list the N instructions around the current PC
+  bt, backtrace       print the call stack
+  h, help             print this message
+  q, quit             stop the program";
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Renders a single flat instruction in Bril's text format
+pub fn format_instr<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    instr: &FlatInstr<P>,
+) -> String {
+    if let InstrKind::Extension = instr.get_instr_kind() {
+        return get_ext_json(instr_view, instr).to_string();
+    }
+
+    let op = Opcode::op_idx_to_op_str(instr.op() as usize);
+    let mut instr_str = String::new();
+    if let Some((offset, len)) = instr.dest.get() {
+        instr_str.push_str(get_var(instr_view, offset, len));
+        if let Some(ty) = Option::<Type>::from(instr.ty) {
+            instr_str.push_str(&format!(": {ty}"));
+        }
+        instr_str.push_str(" = ");
+    }
+    instr_str.push_str(&op);
+
+    if let Some(value) = Option::<BrilValue>::from(instr.value) {
+        instr_str.push_str(&format!(" {value}"));
+    }
+    if let Some((offset, len)) = instr.funcs.get() {
+        instr_str.push_str(&format!(" @{}", get_func(instr_view, offset, len)));
+    }
+    if let Some((offset, len)) = instr.args.get() {
+        for arg in get_args(instr_view, offset, len) {
+            instr_str.push_str(&format!(" {arg}"));
+        }
+    }
+    if let Some((offset, len)) = instr.instr_labels.get() {
+        for label in get_labels_vec(instr_view, offset, len) {
+            instr_str.push_str(&format!(" .{label}"));
+        }
+    }
+    instr_str.push(';');
+    instr_str
+}
+
+/// Parses the arguments to the `break` command,
+/// using `current_func` if no function is specified
+pub fn parse_breakpoint(
+    args: &[&str],
+    current_func: &str,
+) -> Result<Breakpoint, String> {
+    let (func, target) = match args {
+        [func, target] if func.starts_with('@') => (&func[1..], *target),
+        [target] => (current_func, *target),
+        _ => return Err("usage: break [@func] (PC | .label)".to_string()),
+    };
+    let target = if let Some(label) = target.strip_prefix('.') {
+        BreakTarget::Label(label.to_string())
+    } else {
+        let pc = target
+            .parse::<usize>()
+            .map_err(|_| format!("`{target}` is not a PC or a .label"))?;
+        BreakTarget::Pc(pc)
+    };
+    Ok(Breakpoint {
+        func: func.to_string(),
+        target,
+    })
+}
+
+impl Breakpoint {
+    /// Determines if the breakpoint is at `pc` in `instr_view`
+    /// (a breakpoint on a label stops at the first instruction after it)
+    pub fn matches<P: IndexPair>(
+        &self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+    ) -> bool {
+        self.func == get_func_name(instr_view)
+            && match &self.target {
+                BreakTarget::Pc(bp_pc) => *bp_pc == pc,
+                BreakTarget::Label(label) => {
+                    instr_view.labels_at(pc).iter().any(|flat_label| {
+                        let (offset, len) = flat_label.label_idxes.idxes();
+                        get_label_name(instr_view, offset, len) == label
+                    })
+                }
+            }
+    }
+}
+
+impl Breakpoints {
+    /// Creates an empty set of breakpoints for the program whose functions
+    /// are `funcs` (functions which can't be loaded can't have breakpoints)
+    pub fn new<P: IndexPair>(funcs: &FuncTable<'_, P>) -> Self {
+        let locations = funcs
+            .names()
+            .filter_map(|name| {
+                let (view, _) = funcs.get(name).ok()??;
+                let num_instrs = view.instrs.len();
+                let labels = view
+                    .label_table
+                    .iter()
+                    .filter(|label| label.pc < num_instrs)
+                    .map(|label| {
+                        let (offset, len) = label.label_idxes.idxes();
+                        get_label_name(view, offset, len).to_string()
+                    })
+                    .collect();
+                Some((name.to_string(), (num_instrs, labels)))
+            })
+            .collect();
+        Self {
+            locations,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the breakpoint `bp`, returning its number (or an error if its
+    /// function, PC or label doesn't exist, so that it could never stop)
+    pub fn add(&mut self, bp: Breakpoint) -> Result<usize, String> {
+        let Some((num_instrs, labels)) = self.locations.get(&bp.func) else {
+            return Err(format!("no function named @{}", bp.func));
+        };
+        match &bp.target {
+            BreakTarget::Pc(pc) if pc >= num_instrs => {
+                return Err(format!(
+                    "@{} has no PC {pc} (it has {num_instrs} instructions)",
+                    bp.func
+                ));
+            }
+            BreakTarget::Label(label) if !labels.contains(label) => {
+                return Err(format!(
+                    "@{} has no label .{label} before an instruction",
+                    bp.func
+                ));
+            }
+            _ => (),
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.set.insert(id, bp);
+        Ok(id)
+    }
+
+    /// Deletes breakpoint no. `id` (returns `false` if there's no such
+    /// breakpoint)
+    pub fn delete(&mut self, id: usize) -> bool {
+        self.set.remove(&id).is_some()
+    }
+
+    /// Replaces all of the breakpoints with `breakpoints`, which are known
+    /// to be at existing instructions (e.g. ones mapped from source lines)
+    pub fn replace(&mut self, breakpoints: Vec<Breakpoint>) {
+        self.set.clear();
+        for bp in breakpoints {
+            self.set.insert(self.next_id, bp);
+            self.next_id += 1;
+        }
+    }
+
+    /// The breakpoints along with their numbers, in the order they were set
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.set.iter().map(|(&id, bp)| (id, bp))
+    }
+
+    /// Determines if one of the breakpoints is at `pc` in `instr_view`
+    pub fn any_matches<P: IndexPair>(
+        &self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+    ) -> bool {
+        self.set.values().any(|bp| bp.matches(instr_view, pc))
+    }
+}
+
+/// Writes the instructions within `radius` of `pc` in `instr_view` (& the
+/// labels before them) to `out`, marking the one at `pc` with `=>`
+pub fn write_listing<P: IndexPair>(
+    out: &mut impl Write,
+    instr_view: &InstrView<P>,
+    pc: usize,
+    radius: usize,
+) -> io::Result<()> {
+    let start = pc.saturating_sub(radius);
+    let end = (pc + radius + 1).min(instr_view.instrs.len());
+    for i in start..end {
+        for label in instr_view.labels_at(i) {
+            let (offset, len) = label.label_idxes.idxes();
+            let name = get_label_name(instr_view, offset, len);
+            writeln!(out, "         .{name}:")?;
+        }
+        let marker = if i == pc { "=>" } else { "  " };
+        writeln!(
+            out,
+            "{marker} {i:>4}  {}",
+            format_instr(instr_view, &instr_view.instrs[i])
+        )?;
+    }
+    Ok(())
+}
+
+impl Debugger {
+    /// Creates a debugger for the program whose functions are `funcs`,
+    /// which stops before the first instruction
+    pub fn new<P: IndexPair>(funcs: &FuncTable<'_, P>) -> Self {
+        Self {
+            breakpoints: Breakpoints::new(funcs),
+            mode: Mode::Step,
+            frames: vec![],
+        }
+    }
+}
+
+impl Observer for Debugger {
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        self.frames.push((get_func_name(callee).to_string(), 0));
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        _callee: &InstrView<P>,
+        _value: Option<BrilValue>,
+    ) {
+        self.frames.pop();
+    }
+
+    /// If the debugger decides to stop before the instruction at `pc`,
+    /// it repeatedly prompts the user for commands until execution is resumed
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        // Keep track of the current PC in the innermost call
+        let func = get_func_name(instr_view);
+        let call_depth = self.frames.len() - 1;
+        self.frames[call_depth].1 = pc;
+
+        let should_stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next { depth } => call_depth <= depth,
+            Mode::Continue => false,
+        } || self.breakpoints.any_matches(instr_view, pc);
+        if !should_stop {
+            return;
+        }
+
+        // Make sure the program's output so far is visible
+        // before prompting the user
+        io::stdout().flush().expect("unable to flush stdout");
+        eprintln!("@{func}:{pc}  {}", format_instr(instr_view, instr));
+
+        let stdin = io::stdin();
+        let mut line = String::new();
+        loop {
+            eprint!("(fbril-dbg) ");
+            io::stderr().flush().expect("unable to flush stderr");
+            line.clear();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                // EOF: treat this the same as `quit`
+                std::process::exit(0);
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((cmd, args)) = words.split_first() else {
+                continue;
+            };
+            match *cmd {
+                "s" | "step" => {
+                    self.mode = Mode::Step;
+                    return;
+                }
+                "n" | "next" => {
+                    self.mode = Mode::Next { depth: call_depth };
+                    return;
+                }
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return;
+                }
+                "b" | "break" => match parse_breakpoint(args, func)
+                    .and_then(|bp| self.breakpoints.add(bp))
+                {
+                    Ok(id) => eprintln!("breakpoint {id} set"),
+                    Err(msg) => eprintln!("{msg}"),
+                },
+                "d" | "delete" => {
+                    match args.first().and_then(|n| n.parse::<usize>().ok()) {
+                        Some(n) if self.breakpoints.delete(n) => (),
+                        _ => eprintln!("usage: delete N (see `info`)"),
+                    }
+                }
+                "i" | "info" => {
+                    for (id, bp) in self.breakpoints.iter() {
+                        eprintln!("{id}: {bp}");
+                    }
+                }
+                "p" | "print" => {
+                    for var in args {
+                        match env.get(var) {
+                            Some(value) => eprintln!("{var} = {value}"),
+                            None => eprintln!("{var} is undefined"),
+                        }
+                    }
+                }
+                "e" | "env" => {
+                    let mut vars: Vec<(&&str, &BrilValue)> =
+                        env.iter().collect();
+                    vars.sort_by_key(|(var, _)| **var);
+                    for (var, value) in vars {
+                        eprintln!("{var}: {} = {value}", value.get_type());
+                    }
+                }
+                "l" | "list" => {
+                    let radius = args
+                        .first()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .unwrap_or(5);
+                    write_listing(&mut io::stderr(), instr_view, pc, radius)
+                        .expect("unable to write to stderr");
+                }
+                "bt" | "backtrace" => {
+                    for (depth, (func, pc)) in
+                        self.frames.iter().enumerate().rev()
+                    {
+                        eprintln!("#{depth} @{func}:{pc}");
+                    }
+                }
+                "h" | "help" => eprintln!("{HELP}"),
+                "q" | "quit" => std::process::exit(0),
+                _ => eprintln!("unknown command `{cmd}` (try `help`)"),
+            }
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.target {
+            BreakTarget::Pc(pc) => write!(f, "@{} {pc}", self.func),
+            BreakTarget::Label(label) => write!(f, "@{} .{label}", self.func),
+        }
+    }
+}
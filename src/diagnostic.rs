@@ -0,0 +1,164 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The kind of error that occurred while loading / interpreting a program
+/// (serialized in `snake_case`, e.g. `"undefined_variable"`)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The `.fbril` file couldn't be opened, or is truncated / corrupt
+    MalformedFile,
+    /// An instruction has the wrong no. of args / labels, or a bad opcode
+    MalformedInstr,
+    UndefinedVariable,
+    UndefinedFunction,
+    UndefinedLabel,
+    /// An instruction's operands (or a call's arguments) are ill-typed
+    TypeError,
+    /// A call used as a value op returned without a value
+    MissingReturnValue,
+    DivisionByZero,
+    /// A command-line argument to `main` couldn't be parsed
+    BadArgument,
+    StepLimit,
+    CallDepthLimit,
+    Timeout,
+    /// The program's output couldn't be written
+    Io,
+    /// A replayed run didn't execute the same instructions as its trace
+    ReplayMismatch,
+    /// An extension instruction (which the interpreter can't execute)
+    /// was reached
+    UnsupportedInstr,
+    /// A host function (see `host::HostFns`) returned an error
+    HostError,
+}
+
+/// An error reported by flat-bril, along with where it occurred
+/// - `func` & `pc` identify the instruction that failed
+///   (these are `None` for errors that aren't tied to an instruction,
+///   e.g. when the file can't be loaded)
+/// - `line` is the line of that instruction in the program's source file,
+///   if it's known (see `dap::SourceMap::locate`; omitted from the JSON
+///   otherwise)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub code: ErrorCode,
+    pub func: Option<String>,
+    pub pc: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl ErrorCode {
+    /// Whether the interpreter can carry on past an error of this kind
+    /// (see `InterpContext::keep_going`): i.e. errors caused by a single
+    /// instruction's operands, rather than by a malformed program or by
+    /// exceeding a limit
+    pub fn is_recoverable(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::UndefinedVariable
+                | ErrorCode::UndefinedFunction
+                | ErrorCode::TypeError
+                | ErrorCode::MissingReturnValue
+                | ErrorCode::DivisionByZero
+                | ErrorCode::UnsupportedInstr
+                | ErrorCode::HostError
+        )
+    }
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic that isn't (yet) tied to an instruction
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            func: None,
+            pc: None,
+            line: None,
+            message: message.into(),
+        }
+    }
+
+    /// Records that the error occurred at `pc` in the function `func`
+    /// (unless a location has already been recorded, since errors are
+    /// located by the innermost call in which they occur)
+    pub fn located(mut self, func: &str, pc: usize) -> Self {
+        if self.func.is_none() {
+            self.func = Some(func.to_string());
+            self.pc = Some(pc);
+        }
+        self
+    }
+
+    /// Renders the diagnostic as a single-line JSON record
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("unable to serialize diagnostic")
+    }
+}
+
+/// Extracts the message from the payload of a caught panic
+pub fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "interpreter panicked".to_string()
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        match (&self.func, self.pc, self.line) {
+            (Some(func), Some(pc), Some(line)) => {
+                write!(f, " (at @{func}:{pc}, line {line})")
+            }
+            (Some(func), Some(pc), None) => write!(f, " (at @{func}:{pc})"),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod diagnostic_tests {
+    use super::*;
+
+    /// Errors keep the location of the innermost call they occurred in,
+    /// and are serialized as flat JSON records
+    #[test]
+    fn test_located_diagnostic_to_json() {
+        let diagnostic = Diagnostic::new(ErrorCode::DivisionByZero, "oops")
+            .located("callee", 3)
+            .located("main", 7);
+        assert_eq!(diagnostic.to_string(), "oops (at @callee:3)");
+        assert_eq!(
+            diagnostic.to_json(),
+            r#"{"code":"division_by_zero","func":"callee","pc":3,"message":"oops"}"#
+        );
+
+        // (the source line is only shown once it's known)
+        let diagnostic = Diagnostic {
+            line: Some(12),
+            ..diagnostic
+        };
+        assert_eq!(diagnostic.to_string(), "oops (at @callee:3, line 12)");
+        assert!(diagnostic.to_json().contains(r#""line":12"#));
+    }
+}
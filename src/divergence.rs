@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+
+use crate::debugger::format_instr;
+use crate::interp::{
+    Environment, InterpContext, Limits, get_arg_pairs, get_func_name, get_var,
+    interp_program,
+};
+use crate::memfile::ProgramViews;
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The max no. of instructions each program is run for when
+/// `Limits::max_steps` isn't set (since every step is recorded)
+pub const DEFAULT_MAX_STEPS: u64 = 1_000_000;
+
+/// A (non-label) instruction executed by a program
+/// - `env_hash` is a hash of the environment of the current function
+///   just before the instruction is executed (see `hash_env`)
+/// - `printed` is the line printed by the instruction (if it's a `print`)
+#[derive(Debug, Clone, PartialEq)]
+struct Step {
+    env_hash: u64,
+    printed: Option<String>,
+}
+
+/// An `Observer` which records every step of a run
+/// - If `detail_step` is set, the instructions executed at that step & the
+///   one before it are rendered into `locations`, & the environment just
+///   before that step is copied into `env` (sorted by variable name)
+#[derive(Default)]
+struct StepRecorder {
+    steps: Vec<Step>,
+    detail_step: Option<usize>,
+    locations: HashMap<usize, Location>,
+    env: Vec<(String, BrilValue)>,
+}
+
+/// A run of a program, as recorded by a `StepRecorder`
+/// (`outcome` holds the error message if interpretation failed)
+struct Recording {
+    recorder: StepRecorder,
+    outcome: Result<(), String>,
+}
+
+/// An instruction executed by a program: `instr` is the instruction at
+/// `pc` in `func`, rendered in Bril's text format
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub func: String,
+    pub pc: usize,
+    pub instr: String,
+}
+
+/// A variable whose value differs between the two runs
+/// (`None` means the variable is undefined in that run)
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarDiff {
+    pub var: String,
+    pub old: Option<BrilValue>,
+    pub new: Option<BrilValue>,
+}
+
+/// How the two runs differ at the step where they diverge
+/// - `Environment`: the environments differ after the instruction
+///   is executed (in the variables listed)
+/// - `Output`: the instruction prints different lines in each run (`None`
+///   means it doesn't print anything in that run)
+/// - `Stopped`: one run stops (returns or fails) at this step, while the
+///   other one continues
+/// - `Outcome`: both runs execute the same steps, but only one of them
+///   fails (or they fail differently)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Difference {
+    Environment(Vec<VarDiff>),
+    Output {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Stopped,
+    Outcome,
+}
+
+/// The first point at which the two runs differ
+/// - `step` is the index of the instruction at which they diverge (counting
+///   the non-label instructions executed so far, from 0)
+/// - `old` & `new` are the instructions each run executed at `step`
+///   (`None` if that run had already stopped)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub step: usize,
+    pub difference: Difference,
+    pub old: Option<Location>,
+    pub new: Option<Location>,
+}
+
+/// The result of running two programs in lockstep with `find_divergence`
+/// - `steps` is the no. of steps at which both runs agree
+/// - `old_outcome` & `new_outcome` hold the error messages of the runs which
+///   failed (e.g. because `max_steps` was exceeded)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DivergenceReport {
+    pub steps: usize,
+    pub divergence: Option<Divergence>,
+    pub old_outcome: Result<(), String>,
+    pub new_outcome: Result<(), String>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Hashes the (variable, value) pairs in `env`, independently of the order
+/// in which the `HashMap` stores them
+fn hash_env(env: &Environment) -> u64 {
+    env.iter().fold(0u64, |acc, (var, value)| {
+        let mut hasher = DefaultHasher::new();
+        var.hash(&mut hasher);
+        match value {
+            BrilValue::IntVal(n) => (0u8, *n).hash(&mut hasher),
+            BrilValue::BoolVal(b) => (1u8, bool::from(*b)).hash(&mut hasher),
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(ext) => (2u8, ext).hash(&mut hasher),
+        }
+        acc.wrapping_add(hasher.finish())
+    })
+}
+
+/// The line that the `print` instruction `instr` will print given `env`
+/// (`None` if one of its args is undefined, in which case it fails)
+fn printed_line<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    instr: &FlatInstr<P>,
+    env: &Environment,
+) -> Option<String> {
+    let values = get_arg_pairs(instr_view, instr.args)
+        .iter()
+        .map(|pair| {
+            let (offset, len) = pair.idxes();
+            env.get(get_var(instr_view, offset, len))
+                .map(BrilValue::to_string)
+        })
+        .collect::<Option<Vec<String>>>()?;
+    Some(values.join(" "))
+}
+
+impl Observer for StepRecorder {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        let step = self.steps.len();
+        if let Some(detail_step) = self.detail_step {
+            if step + 1 == detail_step || step == detail_step {
+                let location = Location {
+                    func: get_func_name(instr_view).to_string(),
+                    pc,
+                    instr: format_instr(instr_view, instr),
+                };
+                self.locations.insert(step, location);
+            }
+            if step == detail_step {
+                self.env = env
+                    .iter()
+                    .map(|(var, value)| (var.to_string(), *value))
+                    .collect();
+                self.env.sort_by(|a, b| a.0.cmp(&b.0));
+            }
+        }
+        let printed = match Opcode::try_from(instr.op()) {
+            Ok(Opcode::Print) => printed_line(instr_view, instr, env),
+            _ => None,
+        };
+        self.steps.push(Step {
+            env_hash: hash_env(env),
+            printed,
+        });
+    }
+}
+
+/// Runs `program` with the args `args` to `main`, recording every step
+/// (see `StepRecorder` for what `detail_step` does)
+fn record(
+    program: &ProgramViews,
+    args: &[&str],
+    limits: Limits,
+    detail_step: Option<usize>,
+) -> Recording {
+    let recorder = StepRecorder {
+        detail_step,
+        ..StepRecorder::default()
+    };
+    // (the printed lines are recorded by the observer instead)
+    let mut ctx =
+        InterpContext::new(io::sink(), limits).with_observer(recorder);
+    let result = match program {
+        ProgramViews::Narrow(program) => {
+            interp_program(program, args.to_vec(), &mut ctx)
+        }
+        ProgramViews::Wide(program) => {
+            interp_program(program, args.to_vec(), &mut ctx)
+        }
+        ProgramViews::Compact(program) => {
+            interp_program(program, args.to_vec(), &mut ctx)
+        }
+    };
+    Recording {
+        recorder: ctx.observer,
+        outcome: result.map_err(|diagnostic| diagnostic.to_string()),
+    }
+}
+
+/// Lists the variables whose values differ between the environments
+/// `old` & `new` (which are sorted by variable name)
+fn diff_envs(
+    old: &[(String, BrilValue)],
+    new: &[(String, BrilValue)],
+) -> Vec<VarDiff> {
+    let lookup = |env: &[(String, BrilValue)], var: &str| {
+        env.iter()
+            .find(|(name, _)| name == var)
+            .map(|(_, value)| *value)
+    };
+    let mut vars: Vec<&String> =
+        old.iter().chain(new).map(|(var, _)| var).collect();
+    vars.sort();
+    vars.dedup();
+    vars.into_iter()
+        .filter_map(|var| {
+            let (old, new) = (lookup(old, var), lookup(new, var));
+            (old != new).then(|| VarDiff {
+                var: var.clone(),
+                old,
+                new,
+            })
+        })
+        .collect()
+}
+
+/// Runs the programs `old` & `new` in lockstep on the same `args` to `main`,
+/// comparing them after every (non-label) instruction, & reports the first
+/// instruction at which their environments or output differ.
+/// - The two programs are expected to execute the same no. of instructions,
+///   as with optimization passes that rewrite instructions in place (e.g.
+///   `peephole`): after a pass that adds or removes instructions, the
+///   divergence found is just the first instruction it changed.
+/// - Each run is limited to `DEFAULT_MAX_STEPS` instructions unless
+///   `limits.max_steps` is set.
+pub fn find_divergence(
+    old: &ProgramViews,
+    new: &ProgramViews,
+    args: &[&str],
+    limits: Limits,
+) -> DivergenceReport {
+    let limits = Limits {
+        max_steps: limits.max_steps.or(Some(DEFAULT_MAX_STEPS)),
+        ..limits
+    };
+    let old_run = record(old, args, limits, None);
+    let new_run = record(new, args, limits, None);
+    let (old_steps, new_steps) =
+        (&old_run.recorder.steps, &new_run.recorder.steps);
+
+    // The first step at which the two runs differ, & whether it's the
+    // environment before that step which differs
+    let mut first_difference = None;
+    for step in 0..old_steps.len().max(new_steps.len()) {
+        match (old_steps.get(step), new_steps.get(step)) {
+            (Some(old_step), Some(new_step)) => {
+                if old_step.env_hash != new_step.env_hash {
+                    first_difference = Some((step, true));
+                } else if old_step.printed != new_step.printed {
+                    first_difference = Some((step, false));
+                } else {
+                    continue;
+                }
+            }
+            _ => first_difference = Some((step, false)),
+        }
+        break;
+    }
+
+    let mut report = DivergenceReport {
+        steps: old_steps.len().min(new_steps.len()),
+        divergence: None,
+        old_outcome: old_run.outcome.clone(),
+        new_outcome: new_run.outcome.clone(),
+    };
+    let Some((step, env_differs)) = first_difference else {
+        if old_run.outcome != new_run.outcome {
+            report.divergence = Some(Divergence {
+                step: report.steps,
+                difference: Difference::Outcome,
+                old: None,
+                new: None,
+            });
+        }
+        return report;
+    };
+
+    // Re-run both programs to render the instructions around `step`
+    // (which is cheaper than rendering every instruction up front)
+    let old_detail = record(old, args, limits, Some(step)).recorder;
+    let new_detail = record(new, args, limits, Some(step)).recorder;
+    let divergence = if env_differs && step > 0 {
+        // (the instruction before `step` is the one which changed the env)
+        Divergence {
+            step: step - 1,
+            difference: Difference::Environment(diff_envs(
+                &old_detail.env,
+                &new_detail.env,
+            )),
+            old: old_detail.locations.get(&(step - 1)).cloned(),
+            new: new_detail.locations.get(&(step - 1)).cloned(),
+        }
+    } else {
+        let difference = match (old_steps.get(step), new_steps.get(step)) {
+            // (e.g. the programs take different arguments)
+            (Some(_), Some(_)) if env_differs => Difference::Environment(
+                diff_envs(&old_detail.env, &new_detail.env),
+            ),
+            (Some(old_step), Some(new_step)) => Difference::Output {
+                old: old_step.printed.clone(),
+                new: new_step.printed.clone(),
+            },
+            _ => Difference::Stopped,
+        };
+        Divergence {
+            step,
+            difference,
+            old: old_detail.locations.get(&step).cloned(),
+            new: new_detail.locations.get(&step).cloned(),
+        }
+    };
+    report.steps = divergence.step;
+    report.divergence = Some(divergence);
+    report
+}
+
+impl DivergenceReport {
+    /// Renders the report as plain text: the instructions each program
+    /// executed at the step where they diverge, followed by what differs
+    pub fn to_text(&self) -> String {
+        let outcome = |outcome: &Result<(), String>| match outcome {
+            Ok(()) => "returned".to_string(),
+            Err(msg) => format!("error: {msg}"),
+        };
+        let Some(divergence) = &self.divergence else {
+            let mut text = format!(
+                "no divergence: both programs executed the same {} \
+                instruction(s)\n",
+                self.steps
+            );
+            if let Err(msg) = &self.old_outcome {
+                writeln!(text, "(both stopped with error: {msg})").unwrap();
+            }
+            return text;
+        };
+
+        let what = match &divergence.difference {
+            Difference::Environment(_) => "environments differ",
+            Difference::Output { .. } => "output differs",
+            Difference::Stopped => "one program stopped",
+            Difference::Outcome => "outcomes differ",
+        };
+        let mut text =
+            format!("programs diverge at step {} ({what}):\n", divergence.step);
+        let runs = [
+            ("old", &divergence.old, &self.old_outcome),
+            ("new", &divergence.new, &self.new_outcome),
+        ];
+        for (name, location, run_outcome) in runs {
+            match location {
+                Some(Location { func, pc, instr }) => {
+                    writeln!(text, "  {name}  @{func}:{pc}  {instr}").unwrap()
+                }
+                None => writeln!(text, "  {name}  {}", outcome(run_outcome))
+                    .unwrap(),
+            }
+        }
+        let value = |value: &Option<BrilValue>| {
+            value.map_or("undefined".to_string(), |value| value.to_string())
+        };
+        match &divergence.difference {
+            Difference::Environment(diffs) => {
+                for VarDiff { var, old, new } in diffs {
+                    writeln!(
+                        text,
+                        "  {var}: {} (old) vs {} (new)",
+                        value(old),
+                        value(new)
+                    )
+                    .unwrap();
+                }
+            }
+            Difference::Output { old, new } => {
+                let line = |line: &Option<String>| {
+                    line.as_ref().map_or("nothing".to_string(), |line| {
+                        format!("`{line}`")
+                    })
+                };
+                writeln!(
+                    text,
+                    "  prints {} (old) vs {} (new)",
+                    line(old),
+                    line(new)
+                )
+                .unwrap();
+            }
+            Difference::Stopped | Difference::Outcome => (),
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod divergence_tests {
+    use serde_json::{Value, json};
+
+    use crate::divergence::{Difference, VarDiff, find_divergence};
+    use crate::interp::Limits;
+    use crate::memfile::{AlignedBytes, json_to_fbril_bytes, load_program};
+    use crate::types::BrilValue;
+
+    /// Flattens a `main` function with the instructions `instrs`
+    fn flatten(instrs: Value) -> AlignedBytes {
+        let program = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": instrs
+        }] });
+        AlignedBytes::new(&json_to_fbril_bytes(&program))
+    }
+
+    /// The first instruction which changes the environment or the output
+    /// is reported, along with the variables / lines that differ
+    #[test]
+    fn test_find_divergence() {
+        let program = |op: &str, extra_print: bool| {
+            let mut instrs = vec![
+                json!({ "op": "const", "dest": "one", "type": "int",
+                        "value": 1 }),
+                json!({ "label": "loop" }),
+                json!({ "op": op, "dest": "x", "type": "int",
+                        "args": ["n", "one"] }),
+                json!({ "op": "print", "args": ["x"] }),
+            ];
+            if extra_print {
+                instrs.push(json!({ "op": "print", "args": ["one"] }));
+            }
+            flatten(Value::Array(instrs))
+        };
+        let (add, sub, longer) = (
+            program("add", false),
+            program("sub", false),
+            program("add", true),
+        );
+        let add = load_program(&add).unwrap();
+        let sub = load_program(&sub).unwrap();
+        let longer = load_program(&longer).unwrap();
+        let limits = Limits::default();
+
+        let report = find_divergence(&add, &add, &["5"], limits);
+        assert_eq!(report.divergence, None);
+        assert_eq!(report.steps, 3);
+
+        let report = find_divergence(&add, &sub, &["5"], limits);
+        let divergence = report.divergence.expect("programs diverge");
+        assert_eq!(divergence.step, 1);
+        assert_eq!(
+            divergence.difference,
+            Difference::Environment(vec![VarDiff {
+                var: "x".to_string(),
+                old: Some(BrilValue::IntVal(6)),
+                new: Some(BrilValue::IntVal(4)),
+            }])
+        );
+        let (old, new) = (divergence.old.unwrap(), divergence.new.unwrap());
+        assert_eq!((old.func.as_str(), old.pc), ("main", 1));
+        assert_eq!(old.instr, "x: int = add n one;");
+        assert_eq!(new.instr, "x: int = sub n one;");
+
+        let report = find_divergence(&add, &longer, &["5"], limits);
+        let text = report.to_text();
+        let divergence = report.divergence.expect("programs diverge");
+        assert_eq!(divergence.step, 3);
+        assert_eq!(divergence.difference, Difference::Stopped);
+        assert_eq!(divergence.old, None);
+        assert_eq!(divergence.new.unwrap().instr, "print one;");
+        assert!(text.contains("  old  returned\n"));
+    }
+}
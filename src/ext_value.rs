@@ -0,0 +1,168 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A runtime value of a type which an extension adds to Bril (e.g. a string
+/// or a record), which is printed using its `Display` impl
+pub trait ExtValue: fmt::Display + fmt::Debug {
+    /// The name of the value's type (e.g. `string`), which the debuggers
+    /// show alongside the value
+    fn type_name(&self) -> &str;
+}
+
+/// A handle to an extension's value, which is what `BrilValue::Extern`
+/// holds (so that `BrilValue`s can still be copied & compared cheaply)
+/// - The values themselves are kept in a per-thread heap (see `ExternRef::new`)
+///   until `clear_ext_values` is called, so that they can be printed
+///   wherever a `BrilValue` is displayed
+/// - In Bril programs, variables holding these values have the type
+///   `extern`. They can be passed through `id`, `print` & calls, and are
+///   usually created & consumed by host functions (see `host::HostFns`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ExternRef(u64);
+
+thread_local! {
+    /// The values which `ExternRef`s on this thread refer to
+    /// (indexed by `ExternRef`)
+    static EXT_VALUES: RefCell<Vec<Rc<dyn ExtValue>>> =
+        const { RefCell::new(vec![]) };
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl ExternRef {
+    /// Stores `value` in this thread's heap & returns a handle to it
+    pub fn new(value: impl ExtValue + 'static) -> Self {
+        EXT_VALUES.with_borrow_mut(|values| {
+            values.push(Rc::new(value));
+            ExternRef(values.len() as u64 - 1)
+        })
+    }
+
+    /// The value this handle refers to (`None` if it was created on another
+    /// thread, or before the values were cleared)
+    pub fn get(self) -> Option<Rc<dyn ExtValue>> {
+        EXT_VALUES.with_borrow(|values| values.get(self.0 as usize).cloned())
+    }
+}
+
+/// Frees every value stored on this thread, invalidating their handles
+pub fn clear_ext_values() {
+    EXT_VALUES.with_borrow_mut(Vec::clear);
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl fmt::Display for ExternRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => write!(f, "{value}"),
+            None => write!(f, "<extern #{}>", self.0),
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod ext_value_tests {
+    use std::fmt;
+
+    use serde_json::json;
+
+    use crate::diagnostic::ErrorCode;
+    use crate::ext_value::{ExtValue, ExternRef, clear_ext_values};
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::types::BrilValue;
+
+    #[derive(Debug)]
+    struct Str(String);
+
+    impl fmt::Display for Str {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl ExtValue for Str {
+        fn type_name(&self) -> &str {
+            "string"
+        }
+    }
+
+    /// Values created by host functions can be passed through `id`, calls
+    /// & `print`, but not used by Core Bril's operations
+    #[test]
+    fn test_ext_values() {
+        let json = json!({ "functions": [
+            {
+                "name": "main",
+                "instrs": [
+                    { "op": "const", "dest": "n", "type": "int", "value": 3 },
+                    { "op": "call", "dest": "s", "type": "extern",
+                      "funcs": ["repeat"], "args": ["n"] },
+                    { "op": "id", "dest": "t", "type": "extern",
+                      "args": ["s"] },
+                    { "op": "call", "dest": "u", "type": "extern",
+                      "funcs": ["shout"], "args": ["t"] },
+                    { "op": "print", "args": ["u", "n"] },
+                    { "op": "add", "dest": "v", "type": "int",
+                      "args": ["u", "n"] }
+                ]
+            },
+            {
+                "name": "shout",
+                "args": [{ "name": "x", "type": "extern" }],
+                "type": "extern",
+                "instrs": [
+                    { "op": "call", "dest": "y", "type": "extern",
+                      "funcs": ["upper"], "args": ["x"] },
+                    { "op": "ret", "args": ["y"] }
+                ]
+            }
+        ]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+
+        let string_arg = |args: &[BrilValue]| match args {
+            [BrilValue::Extern(s)] => s
+                .get()
+                .map(|value| value.to_string())
+                .ok_or_else(|| "dangling string".to_string()),
+            _ => Err("expected a string".to_string()),
+        };
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_host_fn("repeat", |args| match args {
+                [BrilValue::IntVal(n)] => {
+                    let s = "ab".repeat(*n as usize);
+                    Ok(Some(BrilValue::Extern(ExternRef::new(Str(s)))))
+                }
+                _ => Err("expected an int".to_string()),
+            })
+            .with_host_fn("upper", move |args| {
+                let s = string_arg(args)?.to_uppercase();
+                Ok(Some(BrilValue::Extern(ExternRef::new(Str(s)))))
+            });
+        let error = interp_program(&program, vec![], &mut ctx).unwrap_err();
+        assert_eq!(String::from_utf8_lossy(&ctx.out), "ABABAB 3\n");
+        assert_eq!(error.code, ErrorCode::TypeError);
+
+        let value = BrilValue::Extern(ExternRef::new(Str("x".to_string())));
+        assert_eq!(value.to_string(), "x");
+        clear_ext_values();
+        assert!(value.to_string().starts_with("<extern #"));
+    }
+}
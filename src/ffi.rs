@@ -0,0 +1,348 @@
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use memmap2::Mmap;
+
+use crate::diagnostic::ErrorCode;
+use crate::interp::{InterpContext, Limits, interp_program};
+use crate::memfile::{self, ProgramViews};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+// The C API for embedding the flat interpreter (see `include/flat_bril.h`,
+// which is generated from this file by cbindgen):
+// - `fbril_load` mmaps & validates an `.fbril` file
+// - `fbril_run` interprets it, passing its output to a callback
+// - `fbril_free` releases it
+// Every function returns an `FbrilStatus`, and a message describing the most
+// recent error on the current thread is available via `fbril_last_error`.
+
+/// A flat Bril program loaded by `fbril_load`
+/// (opaque to C, and must be released with `fbril_free`)
+pub struct FbrilProgram {
+    mmap: Mmap,
+}
+
+/// Result of a call to the C API
+/// (the variants after `FBRIL_STATUS_PANIC` mirror `ErrorCode`)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FbrilStatus {
+    Ok = 0,
+    /// A required pointer argument was `NULL`
+    NullArgument,
+    /// A path or argument string wasn't valid UTF-8
+    InvalidUtf8,
+    /// The interpreter panicked
+    Panic,
+    MalformedFile,
+    MalformedInstr,
+    UndefinedVariable,
+    UndefinedFunction,
+    UndefinedLabel,
+    TypeError,
+    MissingReturnValue,
+    DivisionByZero,
+    BadArgument,
+    StepLimit,
+    CallDepthLimit,
+    Timeout,
+    Io,
+    ReplayMismatch,
+    UnsupportedInstr,
+    HostError,
+}
+
+/// Called with each chunk of output produced by the program
+/// (`data` is only valid for the duration of the call, and is not
+/// NUL-terminated). `user_data` is passed through from `fbril_run`.
+pub type FbrilOutputCallback = Option<
+    unsafe extern "C" fn(data: *const u8, len: usize, user_data: *mut c_void),
+>;
+
+/// Forwards everything written to it to an `FbrilOutputCallback`
+/// (output is discarded if there's no callback)
+struct CallbackWriter {
+    callback: FbrilOutputCallback,
+    user_data: *mut c_void,
+}
+
+thread_local! {
+    /// Message describing the most recent error on this thread
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl From<ErrorCode> for FbrilStatus {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::MalformedFile => FbrilStatus::MalformedFile,
+            ErrorCode::MalformedInstr => FbrilStatus::MalformedInstr,
+            ErrorCode::UndefinedVariable => FbrilStatus::UndefinedVariable,
+            ErrorCode::UndefinedFunction => FbrilStatus::UndefinedFunction,
+            ErrorCode::UndefinedLabel => FbrilStatus::UndefinedLabel,
+            ErrorCode::TypeError => FbrilStatus::TypeError,
+            ErrorCode::MissingReturnValue => FbrilStatus::MissingReturnValue,
+            ErrorCode::DivisionByZero => FbrilStatus::DivisionByZero,
+            ErrorCode::BadArgument => FbrilStatus::BadArgument,
+            ErrorCode::StepLimit => FbrilStatus::StepLimit,
+            ErrorCode::CallDepthLimit => FbrilStatus::CallDepthLimit,
+            ErrorCode::Timeout => FbrilStatus::Timeout,
+            ErrorCode::Io => FbrilStatus::Io,
+            ErrorCode::ReplayMismatch => FbrilStatus::ReplayMismatch,
+            ErrorCode::UnsupportedInstr => FbrilStatus::UnsupportedInstr,
+            ErrorCode::HostError => FbrilStatus::HostError,
+        }
+    }
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(callback) = self.callback {
+            // SAFETY: the caller of `fbril_run` guarantees that `callback`
+            // can be called with `user_data`
+            unsafe { callback(buf.as_ptr(), buf.len(), self.user_data) };
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records `message` as the most recent error & returns `status`
+fn fail(status: FbrilStatus, message: impl Into<String>) -> FbrilStatus {
+    // Interior NULs can't be represented in a C string, so we drop them
+    let message = CString::new(message.into().replace('\0', ""))
+        .expect("NULs were removed");
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+    status
+}
+
+/// Converts a C string to a `&str`, failing if it isn't valid UTF-8
+/// - Safety: `s` must be a valid, NUL-terminated C string
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, FbrilStatus> {
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| {
+        fail(FbrilStatus::InvalidUtf8, "string is not valid UTF-8")
+    })
+}
+
+/// Loads the `.fbril` file at `path`, storing a handle to it in `*out`
+/// (which is set to `NULL` if the file can't be loaded)
+///
+/// # Safety
+/// `path` must be a valid, NUL-terminated C string, and `out` must be
+/// valid for writes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fbril_load(
+    path: *const c_char,
+    out: *mut *mut FbrilProgram,
+) -> FbrilStatus {
+    if path.is_null() || out.is_null() {
+        return fail(
+            FbrilStatus::NullArgument,
+            "`path` and `out` must be non-null",
+        );
+    }
+    unsafe { *out = ptr::null_mut() };
+    let path = match unsafe { to_str(path) } {
+        Ok(path) => path,
+        Err(status) => return status,
+    };
+    let mmap = match memfile::mmap_existing_file(path) {
+        Ok(mmap) => mmap,
+        Err(msg) => return fail(FbrilStatus::MalformedFile, msg),
+    };
+    // Validate the file now, so that errors are reported when it's loaded
+    if let Err(msg) = memfile::load_program(&mmap) {
+        return fail(
+            FbrilStatus::MalformedFile,
+            format!("malformed file `{path}`: {msg}"),
+        );
+    }
+    unsafe { *out = Box::into_raw(Box::new(FbrilProgram { mmap })) };
+    FbrilStatus::Ok
+}
+
+/// Interprets `program`, passing the `num_args` strings in `args` to `main`.
+/// The program's output is passed to `callback` (along with `user_data`)
+/// as it is produced; `callback` may be `NULL`, in which case the output
+/// is discarded.
+/// - A program can be run any number of times (including from several
+///   threads at once)
+///
+/// # Safety
+/// - `program` must have been returned by `fbril_load` (and not yet freed)
+/// - `args` must point to `num_args` valid, NUL-terminated C strings
+///   (it may be `NULL` if `num_args` is 0)
+/// - `callback` must be safe to call with `user_data`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fbril_run(
+    program: *const FbrilProgram,
+    args: *const *const c_char,
+    num_args: usize,
+    callback: FbrilOutputCallback,
+    user_data: *mut c_void,
+) -> FbrilStatus {
+    if program.is_null() || (args.is_null() && num_args != 0) {
+        return fail(
+            FbrilStatus::NullArgument,
+            "`program` and `args` must be non-null",
+        );
+    }
+    let program = unsafe { &*program };
+    let mut arg_strs = vec![];
+    for i in 0..num_args {
+        let arg = unsafe { *args.add(i) };
+        if arg.is_null() {
+            return fail(
+                FbrilStatus::NullArgument,
+                format!("argument #{i} is null"),
+            );
+        }
+        match unsafe { to_str(arg) } {
+            Ok(arg) => arg_strs.push(arg),
+            Err(status) => return status,
+        }
+    }
+
+    // (the file was already validated by `fbril_load`)
+    let views = memfile::load_program(&program.mmap)
+        .expect("program was validated when it was loaded");
+    let out = BufWriter::new(CallbackWriter {
+        callback,
+        user_data,
+    });
+    let mut ctx = InterpContext::new(out, Limits::default());
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match &views {
+        ProgramViews::Narrow(views) => {
+            interp_program(views, arg_strs, &mut ctx)
+        }
+        ProgramViews::Wide(views) => interp_program(views, arg_strs, &mut ctx),
+        ProgramViews::Compact(views) => {
+            interp_program(views, arg_strs, &mut ctx)
+        }
+    }));
+    match result {
+        Err(_) => fail(FbrilStatus::Panic, "interpreter panicked"),
+        Ok(Err(diagnostic)) => {
+            fail(diagnostic.code.into(), diagnostic.to_string())
+        }
+        Ok(Ok(())) => match ctx.out.flush() {
+            Ok(()) => FbrilStatus::Ok,
+            Err(e) => {
+                fail(FbrilStatus::Io, format!("unable to write output: {e}"))
+            }
+        },
+    }
+}
+
+/// Releases a program returned by `fbril_load` (does nothing if `program`
+/// is `NULL`)
+///
+/// # Safety
+/// `program` must have been returned by `fbril_load`, and must not be used
+/// after this call
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fbril_free(program: *mut FbrilProgram) {
+    if !program.is_null() {
+        drop(unsafe { Box::from_raw(program) });
+    }
+}
+
+/// Returns a message describing the most recent error on the current thread,
+/// or `NULL` if there hasn't been one. The string is owned by flat-bril, and
+/// is only valid until the next call to the C API on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn fbril_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod ffi_tests {
+    use std::ffi::{CStr, CString, c_void};
+    use std::fs::{self, File};
+    use std::io::BufReader;
+    use std::ptr;
+
+    use crate::ffi::{
+        FbrilProgram, FbrilStatus, fbril_free, fbril_last_error, fbril_load,
+        fbril_run,
+    };
+    use crate::memfile::json_to_fbril_bytes;
+
+    /// Output callback which appends the output to the `Vec<u8>`
+    /// passed as `user_data`
+    unsafe extern "C" fn collect_output(
+        data: *const u8,
+        len: usize,
+        user_data: *mut c_void,
+    ) {
+        let out = unsafe { &mut *(user_data as *mut Vec<u8>) };
+        out.extend_from_slice(unsafe { std::slice::from_raw_parts(data, len) });
+    }
+
+    /// A program can be loaded, run (with args) & freed via the C API,
+    /// and errors are reported through status codes
+    #[test]
+    fn test_load_run_free() {
+        let file = File::open("test/fact.json").expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let path = std::env::temp_dir().join("flat_bril_ffi_test.fbril");
+        fs::write(&path, json_to_fbril_bytes(&json)).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let mut program: *mut FbrilProgram = ptr::null_mut();
+        let status = unsafe { fbril_load(c_path.as_ptr(), &mut program) };
+        assert_eq!(status, FbrilStatus::Ok);
+
+        let arg = CString::new("5").unwrap();
+        let args = [arg.as_ptr()];
+        let mut out: Vec<u8> = vec![];
+        let status = unsafe {
+            fbril_run(
+                program,
+                args.as_ptr(),
+                args.len(),
+                Some(collect_output),
+                &mut out as *mut Vec<u8> as *mut c_void,
+            )
+        };
+        assert_eq!(status, FbrilStatus::Ok);
+        assert_eq!(String::from_utf8(out).unwrap(), "120\n");
+
+        // `main` expects an int
+        let arg = CString::new("five").unwrap();
+        let args = [arg.as_ptr()];
+        let status = unsafe {
+            fbril_run(program, args.as_ptr(), 1, None, ptr::null_mut())
+        };
+        assert_eq!(status, FbrilStatus::BadArgument);
+        assert!(!fbril_last_error().is_null());
+        let message = unsafe { CStr::from_ptr(fbril_last_error()) };
+        assert!(message.to_str().unwrap().contains("five"));
+
+        unsafe { fbril_free(program) };
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::interp::{Environment, get_func_name};
+use crate::observer::Observer;
+use crate::printer::pad_right;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The totals for a single function, accumulated across all of its calls
+/// - `self_*` only counts the instructions executed (& the time spent) in
+///   the function itself, while `total_*` also counts its callees
+/// - A recursive call isn't counted again in `total_*`, since its time is
+///   already included in the outermost call's
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FuncStats {
+    pub calls: u64,
+    pub self_instrs: u64,
+    pub total_instrs: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+/// A call which is currently on the stack
+/// - `path_len` is the length of `FuncProfiler::path` before the call
+/// - `child_*` are the instructions executed (& the time spent) in the
+///   calls it has made so far
+#[derive(Debug)]
+struct Frame {
+    func: String,
+    path_len: usize,
+    instrs: u64,
+    child_instrs: u64,
+    start: Instant,
+    child_time: Duration,
+}
+
+/// An `Observer` which records how many instructions each function executes
+/// & how long it takes, both per function & per call stack (the latter can
+/// be rendered as a flame graph, see `collapsed_stacks`)
+/// - `path` is the current call stack, as function names separated by `;`
+/// - `stacks` maps each call stack to the no. of instructions executed in
+///   its innermost function
+#[derive(Debug, Default)]
+pub struct FuncProfiler {
+    stack: Vec<Frame>,
+    path: String,
+    stacks: HashMap<String, u64>,
+    funcs: HashMap<String, FuncStats>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl FuncProfiler {
+    /// Creates a profiler which hasn't observed anything yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that the innermost call on the stack has returned
+    fn pop_frame(&mut self) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        let elapsed = frame.start.elapsed();
+        let recursive = self.stack.iter().any(|f| f.func == frame.func);
+        let stats = self.funcs.entry(frame.func).or_default();
+        stats.self_instrs += frame.instrs;
+        stats.self_time += elapsed.saturating_sub(frame.child_time);
+        if !recursive {
+            stats.total_instrs += frame.instrs + frame.child_instrs;
+            stats.total_time += elapsed;
+        }
+
+        if frame.instrs > 0 {
+            *self.stacks.entry(self.path.clone()).or_default() += frame.instrs;
+        }
+        self.path.truncate(frame.path_len);
+        if let Some(caller) = self.stack.last_mut() {
+            caller.child_instrs += frame.instrs + frame.child_instrs;
+            caller.child_time += elapsed;
+        }
+    }
+
+    /// Records the calls which are still on the stack (e.g. because the
+    /// program failed part-way through) as if they'd returned now
+    pub fn finish(&mut self) {
+        while !self.stack.is_empty() {
+            self.pop_frame();
+        }
+    }
+
+    /// The totals for each function that was called
+    pub fn func_stats(&self) -> &HashMap<String, FuncStats> {
+        &self.funcs
+    }
+
+    /// Renders the call stacks in the "collapsed stack" format read by
+    /// `inferno-flamegraph` & `flamegraph.pl`: one line per call stack, of
+    /// the form `main;f;g 42`, where the weight is the no. of instructions
+    /// executed in the innermost function (sorted by call stack)
+    pub fn collapsed_stacks(&self) -> String {
+        let mut stacks: Vec<(&String, &u64)> = self.stacks.iter().collect();
+        stacks.sort_unstable();
+        stacks
+            .into_iter()
+            .map(|(path, instrs)| format!("{path} {instrs}\n"))
+            .collect()
+    }
+
+    /// Renders the totals for each function as a table, hottest function
+    /// (by the no. of instructions executed in it) first
+    pub fn to_text(&self) -> String {
+        let mut funcs: Vec<(&String, &FuncStats)> = self.funcs.iter().collect();
+        funcs.sort_by(|(name1, stats1), (name2, stats2)| {
+            (stats2.self_instrs, name1).cmp(&(stats1.self_instrs, name2))
+        });
+        let name_width = funcs
+            .iter()
+            .map(|(name, _)| name.len() + 1)
+            .max()
+            .unwrap_or(0)
+            .max("FUNCTION".len());
+        let mut text = format!(
+            "{}  {:>8}  {:>12}  {:>12}  {:>10}  {:>10}\n",
+            pad_right("FUNCTION", name_width),
+            "CALLS",
+            "SELF INSTRS",
+            "TOTAL INSTRS",
+            "SELF ms",
+            "TOTAL ms"
+        );
+        for (name, stats) in funcs {
+            writeln!(
+                text,
+                "{}  {:>8}  {:>12}  {:>12}  {:>10.3}  {:>10.3}",
+                pad_right(&format!("@{name}"), name_width),
+                stats.calls,
+                stats.self_instrs,
+                stats.total_instrs,
+                stats.self_time.as_secs_f64() * 1000.0,
+                stats.total_time.as_secs_f64() * 1000.0
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+impl Observer for FuncProfiler {
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        let func = get_func_name(callee);
+        let path_len = self.path.len();
+        if !self.path.is_empty() {
+            self.path.push(';');
+        }
+        self.path.push_str(func);
+        self.funcs.entry(func.to_string()).or_default().calls += 1;
+        self.stack.push(Frame {
+            func: func.to_string(),
+            path_len,
+            instrs: 0,
+            child_instrs: 0,
+            start: Instant::now(),
+            child_time: Duration::ZERO,
+        });
+    }
+
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        _instr_view: &InstrView<P>,
+        _pc: usize,
+        _instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        // (`on_call` has always been called for the function already)
+        if let Some(frame) = self.stack.last_mut() {
+            frame.instrs += 1;
+        }
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        _callee: &InstrView<P>,
+        _value: Option<BrilValue>,
+    ) {
+        self.pop_frame();
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod flamegraph_tests {
+    use crate::flamegraph::FuncProfiler;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+
+    /// Instructions are attributed to the innermost function on the stack,
+    /// and a function's totals include its callees
+    #[test]
+    fn test_func_profiler() {
+        let json_str = std::fs::read_to_string("test/call-with-args.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let mut profiler = FuncProfiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec![], &mut ctx).expect("program should run");
+        profiler.finish();
+
+        // 5 instrs in `main` + 4 in `add2`
+        assert_eq!(profiler.collapsed_stacks(), "main 5\nmain;add2 4\n");
+        let stats = profiler.func_stats();
+        assert_eq!(stats["main"].calls, 1);
+        assert_eq!(stats["main"].self_instrs, 5);
+        assert_eq!(stats["main"].total_instrs, 9);
+        assert_eq!(stats["add2"].total_instrs, 4);
+        assert!(stats["main"].total_time >= stats["add2"].total_time);
+        assert!(
+            profiler
+                .to_text()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .starts_with("@main")
+        );
+    }
+}
@@ -1,14 +1,86 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use serde::Deserializer;
+use serde::de::{
+    self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+
+use crate::slots;
 use crate::types::*;
 
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// An error found while flattening a malformed JSON Bril function,
+/// located by the path of the offending element
+/// (e.g. `functions[2].instrs[17].value`)
+/// - `func` is the name of the function (if it has a valid one)
+/// - `path` is relative to the function, unless `in_program` is called
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlattenError {
+    pub func: Option<String>,
+    pub path: String,
+    pub message: String,
+}
+
 /* -------------------------------------------------------------------------- */
 /*                                 Actual code                                */
 /* -------------------------------------------------------------------------- */
 
+impl FlattenError {
+    /// Prefixes the path of the error with `prefix` (the path of the
+    /// element containing the one that the error refers to)
+    fn at(self, prefix: &str) -> Self {
+        let path = if self.path.is_empty() || self.path.starts_with('[') {
+            format!("{prefix}{}", self.path)
+        } else {
+            format!("{prefix}.{}", self.path)
+        };
+        Self { path, ..self }
+    }
+
+    /// Locates the error in a program, where the function is the
+    /// `func_idx`-th element of the `functions` array
+    pub fn in_program(self, func_idx: usize) -> Self {
+        self.at(&format!("functions[{func_idx}]"))
+    }
+}
+
+impl fmt::Display for FlattenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)?;
+        if let Some(func) = &self.func {
+            write!(f, " (in @{func})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Extracts the name of a variable, label or function, which must be a
+/// non-empty string
+fn expect_name(json: &serde_json::Value) -> Result<&str, FlattenError> {
+    match json.as_str() {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(FlattenError {
+            func: None,
+            path: String::new(),
+            message: format!("expected a non-empty string, but found {json}"),
+        }),
+    }
+}
+
 /// Takes in a vector of JSON values (representing variables / labels),
-/// a vector `global_idxes_vec` storing the start & end index of  
+/// a vector `global_idxes_vec` storing the offset & length of  
 /// the byte representation of each var in `buffer` (a byte sequence),
-/// and returns the start / end index of the buffer corresponding to the
-/// elements in `json_vec`
+/// and returns the offset & length of the part of `global_idxes_vec`
+/// corresponding to the elements in `json_vec`
 ///
 /// Example:
 /// - json_vec = args_json_vec
@@ -18,34 +90,40 @@ use crate::types::*;
 /// - json_vec = labels_json_vec
 /// - global_idxes_vec = all_labels_idxes
 /// - buffer = all_labels
+///
+/// Returns a `FlattenError` (whose path is relative to `json_vec`) if
+/// one of the elements isn't a non-empty string.
 pub fn flatten_instr_array_fields(
     json_vec: &[serde_json::Value],
-    global_idxes_vec: &mut Vec<(u32, u32)>,
+    global_idxes_vec: &mut Vec<(usize, usize)>,
     buffer: &mut Vec<u8>,
-) -> (u32, u32) {
+) -> Result<(usize, usize), FlattenError> {
     // Convert each JSON string in `json_vec` into a
     // `&[u8]` byte slice
     let bytes_vec: Vec<&[u8]> = json_vec
         .iter()
-        .map(|v| v.as_str().unwrap().as_bytes())
-        .collect();
+        .enumerate()
+        .map(|(i, v)| {
+            expect_name(v)
+                .map(str::as_bytes)
+                .map_err(|e| e.at(&format!("[{i}]")))
+        })
+        .collect::<Result<_, _>>()?;
 
-    // `idxes_vec` stores the start & end indexes
+    // `idxes_vec` stores the offset & length
     // of each variable in `bytes_vec` (this is necessary
     // since later on, we're concatenating all the byte slices tgt)
-    let mut idxes_vec: Vec<(u32, u32)> = Vec::new();
-    let mut n: u32 = buffer.len() as u32;
+    let mut idxes_vec: Vec<(usize, usize)> = Vec::new();
+    let mut n = buffer.len();
     for var in bytes_vec.iter() {
-        idxes_vec.push((n, n + (var.len() - 1) as u32));
-        n += var.len() as u32;
+        idxes_vec.push((n, var.len()));
+        n += var.len();
     }
 
-    // Compute the start & end indexes of all variables mentioned
+    // Compute the offset & length of all variables mentioned
     // by this instruction in `idxes-vec`
-    let start_idx = global_idxes_vec.len();
+    let var_idxes = (global_idxes_vec.len(), idxes_vec.len());
     global_idxes_vec.extend_from_slice(idxes_vec.as_slice());
-    let end_idx = global_idxes_vec.len() - 1;
-    let var_idxes = (start_idx as u32, end_idx as u32);
 
     // Concatenate all the `&[u8]`s in `bytes_vec` into
     // one single vector of bytes
@@ -55,13 +133,25 @@ pub fn flatten_instr_array_fields(
     // bytes that we just cretaed
     buffer.extend_from_slice(vars_vec.as_slice());
 
-    var_idxes
+    Ok(var_idxes)
 }
 
 /// Takes in a JSON function representing one single Bril function,
 /// and returns a vector containing the flattened instructions in the function
-/// (in the same order)
+/// (in the same order).
+/// Panics if the function is malformed (see `try_flatten_instrs`).
 pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
+    try_flatten_instrs(func_json).unwrap_or_else(|e| panic!("{e}"))
+}
+
+/// Like `flatten_instrs`, but returns a `FlattenError` locating the
+/// offending element if the function is malformed (e.g. if an instruction
+/// has no opcode, or if a `const` has no value).
+/// Instructions whose opcode isn't in core Bril are kept as extension
+/// instructions (see `EXT_OP`).
+pub fn try_flatten_instrs(
+    func_json: &serde_json::Value,
+) -> Result<InstrStore, FlattenError> {
     // We reserve a buffer of size `NUM_ARGS` that contains
     // all the variables used in this function.
     // We also do the same for dests, labels and funcs.
@@ -77,21 +167,30 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
     let mut all_vars: Vec<u8> = Vec::with_capacity(NUM_VARS * 2);
 
     // `all_args_idxes` stores the start & end indexes of each arg in `all_vars`
-    let mut all_args_idxes: Vec<(u32, u32)> = Vec::with_capacity(NUM_ARGS);
+    let mut all_args_idxes: Vec<(usize, usize)> = Vec::with_capacity(NUM_ARGS);
 
     // `all_labels_idxes` stores the start & end indexes of each label in `all_labels`
     let mut all_labels: Vec<u8> = Vec::with_capacity(NUM_LABELS * 5);
-    let mut all_labels_idxes: Vec<(u32, u32)> = Vec::with_capacity(NUM_LABELS);
+    let mut all_labels_idxes: Vec<(usize, usize)> =
+        Vec::with_capacity(NUM_LABELS);
 
     let mut all_funcs: Vec<u8> = Vec::with_capacity(NUM_FUNCS);
 
-    let func_name = func_json["name"]
-        .as_str()
-        .expect("Expected `name` to be a string");
+    // `all_ext` stores the JSON of each extension instruction
+    let mut all_ext: Vec<u8> = vec![];
+
+    let func_name =
+        expect_name(&func_json["name"]).map_err(|e| e.at("name"))?;
     let func_name_bytes: Vec<u8> = func_name.as_bytes().to_vec();
+    // Errors are located relative to the function
+    let error = |path: String, message: &str| FlattenError {
+        func: Some(func_name.to_string()),
+        path,
+        message: message.to_string(),
+    };
     let instrs = func_json["instrs"]
         .as_array()
-        .expect("Expected `instrs` to be a JSON array");
+        .ok_or_else(|| error("instrs".to_string(), "expected an array"))?;
 
     // Figure out if the function has a return type
     let mut func_ret_ty: Option<Type> = None;
@@ -107,28 +206,32 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
     // when `func_json["args"]` doesn't exist)
     let mut func_args: Vec<FuncArg> = vec![];
     if let Some(func_args_json) = func_json["args"].as_array() {
-        for func_arg in func_args_json {
-            let arg_name: &str = func_arg["name"]
-                .as_str()
-                .expect("Expected `name` to be a string");
+        for (i, func_arg) in func_args_json.iter().enumerate() {
+            let arg_name: &str =
+                expect_name(&func_arg["name"]).map_err(|e| FlattenError {
+                    func: Some(func_name.to_string()),
+                    ..e.at(&format!("args[{i}].name"))
+                })?;
 
-            // Find the start/end index of the argument string in the
-            // `all_vars` buffer, & add the bytes of the arg to `all_vars`
+            // Find the offset of the argument string in the `all_vars`
+            // buffer, & add the bytes of the arg to `all_vars`
             let arg_bytes: &[u8] = arg_name.as_bytes();
-            let start_idx = all_vars.len() as u32;
+            let arg_idxes = (all_vars.len(), arg_bytes.len());
             all_vars.extend_from_slice(arg_bytes);
-            let end_idx = (all_vars.len() - 1) as u32;
 
             if let Ok(arg_type) =
                 serde_json::from_value::<Type>(func_arg["type"].clone())
             {
                 let func_arg_struct = FuncArg {
-                    arg_name_idxes: (start_idx, end_idx),
+                    arg_name_idxes: arg_idxes,
                     arg_type,
                 };
                 func_args.push(func_arg_struct);
             } else {
-                panic!("Missing type for function argument in source file")
+                return Err(error(
+                    format!("args[{i}].type"),
+                    "expected `int`, `bool` or a pointer type",
+                ));
             }
         }
     }
@@ -137,45 +240,68 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
     // and labels that we encounter (in the order they appear in the Bril file)
     let mut all_instrs_labels: Vec<Instr> = Vec::with_capacity(NUM_INSTRS);
 
-    for instr in instrs {
-        if let Some(label) = instr["label"].as_str() {
+    for (i, instr) in instrs.iter().enumerate() {
+        let at = |field: &str| format!("instrs[{i}].{field}");
+        // Errors from `flatten_instr_array_fields` are located relative to
+        // the array
+        let in_field = |field: &str| {
+            let path = at(field);
+            let func_name = func_name.to_string();
+            move |e: FlattenError| FlattenError {
+                func: Some(func_name),
+                ..e.at(&path)
+            }
+        };
+        if let Some(label) = instr.get("label") {
+            let label = expect_name(label).map_err(in_field("label"))?;
             // Instruction is a label, doesn't have an opcode
 
             // Add the current label to the global buffer of labels,
-            // and keep track of the indices in `all_labels` corersponding to
-            // the start & end of the label
+            // and keep track of the offset & length of the label in
+            // `all_labels`
             let label_bytes = label.as_bytes();
-            let start_idx = all_labels.len() as u32;
+            let label_idxes = (all_labels.len(), label_bytes.len());
             all_labels.extend(label_bytes);
-            let end_idx = (all_labels.len() - 1) as u32;
 
-            all_instrs_labels.push(Instr::make_label((start_idx, end_idx)));
+            all_instrs_labels.push(Instr::make_label(label_idxes));
 
             continue;
         } else {
-            let opcode: Opcode = serde_json::from_value(instr["op"].clone())
-                .expect("Invalid opcode");
+            expect_name(&instr["op"]).map_err(in_field("op"))?;
+            let Ok(opcode) =
+                serde_json::from_value::<Opcode>(instr["op"].clone())
+            else {
+                // Instructions from Bril extensions are kept as JSON, so that
+                // they're unflattened exactly as they were written
+                let json = instr.to_string();
+                let json_idxes = (all_ext.len(), json.len());
+                all_ext.extend_from_slice(json.as_bytes());
+                all_instrs_labels.push(Instr::make_extension(json_idxes));
+                continue;
+            };
             let opcode_idx = opcode.get_index() as u32;
 
-            // Obtain the start/end indexes into the all_args_idxes Vec
-            // (used to populate the `args` field of the `Instr` struct)
+            // Obtain the offset & length of the args in the all_args_idxes
+            // Vec (used to populate the `args` field of the `Instr` struct)
             let mut arg_idxes = None;
+            // (an empty `args` array is kept, so that it's unflattened as
+            // it was written)
             if let Some(args_json_vec) = instr["args"].as_array() {
-                let (start_idx, end_idx) = flatten_instr_array_fields(
-                    args_json_vec,
-                    &mut all_args_idxes,
-                    &mut all_vars,
+                arg_idxes = Some(
+                    flatten_instr_array_fields(
+                        args_json_vec,
+                        &mut all_args_idxes,
+                        &mut all_vars,
+                    )
+                    .map_err(in_field("args"))?,
                 );
-                arg_idxes = Some((start_idx, end_idx))
             }
 
             // Populate the `dest` field of the `Instr` struct
             let mut dest_idx = None;
-            if let Some(dest) = instr["dest"].as_str() {
-                dest_idx = Some((
-                    all_vars.len() as u32,
-                    (all_vars.len() + dest.len() - 1) as u32,
-                ));
+            if let Some(dest) = instr.get("dest") {
+                let dest = expect_name(dest).map_err(in_field("dest"))?;
+                dest_idx = Some((all_vars.len(), dest.len()));
                 all_vars.extend_from_slice(dest.as_bytes());
             }
 
@@ -193,34 +319,46 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
                 value = Some(BrilValue::IntVal(int_value));
             } else if let Some(b) = instr["value"].as_bool() {
                 value = Some(BrilValue::BoolVal(b.into()));
+            } else if opcode.spec().role == OpRole::Const {
+                return Err(error(at("value"), "expected an int or a bool"));
             }
 
             // Populate the `labels` field of the `Instr` struct
             let mut labels_idxes = None;
             if let Some(labels_json_vec) = instr["labels"].as_array() {
-                let (start_idx, end_idx) = flatten_instr_array_fields(
-                    labels_json_vec,
-                    &mut all_labels_idxes,
-                    &mut all_labels,
+                labels_idxes = Some(
+                    flatten_instr_array_fields(
+                        labels_json_vec,
+                        &mut all_labels_idxes,
+                        &mut all_labels,
+                    )
+                    .map_err(in_field("labels"))?,
                 );
-                labels_idxes = Some((start_idx, end_idx));
             }
 
             // Handle `func` field in `Instr` struct
-            // Because we only handle core Bril we assume only one func is referenced
+            // Because we only handle core Bril we assume at most one func is
+            // referenced (an empty `funcs` array, which `unflatten` writes for
+            // every instruction other than a `call`, is the same as a missing
+            // one, since there's no list of funcs to keep)
             let mut func_idx = None;
             if let Some(funcs_json_vec) = instr["funcs"].as_array() {
-                let funcs_vec: Vec<&[u8]> = funcs_json_vec
-                    .iter()
-                    .map(|v| v.as_str().unwrap().as_bytes())
-                    .collect();
-                assert!(funcs_vec.len() == 1);
-                let func = funcs_vec.concat();
-                func_idx = Some((
-                    all_funcs.len() as u32,
-                    (all_funcs.len() + func.len() - 1) as u32,
-                ));
-                all_funcs.extend_from_slice(func.as_slice());
+                match funcs_json_vec.as_slice() {
+                    [] => (),
+                    [func] => {
+                        let func = expect_name(func)
+                            .map_err(in_field("funcs[0]"))?
+                            .as_bytes();
+                        func_idx = Some((all_funcs.len(), func.len()));
+                        all_funcs.extend_from_slice(func);
+                    }
+                    _ => {
+                        return Err(error(
+                            at("funcs"),
+                            "expected at most one function",
+                        ));
+                    }
+                }
             }
 
             let instr = Instr {
@@ -237,7 +375,7 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
         }
     }
 
-    InstrStore {
+    let mut instr_store = InstrStore {
         func_name: func_name_bytes,
         func_args,
         func_ret_ty,
@@ -247,7 +385,450 @@ pub fn flatten_instrs(func_json: &serde_json::Value) -> InstrStore {
         labels_store: all_labels,
         funcs_store: all_funcs,
         instrs: all_instrs_labels,
+        num_slots: 0,
+        dest_slots: vec![],
+        arg_slots: vec![],
+        ext_store: all_ext,
+    };
+    slots::assign_slots(&mut instr_store);
+    Ok(instr_store)
+}
+
+/// Renders `count` occurrences of `noun`, e.g. "2 args", "at most 1 arg"
+fn describe_count(count: &RangeInclusive<usize>, noun: &str) -> String {
+    let (lo, hi) = (*count.start(), *count.end());
+    let plural = if hi == 1 { "" } else { "s" };
+    if lo == hi {
+        format!("{lo} {noun}{plural}")
+    } else if hi == usize::MAX {
+        format!("at least {lo} {noun}s")
+    } else {
+        format!("at most {hi} {noun}{plural}")
+    }
+}
+
+/// Checks that each instruction in the flattened function `store` has
+/// as many args, labels & funcs as its opcode expects (& a dest if it's a
+/// value operation), and that every label referenced by a `jmp` / `br` is
+/// defined in the function. Returns an error listing every offending
+/// instruction (one per line, naming the function & the index of the
+/// instruction), since otherwise these errors only surface as panics when
+/// the instruction is interpreted.
+/// - Parameters with the same name are rejected too, since they'd share a
+///   slot (& the loader expects a slot per parameter)
+pub fn validate_instr_store(store: &InstrStore) -> Result<(), String> {
+    validate_instrs(store, true)
+}
+
+/// Like `validate_instr_store`, but instructions are identified by their
+/// PCs in the flat format (which don't count labels) rather than their
+/// indexes in the JSON, for functions that were loaded from a `.fbril` file
+pub fn validate_flat_instrs(store: &InstrStore) -> Result<(), String> {
+    validate_instrs(store, false)
+}
+
+/// Implements `validate_instr_store` & `validate_flat_instrs`, where
+/// `count_labels` determines whether instructions are numbered by their
+/// indexes in `store.instrs` (which includes labels) or by their PCs
+fn validate_instrs(
+    store: &InstrStore,
+    count_labels: bool,
+) -> Result<(), String> {
+    let func_name = String::from_utf8_lossy(&store.func_name);
+    let mut param_names = HashSet::new();
+    let duplicate_params = store.func_args.iter().filter_map(|func_arg| {
+        let (offset, len) = func_arg.arg_name_idxes;
+        let name =
+            String::from_utf8_lossy(&store.var_store[offset..offset + len]);
+        (!param_names.insert(name.clone())).then(|| {
+            format!(
+                "@{func_name}: parameter `{name}` is declared more than once"
+            )
+        })
+    });
+    let label_str = |(offset, len): (usize, usize)| {
+        String::from_utf8_lossy(&store.labels_store[offset..offset + len])
+    };
+    let count = |idxes: Option<(usize, usize)>| idxes.map_or(0, |(_, len)| len);
+    let defined_labels: HashSet<_> = store
+        .instrs
+        .iter()
+        .filter(|instr| instr.op == u32::MAX)
+        .filter_map(|instr| instr.label)
+        .map(label_str)
+        .collect();
+    // (only the first problem with each instruction is reported)
+    let check_instr = |idx: usize, instr: &Instr| {
+        // Labels & extension instructions don't have a core opcode
+        let Some(op) = Opcode::u32_to_opcode(instr.op) else {
+            return Ok(());
+        };
+        let error = |message: String| {
+            Err(format!(
+                "@{func_name}: instruction {idx} (`{op}`) {message}"
+            ))
+        };
+
+        let actual = [
+            count(instr.args),
+            count(instr.instr_labels),
+            // `funcs` indexes the bytes of the (only) callee's name
+            usize::from(instr.funcs.is_some()),
+        ];
+        for ((expected, actual), noun) in op
+            .operand_counts()
+            .iter()
+            .zip(actual)
+            .zip(["arg", "label", "function"])
+        {
+            if !expected.contains(&actual) {
+                return error(format!(
+                    "takes {}, but has {actual}",
+                    describe_count(expected, noun)
+                ));
+            }
+        }
+        if op == Opcode::Phi && actual[0] != actual[1] {
+            return error(format!(
+                "has {} args, but {} labels",
+                actual[0], actual[1]
+            ));
+        }
+        if matches!(
+            instr.get_instr_kind(),
+            InstrKind::ValueOp | InstrKind::Const
+        ) && instr.dest.is_none()
+        {
+            return error("has no dest".to_string());
+        }
+
+        if let (Opcode::Jmp | Opcode::Br, Some((offset, len))) =
+            (op, instr.instr_labels)
+        {
+            for &pair in &store.labels_idxes_store[offset..offset + len] {
+                let label = label_str(pair);
+                if !defined_labels.contains(&label) {
+                    return error(format!(
+                        "refers to undefined label .{label}"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    };
+    let mut num_labels = 0;
+    let errors: Vec<String> = duplicate_params
+        .chain(store.instrs.iter().enumerate().filter_map(|(idx, instr)| {
+            let pc = idx - num_labels;
+            if instr.op == u32::MAX && !count_labels {
+                num_labels += 1;
+            }
+            check_instr(pc, instr).err()
+        }))
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
+
+/// How malformed fields in a JSON Bril function are handled
+/// (see `check_func_json`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ingest {
+    /// Malformed fields are reported as warnings & ignored, and instructions
+    /// with unknown opcodes are kept as extension instructions
+    #[default]
+    Permissive,
+    /// Malformed fields are errors
+    Strict,
+}
+
+/// The fields that a JSON Bril function may have (`pos` / `pos_end` are
+/// the source positions emitted by `bril2json -p`)
+const FUNC_FIELDS: [&str; 6] =
+    ["name", "args", "type", "instrs", "pos", "pos_end"];
+
+/// The fields that a JSON Bril instruction (or label) may have
+const INSTR_FIELDS: [&str; 10] = [
+    "op", "dest", "type", "args", "funcs", "labels", "value", "label", "pos",
+    "pos_end",
+];
+
+/// The fields that a parameter of a JSON Bril function may have
+const ARG_FIELDS: [&str; 2] = ["name", "type"];
+
+/// Checks a JSON Bril function for fields which `flatten_instrs` would
+/// otherwise silently ignore: unknown opcodes, types other than `int`,
+/// `bool` & pointers to them, values which aren't ints or bools & unexpected
+/// fields.
+/// - In `Ingest::Strict` mode, the problems are returned as an error
+///   (one per line)
+/// - In `Ingest::Permissive` mode, the problems are returned as warnings
+///   (instructions with unknown opcodes are kept as extension instructions,
+///   whose other fields aren't checked)
+pub fn check_func_json(
+    func_json: &serde_json::Value,
+    ingest: Ingest,
+) -> Result<Vec<String>, String> {
+    let func_name = func_json["name"].as_str().unwrap_or("?").to_string();
+    let mut problems = vec![];
+    let is_type = |ty: &serde_json::Value| {
+        serde_json::from_value::<Type>(ty.clone()).is_ok()
+    };
+    let unexpected_fields =
+        |json: &serde_json::Value, allowed: &[&str]| -> Vec<String> {
+            json.as_object()
+                .into_iter()
+                .flat_map(|fields| fields.keys())
+                .filter(|field| !allowed.contains(&field.as_str()))
+                .cloned()
+                .collect()
+        };
+
+    for field in unexpected_fields(func_json, &FUNC_FIELDS) {
+        problems.push(format!("@{func_name}: unexpected field `{field}`"));
+    }
+    if let Some(ty) = func_json.get("type")
+        && !is_type(ty)
+    {
+        problems.push(format!("@{func_name}: unknown return type {ty}"));
+    }
+    for arg in func_json["args"].as_array().into_iter().flatten() {
+        let arg_name = arg["name"].as_str().unwrap_or("?");
+        for field in unexpected_fields(arg, &ARG_FIELDS) {
+            problems.push(format!(
+                "@{func_name}: unexpected field `{field}` in parameter \
+                `{arg_name}`"
+            ));
+        }
+        if !is_type(&arg["type"]) {
+            problems.push(format!(
+                "@{func_name}: unknown type {} for parameter `{arg_name}`",
+                arg["type"]
+            ));
+        }
+    }
+
+    for (idx, instr) in func_json["instrs"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let at = format!("@{func_name}: instruction {idx}");
+        for field in unexpected_fields(instr, &INSTR_FIELDS) {
+            problems.push(format!("{at}: unexpected field `{field}`"));
+        }
+        if instr.get("label").is_some() {
+            continue;
+        }
+        if serde_json::from_value::<Opcode>(instr["op"].clone()).is_err() {
+            let kept = match ingest {
+                Ingest::Permissive => " (kept as an extension instruction)",
+                Ingest::Strict => "",
+            };
+            problems
+                .push(format!("{at}: unknown opcode {}{kept}", instr["op"]));
+            continue;
+        }
+        if let Some(ty) = instr.get("type")
+            && !is_type(ty)
+        {
+            problems.push(format!("{at}: unknown type {ty}"));
+        }
+        if let Some(value) = instr.get("value")
+            && !(value.is_i64() || value.is_boolean())
+        {
+            problems.push(format!("{at}: invalid value {value}"));
+        }
+    }
+
+    match ingest {
+        Ingest::Strict if !problems.is_empty() => Err(problems.join("\n")),
+        _ => Ok(problems),
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                  Streaming                                 */
+/* -------------------------------------------------------------------------- */
+
+/// Visits the top-level JSON object of a Bril program, skipping over
+/// everything except the `functions` array
+struct ProgramVisitor<F> {
+    on_func: F,
+}
+
+/// Visits the `functions` array of a Bril program, calling `on_func`
+/// on each function as soon as it has been parsed
+struct FunctionsVisitor<'f, F> {
+    on_func: &'f mut F,
+}
+
+impl<'de, F> Visitor<'de> for ProgramVisitor<F>
+where
+    F: FnMut(serde_json::Value) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a JSON Bril program")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(
+        mut self,
+        mut map: A,
+    ) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "functions" {
+                map.next_value_seed(FunctionsVisitor {
+                    on_func: &mut self.on_func,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de, F> DeserializeSeed<'de> for FunctionsVisitor<'_, F>
+where
+    F: FnMut(serde_json::Value) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, F> Visitor<'de> for FunctionsVisitor<'_, F>
+where
+    F: FnMut(serde_json::Value) -> Result<(), String>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an array of Bril functions")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(func) = seq.next_element::<serde_json::Value>()? {
+            (self.on_func)(func).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a JSON Bril program from `reader` one function at a time,
+/// calling `on_func` on the flattened version of each function.
+/// Unlike parsing the whole program into a `serde_json::Value`, only one
+/// function is held in memory at once, so very large programs can be
+/// flattened in bounded memory.
+/// Each function is checked according to `ingest` before it's flattened
+/// (see `check_func_json`), and any warnings are printed to `stderr`.
+/// (Returns an error if the JSON is malformed, if any function fails
+/// `check_func_json`, `try_flatten_instrs` or `validate_instr_store`,
+/// or if `on_func` fails,
+/// in which case `on_func`'s error is returned as-is.)
+/// - The remaining functions are still checked after one of them fails, so
+///   that the problems with every function are reported at once (one per
+///   line), but `on_func` isn't called again
+pub fn flatten_functions_streaming<R: Read>(
+    reader: R,
+    ingest: Ingest,
+    mut on_func: impl FnMut(InstrStore) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut func_err = None;
+    let mut problems = vec![];
+    let mut func_idx = 0;
+    let visitor = ProgramVisitor {
+        on_func: |func: serde_json::Value| {
+            func_idx += 1;
+            let instr_store =
+                check_func_json(&func, ingest).and_then(|warnings| {
+                    for warning in warnings {
+                        eprintln!("warning: {warning}");
+                    }
+                    let instr_store = try_flatten_instrs(&func)
+                        .map_err(|e| e.in_program(func_idx - 1).to_string())?;
+                    validate_instr_store(&instr_store)?;
+                    Ok(instr_store)
+                });
+            match instr_store {
+                Ok(instr_store) if problems.is_empty() => on_func(instr_store)
+                    .inspect_err(|e| func_err = Some(e.clone())),
+                Ok(_) => Ok(()),
+                Err(problem) => {
+                    problems.push(problem);
+                    Ok(())
+                }
+            }
+        },
+    };
+    let parsed = deserializer
+        .deserialize_map(visitor)
+        .and_then(|()| deserializer.end());
+    match (parsed, func_err) {
+        (Err(_), Some(func_err)) => return Err(func_err),
+        (Err(e), None) => problems.push(format!("unable to parse JSON: {e}")),
+        (Ok(()), _) => (),
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("\n"))
+    }
+}
+
+/// Reads the Bril program at `path` as JSON: `.json` files are parsed
+/// directly, while any other file (e.g. `.bril`) is assumed to be in
+/// Bril's text format & is converted by piping it through `bril2json -p`
+/// (which must be on the `PATH`), so that each instruction has its `pos`
+pub fn read_bril_source(path: &Path) -> Result<serde_json::Value, String> {
+    let display = path.display();
+    let source = fs::read(path)
+        .map_err(|e| format!("unable to read `{display}`: {e}"))?;
+    let json = if path.extension().is_some_and(|ext| ext == "json") {
+        source
+    } else {
+        let mut child = Command::new("bril2json")
+            .arg("-p")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("unable to run `bril2json`: {e}"))?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(&source)
+            .map_err(|e| format!("unable to write to `bril2json`: {e}"))?;
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("unable to run `bril2json`: {e}"))?;
+        if !output.status.success() {
+            return Err(format!(
+                "`bril2json` failed on `{display}`: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        output.stdout
+    };
+    let json: serde_json::Value = serde_json::from_slice(&json)
+        .map_err(|e| format!("invalid JSON in `{display}`: {e}"))?;
+    if !json["functions"].is_array() {
+        return Err(format!("`{display}` has no `functions` array"));
     }
+    Ok(json)
 }
 
 /* -------------------------------------------------------------------------- */
@@ -260,6 +841,7 @@ mod flatten_tests {
     use std::io;
     use std::{fs, fs::File, io::BufReader};
 
+    use crate::flatten::Ingest;
     use crate::types::{InstrStore, Opcode};
 
     // We use `strum` to iterate over every variant in the `Opcode` enum easily
@@ -292,9 +874,20 @@ mod flatten_tests {
         }
     }
 
+    /// Checks that `Opcode::try_from` round-trips every opcode
+    /// and rejects values that don't correspond to an opcode
+    #[test]
+    fn test_opcode_try_from() {
+        for opcode in Opcode::iter() {
+            assert_eq!(Opcode::try_from(opcode as u32), Ok(opcode));
+        }
+        assert!(Opcode::try_from(Opcode::iter().count() as u32).is_err());
+        assert!(Opcode::try_from(u32::MAX).is_err());
+    }
+
     /// Test that for each JSON file in the `test` directory,
     /// its flattened presentation is well-formed
-    /// (i.e. for pairs of indices, the end index is always >= the start index)
+    /// (i.e. each `(offset, len)` pair lies within the store it indexes into)
     #[test]
     fn test_bril_instrs_wf() -> io::Result<()> {
         for entry in fs::read_dir("test")? {
@@ -315,35 +908,312 @@ mod flatten_tests {
                     .expect("Expected `functions` to be a JSON array");
                 let instr_store: InstrStore =
                     flatten::flatten_instrs(&functions[0]);
-                for instr in instr_store.instrs {
-                    if let Some((args_start, args_end)) = instr.args {
-                        assert!(
-                            args_end >= args_start,
-                            "{} >= {} is false",
-                            args_end,
-                            args_start
-                        );
-                    }
-                    if let Some((labels_start, labels_end)) = instr.instr_labels
-                    {
-                        assert!(
-                            labels_end >= labels_start,
-                            "{} >= {} is false",
-                            labels_end,
-                            labels_start
-                        );
-                    }
-                    if let Some((funcs_start, funcs_end)) = instr.funcs {
-                        assert!(
-                            funcs_end >= funcs_start,
-                            "{} >= {} is false",
-                            funcs_end,
-                            funcs_start
-                        );
-                    }
+                let in_bounds = |idxes: Option<(usize, usize)>, len: usize| {
+                    idxes.is_none_or(|(offset, n)| offset + n <= len)
+                };
+                for instr in &instr_store.instrs {
+                    assert!(in_bounds(
+                        instr.args,
+                        instr_store.args_idxes_store.len()
+                    ));
+                    assert!(in_bounds(
+                        instr.instr_labels,
+                        instr_store.labels_idxes_store.len()
+                    ));
+                    assert!(in_bounds(
+                        instr.funcs,
+                        instr_store.funcs_store.len()
+                    ));
                 }
             }
         }
         Ok(())
     }
+
+    /// Test that a jump to a label which the function doesn't define is
+    /// rejected when the function is flattened, naming the missing label
+    /// & the index of the instruction which refers to it
+    #[test]
+    fn test_validate_undefined_label() {
+        let func = |target: &str| {
+            serde_json::json!({ "name": "main", "instrs": [
+                { "op": "const", "dest": "c", "type": "bool", "value": true },
+                { "label": "then" },
+                { "op": "br", "args": ["c"], "labels": ["then", target] },
+            ] })
+        };
+        let store = flatten::flatten_instrs(&func("then"));
+        assert_eq!(flatten::validate_instr_store(&store), Ok(()));
+        let store = flatten::flatten_instrs(&func("missing"));
+        assert_eq!(
+            flatten::validate_instr_store(&store),
+            Err("@main: instruction 2 (`br`) refers to undefined label \
+                .missing"
+                .to_string())
+        );
+    }
+
+    /// Test that instructions with the wrong number of args / labels / funcs
+    /// for their opcode are rejected when the function is flattened
+    #[test]
+    fn test_validate_operand_counts() {
+        let validate = |instr: serde_json::Value| {
+            let func = serde_json::json!({ "name": "main", "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "label": "l" },
+                instr,
+            ] });
+            flatten::validate_instr_store(&flatten::flatten_instrs(&func))
+        };
+        assert_eq!(
+            validate(serde_json::json!({ "op": "add", "dest": "y",
+                "type": "int", "args": ["x", "x", "x"] })),
+            Err("@main: instruction 2 (`add`) takes 2 args, but has 3"
+                .to_string())
+        );
+        assert_eq!(
+            validate(serde_json::json!({ "op": "br", "args": ["x"],
+                "labels": ["l"] })),
+            Err("@main: instruction 2 (`br`) takes 2 labels, but has 1"
+                .to_string())
+        );
+        assert_eq!(
+            validate(serde_json::json!({ "op": "ret", "args": ["x", "x"] })),
+            Err(
+                "@main: instruction 2 (`ret`) takes at most 1 arg, but has 2"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            validate(serde_json::json!({ "op": "call", "args": ["x"] })),
+            Err("@main: instruction 2 (`call`) takes 1 function, but has 0"
+                .to_string())
+        );
+        assert_eq!(
+            validate(serde_json::json!({ "op": "id", "args": ["x"] })),
+            Err("@main: instruction 2 (`id`) has no dest".to_string())
+        );
+        assert_eq!(
+            validate(serde_json::json!({ "op": "print", "args": ["x", "x"] })),
+            Ok(())
+        );
+    }
+
+    /// Test that a function whose parameters share a name is rejected
+    /// (rather than being flattened into a file that doesn't load)
+    #[test]
+    fn test_validate_duplicate_params() {
+        let json = serde_json::json!({ "functions": [{
+            "name": "f",
+            "args": [
+                { "name": "n", "type": "int" },
+                { "name": "m", "type": "int" },
+                { "name": "n", "type": "bool" }
+            ],
+            "instrs": [{ "op": "print", "args": ["n", "m"] }]
+        }] });
+        assert_eq!(
+            crate::memfile::flatten_to_bytes(&json.to_string()),
+            Err("@f: parameter `n` is declared more than once".to_string())
+        );
+    }
+
+    /// Test that every malformed instruction in a program is reported
+    /// (one per line), rather than just the first one
+    #[test]
+    fn test_validate_reports_every_problem() {
+        let json = serde_json::json!({ "functions": [
+            { "name": "main", "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "add", "dest": "y", "type": "int", "args": ["x"] },
+                { "op": "jmp", "labels": ["missing"] },
+            ] },
+            { "name": "f", "instrs": [{ "op": "id", "args": ["x"] }] },
+        ] });
+        assert_eq!(
+            crate::memfile::flatten_to_bytes(&json.to_string()),
+            Err("@main: instruction 1 (`add`) takes 2 args, but has 1\n\
+                @main: instruction 2 (`jmp`) refers to undefined label \
+                .missing\n\
+                @f: instruction 0 (`id`) has no dest"
+                .to_string())
+        );
+    }
+
+    /// Test that malformed fields are errors in strict mode (all of which
+    /// are reported), but only warnings in permissive mode (which keeps
+    /// unknown opcodes)
+    #[test]
+    fn test_check_func_json() {
+        let func = serde_json::json!({ "name": "main", "instrs": [
+            { "op": "const", "dest": "x", "type": "float", "value": 1 },
+            { "op": "fadd", "dest": "y", "type": "int", "args": ["x"] },
+            { "op": "print", "args": ["x"], "extra": true },
+        ] });
+        assert_eq!(
+            flatten::check_func_json(&func, Ingest::Strict),
+            Err("@main: instruction 0: unknown type \"float\"\n\
+                @main: instruction 1: unknown opcode \"fadd\"\n\
+                @main: instruction 2: unexpected field `extra`"
+                .to_string())
+        );
+        assert_eq!(
+            flatten::check_func_json(&func, Ingest::Permissive),
+            Ok(vec![
+                "@main: instruction 0: unknown type \"float\"".to_string(),
+                "@main: instruction 1: unknown opcode \"fadd\" (kept as an \
+                extension instruction)"
+                    .to_string(),
+                "@main: instruction 2: unexpected field `extra`".to_string(),
+            ])
+        );
+    }
+
+    /// Test that malformed functions are reported with the path of the
+    /// offending element, instead of panicking
+    #[test]
+    fn test_flatten_error_paths() {
+        let error = |func: serde_json::Value| {
+            flatten::try_flatten_instrs(&func)
+                .map(|_| ())
+                .map_err(|e| e.in_program(2).to_string())
+        };
+        assert_eq!(
+            error(serde_json::json!({ "name": "main", "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "const", "dest": "y", "type": "int" },
+            ] })),
+            Err("functions[2].instrs[1].value: expected an int or a bool \
+                (in @main)"
+                .to_string())
+        );
+        assert_eq!(
+            error(serde_json::json!({ "name": "main", "instrs": [
+                { "dest": "x", "type": "int" },
+            ] })),
+            Err("functions[2].instrs[0].op: expected a non-empty string, \
+                but found null (in @main)"
+                .to_string())
+        );
+        assert_eq!(
+            error(serde_json::json!({ "name": "main", "instrs": [
+                { "op": "jmp", "labels": [""] },
+            ] })),
+            Err("functions[2].instrs[0].labels[0]: expected a non-empty \
+                string, but found \"\" (in @main)"
+                .to_string())
+        );
+        assert_eq!(
+            error(serde_json::json!({ "instrs": [] })),
+            Err("functions[2].name: expected a non-empty string, but found \
+                null"
+                .to_string())
+        );
+    }
+
+    /// Empty `args` & `labels` lists are stored as `(offset, 0)` (so they're
+    /// unflattened as `[]`, for every kind of instruction), missing ones as
+    /// `None` (so they're left out), & an empty `funcs` list is the same as
+    /// a missing one
+    #[test]
+    fn test_empty_lists() {
+        use crate::memfile::{
+            AlignedBytes, get_flat_program, json_to_fbril_bytes,
+        };
+        use crate::unflatten;
+
+        let func = serde_json::json!({
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "print", "args": [] },
+                { "op": "print", "args": [], "labels": [], "funcs": [] },
+                { "op": "nop", "args": [], "labels": [] },
+                { "op": "const", "dest": "y", "type": "int", "value": 2,
+                  "args": [] },
+                { "op": "ret" },
+            ]
+        });
+        let store = flatten::flatten_instrs(&func);
+        let [x, print, print_labels, nop, y, ret] = &store.instrs[..] else {
+            panic!("expected 6 instrs");
+        };
+        assert_eq!((x.args, x.instr_labels), (None, None));
+        assert_eq!(print.args, Some((0, 0)));
+        assert_eq!(
+            (
+                print_labels.args,
+                print_labels.instr_labels,
+                print_labels.funcs
+            ),
+            (Some((0, 0)), Some((0, 0)), None)
+        );
+        assert_eq!((nop.args, nop.instr_labels), (Some((0, 0)), Some((0, 0))));
+        assert_eq!((y.args, y.instr_labels), (Some((0, 0)), None));
+        assert_eq!((ret.args, ret.instr_labels), (None, None));
+
+        // (the lists are the same after a round trip through a flat file)
+        let data = AlignedBytes::new(&json_to_fbril_bytes(
+            &serde_json::json!({ "functions": [func] }),
+        ));
+        let program = get_flat_program(&data).expect("valid file should load");
+        let from_file = InstrStore::from(program.views()[0].clone());
+        for store in [store, from_file] {
+            let instrs = &unflatten::unflatten_instrs(&store)["instrs"];
+            assert_eq!(instrs[0], func["instrs"][0]);
+            assert_eq!(
+                instrs[1],
+                serde_json::json!({ "op": "print", "args": [] })
+            );
+            assert_eq!(
+                instrs[2],
+                serde_json::json!({ "op": "print", "args": [], "labels": [] })
+            );
+            assert_eq!(instrs[3], func["instrs"][3]);
+            assert_eq!(instrs[4], func["instrs"][4]);
+            assert_eq!(instrs[5], serde_json::json!({ "op": "ret" }));
+        }
+
+        let error = flatten::try_flatten_instrs(&serde_json::json!({
+            "name": "main",
+            "instrs": [{ "op": "call", "funcs": ["f", "g"] }]
+        }))
+        .map(|_| ())
+        .map_err(|e| e.to_string());
+        assert!(error.unwrap_err().contains("expected at most one function"));
+    }
+
+    /// Checks that (nested) pointer types survive flattening & unflattening,
+    /// and that their flat encoding is decoded back to the same `Type`
+    #[test]
+    fn test_pointer_types() {
+        use crate::types::{BaseType, FlatType, Type};
+        use crate::unflatten;
+
+        let ptr_ptr_int = serde_json::json!({ "ptr": { "ptr": "int" } });
+        let ty: Type = serde_json::from_value(ptr_ptr_int.clone()).unwrap();
+        assert_eq!(
+            ty,
+            Type::Ptr {
+                pointee: BaseType::Int,
+                depth: 2
+            }
+        );
+        assert_eq!(ty.to_string(), "ptr<ptr<int>>");
+        assert_eq!(Type::try_from(FlatType::from(ty)), Ok(ty));
+
+        let func = serde_json::json!({
+            "name": "deref",
+            "args": [{ "name": "p", "type": ptr_ptr_int }],
+            "type": { "ptr": "bool" },
+            "instrs": [
+                { "op": "id", "dest": "q", "type": ptr_ptr_int, "args": ["p"] },
+            ]
+        });
+        let func_json =
+            unflatten::unflatten_instrs(&flatten::flatten_instrs(&func));
+        assert_eq!(func_json["args"], func["args"]);
+        assert_eq!(func_json["type"], func["type"]);
+        assert_eq!(func_json["instrs"][0]["type"], ptr_ptr_int);
+    }
 }
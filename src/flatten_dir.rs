@@ -0,0 +1,249 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::flatten::read_bril_source;
+use crate::memfile::try_json_to_fbril_bytes;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// What happened to a single source file in `flatten_dir`
+/// - `Skipped`: the file isn't a Bril program (e.g. a JSON manifest), or
+///   another source file is flattened to the same `.fbril` file
+///   (e.g. `foo.bril` when there's also a `foo.json`)
+/// - `Failed`: the file couldn't be converted / flattened / written
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Converted,
+    Skipped(String),
+    Failed(String),
+}
+
+/// The result of converting a single source file
+/// (`output` is the `.fbril` file that it's flattened to)
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileResult {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub outcome: Outcome,
+}
+
+/// The results for every `.json` / `.bril` file found by `flatten_dir`
+/// (in the order given by `find_sources`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlattenDirReport {
+    pub results: Vec<FileResult>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Collects the `.json` & `.bril` files in `dir` & its subdirectories
+/// (except for `exclude`, i.e. the output directory), sorted by path
+/// (a `.json` file comes before a `.bril` file with the same name)
+fn find_sources(
+    dir: &Path,
+    exclude: Option<&Path>,
+    sources: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("unable to read `{}`: {e}", dir.display()))?;
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(|e| format!("unable to read `{}`: {e}", dir.display()))?;
+    paths.sort_by_key(|path| {
+        (
+            path.with_extension(""),
+            path.extension() != Some("json".as_ref()),
+        )
+    });
+    for path in paths {
+        if path.is_dir() {
+            if exclude != path.canonicalize().ok().as_deref() {
+                find_sources(&path, exclude, sources)?;
+            }
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "json" || ext == "bril")
+        {
+            sources.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Flattens the program at `source` into the `.fbril` file `output`
+fn flatten_file(source: &Path, output: &Path) -> Outcome {
+    let json = match read_bril_source(source) {
+        Ok(json) => json,
+        // (JSON files which aren't Bril programs are just skipped)
+        Err(msg) if msg.ends_with("has no `functions` array") => {
+            return Outcome::Skipped("not a Bril program".to_string());
+        }
+        Err(msg) => return Outcome::Failed(msg),
+    };
+    // (every problem with the program is reported on the file's line)
+    let bytes = match try_json_to_fbril_bytes(&json) {
+        Ok(bytes) => bytes,
+        Err(msg) => {
+            return Outcome::Failed(format!(
+                "unable to flatten program: {}",
+                msg.replace('\n', "; ")
+            ));
+        }
+    };
+    let written = output
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(output, bytes));
+    match written {
+        Ok(()) => Outcome::Converted,
+        Err(e) => Outcome::Failed(format!(
+            "unable to write `{}`: {e}",
+            output.display()
+        )),
+    }
+}
+
+/// Flattens every `.json` & `.bril` file (see `read_bril_source`) in
+/// `in_dir` & its subdirectories into an `.fbril` file in `out_dir`, at the
+/// same path relative to `out_dir` as the source file is to `in_dir`
+/// (e.g. `in_dir/core/fact.bril` becomes `out_dir/core/fact.fbril`).
+/// Returns an error only if `in_dir` can't be read: failures to convert
+/// individual files are recorded in the report.
+pub fn flatten_dir(
+    in_dir: &Path,
+    out_dir: &Path,
+) -> Result<FlattenDirReport, String> {
+    let mut sources = vec![];
+    let exclude = out_dir.canonicalize().ok();
+    find_sources(in_dir, exclude.as_deref(), &mut sources)?;
+
+    let mut results: Vec<FileResult> = vec![];
+    for source in sources {
+        let relative = source.strip_prefix(in_dir).unwrap_or(&source);
+        let output = out_dir.join(relative).with_extension("fbril");
+        // (`foo.json` comes before `foo.bril`, so JSON files win)
+        let duplicate = results.iter().find(|result| {
+            result.output == output
+                && !matches!(result.outcome, Outcome::Skipped(_))
+        });
+        let outcome = match duplicate {
+            Some(result) => Outcome::Skipped(format!(
+                "`{}` is flattened to the same file",
+                result.source.display()
+            )),
+            None => flatten_file(&source, &output),
+        };
+        results.push(FileResult {
+            source,
+            output,
+            outcome,
+        });
+    }
+    Ok(FlattenDirReport { results })
+}
+
+impl FlattenDirReport {
+    /// The no. of files which were converted, skipped & failed (in that order)
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let count = |pred: fn(&Outcome) -> bool| {
+            self.results.iter().filter(|r| pred(&r.outcome)).count()
+        };
+        (
+            count(|outcome| matches!(outcome, Outcome::Converted)),
+            count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+            count(|outcome| matches!(outcome, Outcome::Failed(_))),
+        )
+    }
+
+    /// Renders the report as plain text: one line per file which wasn't
+    /// converted (with the reason why), followed by a summary
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for result in &self.results {
+            let (status, reason) = match &result.outcome {
+                Outcome::Converted => continue,
+                Outcome::Skipped(reason) => ("skipped", reason),
+                Outcome::Failed(reason) => ("FAILED", reason),
+            };
+            writeln!(text, "{status:<8} {}: {reason}", result.source.display())
+                .unwrap();
+        }
+        let (converted, skipped, failed) = self.counts();
+        writeln!(
+            text,
+            "{converted} converted, {skipped} skipped, {failed} failed"
+        )
+        .unwrap();
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod flatten_dir_tests {
+    use std::fs;
+
+    use crate::flatten_dir::{Outcome, flatten_dir};
+
+    /// Relative paths are preserved, non-Bril JSON files & duplicates are
+    /// skipped, & malformed files are reported without stopping the batch
+    #[test]
+    fn test_flatten_dir() {
+        let root = std::env::temp_dir()
+            .join(format!("flat-bril-flatten-dir-{}", std::process::id()));
+        let (in_dir, out_dir) = (root.join("in"), root.join("out"));
+        fs::create_dir_all(in_dir.join("nested")).unwrap();
+        fs::copy("test/fact.json", in_dir.join("nested/fact.json")).unwrap();
+        fs::copy("test/gcd.json", in_dir.join("gcd.json")).unwrap();
+        fs::write(in_dir.join("gcd.bril"), "@main {}").unwrap();
+        fs::write(in_dir.join("manifest.json"), "{\"programs\": []}").unwrap();
+        fs::write(in_dir.join("broken.json"), "{ not json").unwrap();
+        fs::write(
+            in_dir.join("nested/bad_call.json"),
+            r#"{ "functions": [{ "name": "main", "instrs": [
+                { "op": "call", "args": ["x"] }
+            ] }] }"#,
+        )
+        .unwrap();
+        fs::write(in_dir.join("notes.txt"), "ignored").unwrap();
+
+        let report = flatten_dir(&in_dir, &out_dir).expect("directory exists");
+        let outcomes: Vec<(String, &Outcome)> = report
+            .results
+            .iter()
+            .map(|result| {
+                let relative = result.output.strip_prefix(&out_dir).unwrap();
+                (relative.display().to_string(), &result.outcome)
+            })
+            .collect();
+        assert_eq!(outcomes.len(), 6);
+        assert!(matches!(outcomes[0], (_, Outcome::Failed(_))));
+        assert_eq!(outcomes[1], ("gcd.fbril".into(), &Outcome::Converted));
+        assert!(matches!(outcomes[2], (_, Outcome::Skipped(_))));
+        assert_eq!(
+            outcomes[3].1,
+            &Outcome::Skipped("not a Bril program".into())
+        );
+        let Outcome::Failed(reason) = outcomes[4].1 else {
+            panic!("a malformed program isn't flattened");
+        };
+        assert!(reason.starts_with("unable to flatten program: "));
+        assert!(!out_dir.join("nested/bad_call.fbril").exists());
+        assert_eq!(
+            outcomes[5],
+            ("nested/fact.fbril".into(), &Outcome::Converted)
+        );
+        assert!(out_dir.join("nested/fact.fbril").exists());
+        assert_eq!(report.counts(), (2, 2, 2));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+use crate::interp::{get_n_args, get_var};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// Superinstructions, i.e. pairs of adjacent instructions which the
+/// interpreter executes in a single dispatch (see `fuse_instrs`)
+/// - `ConstBinop`: a `const` followed by a binary operation
+/// - `CmpBr`: a comparison followed by a `br` on its result
+/// - `IdPrint`: an `id` followed by a `print`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusedOp {
+    ConstBinop = 1,
+    CmpBr = 2,
+    IdPrint = 3,
+}
+
+/// Fused opcodes have this bit set. They only exist in memory (the loader
+/// rejects them), so they never clash with the opcodes in `.fbril` files.
+/// (This bit is low enough that fused opcodes fit in the `u16` opcodes of
+/// compact-format files, see `OpWord`.)
+pub const FUSED_OP_FLAG: u32 = 1 << 15;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl FusedOp {
+    /// The opcode of a superinstruction whose first instruction is a `first`
+    /// (the second instruction is left untouched, so its opcode is kept)
+    pub fn encode(self, first: Opcode) -> u32 {
+        FUSED_OP_FLAG | (self as u32) << 8 | first as u32
+    }
+
+    /// Splits a fused opcode into the kind of superinstruction & the opcode
+    /// of its first instruction (returns `None` if `op` isn't fused)
+    pub fn decode(op: u32) -> Option<(Self, Opcode)> {
+        if op == u32::MAX || op & FUSED_OP_FLAG == 0 {
+            return None;
+        }
+        let fused = match (op >> 8) & 0x7f {
+            1 => FusedOp::ConstBinop,
+            2 => FusedOp::CmpBr,
+            3 => FusedOp::IdPrint,
+            _ => return None,
+        };
+        Some((fused, Opcode::u32_to_opcode(op & 0xff)?))
+    }
+}
+
+/// The superinstruction (if any) formed by the instructions at `pc` & `pc + 1`
+fn fusable_pair<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    instrs: &[FlatInstr<P>],
+    pc: usize,
+) -> Option<FusedOp> {
+    use Opcode::*;
+    let (first, second) = (instrs.get(pc)?, instrs.get(pc + 1)?);
+    match (
+        Opcode::u32_to_opcode(first.op())?,
+        Opcode::u32_to_opcode(second.op())?,
+    ) {
+        (Const, op) if op.is_binop() => Some(FusedOp::ConstBinop),
+        (Eq | Lt | Gt | Le | Ge, Br) => {
+            // The `br` must branch on the result of the comparison
+            let (dest_offset, dest_len) = first.dest.get()?;
+            let [cond] = get_n_args(instr_view, second.args)?;
+            (get_var(instr_view, dest_offset, dest_len) == cond)
+                .then_some(FusedOp::CmpBr)
+        }
+        (Id, Print) => Some(FusedOp::IdPrint),
+        _ => None,
+    }
+}
+
+/// Fuses common pairs of adjacent instructions in `instr_view` into
+/// superinstructions, returning the new instructions (use `with_instrs`
+/// to interpret them). Each pair becomes a single instruction with a fused
+/// opcode (see `FusedOp`) at the PC of its first instruction, which the
+/// interpreter executes along with the instruction after it, so PCs
+/// (and hence labels & errors) are unaffected.
+/// - Pairs are fused greedily from the start of the function, except that
+///   a comparison is left for a `cmp + br` pair rather than being fused with
+///   the `const` before it (since `cmp + br` saves the most work)
+/// - Instructions on either side of a label are never fused, so the second
+///   instruction of a pair can't be the target of a jump
+pub fn fuse_instrs<P: IndexPair>(
+    instr_view: &InstrView<P>,
+) -> Vec<FlatInstr<P>> {
+    let mut instrs = instr_view.instrs.to_vec();
+    let mut pc = 0;
+    while pc < instrs.len() {
+        let fused = match fusable_pair(instr_view, &instrs, pc) {
+            _ if !instr_view.labels_at(pc + 1).is_empty() => None,
+            Some(FusedOp::ConstBinop)
+                if fusable_pair(instr_view, &instrs, pc + 1)
+                    == Some(FusedOp::CmpBr) =>
+            {
+                None
+            }
+            fused => fused,
+        };
+        match fused {
+            Some(fused) => {
+                let first = Opcode::u32_to_opcode(instrs[pc].op())
+                    .expect("only instructions with opcodes are fused");
+                instrs[pc].set_op(fused.encode(first));
+                pc += 2;
+            }
+            None => pc += 1,
+        }
+    }
+    instrs
+}
+
+/// A copy of `instr_view` whose instructions are `instrs`
+/// (e.g. the superinstructions produced by `fuse_instrs`)
+pub fn with_instrs<'a, P: IndexPair>(
+    instr_view: &InstrView<'a, P>,
+    instrs: &'a [FlatInstr<P>],
+) -> InstrView<'a, P> {
+    InstrView {
+        instrs,
+        ..instr_view.clone()
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod fusion_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::fusion::{FusedOp, fuse_instrs, with_instrs};
+    use crate::interp::interp_program_captured;
+    use crate::memfile::{
+        AlignedBytes, FlatProgram, get_flat_program, json_to_fbril_bytes,
+    };
+    use crate::types::FlatInstr;
+
+    /// Fusing a program's instructions doesn't change its output or its
+    /// dynamic instruction count
+    #[test]
+    fn test_fused_program_behaves_the_same() {
+        for (name, args) in [
+            ("check-primes", vec!["50"]),
+            ("fizz-buzz", vec!["30"]),
+            ("sum-check", vec!["100"]),
+            ("pythagorean_triple", vec!["30"]),
+        ] {
+            let file = File::open(format!("test/{name}.json"))
+                .expect("Unable to open file");
+            let json: serde_json::Value =
+                serde_json::from_reader(BufReader::new(file))
+                    .expect("Unable to parse JSON");
+            let bytes = json_to_fbril_bytes(&json);
+            let data = AlignedBytes::new(&bytes);
+            let program =
+                get_flat_program(&data).expect("valid file should load");
+
+            let fused: Vec<Vec<FlatInstr>> =
+                program.views().iter().map(fuse_instrs).collect();
+            let num_fused = fused
+                .iter()
+                .flatten()
+                .filter(|instr| FusedOp::decode(instr.op).is_some())
+                .count();
+            assert!(num_fused > 0, "{name}: nothing was fused");
+            let fused_program = FlatProgram::new(
+                program
+                    .views()
+                    .iter()
+                    .zip(&fused)
+                    .map(|(view, instrs)| with_instrs(view, instrs))
+                    .collect(),
+            );
+
+            let expected = interp_program_captured(&program, args.clone())
+                .expect("program should run");
+            let actual = interp_program_captured(&fused_program, args)
+                .expect("fused program should run");
+            assert_eq!(actual, expected, "{name}");
+        }
+    }
+}
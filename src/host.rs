@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::types::BrilValue;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A native function which Bril programs can `call`: it's passed the values
+/// of the call's args, & returns the call's result (`None` for a function
+/// which doesn't return a value), or an error message
+pub type HostFn =
+    Box<dyn FnMut(&[BrilValue]) -> Result<Option<BrilValue>, String>>;
+
+/// The host functions which have been registered with an `InterpContext`
+/// (see `InterpContext::with_host_fn`), by name
+/// - A `call` to a function which the program doesn't define is dispatched
+///   to the host function with that name (so the program's own functions
+///   take precedence)
+/// - Host calls count as a single step, don't count towards the call depth
+///   & aren't reported to observers. They're only supported by the
+///   recursive interpreter (not by `scheduler::Task`).
+#[derive(Default)]
+pub struct HostFns {
+    funcs: HashMap<String, HostFn>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl HostFns {
+    /// Registers `func` under `name` (replacing any existing host function
+    /// with that name)
+    pub fn register(
+        &mut self,
+        name: &str,
+        func: impl FnMut(&[BrilValue]) -> Result<Option<BrilValue>, String>
+        + 'static,
+    ) {
+        self.funcs.insert(name.to_string(), Box::new(func));
+    }
+
+    /// Whether no host functions have been registered
+    pub fn is_empty(&self) -> bool {
+        self.funcs.is_empty()
+    }
+
+    /// Whether there's a host function called `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.funcs.contains_key(name)
+    }
+
+    /// Calls the host function `name` with `args`
+    /// (returns an error if there's no such function, or if it fails)
+    pub fn call(
+        &mut self,
+        name: &str,
+        args: &[BrilValue],
+    ) -> Result<Option<BrilValue>, Diagnostic> {
+        let func = self.funcs.get_mut(name).ok_or_else(|| {
+            Diagnostic::new(
+                ErrorCode::UndefinedFunction,
+                format!("call to undefined function @{name}"),
+            )
+        })?;
+        func(args).map_err(|msg| {
+            Diagnostic::new(
+                ErrorCode::HostError,
+                format!("host function @{name} failed: {msg}"),
+            )
+        })
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod host_tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use crate::diagnostic::ErrorCode;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::types::BrilValue;
+
+    /// Calls to functions which the program doesn't define are dispatched to
+    /// the registered host functions, with their args & results marshalled
+    /// as `BrilValue`s
+    #[test]
+    fn test_host_fns() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "a", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "b", "type": "int", "value": 5 },
+                { "op": "call", "dest": "c", "type": "int",
+                  "funcs": ["host_add"], "args": ["a", "b"] },
+                { "op": "call", "funcs": ["log"], "args": ["c"] },
+                { "op": "print", "args": ["c"] }
+            ]
+        }]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+
+        let logged = Rc::new(RefCell::new(vec![]));
+        let log = Rc::clone(&logged);
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_host_fn("host_add", |args| match args {
+                [BrilValue::IntVal(x), BrilValue::IntVal(y)] => {
+                    Ok(Some(BrilValue::IntVal(x + y)))
+                }
+                _ => Err("expected two ints".to_string()),
+            })
+            .with_host_fn("log", move |args| {
+                log.borrow_mut().extend_from_slice(args);
+                Ok(None)
+            });
+        interp_program(&program, vec!["3"], &mut ctx).unwrap();
+        assert_eq!(String::from_utf8_lossy(&ctx.out), "8\n");
+        assert_eq!(*logged.borrow(), vec![BrilValue::IntVal(8)]);
+
+        // (errors from host functions are reported at the `call`)
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_host_fn("host_add", |_| Err("out of fuel".to_string()));
+        let error = interp_program(&program, vec!["3"], &mut ctx).unwrap_err();
+        assert_eq!(error.code, ErrorCode::HostError);
+        assert_eq!(error.pc, Some(1));
+        assert!(error.message.contains("out of fuel"));
+
+        // (as are results of the wrong type)
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_host_fn("host_add", |_| {
+                Ok(Some(BrilValue::BoolVal(true.into())))
+            })
+            .with_host_fn("log", |_| Ok(None));
+        let error = interp_program(&program, vec!["3"], &mut ctx).unwrap_err();
+        assert_eq!(error.code, ErrorCode::TypeError);
+
+        // (without host functions, the calls are undefined)
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        let error = interp_program(&program, vec!["3"], &mut ctx).unwrap_err();
+        assert_eq!(error.code, ErrorCode::UndefinedFunction);
+    }
+}
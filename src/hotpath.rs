@@ -0,0 +1,209 @@
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::profile::{FuncProfile, Profile};
+use crate::program::Program;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A basic block or loop which appears in a `HotPathReport`
+/// - `name` is the name of the block (or of the loop's header), as given by
+///   `Cfg::block_name`
+/// - `entries` is the no. of times the block (or the loop's header) was
+///   entered, i.e. the no. of iterations for a loop
+/// - `steps` is the no. of instructions executed in the block (or in any
+///   block of the loop, excluding the functions it calls), and `share` is
+///   the fraction of the whole run's dynamic instruction count that it
+///   accounts for
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotRegion {
+    pub func: String,
+    pub name: String,
+    pub entries: u64,
+    pub steps: u64,
+    pub share: f64,
+}
+
+/// The (up to `n`) hottest basic blocks & loops of a run, sorted by the no.
+/// of instructions executed in them (hottest first)
+/// - `steps` is the dynamic instruction count of the run (excluding labels)
+#[derive(Debug, Clone, PartialEq)]
+pub struct HotPathReport {
+    pub blocks: Vec<HotRegion>,
+    pub loops: Vec<HotRegion>,
+    pub steps: u64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of (non-label) instructions executed in each block of `cfg`,
+/// according to the counts in `profile`
+fn block_steps(cfg: &Cfg, starts: &[usize], profile: &FuncProfile) -> Vec<u64> {
+    cfg.blocks
+        .iter()
+        .zip(starts)
+        .map(|(block, &start)| {
+            profile
+                .counts
+                .get(start..start + block.instrs.len())
+                .map_or(0, |counts| counts.iter().sum())
+        })
+        .collect()
+}
+
+impl HotPathReport {
+    /// Finds the `n` hottest blocks & loops of `program` during the run
+    /// recorded in `profile` (`program` must be the program that was
+    /// profiled)
+    pub fn new<P: IndexPair>(
+        program: &[InstrView<P>],
+        profile: &Profile,
+        n: usize,
+    ) -> Self {
+        let mut blocks = vec![];
+        let mut loops = vec![];
+        for func in Program::new(program).functions() {
+            let Some(func_profile) = profile.functions.get(func.name()) else {
+                continue;
+            };
+            let cfg = Cfg::new(func);
+            let starts = cfg.block_start_pcs();
+            let steps = block_steps(&cfg, &starts, func_profile);
+            let entries = |b: usize| {
+                func_profile.counts.get(starts[b]).copied().unwrap_or(0)
+            };
+            let region = |b: usize, steps: u64| HotRegion {
+                func: cfg.name.clone(),
+                name: cfg.block_name(b),
+                entries: entries(b),
+                steps,
+                share: 0.0,
+            };
+            blocks.extend(
+                (0..cfg.blocks.len())
+                    .filter(|&b| steps[b] > 0)
+                    .map(|b| region(b, steps[b])),
+            );
+            for l in cfg.natural_loops(&cfg.dominators()) {
+                let loop_steps = l.body.iter().map(|&b| steps[b]).sum();
+                if loop_steps > 0 {
+                    loops.push(region(l.header, loop_steps));
+                }
+            }
+        }
+
+        let steps: u64 = blocks.iter().map(|block| block.steps).sum();
+        for regions in [&mut blocks, &mut loops] {
+            // (ties are broken by program order, since the sort is stable)
+            regions.sort_by_key(|region| Reverse(region.steps));
+            regions.truncate(n);
+            for region in regions.iter_mut() {
+                region.share = region.steps as f64 / steps as f64;
+            }
+        }
+        Self {
+            blocks,
+            loops,
+            steps,
+        }
+    }
+
+    /// Renders the report as a table, similar to `perf report`
+    pub fn to_text(&self) -> String {
+        let mut text =
+            format!("hot paths ({} instructions executed):\n", self.steps);
+        for (title, regions) in
+            [("blocks", &self.blocks), ("loops", &self.loops)]
+        {
+            writeln!(
+                text,
+                "  {:>6}  {:>10}  {:>10}  {title}",
+                "share", "instrs", "entries"
+            )
+            .unwrap();
+            for region in regions {
+                writeln!(
+                    text,
+                    "  {:>5.1}%  {:>10}  {:>10}  @{} {}",
+                    region.share * 100.0,
+                    region.steps,
+                    region.entries,
+                    region.func,
+                    region.name
+                )
+                .unwrap();
+            }
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod hotpath_tests {
+    use serde_json::json;
+
+    use crate::hotpath::{HotPathReport, HotRegion};
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::profile::Profiler;
+
+    /// The body of a loop is its hottest block, & the loop accounts for
+    /// all the instructions executed in its blocks
+    #[test]
+    fn test_hot_path_report() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "n", "type": "int", "value": 4 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "c", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["c"] },
+                { "label": "body" },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "print", "args": ["i"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "print", "args": ["n"] }
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let mut profiler = Profiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec![], &mut ctx).expect("program runs");
+        let report =
+            HotPathReport::new(program.views(), &profiler.profile(), 2);
+
+        // 3 (entry) + 5 * 2 (loop) + 4 * 3 (body) + 1 (done)
+        assert_eq!(report.steps, 26);
+        let region = |name: &str, entries, steps| HotRegion {
+            func: "main".to_string(),
+            name: name.to_string(),
+            entries,
+            steps,
+            share: steps as f64 / 26.0,
+        };
+        assert_eq!(
+            report.blocks,
+            [region(".body", 4, 12), region(".loop", 5, 10)]
+        );
+        assert_eq!(report.loops, [region(".loop", 5, 22)]);
+    }
+}
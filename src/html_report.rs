@@ -0,0 +1,224 @@
+use std::fmt::Write;
+
+use crate::coverage::CoverageReport;
+use crate::debugger::format_instr;
+use crate::hotpath::HotPathReport;
+use crate::interp::{get_func_name, get_label_name};
+use crate::profile::Profile;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of blocks listed as hot blocks at the top of an HTML report
+pub const NUM_HOT_BLOCKS: usize = 10;
+
+/// The stylesheet embedded in every HTML report (reports are
+/// self-contained, so that they can be opened without any other files)
+/// - An instruction's background is shaded by how often it was executed
+///   relative to the hottest instruction (see `heat_style`), and
+///   instructions which were never executed are greyed out
+const STYLE: &str = "\
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { padding: 0.15em 0.8em; text-align: left; }
+th { border-bottom: 1px solid #888; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+table.listing td { font-family: monospace; white-space: pre; }
+tr.label td { color: #0a5; font-weight: bold; }
+tr.cold td { color: #aaa; }
+h2 { margin-top: 1.5em; font-family: monospace; }";
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Escapes the characters which are special in HTML
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The inline style which shades an instruction executed `count` times,
+/// where the hottest instruction was executed `max_count` times
+fn heat_style(count: u64, max_count: u64) -> String {
+    if count == 0 || max_count == 0 {
+        return String::new();
+    }
+    let heat = count as f64 / max_count as f64;
+    format!(
+        " style=\"background: rgba(255, 90, 0, {:.2})\"",
+        0.1 + heat * 0.6
+    )
+}
+
+/// Renders a self-contained HTML page showing the run of `program` recorded
+/// in `profile` (`program` must be the program that was profiled):
+/// - A summary of each function (the no. of instructions executed in it, &
+///   the fraction of its instructions that were executed at all)
+/// - The `NUM_HOT_BLOCKS` hottest blocks (see `HotPathReport`)
+/// - The disassembled program, with the no. of times each instruction was
+///   executed, shaded by how hot it is
+pub fn render_html_report<P: IndexPair>(
+    program: &[InstrView<P>],
+    profile: &Profile,
+) -> String {
+    let coverage = CoverageReport::new(program, profile);
+    let hot_paths = HotPathReport::new(program, profile, NUM_HOT_BLOCKS);
+    let count_at = |func: &str, pc: usize| {
+        profile
+            .functions
+            .get(func)
+            .and_then(|func| func.counts.get(pc))
+            .copied()
+            .unwrap_or(0)
+    };
+    let max_count = profile
+        .functions
+        .values()
+        .flat_map(|func| func.counts.iter().copied())
+        .max()
+        .unwrap_or(0);
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>flat-bril profile</title>\n<style>\n{STYLE}\n</style>\n\
+        </head>\n<body>\n<h1>flat-bril profile</h1>\n\
+        <p>{} instructions executed</p>\n",
+        hot_paths.steps
+    );
+
+    html.push_str(
+        "<table>\n<tr><th>function</th><th>instrs executed</th>\
+        <th>share</th><th>coverage</th></tr>\n",
+    );
+    for func in &coverage.functions {
+        let steps: u64 = profile
+            .functions
+            .get(&func.name)
+            .map_or(0, |profile| profile.counts.iter().sum());
+        let share = if hot_paths.steps == 0 {
+            0.0
+        } else {
+            steps as f64 / hot_paths.steps as f64 * 100.0
+        };
+        let covered = if func.total == 0 {
+            100.0
+        } else {
+            func.executed as f64 / func.total as f64 * 100.0
+        };
+        writeln!(
+            html,
+            "<tr><td><a href=\"#{name}\">@{name}</a></td>\
+            <td class=\"num\">{steps}</td><td class=\"num\">{share:.1}%</td>\
+            <td class=\"num\">{}/{} ({covered:.1}%)</td></tr>",
+            func.executed,
+            func.total,
+            name = escape(&func.name)
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+
+    html.push_str(
+        "<h2>hot blocks</h2>\n<table>\n<tr><th>block</th><th>entries</th>\
+        <th>instrs executed</th><th>share</th></tr>\n",
+    );
+    for block in &hot_paths.blocks {
+        writeln!(
+            html,
+            "<tr><td>@{} {}</td><td class=\"num\">{}</td>\
+            <td class=\"num\">{}</td><td class=\"num\">{:.1}%</td></tr>",
+            escape(&block.func),
+            escape(&block.name),
+            block.entries,
+            block.steps,
+            block.share * 100.0
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+
+    for view in program {
+        let name = get_func_name(view);
+        writeln!(
+            html,
+            "<h2 id=\"{name}\">@{name}</h2>\n<table class=\"listing\">",
+            name = escape(name)
+        )
+        .unwrap();
+        // (each label comes right before the instruction at its PC)
+        for pc in 0..=view.instrs.len() {
+            for label in view.labels_at(pc) {
+                let (offset, len) = label.label_idxes.idxes();
+                writeln!(
+                    html,
+                    "<tr class=\"label\"><td></td><td></td><td>.{}:</td></tr>",
+                    escape(get_label_name(view, offset, len))
+                )
+                .unwrap();
+            }
+            let Some(instr) = view.instrs.get(pc) else {
+                continue;
+            };
+            let count = count_at(name, pc);
+            writeln!(
+                html,
+                "<tr{}{}><td class=\"num\">{count}</td>\
+                <td class=\"num\">{pc}</td><td>  {}</td></tr>",
+                if count == 0 { " class=\"cold\"" } else { "" },
+                heat_style(count, max_count),
+                escape(&format_instr(view, instr))
+            )
+            .unwrap();
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod html_report_tests {
+    use crate::html_report::render_html_report;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+    use crate::profile::Profiler;
+
+    /// Every function is summarized & listed with its labels, and
+    /// instructions that were never executed are greyed out
+    #[test]
+    fn test_html_report() {
+        let json_str = std::fs::read_to_string("test/gcd.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let mut profiler = Profiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec!["4", "4"], &mut ctx)
+            .expect("program should run");
+
+        let html = render_html_report(program.views(), &profiler.profile());
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.ends_with("</html>\n"));
+        assert!(html.contains("<a href=\"#main\">@main</a>"));
+        assert!(html.contains("<h2 id=\"main\">@main</h2>"));
+        assert!(html.contains("<td>.if.1:</td>"));
+        assert!(html.contains("<td>@main &lt;entry&gt;</td>"));
+        // (with equal args, the `.if.1` branch is never taken)
+        assert!(html.contains(
+            "<tr class=\"cold\"><td class=\"num\">0</td>\
+            <td class=\"num\">5</td><td>  v3: int = sub v1 v0;</td></tr>"
+        ));
+        assert!(html.contains("background: rgba(255, 90, 0, 0.70)"));
+    }
+}
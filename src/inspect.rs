@@ -0,0 +1,612 @@
+use std::fmt::Write;
+use std::mem::offset_of;
+
+use zerocopy::{Immutable, IntoBytes, TryFromBytes};
+
+use crate::memfile::{read_header, read_toc, slice_prefix};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of bytes shown on each row of the hexdump
+const ROW_WIDTH: usize = 16;
+
+/// The names of the fields of a `Toc`, in the order they're stored
+/// (which is also the order of the sections they describe)
+const TOC_FIELDS: [&str; 9] = [
+    "strings",
+    "func_args",
+    "arg_idxes_store",
+    "labels_idxes_store",
+    "instrs",
+    "label_table",
+    "dest_slots",
+    "arg_slots",
+    "ext_store",
+];
+
+/// Writes the hexdump of a file to `out`, section by section
+/// (`data` is the whole file & `pos` is the offset of the first byte
+/// which hasn't been dumped yet)
+struct Dumper<'a, 'o> {
+    data: &'a [u8],
+    pos: usize,
+    out: &'o mut String,
+}
+
+/// Reads the sections of a file (which follow the `Toc`) before they're
+/// dumped, since some of them are referred to by earlier sections
+/// - `num_read` is the no. of sections which have been read so far:
+///   once a section can't be read, `error` is set & every later section
+///   is left empty
+struct Reader<'a> {
+    buffer: &'a [u8],
+    num_read: usize,
+    error: Option<String>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a> Reader<'a> {
+    /// Slices the next section (with `count` entries) off the front of the
+    /// buffer, like `slice_prefix`
+    fn take<T: TryFromBytes + Immutable>(
+        &mut self,
+        count: usize,
+        section: &str,
+    ) -> &'a [T] {
+        if self.error.is_some() {
+            return &[];
+        }
+        match slice_prefix(self.buffer, count, section) {
+            Ok((items, rest)) => {
+                self.buffer = rest;
+                self.num_read += 1;
+                items
+            }
+            Err(msg) => {
+                self.error = Some(msg);
+                &[]
+            }
+        }
+    }
+
+    /// Checks that the section with index `idx` (in the order they're read)
+    /// could be read
+    fn check(&self, idx: usize) -> Result<(), String> {
+        match &self.error {
+            Some(msg) if idx >= self.num_read => Err(msg.clone()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The part of `store` that the `(offset, len)` pair `idxes` refers to
+/// (if it's in bounds)
+fn slice_of<T>(store: &[T], (offset, len): (usize, usize)) -> Option<&[T]> {
+    store.get(offset..offset.checked_add(len)?)
+}
+
+/// Describes the index pair `pair`, along with the string it refers to in
+/// `store` (if it's in bounds)
+fn describe_str<P: IndexPair>(pair: P, store: &[u8]) -> String {
+    let (first, second) = pair.fields();
+    match pair.get() {
+        None => "none".to_string(),
+        Some(idxes) if first >= 0 && second >= 0 => {
+            match slice_of(store, idxes).map(str::from_utf8) {
+                Some(Ok(text)) => format!("({first}, {second}) {text:?}"),
+                _ => format!("({first}, {second}) <out of bounds>"),
+            }
+        }
+        Some(_) => format!("({first}, {second}) <out of bounds>"),
+    }
+}
+
+/// Describes the index pair `pair` into `idxes_store` (`arg_idxes_store` or
+/// `labels_idxes_store`), along with the strings which the pairs it refers
+/// to point to in `store` (labels are given a leading `.`)
+fn describe_strs<P: IndexPair>(
+    pair: P,
+    idxes_store: &[P],
+    store: &[u8],
+    prefix: &str,
+) -> String {
+    let (first, second) = pair.fields();
+    let Some(idxes) = pair.get() else {
+        return "none".to_string();
+    };
+    let names: Option<Vec<String>> =
+        slice_of(idxes_store, idxes).map(|pairs| {
+            pairs
+                .iter()
+                .map(|&pair| {
+                    match pair.get().and_then(|idxes| slice_of(store, idxes)) {
+                        Some(bytes) => {
+                            format!(
+                                "{prefix}{}",
+                                String::from_utf8_lossy(bytes)
+                            )
+                        }
+                        None => "?".to_string(),
+                    }
+                })
+                .collect()
+        });
+    match names {
+        Some(names) if first >= 0 && second >= 0 => {
+            format!("({first}, {second}) [{}]", names.join(", "))
+        }
+        _ => format!("({first}, {second}) <out of bounds>"),
+    }
+}
+
+/// Describes a type stored in the file
+fn describe_type(ty: FlatType) -> String {
+    match Option::<Type>::from(ty) {
+        Some(ty) => ty.to_string(),
+        None if ty == FlatType::NULL => "none".to_string(),
+        None => "<invalid>".to_string(),
+    }
+}
+
+impl Dumper<'_, '_> {
+    /// Writes `len` bytes starting at `pos` as rows of `ROW_WIDTH` bytes,
+    /// the first of which is annotated with `note`
+    fn row(&mut self, len: usize, note: &str) {
+        let end = (self.pos + len).min(self.data.len());
+        for (i, chunk) in self.data[self.pos..end].chunks(ROW_WIDTH).enumerate()
+        {
+            let hex: Vec<String> =
+                chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            let note = if i == 0 { note } else { "" };
+            let offset = self.pos + i * ROW_WIDTH;
+            let line = format!("{offset:08x}  {:<47}  {note}", hex.join(" "));
+            let _ = writeln!(self.out, "{}", line.trim_end());
+        }
+        self.pos = end;
+    }
+
+    /// Writes the heading of the part of the file called `name`
+    fn heading(&mut self, name: &str, detail: &str) {
+        let _ = writeln!(self.out, "{:08x} <{name}>: {detail}", self.pos);
+    }
+
+    /// Writes the padding after a section of `len` bytes (see `push_section`)
+    fn padding(&mut self, len: usize) {
+        let padding =
+            (len.next_multiple_of(4) - len).min(self.data.len() - self.pos);
+        if padding > 0 {
+            self.row(padding, "(padding)");
+        }
+    }
+
+    /// Dumps a section whose entries are `items`, where `describe` splits
+    /// each entry into annotated fields of `(offset, no. of bytes, note)`.
+    /// The fields are dumped in the order they're laid out in (which may
+    /// not be the order they're declared in, since the structs in the file
+    /// are `repr(packed)` but not `repr(C)`), & the first row of each entry
+    /// is annotated with its index. Bytes of the entry which aren't part of
+    /// any field are dumped as is.
+    fn items<T: IntoBytes + Immutable>(
+        &mut self,
+        name: &str,
+        items: &[T],
+        describe: impl Fn(&T) -> Vec<(usize, usize, String)>,
+    ) {
+        let detail =
+            format!("{} entries of {} bytes", items.len(), size_of::<T>());
+        self.heading(name, &detail);
+        for (i, item) in items.iter().enumerate() {
+            let start = self.pos;
+            let mut fields = describe(item);
+            fields.sort_by_key(|&(offset, ..)| offset);
+            for (field_idx, (offset, len, note)) in fields.iter().enumerate() {
+                if self.pos < start + offset {
+                    self.row(start + offset - self.pos, "");
+                }
+                if field_idx == 0 {
+                    self.row(*len, &format!("[{i}] {note}"));
+                } else {
+                    self.row(*len, note);
+                }
+            }
+            let end = start + size_of::<T>();
+            if self.pos < end {
+                self.row(end - self.pos, "");
+            }
+        }
+        self.padding(size_of_val(items));
+    }
+
+    /// Dumps a section of strings, annotating each row with its characters
+    /// (like `hexdump -C`)
+    fn strings(&mut self, name: &str, bytes: &[u8]) {
+        self.heading(name, &format!("{} bytes", bytes.len()));
+        for chunk in bytes.chunks(ROW_WIDTH) {
+            let text: String = chunk
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            self.row(chunk.len(), &format!("|{text}|"));
+        }
+        self.padding(bytes.len());
+    }
+}
+
+/// Dumps the parts of a file whose index pairs are of type `P`, which come
+/// after the `Toc` (see `inspect`)
+fn inspect_sections<P: IndexPair>(
+    dumper: &mut Dumper,
+    num_funcs: usize,
+    toc: &Toc,
+    resolved: bool,
+) -> Result<(), String> {
+    let mut reader = Reader {
+        buffer: &dumper.data[dumper.pos..],
+        num_read: 0,
+        error: None,
+    };
+    let func_table: &[FuncRecord<P>] = reader.take(num_funcs, "function table");
+    // (an `InstrView` spanning the whole of every section, as in
+    // `memfile::ProgramData`, whose string stores are all the string table)
+    let strings = reader.take(toc.strings, "strings");
+    let s = InstrView::<P> {
+        func_name: strings,
+        func_args: reader.take(toc.func_args, "func_args"),
+        func_ret_ty: FlatType::NULL,
+        var_store: strings,
+        arg_idxes_store: reader.take(toc.arg_idxes_store, "arg_idxes_store"),
+        labels_idxes_store: reader
+            .take(toc.labels_idxes_store, "labels_idxes_store"),
+        labels_store: strings,
+        funcs_store: strings,
+        instrs: reader.take(toc.instrs, "instrs"),
+        label_table: reader.take(toc.label_table, "label_table"),
+        num_slots: 0,
+        dest_slots: reader.take(toc.dest_slots, "dest_slots"),
+        arg_slots: reader.take(toc.arg_slots, "arg_slots"),
+        ext_store: reader.take(toc.ext_store, "ext_store"),
+        resolved: if resolved {
+            reader.take(toc.instrs, "resolved")
+        } else {
+            &[]
+        },
+    };
+    let pair = size_of::<P>();
+    let word = size_of::<usize>();
+    let ty = size_of::<FlatType>();
+
+    reader.check(0)?;
+    dumper.items("func_table", func_table, |record| {
+        let record = *record;
+        let name = describe_str(record.name, s.func_name);
+        vec![
+            (
+                offset_of!(FuncRecord<P>, name),
+                pair,
+                format!("name: {name}"),
+            ),
+            (
+                offset_of!(FuncRecord<P>, start_pc),
+                word,
+                format!("start_pc: {}", { record.start_pc }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, end_pc),
+                word,
+                format!("end_pc: {}", { record.end_pc }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, first_arg),
+                word,
+                format!("first_arg: {}", { record.first_arg }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, num_args),
+                word,
+                format!("num_args: {}", { record.num_args }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, ret_ty),
+                ty,
+                format!("ret_ty: {}", describe_type(record.ret_ty)),
+            ),
+            (
+                offset_of!(FuncRecord<P>, num_slots),
+                word,
+                format!("num_slots: {}", { record.num_slots }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, first_label),
+                word,
+                format!("first_label: {}", { record.first_label }),
+            ),
+            (
+                offset_of!(FuncRecord<P>, num_labels),
+                word,
+                format!("num_labels: {}", { record.num_labels }),
+            ),
+        ]
+    });
+    reader.check(1)?;
+    dumper.strings("strings", strings);
+    reader.check(2)?;
+    dumper.items("func_args", s.func_args, |arg| {
+        let arg = *arg;
+        let name = describe_str(arg.arg_name_idxes, s.var_store);
+        vec![
+            (
+                offset_of!(FlatFuncArg<P>, arg_name_idxes),
+                pair,
+                format!("name: {name}"),
+            ),
+            (
+                offset_of!(FlatFuncArg<P>, arg_type),
+                ty,
+                format!("type: {}", describe_type(arg.arg_type)),
+            ),
+        ]
+    });
+    reader.check(3)?;
+    dumper.items("arg_idxes_store", s.arg_idxes_store, |&arg| {
+        vec![(0, pair, describe_str(arg, s.var_store))]
+    });
+    reader.check(4)?;
+    dumper.items("labels_idxes_store", s.labels_idxes_store, |&label| {
+        vec![(0, pair, describe_str(label, s.labels_store))]
+    });
+    reader.check(5)?;
+    dumper.items("instrs", s.instrs, |instr| {
+        let op = instr.op();
+        let op_name = match op {
+            u32::MAX => "label".to_string(),
+            EXT_OP => "extension".to_string(),
+            _ => Opcode::u32_to_opcode(op)
+                .map_or("<invalid>".to_string(), |op| op.to_string()),
+        };
+        // (the `label` of an extension instruction is its JSON)
+        let label_store = if op == EXT_OP {
+            s.ext_store
+        } else {
+            s.labels_store
+        };
+        let label = describe_str(instr.label, label_store);
+        let dest = describe_str(instr.dest, s.var_store);
+        let args =
+            describe_strs(instr.args, s.arg_idxes_store, s.var_store, "");
+        let labels = describe_strs(
+            instr.instr_labels,
+            s.labels_idxes_store,
+            s.labels_store,
+            ".",
+        );
+        let funcs = describe_str(instr.funcs, s.funcs_store);
+        let value = match Option::<BrilValue>::from(instr.value) {
+            Some(value) => value.to_string(),
+            None => "none".to_string(),
+        };
+        vec![
+            (
+                offset_of!(FlatInstr<P>, op),
+                size_of::<P::Op>(),
+                format!("op: {op} ({op_name})"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, label),
+                pair,
+                format!("label: {label}"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, dest),
+                pair,
+                format!("dest: {dest}"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, args),
+                pair,
+                format!("args: {args}"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, instr_labels),
+                pair,
+                format!("labels: {labels}"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, funcs),
+                pair,
+                format!("funcs: {funcs}"),
+            ),
+            (
+                offset_of!(FlatInstr<P>, ty),
+                ty,
+                format!("ty: {}", describe_type(instr.ty)),
+            ),
+            (
+                offset_of!(FlatInstr<P>, value),
+                size_of::<FlatBrilValue>(),
+                format!("value: {value}"),
+            ),
+        ]
+    });
+    reader.check(6)?;
+    dumper.items("label_table", s.label_table, |label| {
+        let label = *label;
+        let name = describe_str(label.label_idxes, s.labels_store);
+        vec![
+            (
+                offset_of!(FlatLabel<P>, label_idxes),
+                pair,
+                format!("name: {name}"),
+            ),
+            (
+                offset_of!(FlatLabel<P>, pc),
+                word,
+                format!("pc: {}", { label.pc }),
+            ),
+        ]
+    });
+    reader.check(7)?;
+    dumper.items("dest_slots", s.dest_slots, |slot| {
+        vec![(0, 4, format!("slot {slot}"))]
+    });
+    reader.check(8)?;
+    dumper.items("arg_slots", s.arg_slots, |slot| {
+        vec![(0, 4, format!("slot {slot}"))]
+    });
+    reader.check(9)?;
+    dumper.strings("ext_store", s.ext_store);
+    if !resolved {
+        return Ok(());
+    }
+    reader.check(10)?;
+    let describe = |operand: u32| match operand {
+        UNRESOLVED => "none".to_string(),
+        operand => operand.to_string(),
+    };
+    dumper.items("resolved", s.resolved, |resolved| {
+        let [true_pc, false_pc] = resolved.targets;
+        vec![
+            (
+                offset_of!(ResolvedOperands, targets),
+                8,
+                format!(
+                    "targets: {}, {}",
+                    describe(true_pc),
+                    describe(false_pc)
+                ),
+            ),
+            (
+                offset_of!(ResolvedOperands, callee),
+                4,
+                format!("callee: {}", describe(resolved.callee)),
+            ),
+        ]
+    });
+    Ok(())
+}
+
+/// Writes an annotated hexdump of the flat Bril file `data` to `out`, where
+/// every region is labeled with the part of the file it belongs to (the
+/// `Header`, the `Toc`, the function table & each section) & the fields of
+/// each entry (e.g. the index pairs of an instruction) are decoded inline,
+/// along with the strings they point to. Returns an error if the file is
+/// malformed, after dumping the parts of the file which could be read
+/// (the rest of the file is dumped as is).
+pub fn inspect(data: &[u8], out: &mut String) -> Result<(), String> {
+    let mut dumper = Dumper { data, pos: 0, out };
+    let result = (|| {
+        let (header, rest) = read_header(data)?;
+        let format = if header.flags & WIDE_FORMAT_FLAG != 0 {
+            "wide"
+        } else if header.flags & COMPACT_FORMAT_FLAG != 0 {
+            "compact"
+        } else {
+            "narrow"
+        };
+        let resolved = header.flags & RESOLVED_FLAG != 0;
+        dumper.heading("header", &format!("{} bytes", size_of::<Header>()));
+        dumper.row(
+            8,
+            &format!(
+                "flags: {:#x} (version {}, {format} format{})",
+                header.flags,
+                header.version(),
+                if resolved { ", resolved" } else { "" }
+            ),
+        );
+        dumper.row(8, &format!("num_funcs: {}", header.num_funcs));
+
+        let (toc, _) = read_toc(rest)?;
+        dumper.heading("toc", &format!("{} bytes", size_of::<Toc>()));
+        let counts = toc.as_bytes().chunks(8).map(|bytes| {
+            u64::from_ne_bytes(bytes.try_into().expect("`Toc` holds `usize`s"))
+        });
+        for (field, count) in TOC_FIELDS.iter().zip(counts) {
+            dumper.row(8, &format!("{field}: {count}"));
+        }
+
+        let num_funcs = usize::try_from(header.num_funcs).unwrap_or(usize::MAX);
+        if header.flags & WIDE_FORMAT_FLAG != 0 {
+            inspect_sections::<I64Pair>(&mut dumper, num_funcs, toc, resolved)
+        } else if header.flags & COMPACT_FORMAT_FLAG != 0 {
+            inspect_sections::<I16Pair>(&mut dumper, num_funcs, toc, resolved)
+        } else {
+            inspect_sections::<I32Pair>(&mut dumper, num_funcs, toc, resolved)
+        }
+    })();
+    if dumper.pos < data.len() {
+        let label = if result.is_ok() {
+            "(trailing bytes)"
+        } else {
+            "(unreadable)"
+        };
+        let _ = writeln!(dumper.out, "{:08x} <{label}>", dumper.pos);
+        dumper.row(data.len() - dumper.pos, "");
+    }
+    result
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod inspect_tests {
+    use serde_json::json;
+
+    use crate::inspect::inspect;
+    use crate::memfile::{AlignedBytes, json_to_fbril_bytes};
+
+    /// Every byte of the file is dumped exactly once, index pairs are
+    /// decoded to the strings they point to, & a truncated file is dumped
+    /// as far as it can be read
+    #[test]
+    fn test_inspect() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "jmp", "labels": ["end"] },
+                { "label": "end" },
+                { "op": "print", "args": ["x"] },
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let aligned = AlignedBytes::new(&bytes);
+        let data = &aligned;
+
+        let mut out = String::new();
+        assert_eq!(inspect(data, &mut out), Ok(()));
+        // (rows start with their offset & two spaces, unlike headings)
+        let num_dumped: usize = out
+            .lines()
+            .filter(|line| line.get(8..10) == Some("  "))
+            .map(|line| line[10..].get(..47).unwrap_or(&line[10..]))
+            .map(|hex| hex.split_whitespace().count())
+            .sum();
+        assert_eq!(num_dumped, data.len());
+        assert!(out.contains("[0] name: (0, 4) \"main\""));
+        assert!(out.contains("dest: (4, 1) \"x\""));
+        assert!(out.contains("args: (0, 1) [x]"));
+        assert!(out.contains("labels: (0, 1) [.end]"));
+        assert!(out.contains("[0] name: (5, 3) \"end\""));
+
+        let truncated = &data[..data.len() - 20];
+        let mut out = String::new();
+        let error = inspect(truncated, &mut out).unwrap_err();
+        assert!(error.contains("section"), "{error}");
+        assert!(out.contains("<instrs>"));
+        assert!(out.contains("<(unreadable)>"));
+    }
+}
@@ -1,540 +1,2396 @@
 #![allow(unused_variables)]
 use core::panic;
+use std::cell::{Cell, OnceCell};
 use std::collections::HashMap;
+use std::io::Write;
 use std::str;
+use std::time::{Duration, Instant};
 
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::fusion::FusedOp;
+use crate::host::HostFns;
+use crate::memfile::{
+    self, AlignedBytes, FlatProgram, ProgramData, ProgramViews,
+};
+use crate::observer::{NoObserver, Observer};
+use crate::predecode::{self, DecodedFunc};
+use crate::timing::{self, Category, Timer};
+use crate::typecheck::check_instr_types;
 use crate::types::*;
 
 // An environment maps variable names (`&str`s) to values
 pub type Environment<'a> = HashMap<&'a str, BrilValue>;
 
-/// Extracts the variable name (string) that occupies `start_idx` to `end_idx`
-/// (inclusive) in `instr_view.var_store`
-pub fn get_var<'a>(
-    instr_view: &'a InstrView,
-    start_idx: u32,
-    end_idx: u32,
+/// A pool of empty environments which are reused across calls, so that
+/// recursion-heavy programs don't allocate a fresh `HashMap` for every call
+/// (an environment keeps its capacity when it's returned to the pool,
+/// so the pool never holds more environments than the max call depth)
+#[derive(Debug, Default)]
+pub struct EnvPool<'a> {
+    free: Vec<Environment<'a>>,
+}
+
+impl<'a> EnvPool<'a> {
+    /// Takes an empty environment from the pool
+    /// (allocating a new one if the pool is empty)
+    pub fn take(&mut self) -> Environment<'a> {
+        self.free.pop().unwrap_or_default()
+    }
+
+    /// Clears `env` & returns it to the pool
+    pub fn give(&mut self, mut env: Environment<'a>) {
+        env.clear();
+        self.free.push(env);
+    }
+
+    /// The (approximate) no. of bytes allocated for `env`'s table
+    /// (a key-value pair & a control byte per bucket)
+    pub fn env_bytes(env: &Environment<'a>) -> usize {
+        env.capacity() * (std::mem::size_of::<(&str, BrilValue)>() + 1)
+    }
+
+    /// The (approximate) no. of bytes allocated for the environments in the
+    /// pool (since environments are never shrunk, once every call has
+    /// returned this is the peak size of all the callees' environments)
+    pub fn allocated_bytes(&self) -> usize {
+        self.free.iter().map(Self::env_bytes).sum()
+    }
+}
+
+/// A side table caching the labels that each `jmp` / `br` in a function
+/// jumps to, so that its labels are only resolved (by searching the function's
+/// label table for them) the first time it's executed: `targets[pc]` holds the
+/// indexes in `label_table` of the targets of the instruction at `pc`
+/// (`[true, false]` for a `br`, and `[target, target]` for a `jmp`),
+/// or `UNRESOLVED` if it hasn't been executed yet
+#[derive(Debug)]
+pub struct BranchTargets {
+    targets: Vec<Cell<[usize; 2]>>,
+}
+
+/// Marks the instructions in `BranchTargets` whose targets aren't cached
+const UNRESOLVED: [usize; 2] = [usize::MAX; 2];
+
+impl BranchTargets {
+    /// Creates an empty side table for a function with `num_instrs` instrs
+    pub fn new(num_instrs: usize) -> Self {
+        Self {
+            targets: vec![Cell::new(UNRESOLVED); num_instrs],
+        }
+    }
+
+    /// Returns the cached targets of the instruction at `pc`, calling
+    /// `resolve` to compute them if they aren't cached yet
+    /// (errors aren't cached, so a malformed instruction fails every time)
+    pub fn get_or_resolve(
+        &self,
+        pc: usize,
+        resolve: impl FnOnce() -> Result<[usize; 2], Diagnostic>,
+    ) -> Result<[usize; 2], Diagnostic> {
+        let cached = self.targets[pc].get();
+        if cached != UNRESOLVED {
+            return Ok(cached);
+        }
+        let targets = resolve()?;
+        self.targets[pc].set(targets);
+        Ok(targets)
+    }
+}
+
+/// A function in a `FuncTable`: its name, & its `InstrView` along with the
+/// side table of its branch targets once it's been loaded
+/// (or the reason why it couldn't be), and its pre-decoded instructions
+/// once they're needed (see `predecode.rs`)
+struct FuncEntry<'a, P: IndexPair> {
+    name: String,
+    loaded: OnceCell<Result<(InstrView<'a, P>, BranchTargets), String>>,
+    decoded: OnceCell<DecodedFunc>,
+}
+
+/// The functions of a program (in the order they appear in the file), which
+/// `call` instructions are resolved against by name. A function's `InstrView`
+/// is only built (& validated) the first time it's called, so a large program
+/// which only calls a few of its functions doesn't pay for loading the rest
+/// (see `memfile::load_program_lazily`). If several functions have the same
+/// name, the last one is called.
+pub struct FuncTable<'a, P: IndexPair = I32Pair> {
+    funcs: Vec<FuncEntry<'a, P>>,
+    by_name: HashMap<String, usize>,
+    /// The file that the functions are loaded from
+    /// (`None` if they've all been loaded already)
+    program: Option<ProgramData<'a, P>>,
+}
+
+impl<'a, P: IndexPair> FuncTable<'a, P> {
+    /// Creates a table of functions which have already been loaded
+    pub fn new(views: &[InstrView<'a, P>]) -> Self {
+        let funcs = views.iter().map(|view| FuncEntry {
+            name: get_func_name(view).to_string(),
+            loaded: OnceCell::from(Ok((
+                view.clone(),
+                BranchTargets::new(view.instrs.len()),
+            ))),
+            decoded: OnceCell::new(),
+        });
+        Self::from_entries(funcs, None)
+    }
+
+    /// Creates a table of the functions in a flat Bril file, which are
+    /// loaded from `program` on demand
+    pub fn lazy(program: ProgramData<'a, P>) -> Self {
+        let funcs: Vec<FuncEntry<'a, P>> = program
+            .func_names()
+            .iter()
+            .map(|name| FuncEntry {
+                name: name.to_string(),
+                loaded: OnceCell::new(),
+                decoded: OnceCell::new(),
+            })
+            .collect();
+        Self::from_entries(funcs.into_iter(), Some(program))
+    }
+
+    fn from_entries(
+        entries: impl Iterator<Item = FuncEntry<'a, P>>,
+        program: Option<ProgramData<'a, P>>,
+    ) -> Self {
+        let funcs: Vec<FuncEntry<'a, P>> = entries.collect();
+        let by_name = funcs
+            .iter()
+            .enumerate()
+            .map(|(idx, func)| (func.name.clone(), idx))
+            .collect();
+        Self {
+            funcs,
+            by_name,
+            program,
+        }
+    }
+
+    /// Loads function `idx` (if it hasn't been loaded already)
+    fn load(
+        &self,
+        idx: usize,
+    ) -> Result<(&InstrView<'a, P>, &BranchTargets), Diagnostic> {
+        let func = &self.funcs[idx];
+        let loaded = func.loaded.get_or_init(|| {
+            let program = self
+                .program
+                .as_ref()
+                .expect("functions without a file are already loaded");
+            let view = program.func_view(idx)?;
+            let targets = BranchTargets::new(view.instrs.len());
+            Ok((view, targets))
+        });
+        match loaded {
+            Ok((view, targets)) => Ok((view, targets)),
+            Err(msg) => Err(Diagnostic::new(
+                ErrorCode::MalformedFile,
+                format!("malformed function @{}: {msg}", func.name),
+            )),
+        }
+    }
+
+    /// Looks up the function called `name`, loading it if it hasn't been
+    /// loaded yet (returns `Ok(None)` if there's no such function, or an
+    /// error if the function is malformed)
+    pub fn get(
+        &self,
+        name: &str,
+    ) -> Result<Option<(&InstrView<'a, P>, &BranchTargets)>, Diagnostic> {
+        self.by_name
+            .get(name)
+            .map(|&idx| self.load(idx))
+            .transpose()
+    }
+
+    /// The index of the function called `name` (which `decoded` takes),
+    /// or `None` if there's no such function
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Loads function `idx` (if it hasn't been loaded already), returning
+    /// its `InstrView` along with its pre-decoded instructions, which are
+    /// decoded the first time they're needed (see `predecode::decode_func`)
+    pub fn decoded(
+        &self,
+        idx: usize,
+    ) -> Result<(&InstrView<'a, P>, &DecodedFunc), Diagnostic> {
+        let (view, _) = self.load(idx)?;
+        let decoded = self.funcs[idx]
+            .decoded
+            .get_or_init(|| predecode::decode_func(view, self));
+        Ok((view, decoded))
+    }
+
+    /// The no. of functions in the table
+    pub fn len(&self) -> usize {
+        self.funcs.len()
+    }
+
+    /// Whether the table has no functions
+    pub fn is_empty(&self) -> bool {
+        self.funcs.is_empty()
+    }
+
+    /// The names of all the functions, in the order they appear in the file
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.funcs.iter().map(|func| func.name.as_str())
+    }
+
+    /// Whether the function called `name` has been loaded
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.by_name
+            .get(name)
+            .is_some_and(|&idx| self.funcs[idx].loaded.get().is_some())
+    }
+
+    /// Loads every function, returning their `InstrView`s in the order they
+    /// appear in the file (e.g. for passes & reports over the whole program)
+    pub fn views(&self) -> Result<Vec<InstrView<'a, P>>, Diagnostic> {
+        (0..self.funcs.len())
+            .map(|idx| self.load(idx).map(|(view, _)| view.clone()))
+            .collect()
+    }
+}
+
+/// Limits on how much work the interpreter may do before aborting
+/// (`None` means the corresponding resource is unbounded)
+/// - `max_steps`: max no. of (non-label) instructions executed
+/// - `max_call_depth`: max no. of nested function calls
+/// - `timeout`: max wall-clock time spent interpreting
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_call_depth: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+/// We only check the wall clock once every `TIMEOUT_CHECK_INTERVAL` steps,
+/// since calling `Instant::now` on every instruction is expensive
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// State that is shared across all (nested) calls to `interp_instr_view`
+/// - `out` is where the output of `print` instructions is written to
+/// - `steps` is the no. of (non-label) instructions executed so far
+/// - `call_depth` is the no. of function calls currently on the stack
+///   (& `peak_call_depth` is the most there have been at once)
+/// - `env_bytes` is the (approximate) no. of bytes allocated for variable
+///   environments by the last run (see `EnvPool::allocated_bytes`)
+/// - `observer` is notified of every instruction, call, return & branch
+///   (by default, this is `NoObserver`, which does nothing)
+/// - `check_types` enables checking the runtime types of every
+///   instruction's operands & dest before it's executed
+///   (see `typecheck::check_instr_types`; off by default)
+/// - `host_fns` are the native functions which the program can `call`
+///   (see `with_host_fn`)
+/// - `keep_going` makes the interpreter carry on past recoverable errors
+///   (see `ErrorCode::is_recoverable`) instead of aborting, recording them
+///   in `errors` (off by default; see `recover_from_error`). Like host
+///   functions, this is only supported by the recursive interpreter.
+/// - `predecode` makes `interp_entry` decode each function's instructions
+///   before running them (see `predecode.rs`) whenever nothing needs to
+///   look up variables by name, i.e. if the observer ignores every event &
+///   `check_types`, `keep_going` & host functions aren't used (on by
+///   default; turning it off runs the zero-copy instructions directly)
+pub struct InterpContext<W: Write, O: Observer = NoObserver> {
+    pub out: W,
+    pub limits: Limits,
+    pub steps: u64,
+    pub call_depth: usize,
+    pub peak_call_depth: usize,
+    pub env_bytes: usize,
+    pub observer: O,
+    pub check_types: bool,
+    pub host_fns: HostFns,
+    pub keep_going: bool,
+    pub errors: Vec<Diagnostic>,
+    pub predecode: bool,
+    /// When interpretation started (only recorded if there's a timeout,
+    /// since the clock isn't available on every target, e.g. `wasm32`)
+    start_time: Option<Instant>,
+}
+
+/// The result of running a program with `interp_program_captured`
+/// - `lines` are the lines printed by the program (without trailing newlines)
+/// - `steps` is the dynamic instruction count, i.e. the no. of (non-label)
+///   instructions executed
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutput {
+    pub lines: Vec<String>,
+    pub steps: u64,
+}
+
+impl<W: Write> InterpContext<W> {
+    /// Creates a fresh context which writes program output to `out`
+    pub fn new(out: W, limits: Limits) -> Self {
+        Self {
+            out,
+            limits,
+            steps: 0,
+            call_depth: 0,
+            peak_call_depth: 0,
+            env_bytes: 0,
+            observer: NoObserver,
+            check_types: false,
+            host_fns: HostFns::default(),
+            keep_going: false,
+            errors: vec![],
+            predecode: true,
+            start_time: limits.timeout.map(|_| Instant::now()),
+        }
+    }
+}
+
+impl<W: Write, O: Observer> InterpContext<W, O> {
+    /// Attaches an `observer` to the context (replacing the existing one)
+    pub fn with_observer<O2: Observer>(
+        self,
+        observer: O2,
+    ) -> InterpContext<W, O2> {
+        InterpContext {
+            out: self.out,
+            limits: self.limits,
+            steps: self.steps,
+            call_depth: self.call_depth,
+            peak_call_depth: self.peak_call_depth,
+            env_bytes: self.env_bytes,
+            observer,
+            check_types: self.check_types,
+            host_fns: self.host_fns,
+            keep_going: self.keep_going,
+            errors: self.errors,
+            predecode: self.predecode,
+            start_time: self.start_time,
+        }
+    }
+
+    /// Registers `func` as a host function called `name`, so that calls to
+    /// `@name` (if the program has no function called `name`) are
+    /// dispatched to it (see `host::HostFns`)
+    pub fn with_host_fn(
+        mut self,
+        name: &str,
+        func: impl FnMut(&[BrilValue]) -> Result<Option<BrilValue>, String>
+        + 'static,
+    ) -> Self {
+        self.host_fns.register(name, func);
+        self
+    }
+
+    /// Records that one more instruction is about to be executed,
+    /// returning an error if doing so exceeds the step limit or the timeout
+    /// (an instruction refused by the step limit isn't counted, so `steps`
+    /// never exceeds it)
+    pub fn tick(&mut self) -> Result<(), Diagnostic> {
+        if let Some(max_steps) = self.limits.max_steps
+            && self.steps >= max_steps
+        {
+            return Err(Diagnostic::new(
+                ErrorCode::StepLimit,
+                format!("exceeded the maximum of {max_steps} steps"),
+            ));
+        }
+        self.steps += 1;
+        if self.steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) {
+            self.check_timeout()?;
+        }
+        Ok(())
+    }
+
+    /// Returns an error if interpretation has taken longer than the timeout
+    /// (if there is one)
+    fn check_timeout(&self) -> Result<(), Diagnostic> {
+        if let Some(timeout) = self.limits.timeout
+            && self
+                .start_time
+                .is_some_and(|start| start.elapsed() > timeout)
+        {
+            return Err(Diagnostic::new(
+                ErrorCode::Timeout,
+                format!(
+                    "exceeded the timeout of {:.3}s",
+                    timeout.as_secs_f64()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The no. of instructions which an observer may execute when it runs a
+    /// loop (see `Observer::on_back_edge`): all the steps left under the
+    /// step limit, but no more than `TIMEOUT_CHECK_INTERVAL` if there's a
+    /// timeout (so that the clock is still checked regularly)
+    fn loop_step_budget(&self) -> u64 {
+        let remaining = self
+            .limits
+            .max_steps
+            .map_or(u64::MAX, |max_steps| max_steps.saturating_sub(self.steps));
+        match self.limits.timeout {
+            Some(_) => remaining.min(TIMEOUT_CHECK_INTERVAL),
+            None => remaining,
+        }
+    }
+}
+
+/// Converts `bytes`, a string from the store `store` of an `InstrView`, to
+/// a `&str`, panicking if it isn't valid UTF-8
+/// (`memfile::validate_instr_view` rules this out for every view of a loaded
+/// file, but since an `InstrView` can also be built by hand, the check is
+/// still made here: it's cheap, since names are short)
+fn store_str<'a>(bytes: &'a [u8], store: &str) -> &'a str {
+    str::from_utf8(bytes)
+        .unwrap_or_else(|e| panic!("{store} isn't valid UTF-8: {e}"))
+}
+
+/// Extracts the variable name (string) that occupies the `len` bytes
+/// starting at `offset` in `instr_view.var_store`
+pub fn get_var<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    offset: usize,
+    len: usize,
 ) -> &'a str {
-    let start_idx = start_idx as usize;
-    let end_idx = end_idx as usize;
-    str::from_utf8(&instr_view.var_store[start_idx..=end_idx])
-        .expect("invalid utf-8")
+    let bytes = &instr_view.var_store[offset..offset + len];
+    store_str(bytes, "var_store")
 }
 
 /// Extracts a vec of args (variable name strings) that correspond to the
-/// `args_start` to `args_end` indices (inclusive) in `instr_view.arg_idxes_store`
-pub fn get_args<'a>(
-    instr_view: &'a InstrView,
-    args_start: u32,
-    args_end: u32,
+/// `len` index pairs starting at `offset` in `instr_view.arg_idxes_store`
+pub fn get_args<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    offset: usize,
+    len: usize,
 ) -> Vec<&'a str> {
-    let arg_start = args_start as usize;
-    let arg_end = args_end as usize;
-    let args_idxes_slice = &instr_view.arg_idxes_store[arg_start..=arg_end];
+    let args_idxes_slice = &instr_view.arg_idxes_store[offset..offset + len];
     args_idxes_slice
         .iter()
-        .map(|i32pair| {
-            let (start_idx, end_idx) = <(u32, u32)>::from(*i32pair);
-            get_var(instr_view, start_idx, end_idx)
+        .map(|pair| {
+            let (offset, len) = pair.idxes();
+            get_var(instr_view, offset, len)
         })
         .collect()
 }
 
-/// Extracts the label name (string) that occupies `start_idx` to `end_idx`
-/// (inclusive) in `instr_view.labels_store`
-pub fn get_label_name<'a>(
-    instr_view: &'a InstrView,
-    start_idx: u32,
-    end_idx: u32,
+/// Extracts the index pairs of the args in `instr_view.var_store`
+/// for an instruction whose `args` field is `args`
+/// (returns an empty slice if the instruction has no args)
+pub fn get_arg_pairs<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    args: P,
+) -> &'a [P] {
+    match args.get() {
+        Some((offset, len)) => {
+            &instr_view.arg_idxes_store[offset..offset + len]
+        }
+        None => &[],
+    }
+}
+
+/// Extracts the args (variable name strings) of an instruction that has
+/// exactly `N` args, without allocating a `Vec`
+/// (returns `None` if the instruction has a different no. of args)
+pub fn get_n_args<'a, P: IndexPair, const N: usize>(
+    instr_view: &'a InstrView<P>,
+    args: P,
+) -> Option<[&'a str; N]> {
+    let pairs: &[P; N] = get_arg_pairs(instr_view, args).try_into().ok()?;
+    Some(pairs.map(|pair| {
+        let (offset, len) = pair.idxes();
+        get_var(instr_view, offset, len)
+    }))
+}
+
+/// Extracts the label name (string) that occupies the `len` bytes
+/// starting at `offset` in `instr_view.labels_store`
+pub fn get_label_name<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    offset: usize,
+    len: usize,
 ) -> &'a str {
-    let start_idx = start_idx as usize;
-    let end_idx = end_idx as usize;
-    str::from_utf8(&instr_view.labels_store[start_idx..=end_idx])
-        .expect("invalid utf-8")
-}
-
-/// Extracts a vec of labels that correspond to the
-/// `labels_start` to `labels_end` indices (inclusive) in `instr_view.labels_idxes_store`
-pub fn get_labels_vec<'a>(
-    instr_view: &'a InstrView,
-    labels_start: u32,
-    labels_end: u32,
+    let bytes = &instr_view.labels_store[offset..offset + len];
+    store_str(bytes, "labels_store")
+}
+
+/// Extracts the JSON of the extension instruction `instr` from
+/// `instr_view.ext_store` (see `EXT_OP`)
+pub fn get_ext_json<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    instr: &FlatInstr<P>,
+) -> &'a str {
+    let (offset, len) = instr.label.idxes();
+    let bytes = &instr_view.ext_store[offset..offset + len];
+    store_str(bytes, "ext_store")
+}
+
+/// Extracts a vec of labels that correspond to the `len` index pairs
+/// starting at `offset` in `instr_view.labels_idxes_store`
+pub fn get_labels_vec<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    offset: usize,
+    len: usize,
 ) -> Vec<&'a str> {
-    let label_start = labels_start as usize;
-    let label_end = labels_end as usize;
     let labels_idxes_slice =
-        &instr_view.labels_idxes_store[label_start..=label_end];
+        &instr_view.labels_idxes_store[offset..offset + len];
     labels_idxes_slice
         .iter()
-        .map(|i32pair| {
-            let (start_idx, end_idx) = <(u32, u32)>::from(*i32pair);
-            get_label_name(instr_view, start_idx, end_idx)
+        .map(|pair| {
+            let (offset, len) = pair.idxes();
+            get_label_name(instr_view, offset, len)
         })
         .collect()
 }
 
-/// Extracts the function name (string) that occupies `start_idx` to `end_idx`
-/// (inclusive) in `instr_view.funcs_store`
-pub fn get_func<'a>(
-    instr_view: &'a InstrView,
-    start_idx: u32,
-    end_idx: u32,
+/// Extracts the labels of an instruction that has exactly `N` labels
+/// (where `instr_labels` is the instruction's `instr_labels` field),
+/// without allocating a `Vec`
+/// (returns `None` if the instruction has a different no. of labels)
+pub fn get_n_labels<'a, P: IndexPair, const N: usize>(
+    instr_view: &'a InstrView<P>,
+    instr_labels: P,
+) -> Option<[&'a str; N]> {
+    let (offset, len) = instr_labels.get()?;
+    let pairs: &[P; N] = instr_view.labels_idxes_store[offset..offset + len]
+        .try_into()
+        .ok()?;
+    Some(pairs.map(|pair| {
+        let (offset, len) = pair.idxes();
+        get_label_name(instr_view, offset, len)
+    }))
+}
+
+/// Extracts the function name (string) that occupies the `len` bytes
+/// starting at `offset` in `instr_view.funcs_store`
+pub fn get_func<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    offset: usize,
+    len: usize,
+) -> &'a str {
+    let bytes = &instr_view.funcs_store[offset..offset + len];
+    store_str(bytes, "funcs_store")
+}
+
+/// Extracts the name of the function corresponding to `instr_view`
+/// (with the null bytes used to pad the name removed)
+pub fn get_func_name<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+) -> &'a str {
+    store_str(instr_view.func_name, "func_name").trim_end_matches(char::from(0))
+}
+
+/// Looks up the value of the variable `var` in `env`
+/// (returns an error if `var` is undefined)
+pub fn get_value<'e>(
+    env: &'e Environment,
+    var: &str,
+) -> Result<&'e BrilValue, Diagnostic> {
+    let _timer = Timer::start(Category::EnvLookup);
+    env.get(var).ok_or_else(|| {
+        Diagnostic::new(
+            ErrorCode::UndefinedVariable,
+            format!("undefined variable `{var}`"),
+        )
+    })
+}
+
+/// Extracts the name of the `idx`-th label in `instr_view.label_table`
+pub fn get_table_label_name<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    idx: usize,
 ) -> &'a str {
-    let start_idx = start_idx as usize;
-    let end_idx = end_idx as usize;
-    str::from_utf8(&instr_view.funcs_store[start_idx..=end_idx])
-        .expect("invalid utf-8")
+    let (offset, len) = instr_view.label_table[idx].label_idxes.idxes();
+    get_label_name(instr_view, offset, len)
+}
+
+/// Returns the index in `instr_view.label_table` of a label as an `Option`.
+/// (Returns `None` if the function has no such label.)
+pub fn get_label_idx<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    label_str: &str,
+) -> Option<usize> {
+    (0..instr_view.label_table.len())
+        .find(|&idx| get_table_label_name(instr_view, idx) == label_str)
 }
 
 /// Returns the PC (index in the list of instrs) corresponding to a label
-/// as an `Option`. (Returns `None` if no such PC exists.)
-pub fn get_pc_of_label(
-    instr_view: &InstrView,
+/// as an `Option`, i.e. the PC of the first instruction after the label.
+/// (Returns `None` if no such label exists.)
+pub fn get_pc_of_label<P: IndexPair>(
+    instr_view: &InstrView<P>,
     label_str: &str,
 ) -> Option<usize> {
-    // Iterate over the list of instrs to find the index (PC)
-    // of the instr corresponding to the label (we do this
-    // by comparing the actual label strings)
-    instr_view.instrs.iter().position(|instr| {
-        if instr.op == u32::MAX {
-            let candidate_label_str = get_label_name(
-                instr_view,
-                instr.label.first as u32,
-                instr.label.second as u32,
-            );
-            candidate_label_str == label_str
-        } else {
-            false
+    get_label_idx(instr_view, label_str)
+        .map(|idx| instr_view.label_table[idx].pc)
+}
+
+/// The error for an extension instruction, naming its opcode
+/// (which is read from the instruction's JSON in `ext_store`)
+#[cold]
+pub fn unsupported_instr<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    instr: &FlatInstr<P>,
+) -> Diagnostic {
+    let op = serde_json::from_str::<serde_json::Value>(get_ext_json(
+        instr_view, instr,
+    ))
+    .map(|json| json["op"].clone())
+    .unwrap_or_default();
+    let hint = match op.as_str() {
+        Some("band" | "bor" | "bxor" | "shl" | "shr")
+            if !cfg!(feature = "bitwise") =>
+        {
+            " (rebuild flat-bril with `--features bitwise` to enable it)"
         }
-    })
+        _ => "",
+    };
+    let op = if op.is_null() {
+        "?".to_string()
+    } else {
+        op.to_string()
+    };
+    Diagnostic::new(
+        ErrorCode::UnsupportedInstr,
+        format!("the extension opcode {op} isn't supported{hint}"),
+    )
+}
+
+/// The error for a `jmp` / `br` to a label that doesn't exist
+pub fn undefined_label(label: &str) -> Diagnostic {
+    Diagnostic::new(
+        ErrorCode::UndefinedLabel,
+        format!("undefined label .{label}"),
+    )
+}
+
+/// The index in the label table of the label that the `jmp` instruction
+/// `instr` (at `pc`) jumps to, which is looked up in `targets`
+/// (returns an error if the instruction is malformed or its label is undefined)
+pub fn jump_target<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    targets: &BranchTargets,
+    pc: usize,
+    instr: &FlatInstr<P>,
+) -> Result<usize, Diagnostic> {
+    let _timer = Timer::start(Category::BranchResolution);
+    let [target, _] = targets.get_or_resolve(pc, || {
+        // Grab the label string that the instruction jumps to
+        let Some([label_str]) = get_n_labels(instr_view, instr.instr_labels)
+        else {
+            return Err(Diagnostic::new(
+                ErrorCode::MalformedInstr,
+                "no. of labels in jmp instr != 1",
+            ));
+        };
+
+        let target = get_label_idx(instr_view, label_str)
+            .ok_or_else(|| undefined_label(label_str))?;
+        Ok([target, target])
+    })?;
+    Ok(target)
+}
+
+/// The index in the label table of the label that the `br` instruction
+/// `instr` (at `pc`) jumps to, where `condition` is the value of its argument
+/// & the targets are looked up in `targets` (returns an error if the
+/// instruction is malformed or either of its labels is undefined)
+pub fn branch_target<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    targets: &BranchTargets,
+    pc: usize,
+    instr: &FlatInstr<P>,
+    condition: bool,
+) -> Result<usize, Diagnostic> {
+    let _timer = Timer::start(Category::BranchResolution);
+    let [true_target, false_target] = targets.get_or_resolve(pc, || {
+        let Some([true_lbl, false_lbl]) =
+            get_n_labels(instr_view, instr.instr_labels)
+        else {
+            return Err(Diagnostic::new(
+                ErrorCode::MalformedInstr,
+                "br instruction is malformed (has != 2 labels)",
+            ));
+        };
+
+        let true_target = get_label_idx(instr_view, true_lbl)
+            .ok_or_else(|| undefined_label(true_lbl))?;
+        let false_target = get_label_idx(instr_view, false_lbl)
+            .ok_or_else(|| undefined_label(false_lbl))?;
+        Ok([true_target, false_target])
+    })?;
+    Ok(if condition { true_target } else { false_target })
+}
+
+/// Interprets a `const` instruction
+/// (returns an error if the instruction has no value)
+pub fn interp_const<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    instr: &FlatInstr<P>,
+    env: &mut Environment<'a>,
+) -> Result<(), Diagnostic> {
+    let (dest_offset, dest_len) = instr.dest.idxes();
+    let dest = get_var(instr_view, dest_offset, dest_len);
+    let value = instr.value.try_into().map_err(|_| {
+        Diagnostic::new(
+            ErrorCode::MalformedInstr,
+            "`const` instruction has no value",
+        )
+    })?;
+
+    // Extend the environment so that `dest |-> value`
+    env.insert(dest, value);
+    Ok(())
+}
+
+/// Interprets a `print` instruction, writing the values of its args to `out`
+/// (returns an error if an arg is undefined or `out` can't be written to)
+pub fn interp_print<P: IndexPair, W: Write>(
+    instr_view: &InstrView<P>,
+    instr: &FlatInstr<P>,
+    env: &Environment,
+    out: &mut W,
+) -> Result<(), Diagnostic> {
+    let arg_pairs = get_arg_pairs(instr_view, instr.args);
+    let arg_value = |pair: &P| {
+        let (offset, len) = pair.idxes();
+        get_value(env, get_var(instr_view, offset, len))
+    };
+
+    // Check that all the args are defined before printing
+    // anything (so that we never print part of a line)
+    for pair in arg_pairs {
+        arg_value(pair)?;
+    }
+
+    // Actually print out the value of the arguments
+    // (writing them one at a time rather than building
+    // a `String` for the whole line)
+    // NOTE TO SELF: DO NOT REMOVE THIS WRITELN
+    let write_err = |e: std::io::Error| {
+        Diagnostic::new(
+            ErrorCode::Io,
+            format!("unable to write program output: {e}"),
+        )
+    };
+    for (i, pair) in arg_pairs.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        out.write_all(sep.as_bytes()).map_err(write_err)?;
+        arg_value(pair)?.write_bril(out).map_err(write_err)?;
+    }
+    writeln!(out).map_err(write_err)?;
+    Ok(())
 }
 
 /// Interprets a unary value operation (`not` and `id`)
-/// (panics if `op` is not an unop)
-pub fn interp_unop<'a>(
-    instr_view: &'a InstrView,
+/// (panics if `op` is not an unop, and returns an error if the
+/// instruction is malformed or its argument is ill-typed)
+pub fn interp_unop<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
     op: Opcode,
-    instr: &FlatInstr,
+    instr: &FlatInstr<P>,
     env: &mut Environment<'a>,
-) {
+) -> Result<(), Diagnostic> {
     if !op.is_unop() {
         panic!("interp_unop called on a non-unary value operation");
     }
 
-    let (dest_start, dest_end): (u32, u32) = instr.dest.into();
-    let dest = get_var(instr_view, dest_start, dest_end);
-    let (args_start, args_end): (u32, u32) = instr.args.into();
-    let args = get_args(instr_view, args_start, args_end);
-    assert!(
-        args.len() == 1,
-        "unary instruction is malformed (no. of args != 1)"
-    );
+    let (dest_offset, dest_len) = instr.dest.idxes();
+    let dest = get_var(instr_view, dest_offset, dest_len);
+    let Some([arg]) = get_n_args(instr_view, instr.args) else {
+        return Err(Diagnostic::new(
+            ErrorCode::MalformedInstr,
+            "unary instruction is malformed (no. of args != 1)",
+        ));
+    };
+
+    let result = eval_unop(op, *get_value(env, arg)?)?;
+    env.insert(dest, result);
+    Ok(())
+}
 
-    let arg = args[0];
-    let value = env.get(arg).expect("arg missing from env");
-    let result = match (op, value) {
+/// Computes the result of the unary operation `op` (`not` or `id`) on
+/// `value` (returns an error if `value` is ill-typed)
+pub fn eval_unop(
+    op: Opcode,
+    value: BrilValue,
+) -> Result<BrilValue, Diagnostic> {
+    match (op, value) {
         (Opcode::Not, BrilValue::BoolVal(b)) => {
-            let b = bool::from(*b);
-            BrilValue::BoolVal((!b).into())
-        }
-        (Opcode::Id, _) => *value,
-        _ => {
-            panic!("argument to unary instruction is ill-typed");
+            let b = bool::from(b);
+            Ok(BrilValue::BoolVal((!b).into()))
         }
-    };
-    env.insert(dest, result);
+        (Opcode::Id, _) => Ok(value),
+        _ => Err(Diagnostic::new(
+            ErrorCode::TypeError,
+            format!("argument to `{op}` is ill-typed"),
+        )),
+    }
+}
+
+/// The error for a binop whose operands are ill-typed
+fn ill_typed_operands(op: Opcode) -> Diagnostic {
+    Diagnostic::new(
+        ErrorCode::TypeError,
+        format!("operands to `{op}` are ill-typed"),
+    )
 }
 
-/// Interprets a binary value operation (panics if `op` is not a binop)
-pub fn interp_binop<'a>(
-    instr_view: &'a InstrView,
+/// Interprets a binary value operation (panics if `op` is not a binop,
+/// and returns an error if the instruction is malformed, its operands are
+/// ill-typed, or it divides by zero)
+pub fn interp_binop<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
     op: Opcode,
-    instr: &FlatInstr,
+    instr: &FlatInstr<P>,
     env: &mut Environment<'a>,
-) {
-    use BrilValue::*;
-    use Opcode::*;
-
+) -> Result<(), Diagnostic> {
     if !op.is_binop() {
         panic!("interp_binop called on a non-binary value operation");
     }
 
-    let (dest_start, dest_end): (u32, u32) = instr.dest.into();
-    let dest = get_var(instr_view, dest_start, dest_end);
+    let (dest_offset, dest_len) = instr.dest.idxes();
+    let dest = get_var(instr_view, dest_offset, dest_len);
+
+    let Some([left, right]) = get_n_args(instr_view, instr.args) else {
+        return Err(Diagnostic::new(
+            ErrorCode::MalformedInstr,
+            format!("no. of args to `{op}` != 2"),
+        ));
+    };
 
-    let (args_start, args_end): (u32, u32) = instr.args.into();
-    let args = get_args(instr_view, args_start, args_end);
-    assert!(args.len() == 2, "no. of args to arithmetic op != 2");
+    let x = get_value(env, left)?;
+    let y = get_value(env, right)?;
+    let value = eval_binop(op, *x, *y)?;
+    env.insert(dest, value);
+    Ok(())
+}
 
-    let x = env.get(args[0]).expect("left operand missing from env");
-    let y = env.get(args[1]).expect("right operand missing from env");
+/// Computes the result of the binary operation `op` on `x` & `y`
+/// (returns an error if the operands are ill-typed, or if `op` divides by
+/// zero)
+pub fn eval_binop(
+    op: Opcode,
+    x: BrilValue,
+    y: BrilValue,
+) -> Result<BrilValue, Diagnostic> {
+    use BrilValue::*;
+    use Opcode::*;
 
     match (x, y) {
-        (IntVal(v1), IntVal(v2)) => {
-            let value = match op {
-                // Arithmetic
-                Add => IntVal(v1.wrapping_add(*v2)),
-                Sub => IntVal(v1.wrapping_sub(*v2)),
-                Mul => IntVal(v1.wrapping_mul(*v2)),
-                Div => IntVal(v1.wrapping_div(*v2)),
-                // Comparison
-                Eq => BoolVal((v1 == v2).into()),
-                Ge => BoolVal((v1 >= v2).into()),
-                Gt => BoolVal((v1 > v2).into()),
-                Le => BoolVal((v1 <= v2).into()),
-                Lt => BoolVal((v1 < v2).into()),
-                _ => unreachable!(),
-            };
-
-            env.insert(dest, value);
-        }
+        (IntVal(v1), IntVal(v2)) => Ok(match op {
+            // Arithmetic
+            Add => IntVal(v1.wrapping_add(v2)),
+            Sub => IntVal(v1.wrapping_sub(v2)),
+            Mul => IntVal(v1.wrapping_mul(v2)),
+            Div if v2 == 0 => {
+                return Err(Diagnostic::new(
+                    ErrorCode::DivisionByZero,
+                    "division by zero",
+                ));
+            }
+            Div => IntVal(v1.wrapping_div(v2)),
+            // Comparison
+            Eq => BoolVal((v1 == v2).into()),
+            Ge => BoolVal((v1 >= v2).into()),
+            Gt => BoolVal((v1 > v2).into()),
+            Le => BoolVal((v1 <= v2).into()),
+            Lt => BoolVal((v1 < v2).into()),
+            // Bitwise (shift amounts are taken modulo 64)
+            #[cfg(feature = "bitwise")]
+            Band => IntVal(v1 & v2),
+            #[cfg(feature = "bitwise")]
+            Bor => IntVal(v1 | v2),
+            #[cfg(feature = "bitwise")]
+            Bxor => IntVal(v1 ^ v2),
+            #[cfg(feature = "bitwise")]
+            Shl => IntVal(v1.wrapping_shl(v2 as u32)),
+            #[cfg(feature = "bitwise")]
+            Shr => IntVal(v1.wrapping_shr(v2 as u32)),
+            _ => return Err(ill_typed_operands(op)),
+        }),
         (BoolVal(b1), BoolVal(b2)) => {
-            let b1 = bool::from(*b1);
-            let b2 = bool::from(*b2);
+            let b1 = bool::from(b1);
+            let b2 = bool::from(b2);
             // Logic
-            let value = match op {
+            Ok(match op {
                 And => BoolVal((b1 && b2).into()),
                 Or => BoolVal((b1 || b2).into()),
-                _ => unreachable!(),
-            };
-            env.insert(dest, value);
+                _ => return Err(ill_typed_operands(op)),
+            })
         }
-        (_, _) => {
-            panic!("operands to binop are ill-typed")
+        (_, _) => Err(ill_typed_operands(op)),
+    }
+}
+
+/// The error for a call (used as a value op) to `func_name`,
+/// which returned without a value
+pub fn missing_return_value(func_name: &str) -> Diagnostic {
+    Diagnostic::new(
+        ErrorCode::MissingReturnValue,
+        format!("@{func_name} didn't return a value"),
+    )
+}
+
+/// Checks that a `call` which supplies `num_args` args to `call_view`
+/// supplies exactly as many args as the callee has parameters
+pub fn check_call_arity<P: IndexPair>(
+    call_view: &InstrView<P>,
+    num_args: usize,
+) -> Result<(), Diagnostic> {
+    if call_view.func_args.len() == num_args {
+        return Ok(());
+    }
+    Err(Diagnostic::new(
+        ErrorCode::TypeError,
+        format!(
+            "@{} expects {} argument(s), but {num_args} were supplied",
+            get_func_name(call_view),
+            call_view.func_args.len()
+        ),
+    ))
+}
+
+/// Binds the args supplied to the `call` instruction `instr` (in
+/// `instr_view`, whose variables are in `env`) to the parameters of
+/// `call_view` in `callee_env` (returns an error if the no. of args is
+/// wrong, or if an arg is undefined or doesn't have the type of its
+/// parameter)
+pub fn bind_call_args<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    env: &Environment<'a>,
+    instr: &FlatInstr<P>,
+    call_view: &'a InstrView<P>,
+    callee_env: &mut Environment<'a>,
+) -> Result<(), Diagnostic> {
+    let arg_pairs = get_arg_pairs(instr_view, instr.args);
+    check_call_arity(call_view, arg_pairs.len())?;
+    for (flat_arg, arg_pair) in call_view.func_args.iter().zip(arg_pairs) {
+        let (offset, len) = arg_pair.idxes();
+        let arg_value = get_value(env, get_var(instr_view, offset, len))?;
+
+        // Function args
+        let (offset, len) = flat_arg.arg_name_idxes.idxes();
+        let arg_name = get_var(call_view, offset, len);
+
+        check_param_type(call_view, flat_arg, arg_value)?;
+        callee_env.insert(arg_name, *arg_value);
+    }
+    Ok(())
+}
+
+/// Checks that `value`, which is passed to the parameter `flat_arg` of
+/// `call_view`, has the parameter's type
+pub fn check_param_type<P: IndexPair>(
+    call_view: &InstrView<P>,
+    flat_arg: &FlatFuncArg<P>,
+    value: &BrilValue,
+) -> Result<(), Diagnostic> {
+    let param_name = || {
+        let (offset, len) = flat_arg.arg_name_idxes.idxes();
+        get_var(call_view, offset, len)
+    };
+    let func_name = get_func_name(call_view);
+    let desired_arg_type: FlatType = flat_arg.arg_type;
+    let actual_arg_type: FlatType = value.get_type().into();
+    match (desired_arg_type, actual_arg_type) {
+        (FlatType::INT, FlatType::INT) | (FlatType::BOOL, FlatType::BOOL) => {
+            Ok(())
         }
+        #[cfg(feature = "ext-values")]
+        (FlatType::EXTERN, FlatType::EXTERN) => Ok(()),
+        (FlatType::NULL, _) | (_, FlatType::NULL) => Err(Diagnostic::new(
+            ErrorCode::TypeError,
+            format!(
+                "encountered null type for argument `{}` of @{func_name}",
+                param_name()
+            ),
+        )),
+        (_, _) => Err(Diagnostic::new(
+            ErrorCode::TypeError,
+            format!(
+                "type of supplied argument doesn't match expected type of \
+                argument `{}` of @{func_name}",
+                param_name()
+            ),
+        )),
     }
 }
 
-/// Interprets a function call
-pub fn interp_call<'a>(
-    instr_view: &'a InstrView,
+/// Looks up the function called by the `call` instruction `instr` in `funcs`
+/// (returns an error if there's no such function, or if it's malformed)
+pub fn lookup_callee<'a, 't, P: IndexPair>(
+    instr_view: &InstrView<P>,
+    instr: &FlatInstr<P>,
+    funcs: &'t FuncTable<'a, P>,
+) -> Result<(&'t InstrView<'a, P>, &'t BranchTargets), Diagnostic> {
+    let (funcs_offset, funcs_len) = instr.funcs.idxes();
+    let func_name = get_func(instr_view, funcs_offset, funcs_len);
+    funcs.get(func_name)?.ok_or_else(|| {
+        Diagnostic::new(
+            ErrorCode::UndefinedFunction,
+            format!("call to undefined function @{func_name}"),
+        )
+    })
+}
+
+/// The error for a call to `func_name` which would exceed the maximum call
+/// depth of `max_call_depth`
+pub fn call_depth_exceeded(
+    max_call_depth: usize,
+    func_name: &str,
+) -> Diagnostic {
+    Diagnostic::new(
+        ErrorCode::CallDepthLimit,
+        format!(
+            "exceeded the maximum call depth of {max_call_depth} \
+            (when calling @{func_name})"
+        ),
+    )
+}
+
+/// Interprets a call to the host function `func_name` in `host_fns`, passing
+/// it the values of the `call` instruction `instr`'s args & storing its
+/// result in the instruction's dest (if it's a value op)
+/// (returns an error if an arg is undefined, if the host function fails, or
+/// if its result doesn't have the dest's type)
+fn interp_host_call<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
     env: &mut Environment<'a>,
-    funcs: &HashMap<&str, &InstrView>,
-    instr: &FlatInstr,
+    instr: &FlatInstr<P>,
     instr_kind: InstrKind,
-) {
-    let (funcs_start, funcs_end): (u32, u32) = instr.funcs.into();
-    let func_name = get_func(instr_view, funcs_start, funcs_end);
-
-    let call_view = funcs
-        .get(func_name)
-        .expect("func_name missing from funcs hashmap");
-
-    let mut fresh_env = Environment::new();
-    // No args supplied to function call, just interpret the callee
-    if instr.args.first == -1 && instr.args.second == -1 {
-        let possible_return_value =
-            interp_instr_view(call_view, &mut fresh_env, funcs)
-                .expect("error encountered when interpreting instr_view");
-        match instr_kind {
-            InstrKind::ValueOp => {
-                // Call function
-                let ret_value = possible_return_value
-                    .expect("missing return value for Call ValueOp");
-                let (dest_start, dest_end): (u32, u32) = instr.dest.into();
-                let dest_var = get_var(instr_view, dest_start, dest_end);
-                env.insert(dest_var, ret_value);
-            }
-            InstrKind::EffectOp => {
-                // There's no dest if it's an effect-op, so we're done
+    func_name: &str,
+    host_fns: &mut HostFns,
+) -> Result<(), Diagnostic> {
+    let args = match instr.args.get() {
+        Some((args_offset, args_len)) => {
+            get_args(instr_view, args_offset, args_len)
+        }
+        None => vec![],
+    };
+    let arg_values = args
+        .iter()
+        .map(|arg| get_value(env, arg).copied())
+        .collect::<Result<Vec<BrilValue>, Diagnostic>>()?;
+    let ret_value = host_fns.call(func_name, &arg_values)?;
+    if let InstrKind::ValueOp = instr_kind {
+        let ret_value =
+            ret_value.ok_or_else(|| missing_return_value(func_name))?;
+        if let Some(ty) = Option::<Type>::from(instr.ty)
+            && ty != ret_value.get_type()
+        {
+            return Err(Diagnostic::new(
+                ErrorCode::TypeError,
+                format!(
+                    "host function @{func_name} returned {ret_value}, \
+                    which isn't of type {ty}"
+                ),
+            ));
+        }
+        let (dest_offset, dest_len) = instr.dest.idxes();
+        let dest_var = get_var(instr_view, dest_offset, dest_len);
+        env.insert(dest_var, ret_value);
+    }
+    Ok(())
+}
+
+/// Interprets a function call, binding the callee's arguments in an
+/// environment taken from `pool`
+/// (returns an error if the callee doesn't exist, the arguments are
+/// ill-typed, the call exceeds the maximum call depth,
+/// or if interpreting the callee fails)
+pub fn interp_call<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    env: &mut Environment<'a>,
+    funcs: &'a FuncTable<'_, P>,
+    instr: &FlatInstr<P>,
+    instr_kind: InstrKind,
+    ctx: &mut InterpContext<W, O>,
+    pool: &mut EnvPool<'a>,
+) -> Result<(), Diagnostic> {
+    let (call_view, call_targets) =
+        match lookup_callee(instr_view, instr, funcs) {
+            Err(e) if e.code == ErrorCode::UndefinedFunction => {
+                let (funcs_offset, funcs_len) = instr.funcs.idxes();
+                let func_name = get_func(instr_view, funcs_offset, funcs_len);
+                if !ctx.host_fns.contains(func_name) {
+                    return Err(e);
+                }
+                return interp_host_call(
+                    instr_view,
+                    env,
+                    instr,
+                    instr_kind,
+                    func_name,
+                    &mut ctx.host_fns,
+                );
             }
-            _ => unreachable!(),
+            callee => callee?,
+        };
+    let func_name = get_func_name(call_view);
+
+    if let Some(max_call_depth) = ctx.limits.max_call_depth
+        && ctx.call_depth >= max_call_depth
+    {
+        return Err(call_depth_exceeded(max_call_depth, func_name));
+    }
+    ctx.call_depth += 1;
+    ctx.peak_call_depth = ctx.peak_call_depth.max(ctx.call_depth);
+
+    // Bind the args supplied to the call instruction to the
+    // callee's parameters, then call the function (returning the
+    // callee's environment to the pool once it's done)
+    let mut fresh_env = pool.take();
+    let ret_value =
+        bind_call_args(instr_view, env, instr, call_view, &mut fresh_env)
+            .and_then(|()| {
+                interp_func(
+                    call_view,
+                    call_targets,
+                    &mut fresh_env,
+                    funcs,
+                    ctx,
+                    pool,
+                )
+            });
+    pool.give(fresh_env);
+    // (the call is over even if it failed, since `keep_going` may carry on
+    // after the error)
+    ctx.call_depth -= 1;
+    let ret_value = ret_value?;
+    match instr_kind {
+        InstrKind::ValueOp => {
+            let ret_value =
+                ret_value.ok_or_else(|| missing_return_value(func_name))?;
+            let (dest_offset, dest_len) = instr.dest.idxes();
+            let dest_var = get_var(instr_view, dest_offset, dest_len);
+            env.insert(dest_var, ret_value);
         }
-    } else {
-        // The function call has args supplied to it
-        let instr_args = instr.args;
+        InstrKind::EffectOp => {
+            // There's no dest if it's an effect-op, so we're done
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
 
-        let (args_start, args_end): (u32, u32) = instr.args.into();
+/// Interprets the function `instr_view` (whose arguments have already been
+/// bound in `env`, and whose branch targets are cached in `targets`),
+/// notifying `ctx.observer` when the function is entered & when it returns
+fn interp_func<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    targets: &BranchTargets,
+    env: &mut Environment<'a>,
+    funcs: &'a FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+    pool: &mut EnvPool<'a>,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    ctx.observer.on_call(instr_view, env);
+    let ret_value =
+        interp_instr_view(instr_view, targets, env, funcs, ctx, pool)?;
+    ctx.observer.on_return(instr_view, ret_value);
+    Ok(ret_value)
+}
 
-        // Args supplied to call instruction
-        let args = get_args(instr_view, args_start, args_end);
+/// Interprets a `phi` instruction, where `prev_label` is the label of the
+/// block that control came from: `dest` is assigned the arg corresponding to
+/// `prev_label`. If there's no such arg, or if it's undefined, `dest` becomes
+/// undefined (as in the reference interpreter).
+pub fn interp_phi<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    instr: &FlatInstr<P>,
+    env: &mut Environment<'a>,
+    prev_label: Option<&str>,
+) -> Result<(), Diagnostic> {
+    let (dest_offset, dest_len) = instr.dest.idxes();
+    let dest = get_var(instr_view, dest_offset, dest_len);
+    let args = instr
+        .args
+        .get()
+        .map_or(vec![], |(offset, len)| get_args(instr_view, offset, len));
+    let labels = instr.instr_labels.get().map_or(vec![], |(offset, len)| {
+        get_labels_vec(instr_view, offset, len)
+    });
+    if args.len() != labels.len() {
+        return Err(Diagnostic::new(
+            ErrorCode::MalformedInstr,
+            "phi instruction must have as many args as labels",
+        ));
+    }
 
-        let args_values: Vec<&BrilValue> = args
-            .into_iter()
-            .map(|a| env.get(a).expect("arg missing from env"))
-            .collect();
+    let value = prev_label
+        .and_then(|prev| labels.iter().position(|label| *label == prev))
+        .and_then(|i| env.get(args[i]).copied());
+    match value {
+        Some(value) => env.insert(dest, value),
+        None => env.remove(dest),
+    };
+    Ok(())
+}
 
-        for (flat_arg, arg_value) in call_view.func_args.iter().zip(args_values)
-        {
-            // Check typing
-            let (start_idx, end_idx): (u32, u32) =
-                flat_arg.arg_name_idxes.into();
-
-            // Function args
-            let arg_name = get_var(call_view, start_idx, end_idx);
-
-            let desired_arg_type: FlatType = flat_arg.arg_type;
-            let actual_arg_type: FlatType = arg_value.get_type().into();
-            match (desired_arg_type, actual_arg_type) {
-                (FlatType::Int, FlatType::Int)
-                | (FlatType::Bool, FlatType::Bool) => {
-                    // Function arg is well-typed, extend the env with the arg_value
-                    fresh_env.insert(arg_name, *arg_value);
-                }
-                (FlatType::Null, _) | (_, FlatType::Null) => {
-                    panic!("encountered null type for function argument");
-                }
-                (_, _) => {
-                    panic!(
-                        "Type of supplied argument doesn't match expected type of function argument"
-                    );
-                }
-            }
+/// Moves on to the second instruction of the superinstruction whose first
+/// instruction is at `current_instr_ptr`, notifying `ctx.observer` & counting
+/// it as a step (as if the instructions hadn't been fused)
+fn next_fused_instr<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    env: &Environment<'a>,
+    ctx: &mut InterpContext<W, O>,
+    current_instr_ptr: &mut usize,
+) -> Result<&'a FlatInstr<P>, Diagnostic> {
+    *current_instr_ptr += 1;
+    let instr = &instr_view.instrs[*current_instr_ptr];
+    ctx.observer
+        .on_instr(instr_view, *current_instr_ptr, instr, env);
+    ctx.tick()?;
+    Ok(instr)
+}
+
+/// Interprets the superinstruction at `current_instr_ptr` (see `fusion.rs`),
+/// i.e. the instruction there & the one after it, leaving `current_instr_ptr`
+/// at the next instruction to execute (& `next_label` at the next label to be
+/// reached, if it branches)
+#[allow(clippy::too_many_arguments)]
+fn interp_fused<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    targets: &BranchTargets,
+    fused: FusedOp,
+    first_op: Opcode,
+    env: &mut Environment<'a>,
+    ctx: &mut InterpContext<W, O>,
+    current_instr_ptr: &mut usize,
+    next_label: &mut usize,
+) -> Result<(), Diagnostic> {
+    let first = &instr_view.instrs[*current_instr_ptr];
+    ctx.tick()?;
+    match fused {
+        FusedOp::ConstBinop => {
+            interp_const(instr_view, first, env)?;
+            let second =
+                next_fused_instr(instr_view, env, ctx, current_instr_ptr)?;
+            let op = Opcode::try_from(second.op()).map_err(|msg| {
+                Diagnostic::new(ErrorCode::MalformedInstr, msg)
+            })?;
+            interp_binop(instr_view, op, second, env)?;
+            *current_instr_ptr += 1;
         }
+        FusedOp::CmpBr => {
+            // The comparison is done directly on the operands, and its result
+            // is branched on without looking it up in `env` again
+            let Some([left, right]) = get_n_args(instr_view, first.args) else {
+                return Err(Diagnostic::new(
+                    ErrorCode::MalformedInstr,
+                    format!("no. of args to `{first_op}` != 2"),
+                ));
+            };
+            let (BrilValue::IntVal(x), BrilValue::IntVal(y)) =
+                (*get_value(env, left)?, *get_value(env, right)?)
+            else {
+                return Err(ill_typed_operands(first_op));
+            };
+            let condition = match first_op {
+                Opcode::Eq => x == y,
+                Opcode::Lt => x < y,
+                Opcode::Gt => x > y,
+                Opcode::Le => x <= y,
+                Opcode::Ge => x >= y,
+                _ => unreachable!("only comparisons are fused with `br`s"),
+            };
+            let (dest_offset, dest_len) = first.dest.idxes();
+            let dest = get_var(instr_view, dest_offset, dest_len);
+            env.insert(dest, BrilValue::BoolVal(condition.into()));
 
-        // Call function
-        match instr_kind {
-            InstrKind::ValueOp => {
-                // Call function
-                let ret_value =
-                    interp_instr_view(call_view, &mut fresh_env, funcs)
-                        .expect("error interpreting function call");
-                let (dest_start, dest_end): (u32, u32) = instr.dest.into();
-                let dest_var = get_var(instr_view, dest_start, dest_end);
-                env.insert(dest_var, ret_value.expect("missing return value"));
-            }
-            InstrKind::EffectOp => {
-                // There is no return value, so we can just ignore the result
-                // of `inerp_instr_view`
-                interp_instr_view(call_view, &mut fresh_env, funcs)
-                    .expect("error interpreting function call");
+            let second =
+                next_fused_instr(instr_view, env, ctx, current_instr_ptr)?;
+            let target = branch_target(
+                instr_view,
+                targets,
+                *current_instr_ptr,
+                second,
+                condition,
+            )?;
+            let target_pc = instr_view.label_table[target].pc;
+            ctx.observer.on_branch(
+                instr_view,
+                *current_instr_ptr,
+                condition,
+                target_pc,
+            );
+            *current_instr_ptr = target_pc;
+            *next_label = target;
+        }
+        FusedOp::IdPrint => {
+            interp_unop(instr_view, Opcode::Id, first, env)?;
+            let second =
+                next_fused_instr(instr_view, env, ctx, current_instr_ptr)?;
+            interp_print(instr_view, second, env, &mut ctx.out)?;
+            *current_instr_ptr += 1;
+        }
+    }
+    Ok(())
+}
 
-                // There's no dest if it's an effect-op, so we're done
+/// Carries on past the `error` raised by the instruction `instr` at `pc`
+/// (whose opcode is `op`, unless it's an extension instruction) if
+/// `ctx.keep_going` is set, so that as many errors as possible are found
+/// in one run: the error is recorded in `ctx.errors`, the instruction's
+/// dest becomes undefined, & a `print` writes `<error>` in place of the
+/// args that are undefined.
+/// - The dests of failed instructions are `poisoned`, so errors caused by
+///   using them (until they're reassigned) aren't recorded again
+/// - Returns the error instead if it can't be recovered from: i.e. if
+///   `keep_going` isn't set, the error isn't recoverable, it occurred in a
+///   nested call (which has already been aborted), or it was raised by a
+///   `jmp` / `br` (which can't pick a target)
+/// - Superinstructions (see `fusion`) are never recovered from
+#[allow(clippy::too_many_arguments)]
+fn recover_from_error<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    instr: &FlatInstr<P>,
+    op: Option<Opcode>,
+    pc: usize,
+    error: Diagnostic,
+    env: &mut Environment<'a>,
+    poisoned: &mut Vec<&'a str>,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<(), Diagnostic> {
+    if !ctx.keep_going
+        || !error.code.is_recoverable()
+        || error.func.is_some()
+        || matches!(op, Some(Opcode::Jmp | Opcode::Br))
+    {
+        return Err(error);
+    }
+    let args: Vec<&str> = get_arg_pairs(instr_view, instr.args)
+        .iter()
+        .map(|pair| {
+            let (offset, len) = pair.idxes();
+            get_var(instr_view, offset, len)
+        })
+        .collect();
+    if !args
+        .iter()
+        .any(|arg| poisoned.contains(arg) && !env.contains_key(arg))
+    {
+        ctx.errors
+            .push(error.located(get_func_name(instr_view), pc));
+    }
+    if let Some((dest_offset, dest_len)) = instr.dest.get() {
+        let dest = get_var(instr_view, dest_offset, dest_len);
+        env.remove(dest);
+        if !poisoned.contains(&dest) {
+            poisoned.push(dest);
+        }
+    }
+
+    if op == Some(Opcode::Print) {
+        let write_err = |e: std::io::Error| {
+            Diagnostic::new(
+                ErrorCode::Io,
+                format!("unable to write program output: {e}"),
+            )
+        };
+        for (i, arg) in args.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            ctx.out.write_all(sep.as_bytes()).map_err(write_err)?;
+            match env.get(arg) {
+                Some(value) => value.write_bril(&mut ctx.out),
+                None => ctx.out.write_all(b"<error>"),
             }
-            _ => unreachable!(),
+            .map_err(write_err)?;
         }
+        writeln!(ctx.out).map_err(write_err)?;
     }
+    Ok(())
 }
 
 /// Interprets all the instructions in `instr_view` using the supplied `env`
-pub fn interp_instr_view<'a>(
-    instr_view: &'a InstrView,
+/// (the output of `print` instructions is written to `ctx.out`, the
+/// environments of nested calls are taken from `pool`, and the targets of
+/// `jmp`s & `br`s are cached in `targets`).
+/// Errors are located at the PC in `instr_view` where they occurred
+/// (unless they occurred in a nested call).
+pub fn interp_instr_view<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    targets: &BranchTargets,
     env: &mut Environment<'a>,
-    funcs: &HashMap<&str, &InstrView>,
-) -> Result<Option<BrilValue>, String> {
-    let func_name = str::from_utf8(instr_view.func_name).unwrap();
-
+    funcs: &'a FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+    pool: &mut EnvPool<'a>,
+) -> Result<Option<BrilValue>, Diagnostic> {
     let mut current_instr_ptr = 0; // Initialize program counter
+    interp_instrs(
+        instr_view,
+        targets,
+        env,
+        funcs,
+        ctx,
+        pool,
+        &mut current_instr_ptr,
+    )
+    .map_err(|e| e.located(get_func_name(instr_view), current_instr_ptr))
+}
+
+/// Offers the loop whose header (at `*current_instr_ptr`) has just been
+/// reached by a back edge to the observer, which may run it (see
+/// `Observer::on_back_edge`), in which case execution moves to wherever
+/// the observer stopped (returns an error if running the loop exceeded the
+/// timeout)
+fn run_loop_in_observer<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    env: &mut Environment<'a>,
+    ctx: &mut InterpContext<W, O>,
+    current_instr_ptr: &mut usize,
+    current_label: &mut Option<usize>,
+    next_label: &mut usize,
+) -> Result<(), Diagnostic> {
+    let max_steps = ctx.loop_step_budget();
+    let Some(exit) = ctx.observer.on_back_edge(
+        instr_view,
+        *current_instr_ptr,
+        env,
+        max_steps,
+    ) else {
+        return Ok(());
+    };
+    ctx.steps += exit.steps;
+    *current_instr_ptr = exit.pc;
+    *current_label = exit.current_label;
+    *next_label = exit.next_label;
+    ctx.check_timeout()
+}
 
-    while current_instr_ptr < instr_view.instrs.len() {
-        let instr = &instr_view.instrs[current_instr_ptr];
+/// The main interpreter loop for `interp_instr_view`, which keeps
+/// `current_instr_ptr` up to date so that errors can be located
+fn interp_instrs<'a, P: IndexPair, W: Write, O: Observer>(
+    instr_view: &'a InstrView<P>,
+    targets: &BranchTargets,
+    env: &mut Environment<'a>,
+    funcs: &'a FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+    pool: &mut EnvPool<'a>,
+    current_instr_ptr: &mut usize,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    // The indexes in the label table of the most recently reached label &
+    // the one before it (`phi` instructions pick their arg based on the
+    // latter), and of the next label that will be reached
+    // (when control falls through / jumps to its PC)
+    let mut current_label: Option<usize> = None;
+    let mut prev_label: Option<usize> = None;
+    let mut next_label = 0;
+    // The variables whose values couldn't be computed
+    // (see `recover_from_error`)
+    let mut poisoned: Vec<&'a str> = vec![];
+    while *current_instr_ptr < instr_view.instrs.len() {
+        // (`Timer`s do nothing unless the `timing` feature is enabled)
+        let dispatch_timer = Timer::start(Category::Dispatch);
+        while let Some(label) = instr_view.label_table.get(next_label)
+            && label.pc <= *current_instr_ptr
+        {
+            prev_label = current_label;
+            current_label = Some(next_label);
+            next_label += 1;
+        }
+        let instr = &instr_view.instrs[*current_instr_ptr];
+        ctx.observer
+            .on_instr(instr_view, *current_instr_ptr, instr, env);
         let instr_kind = instr.get_instr_kind();
         if let InstrKind::Label = instr_kind {
-            // Reached a label annotation in the program, proceed to the next line
-            current_instr_ptr += 1;
+            // Fused opcodes aren't `Opcode`s, so superinstructions end up here
+            // (which keeps them off the path taken by ordinary instructions)
+            if let Some((fused, first_op)) = FusedOp::decode(instr.op()) {
+                drop(dispatch_timer);
+                let _op_timer = Timer::start(Category::Op(first_op));
+                interp_fused(
+                    instr_view,
+                    targets,
+                    fused,
+                    first_op,
+                    env,
+                    ctx,
+                    current_instr_ptr,
+                    &mut next_label,
+                )?;
+                continue;
+            }
+            // (labels are in the label table, so any other such
+            // instruction is malformed & is reported below)
+        }
+        ctx.tick()?;
+        let op = match Opcode::try_from(instr.op()) {
+            Ok(op) => op,
+            Err(msg) => {
+                let error = if instr.op() == EXT_OP {
+                    unsupported_instr(instr_view, instr)
+                } else {
+                    Diagnostic::new(ErrorCode::MalformedInstr, msg)
+                };
+                recover_from_error(
+                    instr_view,
+                    instr,
+                    None,
+                    *current_instr_ptr,
+                    error,
+                    env,
+                    &mut poisoned,
+                    ctx,
+                )?;
+                *current_instr_ptr += 1;
+                continue;
+            }
+        };
+        if ctx.check_types
+            && let Err(error) =
+                check_instr_types(instr_view, op, instr, env, funcs)
+        {
+            recover_from_error(
+                instr_view,
+                instr,
+                Some(op),
+                *current_instr_ptr,
+                error,
+                env,
+                &mut poisoned,
+                ctx,
+            )?;
+            // (an ill-typed `ret` returns without a value)
+            if op == Opcode::Ret {
+                return Ok(None);
+            }
+            *current_instr_ptr += 1;
             continue;
         }
-        let op: Opcode = Opcode::u32_to_opcode(instr.op)
-            .expect("unable to convert u32 to opcode");
+        drop(dispatch_timer);
+        let _op_timer = Timer::start(Category::Op(op));
         match instr_kind {
-            InstrKind::Label => {
+            InstrKind::Label | InstrKind::Extension => {
                 // handled above already
                 unreachable!()
             }
             InstrKind::Const => {
-                let (dest_start, dest_end): (u32, u32) = instr.dest.into();
-                let dest = get_var(instr_view, dest_start, dest_end);
-                let value =
-                    instr.value.try_into().expect("Encountered a null value");
-
-                // Extend the environment so that `dest |-> value`
-                env.insert(dest, value);
-                current_instr_ptr += 1;
+                if let Err(error) = interp_const(instr_view, instr, env) {
+                    recover_from_error(
+                        instr_view,
+                        instr,
+                        Some(op),
+                        *current_instr_ptr,
+                        error,
+                        env,
+                        &mut poisoned,
+                        ctx,
+                    )?;
+                }
+                *current_instr_ptr += 1;
                 continue;
             }
             InstrKind::EffectOp => {
                 if let Opcode::Print = op {
-                    let (args_start, args_end): (u32, u32) = instr.args.into();
-                    let args = get_args(instr_view, args_start, args_end);
-
-                    let arg_values: Vec<&BrilValue> = args
-                        .iter()
-                        .map(|arg| env.get(arg).expect("arg missing from env"))
-                        .collect();
-
-                    let value_strs: Vec<String> = arg_values
-                        .iter()
-                        .map(|value| format!("{value}"))
-                        .collect();
-
-                    let string_to_print = value_strs.join(" ");
-
-                    // Actually print out the value of the arguments
-                    // NOTE TO SELF: DO NOT REMOVE THIS PRINTLN
-                    println!("{string_to_print}");
-
-                    current_instr_ptr += 1;
+                    if let Err(error) =
+                        interp_print(instr_view, instr, env, &mut ctx.out)
+                    {
+                        recover_from_error(
+                            instr_view,
+                            instr,
+                            Some(op),
+                            *current_instr_ptr,
+                            error,
+                            env,
+                            &mut poisoned,
+                            ctx,
+                        )?;
+                    }
+                    *current_instr_ptr += 1;
                 } else if let Opcode::Jmp = op {
-                    // Fetch the start/end idx of the label in the `labels_store`
-                    let (label_start, label_end): (u32, u32) =
-                        instr.instr_labels.into();
-
-                    // Grab the actual vector of label strings corresponding
-                    // to these indices
-                    let labels_vec =
-                        get_labels_vec(instr_view, label_start, label_end);
-
-                    assert!(
-                        labels_vec.len() == 1,
-                        "no. of args to jump instr != 1"
+                    let target = jump_target(
+                        instr_view,
+                        targets,
+                        *current_instr_ptr,
+                        instr,
+                    )?;
+                    let new_pc = instr_view.label_table[target].pc;
+                    ctx.observer.on_jump(
+                        instr_view,
+                        *current_instr_ptr,
+                        new_pc,
                     );
-
-                    let label_str = labels_vec[0];
-
-                    let label_start = label_start as usize;
-                    let label_end = label_end as usize;
-
-                    let all_instrs = instr_view.instrs;
-
-                    // Iterate over the list of instrs to find the index (PC)
-                    // of the instr corresponding to the label (we do this
-                    // by comparing the actual label strings)
-                    let pc_of_label = get_pc_of_label(instr_view, label_str);
-
-                    if let Some(new_pc) = pc_of_label {
-                        // Update `current_instr_ptr` to the PC of the label
-                        current_instr_ptr = new_pc;
-                        continue;
-                    } else {
-                        panic!("cannot find PC corresponding to label")
+                    let is_back_edge = new_pc <= *current_instr_ptr;
+                    // Update `current_instr_ptr` to the PC of the label
+                    *current_instr_ptr = new_pc;
+                    next_label = target;
+                    if is_back_edge && poisoned.is_empty() {
+                        run_loop_in_observer(
+                            instr_view,
+                            env,
+                            ctx,
+                            current_instr_ptr,
+                            &mut current_label,
+                            &mut next_label,
+                        )?;
                     }
+                    continue;
                 } else if let Opcode::Br = op {
-                    let (args_start, args_end): (u32, u32) = instr.args.into();
-                    let args = get_args(instr_view, args_start, args_end);
-                    assert!(
-                        args.len() == 1,
-                        "br instruction must only have 1 arg"
-                    );
-                    let arg = args[0];
-                    let value_of_arg =
-                        env.get(arg).expect("arg missing from env");
+                    let Some([arg]) = get_n_args(instr_view, instr.args) else {
+                        return Err(Diagnostic::new(
+                            ErrorCode::MalformedInstr,
+                            "br instruction must only have 1 arg",
+                        ));
+                    };
+                    let value_of_arg = get_value(env, arg)?;
 
                     if let BrilValue::BoolVal(surrogate_bool) = value_of_arg {
                         let br_condition = bool::from(*surrogate_bool);
-
-                        let (labels_start, labels_end): (u32, u32) =
-                            instr.instr_labels.into();
-                        let labels = get_labels_vec(
+                        let target = branch_target(
                             instr_view,
-                            labels_start,
-                            labels_end,
-                        );
-
-                        assert!(
-                            labels.len() == 2,
-                            "br instruction is malformed (has != 2 labels)"
+                            targets,
+                            *current_instr_ptr,
+                            instr,
+                            br_condition,
+                        )?;
+                        let target_pc = instr_view.label_table[target].pc;
+                        ctx.observer.on_branch(
+                            instr_view,
+                            *current_instr_ptr,
+                            br_condition,
+                            target_pc,
                         );
-
-                        let true_lbl = labels[0];
-                        let true_pc = get_pc_of_label(instr_view, true_lbl)
-                            .expect("label for true case doesn't have a PC");
-
-                        let false_lbl = labels[1];
-                        let false_pc = get_pc_of_label(instr_view, false_lbl)
-                            .expect("label for false case doesn't have a PC");
-
-                        if br_condition {
-                            current_instr_ptr = true_pc;
-                            continue;
-                        } else {
-                            current_instr_ptr = false_pc;
-                            continue;
+                        let is_back_edge = target_pc <= *current_instr_ptr;
+                        *current_instr_ptr = target_pc;
+                        next_label = target;
+                        if is_back_edge && poisoned.is_empty() {
+                            run_loop_in_observer(
+                                instr_view,
+                                env,
+                                ctx,
+                                current_instr_ptr,
+                                &mut current_label,
+                                &mut next_label,
+                            )?;
                         }
+                        continue;
                     } else {
-                        panic!(
-                            "argument to br instruction is ill-typed (doesn't have type bool)"
-                        );
+                        return Err(Diagnostic::new(
+                            ErrorCode::TypeError,
+                            "argument to br instruction is ill-typed \
+                            (doesn't have type bool)",
+                        ));
                     }
                 } else if let Opcode::Call = op {
-                    interp_call(instr_view, env, funcs, instr, instr_kind);
-                    current_instr_ptr += 1;
+                    if let Err(error) = interp_call(
+                        instr_view, env, funcs, instr, instr_kind, ctx, pool,
+                    ) {
+                        recover_from_error(
+                            instr_view,
+                            instr,
+                            Some(op),
+                            *current_instr_ptr,
+                            error,
+                            env,
+                            &mut poisoned,
+                            ctx,
+                        )?;
+                    }
+                    *current_instr_ptr += 1;
                 } else if let Opcode::Ret = op {
                     let return_args = instr.args;
-                    if return_args.first == -1 && return_args.second == -1 {
-                        // No args supplied to Ret
+                    if get_arg_pairs(instr_view, return_args).is_empty() {
+                        // No args supplied to Ret (either a missing or an
+                        // empty `args` list)
                         return Ok(None);
                     }
-                    let args = get_args(
-                        instr_view,
-                        return_args.first as u32,
-                        return_args.second as u32,
-                    );
-                    assert!(
-                        args.len() <= 1,
-                        "too many args supplied to Ret instruction"
-                    );
+                    let Some([arg]) = get_n_args(instr_view, return_args)
+                    else {
+                        return Err(Diagnostic::new(
+                            ErrorCode::MalformedInstr,
+                            "too many args supplied to ret instruction",
+                        ));
+                    };
 
-                    let arg = args[0];
-                    let ret_value = env.get(arg).expect("missing arg in env");
-                    return Ok(Some(*ret_value));
+                    return match get_value(env, arg) {
+                        Ok(ret_value) => Ok(Some(*ret_value)),
+                        Err(error) => {
+                            // (`ret`urns without a value instead)
+                            recover_from_error(
+                                instr_view,
+                                instr,
+                                Some(op),
+                                *current_instr_ptr,
+                                error,
+                                env,
+                                &mut poisoned,
+                                ctx,
+                            )?;
+                            Ok(None)
+                        }
+                    };
                 } else {
                     // There are no more EffectOps to handle
                     unreachable!()
                 }
             }
             InstrKind::ValueOp => {
-                if op.is_binop() {
-                    interp_binop(instr_view, op, instr, env);
+                let result = if op.is_binop() {
+                    interp_binop(instr_view, op, instr, env)
                 } else if op.is_unop() {
-                    interp_unop(instr_view, op, instr, env);
+                    interp_unop(instr_view, op, instr, env)
                 } else if let Opcode::Call = op {
-                    interp_call(instr_view, env, funcs, instr, instr_kind);
+                    interp_call(
+                        instr_view, env, funcs, instr, instr_kind, ctx, pool,
+                    )
+                } else if let Opcode::Phi = op {
+                    let prev_label = prev_label
+                        .map(|idx| get_table_label_name(instr_view, idx));
+                    interp_phi(instr_view, instr, env, prev_label)
                 } else {
                     // there are no more ValueOps to handle
                     unreachable!()
+                };
+                if let Err(error) = result {
+                    recover_from_error(
+                        instr_view,
+                        instr,
+                        Some(op),
+                        *current_instr_ptr,
+                        error,
+                        env,
+                        &mut poisoned,
+                        ctx,
+                    )?;
                 }
-                current_instr_ptr += 1;
+                *current_instr_ptr += 1;
                 continue;
             }
             InstrKind::Nop => {
-                current_instr_ptr += 1;
+                *current_instr_ptr += 1;
             }
         }
     }
     Ok(None)
 }
 
-/// Interprets an entire program using the `cmd_line_args` (args to `main`)
-pub fn interp_program(program: &[InstrView], cmd_line_args: Vec<&str>) {
-    let mut funcs = HashMap::new();
+/// Interprets an entire program using the `cmd_line_args` (args to `main`),
+/// writing the program's output to `ctx.out`.
+/// Returns an error if interpretation is aborted (e.g. because
+/// one of the limits in `ctx.limits` is exceeded, the program has no `main`
+/// function, or a command-line argument can't be parsed).
+pub fn interp_program<P: IndexPair, W: Write, O: Observer>(
+    program: &FlatProgram<P>,
+    cmd_line_args: Vec<&str>,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<(), Diagnostic> {
+    interp_func_table(&FuncTable::new(program.views()), cmd_line_args, ctx)
+}
+
+/// Like `interp_program`, but the program's functions are looked up in
+/// `funcs` (so functions which are loaded lazily are only loaded if
+/// they're called)
+pub fn interp_func_table<P: IndexPair, W: Write, O: Observer>(
+    funcs: &FuncTable<'_, P>,
+    cmd_line_args: Vec<&str>,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<(), Diagnostic> {
+    interp_entry(funcs, "main", cmd_line_args, ctx)?;
+    Ok(())
+}
 
-    // Find the main function
-    for view in program.iter() {
-        let func_name = str::from_utf8(view.func_name).expect("invalid utf-8");
-        // Remove excess null terminators at end of string
-        let func_name = func_name.trim_end_matches(char::from(0));
-        funcs.insert(func_name, view);
+/// Binds the arguments `cmd_line_args` to the parameters of the entry
+/// function `func` in a fresh environment, parsing each of them according to
+/// its parameter's type (returns an error if the no. of arguments is wrong
+/// or one of them can't be parsed)
+pub fn bind_entry_args<'a, P: IndexPair>(
+    func: &'a InstrView<P>,
+    cmd_line_args: &[&str],
+) -> Result<Environment<'a>, Diagnostic> {
+    let entry = get_func_name(func);
+    if func.func_args.len() != cmd_line_args.len() {
+        return Err(Diagnostic::new(
+            ErrorCode::BadArgument,
+            format!(
+                "@{entry} expects {} argument(s), but {} were supplied",
+                func.func_args.len(),
+                cmd_line_args.len()
+            ),
+        ));
     }
 
-    // Prepopulate the env with command line arguments
     let mut env = Environment::new();
-    for (ff_arg, arg_value) in
-        funcs["main"].func_args.iter().zip(cmd_line_args.iter())
-    {
-        let (ff_args_start, ff_args_end): (u32, u32) =
-            ff_arg.arg_name_idxes.into();
-        let arg_name = get_var(funcs["main"], ff_args_start, ff_args_end);
+    for (ff_arg, arg_value) in func.func_args.iter().zip(cmd_line_args.iter()) {
+        let (ff_args_offset, ff_args_len) = ff_arg.arg_name_idxes.idxes();
+        let arg_name = get_var(func, ff_args_offset, ff_args_len);
         match ff_arg.arg_type {
-            FlatType::Bool => {
-                if *arg_value == "true" {
-                    env.insert(arg_name, BrilValue::BoolVal(true.into()));
-                } else if *arg_value == "false" {
-                    env.insert(arg_name, BrilValue::BoolVal(false.into()));
-                }
+            FlatType::BOOL => {
+                let b = arg_value.parse::<bool>().map_err(|_| {
+                    Diagnostic::new(
+                        ErrorCode::BadArgument,
+                        format!(
+                            "argument `{arg_name}`: `{arg_value}` isn't a bool"
+                        ),
+                    )
+                })?;
+                env.insert(arg_name, BrilValue::BoolVal(b.into()));
             }
-            FlatType::Int => {
+            FlatType::INT => {
                 // Actually try to parse the string as an int
-                let i = arg_value
-                    .parse::<i64>()
-                    .expect("Unable to parse string as i64");
+                let i = arg_value.parse::<i64>().map_err(|_| {
+                    Diagnostic::new(
+                        ErrorCode::BadArgument,
+                        format!(
+                            "argument `{arg_name}`: `{arg_value}` isn't an int"
+                        ),
+                    )
+                })?;
                 env.insert(arg_name, BrilValue::IntVal(i));
             }
-            FlatType::Null => {
-                panic!("function argument has unexpected null type");
+            FlatType::NULL => {
+                return Err(Diagnostic::new(
+                    ErrorCode::TypeError,
+                    format!(
+                        "argument `{arg_name}` of @{entry} has unexpected \
+                        null type"
+                    ),
+                ));
+            }
+            arg_type => {
+                return Err(Diagnostic::new(
+                    ErrorCode::BadArgument,
+                    format!(
+                        "argument `{arg_name}` of @{entry} has type {}, \
+                        which can't be passed on the command line",
+                        Type::try_from(arg_type)
+                            .map_or("?".to_string(), |ty| ty.to_string())
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(env)
+}
+
+/// Interprets the function called `entry` (e.g. `main`) in `funcs`, whose
+/// arguments are parsed from `cmd_line_args` according to their types,
+/// returning the value that the function returns (if any).
+/// Returns an error if there's no such function, if the no. of arguments
+/// is wrong or one of them can't be parsed, or if interpretation fails.
+pub fn interp_entry<P: IndexPair, W: Write, O: Observer>(
+    funcs: &FuncTable<'_, P>,
+    entry: &str,
+    cmd_line_args: Vec<&str>,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    let (func, func_targets) = funcs.get(entry)?.ok_or_else(|| {
+        Diagnostic::new(
+            ErrorCode::UndefinedFunction,
+            format!("program has no @{entry} function"),
+        )
+    })?;
+    let mut env = bind_entry_args(func, &cmd_line_args)?;
+    // (the `timing` feature breaks down the time spent by the zero-copy
+    // interpreter, so it always runs the instructions directly)
+    if ctx.predecode
+        && O::IGNORES_EVENTS
+        && !ctx.check_types
+        && !ctx.keep_going
+        && ctx.host_fns.is_empty()
+        && !timing::ENABLED
+    {
+        let idx = funcs.index_of(entry).expect("the entry function exists");
+        return predecode::interp_entry_decoded(funcs, idx, &env, ctx);
+    }
+    let mut pool = EnvPool::default();
+    let result =
+        interp_func(func, func_targets, &mut env, funcs, ctx, &mut pool);
+    ctx.env_bytes = EnvPool::env_bytes(&env) + pool.allocated_bytes();
+    result
+}
+
+/// Interprets an entire program using the `cmd_line_args` (args to `main`),
+/// capturing its output instead of printing it, so that test harnesses can
+/// compare outputs in-process (see `interp_program`)
+pub fn interp_program_captured<P: IndexPair>(
+    program: &FlatProgram<P>,
+    cmd_line_args: Vec<&str>,
+) -> Result<RunOutput, Diagnostic> {
+    let mut ctx = InterpContext::new(vec![], Limits::default());
+    interp_program(program, cmd_line_args, &mut ctx)?;
+    let lines = String::from_utf8_lossy(&ctx.out)
+        .lines()
+        .map(str::to_string)
+        .collect();
+    Ok(RunOutput {
+        lines,
+        steps: ctx.steps,
+    })
+}
+
+/// Interprets the `.fbril` file whose contents are `bytes` (e.g. produced by
+/// `memfile::flatten_to_bytes`), capturing its output, without touching the
+/// filesystem (see `interp_program_captured`)
+/// - `bytes` is copied into a buffer of `u64`s first (see `AlignedBytes`),
+///   since a `&[u8]` isn't necessarily suitably aligned
+pub fn interp_from_bytes(
+    bytes: &[u8],
+    cmd_line_args: Vec<&str>,
+) -> Result<RunOutput, Diagnostic> {
+    let data = AlignedBytes::new(bytes);
+    let program = memfile::load_program(&data).map_err(|msg| {
+        Diagnostic::new(
+            ErrorCode::MalformedFile,
+            format!("malformed file: {msg}"),
+        )
+    })?;
+    match &program {
+        ProgramViews::Narrow(program) => {
+            interp_program_captured(program, cmd_line_args)
+        }
+        ProgramViews::Wide(program) => {
+            interp_program_captured(program, cmd_line_args)
+        }
+        ProgramViews::Compact(program) => {
+            interp_program_captured(program, cmd_line_args)
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod interp_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::diagnostic::{Diagnostic, ErrorCode};
+    use crate::interp::{
+        BranchTargets, FuncTable, InterpContext, Limits, interp_entry,
+        interp_from_bytes, interp_program, interp_program_captured,
+    };
+    use crate::memfile::{
+        AlignedBytes, flatten_to_bytes, get_flat_program, json_to_fbril_bytes,
+    };
+    use crate::types::BrilValue;
+
+    /// Captured output is split into lines, and the dynamic instruction
+    /// count excludes labels
+    #[test]
+    fn test_interp_program_captured() {
+        let file = File::open("test/call-with-args.json")
+            .expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let output = interp_program_captured(&program, vec![])
+            .expect("program should run");
+        assert_eq!(output.lines, ["4", "2", "4"]);
+        // 5 instrs in `main` + 4 in `add2`
+        assert_eq!(output.steps, 9);
+    }
+
+    /// A run stopped by the step limit has executed exactly that many
+    /// instructions, and a limit equal to the no. of steps is enough
+    #[test]
+    fn test_step_limit() {
+        let bytes = flatten_to_bytes(
+            &std::fs::read_to_string("test/call-with-args.json").unwrap(),
+        )
+        .unwrap();
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let run = |max_steps| {
+            let limits = Limits {
+                max_steps: Some(max_steps),
+                ..Limits::default()
+            };
+            let mut ctx = InterpContext::new(vec![], limits);
+            let result = interp_program(&program, vec![], &mut ctx);
+            (result.map_err(|e| e.code), ctx.steps)
+        };
+        assert_eq!(run(9), (Ok(()), 9));
+        assert_eq!(run(8), (Err(ErrorCode::StepLimit), 8));
+        assert_eq!(run(0), (Err(ErrorCode::StepLimit), 0));
+    }
+
+    /// The interpreter records the peak call depth & the memory allocated
+    /// for environments
+    #[test]
+    fn test_memory_stats() {
+        let json_str = std::fs::read_to_string("test/call-with-args.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        interp_program(&program, vec![], &mut ctx).expect("program should run");
+        // (`main` calls `add2`, which doesn't call anything)
+        assert_eq!(ctx.peak_call_depth, 1);
+        assert_eq!(ctx.call_depth, 0);
+        assert!(ctx.env_bytes > 0);
+    }
+
+    /// A program can go from JSON to flat bytes to execution entirely in
+    /// memory, and malformed inputs are reported as errors at either step
+    #[test]
+    fn test_in_memory_pipeline() {
+        let json_str = std::fs::read_to_string("test/call-with-args.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let output =
+            interp_from_bytes(&bytes, vec![]).expect("program should run");
+        assert_eq!(output.lines, ["4", "2", "4"]);
+
+        // (works no matter how the bytes happen to be aligned)
+        let mut shifted = vec![0u8];
+        shifted.extend_from_slice(&bytes);
+        let output = interp_from_bytes(&shifted[1..], vec![]).unwrap();
+        assert_eq!(output.lines, ["4", "2", "4"]);
+
+        assert!(flatten_to_bytes("{").is_err());
+        assert!(flatten_to_bytes(r#"{"functions": 3}"#).is_err());
+        let err = interp_from_bytes(&bytes[..8], vec![]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedFile);
+    }
+
+    /// Branch targets are only resolved the first time they're needed,
+    /// but resolution errors aren't cached
+    #[test]
+    fn test_branch_targets_are_cached() {
+        let targets = BranchTargets::new(2);
+        let mut resolved = 0;
+        for _ in 0..3 {
+            let result = targets.get_or_resolve(0, || {
+                resolved += 1;
+                Ok([1, 0])
+            });
+            assert_eq!(result, Ok([1, 0]));
+        }
+        assert_eq!(resolved, 1);
+
+        let undefined = || {
+            Err(Diagnostic::new(
+                ErrorCode::UndefinedLabel,
+                "undefined label",
+            ))
+        };
+        assert!(targets.get_or_resolve(1, undefined).is_err());
+        assert_eq!(targets.get_or_resolve(1, || Ok([0, 0])), Ok([0, 0]));
+    }
+
+    /// Any function can be the entry point: its arguments are parsed
+    /// according to its parameters' types, & its return value is returned
+    #[test]
+    fn test_interp_entry() {
+        let file =
+            File::open("test/fib_recursive.json").expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let funcs = FuncTable::new(program.views());
+
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        let result = interp_entry(&funcs, "fib", vec!["20"], &mut ctx);
+        assert_eq!(result, Ok(Some(BrilValue::IntVal(6765))));
+        assert!(ctx.out.is_empty());
+
+        let code = |entry: &str, args: Vec<&str>| {
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            interp_entry(&funcs, entry, args, &mut ctx)
+                .unwrap_err()
+                .code
+        };
+        assert_eq!(code("fib", vec![]), ErrorCode::BadArgument);
+        assert_eq!(code("fib", vec!["true"]), ErrorCode::BadArgument);
+        assert_eq!(code("nope", vec![]), ErrorCode::UndefinedFunction);
+    }
+
+    /// The bitwise extension's opcodes are only interpreted with the
+    /// `bitwise` feature (otherwise they're extension instructions)
+    #[test]
+    fn test_bitwise_ops() {
+        let json = serde_json::json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 12 },
+                { "op": "const", "dest": "y", "type": "int", "value": 10 },
+                { "op": "const", "dest": "n", "type": "int", "value": -1 },
+                { "op": "band", "dest": "a", "type": "int", "args": ["x", "y"] },
+                { "op": "bor", "dest": "b", "type": "int", "args": ["x", "y"] },
+                { "op": "bxor", "dest": "c", "type": "int", "args": ["x", "y"] },
+                { "op": "shl", "dest": "d", "type": "int", "args": ["x", "y"] },
+                { "op": "shr", "dest": "e", "type": "int", "args": ["n", "y"] },
+                { "op": "print", "args": ["a", "b", "c", "d", "e"] }
+            ]
+        }] });
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let output = interp_program_captured(&program, vec![]);
+        if cfg!(feature = "bitwise") {
+            assert_eq!(output.unwrap().lines, ["8 14 6 12288 -1"]);
+        } else {
+            let error = output.unwrap_err();
+            assert_eq!(error.code, ErrorCode::UnsupportedInstr);
+            assert!(error.message.contains("--features bitwise"));
+        }
+    }
+
+    /// With `keep_going`, recoverable errors are recorded & the run carries
+    /// on (printing `<error>` for values that couldn't be computed), errors
+    /// caused by those values aren't reported again, & a `br` which can't
+    /// pick a target still aborts the run
+    #[test]
+    fn test_keep_going() {
+        let json = serde_json::json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "a", "type": "int", "value": 4 },
+                { "op": "const", "dest": "z", "type": "int", "value": 0 },
+                { "op": "div", "dest": "q", "type": "int", "args": ["a", "z"] },
+                { "op": "add", "dest": "r", "type": "int", "args": ["q", "a"] },
+                { "op": "print", "args": ["a", "r"] },
+                { "op": "print", "args": ["missing"] },
+                { "op": "const", "dest": "q", "type": "int", "value": 1 },
+                { "op": "add", "dest": "r", "type": "int", "args": ["q", "a"] },
+                { "op": "print", "args": ["r"] },
+                { "op": "br", "args": ["r"], "labels": ["end", "end"] },
+                { "label": "end" }
+            ]
+        }] });
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        let error = interp_program(&program, vec![], &mut ctx).unwrap_err();
+        assert_eq!(
+            (error.code, error.pc),
+            (ErrorCode::DivisionByZero, Some(2))
+        );
+        assert!(ctx.out.is_empty());
+
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        ctx.keep_going = true;
+        let error = interp_program(&program, vec![], &mut ctx).unwrap_err();
+        assert_eq!((error.code, error.pc), (ErrorCode::TypeError, Some(9)));
+        assert_eq!(
+            String::from_utf8_lossy(&ctx.out),
+            "4 <error>\n<error>\n5\n"
+        );
+        let recovered: Vec<_> = ctx
+            .errors
+            .iter()
+            .map(|error| (error.code, error.pc))
+            .collect();
+        assert_eq!(
+            recovered,
+            [
+                (ErrorCode::DivisionByZero, Some(2)),
+                (ErrorCode::UndefinedVariable, Some(5))
+            ]
+        );
+    }
+
+    /// An empty `args` list is the same as a missing one: a `call` with
+    /// `"args": []` binds no parameters, & a `ret` with `"args": []`
+    /// returns no value
+    #[test]
+    fn test_empty_arg_lists() {
+        let json = serde_json::json!({ "functions": [
+            {
+                "name": "main",
+                "instrs": [
+                    { "op": "call", "dest": "x", "type": "int",
+                      "funcs": ["seven"], "args": [] },
+                    { "op": "call", "funcs": ["done"], "args": [] },
+                    { "op": "print", "args": ["x"] },
+                    { "op": "ret", "args": [] }
+                ]
+            },
+            {
+                "name": "seven",
+                "type": "int",
+                "instrs": [
+                    { "op": "const", "dest": "v", "type": "int", "value": 7 },
+                    { "op": "ret", "args": ["v"] }
+                ]
+            },
+            {
+                "name": "done",
+                "args": [],
+                "instrs": [
+                    { "op": "nop", "args": [], "labels": [], "funcs": [] },
+                    { "op": "ret", "args": [] }
+                ]
+            }
+        ] });
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let output = interp_program_captured(&program, vec![])
+            .expect("program should run");
+        assert_eq!(output.lines, ["7"]);
+
+        let funcs = FuncTable::new(program.views());
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        assert_eq!(interp_entry(&funcs, "done", vec![], &mut ctx), Ok(None));
+    }
+
+    /// A program whose `main` takes `n` parameters, passes all of them to
+    /// `@sum` (which adds them up) & prints them along with their sum
+    fn many_args_program(n: usize) -> serde_json::Value {
+        let params: Vec<String> = (0..n).map(|i| format!("p{i}")).collect();
+        let typed_params: Vec<_> = params
+            .iter()
+            .map(|p| serde_json::json!({ "name": p, "type": "int" }))
+            .collect();
+        let mut sum_instrs = vec![serde_json::json!(
+            { "op": "const", "dest": "acc", "type": "int", "value": 0 }
+        )];
+        sum_instrs.extend(params.iter().map(|p| {
+            serde_json::json!(
+                { "op": "add", "dest": "acc", "type": "int", "args": ["acc", p] }
+            )
+        }));
+        sum_instrs.push(serde_json::json!({ "op": "ret", "args": ["acc"] }));
+        serde_json::json!({ "functions": [
+            {
+                "name": "main",
+                "args": typed_params,
+                "instrs": [
+                    { "op": "call", "dest": "s", "type": "int",
+                      "funcs": ["sum"], "args": params },
+                    { "op": "print", "args": params },
+                    { "op": "print", "args": ["s"] }
+                ]
+            },
+            {
+                "name": "sum",
+                "args": typed_params,
+                "type": "int",
+                "instrs": sum_instrs
+            }
+        ] })
+    }
+
+    /// Calls (& functions) with any no. of args are flattened, serialized
+    /// & interpreted correctly, with & without pre-decoding
+    #[test]
+    fn test_many_args() {
+        for n in [0, 1, 2, 7, 16, 33, 64] {
+            let json = many_args_program(n);
+            let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+            let program =
+                get_flat_program(&bytes).expect("valid file should load");
+            let views = program.views();
+            assert_eq!(views[1].func_args.len(), n);
+
+            let args: Vec<String> = (1..=n).map(|i| i.to_string()).collect();
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let expected = [args.join(" "), (n * (n + 1) / 2).to_string()];
+            for predecode in [true, false] {
+                let mut ctx = InterpContext::new(vec![], Limits::default());
+                ctx.predecode = predecode;
+                interp_program(&program, args.clone(), &mut ctx)
+                    .expect("program should run");
+                let out = String::from_utf8(ctx.out).unwrap();
+                assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+            }
+
+            // (too few args, either for `main` or in a `call`, are reported
+            // as an error)
+            if n > 0 {
+                let mut ctx = InterpContext::new(vec![], Limits::default());
+                let error =
+                    interp_program(&program, args[1..].to_vec(), &mut ctx)
+                        .unwrap_err();
+                assert_eq!(error.code, ErrorCode::BadArgument);
+
+                let mut json = json;
+                let call_args = json["functions"][0]["instrs"][0]["args"]
+                    .as_array_mut()
+                    .unwrap();
+                call_args.pop();
+                let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+                let program =
+                    get_flat_program(&bytes).expect("valid file should load");
+                for predecode in [true, false] {
+                    let mut ctx = InterpContext::new(vec![], Limits::default());
+                    ctx.predecode = predecode;
+                    let error =
+                        interp_program(&program, args.clone(), &mut ctx)
+                            .unwrap_err();
+                    assert_eq!(
+                        (error.code, error.message),
+                        (
+                            ErrorCode::TypeError,
+                            format!(
+                                "@sum expects {n} argument(s), but {} were \
+                                supplied",
+                                n - 1
+                            )
+                        )
+                    );
+                }
+            }
+        }
+    }
+
+    /// Runs every program in `test/` which has a reference output
+    /// (`test/*.out`), with the arguments in the `# ARGS:` comment of its
+    /// `.bril` source, checking that it prints exactly that output
+    fn check_reference_outputs() {
+        let mut checked = 0;
+        for entry in std::fs::read_dir("test").expect("Unable to read test/") {
+            let path = entry.expect("Unable to read test/").path();
+            let expected = path.with_extension("out");
+            if path.extension().is_none_or(|ext| ext != "json")
+                || !expected.exists()
+            {
+                continue;
             }
+            let file = File::open(&path).expect("Unable to open file");
+            let json: serde_json::Value =
+                serde_json::from_reader(BufReader::new(file))
+                    .expect("Unable to parse JSON");
+            let source = std::fs::read_to_string(path.with_extension("bril"))
+                .unwrap_or_default();
+            let args: Vec<&str> = source
+                .lines()
+                .find_map(|line| line.split_once("ARGS:"))
+                .map_or(vec![], |(_, args)| args.split_whitespace().collect());
+
+            let bytes = json_to_fbril_bytes(&json);
+            let data = AlignedBytes::new(&bytes);
+            let program =
+                get_flat_program(&data).expect("valid file should load");
+
+            // (programs which end in an error still print what they
+            // printed up until then)
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            let _ = interp_program(&program, args, &mut ctx);
+            assert_eq!(
+                String::from_utf8_lossy(&ctx.out).trim_end(),
+                std::fs::read_to_string(&expected).unwrap().trim_end(),
+                "{} printed the wrong output",
+                path.display()
+            );
+            checked += 1;
         }
+        assert!(checked > 0);
     }
 
-    interp_instr_view(funcs["main"], &mut env, &funcs)
-        .expect("unexpected error when interpreting main");
+    /// Golden test: `print` output matches the reference interpreter's
+    /// byte for byte (run on a thread with a bigger stack, since eg.
+    /// `ackermann` recurses deeply)
+    #[test]
+    fn test_print_matches_reference_outputs() {
+        std::thread::Builder::new()
+            .stack_size(256 << 20)
+            .spawn(check_reference_outputs)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
 }
@@ -0,0 +1,863 @@
+use std::collections::HashMap;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{AbiParam, InstBuilder, MemFlagsData, types};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Module, default_libcall_names};
+
+use crate::interp::{
+    Environment, get_func_name, get_label_idx, get_n_args, get_n_labels,
+    get_var,
+};
+use crate::observer::{LoopExit, Observer};
+use crate::trace::{Trace, TraceRecorder};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The signature of a compiled trace: `vars` points to the values of the
+/// trace's variables (which are loaded on entry & stored back on exit),
+/// the loop runs for at most `max_iters` iterations & the no. of complete
+/// iterations is written to `iters`. Returns the index of the exit taken.
+type TraceFn = unsafe extern "C" fn(
+    vars: *mut i64,
+    max_iters: u64,
+    iters: *mut u64,
+) -> u64;
+
+/// The types of variables that compiled code supports, both of which are
+/// held in an `i64` (bools as 0 or 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VarType {
+    Int,
+    Bool,
+}
+
+/// An instruction in a trace, where variables are indexes into the trace's
+/// list of variables & `exit`s are indexes into its list of exits
+enum TraceOp {
+    Const {
+        dest: usize,
+        value: i64,
+    },
+    Binop {
+        op: Opcode,
+        dest: usize,
+        args: [usize; 2],
+    },
+    /// A `div` exits if it would divide by zero (or overflow), so that the
+    /// interpreter executes it instead
+    Div {
+        dest: usize,
+        args: [usize; 2],
+        exit: usize,
+    },
+    Unop {
+        op: Opcode,
+        dest: usize,
+        arg: usize,
+    },
+    /// A `br` exits if its condition isn't `expected`
+    Guard {
+        cond: usize,
+        expected: bool,
+        exit: usize,
+    },
+}
+
+/// A trace which has been compiled to native code
+/// - `vars` are the names & types of the variables that the trace uses
+/// - `steps` is the no. of instructions in one iteration of the loop
+/// - `exits` are where the interpreter resumes after each exit, with
+///   `steps` being the no. of instructions executed in the iteration that
+///   was left (exit 0 is taken at the loop header, once the loop has run
+///   for as many iterations as it may)
+struct CompiledTrace {
+    vars: Vec<(String, VarType)>,
+    steps: u64,
+    exits: Vec<LoopExit>,
+    code: TraceFn,
+}
+
+/// An `Observer` which compiles hot loops to native code with Cranelift &
+/// runs them instead of the interpreter
+/// - Loops are traced by a `TraceRecorder` (see `trace.rs`), & each trace is
+///   compiled the next time its loop header is reached by a back edge.
+///   Only traces whose instructions are all arithmetic, comparisons, logic
+///   operations, `const`s, `id`s, `nop`s, `jmp`s & `br`s on `int`s & `bool`s
+///   can be compiled (e.g. loops which `print` or `call` are interpreted).
+/// - Every `br` in a trace is compiled to a guard, which returns to the
+///   interpreter (at the `br`'s other target) as soon as the branch goes a
+///   different way from when the trace was recorded, as does a `div` whose
+///   divisor is 0 (so that the interpreter reports the error)
+/// - Compiled code is only entered if every variable that the trace uses is
+///   defined & has the type it had when the trace was compiled
+/// - `traces` maps each function name to the compiled traces of its loops,
+///   keyed by the PCs of their headers (`None` if the trace couldn't be
+///   compiled)
+/// - `native_steps` is the no. of instructions executed by compiled code
+pub struct TraceJit {
+    recorder: TraceRecorder,
+    module: JITModule,
+    traces: HashMap<String, HashMap<usize, Option<CompiledTrace>>>,
+    pub native_steps: u64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl VarType {
+    /// The type of `value` (`None` if compiled code doesn't support it)
+    fn of(value: &BrilValue) -> Option<Self> {
+        match value {
+            BrilValue::IntVal(_) => Some(VarType::Int),
+            BrilValue::BoolVal(_) => Some(VarType::Bool),
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(_) => None,
+        }
+    }
+}
+
+/// The index in `instr_view.label_table` of the label of the block which
+/// contains the instruction at `pc` (i.e. the `current_label` of
+/// `interp::interp_instrs` when it executes the instruction)
+fn block_label<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    pc: usize,
+) -> Option<usize> {
+    labels_reached(instr_view, pc).checked_sub(1)
+}
+
+/// The no. of labels in `instr_view.label_table` at or before `pc`
+/// (i.e. the `next_label` of `interp::interp_instrs` when it executes the
+/// instruction at `pc`)
+fn labels_reached<P: IndexPair>(instr_view: &InstrView<P>, pc: usize) -> usize {
+    instr_view
+        .label_table
+        .iter()
+        .take_while(|label| label.pc <= pc)
+        .count()
+}
+
+/// Translates a trace into `TraceOp`s, giving each variable the type of its
+/// value in `env`
+struct TraceLowering<'a, 'v, P: IndexPair> {
+    instr_view: &'a InstrView<'a, P>,
+    env: &'v Environment<'a>,
+    vars: Vec<(String, VarType)>,
+    var_idxes: HashMap<&'a str, usize>,
+    exits: Vec<LoopExit>,
+    ops: Vec<TraceOp>,
+}
+
+impl<'a, 'v, P: IndexPair> TraceLowering<'a, 'v, P> {
+    /// The index of the variable `name`, which is used as a `ty`
+    /// (returns `None` if the variable doesn't have that type in the
+    /// environment)
+    fn var(&mut self, name: &'a str, ty: VarType) -> Option<usize> {
+        if let Some(&idx) = self.var_idxes.get(name) {
+            return (self.vars[idx].1 == ty).then_some(idx);
+        }
+        if VarType::of(self.env.get(name)?)? != ty {
+            return None;
+        }
+        self.vars.push((name.to_string(), ty));
+        self.var_idxes.insert(name, self.vars.len() - 1);
+        Some(self.vars.len() - 1)
+    }
+
+    /// The index of the `dest` of `instr`, which is used as a `ty`
+    fn dest(&mut self, instr: &FlatInstr<P>, ty: VarType) -> Option<usize> {
+        let (offset, len) = instr.dest.get()?;
+        self.var(get_var(self.instr_view, offset, len), ty)
+    }
+
+    /// The indexes of the `N` args of `instr`, which are all used as `ty`s
+    fn args<const N: usize>(
+        &mut self,
+        instr: &FlatInstr<P>,
+        ty: VarType,
+    ) -> Option<[usize; N]> {
+        let args: [&'a str; N] = get_n_args(self.instr_view, instr.args)?;
+        let mut idxes = [0; N];
+        for (idx, arg) in idxes.iter_mut().zip(args) {
+            *idx = self.var(arg, ty)?;
+        }
+        Some(idxes)
+    }
+
+    /// Adds an exit which resumes at `exit`, returning its index
+    /// (exit 0 is reserved for the loop header)
+    fn exit(&mut self, exit: LoopExit) -> usize {
+        self.exits.push(exit);
+        self.exits.len()
+    }
+
+    /// Lowers `trace`, returning the index in the label table of the loop
+    /// header's label (returns `None` if the trace can't be compiled)
+    fn lower(&mut self, trace: &Trace) -> Option<usize> {
+        if trace.pcs.first() != Some(&trace.header_pc) {
+            return None;
+        }
+        let instr_view = self.instr_view;
+        let label_pc = |label: &str| {
+            let idx = get_label_idx(instr_view, label)?;
+            Some((idx, instr_view.label_table[idx].pc))
+        };
+        let mut guards = trace.guards.iter();
+        let mut header_label = None;
+        for (i, &pc) in trace.pcs.iter().enumerate() {
+            let is_last = i + 1 == trace.pcs.len();
+            let next_pc =
+                trace.pcs.get(i + 1).copied().unwrap_or(trace.header_pc);
+            let instr = instr_view.instrs.get(pc)?;
+            let op = Opcode::try_from(instr.op()).ok()?;
+            match op {
+                Opcode::Jmp => {
+                    let [label] = get_n_labels(instr_view, instr.instr_labels)?;
+                    let (target, target_pc) = label_pc(label)?;
+                    if target_pc != next_pc {
+                        return None;
+                    }
+                    if is_last {
+                        header_label = Some(target);
+                    }
+                    continue;
+                }
+                Opcode::Br => {
+                    let [cond] = self.args(instr, VarType::Bool)?;
+                    let [true_lbl, false_lbl] =
+                        get_n_labels(instr_view, instr.instr_labels)?;
+                    let &(guard_pc, taken) = guards.next()?;
+                    let (target, other) = if taken {
+                        (label_pc(true_lbl)?, label_pc(false_lbl)?)
+                    } else {
+                        (label_pc(false_lbl)?, label_pc(true_lbl)?)
+                    };
+                    if guard_pc != pc || target.1 != next_pc {
+                        return None;
+                    }
+                    let exit = self.exit(LoopExit {
+                        pc: other.1,
+                        current_label: block_label(instr_view, pc),
+                        next_label: other.0,
+                        steps: i as u64 + 1,
+                    });
+                    self.ops.push(TraceOp::Guard {
+                        cond,
+                        expected: taken,
+                        exit,
+                    });
+                    if is_last {
+                        header_label = Some(target.0);
+                    }
+                    continue;
+                }
+                _ if next_pc != pc + 1 => return None,
+                Opcode::Nop => {}
+                Opcode::Const => {
+                    let op = match BrilValue::try_from(instr.value).ok()? {
+                        BrilValue::IntVal(value) => TraceOp::Const {
+                            dest: self.dest(instr, VarType::Int)?,
+                            value,
+                        },
+                        BrilValue::BoolVal(b) => TraceOp::Const {
+                            dest: self.dest(instr, VarType::Bool)?,
+                            value: bool::from(b) as i64,
+                        },
+                        #[cfg(feature = "ext-values")]
+                        BrilValue::Extern(_) => return None,
+                    };
+                    self.ops.push(op);
+                }
+                Opcode::Add | Opcode::Sub | Opcode::Mul => {
+                    let args = self.args(instr, VarType::Int)?;
+                    let dest = self.dest(instr, VarType::Int)?;
+                    self.ops.push(TraceOp::Binop { op, dest, args });
+                }
+                Opcode::Div => {
+                    let args = self.args(instr, VarType::Int)?;
+                    let dest = self.dest(instr, VarType::Int)?;
+                    let exit = self.exit(LoopExit {
+                        pc,
+                        current_label: block_label(instr_view, pc),
+                        next_label: labels_reached(instr_view, pc),
+                        steps: i as u64,
+                    });
+                    self.ops.push(TraceOp::Div { dest, args, exit });
+                }
+                Opcode::Eq
+                | Opcode::Lt
+                | Opcode::Gt
+                | Opcode::Le
+                | Opcode::Ge => {
+                    let args = self.args(instr, VarType::Int)?;
+                    let dest = self.dest(instr, VarType::Bool)?;
+                    self.ops.push(TraceOp::Binop { op, dest, args });
+                }
+                Opcode::And | Opcode::Or => {
+                    let args = self.args(instr, VarType::Bool)?;
+                    let dest = self.dest(instr, VarType::Bool)?;
+                    self.ops.push(TraceOp::Binop { op, dest, args });
+                }
+                Opcode::Not => {
+                    let [arg] = self.args(instr, VarType::Bool)?;
+                    let dest = self.dest(instr, VarType::Bool)?;
+                    self.ops.push(TraceOp::Unop { op, dest, arg });
+                }
+                Opcode::Id => {
+                    let [name] = get_n_args(instr_view, instr.args)?;
+                    let ty = VarType::of(self.env.get(name)?)?;
+                    let arg = self.var(name, ty)?;
+                    let dest = self.dest(instr, ty)?;
+                    self.ops.push(TraceOp::Unop { op, dest, arg });
+                }
+                // (`print`, `call`, `ret`, `phi` & extension instructions
+                // can't be compiled)
+                _ => return None,
+            }
+        }
+        header_label
+    }
+}
+
+/// Compiles `trace`, a trace of a loop in `instr_view` whose header has just
+/// been reached with the environment `env`, into `module`
+/// (returns `None` if the trace can't be compiled)
+fn compile_trace<P: IndexPair>(
+    module: &mut JITModule,
+    instr_view: &InstrView<P>,
+    trace: &Trace,
+    env: &Environment,
+) -> Option<CompiledTrace> {
+    let mut lowering = TraceLowering {
+        instr_view,
+        env,
+        vars: vec![],
+        var_idxes: HashMap::new(),
+        exits: vec![],
+        ops: vec![],
+    };
+    let header_label = lowering.lower(trace)?;
+    let last_pc = *trace.pcs.last()?;
+    lowering.exits.insert(
+        0,
+        LoopExit {
+            pc: trace.header_pc,
+            current_label: block_label(instr_view, last_pc),
+            next_label: header_label,
+            steps: 0,
+        },
+    );
+
+    let ptr = module.target_config().pointer_type();
+    let mut ctx = module.make_context();
+    ctx.func.signature.params = vec![
+        AbiParam::new(ptr),
+        AbiParam::new(types::I64),
+        AbiParam::new(ptr),
+    ];
+    ctx.func.signature.returns = vec![AbiParam::new(types::I64)];
+    let mut fn_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+    let flags = MemFlagsData::trusted();
+
+    // Load the variables & jump to the loop header
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let &[vars_ptr, max_iters, iters_ptr] = builder.block_params(entry) else {
+        unreachable!("the signature has 3 params");
+    };
+    let vars: Vec<Variable> = (0..lowering.vars.len())
+        .map(|idx| {
+            let var = builder.declare_var(types::I64);
+            let value =
+                builder
+                    .ins()
+                    .load(types::I64, flags, vars_ptr, 8 * idx as i32);
+            builder.def_var(var, value);
+            var
+        })
+        .collect();
+    let iters = builder.declare_var(types::I64);
+    let zero = builder.ins().iconst(types::I64, 0);
+    builder.def_var(iters, zero);
+    let header = builder.create_block();
+    let exit = builder.create_block();
+    builder.append_block_param(exit, types::I64);
+    builder.ins().jump(header, &[]);
+
+    // Leave through exit 0 once the loop has run `max_iters` times
+    builder.switch_to_block(header);
+    let n = builder.use_var(iters);
+    let done = builder.ins().icmp(IntCC::Equal, n, max_iters);
+    let body = builder.create_block();
+    let exit_idx = builder.ins().iconst(types::I64, 0);
+    builder
+        .ins()
+        .brif(done, exit, &[exit_idx.into()], body, &[]);
+    builder.switch_to_block(body);
+
+    for op in &lowering.ops {
+        match *op {
+            TraceOp::Const { dest, value } => {
+                let value = builder.ins().iconst(types::I64, value);
+                builder.def_var(vars[dest], value);
+            }
+            TraceOp::Binop {
+                op,
+                dest,
+                args: [x, y],
+            } => {
+                let x = builder.use_var(vars[x]);
+                let y = builder.use_var(vars[y]);
+                let cmp = |cc| match op {
+                    Opcode::Eq => Some(IntCC::Equal),
+                    Opcode::Lt => Some(IntCC::SignedLessThan),
+                    Opcode::Gt => Some(IntCC::SignedGreaterThan),
+                    Opcode::Le => Some(IntCC::SignedLessThanOrEqual),
+                    Opcode::Ge => Some(IntCC::SignedGreaterThanOrEqual),
+                    _ => cc,
+                };
+                let value = match (op, cmp(None)) {
+                    (_, Some(cc)) => {
+                        let b = builder.ins().icmp(cc, x, y);
+                        builder.ins().uextend(types::I64, b)
+                    }
+                    (Opcode::Add, _) => builder.ins().iadd(x, y),
+                    (Opcode::Sub, _) => builder.ins().isub(x, y),
+                    (Opcode::Mul, _) => builder.ins().imul(x, y),
+                    (Opcode::And, _) => builder.ins().band(x, y),
+                    (Opcode::Or, _) => builder.ins().bor(x, y),
+                    _ => unreachable!("`{op}` isn't lowered to a `Binop`"),
+                };
+                builder.def_var(vars[dest], value);
+            }
+            TraceOp::Div {
+                dest,
+                args: [x, y],
+                exit: idx,
+            } => {
+                // (`sdiv` traps on both of these, & the interpreter's
+                // `wrapping_div` of `MIN` by -1 is left to the interpreter)
+                let x = builder.use_var(vars[x]);
+                let y = builder.use_var(vars[y]);
+                let by_zero = builder.ins().icmp_imm_s(IntCC::Equal, y, 0);
+                let is_min =
+                    builder.ins().icmp_imm_s(IntCC::Equal, x, i64::MIN);
+                let by_minus_one =
+                    builder.ins().icmp_imm_s(IntCC::Equal, y, -1);
+                let overflows = builder.ins().band(is_min, by_minus_one);
+                let fails = builder.ins().bor(by_zero, overflows);
+                let exit_idx = builder.ins().iconst(types::I64, idx as i64);
+                let next = builder.create_block();
+                builder
+                    .ins()
+                    .brif(fails, exit, &[exit_idx.into()], next, &[]);
+                builder.switch_to_block(next);
+                let value = builder.ins().sdiv(x, y);
+                builder.def_var(vars[dest], value);
+            }
+            TraceOp::Unop { op, dest, arg } => {
+                let arg = builder.use_var(vars[arg]);
+                let value = match op {
+                    Opcode::Not => builder.ins().bxor_imm_s(arg, 1),
+                    _ => arg,
+                };
+                builder.def_var(vars[dest], value);
+            }
+            TraceOp::Guard {
+                cond,
+                expected,
+                exit: idx,
+            } => {
+                let cond = builder.use_var(vars[cond]);
+                let exit_idx = builder.ins().iconst(types::I64, idx as i64);
+                let next = builder.create_block();
+                if expected {
+                    builder.ins().brif(
+                        cond,
+                        next,
+                        &[],
+                        exit,
+                        &[exit_idx.into()],
+                    );
+                } else {
+                    builder.ins().brif(
+                        cond,
+                        exit,
+                        &[exit_idx.into()],
+                        next,
+                        &[],
+                    );
+                }
+                builder.switch_to_block(next);
+            }
+        }
+    }
+    let n = builder.use_var(iters);
+    let n = builder.ins().iadd_imm_s(n, 1);
+    builder.def_var(iters, n);
+    builder.ins().jump(header, &[]);
+
+    // Store the variables & the no. of iterations, & return the exit's index
+    builder.switch_to_block(exit);
+    let exit_idx = builder.block_params(exit)[0];
+    for (idx, &var) in vars.iter().enumerate() {
+        let value = builder.use_var(var);
+        builder.ins().store(flags, value, vars_ptr, 8 * idx as i32);
+    }
+    let n = builder.use_var(iters);
+    builder.ins().store(flags, n, iters_ptr, 0);
+    builder.ins().return_(&[exit_idx]);
+    builder.seal_all_blocks();
+    builder.finalize(module.target_config());
+
+    let id = module
+        .declare_anonymous_function(&ctx.func.signature)
+        .ok()?;
+    module.define_function(id, &mut ctx).ok()?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().ok()?;
+    let code = module.get_finalized_function(id);
+    Some(CompiledTrace {
+        vars: lowering.vars,
+        steps: trace.pcs.len() as u64,
+        exits: lowering.exits,
+        // SAFETY: the function was compiled with the signature of `TraceFn`
+        code: unsafe { std::mem::transmute::<*const u8, TraceFn>(code) },
+    })
+}
+
+impl CompiledTrace {
+    /// Runs the loop from its header for at most `max_steps` instructions,
+    /// updating `env` (returns `None` without running the loop if a
+    /// variable is undefined or has a different type from when the trace
+    /// was compiled, or if not even one iteration fits in `max_steps`)
+    fn run(&self, env: &mut Environment, max_steps: u64) -> Option<LoopExit> {
+        let max_iters = max_steps / self.steps;
+        if max_iters == 0 {
+            return None;
+        }
+        let mut values = Vec::with_capacity(self.vars.len());
+        for (name, ty) in &self.vars {
+            values.push(match (ty, env.get(name.as_str())?) {
+                (VarType::Int, BrilValue::IntVal(n)) => *n,
+                (VarType::Bool, BrilValue::BoolVal(b)) => bool::from(*b) as i64,
+                _ => return None,
+            });
+        }
+
+        let mut iters = 0;
+        // SAFETY: `values` holds a value for each of the trace's variables,
+        // which is what the code was compiled to load & store
+        let exit_idx =
+            unsafe { (self.code)(values.as_mut_ptr(), max_iters, &mut iters) };
+
+        for ((name, ty), value) in self.vars.iter().zip(values) {
+            if let Some(var) = env.get_mut(name.as_str()) {
+                *var = match ty {
+                    VarType::Int => BrilValue::IntVal(value),
+                    VarType::Bool => BrilValue::BoolVal((value != 0).into()),
+                };
+            }
+        }
+        let exit = self.exits[exit_idx as usize];
+        Some(LoopExit {
+            steps: iters * self.steps + exit.steps,
+            ..exit
+        })
+    }
+}
+
+impl TraceJit {
+    /// Creates a JIT which compiles loops once their back edges have been
+    /// executed more than `threshold` times (returns an error if Cranelift
+    /// can't generate code for this machine)
+    pub fn new(threshold: u64) -> Result<Self, String> {
+        let mut flags = settings::builder();
+        // (the code is placed wherever memory is available, so it can't
+        // assume that anything it calls is nearby)
+        let settings = [
+            ("use_colocated_libcalls", "false"),
+            ("is_pic", "false"),
+            ("opt_level", "speed"),
+        ];
+        for (name, value) in settings {
+            flags.set(name, value).map_err(|e| e.to_string())?;
+        }
+        let isa = cranelift_native::builder()
+            .map_err(|msg| format!("this machine isn't supported: {msg}"))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            recorder: TraceRecorder::new(threshold),
+            module: JITModule::new(JITBuilder::with_isa(
+                isa,
+                default_libcall_names(),
+            )),
+            traces: HashMap::new(),
+            native_steps: 0,
+        })
+    }
+
+    /// The no. of traces that have been compiled
+    pub fn num_compiled(&self) -> usize {
+        self.traces
+            .values()
+            .flat_map(|traces| traces.values())
+            .filter(|trace| trace.is_some())
+            .count()
+    }
+}
+
+impl Observer for TraceJit {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        self.recorder.on_instr(instr_view, pc, instr, env);
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        env: &Environment,
+    ) {
+        self.recorder.on_call(callee, env);
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        value: Option<BrilValue>,
+    ) {
+        self.recorder.on_return(callee, value);
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        self.recorder.on_branch(instr_view, pc, taken, target_pc);
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        self.recorder.on_jump(instr_view, pc, target_pc);
+    }
+
+    fn on_back_edge<'a, P: IndexPair>(
+        &mut self,
+        instr_view: &'a InstrView<P>,
+        header_pc: usize,
+        env: &mut Environment<'a>,
+        max_steps: u64,
+    ) -> Option<LoopExit> {
+        let func = get_func_name(instr_view);
+        let is_compiled = self
+            .traces
+            .get(func)
+            .is_some_and(|traces| traces.contains_key(&header_pc));
+        if !is_compiled {
+            // (the loop is compiled once it's been traced)
+            let trace = self.recorder.traces.iter().find(|trace| {
+                trace.func == func && trace.header_pc == header_pc
+            })?;
+            let compiled =
+                compile_trace(&mut self.module, instr_view, trace, env);
+            self.traces
+                .entry(func.to_string())
+                .or_default()
+                .insert(header_pc, compiled);
+        }
+        let exit = self.traces[func][&header_pc]
+            .as_ref()?
+            .run(env, max_steps)?;
+        self.native_steps += exit.steps;
+        Some(exit)
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod jit_tests {
+    use serde_json::{Value, json};
+
+    use crate::diagnostic::Diagnostic;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::jit::TraceJit;
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+
+    /// Runs `json` with `args` under `limits`, with `jit` as the observer (if
+    /// there is one), returning its output, step count & error (if any)
+    fn run(
+        json: &Value,
+        args: &[&str],
+        limits: Limits,
+        jit: Option<&mut TraceJit>,
+    ) -> (String, u64, Option<Diagnostic>) {
+        let bytes = json_to_fbril_bytes(json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let mut ctx = InterpContext::new(vec![], limits).with_observer(jit);
+        let result = interp_program(&program, args.to_vec(), &mut ctx);
+        let out = String::from_utf8(ctx.out).expect("output is UTF-8");
+        (out, ctx.steps, result.err())
+    }
+
+    /// Sums the numbers below `n`, adding 1 instead of the numbers below
+    /// `half` (so the inner `br` changes direction halfway through), &
+    /// divides `x` by `d` in every iteration (counting `d` down each time)
+    fn sum_loop() -> Value {
+        json!({ "functions": [{
+            "name": "main",
+            "args": [
+                { "name": "n", "type": "int" },
+                { "name": "half", "type": "int" },
+                { "name": "d", "type": "int" }
+            ],
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "sum", "type": "int", "value": 0 },
+                { "op": "const", "dest": "x", "type": "int", "value": 1000 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["cond"] },
+                { "label": "body" },
+                { "op": "lt", "dest": "low", "type": "bool",
+                  "args": ["i", "half"] },
+                { "op": "br", "labels": ["small", "big"], "args": ["low"] },
+                { "label": "small" },
+                { "op": "add", "dest": "sum", "type": "int",
+                  "args": ["sum", "one"] },
+                { "op": "jmp", "labels": ["next"] },
+                { "label": "big" },
+                { "op": "add", "dest": "sum", "type": "int",
+                  "args": ["sum", "i"] },
+                { "label": "next" },
+                { "op": "div", "dest": "x", "type": "int",
+                  "args": ["x", "d"] },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "sub", "dest": "d", "type": "int",
+                  "args": ["d", "one"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "print", "args": ["sum", "x", "low"] }
+            ]
+        }]})
+    }
+
+    /// Compiled loops produce the same output & step count as the
+    /// interpreter, including when a guard fails & the interpreter takes over
+    #[test]
+    fn test_compiled_loop_matches_interpreter() {
+        let json = sum_loop();
+        for args in [
+            ["100", "1000", "-7"],
+            ["1000", "500", "2000"],
+            ["3", "1", "5"],
+        ] {
+            let expected = run(&json, &args, Limits::default(), None);
+            let mut jit = TraceJit::new(2).expect("host is supported");
+            let actual = run(&json, &args, Limits::default(), Some(&mut jit));
+            assert_eq!(actual, expected, "args {args:?}");
+            assert!(expected.2.is_none());
+        }
+
+        let mut jit = TraceJit::new(2).expect("host is supported");
+        run(
+            &json,
+            &["1000", "500", "2000"],
+            Limits::default(),
+            Some(&mut jit),
+        );
+        assert_eq!(jit.num_compiled(), 1);
+        assert!(jit.native_steps > 0);
+    }
+
+    /// Compiled code stops at exactly the same step as the interpreter when
+    /// the step limit is reached
+    #[test]
+    fn test_compiled_loop_respects_step_limit() {
+        let json = sum_loop();
+        for max_steps in [50, 1234, 1235, 4000] {
+            let limits = Limits {
+                max_steps: Some(max_steps),
+                ..Limits::default()
+            };
+            let args = ["1000", "500", "2000"];
+            let expected = run(&json, &args, limits, None);
+            let mut jit = TraceJit::new(2).expect("host is supported");
+            let actual = run(&json, &args, limits, Some(&mut jit));
+            assert_eq!(actual, expected, "max steps {max_steps}");
+            assert_eq!(actual.1, max_steps);
+        }
+    }
+
+    /// A `div` by zero in compiled code is left to the interpreter, which
+    /// reports the error at the `div`
+    #[test]
+    fn test_compiled_div_by_zero() {
+        let json = sum_loop();
+        let args = ["100", "50", "20"];
+        let expected = run(&json, &args, Limits::default(), None);
+        let mut jit = TraceJit::new(2).expect("host is supported");
+        let actual = run(&json, &args, Limits::default(), Some(&mut jit));
+        assert_eq!(actual, expected);
+        assert_eq!(
+            actual.2.expect("divides by zero").to_string(),
+            "division by zero (at @main:11)"
+        );
+        assert!(jit.native_steps > 0);
+    }
+
+    /// Loops containing instructions that can't be compiled are interpreted
+    #[test]
+    fn test_loop_with_print_is_interpreted() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "n", "type": "int", "value": 20 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "print", "args": ["i"] },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["loop", "done"], "args": ["cond"] },
+                { "label": "done" }
+            ]
+        }]});
+        let expected = run(&json, &[], Limits::default(), None);
+        let mut jit = TraceJit::new(2).expect("host is supported");
+        let actual = run(&json, &[], Limits::default(), Some(&mut jit));
+        assert_eq!(actual, expected);
+        assert_eq!(jit.num_compiled(), 0);
+        assert_eq!(jit.native_steps, 0);
+    }
+}
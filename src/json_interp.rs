@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+use serde_json::Value;
+
+use crate::diagnostic::{Diagnostic, ErrorCode};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A Core Bril value
+#[derive(Debug, Clone, Copy)]
+enum Val {
+    Int(i64),
+    Bool(bool),
+}
+
+// An environment maps variable names to values
+type Env<'a> = HashMap<&'a str, Val>;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// A naive Core Bril interpreter which walks the `serde_json::Value`
+/// representation of a program directly (i.e. what you'd write if the program
+/// wasn't flattened first), used as a baseline for the flat interpreter
+/// (by the benchmarks & `run --engine json`).
+/// - Panics if the program is malformed or fails (see `try_interp_program`)
+pub fn interp_program<W: Write>(program: &Value, args: &[&str], out: &mut W) {
+    if let Err(diagnostic) = try_interp_program(program, args, out) {
+        panic!("{diagnostic}");
+    }
+}
+
+/// Like `interp_program`, but a malformed program (or one which uses an op
+/// outside of Core Bril, or fails) is reported as an error instead of a
+/// panic. The args are checked against the parameters of `main` before the
+/// program is walked, like the flat interpreter does.
+pub fn try_interp_program<W: Write>(
+    program: &Value,
+    args: &[&str],
+    out: &mut W,
+) -> Result<(), Diagnostic> {
+    let funcs: HashMap<&str, &Value> = program["functions"]
+        .as_array()
+        .ok_or_else(|| {
+            Diagnostic::new(
+                ErrorCode::MalformedFile,
+                "expected `functions` to be a JSON array",
+            )
+        })?
+        .iter()
+        .map(|func| {
+            let name = func["name"].as_str().ok_or_else(|| {
+                Diagnostic::new(
+                    ErrorCode::MalformedFile,
+                    "expected every function to have a `name`",
+                )
+            })?;
+            Ok((name, func))
+        })
+        .collect::<Result<_, Diagnostic>>()?;
+    let main = funcs.get("main").ok_or_else(|| {
+        Diagnostic::new(
+            ErrorCode::UndefinedFunction,
+            "program has no @main function",
+        )
+    })?;
+    let env = bind_main_args(main, args)?;
+    interp_func(main, env, &funcs, out)?;
+    Ok(())
+}
+
+/// Binds the command-line args `args` to the parameters of `main`, parsing
+/// each of them according to its parameter's type (returns an error if the
+/// no. of args is wrong or one of them can't be parsed)
+fn bind_main_args<'a>(
+    main: &'a Value,
+    args: &[&str],
+) -> Result<Env<'a>, Diagnostic> {
+    let params = main["args"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    if params.len() != args.len() {
+        return Err(Diagnostic::new(
+            ErrorCode::BadArgument,
+            format!(
+                "@main expects {} argument(s), but {} were supplied",
+                params.len(),
+                args.len()
+            ),
+        ));
+    }
+    let mut env = Env::new();
+    for (param, arg) in params.iter().zip(args) {
+        let name = param["name"].as_str().ok_or_else(|| {
+            Diagnostic::new(
+                ErrorCode::MalformedFile,
+                "expected every parameter of @main to have a `name`",
+            )
+        })?;
+        let bad_arg = |ty: &str| {
+            Diagnostic::new(
+                ErrorCode::BadArgument,
+                format!("argument `{name}`: `{arg}` isn't {ty}"),
+            )
+        };
+        let value = match param["type"].as_str() {
+            Some("int") => {
+                Val::Int(arg.parse().map_err(|_| bad_arg("an int"))?)
+            }
+            Some("bool") => {
+                Val::Bool(arg.parse().map_err(|_| bad_arg("a bool"))?)
+            }
+            _ => {
+                return Err(Diagnostic::new(
+                    ErrorCode::BadArgument,
+                    format!(
+                        "argument `{name}` of @main has type {}, which can't \
+                        be passed on the command line",
+                        param["type"]
+                    ),
+                ));
+            }
+        };
+        env.insert(name, value);
+    }
+    Ok(env)
+}
+
+/// What happens after an instruction is interpreted (see `interp_instr`)
+enum Step<'a> {
+    Next,
+    Assign(&'a str, Val),
+    Jump(usize),
+    Return(Option<Val>),
+}
+
+/// Interprets the function `func` with the (already bound) arguments in `env`,
+/// returning its return value (if any)
+/// - Errors are located by the index of the instruction that failed, not
+///   counting labels (i.e. the same PC as in the flattened function)
+fn interp_func<'a, W: Write>(
+    func: &'a Value,
+    mut env: Env<'a>,
+    funcs: &HashMap<&str, &'a Value>,
+    out: &mut W,
+) -> Result<Option<Val>, Diagnostic> {
+    let instrs = func["instrs"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let mut pc = 0;
+    while pc < instrs.len() {
+        let instr = &instrs[pc];
+        pc += 1;
+        let Some(op) = instr["op"].as_str() else {
+            // Labels don't have an `op`
+            continue;
+        };
+        let step =
+            interp_instr(instr, op, &env, instrs, funcs, out).map_err(|e| {
+                let flat_pc = instrs[..pc - 1]
+                    .iter()
+                    .filter(|instr| instr.get("op").is_some())
+                    .count();
+                e.located(func["name"].as_str().unwrap_or_default(), flat_pc)
+            })?;
+        match step {
+            Step::Next => (),
+            Step::Assign(dest, value) => {
+                env.insert(dest, value);
+            }
+            Step::Jump(target) => pc = target,
+            Step::Return(value) => return Ok(value),
+        }
+    }
+    Ok(None)
+}
+
+/// Interprets the instruction `instr` (whose opcode is `op`) of a function
+/// whose instructions are `instrs`, with the variables in `env`
+fn interp_instr<'a, W: Write>(
+    instr: &'a Value,
+    op: &str,
+    env: &Env<'a>,
+    instrs: &'a [Value],
+    funcs: &HashMap<&str, &'a Value>,
+    out: &mut W,
+) -> Result<Step<'a>, Diagnostic> {
+    let malformed = |msg: &str| Diagnostic::new(ErrorCode::MalformedInstr, msg);
+    let args = instr["args"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+    let arg = |i: usize| {
+        let var = args.get(i).and_then(Value::as_str).ok_or_else(|| {
+            malformed(&format!("`{op}` is missing argument {i}"))
+        })?;
+        env.get(var).copied().ok_or_else(|| {
+            Diagnostic::new(
+                ErrorCode::UndefinedVariable,
+                format!("undefined variable `{var}`"),
+            )
+        })
+    };
+    let ill_typed = || {
+        Diagnostic::new(
+            ErrorCode::TypeError,
+            format!("operands to `{op}` are ill-typed"),
+        )
+    };
+    let int_arg = |i: usize| match arg(i)? {
+        Val::Int(n) => Ok(n),
+        Val::Bool(_) => Err(ill_typed()),
+    };
+    let bool_arg = |i: usize| match arg(i)? {
+        Val::Bool(b) => Ok(b),
+        Val::Int(_) => Err(ill_typed()),
+    };
+    let jump = |i: usize| {
+        let label = &instr["labels"][i];
+        instrs
+            .iter()
+            .position(|instr| instr["label"] == *label)
+            .map(Step::Jump)
+            .ok_or_else(|| {
+                Diagnostic::new(
+                    ErrorCode::UndefinedLabel,
+                    format!(
+                        "undefined label .{}",
+                        label.as_str().unwrap_or("")
+                    ),
+                )
+            })
+    };
+
+    let value = match op {
+        "const" => match &instr["value"] {
+            Value::Bool(b) => Val::Bool(*b),
+            n => Val::Int(n.as_i64().ok_or_else(|| {
+                malformed("`const` has no int or bool value")
+            })?),
+        },
+        "add" => Val::Int(int_arg(0)?.wrapping_add(int_arg(1)?)),
+        "sub" => Val::Int(int_arg(0)?.wrapping_sub(int_arg(1)?)),
+        "mul" => Val::Int(int_arg(0)?.wrapping_mul(int_arg(1)?)),
+        "div" => match (int_arg(0)?, int_arg(1)?) {
+            (_, 0) => {
+                return Err(Diagnostic::new(
+                    ErrorCode::DivisionByZero,
+                    "division by zero",
+                ));
+            }
+            (n, d) => Val::Int(n.wrapping_div(d)),
+        },
+        "eq" => Val::Bool(int_arg(0)? == int_arg(1)?),
+        "lt" => Val::Bool(int_arg(0)? < int_arg(1)?),
+        "gt" => Val::Bool(int_arg(0)? > int_arg(1)?),
+        "le" => Val::Bool(int_arg(0)? <= int_arg(1)?),
+        "ge" => Val::Bool(int_arg(0)? >= int_arg(1)?),
+        "and" => Val::Bool(bool_arg(0)? && bool_arg(1)?),
+        "or" => Val::Bool(bool_arg(0)? || bool_arg(1)?),
+        "not" => Val::Bool(!bool_arg(0)?),
+        "id" => arg(0)?,
+        "print" => {
+            let values = (0..args.len())
+                .map(|i| arg(i).map(|value| value.to_string()))
+                .collect::<Result<Vec<String>, _>>()?;
+            writeln!(out, "{}", values.join(" ")).map_err(|e| {
+                Diagnostic::new(
+                    ErrorCode::Io,
+                    format!("unable to write program output: {e}"),
+                )
+            })?;
+            return Ok(Step::Next);
+        }
+        "jmp" => return jump(0),
+        "br" => return jump(if bool_arg(0)? { 0 } else { 1 }),
+        "call" => {
+            let name = instr["funcs"][0]
+                .as_str()
+                .ok_or_else(|| malformed("`call` has no function"))?;
+            let callee = funcs.get(name).ok_or_else(|| {
+                Diagnostic::new(
+                    ErrorCode::UndefinedFunction,
+                    format!("call to undefined function @{name}"),
+                )
+            })?;
+            let params =
+                callee["args"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+            if params.len() != args.len() {
+                return Err(Diagnostic::new(
+                    ErrorCode::TypeError,
+                    format!(
+                        "@{name} expects {} argument(s), but {} were supplied",
+                        params.len(),
+                        args.len()
+                    ),
+                ));
+            }
+            let callee_env = params
+                .iter()
+                .enumerate()
+                .map(|(i, param)| {
+                    let param = param["name"].as_str().ok_or_else(|| {
+                        malformed("callee has a parameter without a `name`")
+                    })?;
+                    Ok((param, arg(i)?))
+                })
+                .collect::<Result<Env, Diagnostic>>()?;
+            let ret_value = interp_func(callee, callee_env, funcs, out)?;
+            match (instr.get("dest"), ret_value) {
+                (None, _) => return Ok(Step::Next),
+                (Some(_), Some(value)) => value,
+                (Some(_), None) => {
+                    return Err(Diagnostic::new(
+                        ErrorCode::MissingReturnValue,
+                        format!("@{name} didn't return a value"),
+                    ));
+                }
+            }
+        }
+        "ret" => {
+            let value = if args.is_empty() { None } else { Some(arg(0)?) };
+            return Ok(Step::Return(value));
+        }
+        "nop" => return Ok(Step::Next),
+        _ => {
+            return Err(Diagnostic::new(
+                ErrorCode::UnsupportedInstr,
+                format!("the JSON interpreter doesn't support `{op}`"),
+            ));
+        }
+    };
+    let dest = instr["dest"]
+        .as_str()
+        .ok_or_else(|| malformed(&format!("`{op}` has no dest")))?;
+    Ok(Step::Assign(dest, value))
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl fmt::Display for Val {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Val::Int(n) => write!(f, "{n}"),
+            Val::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod json_interp_tests {
+    use crate::diagnostic::ErrorCode;
+    use crate::json_interp::try_interp_program;
+
+    /// The JSON interpreter prints the same output as Brili, and reports
+    /// malformed programs as errors
+    #[test]
+    fn test_try_interp_program() {
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("test/call-with-args.json")
+                .expect("Unable to read file"),
+        )
+        .expect("Unable to parse JSON");
+        let mut out = vec![];
+        try_interp_program(&json, &[], &mut out).expect("program should run");
+        let expected = std::fs::read_to_string("test/call-with-args.out")
+            .expect("Unable to read file");
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let no_main = serde_json::json!({"functions": []});
+        let err = try_interp_program(&no_main, &[], &mut vec![]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UndefinedFunction);
+        let not_a_program = serde_json::json!({"functions": 3});
+        let err =
+            try_interp_program(&not_a_program, &[], &mut vec![]).unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedFile);
+    }
+
+    /// The args are checked against the parameters of `main` (like the flat
+    /// interpreter does), and runtime errors are located by their PC
+    #[test]
+    fn test_try_interp_program_errors() {
+        let lcm: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("test/lcm.json")
+                .expect("Unable to read file"),
+        )
+        .expect("Unable to parse JSON");
+        let run = |program: &serde_json::Value, args: &[&str]| {
+            try_interp_program(program, args, &mut vec![]).unwrap_err()
+        };
+        let err = run(&lcm, &[]);
+        assert_eq!(
+            (err.code, err.message.as_str()),
+            (
+                ErrorCode::BadArgument,
+                "@main expects 2 argument(s), but 0 were supplied"
+            )
+        );
+        assert_eq!(
+            run(&lcm, &["64", "true"]).message,
+            "argument `y`: `true` isn't an int"
+        );
+
+        let program = serde_json::json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "b", "type": "bool" }],
+            "instrs": [
+                { "op": "const", "dest": "zero", "type": "int", "value": 0 },
+                { "op": "br", "args": ["b"], "labels": ["then", "else"] },
+                { "label": "then" },
+                { "op": "div", "dest": "x", "type": "int",
+                  "args": ["zero", "zero"] },
+                { "label": "else" },
+                { "op": "print", "args": ["y"] }
+            ]
+        }] });
+        assert_eq!(
+            run(&program, &["yes"]).message,
+            "argument `b`: `yes` isn't a bool"
+        );
+        let err = run(&program, &["true"]);
+        assert_eq!(
+            (err.code, err.func.as_deref(), err.pc),
+            (ErrorCode::DivisionByZero, Some("main"), Some(2))
+        );
+        let err = run(&program, &["false"]);
+        assert_eq!((err.code, err.pc), (ErrorCode::UndefinedVariable, Some(3)));
+    }
+}
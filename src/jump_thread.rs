@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use crate::cfg::{Cfg, Operation};
+use crate::program::Function;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// The label that control ends up at after jumping to `label`, skipping
+/// over empty blocks (which fall through to the next block) & blocks which
+/// only contain a `jmp` (stops early at an undefined label, or if the
+/// blocks form a cycle, e.g. `.l: jmp .l;`)
+fn final_target<'a>(cfg: &'a Cfg, label: &'a str) -> &'a str {
+    let mut target = label;
+    let mut visited = HashSet::new();
+    while visited.insert(target) {
+        let Some(b) = cfg.block_index(target) else {
+            break;
+        };
+        let next = match cfg.blocks[b].instrs.as_slice() {
+            [] => cfg.blocks.get(b + 1).and_then(|next| next.label.as_deref()),
+            [jmp] if jmp.op == Opcode::Jmp => {
+                jmp.labels.first().map(String::as_str)
+            }
+            _ => None,
+        };
+        match next {
+            Some(next) => target = next,
+            None => break,
+        }
+    }
+    target
+}
+
+/// Removes the `jmp`s to the block right after them, which control falls
+/// through to anyway (returns whether any were removed)
+fn remove_jumps_to_next(cfg: &mut Cfg) -> bool {
+    let mut removed = false;
+    for b in 1..cfg.blocks.len() {
+        let next = cfg.blocks[b].label.clone();
+        let block = &mut cfg.blocks[b - 1];
+        if let Some(jmp) = block.instrs.last()
+            && jmp.op == Opcode::Jmp
+            && next.is_some()
+            && jmp.labels.first() == next.as_ref()
+        {
+            block.instrs.pop();
+            removed = true;
+        }
+    }
+    removed
+}
+
+/// Removes the empty blocks whose labels aren't the target of any `jmp` or
+/// `br` (returns whether any were removed)
+fn remove_empty_blocks(cfg: &mut Cfg) -> bool {
+    let targets: HashSet<String> = cfg
+        .blocks
+        .iter()
+        .filter_map(|block| block.instrs.last())
+        .filter(|instr| matches!(instr.op, Opcode::Jmp | Opcode::Br))
+        .flat_map(|instr| instr.labels.iter().cloned())
+        .collect();
+    let num_blocks = cfg.blocks.len();
+    cfg.blocks.retain(|block| {
+        !block.instrs.is_empty()
+            || block.label.as_ref().is_some_and(|l| targets.contains(l))
+    });
+    cfg.blocks.len() < num_blocks
+}
+
+/// Threads the jumps in `cfg`: every `jmp` & `br` whose target is an empty
+/// block or a block which only contains a `jmp` is redirected to where
+/// control ends up (collapsing chains of `jmp`s), `br`s whose targets are
+/// then the same become `jmp`s, and the blocks which are then unreachable
+/// or empty are removed, along with `jmp`s to the next block (returns the
+/// no. of targets redirected)
+///
+/// Functions with `phi`s are left unchanged, since their labels refer to
+/// the predecessors of their blocks, which threading changes
+pub fn thread_jumps(cfg: &mut Cfg) -> usize {
+    if cfg
+        .blocks
+        .iter()
+        .flat_map(|block| &block.instrs)
+        .any(|instr| instr.op == Opcode::Phi)
+    {
+        return 0;
+    }
+
+    let targets: Vec<Vec<String>> = cfg
+        .blocks
+        .iter()
+        .map(|block| match block.instrs.last() {
+            Some(instr) if matches!(instr.op, Opcode::Jmp | Opcode::Br) => {
+                instr
+                    .labels
+                    .iter()
+                    .map(|label| final_target(cfg, label).to_string())
+                    .collect()
+            }
+            _ => vec![],
+        })
+        .collect();
+    let mut threaded = 0;
+    for (block, labels) in cfg.blocks.iter_mut().zip(targets) {
+        let Some(instr) = block.instrs.last_mut() else {
+            continue;
+        };
+        if labels.is_empty() {
+            continue;
+        }
+        threaded += instr
+            .labels
+            .iter()
+            .zip(&labels)
+            .filter(|(old, new)| old != new)
+            .count();
+        instr.labels = labels;
+        if instr.op == Opcode::Br
+            && instr.labels.len() == 2
+            && instr.labels[0] == instr.labels[1]
+        {
+            *instr = Operation {
+                op: Opcode::Jmp,
+                dest: None,
+                ty: None,
+                value: None,
+                args: vec![],
+                labels: vec![instr.labels[0].clone()],
+                func: None,
+            };
+        }
+    }
+
+    cfg.remove_unreachable_blocks();
+    // (removing a `jmp` can leave its block empty, & removing an empty
+    // block can make the `jmp` before it a jump to the next block)
+    loop {
+        let removed_jumps = remove_jumps_to_next(cfg);
+        if !remove_empty_blocks(cfg) && !removed_jumps {
+            break;
+        }
+    }
+    threaded
+}
+
+/// Threads the jumps of a function (see `thread_jumps`), producing a new
+/// flat function
+pub fn jump_thread<P: IndexPair>(func: Function<'_, P>) -> InstrStore {
+    let mut cfg = Cfg::new(func);
+    thread_jumps(&mut cfg);
+    cfg.to_instr_store()
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod jump_thread_tests {
+    use serde_json::json;
+
+    use crate::cfg::Cfg;
+    use crate::interp::interp_program_captured;
+    use crate::jump_thread::{jump_thread, thread_jumps};
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::program::Program;
+    use crate::types::Opcode;
+
+    /// Chains of `jmp`s & empty blocks are skipped, a `br` whose targets end
+    /// up in the same place becomes a `jmp` (which is then removed, since it
+    /// jumps to the next block), & the program's output doesn't change
+    #[test]
+    fn test_thread_jumps() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "zero", "type": "int", "value": 0 },
+                { "op": "lt", "dest": "neg", "type": "bool",
+                  "args": ["n", "zero"] },
+                { "op": "br", "labels": ["a", "b"], "args": ["neg"] },
+                { "label": "a" },
+                { "op": "jmp", "labels": ["c"] },
+                { "label": "b" },
+                { "label": "c" },
+                { "op": "jmp", "labels": ["d"] },
+                { "label": "d" },
+                { "op": "print", "args": ["n"] },
+                { "op": "jmp", "labels": ["e"] },
+                { "label": "e" },
+                { "label": "f" },
+                { "op": "jmp", "labels": ["g"] },
+                { "label": "g" },
+                { "op": "print", "args": ["zero"] }
+            ]
+        }]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let main = Program::new(program.views()).function("main").unwrap();
+
+        let mut cfg = Cfg::new(main);
+        // (.a -> .d & .b -> .d for the `br`, .e -> .g for the `jmp` after
+        // the 1st `print`, & .c -> .d for the `jmp` in .a, which is then
+        // unreachable)
+        assert_eq!(thread_jumps(&mut cfg), 4);
+        let labels: Vec<_> =
+            cfg.blocks.iter().map(|b| b.label.as_deref()).collect();
+        assert_eq!(labels, [None, Some("d"), Some("g")]);
+        assert!(
+            cfg.blocks
+                .iter()
+                .flat_map(|b| &b.instrs)
+                .all(|instr| { !matches!(instr.op, Opcode::Jmp | Opcode::Br) })
+        );
+
+        let stores = Program::new(program.views()).functions().map(jump_thread);
+        let bytes =
+            AlignedBytes::new(&instr_stores_to_fbril_bytes(stores.collect()));
+        let threaded = get_flat_program(&bytes).expect("should load");
+        for n in ["3", "-1"] {
+            let expected = interp_program_captured(&program, vec![n]).unwrap();
+            let actual = interp_program_captured(&threaded, vec![n]).unwrap();
+            assert_eq!(actual.lines, expected.lines);
+            assert!(actual.steps < expected.steps);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::cfg::{BasicBlock, Cfg, Operation};
+use crate::profile::{FuncProfile, Profile};
+use crate::program::Program;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// How often control falls through between blocks: of the `total` no. of
+/// times control passed from one block to another, the no. of times the
+/// second block came right after the first, before (`before`) & after
+/// (`after`) the blocks were reordered
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FallThroughStats {
+    pub total: u64,
+    pub before: u64,
+    pub after: u64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl FallThroughStats {
+    /// Adds the stats of another function to these ones
+    pub fn add(&mut self, other: FallThroughStats) {
+        self.total += other.total;
+        self.before += other.before;
+        self.after += other.after;
+    }
+
+    /// The fraction of transitions between blocks which fell through,
+    /// before & after reordering (both are 1 if there were no transitions)
+    pub fn rates(&self) -> (f64, f64) {
+        if self.total == 0 {
+            return (1.0, 1.0);
+        }
+        let total = self.total as f64;
+        (self.before as f64 / total, self.after as f64 / total)
+    }
+}
+
+/// The no. of times each edge `(from block, to block)` in `cfg` was taken
+/// according to `profile` (jumps are recorded in the profile, and blocks
+/// without a terminator always fall through to the next block)
+fn edge_counts(
+    cfg: &Cfg,
+    starts: &[usize],
+    profile: &FuncProfile,
+) -> HashMap<(usize, usize), u64> {
+    // (jumps to a PC where several blocks start are attributed to the first
+    // one, since any empty blocks there fall through to the others)
+    let mut block_at: HashMap<usize, usize> = HashMap::new();
+    for (b, &pc) in starts.iter().enumerate() {
+        block_at.entry(pc).or_insert(b);
+    }
+    let mut counts: HashMap<(usize, usize), u64> = HashMap::new();
+    for &(from, to, count) in &profile.edges {
+        let from_block = starts.partition_point(|&start| start <= from);
+        if let (Some(from_block), Some(&to_block)) =
+            (from_block.checked_sub(1), block_at.get(&to))
+        {
+            *counts.entry((from_block, to_block)).or_default() += count;
+        }
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        if b + 1 < cfg.blocks.len()
+            && block.instrs.last().is_none_or(|last| !last.is_terminator())
+        {
+            *counts.entry((b, b + 1)).or_default() +=
+                profile.counts.get(starts[b]).copied().unwrap_or(0);
+        }
+    }
+    counts
+}
+
+/// Orders the blocks so that hot edges become fall-throughs, using the
+/// bottom-up chaining of Pettis & Hansen's "Profile Guided Code Positioning":
+/// edges are visited from hottest to coldest, and the chains of blocks at
+/// either end of an edge are joined if the edge goes from the end of one
+/// chain to the start of the other. The entry block's chain comes first,
+/// followed by the other chains which were executed (hottest first),
+/// and then the cold chains (in their original order).
+fn hot_layout(
+    block_counts: &[u64],
+    edges: &HashMap<(usize, usize), u64>,
+) -> Vec<usize> {
+    let num_blocks = block_counts.len();
+    let mut chains: Vec<Vec<usize>> =
+        (0..num_blocks).map(|b| vec![b]).collect();
+    let mut chain_of: Vec<usize> = (0..num_blocks).collect();
+
+    let mut hot_edges: Vec<(usize, usize, u64)> = edges
+        .iter()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(&(from, to), &count)| (from, to, count))
+        .collect();
+    hot_edges
+        .sort_unstable_by_key(|&(from, to, count)| (Reverse(count), from, to));
+    for (from, to, _) in hot_edges {
+        let (from_chain, to_chain) = (chain_of[from], chain_of[to]);
+        // (nothing can be placed before the entry block)
+        if from_chain != to_chain
+            && to != 0
+            && chains[from_chain].last() == Some(&from)
+            && chains[to_chain].first() == Some(&to)
+        {
+            let tail = std::mem::take(&mut chains[to_chain]);
+            for &b in &tail {
+                chain_of[b] = from_chain;
+            }
+            chains[from_chain].extend(tail);
+        }
+    }
+
+    let mut chains: Vec<Vec<usize>> = chains
+        .into_iter()
+        .filter(|chain| !chain.is_empty())
+        .collect();
+    chains.sort_by_key(|chain| {
+        let heat = chain.iter().map(|&b| block_counts[b]).max().unwrap_or(0);
+        (chain[0] != 0, heat == 0, Reverse(heat), chain[0])
+    });
+    chains.concat()
+}
+
+/// Creates an instruction with no dest, args etc.
+fn operation(op: Opcode, labels: Vec<String>) -> Operation {
+    Operation {
+        op,
+        dest: None,
+        ty: None,
+        value: None,
+        args: vec![],
+        labels,
+        func: None,
+    }
+}
+
+/// Reorders the blocks of `cfg` (which must have just been built from a flat
+/// function) using the function's `profile`, so that hot blocks are laid out
+/// contiguously along their hottest edges & cold blocks are moved to the end
+/// (see `hot_layout`). Blocks which used to fall through to a block that's
+/// no longer after them get an explicit `jmp` (or a `ret`, if they were at
+/// the end of the function), and `jmp`s to the next block are removed.
+/// Returns an error if `profile` doesn't come from the same function.
+pub fn reorder_blocks(
+    cfg: &mut Cfg,
+    profile: &FuncProfile,
+) -> Result<FallThroughStats, String> {
+    let starts = cfg.block_start_pcs();
+    let num_instrs = cfg
+        .blocks
+        .last()
+        .map_or(0, |block| starts[starts.len() - 1] + block.instrs.len());
+    if profile.counts.len() != num_instrs {
+        return Err(format!(
+            "the profile of @{} has counts for {} instructions, \
+            but the function has {num_instrs}",
+            cfg.name,
+            profile.counts.len()
+        ));
+    }
+
+    // (an empty block is entered as often as the block after it, so it's
+    // just as hot)
+    let block_counts: Vec<u64> = starts
+        .iter()
+        .map(|&pc| profile.counts.get(pc).copied().unwrap_or(0))
+        .collect();
+    let edges = edge_counts(cfg, &starts, profile);
+    let order = hot_layout(&block_counts, &edges);
+    let mut position = vec![0; order.len()];
+    for (i, &b) in order.iter().enumerate() {
+        position[b] = i;
+    }
+
+    let mut stats = FallThroughStats::default();
+    for (&(from, to), &count) in &edges {
+        stats.total += count;
+        if to == from + 1 {
+            stats.before += count;
+        }
+        if position[to] == position[from] + 1 {
+            stats.after += count;
+        }
+    }
+
+    // Blocks which are no longer fallen through to need a label to jump to
+    let num_blocks = cfg.blocks.len();
+    let falls_through = |block: &BasicBlock| {
+        block.instrs.last().is_none_or(|last| !last.is_terminator())
+    };
+    for b in 0..num_blocks.saturating_sub(1) {
+        if falls_through(&cfg.blocks[b])
+            && position[b + 1] != position[b] + 1
+            && cfg.blocks[b + 1].label.is_none()
+        {
+            cfg.blocks[b + 1].label = Some(cfg.fresh_label("b"));
+        }
+    }
+
+    let blocks: Vec<BasicBlock> = order
+        .iter()
+        .map(|&b| {
+            let mut block = cfg.blocks[b].clone();
+            let next =
+                order.get(position[b] + 1).map(|&next| &cfg.blocks[next]);
+            if !falls_through(&block) {
+                let last = block.instrs.last().expect("block has a terminator");
+                if last.op == Opcode::Jmp
+                    && next.is_some_and(|next| {
+                        next.label.as_ref() == last.labels.first()
+                    })
+                {
+                    block.instrs.pop();
+                }
+            } else if b + 1 < num_blocks {
+                if position[b + 1] != position[b] + 1 {
+                    let label = cfg.blocks[b + 1]
+                        .label
+                        .clone()
+                        .expect("block was given a label above");
+                    block.instrs.push(operation(Opcode::Jmp, vec![label]));
+                }
+            } else if next.is_some() {
+                block.instrs.push(operation(Opcode::Ret, vec![]));
+            }
+            block
+        })
+        .collect();
+    cfg.blocks = blocks;
+    Ok(stats)
+}
+
+/// Reorders the blocks of every function in `views` (see `reorder_blocks`),
+/// returning the new functions along with the combined fall-through stats.
+/// Functions which aren't in `profile` were never called, so they're
+/// left as they are.
+pub fn reorder_program<P: IndexPair>(
+    views: &[InstrView<P>],
+    profile: &Profile,
+) -> Result<(Vec<InstrStore>, FallThroughStats), String> {
+    let mut stats = FallThroughStats::default();
+    let mut stores = vec![];
+    for func in Program::new(views).functions() {
+        let Some(func_profile) = profile.functions.get(func.name()) else {
+            stores.push(InstrStore::from(func.view().clone()));
+            continue;
+        };
+        let mut cfg = Cfg::new(func);
+        stats.add(reorder_blocks(&mut cfg, func_profile)?);
+        stores.push(cfg.to_instr_store());
+    }
+    Ok((stores, stats))
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod layout_tests {
+    use serde_json::json;
+    use zerocopy::IntoBytes;
+
+    use crate::cfg::Cfg;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::layout::{reorder_blocks, reorder_program};
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::profile::{Profile, Profiler};
+    use crate::program::Program;
+
+    /// A loop body placed after the loop's exit is moved up next to the
+    /// loop's header (which makes the hot edges fall-throughs), and the
+    /// reordered program still behaves the same
+    #[test]
+    fn test_reorder_hot_blocks() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["cond"] },
+                { "label": "done" },
+                { "op": "print", "args": ["i"] },
+                { "op": "ret" },
+                { "label": "body" },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "jmp", "labels": ["loop"] }
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        let mut profiler = Profiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec!["10"], &mut ctx).expect("program runs");
+        let profile = profiler.profile();
+        assert_eq!(profile.functions["main"].counts[2], 11);
+        // (the profile survives a round-trip through JSON)
+        let profile = Profile::from_json(&profile.to_json()).unwrap();
+
+        let main = Program::new(program.views()).function("main").unwrap();
+        let mut cfg = Cfg::new(main);
+        let stats = reorder_blocks(&mut cfg, &profile.functions["main"])
+            .expect("profile matches");
+        let labels: Vec<Option<&str>> = cfg
+            .blocks
+            .iter()
+            .map(|block| block.label.as_deref())
+            .collect();
+        assert_eq!(labels, [None, Some("loop"), Some("body"), Some("done")]);
+        // Control enters `loop` once, goes from `loop` to `body` & back
+        // 10 times each, and goes to `done` once: only the first & last
+        // transitions fell through before, but the ones into `body` do now
+        assert_eq!((stats.total, stats.before, stats.after), (22, 2, 11));
+
+        let (stores, _) = reorder_program(program.views(), &profile).unwrap();
+        let reordered = instr_stores_to_fbril_bytes(stores);
+        let reordered_words = AlignedBytes::new(&reordered);
+        let reordered_program =
+            get_flat_program(&reordered_words.as_bytes()[..reordered.len()])
+                .expect("reordered program should load");
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        interp_program(&reordered_program, vec!["10"], &mut ctx)
+            .expect("reordered program runs");
+        assert_eq!(ctx.out, b"10\n");
+    }
+}
@@ -0,0 +1,67 @@
+// The modules that make up flat-bril, which are shared by the `flat-bril`
+// binary (see `main.rs`) and the Criterion benchmarks (see `benches/`)
+pub mod branch_stats;
+#[cfg(feature = "bril-rs")]
+pub mod bril_rs;
+pub mod callgraph;
+pub mod cfg;
+pub mod chrome_trace;
+pub mod conformance;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
+pub mod divergence;
+#[cfg(feature = "ext-values")]
+pub mod ext_value;
+#[cfg(feature = "mmap")]
+pub mod ffi;
+pub mod flamegraph;
+pub mod flatten;
+pub mod flatten_dir;
+pub mod fusion;
+pub mod host;
+pub mod hotpath;
+pub mod html_report;
+pub mod inspect;
+pub mod interp;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod json_interp;
+pub mod json_roundtrip;
+pub mod jump_thread;
+pub mod layout;
+pub mod licm;
+pub mod lint;
+pub mod memfile;
+pub mod migrate;
+pub mod observer;
+pub mod op_profile;
+pub mod patch;
+pub mod peephole;
+pub mod predecode;
+pub mod printer;
+pub mod profile;
+pub mod program;
+pub mod ranges;
+pub mod repl;
+pub mod replay;
+pub mod reverse_debugger;
+pub mod scheduler;
+pub mod size_report;
+pub mod slots;
+pub mod snapshot;
+pub mod specialize;
+pub mod ssa;
+pub mod symexec;
+#[cfg(feature = "mmap")]
+pub mod suite;
+pub mod taint;
+pub mod timing;
+pub mod trace;
+pub mod typecheck;
+pub mod types;
+pub mod unflatten;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod watch;
@@ -0,0 +1,434 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::cfg::{BasicBlock, Cfg, Dominators, Loop, Operation};
+use crate::program::Function;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Determines if an instruction can be moved without changing the program's
+/// behavior (aside from division, which may fail, and is handled separately)
+fn is_pure(op: Opcode) -> bool {
+    !matches!(
+        op,
+        Opcode::Jmp
+            | Opcode::Br
+            | Opcode::Call
+            | Opcode::Ret
+            | Opcode::Print
+            | Opcode::Nop
+            | Opcode::Phi
+    )
+}
+
+/// Determines if an instruction has a visible effect (so that a `div` which
+/// may fail can't be hoisted above it)
+fn has_effect(op: Opcode) -> bool {
+    matches!(op, Opcode::Print | Opcode::Call | Opcode::Ret)
+}
+
+/// The variables whose only definition in `cfg` is a `const` with a nonzero
+/// int value (so dividing by them never fails)
+fn nonzero_consts(cfg: &Cfg) -> HashSet<&str> {
+    let mut num_defs: HashMap<&str, usize> = HashMap::new();
+    for (arg, _) in &cfg.args {
+        *num_defs.entry(arg).or_default() += 1;
+    }
+    let instrs = cfg.blocks.iter().flat_map(|block| &block.instrs);
+    for dest in instrs.clone().filter_map(|instr| instr.dest.as_deref()) {
+        *num_defs.entry(dest).or_default() += 1;
+    }
+    instrs
+        .filter(|instr| {
+            instr.op == Opcode::Const
+                && matches!(instr.value, Some(BrilValue::IntVal(n)) if n != 0)
+        })
+        .filter_map(|instr| instr.dest.as_deref())
+        .filter(|dest| num_defs[dest] == 1)
+        .collect()
+}
+
+/// The label of a loop's header (which always has one, since
+/// the back edges to it are jumps)
+fn header_label(cfg: &Cfg, lp: &Loop) -> String {
+    cfg.blocks[lp.header]
+        .label
+        .clone()
+        .expect("loop headers are labeled")
+}
+
+/// Finds the instructions in `lp` which can be hoisted into its preheader,
+/// as `(block, instruction index)` pairs in the order they must be executed.
+/// An instruction `x = op args` is hoisted if:
+/// - `op` is pure, & every arg is either not defined in the loop,
+///   or is defined by an instruction which is hoisted
+/// - `x` is defined only once in the loop, & isn't live on entry to it
+///   (so every use of `x` in the loop sees this definition)
+/// - the instruction dominates all the loop's exits, or `x` is dead after
+///   the loop
+/// - if `op` is `div`, its divisor is a nonzero constant, or the division
+///   dominates all the loop's exits (even if `x` is dead after the loop) &
+///   no instruction with an effect (see `has_effect`) can run before it in
+///   the loop (since hoisting it may introduce a division by zero, or
+///   report one before the effect happens, otherwise)
+fn find_invariants(
+    cfg: &Cfg,
+    lp: &Loop,
+    dominators: &Dominators,
+    live_in: &[HashSet<String>],
+) -> Vec<(usize, usize)> {
+    let mut blocks: Vec<usize> = lp.body.iter().copied().collect();
+    blocks.sort_unstable();
+    let nonzero_consts = nonzero_consts(cfg);
+    // (only the instructions after the `i`th one in block `b`, & those in
+    // the blocks which `b` dominates, run after it in the first iteration)
+    let effects: Vec<(usize, usize)> = blocks
+        .iter()
+        .flat_map(|&b| {
+            let instrs = cfg.blocks[b].instrs.iter().enumerate();
+            instrs
+                .filter(|(_, instr)| has_effect(instr.op))
+                .map(move |(i, _)| (b, i))
+        })
+        .collect();
+    let effect_runs_before = |b: usize, i: usize| {
+        effects.iter().any(|&(effect_b, effect_i)| {
+            if effect_b == b {
+                effect_i < i
+            } else {
+                !dominators.dominates(b, effect_b)
+            }
+        })
+    };
+
+    let mut num_defs: HashMap<&str, usize> = HashMap::new();
+    for &b in &blocks {
+        for dest in cfg.blocks[b].instrs.iter().filter_map(|i| i.dest.as_ref())
+        {
+            *num_defs.entry(dest).or_default() += 1;
+        }
+    }
+    let exits: Vec<usize> = blocks
+        .iter()
+        .copied()
+        .filter(|&b| cfg.successors(b).iter().any(|s| !lp.body.contains(s)))
+        .collect();
+    let live_after: HashSet<&String> = exits
+        .iter()
+        .flat_map(|&b| cfg.successors(b))
+        .filter(|s| !lp.body.contains(s))
+        .flat_map(|s| &live_in[s])
+        .collect();
+
+    let mut hoisted: Vec<(usize, usize)> = vec![];
+    let mut invariant_vars: HashSet<&str> = HashSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in &blocks {
+            let dominates_exits =
+                exits.iter().all(|&exit| dominators.dominates(b, exit));
+            for (i, instr) in cfg.blocks[b].instrs.iter().enumerate() {
+                let Some(dest) = &instr.dest else {
+                    continue;
+                };
+                if invariant_vars.contains(dest.as_str())
+                    || !is_pure(instr.op)
+                    || num_defs[dest.as_str()] != 1
+                    || live_in[lp.header].contains(dest)
+                {
+                    continue;
+                }
+                let args_invariant = instr.args.iter().all(|arg| {
+                    !num_defs.contains_key(arg.as_str())
+                        || invariant_vars.contains(arg.as_str())
+                });
+                let may_fail = instr.op == Opcode::Div
+                    && !instr.args.get(1).is_some_and(|divisor| {
+                        nonzero_consts.contains(divisor.as_str())
+                    });
+                let safe = if may_fail {
+                    dominates_exits
+                        && !exits.is_empty()
+                        && !effect_runs_before(b, i)
+                } else {
+                    dominates_exits || !live_after.contains(dest)
+                };
+                if args_invariant && safe {
+                    invariant_vars.insert(dest);
+                    hoisted.push((b, i));
+                    changed = true;
+                }
+            }
+        }
+    }
+    hoisted
+}
+
+/// Hoists the loop-invariant instructions of `lp` into a new preheader
+/// (a block which is inserted just before the header, and which is entered
+/// by every edge into the loop from outside it). No preheader is inserted
+/// if there's nothing to hoist.
+fn hoist_invariants(cfg: &mut Cfg, lp: &Loop, dominators: &Dominators) {
+    let live_in = cfg.live_in();
+    let hoisted = find_invariants(cfg, lp, dominators, &live_in);
+    if hoisted.is_empty() {
+        return;
+    }
+
+    let header = lp.header;
+    let header_label = header_label(cfg, lp);
+    let preheader_label = cfg.fresh_label(&format!("{header_label}.preheader"));
+
+    // Jumps into the loop from outside it now go to the preheader
+    let preds = cfg.predecessors();
+    for &pred in &preds[header] {
+        if lp.body.contains(&pred) {
+            continue;
+        }
+        if let Some(last) = cfg.blocks[pred].instrs.last_mut()
+            && matches!(last.op, Opcode::Jmp | Opcode::Br)
+        {
+            for label in &mut last.labels {
+                if *label == header_label {
+                    *label = preheader_label.clone();
+                }
+            }
+        }
+    }
+    // A block in the loop which falls through to the header would fall
+    // through to the preheader instead, so it needs an explicit jump
+    if header > 0
+        && lp.body.contains(&(header - 1))
+        && cfg.blocks[header - 1]
+            .instrs
+            .last()
+            .is_none_or(|last| !last.is_terminator())
+    {
+        cfg.blocks[header - 1].instrs.push(Operation {
+            op: Opcode::Jmp,
+            dest: None,
+            ty: None,
+            value: None,
+            args: vec![],
+            labels: vec![header_label],
+            func: None,
+        });
+    }
+
+    let instrs: Vec<Operation> = hoisted
+        .iter()
+        .map(|&(b, i)| cfg.blocks[b].instrs[i].clone())
+        .collect();
+    let hoisted: HashSet<(usize, usize)> = hoisted.into_iter().collect();
+    for (b, block) in cfg.blocks.iter_mut().enumerate() {
+        let mut i = 0;
+        block.instrs.retain(|_| {
+            i += 1;
+            !hoisted.contains(&(b, i - 1))
+        });
+    }
+    cfg.blocks.insert(
+        header,
+        BasicBlock {
+            label: Some(preheader_label),
+            instrs,
+        },
+    );
+}
+
+/// Performs loop-invariant code motion on a CFG: the invariant instructions
+/// in each natural loop are moved into a preheader for that loop
+/// (see `find_invariants` for the exact criteria). Inner loops are processed
+/// first, so that instructions hoisted out of an inner loop can then be
+/// hoisted out of the loops containing it.
+pub fn hoist_loop_invariants(cfg: &mut Cfg) {
+    // Loops are identified by their header's label, since inserting a
+    // preheader shifts the indices of the blocks after it
+    let mut processed: HashSet<String> = HashSet::new();
+    loop {
+        let dominators = cfg.dominators();
+        let loops = cfg.natural_loops(&dominators);
+        let Some(lp) = loops
+            .iter()
+            .find(|lp| !processed.contains(&header_label(cfg, lp)))
+        else {
+            break;
+        };
+        processed.insert(header_label(cfg, lp));
+        hoist_invariants(cfg, lp, &dominators);
+    }
+}
+
+/// Performs loop-invariant code motion on a function (see
+/// `hoist_loop_invariants`), producing a new flat function
+pub fn licm<P: IndexPair>(func: Function<'_, P>) -> InstrStore {
+    let mut cfg = Cfg::new(func);
+    hoist_loop_invariants(&mut cfg);
+    cfg.to_instr_store()
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod licm_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use serde_json::json;
+
+    use crate::cfg::Cfg;
+    use crate::diagnostic::ErrorCode;
+    use crate::interp::{
+        InterpContext, Limits, interp_program, interp_program_captured,
+    };
+    use crate::licm::{hoist_loop_invariants, licm};
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::program::Program;
+    use crate::types::InstrStore;
+
+    /// Interprets the program in `bytes` before & after running LICM on it,
+    /// checking that the output is the same
+    fn check_same_output(bytes: &[u8], args: Vec<&str>) {
+        let data = AlignedBytes::new(bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let expected = interp_program_captured(&program, args.clone())
+            .expect("program should run");
+
+        let stores: Vec<InstrStore> = Program::new(program.views())
+            .functions()
+            .map(licm)
+            .collect();
+        let licm_bytes = instr_stores_to_fbril_bytes(stores);
+        let data = AlignedBytes::new(&licm_bytes);
+        let licm_program =
+            get_flat_program(&data).expect("LICM program should load");
+        let actual = interp_program_captured(&licm_program, args)
+            .expect("LICM program should run");
+        assert_eq!(actual.lines, expected.lines);
+        assert!(actual.steps <= expected.steps);
+    }
+
+    /// Invariant instructions are moved into a preheader (but instructions
+    /// whose dest is redefined or live on entry to the loop aren't), and the
+    /// program's output is unchanged
+    #[test]
+    fn test_hoist_invariants() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["cond"] },
+                { "label": "body" },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "const", "dest": "two", "type": "int", "value": 2 },
+                { "op": "mul", "dest": "k", "type": "int",
+                  "args": ["two", "n"] },
+                { "op": "print", "args": ["k"] },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "print", "args": ["i"] }
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let main = Program::new(program.views()).function("main").unwrap();
+
+        let mut cfg = Cfg::new(main);
+        hoist_loop_invariants(&mut cfg);
+        let preheader = cfg.block_index("loop.preheader.0").unwrap();
+        assert_eq!(preheader + 1, cfg.block_index("loop").unwrap());
+        let hoisted: Vec<&str> = cfg.blocks[preheader]
+            .instrs
+            .iter()
+            .filter_map(|instr| instr.dest.as_deref())
+            .collect();
+        assert_eq!(hoisted, ["one", "two", "k"]);
+        check_same_output(&bytes, vec!["3"]);
+
+        for name in ["loopfact", "orders", "pascals-row", "sum-sq-diff"] {
+            let file = File::open(format!("test/{name}.json"))
+                .expect("Unable to open file");
+            let json: serde_json::Value =
+                serde_json::from_reader(BufReader::new(file))
+                    .expect("Unable to parse JSON");
+            let bril = std::fs::read_to_string(format!("test/{name}.bril"))
+                .expect("Unable to read .bril file");
+            let args: Vec<&str> = bril
+                .lines()
+                .find_map(|line| line.split_once("ARGS:"))
+                .map_or(vec![], |(_, args)| args.split_whitespace().collect());
+            check_same_output(&json_to_fbril_bytes(&json), args);
+        }
+    }
+
+    /// A `div` whose divisor may be zero isn't hoisted above a `print` which
+    /// runs before it in the loop (so the `print` still happens before the
+    /// division by zero is reported), but one whose divisor is a nonzero
+    /// constant is
+    #[test]
+    fn test_hoist_div() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "zero", "type": "int", "value": 0 },
+                { "op": "const", "dest": "three", "type": "int", "value": 3 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "label": "loop" },
+                { "op": "print", "args": ["i"] },
+                { "op": "div", "dest": "r", "type": "int",
+                  "args": ["n", "three"] },
+                { "op": "div", "dest": "q", "type": "int",
+                  "args": ["n", "zero"] },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["loop", "done"], "args": ["cond"] },
+                { "label": "done" },
+                { "op": "print", "args": ["r", "q"] }
+            ]
+        }]});
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&data).expect("valid file should load");
+        let main = Program::new(program.views()).function("main").unwrap();
+
+        let mut cfg = Cfg::new(main);
+        hoist_loop_invariants(&mut cfg);
+        let preheader = cfg.block_index("loop.preheader.0").unwrap();
+        let hoisted: Vec<&str> = cfg.blocks[preheader]
+            .instrs
+            .iter()
+            .filter_map(|instr| instr.dest.as_deref())
+            .collect();
+        assert_eq!(hoisted, ["r"]);
+
+        let licm_data = AlignedBytes::new(&instr_stores_to_fbril_bytes(vec![
+            cfg.to_instr_store(),
+        ]));
+        let licm_program =
+            get_flat_program(&licm_data).expect("LICM program should load");
+        for program in [&program, &licm_program] {
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            let error = interp_program(program, vec!["6"], &mut ctx);
+            assert_eq!(error.unwrap_err().code, ErrorCode::DivisionByZero);
+            assert_eq!(ctx.out, b"0\n");
+        }
+    }
+}
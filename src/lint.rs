@@ -0,0 +1,306 @@
+use std::collections::HashSet;
+use std::fmt::{self, Write};
+
+use crate::cfg::Cfg;
+use crate::program::{Function, Program};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The kinds of problems reported by `lint_program`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LintKind {
+    /// An instruction assigns a value to a variable, which is never read
+    /// before it's overwritten or the function returns
+    DeadDest,
+    /// A variable is read on a path along which it hasn't been defined
+    UseBeforeDef,
+    /// Instructions which are never executed, since no path from the
+    /// entry of the function reaches them
+    Unreachable,
+}
+
+/// A problem found by `lint_program`, located at the instruction with
+/// index `pc` in the function `func` (the same locations as the ones
+/// reported by the interpreter)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub func: String,
+    pub pc: usize,
+    pub kind: LintKind,
+    pub message: String,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl LintKind {
+    /// The name of the lint, which is printed alongside each warning
+    pub fn name(self) -> &'static str {
+        match self {
+            LintKind::DeadDest => "dead-dest",
+            LintKind::UseBeforeDef => "use-before-def",
+            LintKind::Unreachable => "unreachable",
+        }
+    }
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "@{}:{}: {} [{}]",
+            self.func,
+            self.pc,
+            self.message,
+            self.kind.name()
+        )
+    }
+}
+
+/// The variables which are definitely defined on entry to every block
+/// (a forwards "must" analysis, where the function's parameters are
+/// defined on entry). Blocks which are unreachable are left as `None`.
+fn defined_in(cfg: &Cfg) -> Vec<Option<HashSet<String>>> {
+    let rpo = cfg.reverse_postorder();
+    let preds = cfg.predecessors();
+    let mut defined_out: Vec<Option<HashSet<String>>> =
+        vec![None; cfg.blocks.len()];
+    let mut defined_in = defined_out.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in &rpo {
+            // Unvisited predecessors (`None`) don't constrain the meet,
+            // since they're only reached through a back edge
+            let mut vars: Option<HashSet<String>> = if b == 0 {
+                Some(cfg.args.iter().map(|(name, _)| name.clone()).collect())
+            } else {
+                None
+            };
+            for pred_out in
+                preds[b].iter().filter_map(|&p| defined_out[p].as_ref())
+            {
+                vars = Some(match vars {
+                    None => pred_out.clone(),
+                    Some(vars) => &vars & pred_out,
+                });
+            }
+            let vars = vars.unwrap_or_default();
+            let mut out = vars.clone();
+            out.extend(
+                cfg.blocks[b]
+                    .instrs
+                    .iter()
+                    .filter_map(|instr| instr.dest.clone()),
+            );
+            defined_in[b] = Some(vars);
+            if defined_out[b].as_ref() != Some(&out) {
+                defined_out[b] = Some(out);
+                changed = true;
+            }
+        }
+    }
+    defined_in
+}
+
+/// Lints a single function (see `lint_program`)
+pub fn lint_function<P: IndexPair>(func: Function<'_, P>) -> Vec<Lint> {
+    let cfg = Cfg::new(func);
+    let start_pcs = cfg.block_start_pcs();
+    let mut lints = vec![];
+    let mut lint = |pc: usize, kind: LintKind, message: String| {
+        lints.push(Lint {
+            func: cfg.name.clone(),
+            pc,
+            kind,
+            message,
+        })
+    };
+
+    // Every variable which is defined somewhere in the function
+    let all_defs: HashSet<&str> = cfg
+        .args
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .chain(
+            cfg.blocks
+                .iter()
+                .flat_map(|block| &block.instrs)
+                .filter_map(|instr| instr.dest.as_deref()),
+        )
+        .collect();
+    let live_in = cfg.live_in();
+    let defined_in = defined_in(&cfg);
+
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        // The PC of the first instruction in the block
+        let first_pc = start_pcs[b];
+        let Some(defined) = &defined_in[b] else {
+            if block.instrs.is_empty() {
+                continue;
+            }
+            let message = match (&block.label, b.checked_sub(1)) {
+                (None, Some(prev)) => match cfg.blocks[prev].instrs.last() {
+                    Some(terminator) => {
+                        format!("unreachable code after `{terminator}`")
+                    }
+                    None => "unreachable code".to_string(),
+                },
+                (Some(label), _) => {
+                    format!("block .{label} is never reached")
+                }
+                (None, None) => "unreachable code".to_string(),
+            };
+            lint(first_pc, LintKind::Unreachable, message);
+            continue;
+        };
+
+        // Uses of variables which may not be defined
+        let mut defined = defined.clone();
+        for (i, instr) in block.instrs.iter().enumerate() {
+            // (a `phi` picks one of its args depending on the path taken,
+            // so an undefined arg isn't necessarily used)
+            if instr.op != Opcode::Phi {
+                let mut reported = HashSet::new();
+                for arg in &instr.args {
+                    if defined.contains(arg) || !reported.insert(arg) {
+                        continue;
+                    }
+                    let message = if all_defs.contains(arg.as_str()) {
+                        format!("`{arg}` may be used before it's defined")
+                    } else {
+                        format!("`{arg}` is never defined")
+                    };
+                    lint(first_pc + i, LintKind::UseBeforeDef, message);
+                }
+            }
+            if let Some(dest) = &instr.dest {
+                defined.insert(dest.clone());
+            }
+        }
+
+        // Dests which aren't live after the instruction that defines them
+        // (found by walking backwards from the variables live on exit)
+        let mut live: HashSet<&str> = cfg
+            .successors(b)
+            .into_iter()
+            .flat_map(|succ| &live_in[succ])
+            .map(String::as_str)
+            .collect();
+        let mut dead = vec![];
+        for (i, instr) in block.instrs.iter().enumerate().rev() {
+            if let Some(dest) = instr.dest.as_deref()
+                && !live.remove(dest)
+            {
+                dead.push((i, dest));
+            }
+            live.extend(instr.args.iter().map(String::as_str));
+        }
+        for (i, dest) in dead.into_iter().rev() {
+            lint(
+                first_pc + i,
+                LintKind::DeadDest,
+                format!("the value assigned to `{dest}` is never used"),
+            );
+        }
+    }
+    lints.sort_by_key(|lint| lint.pc);
+    lints
+}
+
+/// Checks every function in the program for dead destinations, variables
+/// which may be used before they're defined & unreachable code, returning
+/// the problems found in each function in program order
+pub fn lint_program<P: IndexPair>(program: &Program<'_, P>) -> Vec<Lint> {
+    program.functions().flat_map(lint_function).collect()
+}
+
+/// Renders the lints one per line, followed by a summary line
+pub fn lints_to_text(lints: &[Lint]) -> String {
+    let mut text = String::new();
+    for lint in lints {
+        writeln!(text, "{lint}").unwrap();
+    }
+    match lints.len() {
+        0 => writeln!(text, "no problems found").unwrap(),
+        1 => writeln!(text, "1 problem found").unwrap(),
+        n => writeln!(text, "{n} problems found").unwrap(),
+    }
+    text
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod lint_tests {
+    use serde_json::json;
+
+    use crate::lint::{LintKind, lint_program};
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+    use crate::program::Program;
+
+    /// Each kind of lint is reported at the offending instruction, and
+    /// variables which are defined along every path aren't reported
+    #[test]
+    fn test_lint_program() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "c", "type": "bool" }],
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "const", "dest": "unused", "type": "int",
+                  "value": 2 },
+                { "op": "br", "args": ["c"], "labels": ["then", "join"] },
+                { "label": "then" },
+                { "op": "const", "dest": "y", "type": "int", "value": 3 },
+                { "label": "join" },
+                { "op": "add", "dest": "z", "type": "int",
+                  "args": ["x", "y"] },
+                { "op": "print", "args": ["z"] },
+                { "op": "ret" },
+                { "op": "print", "args": ["x"] },
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+        let lints = lint_program(&Program::new(&views));
+        let found: Vec<(usize, LintKind, String)> = lints
+            .iter()
+            .map(|lint| (lint.pc, lint.kind, lint.to_string()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    1,
+                    LintKind::DeadDest,
+                    "@main:1: the value assigned to `unused` is never used \
+                    [dead-dest]"
+                        .to_string()
+                ),
+                (
+                    4,
+                    LintKind::UseBeforeDef,
+                    "@main:4: `y` may be used before it's defined \
+                    [use-before-def]"
+                        .to_string()
+                ),
+                (
+                    7,
+                    LintKind::Unreachable,
+                    "@main:7: unreachable code after `ret;` [unreachable]"
+                        .to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -1,15 +1,58 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
 use std::path::Path;
+use std::time::{Duration, Instant};
 
-use clap::{Arg, ArgAction, Command};
-use interp::interp_program;
-use types::Header;
-use zerocopy::FromBytes;
-mod flatten;
-mod interp;
-mod json_roundtrip;
-mod memfile;
-mod types;
-mod unflatten;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use flat_bril::branch_stats::BranchReport;
+use flat_bril::callgraph::CallGraph;
+use flat_bril::cfg::Cfg;
+use flat_bril::chrome_trace::ChromeTracer;
+use flat_bril::coverage::CoverageReport;
+use flat_bril::dap::SourceMap;
+use flat_bril::debugger::Debugger;
+use flat_bril::diagnostic::{Diagnostic, ErrorCode};
+use flat_bril::divergence::{DivergenceReport, find_divergence};
+use flat_bril::flamegraph::FuncProfiler;
+use flat_bril::flatten::{Ingest, read_bril_source};
+use flat_bril::flatten_dir::flatten_dir;
+use flat_bril::hotpath::HotPathReport;
+use flat_bril::html_report::render_html_report;
+use flat_bril::inspect::inspect;
+use flat_bril::interp::{FuncTable, InterpContext, Limits, interp_entry};
+#[cfg(feature = "jit")]
+use flat_bril::jit::TraceJit;
+use flat_bril::lint::{Lint, lint_program, lints_to_text};
+use flat_bril::memfile::{
+    AlignedBytes, LazyProgram, MmapAdvice, MmapTuning, ProgramViews,
+};
+use flat_bril::observer::{NoObserver, Observer};
+use flat_bril::op_profile::OpProfiler;
+use flat_bril::patch::PatchView;
+use flat_bril::peephole::Peephole;
+use flat_bril::printer::{PrintOptions, disassemble};
+use flat_bril::profile::{Profile, Profiler};
+use flat_bril::program::{Function, Program};
+use flat_bril::repl::Repl;
+use flat_bril::replay::{ExecRecorder, ExecTrace, Replayer};
+use flat_bril::reverse_debugger::{
+    DEFAULT_CHECKPOINT_INTERVAL, ReverseDebugger,
+};
+use flat_bril::scheduler::{Task, TaskStatus};
+use flat_bril::size_report::{CountingReader, SizeReport, layouts_to_text};
+use flat_bril::snapshot::Snapshot;
+use flat_bril::symexec::symexec;
+use flat_bril::taint::TaintTracker;
+use flat_bril::types::{
+    BrilValue, COMPACT_FORMAT_FLAG, FlatInstr, IndexPair, InstrStore,
+    InstrView, Opcode, RESOLVED_FLAG, WIDE_FORMAT_FLAG,
+};
+use flat_bril::watch::Watcher;
+use flat_bril::{
+    conformance, dap, fusion, json_interp, json_roundtrip, jump_thread, layout,
+    licm, memfile, migrate, ranges, specialize, ssa, suite, timing,
+};
+use serde::Serialize;
 
 // To create an `.fbril` file from an existing `.bril` file, do one of the following:
 // 1. Convert a `.bril` file to JSON using `bril2json`, then redirect it to `cargo run`:
@@ -18,6 +61,1705 @@ mod unflatten;
 // `cargo run -- --json --filename test/call.json`
 
 // To interpret a file: `cargo run -- --filename test/call.fbril --interp`
+// (only the program's output is printed to stdout: progress messages,
+// warnings & errors all go to stderr)
+// (or equivalently, `cargo run -- run test/call.fbril`; pass `--entry f`
+// to call the function `f` instead of `main`)
+// (pass `--engine json` to `run` to interpret a JSON file directly,
+// without flattening it, to compare against the flat interpreter:
+// `cargo run -- run --engine json test/call.json`)
+// (pass `--time` before `--interp` to print the time taken, the dynamic
+// instruction count & the no. of instructions per second to stderr)
+// (similarly, `--memory` prints the peak RSS, the bytes allocated for
+// variable environments & the peak call depth)
+// (pass `--fuse` before `--interp` to use superinstructions, or
+// `--check-types` to check the types of every instruction as it runs)
+// (functions are decoded into resolved instructions before they run, unless
+// `--no-predecode` is passed, which interprets the file's instructions
+// directly: `cargo run -- run --no-predecode --time test/call.fbril`)
+// (pass `--keep-going` to carry on past errors caused by a single
+// instruction, reporting all of them at the end of the run:
+// `cargo run -- run --keep-going --check-types test/call.fbril`)
+// (pass `--source` to add the source line of each instruction to runtime
+// errors: `cargo run -- run test/call.fbril --source test/call.json`)
+// (pass `--debug` before `--interp` to step through the program in a
+// debugger, & also `--reverse` to be able to step backwards too:
+// `cargo run -- --filename test/fib_recursive.fbril --debug --reverse --interp 10`)
+
+// (pass `--strict` to reject unknown opcodes, types & fields in the JSON,
+// which are otherwise reported as warnings & ignored, except that
+// instructions with unknown opcodes are kept as extension instructions)
+
+// To see how much space each section of the `.fbril` file takes up, and how
+// its size compares with the JSON's:
+// `bril2json < test/call.bril | cargo run -- flatten --size-report test/call.fbril`
+
+// To also store the resolved target pcs & callees of every instruction, so
+// that they don't have to be looked up by name when the file is run:
+// `bril2json < test/call.bril | cargo run -- flatten --resolved test/call.fbril`
+
+// To use `.fbril` files in pipelines, pass `-` as the file to `flatten`
+// (which then writes to stdout) or `run` (which then reads from stdin):
+// `bril2json < test/call.bril | cargo run -- flatten - | cargo run -- run -`
+
+// To write each function to its own `.fbril` file in a directory:
+// `bril2json < test/call.bril | cargo run -- flatten --per-function call/`
+
+// To add the functions in another JSON file to an existing `.fbril` file:
+// `cargo run -- flatten --append test/more.json test/call.fbril`
+
+// To flatten every `.json` / `.bril` file in a directory (recursively):
+// `cargo run -- flatten-dir test/ --out-dir fbril/`
+
+// To run a suite of programs: `cargo run -- run-suite manifest.json`
+
+// To check conformance against Bril's own test corpus (a checkout of the
+// Bril repo, with `bril2json` on the `PATH`):
+// `cargo run -- conformance ../bril/test/interp`
+
+// To record a profile of a run & use it to lay out hot blocks contiguously:
+// `cargo run -- --filename test/gcd.fbril --profile gcd.json --interp 4 6`
+// `cargo run -- reorder --profile gcd.json test/gcd.fbril test/gcd.pgo.fbril`
+
+// To render a profile as an HTML page (the disassembled program with
+// execution counts, hot blocks & per-function summaries):
+// `cargo run -- report test/gcd.fbril --profile gcd.json -o gcd.html`
+
+// To print the call graph of a program (as Graphviz with `--dot`):
+// `cargo run -- callgraph test/call.fbril --dot`
+// (similarly, `cfg` prints the control-flow graph of a single function)
+// `cargo run -- cfg test/gcd.fbril --func main --dot`
+
+// To print an `.fbril` file as readable Bril text (with the types in each
+// block aligned; pass `--pcs` to see the PC of every instruction):
+// `cargo run -- disasm test/gcd.fbril --pcs`
+// (pass `--addresses` to see the byte offset of each instruction in the
+// file, like `objdump -d`)
+
+// To see how an `.fbril` file is laid out byte by byte (e.g. when the writer
+// & reader in `memfile.rs` disagree): `cargo run -- inspect test/gcd.fbril`
+// (or just the offset, size, no. of entries & alignment of each section:
+// `cargo run -- toc test/gcd.fbril`)
+// (to write the raw bytes of a single section to a file:
+// `cargo run -- extract test/gcd.fbril --section strings -o strings.bin`)
+// (to patch a single instruction of an `.fbril` file in place:
+// `cargo run -- patch test/gcd.fbril --func main --pc 0 --value 5`)
+
+// To list the path conditions under which each `print` & `ret` of a
+// function is reached, treating its parameters as symbolic:
+// `cargo run -- symexec test/gcd.fbril --func main --depth 4`
+
+// To stop a run after 100 instructions, save its state & continue it later:
+// `cargo run -- suspend test/fib_recursive.fbril 10 --steps 100 -o fib.snap`
+// `cargo run -- resume test/fib_recursive.fbril fib.snap`
+
+// To report dead destinations, possibly-undefined variables & unreachable
+// code: `cargo run -- lint test/gcd.fbril`
+
+// To see which functions a run spends its time in, as a flame graph:
+// `cargo run -- run --flamegraph gcd.stacks test/gcd.fbril 4 6`
+// `inferno-flamegraph < gcd.stacks > gcd.svg`
+
+// To count how many times each opcode is executed (e.g. to measure the
+// effect of an optimization pass): `cargo run -- run --profile-ops test/gcd.fbril 4 6`
+
+// To view a timeline of a run's calls (& optionally its blocks) in
+// `chrome://tracing` or Perfetto:
+// `cargo run -- run --chrome-trace trace.json --trace-blocks test/gcd.fbril 4 6`
+
+// To see which `print`s & `br`s are influenced by the program's arguments
+// (or by particular variables, with `--taint VAR`):
+// `cargo run -- run --taint-args test/fitsinside.fbril 12 4 5 13`
+
+// To report which instructions were never executed & the hot spots of a run:
+// `cargo run -- --filename test/gcd.fbril --coverage gcd.cov --interp 4 6`
+// (or pass `--hot-paths 5` to print the 5 hottest blocks & loops, or
+// `--branch-stats 90` to print the branches which went the same way at
+// least 90% of the time)
+
+// To see where the interpreter spends its time (per opcode, dispatch,
+// env lookups & branch resolution), use an instrumented build:
+// `cargo run --release --features timing -- --filename test/gcd.fbril --interp 4 6`
+
+// To re-flatten & re-run a program whenever it's saved (printing a diff of
+// its output after each change):
+// `cargo run -- watch test/gcd.bril --run 4 6`
+
+// To type in Bril instructions & run them one at a time
+// (`:help` lists the commands, e.g. `:load test/fact.json`):
+// `cargo run -- repl`
+
+// To debug programs from an editor (e.g. VS Code), configure it to run this
+// as a Debug Adapter Protocol server, which launches `.json` or `.bril` files
+// (breakpoints are set on source lines using the `pos` of each instruction):
+// `cargo run -- dap`
+
+// To record the instructions executed by a run, re-run it deterministically
+// (checking that it executes the same instructions) & summarize the trace:
+// `cargo run -- run test/gcd.fbril --record gcd.trace 4 6`
+// `cargo run -- run test/gcd.fbril --replay gcd.trace`
+// `cargo run -- trace-info gcd.trace`
+
+// To find the first instruction at which an optimized program behaves
+// differently from the original (running both on the same arguments):
+// `cargo run -- divergence test/gcd.fbril test/gcd.opt.fbril --args 4 6`
+
+// To combine the functions in several `.fbril` files into one program
+// (pass `--allow-override` to let later files redefine functions):
+// `cargo run -- merge lib.fbril main.fbril -o prog.fbril`
+
+// To upgrade an `.fbril` file written by an earlier version of flat-bril
+// (whose layout is older than the current one):
+// `cargo run -- migrate old.fbril -o new.fbril`
+
+// To convert every function in a file to SSA form:
+// `cargo run -- ssa test/gcd.fbril test/gcd.ssa.fbril`
+// (similarly, `licm` hoists loop-invariant instructions out of loops,
+// `peephole` runs the built-in peephole rewrites, `simplify-branches`
+// replaces branches whose conditions are constant by `jmp`s, and
+// `thread-jumps` redirects jumps to `jmp`s & empty blocks to where control
+// ends up)
+
+// To specialize `main` for the args `6` (folding constants, pruning
+// branches & unrolling loops whose bounds are then known):
+// `cargo run -- specialize test/fizz-buzz.fbril fizz-buzz.6.fbril 6`
+
+// To print the range of each int variable (& whether each bool is constant)
+// at the entry & exit of every block, as JSON:
+// `cargo run -- ranges test/gcd.fbril --func main`
+
+/// The result of a run, printed as JSON when `--format json` is specified
+/// - `stdout` contains the lines printed by the program
+///   (including those printed before an error occurred)
+/// - `steps` is the dynamic instruction count
+/// - `error` is `null` if the program ran to completion
+/// - `errors` are the errors which the run carried on past (with
+///   `--keep-going`; omitted if there weren't any)
+#[derive(Debug, Serialize)]
+struct RunReport {
+    stdout: Vec<String>,
+    steps: u64,
+    time_ms: f64,
+    error: Option<Diagnostic>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<Diagnostic>,
+}
+
+/// A summary of how long a run took, printed to stderr when `--time` is
+/// specified
+/// - `steps` is the dynamic instruction count, so `instrs_per_sec` measures
+///   the throughput of the interpreter
+#[derive(Debug, Serialize)]
+struct TimeSummary {
+    time_ms: f64,
+    steps: u64,
+    instrs_per_sec: f64,
+}
+
+impl TimeSummary {
+    fn new(elapsed: Duration, steps: u64) -> Self {
+        let secs = elapsed.as_secs_f64();
+        Self {
+            time_ms: secs * 1000.0,
+            steps,
+            instrs_per_sec: if secs > 0.0 { steps as f64 / secs } else { 0.0 },
+        }
+    }
+
+    /// Prints the summary to stderr in `format` (`text` or `json`)
+    fn print(&self, format: &str) {
+        match format {
+            "json" => eprintln!(
+                "{}",
+                serde_json::to_string(self)
+                    .expect("unable to serialize summary")
+            ),
+            _ => eprintln!(
+                "time: {:.3} ms, {} instrs, {:.0} instrs/s",
+                self.time_ms, self.steps, self.instrs_per_sec
+            ),
+        }
+    }
+}
+
+/// A summary of the memory used by a run, printed to stderr when `--memory`
+/// is specified
+/// - `peak_rss_bytes` is the peak resident set size of the whole process
+///   (including the mmap-ed file), or `None` if it isn't available
+///   (it's read from `/proc`, so it's only available on Linux)
+/// - `env_bytes` & `peak_call_depth` are tracked by the interpreter
+///   (see `InterpContext`)
+#[derive(Debug, Serialize)]
+struct MemorySummary {
+    peak_rss_bytes: Option<u64>,
+    env_bytes: usize,
+    peak_call_depth: usize,
+}
+
+impl MemorySummary {
+    fn new<W: Write, O: Observer>(ctx: &InterpContext<W, O>) -> Self {
+        Self {
+            peak_rss_bytes: peak_rss_bytes(),
+            env_bytes: ctx.env_bytes,
+            peak_call_depth: ctx.peak_call_depth,
+        }
+    }
+
+    /// Prints the summary to stderr in `format` (`text` or `json`)
+    fn print(&self, format: &str) {
+        match format {
+            "json" => eprintln!(
+                "{}",
+                serde_json::to_string(self)
+                    .expect("unable to serialize summary")
+            ),
+            _ => eprintln!(
+                "memory: peak RSS {}, {} bytes of environments, peak call \
+                depth {}",
+                self.peak_rss_bytes
+                    .map_or("unknown".to_string(), |bytes| format!(
+                        "{bytes} bytes"
+                    )),
+                self.env_bytes,
+                self.peak_call_depth
+            ),
+        }
+    }
+}
+
+/// Reads the peak resident set size of this process (the `VmHWM` line of
+/// `/proc/self/status`), in bytes
+fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kb = line
+        .trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim();
+    kb.parse::<u64>().ok().map(|kb| kb * 1024)
+}
+
+/// Command-line flags for limiting how much work the interpreter can do
+fn limit_args() -> [Arg; 3] {
+    [
+        Arg::new("max-steps")
+            .long("max-steps")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u64))
+            .help("Aborts interpretation after N instructions are executed"),
+        Arg::new("max-call-depth")
+            .long("max-call-depth")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Aborts interpretation if more than N calls are nested"),
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .value_parser(clap::value_parser!(f64))
+            .help("Aborts interpretation after SECONDS of wall-clock time"),
+    ]
+}
+
+/// Command-line flags for compiling hot loops to native code (only
+/// available if flat-bril is built with the `jit` feature)
+fn jit_args() -> Vec<Arg> {
+    #[cfg(feature = "jit")]
+    return vec![
+        Arg::new("jit")
+            .long("jit")
+            .value_name("THRESHOLD")
+            .value_parser(clap::value_parser!(u64))
+            .conflicts_with("debug")
+            .help(
+                "Compiles loops to native code once they've run THRESHOLD \
+                times (other tools don't see the instructions executed by \
+                compiled code)",
+            ),
+    ];
+    #[cfg(not(feature = "jit"))]
+    vec![]
+}
+
+/// Command-line flags for interpreting a program (shared by `--interp`
+/// & the `run` subcommand)
+fn interp_args() -> [Arg; 25] {
+    [
+        Arg::new("debug")
+            .long("debug")
+            .action(ArgAction::SetTrue)
+            .help("Interprets the program in an interactive debugger"),
+        Arg::new("reverse")
+            .long("reverse")
+            .action(ArgAction::SetTrue)
+            .requires("debug")
+            .conflicts_with("check-types")
+            .help(
+                "Uses the reverse debugger, which can also step backwards \
+                (by replaying the run from periodic snapshots)",
+            ),
+        Arg::new("fuse")
+            .long("fuse")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("debug")
+            .help(
+                "Fuses common pairs of instructions into \
+                superinstructions before interpreting",
+            ),
+        Arg::new("no-predecode")
+            .long("no-predecode")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("debug")
+            .help(
+                "Interprets every instruction straight from the file, \
+                instead of decoding each function into resolved \
+                instructions (with variables in slots) before running it",
+            ),
+        Arg::new("profile")
+            .short('p')
+            .long("profile")
+            .value_name("PROFILE")
+            .conflicts_with("debug")
+            .help(
+                "Records how often each instruction & jump is executed, \
+                writing the profile to PROFILE as JSON",
+            ),
+        Arg::new("coverage")
+            .long("coverage")
+            .value_name("REPORT")
+            .conflicts_with("debug")
+            .help(
+                "Writes a report of which instructions were never \
+                executed & which were executed most often to REPORT",
+            ),
+        Arg::new("coverage-format")
+            .long("coverage-format")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .requires("coverage")
+            .help("Format of the report written by `--coverage`"),
+        Arg::new("hot-paths")
+            .long("hot-paths")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .conflicts_with("debug")
+            .help(
+                "Prints the N hottest basic blocks & loops (by the no. of \
+                instructions executed in them) to stderr after the run",
+            ),
+        Arg::new("branch-stats")
+            .long("branch-stats")
+            .value_name("PERCENT")
+            .value_parser(clap::value_parser!(f64))
+            .conflicts_with("debug")
+            .help(
+                "Prints the branches which went the same way at least \
+                PERCENT% of the time to stderr after the run",
+            ),
+        Arg::new("error-format")
+            .long("error-format")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .help(
+                "Format of the errors reported when a program can't be \
+                loaded or interpreted",
+            ),
+        Arg::new("format")
+            .long("format")
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .conflicts_with("debug")
+            .help(
+                "Output format when interpreting: `json` prints a JSON \
+                object with the program's output, instruction count, \
+                time taken & error (if any)",
+            ),
+        Arg::new("time")
+            .long("time")
+            .value_name("FORMAT")
+            .value_parser(["text", "json"])
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("text")
+            .conflicts_with("debug")
+            .help(
+                "Prints the time taken, the no. of instructions executed & \
+                the no. of instructions per second to stderr after the run \
+                (as text by default, or as JSON with `--time=json`)",
+            ),
+        Arg::new("memory")
+            .long("memory")
+            .value_name("FORMAT")
+            .value_parser(["text", "json"])
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("text")
+            .conflicts_with("debug")
+            .help(
+                "Prints the peak RSS, the no. of bytes allocated for variable \
+                environments & the peak call depth to stderr after the run \
+                (as text by default, or as JSON with `--memory=json`)",
+            ),
+        Arg::new("flamegraph")
+            .long("flamegraph")
+            .value_name("STACKS")
+            .conflicts_with("debug")
+            .help(
+                "Records the no. of instructions executed & the time spent \
+                in each function, printing the totals to stderr & writing \
+                the call stacks to STACKS in the collapsed format read by \
+                `inferno-flamegraph` / `flamegraph.pl`",
+            ),
+        Arg::new("profile-ops")
+            .long("profile-ops")
+            .value_name("FORMAT")
+            .value_parser(["text", "json"])
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("text")
+            .conflicts_with("debug")
+            .help(
+                "Counts how many times each opcode is executed, printing \
+                the counts (most frequent first) to stderr after the run \
+                (as text by default, or as JSON with `--profile-ops=json`)",
+            ),
+        Arg::new("chrome-trace")
+            .long("chrome-trace")
+            .value_name("TRACE")
+            .conflicts_with("debug")
+            .help(
+                "Writes a timeline of the run to TRACE in Chrome's \
+                trace-event format (viewable in `chrome://tracing` or \
+                Perfetto), with a span for each function call",
+            ),
+        Arg::new("trace-blocks")
+            .long("trace-blocks")
+            .action(ArgAction::SetTrue)
+            .requires("chrome-trace")
+            .help(
+                "Also adds a span for each block executed to the \
+                `--chrome-trace` timeline",
+            ),
+        Arg::new("taint")
+            .long("taint")
+            .value_name("VAR")
+            .action(ArgAction::Append)
+            .conflicts_with("debug")
+            .help(
+                "Marks the variable VAR as tainted (in every function, \
+                whenever it's assigned), tracks the taint through value \
+                operations & calls, & prints the `print`s & `br`s that \
+                were influenced by tainted data to stderr after the run \
+                (can be passed several times)",
+            ),
+        Arg::new("taint-args")
+            .long("taint-args")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("debug")
+            .help(
+                "Marks the arguments of the entry function as tainted \
+                (see `--taint`)",
+            ),
+        Arg::new("entry")
+            .long("entry")
+            .value_name("FUNC")
+            .default_value("main")
+            .help(
+                "The function to call, whose arguments are parsed from the \
+                command line (its return value is printed, if any)",
+            ),
+        Arg::new("check-types")
+            .long("check-types")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("fuse")
+            .help(
+                "Checks the runtime types of every instruction's operands & \
+                dest against its opcode & declared type before executing it",
+            ),
+        Arg::new("keep-going")
+            .long("keep-going")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["debug", "fuse"])
+            .help(
+                "Carries on past errors caused by a single instruction (e.g. \
+                undefined variables, type errors & division by zero), \
+                printing `<error>` in place of the values that couldn't be \
+                computed, & reports every error at the end of the run",
+            ),
+        Arg::new("source").long("source").value_name("SOURCE").help(
+            "The program's source file (JSON, or a `.bril` file, which \
+                is converted with `bril2json -p`), whose line numbers are \
+                added to the locations of runtime errors",
+        ),
+        Arg::new("record")
+            .long("record")
+            .value_name("TRACE")
+            .conflicts_with_all(["debug", "fuse"])
+            .help(
+                "Records the sequence of executed instructions (& the \
+                arguments) to TRACE, for `--replay` or `trace-info`",
+            ),
+        Arg::new("replay")
+            .long("replay")
+            .value_name("TRACE")
+            .conflicts_with_all(["debug", "fuse", "record"])
+            .help(
+                "Re-runs the program with the arguments recorded in TRACE, \
+                failing if it doesn't execute the same instructions",
+            ),
+    ]
+}
+
+/// Extracts the `Limits` specified by the flags in `limit_args`
+fn get_limits(matches: &ArgMatches) -> Limits {
+    Limits {
+        max_steps: matches.get_one::<u64>("max-steps").copied(),
+        max_call_depth: matches.get_one::<usize>("max-call-depth").copied(),
+        timeout: matches
+            .get_one::<f64>("timeout")
+            .map(|secs| Duration::from_secs_f64(*secs)),
+    }
+}
+
+/// Command-line flags for tuning how the `.fbril` file is memory-mapped
+fn mmap_args() -> [Arg; 3] {
+    [
+        Arg::new("mmap-populate")
+            .long("mmap-populate")
+            .action(ArgAction::SetTrue)
+            .help("Pre-faults the whole file into memory before interpreting"),
+        Arg::new("mmap-advise")
+            .long("mmap-advise")
+            .value_name("ADVICE")
+            .value_parser(["sequential", "willneed"])
+            .help("Passes MADV_SEQUENTIAL / MADV_WILLNEED to `madvise`"),
+        Arg::new("mmap-hugepages")
+            .long("mmap-hugepages")
+            .action(ArgAction::SetTrue)
+            .help("(Experimental) Requests transparent hugepages (Linux only)"),
+    ]
+}
+
+/// Extracts the `MmapTuning` specified by the flags in `mmap_args`
+fn get_mmap_tuning(matches: &ArgMatches) -> MmapTuning {
+    let advice =
+        match matches.get_one::<String>("mmap-advise").map(|s| s.as_str()) {
+            Some("sequential") => Some(MmapAdvice::Sequential),
+            Some("willneed") => Some(MmapAdvice::WillNeed),
+            _ => None,
+        };
+    MmapTuning {
+        populate: matches.get_flag("mmap-populate"),
+        advice,
+        hugepages: matches.get_flag("mmap-hugepages"),
+    }
+}
+
+/// The command-line flag for rejecting malformed JSON Bril when flattening
+fn strict_arg() -> Arg {
+    Arg::new("strict")
+        .long("strict")
+        .action(ArgAction::SetTrue)
+        .help(
+            "Rejects unknown opcodes, unknown types & unexpected fields in \
+            the JSON\n(by default, they're reported as warnings & ignored)",
+        )
+}
+
+/// Extracts the `Ingest` mode specified by the flag in `strict_arg`
+fn get_ingest(matches: &ArgMatches) -> Ingest {
+    if matches.get_flag("strict") {
+        Ingest::Strict
+    } else {
+        Ingest::Permissive
+    }
+}
+
+/// Extracts the format to write an `.fbril` file in from the `--wide`,
+/// `--compact` & `--resolved` flags of the `flatten` subcommand (see
+/// `Header::flags`)
+fn get_format_flags(matches: &ArgMatches) -> u64 {
+    let format = if matches.get_flag("wide") {
+        WIDE_FORMAT_FLAG
+    } else if matches.get_flag("compact") {
+        COMPACT_FORMAT_FLAG
+    } else {
+        0
+    };
+    if matches.get_flag("resolved") {
+        format | RESOLVED_FLAG
+    } else {
+        format
+    }
+}
+
+/// Prints `diagnostic` to stderr (as a JSON record if `--error-format json`
+/// was specified)
+fn print_diagnostic(diagnostic: &Diagnostic, matches: &ArgMatches) {
+    match matches
+        .get_one::<String>("error-format")
+        .map(|s| s.as_str())
+    {
+        Some("json") => eprintln!("{}", diagnostic.to_json()),
+        _ => eprintln!("error: {diagnostic}"),
+    }
+}
+
+/// Prints `diagnostic` to stderr (see `print_diagnostic`) & exits with the
+/// non-zero `exit_code`
+fn exit_with_diagnostic(
+    diagnostic: Diagnostic,
+    matches: &ArgMatches,
+    exit_code: i32,
+) -> ! {
+    print_diagnostic(&diagnostic, matches);
+    std::process::exit(exit_code);
+}
+
+/// Prints each line of the error message `msg` to stderr as a separate
+/// error (flattening reports every problem it finds, one per line)
+fn print_errors(msg: &str) {
+    for line in msg.lines() {
+        eprintln!("error: {line}");
+    }
+}
+
+/// Fails a run which carried on past the `errors` (see `--keep-going`),
+/// unless it failed anyway, printing each of them (in the order they
+/// occurred) with `print_diagnostic` if `print` is true
+fn keep_going_result(
+    result: Result<(), Diagnostic>,
+    errors: &[Diagnostic],
+    print: bool,
+    matches: &ArgMatches,
+) -> Result<(), Diagnostic> {
+    if print {
+        for error in errors {
+            print_diagnostic(error, matches);
+        }
+    }
+    match (result, errors) {
+        (Ok(()), [first, ..]) => Err(Diagnostic::new(
+            first.code,
+            format!("the run carried on past {} error(s)", errors.len()),
+        )),
+        (result, _) => result,
+    }
+}
+
+/// Interprets the `.fbril` file `filename` (or the file read from stdin, if
+/// `filename` is `-`) with the args `arg_values` to the entry function, according to the flags in `matches` (see
+/// `run_program`), exiting if the file can't be loaded or interpretation
+/// fails
+fn interp_file(filename: &str, arg_values: Vec<&str>, matches: &ArgMatches) {
+    let (mmap, stdin_bytes);
+    let data: &[u8] = if filename == "-" {
+        stdin_bytes = AlignedBytes::from_reader(io::stdin().lock())
+            .unwrap_or_else(|msg| {
+                let diagnostic = Diagnostic::new(ErrorCode::MalformedFile, msg);
+                exit_with_diagnostic(diagnostic, matches, 1)
+            });
+        &stdin_bytes
+    } else {
+        mmap = memfile::mmap_existing_file_tuned(
+            filename,
+            get_mmap_tuning(matches),
+        )
+        .unwrap_or_else(|msg| {
+            let diagnostic = Diagnostic::new(ErrorCode::MalformedFile, msg);
+            exit_with_diagnostic(diagnostic, matches, 1)
+        });
+        &mmap
+    };
+    // (each function is only loaded when it's first called)
+    let program = memfile::load_program_lazily(data).unwrap_or_else(|msg| {
+        let diagnostic = Diagnostic::new(
+            ErrorCode::MalformedFile,
+            format!("malformed file `{filename}`: {msg}"),
+        );
+        exit_with_diagnostic(diagnostic, matches, 1)
+    });
+    let result = match &program {
+        LazyProgram::Narrow(funcs) => run_program(funcs, arg_values, matches),
+        LazyProgram::Wide(funcs) => run_program(funcs, arg_values, matches),
+        LazyProgram::Compact(funcs) => run_program(funcs, arg_values, matches),
+    };
+    if timing::ENABLED {
+        // (an instrumented build, i.e. one with the `timing` feature)
+        eprint!("{}", timing::take_timings().to_text());
+    }
+    match result {
+        Ok(()) => {}
+        Err(Some(diagnostic)) => exit_with_diagnostic(diagnostic, matches, 2),
+        Err(None) => std::process::exit(2),
+    }
+}
+
+/// Interprets the JSON Bril program `filename` (or the program read from
+/// stdin, if `filename` is `-`) with the args `arg_values` to `main`, by
+/// walking its JSON representation directly (see `json_interp`), exiting if
+/// the file can't be read or interpretation fails
+fn interp_json_file(
+    filename: &str,
+    arg_values: Vec<&str>,
+    matches: &ArgMatches,
+) {
+    let json = if filename == "-" {
+        serde_json::from_reader(io::stdin().lock())
+            .map_err(|e| format!("unable to parse JSON from stdin: {e}"))
+    } else {
+        std::fs::read_to_string(filename)
+            .map_err(|e| format!("unable to read `{filename}`: {e}"))
+            .and_then(|json_str| {
+                serde_json::from_str(&json_str).map_err(|e| {
+                    format!("unable to parse JSON in `{filename}`: {e}")
+                })
+            })
+    };
+    let json: serde_json::Value = json.unwrap_or_else(|msg| {
+        let diagnostic = Diagnostic::new(ErrorCode::MalformedFile, msg);
+        exit_with_diagnostic(diagnostic, matches, 1)
+    });
+    // (buffered, like the flat interpreter's output, so that the two engines
+    // do the same I/O)
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let result =
+        json_interp::try_interp_program(&json, &arg_values, &mut stdout)
+            .and_then(|()| {
+                stdout.flush().map_err(|e| {
+                    Diagnostic::new(
+                        ErrorCode::Io,
+                        format!("unable to write program output: {e}"),
+                    )
+                })
+            });
+    if let Err(diagnostic) = result {
+        exit_with_diagnostic(diagnostic, matches, 2);
+    }
+}
+
+/// Interprets the entry function given by `--entry` in `matches` (`main`
+/// by default) with the args `arg_values`, writing the value it returns
+/// (if any) to `ctx.out` after the program's output
+fn interp_entry_func<P: IndexPair, W: Write, O: Observer>(
+    funcs: &FuncTable<'_, P>,
+    arg_values: Vec<&str>,
+    matches: &ArgMatches,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<(), Diagnostic> {
+    let entry = matches.get_one::<String>("entry").expect("has a default");
+    if let Some(value) = interp_entry(funcs, entry, arg_values, ctx)? {
+        writeln!(ctx.out, "{value}").map_err(|e| {
+            Diagnostic::new(
+                ErrorCode::Io,
+                format!("unable to write program output: {e}"),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Interprets the program whose functions are in `funcs` with the args
+/// `arg_values` to the entry function, according to the flags in `matches` (i.e. the
+/// execution limits, `--entry`, `--fuse`, `--debug`, `--reverse`,
+/// `--check-types`, `--keep-going`, `--source`, `--profile`, `--coverage`, `--hot-paths`, `--branch-stats`, `--record`,
+/// `--replay` & `--format`)
+/// - Fails with `None` if the run's error has already been reported (in the
+///   `--format json` report), so it shouldn't be printed again
+fn run_program<P: IndexPair>(
+    funcs: &FuncTable<'_, P>,
+    arg_values: Vec<&str>,
+    matches: &ArgMatches,
+) -> Result<(), Option<Diagnostic>> {
+    if matches.get_flag("fuse") {
+        // (fusion is a pass over every function, so they're all loaded)
+        let program = funcs.views()?;
+        let fused: Vec<Vec<FlatInstr<P>>> =
+            program.iter().map(fusion::fuse_instrs).collect();
+        let fused_program: Vec<InstrView<P>> = program
+            .iter()
+            .zip(&fused)
+            .map(|(view, instrs)| fusion::with_instrs(view, instrs))
+            .collect();
+        run_with_reports(
+            &FuncTable::new(&fused_program),
+            funcs,
+            arg_values,
+            matches,
+        )
+    } else {
+        run_with_reports(funcs, funcs, arg_values, matches)
+    }
+}
+
+/// Reads the execution trace at `path` (written by `--record`)
+fn read_trace(path: &str) -> Result<ExecTrace, Diagnostic> {
+    std::fs::read(path)
+        .map_err(|e| format!("unable to read `{path}`: {e}"))
+        .and_then(|bytes| {
+            ExecTrace::from_bytes(&bytes)
+                .map_err(|msg| format!("malformed trace `{path}`: {msg}"))
+        })
+        .map_err(|msg| Diagnostic::new(ErrorCode::MalformedFile, msg))
+}
+
+/// Interprets the functions in `to_run` (see `run_program`), writing the
+/// reports requested in `matches` about `program`, i.e. the functions
+/// before any superinstructions were fused
+fn run_with_reports<P: IndexPair>(
+    to_run: &FuncTable<'_, P>,
+    program: &FuncTable<'_, P>,
+    arg_values: Vec<&str>,
+    matches: &ArgMatches,
+) -> Result<(), Option<Diagnostic>> {
+    let limits = get_limits(matches);
+    let profile_path = matches.get_one::<String>("profile");
+    let coverage_path = matches.get_one::<String>("coverage");
+    let num_hot_paths = matches.get_one::<usize>("hot-paths").copied();
+    let min_branch_bias = matches.get_one::<f64>("branch-stats").copied();
+    if matches.get_flag("reverse") {
+        // (a task needs a table which lives as long as the views in it)
+        let views = to_run.views()?;
+        let funcs = FuncTable::new(&views);
+        let entry = matches.get_one::<String>("entry").expect("has a default");
+        let mut debugger = ReverseDebugger::new(
+            &funcs,
+            entry,
+            &arg_values,
+            limits,
+            DEFAULT_CHECKPOINT_INTERVAL,
+        )?;
+        return debugger.run(io::stdin().lock(), io::stdout()).map_err(|e| {
+            Some(Diagnostic::new(
+                ErrorCode::Io,
+                format!("debugger I/O failed: {e}"),
+            ))
+        });
+    }
+    if matches.get_flag("debug") {
+        // The debugger's prompts are interleaved with the program's
+        // output, so the output isn't buffered in debug mode
+        let mut ctx = InterpContext::new(io::stdout(), limits)
+            .with_observer(Debugger::new(to_run));
+        ctx.check_types = matches.get_flag("check-types");
+        return Ok(interp_entry_func(to_run, arg_values, matches, &mut ctx)?);
+    }
+
+    // A replayed run uses the arguments recorded in the trace
+    let entry = matches.get_one::<String>("entry").expect("has a default");
+    let replay_trace = matches
+        .get_one::<String>("replay")
+        .map(|path| read_trace(path))
+        .transpose()?;
+    let arg_values: Vec<&str> = match &replay_trace {
+        Some(trace) => {
+            let trace_args: Vec<&str> =
+                trace.args.iter().map(String::as_str).collect();
+            if trace.entry != *entry {
+                return Err(Some(Diagnostic::new(
+                    ErrorCode::BadArgument,
+                    format!(
+                        "the trace was recorded by calling @{} (pass \
+                        `--entry {}`)",
+                        trace.entry, trace.entry
+                    ),
+                )));
+            }
+            if !arg_values.is_empty() && arg_values != trace_args {
+                return Err(Some(Diagnostic::new(
+                    ErrorCode::BadArgument,
+                    format!(
+                        "the trace was recorded with the arguments `{}`",
+                        trace_args.join(" ")
+                    ),
+                )));
+            }
+            trace_args
+        }
+        None => arg_values,
+    };
+    let mut recorder = matches
+        .get_one::<String>("record")
+        .map(|_| ExecRecorder::new(entry, &arg_values));
+    let mut replayer = replay_trace.as_ref().map(Replayer::new);
+    let mut func_profiler = matches
+        .get_one::<String>("flamegraph")
+        .map(|_| FuncProfiler::new());
+    let mut op_profiler =
+        matches.contains_id("profile-ops").then(OpProfiler::new);
+    let mut chrome_tracer = matches
+        .get_one::<String>("chrome-trace")
+        .map(|_| ChromeTracer::new(matches.get_flag("trace-blocks")));
+    let taint_sources: Vec<&str> = matches
+        .get_many::<String>("taint")
+        .map_or(vec![], |vars| vars.map(String::as_str).collect());
+    let mut taint_tracker = (!taint_sources.is_empty()
+        || matches.get_flag("taint-args"))
+    .then(|| TaintTracker::new(&taint_sources, matches.get_flag("taint-args")));
+    #[cfg(feature = "jit")]
+    let mut jit = matches
+        .get_one::<u64>("jit")
+        .map(|&threshold| TraceJit::new(threshold))
+        .transpose()
+        .map_err(|msg| {
+            Diagnostic::new(
+                ErrorCode::BadArgument,
+                format!("unable to compile loops: {msg}"),
+            )
+        })?;
+    #[cfg(not(feature = "jit"))]
+    let mut jit: Option<NoObserver> = None;
+    let tracing = recorder.is_some()
+        || replayer.is_some()
+        || func_profiler.is_some()
+        || op_profiler.is_some()
+        || chrome_tracer.is_some()
+        || taint_tracker.is_some()
+        || jit.is_some();
+    // (a replayed run can't execute more steps than the trace without
+    // diverging, so it's stopped there in case it would never finish)
+    let limits = match &replay_trace {
+        Some(trace) => {
+            let trace_len = trace.steps.len() as u64;
+            Limits {
+                max_steps: Some(
+                    limits
+                        .max_steps
+                        .map_or(trace_len, |max| max.min(trace_len)),
+                ),
+                ..limits
+            }
+        }
+        None => limits,
+    };
+    let tracers = (
+        (recorder.as_mut(), replayer.as_mut()),
+        (
+            (func_profiler.as_mut(), op_profiler.as_mut()),
+            (
+                (chrome_tracer.as_mut(), taint_tracker.as_mut()),
+                jit.as_mut(),
+            ),
+        ),
+    );
+    let write_file = |path: &str, contents: &[u8], what: &str| {
+        std::fs::write(path, contents).map_err(|e| {
+            Diagnostic::new(
+                ErrorCode::Io,
+                format!("unable to write {what} `{path}`: {e}"),
+            )
+        })
+    };
+
+    let result = if profile_path.is_some()
+        || coverage_path.is_some()
+        || num_hot_paths.is_some()
+        || min_branch_bias.is_some()
+    {
+        // The profile & reports are written even if interpretation fails
+        let mut profiler = Profiler::new();
+        let result = run_observed(
+            to_run,
+            arg_values,
+            matches,
+            limits,
+            (&mut profiler, tracers),
+        );
+        let profile = profiler.profile();
+        if let Some(path) = profile_path {
+            write_file(path, profile.to_json().as_bytes(), "profile")?;
+        }
+        if let Some(path) = coverage_path {
+            // (the report shows the original instructions, not the
+            // superinstructions that were run)
+            let report = CoverageReport::new(&program.views()?, &profile);
+            let contents = match matches
+                .get_one::<String>("coverage-format")
+                .map(|s| s.as_str())
+            {
+                Some("json") => report.to_json(),
+                _ => report.to_text(),
+            };
+            write_file(path, contents.as_bytes(), "coverage report")?;
+        }
+        if let Some(n) = num_hot_paths {
+            // (printed to stderr, so that it isn't mixed up with the
+            // program's output)
+            let report = HotPathReport::new(&program.views()?, &profile, n);
+            eprint!("{}", report.to_text());
+        }
+        if let Some(percent) = min_branch_bias {
+            let report =
+                BranchReport::new(&program.views()?, &profile, percent / 100.0);
+            eprint!("{}", report.to_text());
+        }
+        result
+    } else if tracing {
+        run_observed(to_run, arg_values, matches, limits, tracers)
+    } else {
+        // (`NoObserver`'s callbacks are optimized away entirely)
+        run_observed(to_run, arg_values, matches, limits, NoObserver)
+    };
+
+    // (like the reports, the trace & the call stacks are written even if
+    // interpretation fails)
+    if let (Some(recorder), Some(path)) =
+        (recorder, matches.get_one::<String>("record"))
+    {
+        write_file(path, &recorder.trace.to_bytes(), "trace")?;
+    }
+    if let (Some(mut profiler), Some(path)) =
+        (func_profiler, matches.get_one::<String>("flamegraph"))
+    {
+        profiler.finish();
+        let stacks = profiler.collapsed_stacks();
+        write_file(path, stacks.as_bytes(), "call stacks")?;
+        eprint!("{}", profiler.to_text());
+    }
+    if let (Some(mut tracer), Some(path)) =
+        (chrome_tracer, matches.get_one::<String>("chrome-trace"))
+    {
+        tracer.finish();
+        write_file(path, tracer.to_json().as_bytes(), "Chrome trace")?;
+    }
+    if let (Some(profiler), Some(format)) =
+        (op_profiler, matches.get_one::<String>("profile-ops"))
+    {
+        let histogram = profiler.histogram();
+        match format.as_str() {
+            "json" => eprintln!("{}", histogram.to_json()),
+            _ => eprint!("{}", histogram.to_text()),
+        }
+    }
+    if let Some(tracker) = taint_tracker {
+        eprint!("{}", tracker.report().to_text());
+    }
+    // (a run which diverges from the trace usually fails for that reason,
+    // so the mismatch is reported instead of the run's error)
+    if let Some(replayer) = replayer {
+        replayer.finish()?;
+    }
+    if matches.get_one::<String>("format").map(|s| s.as_str()) == Some("json") {
+        // (the run's error is already in the report)
+        return result.map_err(|_| None);
+    }
+    Ok(result?)
+}
+
+/// Interprets the program whose functions are in `funcs` with the args
+/// `arg_values` to the entry function under `limits` while notifying
+/// `observer`, printing the program's output according to the `--format`
+/// flag in `matches`
+fn run_observed<P: IndexPair, O: Observer>(
+    funcs: &FuncTable<'_, P>,
+    arg_values: Vec<&str>,
+    matches: &ArgMatches,
+    limits: Limits,
+    observer: O,
+) -> Result<(), Diagnostic> {
+    let source_map = read_source_map(matches)?;
+    let locate = |diagnostic: Diagnostic| match &source_map {
+        Some(map) => map.locate(diagnostic),
+        None => diagnostic,
+    };
+    if matches.get_one::<String>("format").map(|s| s.as_str()) == Some("json") {
+        // Capture the program's output, so that it can be reported
+        // along with the instruction count, time taken & error (if any)
+        let mut ctx =
+            InterpContext::new(vec![], limits).with_observer(observer);
+        ctx.check_types = matches.get_flag("check-types");
+        ctx.keep_going = matches.get_flag("keep-going");
+        ctx.predecode = !matches.get_flag("no-predecode");
+        let start = Instant::now();
+        let result = interp_entry_func(funcs, arg_values, matches, &mut ctx)
+            .map_err(locate);
+        ctx.errors = ctx.errors.into_iter().map(locate).collect();
+        let elapsed = start.elapsed();
+        let report = RunReport {
+            stdout: String::from_utf8_lossy(&ctx.out)
+                .lines()
+                .map(str::to_string)
+                .collect(),
+            steps: ctx.steps,
+            time_ms: elapsed.as_secs_f64() * 1000.0,
+            error: result.as_ref().err().cloned(),
+            errors: ctx.errors.clone(),
+        };
+        if let Some(format) = matches.get_one::<String>("time") {
+            TimeSummary::new(elapsed, ctx.steps).print(format);
+        }
+        if let Some(format) = matches.get_one::<String>("memory") {
+            MemorySummary::new(&ctx).print(format);
+        }
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report)
+                .expect("unable to serialize report")
+        );
+        // (the errors are already in the report)
+        keep_going_result(result, &ctx.errors, false, matches)
+    } else {
+        // Lock stdout once for the whole run & buffer the program's output
+        // (the buffer is flushed even if interpretation fails, so that
+        // all the output produced before the error is visible)
+        let stdout = BufWriter::new(io::stdout().lock());
+        let mut ctx =
+            InterpContext::new(stdout, limits).with_observer(observer);
+        ctx.check_types = matches.get_flag("check-types");
+        ctx.keep_going = matches.get_flag("keep-going");
+        ctx.predecode = !matches.get_flag("no-predecode");
+        let start = Instant::now();
+        let result = interp_entry_func(funcs, arg_values, matches, &mut ctx)
+            .map_err(locate);
+        ctx.errors = ctx.errors.into_iter().map(locate).collect();
+        let flushed = ctx.out.flush().map_err(|e| {
+            Diagnostic::new(
+                ErrorCode::Io,
+                format!("unable to write program output: {e}"),
+            )
+        });
+        // (the summary is printed even if interpretation fails, like the
+        // reports)
+        if let Some(format) = matches.get_one::<String>("time") {
+            TimeSummary::new(start.elapsed(), ctx.steps).print(format);
+        }
+        if let Some(format) = matches.get_one::<String>("memory") {
+            MemorySummary::new(&ctx).print(format);
+        }
+        keep_going_result(result.and(flushed), &ctx.errors, true, matches)
+    }
+}
+
+/// Reads the program's source file given by `--source` (if any), to map
+/// the locations of runtime errors to its lines (see `SourceMap::locate`)
+fn read_source_map(
+    matches: &ArgMatches,
+) -> Result<Option<SourceMap>, Diagnostic> {
+    matches
+        .get_one::<String>("source")
+        .map(|path| {
+            read_bril_source(Path::new(path))
+                .map(|json| SourceMap::from_json(&json))
+                .map_err(|msg| Diagnostic::new(ErrorCode::MalformedFile, msg))
+        })
+        .transpose()
+}
+
+/// The optimization passes which can be run from the command line
+#[derive(Debug, Clone, Copy)]
+enum Pass {
+    Ssa,
+    Licm,
+    Peephole,
+    SimplifyBranches,
+    ThreadJumps,
+}
+
+/// Command-line args for the `.fbril` files read & written by a pass
+fn pass_args() -> [Arg; 2] {
+    [
+        Arg::new("input")
+            .required(true)
+            .value_name("INPUT")
+            .help("The `.fbril` file to transform"),
+        Arg::new("output")
+            .required(true)
+            .value_name("OUTPUT")
+            .help("The `.fbril` file to write the transformed program to"),
+    ]
+}
+
+/// Applies `pass` to every function in `views`
+fn apply_pass<P: IndexPair>(
+    pass: Pass,
+    views: &[InstrView<P>],
+) -> Vec<InstrStore> {
+    Program::new(views)
+        .functions()
+        .map(|func| match pass {
+            Pass::Ssa => ssa::to_ssa(func),
+            Pass::Licm => licm::licm(func),
+            Pass::Peephole => {
+                let mut store = InstrStore::from(func.view().clone());
+                Peephole::new().run(&mut store);
+                store
+            }
+            Pass::SimplifyBranches => ranges::simplify(func),
+            Pass::ThreadJumps => jump_thread::jump_thread(func),
+        })
+        .collect()
+}
+
+/// Applies `pass` to every function in the `.fbril` file `input`,
+/// writing the resulting program to `output`
+fn run_pass(pass: Pass, input: &str, output: &str) -> Result<(), String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let instr_stores = match &program {
+        ProgramViews::Narrow(program) => apply_pass(pass, program.views()),
+        ProgramViews::Wide(program) => apply_pass(pass, program.views()),
+        ProgramViews::Compact(program) => apply_pass(pass, program.views()),
+    };
+    std::fs::write(output, memfile::instr_stores_to_fbril_bytes(instr_stores))
+        .map_err(|e| format!("unable to write `{output}`: {e}"))
+}
+
+/// Specializes `main` in the `.fbril` file `input` for the command-line args
+/// `args` (see `specialize::specialize_program`), writing the resulting
+/// program to `output`
+fn run_specialize(
+    input: &str,
+    output: &str,
+    args: &[&str],
+    max_versions: usize,
+) -> Result<specialize::SpecializeStats, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let (instr_stores, stats) = match &program {
+        ProgramViews::Narrow(program) => {
+            specialize::specialize_program(program.views(), args, max_versions)
+        }
+        ProgramViews::Wide(program) => {
+            specialize::specialize_program(program.views(), args, max_versions)
+        }
+        ProgramViews::Compact(program) => {
+            specialize::specialize_program(program.views(), args, max_versions)
+        }
+    }?;
+    std::fs::write(output, memfile::instr_stores_to_fbril_bytes(instr_stores))
+        .map_err(|e| format!("unable to write `{output}`: {e}"))?;
+    Ok(stats)
+}
+
+/// Reorders the blocks of every function in the `.fbril` file `input`
+/// using the profile at `profile_path`, writing the resulting program to
+/// `output` & returning the fall-through rates before & after reordering
+fn run_reorder(
+    input: &str,
+    output: &str,
+    profile_path: &str,
+) -> Result<layout::FallThroughStats, String> {
+    let profile = std::fs::read_to_string(profile_path)
+        .map_err(|e| format!("unable to read `{profile_path}`: {e}"))
+        .and_then(|json| Profile::from_json(&json))?;
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let (instr_stores, stats) = match &program {
+        ProgramViews::Narrow(program) => {
+            layout::reorder_program(program.views(), &profile)
+        }
+        ProgramViews::Wide(program) => {
+            layout::reorder_program(program.views(), &profile)
+        }
+        ProgramViews::Compact(program) => {
+            layout::reorder_program(program.views(), &profile)
+        }
+    }?;
+    std::fs::write(output, memfile::instr_stores_to_fbril_bytes(instr_stores))
+        .map_err(|e| format!("unable to write `{output}`: {e}"))?;
+    Ok(stats)
+}
+
+/// Renders an HTML report of the run of the `.fbril` file `input` recorded
+/// in the profile at `profile_path` (see `render_html_report`), which is
+/// written to `output`
+fn run_report(
+    input: &str,
+    profile_path: &str,
+    output: &str,
+) -> Result<(), String> {
+    let profile = std::fs::read_to_string(profile_path)
+        .map_err(|e| format!("unable to read `{profile_path}`: {e}"))
+        .and_then(|json| Profile::from_json(&json))?;
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let html = match &program {
+        ProgramViews::Narrow(program) => {
+            render_html_report(program.views(), &profile)
+        }
+        ProgramViews::Wide(program) => {
+            render_html_report(program.views(), &profile)
+        }
+        ProgramViews::Compact(program) => {
+            render_html_report(program.views(), &profile)
+        }
+    };
+    std::fs::write(output, html)
+        .map_err(|e| format!("unable to write `{output}`: {e}"))
+}
+
+/// Renders the call graph of the `.fbril` file `input`
+/// (in Graphviz's DOT language if `dot` is set)
+fn run_callgraph(input: &str, dot: bool) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let graph = match &program {
+        ProgramViews::Narrow(program) => CallGraph::new(program.views()),
+        ProgramViews::Wide(program) => CallGraph::new(program.views()),
+        ProgramViews::Compact(program) => CallGraph::new(program.views()),
+    };
+    Ok(if dot { graph.to_dot() } else { graph.to_text() })
+}
+
+/// Renders the control-flow graph of the function named `func` in the
+/// `.fbril` file `input` (in Graphviz's DOT language if `dot` is set)
+fn run_cfg(input: &str, func: &str, dot: bool) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let cfg = match &program {
+        ProgramViews::Narrow(program) => {
+            program.get(func).map(|view| Cfg::new(Function::new(view)))
+        }
+        ProgramViews::Wide(program) => {
+            program.get(func).map(|view| Cfg::new(Function::new(view)))
+        }
+        ProgramViews::Compact(program) => {
+            program.get(func).map(|view| Cfg::new(Function::new(view)))
+        }
+    }
+    .ok_or_else(|| format!("no function named `{func}` in `{input}`"))?;
+    Ok(if dot { cfg.to_dot() } else { cfg.to_text() })
+}
+
+/// Renders the `.fbril` file `input` in the Bril text format, laid out
+/// according to `opts` (only the function named `func`, if one is given)
+fn run_disasm(
+    input: &str,
+    func: Option<&str>,
+    opts: &PrintOptions,
+) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    disassemble(&mmap, func, opts)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?
+        .ok_or_else(|| {
+            format!("no function named `{}` in `{input}`", func.unwrap_or(""))
+        })
+}
+
+/// Prints an annotated hexdump of the `.fbril` file `input` (see
+/// `inspect::inspect`), including the parts which could be read if the
+/// file is malformed
+fn run_inspect(input: &str) -> Result<(), String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let mut out = String::new();
+    let result = inspect(&mmap, &mut out);
+    print!("{out}");
+    result.map_err(|msg| format!("malformed file `{input}`: {msg}"))
+}
+
+/// Renders the table of contents of the `.fbril` file `input` (see
+/// `memfile::section_layouts`)
+fn run_toc(input: &str) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let layouts = memfile::section_layouts(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    Ok(layouts_to_text(&layouts, mmap.len()))
+}
+
+/// Writes the raw bytes of the section called `section` of the `.fbril`
+/// file `input` to `output` (or to stdout, if `output` is `-`)
+fn run_extract(input: &str, section: &str, output: &str) -> Result<(), String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let bytes = memfile::extract_section(&mmap, section)
+        .map_err(|msg| format!("can't extract from `{input}`: {msg}"))?;
+    if output == "-" {
+        io::stdout()
+            .lock()
+            .write_all(bytes)
+            .map_err(|e| format!("unable to write to stdout: {e}"))
+    } else {
+        std::fs::write(output, bytes)
+            .map_err(|e| format!("unable to write `{output}`: {e}"))?;
+        eprintln!("wrote {} bytes of {section} to `{output}`", bytes.len());
+        Ok(())
+    }
+}
+
+/// A change to a single instruction made by the `patch` subcommand
+/// (see `PatchView`)
+enum InstrPatch {
+    Nop,
+    Op(Opcode),
+    Value(BrilValue),
+}
+
+/// Applies `patch` to instruction `pc` of `@func` in the `.fbril` file
+/// `input`, which is modified in place
+fn run_patch(
+    input: &str,
+    func: &str,
+    pc: usize,
+    patch: InstrPatch,
+) -> Result<(), String> {
+    let mut mmap = memfile::mmap_existing_file_mut(input)?;
+    let mut view = PatchView::new(&mut mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    match patch {
+        InstrPatch::Nop => view.nop_out(func, pc),
+        InstrPatch::Op(op) => view.set_op(func, pc, op),
+        InstrPatch::Value(value) => view.set_value(func, pc, value),
+    }?;
+    mmap.flush()
+        .map_err(|e| format!("unable to write `{input}`: {e}"))?;
+    eprintln!("patched instr {pc} of @{func} in `{input}`");
+    Ok(())
+}
+
+/// Executes the function named `func` in the `.fbril` file `input`
+/// symbolically (see `symexec`), forking at most `max_depth` times along
+/// each path, & renders the paths explored
+fn run_symexec(
+    input: &str,
+    func: &str,
+    max_depth: usize,
+) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let report = match &program {
+        ProgramViews::Narrow(program) => program
+            .get(func)
+            .map(|view| symexec(Function::new(view), max_depth)),
+        ProgramViews::Wide(program) => program
+            .get(func)
+            .map(|view| symexec(Function::new(view), max_depth)),
+        ProgramViews::Compact(program) => program
+            .get(func)
+            .map(|view| symexec(Function::new(view), max_depth)),
+    }
+    .ok_or_else(|| format!("no function named `{func}` in `{input}`"))?;
+    Ok(report.to_text())
+}
+
+/// Where a run started by `suspend` / `resume` starts from: the entry
+/// function & its arguments, or a snapshot saved by an earlier run
+enum RunStart<'s> {
+    Entry(&'s str, Vec<&'s str>),
+    Snapshot(&'s str),
+}
+
+/// Runs the `.fbril` file `input` from `start` (for at most `max_steps`
+/// instructions, if given), printing the program's output (& the value the
+/// entry function returns, if it finishes). If it's stopped before it
+/// finishes, its state is saved to the snapshot `output`.
+fn run_suspendable(
+    input: &str,
+    start: RunStart,
+    max_steps: Option<u64>,
+    output: Option<&str>,
+) -> Result<(), String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    match &program {
+        ProgramViews::Narrow(program) => {
+            run_task(&FuncTable::new(program.views()), start, max_steps, output)
+        }
+        ProgramViews::Wide(program) => {
+            run_task(&FuncTable::new(program.views()), start, max_steps, output)
+        }
+        ProgramViews::Compact(program) => {
+            run_task(&FuncTable::new(program.views()), start, max_steps, output)
+        }
+    }
+}
+
+/// The body of `run_suspendable`, for the functions in `funcs`
+fn run_task<'a, P: IndexPair>(
+    funcs: &'a FuncTable<'a, P>,
+    start: RunStart,
+    max_steps: Option<u64>,
+    output: Option<&str>,
+) -> Result<(), String> {
+    let mut task = match start {
+        RunStart::Entry(entry, args) => {
+            Task::new(funcs, entry, &args, Limits::default())
+        }
+        RunStart::Snapshot(path) => {
+            let json = std::fs::read_to_string(path)
+                .map_err(|e| format!("unable to read `{path}`: {e}"))?;
+            let snapshot = Snapshot::from_json(&json)
+                .map_err(|msg| format!("malformed snapshot `{path}`: {msg}"))?;
+            Task::restore(funcs, &snapshot, Limits::default())
+        }
+    }
+    .map_err(|e| e.to_string())?;
+    match max_steps {
+        Some(max_steps) => {
+            task.run(max_steps);
+        }
+        None => {
+            task.run_to_completion();
+        }
+    }
+    io::stdout()
+        .write_all(&task.out)
+        .map_err(|e| format!("unable to write program output: {e}"))?;
+    match &task.status {
+        TaskStatus::Runnable => {
+            let path = output.expect("`-o` is required with `--steps`");
+            let snapshot = task.snapshot().expect("task is still running");
+            std::fs::write(path, snapshot.to_json())
+                .map_err(|e| format!("unable to write `{path}`: {e}"))?;
+            eprintln!(
+                "suspended after {} instructions, saved to `{path}`",
+                task.steps
+            );
+            Ok(())
+        }
+        TaskStatus::Returned(value) => {
+            if let Some(value) = value {
+                println!("{value}");
+            }
+            Ok(())
+        }
+        TaskStatus::Failed(e) => Err(e.to_string()),
+    }
+}
+
+/// Analyzes the ranges of the variables in every function of the `.fbril`
+/// file `input` (or just the function named `func`, if given), rendering
+/// the results as a JSON array with an element per function
+fn run_ranges(input: &str, func: Option<&str>) -> Result<String, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    let cfgs: Vec<Cfg> = match &program {
+        ProgramViews::Narrow(program) => Program::new(program.views())
+            .functions()
+            .map(Cfg::new)
+            .collect(),
+        ProgramViews::Wide(program) => Program::new(program.views())
+            .functions()
+            .map(Cfg::new)
+            .collect(),
+        ProgramViews::Compact(program) => Program::new(program.views())
+            .functions()
+            .map(Cfg::new)
+            .collect(),
+    };
+    let analyses: Vec<serde_json::Value> = cfgs
+        .iter()
+        .filter(|cfg| func.is_none_or(|func| cfg.name == func))
+        .map(|cfg| ranges::analyze_ranges(cfg).to_json())
+        .collect();
+    if let Some(func) = func
+        && analyses.is_empty()
+    {
+        return Err(format!("no function named `{func}` in `{input}`"));
+    }
+    Ok(serde_json::to_string_pretty(&analyses)
+        .expect("unable to serialize range analysis"))
+}
+
+/// Lints every function in the `.fbril` file `input`
+fn run_lint(input: &str) -> Result<Vec<Lint>, String> {
+    let mmap = memfile::mmap_existing_file(input)?;
+    let program = memfile::load_program(&mmap)
+        .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+    Ok(match &program {
+        ProgramViews::Narrow(program) => {
+            lint_program(&Program::new(program.views()))
+        }
+        ProgramViews::Wide(program) => {
+            lint_program(&Program::new(program.views()))
+        }
+        ProgramViews::Compact(program) => {
+            lint_program(&Program::new(program.views()))
+        }
+    })
+}
+
+/// Combines the functions in the `.fbril` files `inputs` into a single
+/// program, which is written to `output` (see `memfile::merge_instr_stores`)
+fn run_merge(
+    inputs: &[&str],
+    output: &str,
+    allow_override: bool,
+) -> Result<(), String> {
+    let mut programs = vec![];
+    for &input in inputs {
+        let mmap = memfile::mmap_existing_file(input)?;
+        let program = memfile::load_program(&mmap)
+            .map_err(|msg| format!("malformed file `{input}`: {msg}"))?;
+        programs.push((input, program.instr_stores()));
+    }
+    let instr_stores = memfile::merge_instr_stores(programs, allow_override)?;
+    std::fs::write(output, memfile::instr_stores_to_fbril_bytes(instr_stores))
+        .map_err(|e| format!("unable to write `{output}`: {e}"))
+}
+
+/// Prints a `SizeReport` comparing the `.fbril` file `fbril` with the
+/// `json_bytes`-long JSON it was flattened from (as text or JSON,
+/// according to `format`)
+fn print_size_report(
+    fbril: &str,
+    json_bytes: usize,
+    format: &str,
+) -> Result<(), String> {
+    let mmap = memfile::mmap_existing_file(fbril)?;
+    let report = SizeReport::new(json_bytes, &mmap)?;
+    match format {
+        "json" => println!("{}", report.to_json()),
+        _ => print!("{}", report.to_text()),
+    }
+    Ok(())
+}
+
+/// Upgrades the `.fbril` file `input`, which was written by an earlier
+/// version of flat-bril, to the current layout, writing it to `output`
+/// (the file is copied as-is if it's already in the current layout).
+/// Returns a message describing what was done.
+fn run_migrate(input: &str, output: &str) -> Result<String, String> {
+    let data = std::fs::read(input)
+        .map_err(|e| format!("unable to read `{input}`: {e}"))?;
+    let (message, bytes) = match migrate::migrate_bytes(&data)
+        .map_err(|msg| format!("can't migrate `{input}`: {msg}"))?
+    {
+        Some((layout, bytes)) => (
+            format!(
+                "migrated `{input}` ({}) to `{output}`",
+                layout.description()
+            ),
+            bytes,
+        ),
+        None => (
+            format!(
+                "`{input}` is already in the current layout, copied it to \
+                `{output}`"
+            ),
+            data,
+        ),
+    };
+    std::fs::write(output, bytes)
+        .map_err(|e| format!("unable to write `{output}`: {e}"))?;
+    Ok(message)
+}
+
+/// Runs the `.fbril` files `old` & `new` in lockstep on the same `args`,
+/// reporting the first instruction at which they diverge
+fn run_divergence(
+    old: &str,
+    new: &str,
+    args: &[&str],
+    limits: Limits,
+) -> Result<DivergenceReport, String> {
+    let (old_mmap, new_mmap) = (
+        memfile::mmap_existing_file(old)?,
+        memfile::mmap_existing_file(new)?,
+    );
+    let old_program = memfile::load_program(&old_mmap)
+        .map_err(|msg| format!("malformed file `{old}`: {msg}"))?;
+    let new_program = memfile::load_program(&new_mmap)
+        .map_err(|msg| format!("malformed file `{new}`: {msg}"))?;
+    Ok(find_divergence(&old_program, &new_program, args, limits))
+}
 
 fn main() {
     let matches = Command::new("flat-bril")
@@ -28,7 +1770,11 @@ fn main() {
                 .num_args(0..)
                 .allow_hyphen_values(true)
                 .value_name("ARGS_TO_MAIN")
-                .help("Interprets a Flat Bril (.fbril) file"),
+                .help(
+                    "Interprets a Flat Bril (.fbril) file\n(the flags which \
+                    affect interpretation, e.g. `--fuse`, must come before \
+                    `--interp`)",
+                ),
         )
         .arg(
             Arg::new("json")
@@ -54,6 +1800,7 @@ fn main() {
                 .requires("filename")
                 .help("Produces a Flat Bril (.fbril) file"),
         )
+        .arg(strict_arg().requires("fbril"))
         .arg(
             Arg::new("filename")
                 .long("filename")
@@ -65,9 +1812,1407 @@ fn main() {
                     `.fbril` file to write to."
                 ),
         )
+        .args(interp_args())
+        .args(jit_args())
+        .args(limit_args())
+        .args(mmap_args())
+        .subcommand(
+            Command::new("run")
+                .about(
+                    "Interprets a Flat Bril (.fbril) file (the same as \
+                    `--filename FILE --interp ARGS...`)",
+                )
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .value_name("FILE")
+                        .help(
+                            "The `.fbril` file to interpret (`-` reads it \
+                            from stdin), or the JSON Bril program to \
+                            interpret with `--engine json`",
+                        ),
+                )
+                .arg(
+                    Arg::new("args")
+                        .num_args(0..)
+                        .allow_negative_numbers(true)
+                        .value_name("ARGS")
+                        .help("Arguments passed to the entry function"),
+                )
+                .arg(
+                    Arg::new("engine")
+                        .long("engine")
+                        .value_parser(["flat", "json"])
+                        .default_value("flat")
+                        .conflicts_with_all([
+                            "debug",
+                            "fuse",
+                            "no-predecode",
+                            "profile",
+                            "coverage",
+                            "hot-paths",
+                            "branch-stats",
+                            "format",
+                            "time",
+                            "memory",
+                            "flamegraph",
+                            "profile-ops",
+                            "chrome-trace",
+                            "taint",
+                            "taint-args",
+                            "entry",
+                            "check-types",
+                            "record",
+                            "replay",
+                        ])
+                        .help(
+                            "The interpreter to use: `json` walks the JSON \
+                            representation of a Core Bril program directly \
+                            (without flattening it), as a baseline for \
+                            comparing against the flat interpreter\n\
+                            (the execution limits are ignored by `json`)",
+                        ),
+                )
+                .args(interp_args())
+                .args(jit_args())
+                .args(limit_args())
+                .args(mmap_args()),
+        )
+        .subcommand(
+            Command::new("run-suite")
+                .about(
+                    "Runs every program listed in a JSON manifest & reports \
+                    which ones produced the expected output",
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .required(true)
+                        .value_name("MANIFEST")
+                        .help("JSON file listing the programs to run"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["text", "json"])
+                        .default_value("text")
+                        .help("Output format for the report"),
+                )
+                .args(limit_args()),
+        )
+        .subcommand(
+            Command::new("flatten")
+                .about(
+                    "Flattens a JSON Bril program (read from stdin) \
+                    into a Flat Bril (.fbril) file",
+                )
+                .arg(
+                    Arg::new("fbril")
+                        .required(true)
+                        .value_name("FBRIL")
+                        .help(
+                            "The `.fbril` file to write to (`-` writes it to \
+                            stdout), or the directory to write to with \
+                            `--per-function`",
+                        ),
+                )
+                .arg(
+                    Arg::new("append")
+                        .long("append")
+                        .value_name("JSON")
+                        .help(
+                            "Appends the functions in the JSON Bril program \
+                            JSON to the existing FBRIL file\n(FBRIL is only \
+                            replaced once it's been rewritten in full)",
+                        ),
+                )
+                .arg(
+                    Arg::new("wide")
+                        .long("wide")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("append")
+                        .help(
+                            "Writes FBRIL in the wide (64-bit index) format\n\
+                            (by default, this is only used if the program is \
+                            too big for 32-bit indexes)",
+                        ),
+                )
+                .arg(
+                    Arg::new("compact")
+                        .long("compact")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["append", "wide"])
+                        .help(
+                            "Writes FBRIL in the compact (16-bit index & \
+                            opcode) format\n(ignored if the program is too \
+                            big for 16-bit indexes)",
+                        ),
+                )
+                .arg(
+                    Arg::new("resolved")
+                        .long("resolved")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("append")
+                        .help(
+                            "Also stores the target pcs of every jump & \
+                            branch & the callee of every call in FBRIL, so \
+                            that they don't have to be looked up by name \
+                            when it's run (can be combined with `--wide` or \
+                            `--compact`)",
+                        ),
+                )
+                .arg(
+                    Arg::new("per-function")
+                        .long("per-function")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("append")
+                        .help(
+                            "Writes each function to its own `.fbril` file in \
+                            the directory FBRIL, named after the function\n\
+                            (by default, all the functions are written to \
+                            the single file FBRIL)",
+                        ),
+                )
+                .arg(
+                    Arg::new("size-report")
+                        .long("size-report")
+                        .value_name("FORMAT")
+                        .value_parser(["text", "json"])
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("text")
+                        .conflicts_with_all(["append", "per-function"])
+                        .help(
+                            "Prints the size of each section of FBRIL & how \
+                            it compares with the size of the JSON after \
+                            converting (as text by default, or as JSON with \
+                            `--size-report=json`)",
+                        ),
+                )
+                .arg(strict_arg()),
+        )
+        .subcommand(
+            Command::new("flatten-dir")
+                .about(
+                    "Flattens every Bril program (.json, or .bril via \
+                    `bril2json`) in a directory & its subdirectories into \
+                    .fbril files, preserving their relative paths",
+                )
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .value_name("DIR")
+                        .help("The directory containing the Bril programs"),
+                )
+                .arg(
+                    Arg::new("out-dir")
+                        .long("out-dir")
+                        .required(true)
+                        .value_name("OUT_DIR")
+                        .help("The directory to write the .fbril files to"),
+                ),
+        )
+        .subcommand(
+            Command::new("conformance")
+                .about(
+                    "Runs every program in a Bril test corpus (e.g. \
+                    `bril/test/interp`) with the args in its `# ARGS:` \
+                    comment & compares its output with its .out file, \
+                    reporting the percentage of supported tests that pass",
+                )
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .value_name("DIR")
+                        .help(
+                            "The directory containing the tests (.bril files \
+                            with .out files next to them)",
+                        ),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["text", "json"])
+                        .default_value("text")
+                        .help("Output format for the report"),
+                ),
+        )
+        .subcommand(
+            Command::new("ssa")
+                .about(
+                    "Converts every function in a Flat Bril (.fbril) file \
+                    to SSA form",
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("peephole")
+                .about(
+                    "Runs the built-in peephole rewrites over every function \
+                    in a Flat Bril (.fbril) file",
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("reorder")
+                .about(
+                    "Lays out the hot blocks of every function in a Flat Bril \
+                    (.fbril) file contiguously, using a profile recorded \
+                    with `--profile`",
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .required(true)
+                        .value_name("PROFILE")
+                        .help("The profile of a run of INPUT"),
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("report")
+                .about(
+                    "Renders the disassembled Flat Bril (.fbril) file with \
+                    the no. of times each instruction was executed, the \
+                    hottest blocks & a summary of each function, as a \
+                    self-contained HTML page, using a profile recorded with \
+                    `--profile`",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .long("profile")
+                        .required(true)
+                        .value_name("PROFILE")
+                        .help("The profile of a run of INPUT"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .value_name("HTML")
+                        .help("The `.html` file to write to"),
+                ),
+        )
+        .subcommand(
+            Command::new("callgraph")
+                .about(
+                    "Prints the call graph of a Flat Bril (.fbril) file \
+                    (one line per function, listing the functions it calls)",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("dot")
+                        .long("dot")
+                        .action(ArgAction::SetTrue)
+                        .help("Prints the call graph in Graphviz's DOT language"),
+                ),
+        )
+        .subcommand(
+            Command::new("cfg")
+                .about(
+                    "Prints the control-flow graph of a function in a Flat \
+                    Bril (.fbril) file (each block's instructions, followed \
+                    by its successors)",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("FUNC")
+                        .default_value("main")
+                        .help("The function whose CFG is printed"),
+                )
+                .arg(
+                    Arg::new("dot")
+                        .long("dot")
+                        .action(ArgAction::SetTrue)
+                        .help("Prints the CFG in Graphviz's DOT language"),
+                ),
+        )
+        .subcommand(
+            Command::new("disasm")
+                .about(
+                    "Prints a Flat Bril (.fbril) file in the Bril text \
+                    format, with the types in each block aligned & blank \
+                    lines between blocks",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("FUNC")
+                        .help("Only prints this function"),
+                )
+                .arg(
+                    Arg::new("indent")
+                        .long("indent")
+                        .value_name("N")
+                        .default_value("2")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("The no. of spaces before each instruction"),
+                )
+                .arg(
+                    Arg::new("no-align")
+                        .long("no-align")
+                        .action(ArgAction::SetTrue)
+                        .help("Doesn't pad destinations & types to line up"),
+                )
+                .arg(
+                    Arg::new("compact")
+                        .long("compact")
+                        .action(ArgAction::SetTrue)
+                        .help("Omits the blank lines between blocks"),
+                )
+                .arg(
+                    Arg::new("pcs")
+                        .long("pcs")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Adds the PC of each instruction as a comment \
+                            (the locations used in error messages)",
+                        ),
+                )
+                .arg(
+                    Arg::new("addresses")
+                        .long("addresses")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Starts each instruction with its byte offset \
+                            in the file & its PC",
+                        ),
+                )
+                .arg(
+                    Arg::new("no-color")
+                        .long("no-color")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Doesn't highlight opcodes, destinations & labels \
+                            (they're only highlighted when printing to a \
+                            terminal)",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about(
+                    "Prints an annotated hexdump of a Flat Bril (.fbril) \
+                    file, labelling each region with its section & decoding \
+                    the fields of every entry",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                ),
+        )
+        .subcommand(
+            Command::new("toc")
+                .about(
+                    "Prints the table of contents of a Flat Bril (.fbril) \
+                    file: the offset, size, no. of entries & alignment of \
+                    each section",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                ),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about(
+                    "Writes the raw bytes of one section of a Flat Bril \
+                    (.fbril) file (without its padding) to a file, so that \
+                    other tools can inspect or compare it",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("section")
+                        .long("section")
+                        .required(true)
+                        .value_name("SECTION")
+                        .help(
+                            "The section to extract, as named by `toc` \
+                            (e.g. strings, instrs or labels_idxes_store)",
+                        ),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .value_name("OUTPUT")
+                        .help("The file to write to (`-` for stdout)"),
+                ),
+        )
+        .subcommand(
+            Command::new("patch")
+                .about(
+                    "Patches a single instruction of a Flat Bril (.fbril) \
+                    file in place, e.g. replacing it with a `nop`, changing \
+                    its opcode or changing the value of a `const`",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to patch"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("FUNC")
+                        .default_value("main")
+                        .help("The function containing the instruction"),
+                )
+                .arg(
+                    Arg::new("pc")
+                        .long("pc")
+                        .required(true)
+                        .value_name("PC")
+                        .value_parser(clap::value_parser!(usize))
+                        .help(
+                            "The pc of the instruction within the function \
+                            (as printed by `disasm --pcs`)",
+                        ),
+                )
+                .arg(
+                    Arg::new("nop")
+                        .long("nop")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["op", "value"])
+                        .help("Replace the instruction with a `nop`"),
+                )
+                .arg(
+                    Arg::new("op")
+                        .long("op")
+                        .value_name("OPCODE")
+                        .conflicts_with("value")
+                        .help("Replace the instruction's opcode (e.g. `sub`)"),
+                )
+                .arg(
+                    Arg::new("value")
+                        .long("value")
+                        .value_name("VALUE")
+                        .help(
+                            "Replace the value of a `const` \
+                            (an int, `true` or `false`)",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("symexec")
+                .about(
+                    "Executes a function in a Flat Bril (.fbril) file with \
+                    symbolic values for its parameters, printing the path \
+                    conditions under which each `print` & `ret` is reached",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("FUNC")
+                        .default_value("main")
+                        .help("The function to execute"),
+                )
+                .arg(
+                    Arg::new("depth")
+                        .long("depth")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("8")
+                        .help(
+                            "The no. of symbolic branches each path may \
+                            fork at before it's cut off",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("suspend")
+                .about(
+                    "Interprets a Flat Bril (.fbril) file for at most N \
+                    instructions, saving the state of the run to a snapshot \
+                    if it hasn't finished by then (see `resume`)",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to interpret"),
+                )
+                .arg(
+                    Arg::new("args")
+                        .num_args(0..)
+                        .allow_negative_numbers(true)
+                        .value_name("ARGS")
+                        .help("Arguments passed to the entry function"),
+                )
+                .arg(
+                    Arg::new("steps")
+                        .long("steps")
+                        .value_name("N")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u64))
+                        .help("The no. of instructions to execute"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("SNAPSHOT")
+                        .required(true)
+                        .help("Where to save the snapshot (as JSON)"),
+                )
+                .arg(
+                    Arg::new("entry")
+                        .long("entry")
+                        .value_name("FUNC")
+                        .default_value("main")
+                        .help("The function to call"),
+                ),
+        )
+        .subcommand(
+            Command::new("resume")
+                .about(
+                    "Continues a run of a Flat Bril (.fbril) file from a \
+                    snapshot saved by `suspend` (or by an earlier `resume`)",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file that was being interpreted"),
+                )
+                .arg(
+                    Arg::new("snapshot")
+                        .required(true)
+                        .value_name("SNAPSHOT")
+                        .help("The snapshot to resume from"),
+                )
+                .arg(
+                    Arg::new("steps")
+                        .long("steps")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("output")
+                        .help(
+                            "Suspends the run again after N more \
+                            instructions (by default, it runs until it \
+                            finishes)",
+                        ),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("NEW_SNAPSHOT")
+                        .requires("steps")
+                        .help(
+                            "Where to save the snapshot if the run is \
+                            suspended again",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("lint")
+                .about(
+                    "Reports dead destinations, variables which may be used \
+                    before they're defined & unreachable code in a Flat Bril \
+                    (.fbril) file (exits with status 1 if any are found)",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                ),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about(
+                    "Combines the functions in several Flat Bril (.fbril) \
+                    files into a single program (calls between the files \
+                    aren't checked)",
+                )
+                .arg(
+                    Arg::new("inputs")
+                        .required(true)
+                        .num_args(1..)
+                        .value_name("INPUTS")
+                        .help("The `.fbril` files to combine"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .value_name("OUTPUT")
+                        .help("The `.fbril` file to write the program to"),
+                )
+                .arg(
+                    Arg::new("allow-override")
+                        .long("allow-override")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Lets a function defined in a later file replace \
+                            one with the same name in an earlier file\n(by \
+                            default, duplicate functions are an error)",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("migrate")
+                .about(
+                    "Upgrades a Flat Bril (.fbril) file written by an earlier \
+                    version of flat-bril to the current layout",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to upgrade"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .required(true)
+                        .value_name("OUTPUT")
+                        .help("The `.fbril` file to write the upgraded file to"),
+                ),
+        )
+        .subcommand(
+            Command::new("licm")
+                .about(
+                    "Hoists loop-invariant instructions out of the loops \
+                    in a Flat Bril (.fbril) file",
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("simplify-branches")
+                .about(
+                    "Replaces the branches in a Flat Bril (.fbril) file whose \
+                    conditions are constant (according to `ranges`) with \
+                    jumps, & removes the blocks which are then unreachable",
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("thread-jumps")
+                .about(
+                    "Threads the jumps in a Flat Bril (.fbril) file: jumps & \
+                    branches to blocks which are empty or only contain a \
+                    `jmp` are redirected to where control ends up, & the \
+                    blocks which are then unreachable or empty are removed",
+                )
+                .args(pass_args()),
+        )
+        .subcommand(
+            Command::new("specialize")
+                .about(
+                    "Partially evaluates `main` in a Flat Bril (.fbril) file \
+                    for the given args: instructions whose args are known \
+                    are folded, branches whose conditions are known are \
+                    pruned & loops whose bounds are known are unrolled",
+                )
+                .args(pass_args())
+                .arg(
+                    Arg::new("args")
+                        .num_args(0..)
+                        .allow_negative_numbers(true)
+                        .value_name("ARGS")
+                        .help(
+                            "The args of `main` (the specialized `main` \
+                            takes no args)",
+                        ),
+                )
+                .arg(
+                    Arg::new("max-versions")
+                        .long("max-versions")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("32")
+                        .help(
+                            "The maximum no. of specialized copies of each \
+                            block (which bounds how far loops are unrolled)",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("ranges")
+                .about(
+                    "Prints the range of each int variable & whether each \
+                    bool variable is constant, at the entry & exit of every \
+                    block of a Flat Bril (.fbril) file, as JSON",
+                )
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_name("INPUT")
+                        .help("The `.fbril` file to read"),
+                )
+                .arg(
+                    Arg::new("func")
+                        .long("func")
+                        .value_name("FUNC")
+                        .help(
+                            "The function to analyze (by default, every \
+                            function is analyzed)",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about(
+                    "Re-flattens a Bril program (.json, or .bril via \
+                    `bril2json`) whenever it changes, optionally re-running \
+                    it & printing how its output changed",
+                )
+                .arg(
+                    Arg::new("source")
+                        .required(true)
+                        .value_name("SOURCE")
+                        .help("The Bril program to watch"),
+                )
+                .arg(
+                    Arg::new("run")
+                        .long("run")
+                        .action(ArgAction::SetTrue)
+                        .help("Runs the program after each change"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("FBRIL")
+                        .help("Writes the flattened program to FBRIL"),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("250")
+                        .help("How often to check for changes"),
+                )
+                .arg(
+                    Arg::new("args")
+                        .num_args(0..)
+                        .allow_hyphen_values(true)
+                        .value_name("ARGS")
+                        .help("Arguments passed to `main` (with `--run`)"),
+                )
+                .args(limit_args()),
+        )
+        .subcommand(Command::new("repl").about(
+            "Interactively runs Bril instructions (in the text format), \
+            one line at a time",
+        ))
+        .subcommand(Command::new("dap").about(
+            "Runs a Debug Adapter Protocol server on stdin & stdout, so that \
+            editors can debug Bril programs",
+        ))
+        .subcommand(
+            Command::new("trace-info")
+                .about(
+                    "Summarizes an execution trace recorded with `--record` \
+                    (steps per function & the hottest instructions)",
+                )
+                .arg(
+                    Arg::new("trace")
+                        .required(true)
+                        .value_name("TRACE")
+                        .help("The trace file to analyze"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10")
+                        .help("How many of the hottest instructions to list"),
+                )
+                .arg(
+                    Arg::new("dump")
+                        .long("dump")
+                        .action(ArgAction::SetTrue)
+                        .help("Prints every step (as `@func:pc`) instead"),
+                ),
+        )
+        .subcommand(
+            Command::new("divergence")
+                .about(
+                    "Runs two Flat Bril (.fbril) files in lockstep & reports \
+                    the first instruction at which their environments or \
+                    output differ",
+                )
+                .arg(
+                    Arg::new("old")
+                        .required(true)
+                        .value_name("OLD")
+                        .help("The original `.fbril` file"),
+                )
+                .arg(
+                    Arg::new("new")
+                        .required(true)
+                        .value_name("NEW")
+                        .help("The transformed `.fbril` file"),
+                )
+                .arg(
+                    Arg::new("args")
+                        .long("args")
+                        .num_args(0..)
+                        .allow_negative_numbers(true)
+                        .value_name("ARGS")
+                        .help("Arguments passed to `main` in both programs"),
+                )
+                .args(limit_args()),
+        )
         .get_matches();
 
-    if matches.get_flag("json") {
+    if let Some(("run-suite", sub_matches)) = matches.subcommand() {
+        let manifest = sub_matches
+            .get_one::<String>("manifest")
+            .expect("missing manifest");
+        let report = suite::run_suite(manifest, get_limits(sub_matches));
+        match sub_matches.get_one::<String>("format").map(|s| s.as_str()) {
+            Some("json") => println!(
+                "{:#}",
+                serde_json::to_value(&report)
+                    .expect("unable to serialize report")
+            ),
+            _ => println!("{}", report.to_table()),
+        }
+        if report.passed != report.total {
+            std::process::exit(1);
+        }
+    } else if let Some(("flatten", sub_matches)) = matches.subcommand() {
+        let fbril = sub_matches
+            .get_one::<String>("fbril")
+            .expect("missing fbril file");
+        let result = match sub_matches.get_one::<String>("append") {
+            _ if fbril == "-" => {
+                if sub_matches.contains_id("append")
+                    || sub_matches.contains_id("size-report")
+                    || sub_matches.get_flag("per-function")
+                {
+                    Err("`--append`, `--size-report` & `--per-function` \
+                        can't be used when writing to stdout (`-`)"
+                        .to_string())
+                } else {
+                    memfile::stream_json_to_writer(
+                        io::stdin().lock(),
+                        BufWriter::new(io::stdout().lock()),
+                        get_format_flags(sub_matches),
+                        get_ingest(sub_matches),
+                    )
+                }
+            }
+            _ if sub_matches.get_flag("per-function") => {
+                memfile::stream_json_to_fbril_per_function(
+                    io::stdin().lock(),
+                    fbril,
+                    get_format_flags(sub_matches),
+                    get_ingest(sub_matches),
+                )
+                .map(|outputs| {
+                    eprintln!("wrote {} files to `{fbril}`", outputs.len())
+                })
+            }
+            Some(json) => File::open(json)
+                .map_err(|e| format!("unable to open `{json}`: {e}"))
+                .and_then(|file| {
+                    memfile::append_json_to_fbril(
+                        BufReader::new(file),
+                        fbril,
+                        get_ingest(sub_matches),
+                    )
+                }),
+            None => {
+                let mut reader = CountingReader::new(io::stdin().lock());
+                memfile::json_to_fbril(
+                    &mut reader,
+                    fbril.clone(),
+                    get_format_flags(sub_matches),
+                    get_ingest(sub_matches),
+                )
+                .and_then(|()| {
+                    match sub_matches.get_one::<String>("size-report") {
+                        Some(format) => print_size_report(
+                            fbril,
+                            reader.bytes_read(),
+                            format,
+                        ),
+                        None => Ok(()),
+                    }
+                })
+            }
+        };
+        if let Err(msg) = result {
+            print_errors(&msg);
+            std::process::exit(1);
+        }
+    } else if let Some((
+        name @ ("ssa" | "licm" | "peephole" | "simplify-branches"
+        | "thread-jumps"),
+        sub_matches,
+    )) = matches.subcommand()
+    {
+        let pass = match name {
+            "ssa" => Pass::Ssa,
+            "licm" => Pass::Licm,
+            "peephole" => Pass::Peephole,
+            "simplify-branches" => Pass::SimplifyBranches,
+            _ => Pass::ThreadJumps,
+        };
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        let output = sub_matches
+            .get_one::<String>("output")
+            .expect("missing output file");
+        if let Err(msg) = run_pass(pass, input, output) {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("specialize", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        let args: Vec<&str> = sub_matches
+            .get_many::<String>("args")
+            .map(|args| args.map(String::as_str).collect())
+            .unwrap_or_default();
+        let max_versions = *sub_matches
+            .get_one::<usize>("max-versions")
+            .expect("missing max-versions");
+        match run_specialize(
+            get_arg("input"),
+            get_arg("output"),
+            &args,
+            max_versions,
+        ) {
+            Ok(stats) => eprintln!(
+                "@main: blocks {} -> {}, instrs folded {}, \
+                branches pruned {}",
+                stats.blocks_before,
+                stats.blocks_after,
+                stats.folded,
+                stats.pruned_branches
+            ),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("ranges", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        let func = sub_matches.get_one::<String>("func").map(String::as_str);
+        match run_ranges(input, func) {
+            Ok(json) => println!("{json}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("reorder", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        match run_reorder(
+            get_arg("input"),
+            get_arg("output"),
+            get_arg("profile"),
+        ) {
+            Ok(stats) => {
+                let (before, after) = stats.rates();
+                eprintln!(
+                    "dynamic fall-through rate: {:.1}% -> {:.1}%",
+                    before * 100.0,
+                    after * 100.0
+                );
+            }
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("flatten-dir", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            Path::new(
+                sub_matches
+                    .get_one::<String>(name)
+                    .unwrap_or_else(|| panic!("missing {name}")),
+            )
+        };
+        match flatten_dir(get_arg("dir"), get_arg("out-dir")) {
+            Ok(report) => {
+                print!("{}", report.to_text());
+                if report.counts().2 > 0 {
+                    std::process::exit(1);
+                }
+            }
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("conformance", sub_matches)) = matches.subcommand() {
+        let dir = sub_matches.get_one::<String>("dir").expect("missing dir");
+        match conformance::run_conformance(Path::new(dir)) {
+            Ok(report) => {
+                match sub_matches
+                    .get_one::<String>("format")
+                    .map(|s| s.as_str())
+                {
+                    Some("json") => println!("{}", report.to_json()),
+                    _ => println!("{}", report.to_text()),
+                }
+                if report.passed != report.supported {
+                    std::process::exit(1);
+                }
+            }
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("report", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        if let Err(msg) =
+            run_report(get_arg("input"), get_arg("profile"), get_arg("output"))
+        {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("callgraph", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        match run_callgraph(input, sub_matches.get_flag("dot")) {
+            Ok(graph) => print!("{graph}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("cfg", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        match run_cfg(
+            get_arg("input"),
+            get_arg("func"),
+            sub_matches.get_flag("dot"),
+        ) {
+            Ok(cfg) => print!("{cfg}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("disasm", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        let opts = PrintOptions {
+            indent: *sub_matches
+                .get_one::<usize>("indent")
+                .expect("indent has a default"),
+            align_types: !sub_matches.get_flag("no-align"),
+            blank_lines: !sub_matches.get_flag("compact"),
+            show_pcs: sub_matches.get_flag("pcs"),
+            show_addresses: sub_matches.get_flag("addresses"),
+            color: !sub_matches.get_flag("no-color")
+                && io::stdout().is_terminal(),
+        };
+        let func = sub_matches.get_one::<String>("func").map(String::as_str);
+        match run_disasm(input, func, &opts) {
+            Ok(text) => print!("{text}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("inspect", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        if let Err(msg) = run_inspect(input) {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("toc", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        match run_toc(input) {
+            Ok(table) => print!("{table}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("extract", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        if let Err(msg) =
+            run_extract(get_arg("input"), get_arg("section"), get_arg("output"))
+        {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("patch", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches.get_one::<String>(name).map(|s| s.as_str())
+        };
+        let patch = if sub_matches.get_flag("nop") {
+            Ok(InstrPatch::Nop)
+        } else if let Some(op) = get_arg("op") {
+            serde_json::from_value(serde_json::json!(op))
+                .map(InstrPatch::Op)
+                .map_err(|_| format!("unknown opcode `{op}`"))
+        } else if let Some(value) = get_arg("value") {
+            value
+                .parse::<i64>()
+                .map(BrilValue::IntVal)
+                .or_else(|_| {
+                    value.parse::<bool>().map(|b| BrilValue::BoolVal(b.into()))
+                })
+                .map(InstrPatch::Value)
+                .map_err(|_| format!("`{value}` isn't an int or a bool"))
+        } else {
+            Err("one of --nop, --op or --value is required".to_string())
+        };
+        let result = patch.and_then(|patch| {
+            run_patch(
+                get_arg("input").expect("missing input"),
+                get_arg("func").expect("missing func"),
+                *sub_matches.get_one::<usize>("pc").expect("missing pc"),
+                patch,
+            )
+        });
+        if let Err(msg) = result {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some((name @ ("suspend" | "resume"), sub_matches)) =
+        matches.subcommand()
+    {
+        let get_arg = |name: &str| {
+            sub_matches.get_one::<String>(name).map(|s| s.as_str())
+        };
+        let input = get_arg("input").expect("missing input");
+        let start = if name == "suspend" {
+            let args = sub_matches
+                .get_many::<String>("args")
+                .unwrap_or_default()
+                .map(|s| s.as_str())
+                .collect();
+            RunStart::Entry(get_arg("entry").expect("has a default"), args)
+        } else {
+            RunStart::Snapshot(get_arg("snapshot").expect("missing snapshot"))
+        };
+        let max_steps = sub_matches.get_one::<u64>("steps").copied();
+        if let Err(msg) =
+            run_suspendable(input, start, max_steps, get_arg("output"))
+        {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("symexec", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        let depth = sub_matches
+            .get_one::<usize>("depth")
+            .expect("has a default");
+        match run_symexec(get_arg("input"), get_arg("func"), *depth) {
+            Ok(report) => print!("{report}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("lint", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        match run_lint(input) {
+            Ok(lints) => {
+                print!("{}", lints_to_text(&lints));
+                if !lints.is_empty() {
+                    std::process::exit(1);
+                }
+            }
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("merge", sub_matches)) = matches.subcommand() {
+        let inputs: Vec<&str> = sub_matches
+            .get_many::<String>("inputs")
+            .expect("missing input files")
+            .map(String::as_str)
+            .collect();
+        let output = sub_matches
+            .get_one::<String>("output")
+            .expect("missing output file");
+        if let Err(msg) =
+            run_merge(&inputs, output, sub_matches.get_flag("allow-override"))
+        {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("migrate", sub_matches)) = matches.subcommand() {
+        let input = sub_matches
+            .get_one::<String>("input")
+            .expect("missing input file");
+        let output = sub_matches
+            .get_one::<String>("output")
+            .expect("missing output file");
+        match run_migrate(input, output) {
+            Ok(message) => eprintln!("{message}"),
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("watch", sub_matches)) = matches.subcommand() {
+        let mut watcher = Watcher::new(
+            sub_matches
+                .get_one::<String>("source")
+                .expect("missing source file"),
+        );
+        watcher.run = sub_matches.get_flag("run");
+        watcher.output = sub_matches.get_one::<String>("out").map(Into::into);
+        watcher.args = sub_matches
+            .get_many::<String>("args")
+            .unwrap_or_default()
+            .cloned()
+            .collect();
+        watcher.limits = get_limits(sub_matches);
+        let interval = Duration::from_millis(
+            *sub_matches
+                .get_one::<u64>("interval")
+                .expect("has a default"),
+        );
+        if let Err(e) = watcher.watch(interval, io::stdout().lock()) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    } else if let Some(("trace-info", sub_matches)) = matches.subcommand() {
+        let path = sub_matches
+            .get_one::<String>("trace")
+            .expect("missing trace file");
+        let trace = read_trace(path).unwrap_or_else(|diagnostic| {
+            eprintln!("error: {diagnostic}");
+            std::process::exit(1);
+        });
+        if sub_matches.get_flag("dump") {
+            // (locked once, since traces can be very long)
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            for idx in 0..trace.steps.len() {
+                if writeln!(stdout, "{}", trace.format_step(idx)).is_err() {
+                    break;
+                }
+            }
+        } else {
+            let top =
+                sub_matches.get_one::<usize>("top").expect("has a default");
+            print!("{}", trace.summary(*top));
+        }
+    } else if let Some(("divergence", sub_matches)) = matches.subcommand() {
+        let get_arg = |name: &str| {
+            sub_matches
+                .get_one::<String>(name)
+                .unwrap_or_else(|| panic!("missing {name}"))
+        };
+        let args: Vec<&str> = sub_matches
+            .get_many::<String>("args")
+            .unwrap_or_default()
+            .map(String::as_str)
+            .collect();
+        match run_divergence(
+            get_arg("old"),
+            get_arg("new"),
+            &args,
+            get_limits(sub_matches),
+        ) {
+            Ok(report) => {
+                print!("{}", report.to_text());
+                if report.divergence.is_some() {
+                    std::process::exit(1);
+                }
+            }
+            Err(msg) => {
+                eprintln!("error: {msg}");
+                std::process::exit(1);
+            }
+        }
+    } else if let Some(("dap", _)) = matches.subcommand() {
+        if let Err(msg) = dap::serve(io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("error: {msg}");
+            std::process::exit(1);
+        }
+    } else if let Some(("repl", _)) = matches.subcommand() {
+        let stdout = io::stdout();
+        if let Err(e) = Repl::new().run(io::stdin().lock(), stdout.lock()) {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+    } else if matches.get_flag("json") {
         let input_json_opt = matches.get_one::<String>("filename");
 
         let verbose = matches.get_flag("verbose");
@@ -78,8 +3223,16 @@ fn main() {
         // Convert the JSON Bril program to a flat Bril program
         match matches.get_one::<String>("filename") {
             Some(filename) => {
-                println!("Processing {}", filename);
-                memfile::json_to_fbril(filename.clone());
+                eprintln!("Processing {}", filename);
+                if let Err(msg) = memfile::json_to_fbril(
+                    io::stdin().lock(),
+                    filename.clone(),
+                    0,
+                    get_ingest(&matches),
+                ) {
+                    print_errors(&msg);
+                    std::process::exit(1);
+                }
             }
             None => {
                 eprintln!("Error: --fbril requires a filename argument");
@@ -92,33 +3245,20 @@ fn main() {
         let filename = matches
             .get_one::<String>("filename")
             .expect("missing filename");
-
         let arg_values: Vec<&str> =
             possible_arg_values.map(|s| s.as_str()).collect();
-        // Actually interpret a flat Bril file
-        if !Path::new(&filename).exists() {
-            panic!("tried to open a non-existent file");
-        }
-
-        let new_mmap =
-            memfile::mmap_new_file(filename.as_str(), 100000000, false);
-        let (new_header, remaining_mmap) =
-            Header::ref_from_prefix(&new_mmap).unwrap();
-
-        let mut offset = 0;
-
-        let mut program_vec = vec![];
-        for size in new_header.sizes {
-            if size != 0 {
-                let size = size as usize;
-                let instr_view = memfile::get_instr_view(
-                    &remaining_mmap[offset..offset + size],
-                );
-                program_vec.push(instr_view);
-                offset += size;
-            }
+        interp_file(filename, arg_values, &matches);
+    } else if let Some(("run", sub_matches)) = matches.subcommand() {
+        let filename =
+            sub_matches.get_one::<String>("file").expect("missing file");
+        let arg_values: Vec<&str> = sub_matches
+            .get_many::<String>("args")
+            .unwrap_or_default()
+            .map(|s| s.as_str())
+            .collect();
+        match sub_matches.get_one::<String>("engine").map(|s| s.as_str()) {
+            Some("json") => interp_json_file(filename, arg_values, sub_matches),
+            _ => interp_file(filename, arg_values, sub_matches),
         }
-        let program = program_vec.as_slice();
-        interp_program(program, arg_values);
     }
 }
@@ -1,110 +1,29 @@
 #![allow(dead_code, unused_imports)]
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
-use std::io::Read;
+#[cfg(feature = "mmap")]
+use std::fs::File;
+#[cfg(feature = "mmap")]
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::str;
 
-use memmap2::{Mmap, MmapMut};
+#[cfg(feature = "mmap")]
+use memmap2::{Advice, Mmap, MmapMut, MmapOptions};
 use num_traits::ops::bytes;
+use zerocopy::{ConvertError, TryFromBytes, ValidityError};
 use zerocopy::{
     FromBytes, Immutable, IntoBytes, KnownLayout, SizeError, Unaligned,
 };
-use zerocopy::{TryFromBytes, ValidityError};
 
 use crate::flatten;
-use crate::interp;
+use crate::interp::{self, FuncTable};
 use crate::types::*;
 
 /* -------------------------------------------------------------------------- */
 /*                              Writing to buffer                             */
 /* -------------------------------------------------------------------------- */
 
-/// Mmaps a new file with `size` bytes, returning a handle to the mmap-ed buffer
-pub fn mmap_new_file(
-    filename: &str,
-    size: u64,
-    should_truncate: bool,
-) -> MmapMut {
-    let file = std::fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .truncate(should_truncate)
-        .create(true)
-        .open(filename)
-        .unwrap();
-    file.set_len(size).unwrap();
-    let buffer = unsafe { MmapMut::map_mut(&file) };
-    buffer.unwrap()
-}
-
-/// Writes `data` to the first `len` bytes of `buffer`,
-/// (where `len = sizeof(data)`), & returns a mutable reference to
-/// `buffer[len..]` (i.e. the suffix of `buffer` after the first `len` bytes).
-/// - Note: Use `write_bump` when `data` has type `T` where `T` is *not* a slice
-///   (i.e. when the size of `T` is not statically known)
-fn write_bump<'a, T: IntoBytes + Immutable + ?Sized>(
-    buffer: &'a mut [u8],
-    data: &'a T,
-) -> Result<&'a mut [u8], SizeError<&'a T, &'a mut [u8]>> {
-    let len = size_of_val(data);
-    data.write_to_prefix(buffer)?;
-    Ok(&mut buffer[len..])
-}
-
-/// Writes `data` to the first `len` bytes of `buffer`
-/// (where `len = sizeof(data)`), and returns a mutable reference to
-/// `buffer[len..]` (i.e. the suffix of `buffer` after the first `len` bytes).
-/// - Note: `write_bytes` is a specialized version of `write_bump` where
-///   `data: &[u8]` (i.e. `data` is just a slice containing bytes)
-fn write_bytes<'a>(buffer: &'a mut [u8], data: &[u8]) -> Option<&'a mut [u8]> {
-    let len = data.len();
-    buffer[0..len].copy_from_slice(data);
-    Some(&mut buffer[len..])
-}
-
-/// Converts an `InstrView` to a vec of bytes
-pub fn convert_instr_view_to_bytes(instr_view: &InstrView) -> Vec<u8> {
-    let mut bytes_vec = vec![];
-
-    let toc = instr_view.get_sizes();
-    bytes_vec.extend_from_slice(toc.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.func_name.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.func_args.as_bytes());
-    let func_ret_ty = instr_view.func_ret_ty;
-    bytes_vec.extend_from_slice(func_ret_ty.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.var_store.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.arg_idxes_store.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.labels_idxes_store.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.labels_store.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.funcs_store.as_bytes());
-    bytes_vec.extend_from_slice(instr_view.instrs.as_bytes());
-
-    bytes_vec
-}
-
-/// Writes the `InstrView` to a buffer (note: `buffer` is modified in place)
-fn dump_to_buffer<'a>(instr_view: &'a InstrView, buffer: &'a mut [u8]) {
-    // Write the table of contents to the buffer
-    let toc = instr_view.get_sizes();
-
-    let new_buffer =
-        write_bump(buffer, &toc).expect("error writing Toc to buffer");
-
-    // Write the acutal contents of the `InstrView` to the buffer
-    let new_buffer = write_bytes(new_buffer, instr_view.func_name).unwrap();
-    let new_buffer = write_bump(new_buffer, instr_view.func_args).unwrap();
-    let func_ret_ty = instr_view.func_ret_ty;
-    let new_buffer = write_bump(new_buffer, &func_ret_ty).unwrap();
-    let new_buffer = write_bytes(new_buffer, instr_view.var_store).unwrap();
-    let new_buffer =
-        write_bump(new_buffer, instr_view.arg_idxes_store).unwrap();
-    let new_buffer =
-        write_bump(new_buffer, instr_view.labels_idxes_store).unwrap();
-    let new_buffer = write_bytes(new_buffer, instr_view.labels_store).unwrap();
-    let new_buffer = write_bytes(new_buffer, instr_view.funcs_store).unwrap();
-    write_bump(new_buffer, instr_view.instrs).unwrap();
-}
-
 /// Pads a vec till its length is a multiple of 4
 pub fn pad_vec(mut vec: Vec<u8>) -> Vec<u8> {
     let remainder = vec.len() % 4;
@@ -119,161 +38,2524 @@ pub fn pad_vec(mut vec: Vec<u8>) -> Vec<u8> {
     vec
 }
 
+/// A function's name without the NULs which pad the names read from older
+/// files
+fn trim_func_name(name: &[u8]) -> &[u8] {
+    let len = name
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    &name[..len]
+}
+
+/// Converts the flattened functions of a program (the `InstrStore`s) to the
+/// contents of an `.fbril` file, using `P` for the index pairs: the stores
+/// of all the functions are appended to one another (see
+/// `InstrStore::append`), their names are interned into one string table
+/// (see `Toc::strings`), and each function gets a `FuncRecord` in the
+/// function table, which records where its part of the sections is.
+/// The file is built up in a `Vec` which grows as each section is appended,
+/// so the only limit on the size of the program is the size of the indexes.
+/// Returns an error (rather than silently truncating indexes) if the
+/// program's stores are too big for `P`.
+pub fn instr_stores_to_bytes<P: IndexPair>(
+    instr_stores: Vec<InstrStore>,
+) -> Result<Vec<u8>, String> {
+    let mut program = InstrStore::default();
+    let mut func_names: Vec<Vec<u8>> = vec![];
+    // (the pcs & labels of each function are filled in below, once its
+    // labels have been moved out of `program.instrs`)
+    let mut func_table: Vec<FuncRecord<P>> = vec![];
+    let mut instr_starts: Vec<usize> = vec![];
+    for (func_idx, mut instr_store) in instr_stores.into_iter().enumerate() {
+        let name = trim_func_name(&instr_store.func_name);
+        if name.is_empty() {
+            return Err(format!("function #{func_idx} has no name"));
+        }
+        if instr_store.dest_slots.len() != instr_store.instrs.len() {
+            return Err(format!(
+                "function `@{}` has {} dest slots but {} instrs",
+                String::from_utf8_lossy(name),
+                instr_store.dest_slots.len(),
+                instr_store.instrs.len()
+            ));
+        }
+        func_names.push(name.to_vec());
+
+        let var_offset = program.var_store.len();
+        let first_arg = program.func_args.len();
+        program
+            .func_args
+            .extend(instr_store.func_args.drain(..).map(|func_arg| {
+                let (offset, len) = func_arg.arg_name_idxes;
+                FuncArg {
+                    arg_name_idxes: (offset + var_offset, len),
+                    ..func_arg
+                }
+            }));
+        func_table.push(FuncRecord {
+            // (filled in below, once the names have been interned)
+            name: P::from_idxes(None),
+            start_pc: 0,
+            end_pc: 0,
+            first_arg,
+            num_args: program.func_args.len() - first_arg,
+            ret_ty: instr_store.func_ret_ty.into(),
+            num_slots: instr_store.num_slots,
+            first_label: 0,
+            num_labels: 0,
+        });
+        instr_starts.push(program.instrs.len());
+        program.append(instr_store);
+    }
+
+    let strings = intern_strings(&mut program, &func_names);
+    for (record, name) in func_table.iter_mut().zip(&func_names) {
+        record.name = P::from_idxes(Some(strings.idxes[name.as_slice()]));
+    }
+    let max_store_len = [
+        strings.bytes.len(),
+        program.args_idxes_store.len(),
+        program.labels_idxes_store.len(),
+        program.ext_store.len(),
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or(0);
+    // (both fields of an `(offset, len)` pair can be as big as the length
+    // of the store it refers to)
+    if max_store_len > P::MAX_IDX {
+        return Err(format!(
+            "the program has a store with {max_store_len} entries, \
+            which is too many for {}-bit indexes",
+            8 * size_of::<P>() / 2
+        ));
+    }
+
+    // Labels are moved out of `instrs` (& `dest_slots`) into the label table,
+    // where each one is mapped to the pc of the instruction it precedes
+    // (relative to the start of its function)
+    let mut flat_instrs: Vec<FlatInstr<P>> = vec![];
+    let mut label_table: Vec<FlatLabel<P>> = vec![];
+    let mut dest_slots: Vec<u32> = vec![];
+    let instr_ends = instr_starts.iter().skip(1).copied();
+    let instr_ranges = instr_starts
+        .iter()
+        .copied()
+        .zip(instr_ends.chain([program.instrs.len()]));
+    for (record, (start, end)) in func_table.iter_mut().zip(instr_ranges) {
+        let start_pc = flat_instrs.len();
+        let first_label = label_table.len();
+        for (instr, &slot) in program.instrs[start..end]
+            .iter()
+            .zip(&program.dest_slots[start..end])
+        {
+            if instr.op == u32::MAX {
+                label_table.push(FlatLabel {
+                    label_idxes: P::from_idxes(instr.label),
+                    pc: flat_instrs.len() - start_pc,
+                });
+            } else {
+                flat_instrs.push(instr.clone().into());
+                dest_slots.push(slot);
+            }
+        }
+        record.start_pc = start_pc;
+        record.end_pc = flat_instrs.len();
+        record.first_label = first_label;
+        record.num_labels = label_table.len() - first_label;
+    }
+
+    let strings = pad_vec(strings.bytes);
+    let func_args: Vec<FlatFuncArg<P>> = program
+        .func_args
+        .into_iter()
+        .map(FlatFuncArg::from)
+        .collect();
+    let arg_idxes_store: Vec<P> = program
+        .args_idxes_store
+        .into_iter()
+        .map(|arg_idxes| P::from_idxes(Some(arg_idxes)))
+        .collect();
+    let labels_idxes_store: Vec<P> = program
+        .labels_idxes_store
+        .into_iter()
+        .map(|lbl_idx| P::from_idxes(Some(lbl_idx)))
+        .collect();
+    let ext_store = pad_vec(program.ext_store);
+    let toc = Toc {
+        strings: strings.len(),
+        func_args: func_args.len(),
+        arg_idxes_store: arg_idxes_store.len(),
+        labels_idxes_store: labels_idxes_store.len(),
+        instrs: flat_instrs.len(),
+        label_table: label_table.len(),
+        dest_slots: dest_slots.len(),
+        arg_slots: program.arg_slots.len(),
+        ext_store: ext_store.len(),
+    };
+
+    let header = Header::new(P::FORMAT_FLAGS, func_table.len() as u64);
+    let mut bytes_vec = header.as_bytes().to_vec();
+    bytes_vec.extend_from_slice(toc.as_bytes());
+    push_section(&mut bytes_vec, func_table.as_bytes());
+    push_section(&mut bytes_vec, &strings);
+    push_section(&mut bytes_vec, func_args.as_bytes());
+    push_section(&mut bytes_vec, arg_idxes_store.as_bytes());
+    push_section(&mut bytes_vec, labels_idxes_store.as_bytes());
+    push_section(&mut bytes_vec, flat_instrs.as_bytes());
+    push_section(&mut bytes_vec, label_table.as_bytes());
+    push_section(&mut bytes_vec, dest_slots.as_bytes());
+    push_section(&mut bytes_vec, program.arg_slots.as_bytes());
+    push_section(&mut bytes_vec, &ext_store);
+    Ok(bytes_vec)
+}
+
+/// The string table of a program (see `Toc::strings`), along with the index
+/// pair of each string in it
+#[derive(Debug, Default)]
+struct StringTable {
+    bytes: Vec<u8>,
+    idxes: HashMap<Vec<u8>, (usize, usize)>,
+}
+
+impl StringTable {
+    /// The index pair of `string` in the table, which is appended to the
+    /// table if it isn't there yet
+    fn intern(&mut self, string: &[u8]) -> (usize, usize) {
+        if let Some(&idxes) = self.idxes.get(string) {
+            return idxes;
+        }
+        let idxes = (self.bytes.len(), string.len());
+        self.bytes.extend_from_slice(string);
+        self.idxes.insert(string.to_vec(), idxes);
+        idxes
+    }
+}
+
+/// Interns the function names (`func_names`), variables, labels & callees
+/// of `program` (the appended stores of every function, see
+/// `InstrStore::append`) into a string table, in the order they first appear,
+/// and rewrites every index pair into `var_store`, `labels_store` &
+/// `funcs_store` to refer to the table instead
+fn intern_strings(
+    program: &mut InstrStore,
+    func_names: &[Vec<u8>],
+) -> StringTable {
+    let mut strings = StringTable::default();
+    for name in func_names {
+        strings.intern(name);
+    }
+    let mut intern = |store: &[u8], (offset, len): (usize, usize)| {
+        strings.intern(&store[offset..offset + len])
+    };
+    for func_arg in &mut program.func_args {
+        func_arg.arg_name_idxes =
+            intern(&program.var_store, func_arg.arg_name_idxes);
+    }
+    for instr in &mut program.instrs {
+        // (the `label` of an extension instruction indexes its JSON, which
+        // stays in `ext_store`)
+        if instr.op != EXT_OP {
+            instr.label = instr
+                .label
+                .map(|idxes| intern(&program.labels_store, idxes));
+        }
+        instr.dest = instr.dest.map(|idxes| intern(&program.var_store, idxes));
+        instr.funcs =
+            instr.funcs.map(|idxes| intern(&program.funcs_store, idxes));
+    }
+    for idxes in &mut program.args_idxes_store {
+        *idxes = intern(&program.var_store, *idxes);
+    }
+    for idxes in &mut program.labels_idxes_store {
+        *idxes = intern(&program.labels_store, *idxes);
+    }
+    strings
+}
+
+/// Appends `section` to `bytes`, followed by enough zeros to pad it to a
+/// multiple of 4 bytes (e.g. the `instrs` of a compact-format file, whose
+/// `FlatInstr`s are 46 bytes each), which `slice_prefix` skips over
+fn push_section(bytes: &mut Vec<u8>, section: &[u8]) {
+    bytes.extend_from_slice(section);
+    let padded_len = bytes.len().next_multiple_of(4);
+    bytes.resize(padded_len, 0);
+}
+
+/// Converts the flattened functions of a program to the contents of an
+/// `.fbril` file whose `Header::flags` are `flags` (i.e. using the index
+/// pairs of that format)
+pub fn encode_instr_stores(
+    instr_stores: Vec<InstrStore>,
+    flags: u64,
+) -> Result<Vec<u8>, String> {
+    let bytes = if flags & WIDE_FORMAT_FLAG != 0 {
+        instr_stores_to_bytes::<I64Pair>(instr_stores)
+    } else if flags & COMPACT_FORMAT_FLAG != 0 {
+        instr_stores_to_bytes::<I16Pair>(instr_stores)
+    } else {
+        instr_stores_to_bytes::<I32Pair>(instr_stores)
+    }?;
+    if flags & RESOLVED_FLAG != 0 {
+        add_resolved_section(bytes)
+    } else {
+        Ok(bytes)
+    }
+}
+
+/// The `ResolvedOperands` of every instruction of the program made up of
+/// `views` (in the order they appear in the file), whose `jmp` / `br` targets
+/// & callees are looked up by name, just as the interpreter would (operands
+/// which can't be resolved are left `UNRESOLVED`)
+fn resolve_operands<P: IndexPair>(
+    views: &[InstrView<P>],
+) -> Vec<ResolvedOperands> {
+    // (if several functions have the same name, the last one is called,
+    // as in `FuncTable`)
+    let func_idxes: HashMap<&str, usize> = views
+        .iter()
+        .enumerate()
+        .map(|(idx, view)| (interp::get_func_name(view), idx))
+        .collect();
+    let func_idxes = &func_idxes;
+    let to_u32 = |idx: Option<usize>| {
+        idx.and_then(|idx| u32::try_from(idx).ok())
+            .filter(|&idx| idx != UNRESOLVED)
+            .unwrap_or(UNRESOLVED)
+    };
+    views
+        .iter()
+        .flat_map(|view| {
+            view.instrs.iter().map(move |instr| {
+                let mut resolved = ResolvedOperands {
+                    targets: [UNRESOLVED; 2],
+                    callee: UNRESOLVED,
+                };
+                match Opcode::u32_to_opcode(instr.op()) {
+                    Some(Opcode::Jmp | Opcode::Br) => {
+                        let labels = instr.instr_labels.get().map_or(
+                            vec![],
+                            |(offset, len)| {
+                                interp::get_labels_vec(view, offset, len)
+                            },
+                        );
+                        for (target, label) in
+                            resolved.targets.iter_mut().zip(labels)
+                        {
+                            *target =
+                                to_u32(interp::get_pc_of_label(view, label));
+                        }
+                    }
+                    Some(Opcode::Call) => {
+                        let (offset, len) = instr.funcs.idxes();
+                        let name = interp::get_func(view, offset, len);
+                        resolved.callee = to_u32(func_idxes.get(name).copied());
+                    }
+                    _ => (),
+                }
+                resolved
+            })
+        })
+        .collect()
+}
+
+/// Adds a `resolved` section (see `ResolvedOperands`) to the contents of an
+/// `.fbril` file which doesn't have one yet, setting `RESOLVED_FLAG` in its
+/// header (the file is returned as is if it already has one)
+pub fn add_resolved_section(mut bytes: Vec<u8>) -> Result<Vec<u8>, String> {
+    let data = AlignedBytes::new(&bytes);
+    let (header, _) = read_header(&data)?;
+    if header.flags & RESOLVED_FLAG != 0 {
+        return Ok(bytes);
+    }
+    let flags = header.flags | RESOLVED_FLAG;
+    let resolved = match load_program(&data)? {
+        ProgramViews::Narrow(program) => resolve_operands(program.views()),
+        ProgramViews::Wide(program) => resolve_operands(program.views()),
+        ProgramViews::Compact(program) => resolve_operands(program.views()),
+    };
+    // (the last section is padded to a multiple of 4 bytes, so the new
+    // section is suitably aligned)
+    push_section(&mut bytes, resolved.as_bytes());
+    bytes[..size_of::<u64>()].copy_from_slice(&flags.to_ne_bytes());
+    Ok(bytes)
+}
+
+/// The `Header::flags` to write the program made up of `instr_stores` with,
+/// given the format that was asked for (`WIDE_FORMAT_FLAG`,
+/// `COMPACT_FORMAT_FLAG` or 0 for the default format): the wide format is
+/// used if the program is too big for the default format, and the default
+/// format is used instead of the compact format if the program is too big
+/// for it (`RESOLVED_FLAG` is kept if it was asked for)
+pub fn choose_format_flags(instr_stores: &[InstrStore], requested: u64) -> u64 {
+    let format = if requested & WIDE_FORMAT_FLAG != 0
+        || needs_wide_format(instr_stores)
+    {
+        WIDE_FORMAT_FLAG
+    } else if requested & COMPACT_FORMAT_FLAG != 0
+        && max_total_store_len(instr_stores) <= I16Pair::MAX_IDX
+    {
+        COMPACT_FORMAT_FLAG
+    } else {
+        0
+    };
+    format | requested & RESOLVED_FLAG
+}
+
+/// Determines if the stores of the program made up of `instr_stores` (which
+/// are shared by all of its functions) are too big for `I32Pair`s,
+/// i.e. if it can only be stored in a wide-format file
+pub fn needs_wide_format(instr_stores: &[InstrStore]) -> bool {
+    max_total_store_len(instr_stores) > I32Pair::MAX_IDX
+}
+
+/// The length of the longest store of the program made up of `instr_stores`
+/// (once the stores of all its functions have been appended to one another,
+/// & their strings interned into the string table)
+fn max_total_store_len(instr_stores: &[InstrStore]) -> usize {
+    let total = |len: fn(&InstrStore) -> usize| -> usize {
+        instr_stores.iter().map(len).sum()
+    };
+    [
+        string_table_len(instr_stores),
+        total(|store| store.args_idxes_store.len()),
+        total(|store| store.labels_idxes_store.len()),
+        total(|store| store.ext_store.len()),
+    ]
+    .into_iter()
+    .max()
+    .unwrap_or(0)
+}
+
+/// The length of the string table of the program made up of `instr_stores`
+/// (see `intern_strings`), i.e. the total length of its distinct names
+fn string_table_len(instr_stores: &[InstrStore]) -> usize {
+    let mut strings: HashSet<&[u8]> = HashSet::new();
+    for store in instr_stores {
+        strings.insert(trim_func_name(&store.func_name));
+        let mut insert = |bytes: fn(&InstrStore) -> &[u8], (offset, len)| {
+            strings.insert(&bytes(store)[offset..offset + len]);
+        };
+        for func_arg in &store.func_args {
+            insert(|store| &store.var_store, func_arg.arg_name_idxes);
+        }
+        for instr in &store.instrs {
+            if instr.op != EXT_OP
+                && let Some(idxes) = instr.label
+            {
+                insert(|store| &store.labels_store, idxes);
+            }
+            if let Some(idxes) = instr.dest {
+                insert(|store| &store.var_store, idxes);
+            }
+            if let Some(idxes) = instr.funcs {
+                insert(|store| &store.funcs_store, idxes);
+            }
+        }
+        for &idxes in &store.args_idxes_store {
+            insert(|store| &store.var_store, idxes);
+        }
+        for &idxes in &store.labels_idxes_store {
+            insert(|store| &store.labels_store, idxes);
+        }
+    }
+    strings.into_iter().map(<[u8]>::len).sum()
+}
+
 /* -------------------------------------------------------------------------- */
 /*                             Reading from buffer                            */
 /* -------------------------------------------------------------------------- */
 
-/// Consume `size.len` items from a byte slice,
-/// skip the remainder of `size.capacity`
-/// elements, and return the items and the rest of the slice.
-fn slice_prefix<T: TryFromBytes + Immutable>(
-    data: &[u8],
-    size: usize,
-) -> (&[T], &[u8]) {
-    <[T]>::try_ref_from_prefix_with_elems(data, size)
-        .expect("Deserialization error in slice_prefix")
+/// Consumes `count` items of type `T` from a byte slice, returning the items
+/// and the rest of the slice (`section` is the name of the section being read,
+/// which is used in error messages)
+/// - Sections are padded to a multiple of 4 bytes (see `push_section`),
+///   so the padding after the items is skipped
+/// - Returns an error if `data` is too short to contain `count` items,
+///   or if the bytes don't form valid `T`s
+pub fn slice_prefix<'a, T: TryFromBytes + Immutable>(
+    data: &'a [u8],
+    count: usize,
+    section: &str,
+) -> Result<(&'a [T], &'a [u8]), String> {
+    let num_bytes = count.checked_mul(size_of::<T>());
+    let padding = match num_bytes {
+        Some(num_bytes) if num_bytes <= data.len() => {
+            (num_bytes.next_multiple_of(4) - num_bytes)
+                .min(data.len() - num_bytes)
+        }
+        _ => {
+            return Err(format!(
+                "{section} section claims {count} entries but only {} bytes remain",
+                data.len()
+            ));
+        }
+    };
+    <[T]>::try_ref_from_prefix_with_elems(data, count)
+        .map(|(items, rest)| (items, &rest[padding..]))
+        .map_err(|e| match e {
+            ConvertError::Alignment(_) => {
+                format!("{section} section is misaligned")
+            }
+            ConvertError::Size(_) => {
+                format!("{section} section is truncated")
+            }
+            ConvertError::Validity(_) => {
+                format!("{section} section contains invalid data")
+            }
+        })
 }
 
 /// Reads the table of contents from a prefix of the byte buffer
-fn read_toc(data: &[u8]) -> (&Toc, &[u8]) {
-    let (toc, remaining_buffer) =
-        Toc::ref_from_prefix(data).expect("error deserializing ToC");
-    (toc, remaining_buffer)
+pub fn read_toc(data: &[u8]) -> Result<(&Toc, &[u8]), String> {
+    Toc::ref_from_prefix(data).map_err(|_| {
+        format!(
+            "table of contents needs {} bytes but only {} bytes remain",
+            size_of::<Toc>(),
+            data.len()
+        )
+    })
 }
 
-/// Get an `InstrView` backed by the data in a byte buffer
-pub fn get_instr_view(data: &[u8]) -> InstrView {
-    let (toc, buffer) = read_toc(data);
+/// Checks that `pair` is a valid `(offset, len)` range of indexes into
+/// a store of length `len` (or `(-1, -1)`, i.e. `None`, if `optional` is true)
+fn check_pair<P: IndexPair>(
+    pair: P,
+    len: usize,
+    optional: bool,
+    describe: impl Fn() -> String,
+) -> Result<(), String> {
+    let (first, second) = pair.fields();
+    if optional && first == -1 && second == -1 {
+        return Ok(());
+    }
+    if first < 0
+        || second < 0
+        || (first as usize)
+            .checked_add(second as usize)
+            .is_none_or(|end| end > len)
+    {
+        return Err(format!(
+            "{} has index pair ({first}, {second}), \
+            which is out of bounds for a store of length {len}",
+            describe()
+        ));
+    }
+    Ok(())
+}
 
-    let (func_name, new_buffer) = slice_prefix::<u8>(buffer, toc.func_name);
-    let (func_args, new_buffer) =
-        slice_prefix::<FlatFuncArg>(new_buffer, toc.func_args);
+/// Like `check_pair`, but for a pair of indexes into a string store:
+/// the range must also be non-empty (only lists of args & labels can be
+/// empty, since every name is a non-empty string), and must start & end on
+/// UTF-8 character boundaries, so that the string it refers to is valid UTF-8
+fn check_str_pair<P: IndexPair>(
+    pair: P,
+    store: &str,
+    optional: bool,
+    describe: impl Fn() -> String,
+) -> Result<(), String> {
+    check_pair(pair, store.len(), optional, &describe)?;
+    let (first, second) = pair.fields();
+    if first == -1 {
+        return Ok(());
+    }
+    if second == 0 {
+        return Err(format!(
+            "{} has index pair ({first}, 0), which refers to an empty string",
+            describe()
+        ));
+    }
+    if !store.is_char_boundary(first as usize)
+        || !store.is_char_boundary((first + second) as usize)
+    {
+        return Err(format!(
+            "{} has index pair ({first}, {second}), \
+            which splits a UTF-8 character",
+            describe()
+        ));
+    }
+    Ok(())
+}
 
-    let (func_ret_ty, new_buffer) =
-        <FlatType>::try_read_from_prefix(new_buffer)
-            .expect("error deserializing func_ret_ty");
+/// Checks that a string section of the file is valid UTF-8
+fn check_utf8<'a>(bytes: &'a [u8], section: &str) -> Result<&'a str, String> {
+    str::from_utf8(bytes)
+        .map_err(|e| format!("{section} section is not valid UTF-8: {e}"))
+}
+
+/// The string stores of an `InstrView`, which `validate_stores` has checked
+/// are valid UTF-8
+#[derive(Debug, Clone, Copy)]
+struct StrStores<'a> {
+    var_store: &'a str,
+    labels_store: &'a str,
+    funcs_store: &'a str,
+    ext_store: &'a str,
+}
+
+/// Checks that every string store of `instr_view` is valid UTF-8
+fn check_str_stores<'a, P: IndexPair>(
+    instr_view: &InstrView<'a, P>,
+) -> Result<StrStores<'a>, String> {
+    check_utf8(instr_view.func_name, "func_name")?;
+    Ok(StrStores {
+        var_store: check_utf8(instr_view.var_store, "var_store")?,
+        labels_store: check_utf8(instr_view.labels_store, "labels_store")?,
+        funcs_store: check_utf8(instr_view.funcs_store, "funcs_store")?,
+        ext_store: check_utf8(instr_view.ext_store, "ext_store")?,
+    })
+}
 
-    let (var_store, new_buffer) = slice_prefix::<u8>(new_buffer, toc.var_store);
+/// Checks the parts of `instr_view` which may be shared with other functions
+/// (see `FuncRecord`): that the index pairs in `func_args`,
+/// `arg_idxes_store` & `labels_idxes_store` are in bounds for the store they
+/// refer to, and that there's a slot for every instruction & arg
+/// (`strs` are its string stores, checked by `check_str_stores`)
+fn validate_stores<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    strs: StrStores,
+) -> Result<(), String> {
+    for (i, func_arg) in instr_view.func_args.iter().enumerate() {
+        check_str_pair(func_arg.arg_name_idxes, strs.var_store, false, || {
+            format!("func_args[{i}]")
+        })?;
+        if !{ func_arg.arg_type }.is_valid() {
+            return Err(format!("func_args[{i}] has an invalid type"));
+        }
+    }
+    for (i, pair) in instr_view.arg_idxes_store.iter().enumerate() {
+        check_str_pair(*pair, strs.var_store, false, || {
+            format!("arg_idxes_store[{i}]")
+        })?;
+    }
+    for (i, pair) in instr_view.labels_idxes_store.iter().enumerate() {
+        check_str_pair(*pair, strs.labels_store, false, || {
+            format!("labels_idxes_store[{i}]")
+        })?;
+    }
+    if instr_view.dest_slots.len() != instr_view.instrs.len()
+        || instr_view.arg_slots.len() != instr_view.arg_idxes_store.len()
+    {
+        return Err(
+            "slot sections don't match the instrs / arg_idxes_store sections"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Checks the parts of `instr_view` which belong to the function alone:
+/// that every index pair in its instructions & label table is in bounds,
+/// that every opcode & type is valid, and that every slot is one of the
+/// function's slots (`strs` are the stores checked by `check_str_stores`).
+/// Labels must be in the label table, sorted by pc, unless `inline_labels`
+/// is true, in which case they're pseudo-instructions in `instrs` instead.
+fn validate_func<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    strs: StrStores,
+    inline_labels: bool,
+) -> Result<(), String> {
+    if !{ instr_view.func_ret_ty }.is_valid() {
+        return Err("func_ret_ty section contains an invalid type".to_string());
+    }
+    for (pc, instr) in instr_view.instrs.iter().enumerate() {
+        let op = instr.op();
+        if op == u32::MAX && !inline_labels {
+            return Err(format!(
+                "instr {pc} is a label, but labels belong in the label table"
+            ));
+        }
+        if op != u32::MAX && op != EXT_OP && Opcode::u32_to_opcode(op).is_none()
+        {
+            return Err(format!("instr {pc} has invalid opcode {op}"));
+        }
+        if !{ instr.ty }.is_valid() {
+            return Err(format!("instr {pc} has an invalid type"));
+        }
+        let describe = |field: &str| format!("instr {pc}'s `{field}`");
+        // (the `label` of an extension instruction is its JSON)
+        let label_store = if op == EXT_OP {
+            strs.ext_store
+        } else {
+            strs.labels_store
+        };
+        check_str_pair(instr.label, label_store, true, || describe("label"))?;
+        check_str_pair(instr.dest, strs.var_store, true, || describe("dest"))?;
+        check_str_pair(instr.funcs, strs.funcs_store, true, || {
+            describe("funcs")
+        })?;
+        check_pair(instr.args, instr_view.arg_idxes_store.len(), true, || {
+            describe("args")
+        })?;
+        check_pair(
+            instr.instr_labels,
+            instr_view.labels_idxes_store.len(),
+            true,
+            || describe("labels"),
+        )?;
+    }
 
+    let num_instrs = instr_view.instrs.len();
+    let mut prev_pc = 0;
+    for (i, label) in instr_view.label_table.iter().enumerate() {
+        check_str_pair(label.label_idxes, strs.labels_store, false, || {
+            format!("label_table[{i}]")
+        })?;
+        let pc = label.pc;
+        if pc > num_instrs {
+            return Err(format!(
+                "label_table[{i}] is at pc {pc}, \
+                but the function only has {num_instrs} instrs"
+            ));
+        }
+        if pc < prev_pc {
+            return Err(format!(
+                "label_table[{i}] is at pc {pc}, \
+                which is before the previous label (at pc {prev_pc})"
+            ));
+        }
+        prev_pc = pc;
+    }
+
+    // (every resolved target must be the pc of one of the function's labels)
+    if !instr_view.resolved.is_empty() {
+        if instr_view.resolved.len() != num_instrs {
+            return Err(
+                "resolved section doesn't match the instrs section".to_string()
+            );
+        }
+        for (pc, resolved) in instr_view.resolved.iter().enumerate() {
+            for target in resolved.targets {
+                let is_label = instr_view
+                    .label_table
+                    .binary_search_by_key(&(target as usize), |label| label.pc)
+                    .is_ok();
+                if target != UNRESOLVED && !is_label {
+                    return Err(format!(
+                        "instr {pc}'s resolved target is pc {target}, \
+                        which has no label"
+                    ));
+                }
+            }
+        }
+    }
+
+    let num_slots = instr_view.num_slots;
+    if num_slots < instr_view.func_args.len() {
+        return Err(format!(
+            "function has {} parameters but only {num_slots} slots",
+            instr_view.func_args.len()
+        ));
+    }
+    // (every variable is a parameter, a dest or an arg, so a function can't
+    // need more slots than that, & a corrupt `num_slots` mustn't make the
+    // interpreter allocate an environment bigger than the file)
+    let max_slots = instr_view.func_args.len()
+        + instr_view.instrs.len()
+        + instr_view.arg_slots.len();
+    if num_slots > max_slots {
+        return Err(format!(
+            "function has {num_slots} slots, but only {} parameters, \
+            {} instrs & {} args",
+            instr_view.func_args.len(),
+            instr_view.instrs.len(),
+            instr_view.arg_slots.len()
+        ));
+    }
+    let check_slot = |slot: u32, describe: &dyn Fn() -> String| {
+        if slot as usize >= num_slots {
+            return Err(format!(
+                "{} is slot {slot}, but the function only has {num_slots} slots",
+                describe()
+            ));
+        }
+        Ok(())
+    };
+    // (only the args of this function's instructions are checked, since
+    // `arg_slots` may be shared with other functions)
+    for (pc, instr) in instr_view.instrs.iter().enumerate() {
+        let dest = instr.dest;
+        if dest.get().is_some() {
+            check_slot(instr_view.dest_slots[pc], &|| {
+                format!("instr {pc}'s dest")
+            })?;
+        }
+        if let Some((offset, len)) = { instr.args }.get() {
+            for i in offset..offset + len {
+                check_slot(instr_view.arg_slots[i], &|| {
+                    format!("arg_slots[{i}]")
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every index pair in `instr_view` is in bounds for the store
+/// it refers to, that every opcode is valid, and that every string is valid
+/// UTF-8, so that the interpreter never slices out of bounds when reading
+/// a corrupt file (or panics when converting one of its strings to a
+/// `&str`, see `interp::get_var`).
+/// - When a file is loaded, these checks are split between `validate_stores`,
+///   which checks the program's shared stores once (its string table is
+///   only checked for UTF-8 once, although it's every string store), &
+///   `validate_func`, which checks each function when its `InstrView` is
+///   built
+/// - Labels must be in the label table, sorted by pc, unless `inline_labels`
+///   is true (see `validate_func`)
+pub fn validate_instr_view<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    inline_labels: bool,
+) -> Result<(), String> {
+    let strs = check_str_stores(instr_view)?;
+    validate_stores(instr_view, strs)?;
+    validate_func(instr_view, strs, inline_labels)
+}
+
+/// The function table & (shared) sections of a flat Bril file whose index
+/// pairs are of type `P`, from which the `InstrView` of each function
+/// is built on demand (see `ProgramData::func_view`)
+pub struct ProgramData<'a, P: IndexPair = I32Pair> {
+    func_table: &'a [FuncRecord<P>],
+    func_names: Vec<&'a str>,
+    /// An `InstrView` spanning the whole of every section
+    /// (whose `func_name` is the string table)
+    sections: InstrView<'a, P>,
+    strs: StrStores<'a>,
+}
+
+/// The range `first..first + len` of a section with `section_len` entries,
+/// which a function claims (returns an error if it's out of bounds)
+pub fn section_range(
+    first: usize,
+    len: usize,
+    section_len: usize,
+    section: &str,
+) -> Result<Range<usize>, String> {
+    match first.checked_add(len) {
+        Some(end) if end <= section_len => Ok(first..end),
+        _ => Err(format!(
+            "function claims {len} entries of the {section} section \
+            starting at {first}, but the section only has {section_len} entries"
+        )),
+    }
+}
+
+impl<'a, P: IndexPair> ProgramData<'a, P> {
+    /// The names of all the functions, in the order they appear in the file
+    pub fn func_names(&self) -> &[&'a str] {
+        &self.func_names
+    }
+
+    /// Builds (& validates) the `InstrView` of function `idx`, whose
+    /// `func_args`, `instrs`, `label_table` & `dest_slots` are its part of
+    /// the corresponding sections (see `FuncRecord`), and whose stores are
+    /// the program's stores
+    pub fn func_view(&self, idx: usize) -> Result<InstrView<'a, P>, String> {
+        let record = self.func_table[idx];
+        let sections = self.sections.clone();
+        let (start_pc, end_pc) = (record.start_pc, record.end_pc);
+        let num_instrs = end_pc.checked_sub(start_pc).ok_or_else(|| {
+            format!(
+                "function ends (at pc {end_pc}) before it starts \
+                (at pc {start_pc})"
+            )
+        })?;
+        let instrs = section_range(
+            start_pc,
+            num_instrs,
+            sections.instrs.len(),
+            "instrs",
+        )?;
+        let args = section_range(
+            record.first_arg,
+            record.num_args,
+            sections.func_args.len(),
+            "func_args",
+        )?;
+        let labels = section_range(
+            record.first_label,
+            record.num_labels,
+            sections.label_table.len(),
+            "label_table",
+        )?;
+        let instr_view = InstrView {
+            func_name: self.func_names[idx].as_bytes(),
+            func_args: &sections.func_args[args],
+            func_ret_ty: record.ret_ty,
+            instrs: &sections.instrs[instrs.clone()],
+            label_table: &sections.label_table[labels],
+            num_slots: record.num_slots,
+            dest_slots: &sections.dest_slots[instrs.clone()],
+            resolved: sections.resolved.get(instrs).unwrap_or_default(),
+            ..sections
+        };
+        validate_func(&instr_view, self.strs, false)?;
+        Ok(instr_view)
+    }
+
+    /// Builds the `InstrView` of every function, in the order they appear
+    /// in the file
+    pub fn views(&self) -> Result<Vec<InstrView<'a, P>>, String> {
+        (0..self.func_table.len())
+            .map(|idx| {
+                self.func_view(idx)
+                    .map_err(|e| format!("function #{idx}: {e}"))
+            })
+            .collect()
+    }
+}
+
+/// Splits the rest of a flat Bril file (`remaining_data`, which follows
+/// `header`) into its function table & sections, checking the function
+/// names & the shared stores (see `validate_stores`), but not the functions
+/// themselves
+fn parse_program<'a, P: IndexPair>(
+    header: &Header,
+    remaining_data: &'a [u8],
+) -> Result<ProgramData<'a, P>, String> {
+    let (toc, buffer) = read_toc(remaining_data)?;
+    let num_funcs = usize::try_from(header.num_funcs).unwrap_or(usize::MAX);
+    let (func_table, new_buffer) =
+        slice_prefix::<FuncRecord<P>>(buffer, num_funcs, "function table")?;
+
+    let (strings, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.strings, "strings")?;
+    let (func_args, new_buffer) =
+        slice_prefix::<FlatFuncArg<P>>(new_buffer, toc.func_args, "func_args")?;
     let (arg_idxes_store, new_buffer) =
-        slice_prefix::<I32Pair>(new_buffer, toc.arg_idxes_store);
-    let (labels_idxes_store, new_buffer) =
-        slice_prefix::<I32Pair>(new_buffer, toc.labels_idxes_store);
-    let (labels_store, new_buffer) =
-        slice_prefix::<u8>(new_buffer, toc.labels_store);
-    let (funcs_store, new_buffer) =
-        slice_prefix::<u8>(new_buffer, toc.funcs_store);
-    let (instrs, _) = slice_prefix::<FlatInstr>(new_buffer, toc.instrs);
-
-    InstrView {
-        func_name,
+        slice_prefix::<P>(new_buffer, toc.arg_idxes_store, "arg_idxes_store")?;
+    let (labels_idxes_store, new_buffer) = slice_prefix::<P>(
+        new_buffer,
+        toc.labels_idxes_store,
+        "labels_idxes_store",
+    )?;
+    let (instrs, new_buffer) =
+        slice_prefix::<FlatInstr<P>>(new_buffer, toc.instrs, "instrs")?;
+    let (label_table, new_buffer) = slice_prefix::<FlatLabel<P>>(
+        new_buffer,
+        toc.label_table,
+        "label_table",
+    )?;
+    let (dest_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.dest_slots, "dest_slots")?;
+    let (arg_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.arg_slots, "arg_slots")?;
+    let (ext_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.ext_store, "ext_store")?;
+    let resolved = if header.flags & RESOLVED_FLAG != 0 {
+        let (resolved, _) = slice_prefix::<ResolvedOperands>(
+            new_buffer, toc.instrs, "resolved",
+        )?;
+        resolved
+    } else {
+        &[]
+    };
+    if let Some(i) = resolved.iter().position(|resolved| {
+        resolved.callee != UNRESOLVED && resolved.callee as usize >= num_funcs
+    }) {
+        return Err(format!(
+            "resolved[{i}] calls function #{}, but there are only \
+            {num_funcs} functions",
+            resolved[i].callee
+        ));
+    }
+
+    // (every string store of a function is the string table, which is
+    // checked once here rather than once per store)
+    let strings_str = check_utf8(strings, "strings")?;
+    let strs = StrStores {
+        var_store: strings_str,
+        labels_store: strings_str,
+        funcs_store: strings_str,
+        ext_store: check_utf8(ext_store, "ext_store")?,
+    };
+    let sections = InstrView {
+        func_name: strings,
         func_args,
-        func_ret_ty,
-        var_store,
+        func_ret_ty: FlatType::NULL,
+        var_store: strings,
         arg_idxes_store,
         labels_idxes_store,
-        labels_store,
-        funcs_store,
+        labels_store: strings,
+        funcs_store: strings,
         instrs,
+        label_table,
+        num_slots: 0,
+        dest_slots,
+        arg_slots,
+        ext_store,
+        resolved,
+    };
+    validate_stores(&sections, strs)?;
+    let func_names = func_table
+        .iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            check_str_pair(record.name, strings_str, false, || {
+                format!("function #{idx}'s name")
+            })?;
+            Ok(&strings_str[{ record.name }.range()])
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(ProgramData {
+        func_table,
+        func_names,
+        sections,
+        strs,
+    })
+}
+
+/// The functions of a program loaded from a flat Bril file (whose index
+/// pairs are of type `P`), along with the file's `Header` & a table mapping
+/// each function's name to its `InstrView`. If several functions have the
+/// same name, the last one is the one that's looked up (see `FuncTable`).
+pub struct FlatProgram<'a, P: IndexPair = I32Pair> {
+    header: Header,
+    views: Vec<InstrView<'a, P>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl<'a, P: IndexPair> FlatProgram<'a, P> {
+    /// Wraps the `InstrView`s of a program's functions (e.g. after a pass
+    /// has rewritten them), whose header is the one they'd be written with
+    pub fn new(views: Vec<InstrView<'a, P>>) -> Self {
+        let header = Header::new(P::FORMAT_FLAGS, views.len() as u64);
+        Self::with_header(header, views)
+    }
+
+    fn with_header(header: Header, views: Vec<InstrView<'a, P>>) -> Self {
+        let by_name = views
+            .iter()
+            .enumerate()
+            .map(|(idx, view)| (interp::get_func_name(view).to_string(), idx))
+            .collect();
+        Self {
+            header,
+            views,
+            by_name,
+        }
+    }
+
+    /// The header of the file that the program was loaded from
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The `InstrView`s of the functions, in the order they appear in the file
+    pub fn views(&self) -> &[InstrView<'a, P>] {
+        &self.views
+    }
+
+    /// Looks up the function called `name` (without the leading `@`)
+    pub fn get(&self, name: &str) -> Option<&InstrView<'a, P>> {
+        self.by_name.get(name).map(|&idx| &self.views[idx])
+    }
+
+    /// The names of all the functions, in the order they appear in the file
+    pub fn func_names(&self) -> Vec<&str> {
+        self.views.iter().map(interp::get_func_name).collect()
+    }
+
+    /// Copies every function in the program into an `InstrStore`
+    pub fn instr_stores(&self) -> Vec<InstrStore> {
+        self.views.iter().cloned().map(InstrStore::from).collect()
+    }
+
+    /// Consumes the program, returning the `InstrView`s of its functions
+    pub fn into_views(self) -> Vec<InstrView<'a, P>> {
+        self.views
+    }
+}
+
+/// A program loaded from a flat Bril file: the file's `Header::flags`
+/// determine whether its index pairs are `I32Pair`s (the default),
+/// `I64Pair`s (the wide format) or `I16Pair`s (the compact format)
+pub enum ProgramViews<'a> {
+    Narrow(FlatProgram<'a>),
+    Wide(FlatProgram<'a, I64Pair>),
+    Compact(FlatProgram<'a, I16Pair>),
+}
+
+impl ProgramViews<'_> {
+    /// The header of the file that the program was loaded from
+    pub fn header(&self) -> &Header {
+        match self {
+            ProgramViews::Narrow(program) => program.header(),
+            ProgramViews::Wide(program) => program.header(),
+            ProgramViews::Compact(program) => program.header(),
+        }
+    }
+
+    /// The names of all the functions in the program
+    pub fn func_names(&self) -> Vec<&str> {
+        match self {
+            ProgramViews::Narrow(program) => program.func_names(),
+            ProgramViews::Wide(program) => program.func_names(),
+            ProgramViews::Compact(program) => program.func_names(),
+        }
+    }
+
+    /// Copies every function in the program into an `InstrStore`
+    pub fn instr_stores(&self) -> Vec<InstrStore> {
+        match self {
+            ProgramViews::Narrow(program) => program.instr_stores(),
+            ProgramViews::Wide(program) => program.instr_stores(),
+            ProgramViews::Compact(program) => program.instr_stores(),
+        }
+    }
+}
+
+/// Reads the `Header` of a flat Bril file, returning it along with the rest
+/// of the file (returns an error if the file is too short, if it's in an
+/// older layout, or if the header has flags we don't know about)
+pub fn read_header(data: &[u8]) -> Result<(&Header, &[u8]), String> {
+    let (header, remaining_data) =
+        Header::ref_from_prefix(data).map_err(|_| {
+            format!(
+                "header needs {} bytes but the file only has {} bytes",
+                size_of::<Header>(),
+                data.len()
+            )
+        })?;
+    let version = header.version();
+    if version < FORMAT_VERSION {
+        return Err(format!(
+            "file is in an older layout (version {version}, but the current \
+            version is {FORMAT_VERSION}), run `flat-bril migrate` to upgrade it"
+        ));
+    } else if version > FORMAT_VERSION {
+        return Err(format!(
+            "file is in layout version {version}, which is newer than the \
+            current version ({FORMAT_VERSION})"
+        ));
+    }
+    let format_flags = WIDE_FORMAT_FLAG | COMPACT_FORMAT_FLAG;
+    let known_flags = format_flags | RESOLVED_FLAG | version << VERSION_SHIFT;
+    if header.flags & !known_flags != 0 {
+        return Err(format!("header has unknown flags {:#x}", header.flags));
+    }
+    if header.flags & format_flags == format_flags {
+        return Err(
+            "header says the file is in both the wide & compact formats"
+                .to_string(),
+        );
+    }
+    Ok((header, remaining_data))
+}
+
+/// The contents of an `.fbril` file which didn't come from an mmap (e.g.
+/// bytes read from `stdin`, or handed to us by a library user), copied into a
+/// buffer of `u64`s so that they're suitably aligned for `load_program`
+pub struct AlignedBytes {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    /// Copies `bytes` into a suitably aligned buffer
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut words = vec![0u64; bytes.len().div_ceil(8)];
+        words.as_mut_bytes()[..bytes.len()].copy_from_slice(bytes);
+        Self {
+            words,
+            len: bytes.len(),
+        }
+    }
+
+    /// Reads everything from `reader` (e.g. `stdin`) into a suitably
+    /// aligned buffer
+    pub fn from_reader<R: std::io::Read>(
+        mut reader: R,
+    ) -> Result<Self, String> {
+        let mut bytes = vec![];
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| format!("unable to read fbril file: {e}"))?;
+        Ok(Self::new(&bytes))
+    }
+}
+
+impl std::ops::Deref for AlignedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.words.as_bytes()[..self.len]
+    }
+}
+
+impl std::ops::DerefMut for AlignedBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.words.as_mut_bytes()[..self.len]
+    }
+}
+
+/// Builds an `InstrView` for every function in a flat Bril file, in whichever
+/// format the file is in (`data` is the contents of the file,
+/// starting with the `Header`).
+/// Returns an error describing the problem if the file is truncated or corrupt.
+pub fn load_program(data: &[u8]) -> Result<ProgramViews<'_>, String> {
+    let (header, remaining_data) = read_header(data)?;
+    if header.flags & WIDE_FORMAT_FLAG != 0 {
+        parse_program(header, remaining_data)?.views().map(|views| {
+            ProgramViews::Wide(FlatProgram::with_header(*header, views))
+        })
+    } else if header.flags & COMPACT_FORMAT_FLAG != 0 {
+        parse_program(header, remaining_data)?.views().map(|views| {
+            ProgramViews::Compact(FlatProgram::with_header(*header, views))
+        })
+    } else {
+        parse_program(header, remaining_data)?.views().map(|views| {
+            ProgramViews::Narrow(FlatProgram::with_header(*header, views))
+        })
+    }
+}
+
+/// A program loaded lazily from a flat Bril file (see `load_program_lazily`),
+/// in either format (see `ProgramViews`)
+pub enum LazyProgram<'a> {
+    Narrow(FuncTable<'a>),
+    Wide(FuncTable<'a, I64Pair>),
+    Compact(FuncTable<'a, I16Pair>),
+}
+
+/// Like `load_program`, but only the `Header`, the function table & the
+/// shared stores are read (& checked) up front: a function's `InstrView` is
+/// built (& validated) the first time it's looked up in the resulting
+/// `FuncTable`, e.g. when it's first called. This makes startup much cheaper
+/// for large programs which only call a few of their functions.
+pub fn load_program_lazily(data: &[u8]) -> Result<LazyProgram<'_>, String> {
+    let (header, remaining_data) = read_header(data)?;
+    if header.flags & WIDE_FORMAT_FLAG != 0 {
+        parse_program(header, remaining_data)
+            .map(|program| LazyProgram::Wide(FuncTable::lazy(program)))
+    } else if header.flags & COMPACT_FORMAT_FLAG != 0 {
+        parse_program(header, remaining_data)
+            .map(|program| LazyProgram::Compact(FuncTable::lazy(program)))
+    } else {
+        parse_program(header, remaining_data)
+            .map(|program| LazyProgram::Narrow(FuncTable::lazy(program)))
+    }
+}
+
+/// Where one part of a flat Bril file (the `Header`, the `Toc`, the function
+/// table or a section) is stored (see `section_layouts`)
+/// - `offset` is the offset of its first byte from the start of the file
+/// - It holds `count` entries of `item_size` bytes each, which must be
+///   aligned to `align` bytes
+/// - `bytes` is its size, including the padding after it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SectionLayout {
+    pub name: &'static str,
+    pub offset: usize,
+    pub count: usize,
+    pub item_size: usize,
+    pub align: usize,
+    pub bytes: usize,
+}
+
+/// The layout of each part of a flat Bril file (the `Header`, the `Toc`, the
+/// function table & each section, in the order they appear in the file),
+/// as described by its `Toc` (`data` is the contents of the file).
+/// The sizes include the padding after each section, so they add up to the
+/// size of the file (unless the file is truncated or has trailing bytes).
+pub fn section_layouts(data: &[u8]) -> Result<Vec<SectionLayout>, String> {
+    let (header, remaining_data) = read_header(data)?;
+    let (toc, _) = read_toc(remaining_data)?;
+    let num_funcs = usize::try_from(header.num_funcs).unwrap_or(usize::MAX);
+    let resolved = header.flags & RESOLVED_FLAG != 0;
+    if header.flags & WIDE_FORMAT_FLAG != 0 {
+        section_layouts_of::<I64Pair>(num_funcs, toc, resolved)
+    } else if header.flags & COMPACT_FORMAT_FLAG != 0 {
+        section_layouts_of::<I16Pair>(num_funcs, toc, resolved)
+    } else {
+        section_layouts_of::<I32Pair>(num_funcs, toc, resolved)
+    }
+}
+
+/// The no. of bytes taken up by each part of a flat Bril file, in the order
+/// they appear in the file (see `section_layouts`)
+pub fn section_sizes(
+    data: &[u8],
+) -> Result<Vec<(&'static str, usize)>, String> {
+    Ok(section_layouts(data)?
+        .into_iter()
+        .map(|section| (section.name, section.bytes))
+        .collect())
+}
+
+/// The raw bytes of the part of a flat Bril file called `name` (one of the
+/// names in `section_layouts`, e.g. `strings` or `instrs`), without the
+/// padding after it (`data` is the contents of the file). Returns an error
+/// if there's no such section, or if the file is too short to contain it.
+pub fn extract_section<'a>(
+    data: &'a [u8],
+    name: &str,
+) -> Result<&'a [u8], String> {
+    let layouts = section_layouts(data)?;
+    let Some(section) = layouts.iter().find(|section| section.name == name)
+    else {
+        let names: Vec<&str> =
+            layouts.iter().map(|section| section.name).collect();
+        return Err(format!(
+            "no section named `{name}` (the sections are {})",
+            names.join(", ")
+        ));
+    };
+    let len = section.count.saturating_mul(section.item_size);
+    section
+        .offset
+        .checked_add(len)
+        .and_then(|end| data.get(section.offset..end))
+        .ok_or_else(|| {
+            format!(
+                "{name} section claims {len} bytes at offset {:#x}, but the \
+                file is only {} bytes",
+                section.offset,
+                data.len()
+            )
+        })
+}
+
+/// The layouts of the parts of a file with `num_funcs` functions whose index
+/// pairs are of type `P`, which has a `resolved` section if `resolved` is
+/// true (see `section_layouts`). Returns an error if a section is too big
+/// for its size (or offset) to be a `usize`.
+fn section_layouts_of<P: IndexPair>(
+    num_funcs: usize,
+    toc: &Toc,
+    resolved: bool,
+) -> Result<Vec<SectionLayout>, String> {
+    // (name, count, item_size, align)
+    fn part<T>(
+        name: &'static str,
+        count: usize,
+    ) -> (&'static str, usize, usize, usize) {
+        (name, count, size_of::<T>(), align_of::<T>())
+    }
+    let mut parts = vec![
+        part::<Header>("header", 1),
+        part::<Toc>("toc", 1),
+        part::<FuncRecord<P>>("func_table", num_funcs),
+        part::<u8>("strings", toc.strings),
+        part::<FlatFuncArg<P>>("func_args", toc.func_args),
+        part::<P>("arg_idxes_store", toc.arg_idxes_store),
+        part::<P>("labels_idxes_store", toc.labels_idxes_store),
+        part::<FlatInstr<P>>("instrs", toc.instrs),
+        part::<FlatLabel<P>>("label_table", toc.label_table),
+        part::<u32>("dest_slots", toc.dest_slots),
+        part::<u32>("arg_slots", toc.arg_slots),
+        part::<u8>("ext_store", toc.ext_store),
+    ];
+    if resolved {
+        parts.push(part::<ResolvedOperands>("resolved", toc.instrs));
     }
+    let mut offset = 0;
+    parts
+        .into_iter()
+        .map(|(name, count, item_size, align)| {
+            let too_big = || {
+                format!(
+                    "{name} section claims {count} items of {item_size} \
+                    bytes, which is too big to be valid"
+                )
+            };
+            let bytes = count
+                .checked_mul(item_size)
+                .and_then(|len| len.checked_next_multiple_of(4))
+                .ok_or_else(too_big)?;
+            let section = SectionLayout {
+                name,
+                offset,
+                count,
+                item_size,
+                align,
+                bytes,
+            };
+            offset = offset.checked_add(bytes).ok_or_else(too_big)?;
+            Ok(section)
+        })
+        .collect()
+}
+
+/// Builds an `InstrView` for every function in a flat Bril file
+/// (`data` is the contents of the file, starting with the `Header`).
+/// Returns an error describing the problem if the file is truncated or corrupt,
+/// or if it's in the wide or compact format (use `load_program` to load files
+/// in any format).
+pub fn get_program_views(data: &[u8]) -> Result<Vec<InstrView<'_>>, String> {
+    get_flat_program(data).map(FlatProgram::into_views)
+}
+
+/// Like `get_program_views`, but returns the whole `FlatProgram`
+/// (e.g. to pass to `interp::interp_program`)
+pub fn get_flat_program(data: &[u8]) -> Result<FlatProgram<'_>, String> {
+    match load_program(data)? {
+        ProgramViews::Narrow(program) => Ok(program),
+        ProgramViews::Wide(_) => {
+            Err("file is in the wide format, which isn't supported here"
+                .to_string())
+        }
+        ProgramViews::Compact(_) => {
+            Err("file is in the compact format, which isn't supported here"
+                .to_string())
+        }
+    }
+}
+
+/// Access pattern hint passed to `madvise` for a mmap-ed flat Bril file
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MmapAdvice {
+    /// `MADV_SEQUENTIAL`: pages will be read in order (aggressive read-ahead)
+    Sequential,
+    /// `MADV_WILLNEED`: pages will be needed soon (start reading them now)
+    WillNeed,
+}
+
+/// Options controlling how an existing flat Bril file is memory-mapped.
+/// On large files, page faults dominate the time taken to load the program,
+/// so these let us trade startup latency for up-front work.
+/// - `populate` pre-faults the whole mapping (`MAP_POPULATE`)
+/// - `advice` is passed to `madvise` after the file is mapped
+/// - `hugepages` (experimental) asks for transparent hugepages
+///   (`MADV_HUGEPAGE`), which only affects file-backed mappings if the kernel
+///   supports THP for read-only file mappings
+#[cfg(feature = "mmap")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MmapTuning {
+    pub populate: bool,
+    pub advice: Option<MmapAdvice>,
+    pub hugepages: bool,
+}
+
+/// Memory-maps an existing flat Bril file (read-only)
+#[cfg(feature = "mmap")]
+pub fn mmap_existing_file(filename: &str) -> Result<Mmap, String> {
+    mmap_existing_file_tuned(filename, MmapTuning::default())
+}
+
+/// Memory-maps an existing flat Bril file for reading & writing, so that it
+/// can be patched in place (see `patch::PatchView`): changes are written back
+/// to the file when the map is flushed or dropped
+#[cfg(feature = "mmap")]
+pub fn mmap_existing_file_mut(filename: &str) -> Result<MmapMut, String> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(filename)
+        .map_err(|e| format!("unable to open `{filename}`: {e}"))?;
+    unsafe { MmapOptions::new().map_mut(&file) }
+        .map_err(|e| format!("unable to mmap `{filename}`: {e}"))
+}
+
+/// Memory-maps an existing flat Bril file (read-only),
+/// applying the options in `tuning`
+#[cfg(feature = "mmap")]
+pub fn mmap_existing_file_tuned(
+    filename: &str,
+    tuning: MmapTuning,
+) -> Result<Mmap, String> {
+    let file = std::fs::File::open(filename)
+        .map_err(|e| format!("unable to open `{filename}`: {e}"))?;
+    let mut options = MmapOptions::new();
+    if tuning.populate {
+        options.populate();
+    }
+    let mmap = unsafe { options.map(&file) }
+        .map_err(|e| format!("unable to mmap `{filename}`: {e}"))?;
+
+    let advise_err = |e| format!("unable to madvise `{filename}`: {e}");
+    match tuning.advice {
+        Some(MmapAdvice::Sequential) => {
+            mmap.advise(Advice::Sequential).map_err(advise_err)?
+        }
+        Some(MmapAdvice::WillNeed) => {
+            mmap.advise(Advice::WillNeed).map_err(advise_err)?
+        }
+        None => (),
+    }
+    if tuning.hugepages {
+        #[cfg(target_os = "linux")]
+        mmap.advise(Advice::HugePage).map_err(advise_err)?;
+        #[cfg(not(target_os = "linux"))]
+        return Err("transparent hugepages are only supported on Linux".into());
+    }
+    Ok(mmap)
 }
 
 /* -------------------------------------------------------------------------- */
 /*                                Actual logic                                */
 /* -------------------------------------------------------------------------- */
 
-/// Writes a JSON Bril program to a mmap-ed flat Bril file
-pub fn json_to_fbril(output_file: String) {
-    // Read in the JSON representation of a Bril file from stdin
-    let mut buffer = String::new();
-    std::io::stdin()
-        .read_to_string(&mut buffer)
-        .expect("Unable to read from stdin");
-
-    // Parse the JSON into serde_json's `Value` datatype
-    let json: serde_json::Value =
-        serde_json::from_str(&buffer).expect("Unable to parse malformed JSON");
+/// Produces the contents of an `.fbril` file (the `Header`, the `Toc` &
+/// function table, and the sections shared by all the functions)
+/// for a JSON Bril program
+/// (the wide format is used if the program is too big for the default one).
+/// Panics if a function is malformed (see `flatten::try_flatten_instrs`)
+/// or refers to a label it doesn't define.
+pub fn json_to_fbril_bytes(json: &serde_json::Value) -> Vec<u8> {
     let functions = json["functions"]
         .as_array()
         .expect("Expected `functions` to be a JSON array");
+    let instr_stores: Vec<InstrStore> = functions
+        .iter()
+        .enumerate()
+        .map(|(func_idx, func)| {
+            let instr_store = flatten::try_flatten_instrs(func)
+                .unwrap_or_else(|e| panic!("{}", e.in_program(func_idx)));
+            if let Err(e) = flatten::validate_instr_store(&instr_store) {
+                panic!("{e}");
+            }
+            instr_store
+        })
+        .collect();
+    instr_stores_to_fbril_bytes(instr_stores)
+}
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(100000);
+/// Produces the contents of an `.fbril` file containing the flattened
+/// functions in `instr_stores` (see `json_to_fbril_bytes`)
+pub fn instr_stores_to_fbril_bytes(instr_stores: Vec<InstrStore>) -> Vec<u8> {
+    let flags = choose_format_flags(&instr_stores, 0);
+    encode_instr_stores(instr_stores, flags)
+        .expect("index pairs should fit in the chosen format")
+}
 
-    // we only allow 10 functions right now
-    let mut sizes_arr: [u64; 10] = [0; 10];
+/// Flattens a JSON Bril program (given as a string) to the contents of an
+/// `.fbril` file, entirely in memory (see `json_to_fbril_bytes`).
+/// Unlike `json_to_fbril_bytes`, malformed programs are reported as errors
+/// rather than panics, so this is the entry point for library users.
+pub fn flatten_to_bytes(json: &str) -> Result<Vec<u8>, String> {
+    let json: serde_json::Value = serde_json::from_str(json)
+        .map_err(|e| format!("unable to parse JSON: {e}"))?;
+    try_json_to_fbril_bytes(&json)
+}
 
-    for (sizes_idx, func) in functions.iter().enumerate() {
-        let instr_store: InstrStore = flatten::flatten_instrs(func);
+/// Like `json_to_fbril_bytes`, but malformed programs are reported as errors
+/// rather than panics (see `flatten_to_bytes`)
+pub fn try_json_to_fbril_bytes(
+    json: &serde_json::Value,
+) -> Result<Vec<u8>, String> {
+    let functions = json["functions"]
+        .as_array()
+        .ok_or("expected `functions` to be a JSON array")?;
+    // (every function is checked, so that all their problems are reported)
+    let mut instr_stores = vec![];
+    let mut problems = vec![];
+    for (func_idx, func) in functions.iter().enumerate() {
+        let instr_store = flatten::try_flatten_instrs(func)
+            .map_err(|e| e.in_program(func_idx).to_string())
+            .and_then(|instr_store| {
+                flatten::validate_instr_store(&instr_store)?;
+                Ok(instr_store)
+            });
+        match instr_store {
+            Ok(instr_store) => instr_stores.push(instr_store),
+            Err(problem) => problems.push(problem),
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems.join("\n"));
+    }
+    Ok(instr_stores_to_fbril_bytes(instr_stores))
+}
 
-        // Convert an `InstrStore` to an `InstrView`
-        let padded_func_name = pad_vec(instr_store.func_name);
-        let flat_func_name = padded_func_name.as_slice();
-        let flat_func_arg_vec: Vec<FlatFuncArg> = instr_store
-            .func_args
-            .into_iter()
-            .map(|func_arg| func_arg.into())
-            .collect();
+/// Combines the functions of several programs into one, in the order in
+/// which they appear. Each program is paired with the name of the file it
+/// came from, which is used in error messages. No references between the
+/// programs are resolved: a `call` to a function which isn't defined in any
+/// of the programs is left as-is.
+/// - Returns an error if two programs define a function with the same name,
+///   unless `allow_override` is true, in which case the later definition
+///   replaces the earlier one (keeping the earlier one's position)
+pub fn merge_instr_stores(
+    programs: Vec<(&str, Vec<InstrStore>)>,
+    allow_override: bool,
+) -> Result<Vec<InstrStore>, String> {
+    let mut merged: Vec<InstrStore> = vec![];
+    // The index of each function in `merged`, & the file it came from
+    let mut defined: HashMap<String, (usize, &str)> = HashMap::new();
+    for (source, instr_stores) in programs {
+        for instr_store in instr_stores {
+            // (names read from a file are padded with NULs)
+            let func_name = String::from_utf8_lossy(&instr_store.func_name)
+                .trim_end_matches(char::from(0))
+                .to_string();
+            match defined.get(&func_name) {
+                Some(&(idx, _)) if allow_override => {
+                    merged[idx] = instr_store;
+                    defined.insert(func_name, (idx, source));
+                }
+                Some((_, prev_source)) => {
+                    return Err(format!(
+                        "function `@{func_name}` is defined in both \
+                        `{prev_source}` and `{source}` (pass \
+                        `--allow-override` to use the later definition)"
+                    ));
+                }
+                None => {
+                    defined.insert(func_name, (merged.len(), source));
+                    merged.push(instr_store);
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
 
-        let flat_func_args: &[FlatFuncArg] = flat_func_arg_vec.as_slice();
-        let flat_func_ret_ty: FlatType = instr_store.func_ret_ty.into();
+/// Flattens the functions of the JSON Bril program read from `reader` one at
+/// a time (see `flatten::flatten_functions_streaming`), adding them to the
+/// end of `instr_stores` (an error is returned if a function is already
+/// defined in `instr_stores`)
+#[cfg(feature = "mmap")]
+fn flatten_streaming<R: Read>(
+    reader: R,
+    instr_stores: &mut Vec<InstrStore>,
+    ingest: flatten::Ingest,
+) -> Result<(), String> {
+    let mut func_names: HashSet<Vec<u8>> = instr_stores
+        .iter()
+        .map(|instr_store| instr_store.func_name.clone())
+        .collect();
+    flatten::flatten_functions_streaming(reader, ingest, |instr_store| {
+        if !func_names.insert(instr_store.func_name.clone()) {
+            return Err(format!(
+                "function `@{}` is already defined",
+                String::from_utf8_lossy(&instr_store.func_name)
+            ));
+        }
+        instr_stores.push(instr_store);
+        Ok(())
+    })
+}
 
-        let padded_var_store = pad_vec(instr_store.var_store);
-        let flat_var_store: &[u8] = padded_var_store.as_slice();
-        let flat_arg_idxes_vec: Vec<I32Pair> = instr_store
-            .args_idxes_store
-            .into_iter()
-            .map(|arg_idxes| arg_idxes.into())
-            .collect();
-        let flat_arg_idxes_store = flat_arg_idxes_vec.as_slice();
+/// Writes the `.fbril` file for the program made up of `instr_stores` to
+/// `output_file`, in the format given by `format` if the program fits in it
+/// (see `choose_format_flags`).
+/// - The file is written to a temporary file next to `output_file`, which
+///   then replaces it, so if writing fails part-way through, the original
+///   contents of `output_file` (if any) are left untouched
+#[cfg(feature = "mmap")]
+fn write_fbril_file(
+    output_file: &str,
+    instr_stores: Vec<InstrStore>,
+    format: u64,
+) -> Result<(), String> {
+    let flags = choose_format_flags(&instr_stores, format);
+    let bytes = encode_instr_stores(instr_stores, flags)?;
+    let io_err =
+        |e: std::io::Error| format!("unable to write `{output_file}`: {e}");
+    let tmp_file = format!("{output_file}.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_file).map_err(io_err)?);
+    writer.write_all(&bytes).map_err(io_err)?;
+    writer.flush().map_err(io_err)?;
+    drop(writer);
+    std::fs::rename(&tmp_file, output_file).map_err(io_err)
+}
+
+/// Flattens the JSON Bril program read from `reader` & writes the contents
+/// of the resulting `.fbril` file to `writer` (e.g. `stdout`), in the format
+/// given by `format` (see `stream_json_to_fbril`)
+#[cfg(feature = "mmap")]
+pub fn stream_json_to_writer<R: Read, W: Write>(
+    reader: R,
+    mut writer: W,
+    format: u64,
+    ingest: flatten::Ingest,
+) -> Result<(), String> {
+    let mut instr_stores = vec![];
+    flatten_streaming(reader, &mut instr_stores, ingest)?;
+    let flags = choose_format_flags(&instr_stores, format);
+    let bytes = encode_instr_stores(instr_stores, flags)?;
+    let io_err = |e: std::io::Error| format!("unable to write fbril file: {e}");
+    writer.write_all(&bytes).map_err(io_err)?;
+    writer.flush().map_err(io_err)
+}
+
+/// The name of the `.fbril` file that the function `func_name` is written to
+/// by `stream_json_to_fbril_per_function` (characters which can't safely
+/// appear in a file name are replaced with `_`)
+pub fn per_function_file_name(func_name: &[u8]) -> String {
+    let name: String = String::from_utf8_lossy(func_name)
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{}.fbril", name.trim_start_matches('.'))
+}
 
-        let flat_label_idxes_vec: Vec<I32Pair> = instr_store
-            .labels_idxes_store
+/// Flattens the JSON Bril program read from `reader` & writes each function
+/// to its own `.fbril` file in the directory `out_dir` (which is created if
+/// it doesn't exist), named after the function (see
+/// `per_function_file_name`). Returns the files written, in the order in
+/// which the functions appear.
+/// - Each file is written in the format given by `format` if the function
+///   fits in it (see `write_fbril_file`)
+/// - No references between the functions are resolved: a `call` to another
+///   function is left as-is (see `merge_instr_stores`)
+/// - Returns an error (before writing anything) if two functions would be
+///   written to the same file
+#[cfg(feature = "mmap")]
+pub fn stream_json_to_fbril_per_function<R: Read>(
+    reader: R,
+    out_dir: &str,
+    format: u64,
+    ingest: flatten::Ingest,
+) -> Result<Vec<String>, String> {
+    let mut instr_stores = vec![];
+    flatten_streaming(reader, &mut instr_stores, ingest)?;
+
+    let mut file_names = HashSet::new();
+    let mut outputs = vec![];
+    for instr_store in &instr_stores {
+        let file_name = per_function_file_name(&instr_store.func_name);
+        if !file_names.insert(file_name.clone()) {
+            return Err(format!(
+                "function `@{}` would overwrite another function's file \
+                `{file_name}`",
+                String::from_utf8_lossy(&instr_store.func_name)
+            ));
+        }
+        let path = std::path::Path::new(out_dir).join(file_name);
+        let path = path.to_str().ok_or_else(|| {
+            format!("`{}` isn't a valid file name", path.display())
+        })?;
+        outputs.push(path.to_string());
+    }
+
+    std::fs::create_dir_all(out_dir)
+        .map_err(|e| format!("unable to create `{out_dir}`: {e}"))?;
+    for (instr_store, output) in instr_stores.into_iter().zip(&outputs) {
+        write_fbril_file(output, vec![instr_store], format)?;
+    }
+    Ok(outputs)
+}
+
+/// Flattens the JSON Bril program read from `reader` & writes it to the
+/// `.fbril` file `output_file`. The JSON is flattened one function at a time
+/// (see `flatten::flatten_functions_streaming`), so that it never has to be
+/// held in memory in its entirety, but since the functions' stores are
+/// shared in the file, the file is only written once every function has
+/// been flattened.
+/// - The file is written in the format given by `format` (`WIDE_FORMAT_FLAG`,
+///   `COMPACT_FORMAT_FLAG` or 0), unless the program doesn't fit in it
+///   (see `choose_format_flags`)
+/// - Malformed fields are handled according to `ingest`
+///   (see `flatten::check_func_json`)
+#[cfg(feature = "mmap")]
+pub fn stream_json_to_fbril<R: Read>(
+    reader: R,
+    output_file: &str,
+    format: u64,
+    ingest: flatten::Ingest,
+) -> Result<(), String> {
+    let mut instr_stores = vec![];
+    flatten_streaming(reader, &mut instr_stores, ingest)?;
+    write_fbril_file(output_file, instr_stores, format)
+}
+
+/// Appends the functions of the JSON Bril program read from `reader` to the
+/// existing `.fbril` file `fbril_file`. Since the functions' stores are
+/// shared, the whole file is rewritten (in the same format, unless the new
+/// functions don't fit in it, see `choose_format_flags`), with the new functions
+/// after the existing ones.
+/// - The file is replaced once the new program has been written in full
+///   (see `write_fbril_file`), so if appending fails part-way through,
+///   the file still contains the original program.
+/// - Returns an error if one of the new functions is already defined in the
+///   file
+#[cfg(feature = "mmap")]
+pub fn append_json_to_fbril<R: Read>(
+    reader: R,
+    fbril_file: &str,
+    ingest: flatten::Ingest,
+) -> Result<(), String> {
+    let (mut instr_stores, format) = {
+        let mmap = mmap_existing_file(fbril_file)?;
+        let program = load_program(&mmap)
+            .map_err(|e| format!("malformed file `{fbril_file}`: {e}"))?;
+        let format = program.header().flags
+            & (WIDE_FORMAT_FLAG | COMPACT_FORMAT_FLAG | RESOLVED_FLAG);
+        (program.instr_stores(), format)
+    };
+    flatten_streaming(reader, &mut instr_stores, ingest)?;
+    write_fbril_file(fbril_file, instr_stores, format)
+}
+
+/// Flattens the JSON Bril program read from `reader` (e.g. `stdin`) to the
+/// `.fbril` file `output_file` (in the format given by `format`, see
+/// `stream_json_to_fbril`)
+#[cfg(feature = "mmap")]
+pub fn json_to_fbril<R: Read>(
+    reader: R,
+    output_file: String,
+    format: u64,
+    ingest: flatten::Ingest,
+) -> Result<(), String> {
+    stream_json_to_fbril(reader, &output_file, format, ingest)?;
+
+    // Note: we're keeping this around as a sanity check
+    let mmap = mmap_existing_file(&output_file)?;
+    load_program(&mmap).map_err(|e| {
+        format!("wrote a malformed program to the fbril file: {e}")
+    })?;
+
+    eprintln!("succesfully wrote to fbril file!");
+    Ok(())
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+#[cfg(all(test, feature = "mmap"))]
+mod memfile_tests {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::{fs::File, io::BufReader};
+
+    use zerocopy::{FromBytes, IntoBytes};
+
+    use crate::diagnostic::ErrorCode;
+    use crate::flatten::{Ingest, flatten_instrs};
+    use crate::interp::{
+        self, InterpContext, Limits, get_table_label_name, interp_func_table,
+        interp_program, interp_program_captured,
+    };
+    use crate::memfile::{
+        AlignedBytes, LazyProgram, MmapAdvice, MmapTuning, ProgramViews,
+        append_json_to_fbril, choose_format_flags, encode_instr_stores,
+        extract_section, get_flat_program, get_program_views,
+        instr_stores_to_fbril_bytes, json_to_fbril_bytes, load_program,
+        load_program_lazily, merge_instr_stores, mmap_existing_file,
+        mmap_existing_file_tuned, pad_vec, per_function_file_name,
+        section_layouts, stream_json_to_fbril,
+        stream_json_to_fbril_per_function, stream_json_to_writer,
+    };
+    use crate::types::{
+        COMPACT_FORMAT_FLAG, FlatType, FuncRecord, Header, I16Pair, IndexPair,
+        InstrStore, MAX_PTR_DEPTH, PTR_DEPTH_SHIFT, RESOLVED_FLAG,
+        ResolvedOperands, Toc, UNRESOLVED, WIDE_FORMAT_FLAG,
+    };
+    use crate::unflatten::unflatten_instrs;
+
+    /// Produces the contents of an `.fbril` file for the JSON Bril program
+    /// at `path`
+    fn fbril_data(path: &str) -> AlignedBytes {
+        let file = File::open(path).expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        AlignedBytes::new(&json_to_fbril_bytes(&json))
+    }
+
+    /// A well-formed file loads successfully
+    #[test]
+    fn test_load_valid_file() {
+        let data = fbril_data("test/call-with-args.json");
+        let program = get_program_views(&data).expect("valid file should load");
+        assert_eq!(program.len(), 2);
+    }
+
+    /// Every proper prefix of a valid file is rejected with an error
+    /// (rather than a panic)
+    #[test]
+    fn test_truncated_file_is_rejected() {
+        let data = fbril_data("test/call-with-args.json");
+        for truncated_len in 0..data.len() {
+            let result = get_program_views(&data[..truncated_len]);
+            assert!(result.is_err(), "prefix of length {truncated_len}");
+        }
+    }
+
+    /// Out-of-bounds index pairs are rejected
+    #[test]
+    fn test_corrupt_file_is_rejected() {
+        let mut data = fbril_data("test/call-with-args.json");
+        // Overwrite the 2nd component of the index pair of the first
+        // function's name (the first field of its `FuncRecord`, which comes
+        // right after the `Toc`) with an out-of-bounds index
+        let offset = size_of::<Header>() + size_of::<Toc>() + 4;
+        data[offset..offset + 4].copy_from_slice(&1000i32.to_le_bytes());
+        let result = get_program_views(&data);
+        assert!(result.unwrap_err().contains("out of bounds"));
+    }
+
+    /// A function which claims to need more slots than it has variables is
+    /// rejected at load time (rather than the interpreter trying to
+    /// allocate that many slots)
+    #[test]
+    fn test_too_many_slots_is_rejected() {
+        let mut data = fbril_data("test/call-with-args.json");
+        let offset = size_of::<Header>()
+            + size_of::<Toc>()
+            + std::mem::offset_of!(FuncRecord, num_slots);
+        data[offset..offset + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        let result = get_program_views(&data);
+        assert!(result.unwrap_err().contains("1099511627776 slots"));
+
+        // (variables which are used without being defined need slots too)
+        let json = serde_json::json!({ "functions": [{
+            "name": "main",
+            "instrs": [{ "op": "print", "args": ["a", "b", "c"] }]
+        }] });
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&data).expect("valid file should load");
+        assert_eq!({ program.views()[0].num_slots }, 3);
+    }
+
+    /// A type whose pointer depth is corrupt is rejected at load time
+    /// (rather than printing the type trying to allocate a huge string)
+    #[test]
+    fn test_corrupt_ptr_depth_is_rejected() {
+        let mut data = fbril_data("test/call-with-args.json");
+        let func_args = section_layouts(&data)
+            .unwrap()
             .into_iter()
-            .map(|lbl_idx| lbl_idx.into())
+            .find(|layout| layout.name == "func_args")
+            .expect("file has a func_args section");
+        assert!(func_args.count > 0);
+        let offset =
+            func_args.offset + func_args.item_size - size_of::<FlatType>();
+        // (a pointer to an int, since `FlatType::INT` is 0)
+        let ty = (u32::MAX as usize) << PTR_DEPTH_SHIFT;
+        data[offset..offset + size_of::<FlatType>()]
+            .copy_from_slice(&ty.to_le_bytes());
+        let result = get_program_views(&data);
+        assert!(result.unwrap_err().contains("has an invalid type"));
+
+        let ty = (MAX_PTR_DEPTH as usize) << PTR_DEPTH_SHIFT;
+        data[offset..offset + size_of::<FlatType>()]
+            .copy_from_slice(&ty.to_le_bytes());
+        assert!(get_program_views(&data).is_ok());
+    }
+
+    /// Strings that aren't valid UTF-8 are rejected at load time
+    #[test]
+    fn test_invalid_utf8_is_rejected() {
+        let mut data = fbril_data("test/call-with-args.json");
+        // The first function's name comes right after the function table
+        let offset = size_of::<Header>()
+            + size_of::<Toc>()
+            + 2 * size_of::<FuncRecord>();
+        data[offset] = 0xFF;
+        let result = get_program_views(&data);
+        assert!(result.unwrap_err().contains("not valid UTF-8"));
+    }
+
+    /// Instructions from Bril extensions survive a round trip through an
+    /// `.fbril` file unchanged, and are only an error if they're executed
+    #[test]
+    fn test_extension_instrs() {
+        let instrs = serde_json::json!([
+            { "op": "const", "dest": "n", "type": "int", "value": 4 },
+            { "op": "print", "args": ["n"] },
+            { "op": "alloc", "dest": "p", "type": { "ptr": "int" },
+              "args": ["n"] },
+            { "op": "free", "args": ["p"] },
+        ]);
+        let json = serde_json::json!({ "functions": [
+            { "name": "main", "instrs": instrs },
+        ] });
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let views = program.views();
+        let unflattened = unflatten_instrs(&InstrStore::from(views[0].clone()));
+        // (core instructions are unflattened with empty `labels` & `funcs`)
+        assert_eq!(
+            unflattened["instrs"].as_array().unwrap()[2..],
+            instrs.as_array().unwrap()[2..]
+        );
+
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        let diagnostic =
+            interp_program(&program, vec![], &mut ctx).unwrap_err();
+        assert_eq!(diagnostic.code, ErrorCode::UnsupportedInstr);
+        assert_eq!(
+            diagnostic.to_string(),
+            "the extension opcode \"alloc\" isn't supported (at @main:2)"
+        );
+        assert_eq!(ctx.out, b"4\n");
+    }
+
+    /// An empty `args` list is stored as an empty `(offset, 0)` range
+    /// (rather than as a missing list) & survives a round trip
+    #[test]
+    fn test_empty_args() {
+        let json = serde_json::json!({ "functions": [
+            { "name": "main", "instrs": [
+                { "op": "print", "args": [] },
+                { "op": "call", "funcs": ["f"], "args": [] },
+            ] },
+            { "name": "f", "instrs": [{ "op": "ret", "args": [] }] },
+        ] });
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let views = program.views();
+        for instr in views[0].instrs {
+            assert!(matches!(instr.args.get(), Some((_, 0))));
+        }
+        let unflattened = unflatten_instrs(&InstrStore::from(views[0].clone()));
+        for instr in unflattened["instrs"].as_array().unwrap() {
+            assert_eq!(instr["args"], serde_json::json!([]));
+        }
+
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        assert!(interp_program(&program, vec![], &mut ctx).is_ok());
+        assert_eq!(ctx.out, b"\n");
+    }
+
+    /// Labels are stored in the label table (mapped to the PC of the
+    /// instruction after them) rather than in `instrs`, and are put back
+    /// in place when the function is converted back to an `InstrStore`
+    #[test]
+    fn test_label_table() {
+        let func = serde_json::json!({ "name": "main", "instrs": [
+            { "op": "const", "dest": "c", "type": "bool", "value": true },
+            { "op": "br", "args": ["c"], "labels": ["left", "right"] },
+            { "label": "left" },
+            { "op": "const", "dest": "x", "type": "int", "value": 1 },
+            { "op": "jmp", "labels": ["join"] },
+            { "label": "right" },
+            { "op": "const", "dest": "x", "type": "int", "value": 2 },
+            { "label": "join" },
+            { "op": "phi", "dest": "y", "type": "int", "args": ["x", "x"],
+              "labels": ["left", "right"] },
+            { "op": "print", "args": ["y"] },
+            { "label": "end" },
+        ] });
+        let json = serde_json::json!({ "functions": [func] });
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let views = program.views();
+
+        let view = &views[0];
+        assert_eq!(view.instrs.len(), 7);
+        assert!(view.instrs.iter().all(|instr| instr.op != u32::MAX));
+        let table: Vec<(&str, usize)> = (0..view.label_table.len())
+            .map(|idx| {
+                (get_table_label_name(view, idx), view.label_table[idx].pc)
+            })
             .collect();
-        let flat_label_idxes = flat_label_idxes_vec.as_slice();
+        assert_eq!(table, [("left", 2), ("right", 4), ("join", 5), ("end", 7)]);
+        assert_eq!(
+            unflatten_instrs(&InstrStore::from(view.clone())),
+            unflatten_instrs(&flatten_instrs(&func))
+        );
 
-        let padded_labels_store = pad_vec(instr_store.labels_store);
-        let flat_labels_store = padded_labels_store.as_slice();
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        interp_program(&program, vec![], &mut ctx).expect("program runs");
+        assert_eq!(ctx.out, b"1\n");
+    }
 
-        let padded_funcs_store = pad_vec(instr_store.funcs_store);
-        let flat_funcs_store = padded_funcs_store.as_slice();
-        let flat_instrs_vec: Vec<FlatInstr> = instr_store
-            .instrs
-            .into_iter()
-            .map(|instr| instr.into())
+    /// All the functions share the same stores, and each one's instructions
+    /// are its own part of the `instrs` section, so programs can have any
+    /// number of functions (& survive a round trip through `InstrStore`s).
+    /// Names used by several functions are only stored once.
+    #[test]
+    fn test_function_table() {
+        // `@main` calls `@f1`, which calls `@f2`, ..., & each one prints
+        // its index (as a local variable with the same name in each function)
+        let funcs: Vec<serde_json::Value> = (0..12)
+            .map(|i| {
+                let mut instrs = vec![
+                    serde_json::json!({ "op": "const", "dest": "i",
+                                        "type": "int", "value": i }),
+                    serde_json::json!({ "op": "print", "args": ["i"] }),
+                ];
+                if i < 11 {
+                    instrs.push(serde_json::json!({
+                        "op": "call", "funcs": [format!("f{}", i + 1)]
+                    }));
+                }
+                let name = if i == 0 {
+                    "main".to_string()
+                } else {
+                    format!("f{i}")
+                };
+                serde_json::json!({ "name": name, "instrs": instrs })
+            })
             .collect();
-        let flat_instrs: &[FlatInstr] = flat_instrs_vec.as_slice();
-        let instr_view = InstrView {
-            func_name: flat_func_name,
-            func_args: flat_func_args,
-            func_ret_ty: flat_func_ret_ty,
-            var_store: flat_var_store,
-            arg_idxes_store: flat_arg_idxes_store,
-            labels_idxes_store: flat_label_idxes,
-            labels_store: flat_labels_store,
-            funcs_store: flat_funcs_store,
-            instrs: flat_instrs,
+        let json = serde_json::json!({ "functions": funcs });
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let views = program.views();
+
+        assert_eq!(views.len(), 12);
+        for view in views {
+            assert_eq!(view.var_store.as_ptr(), views[0].var_store.as_ptr());
+            assert!(view.instrs.len() <= 3);
+        }
+        // (each callee is interned along with the function's name, & `i`
+        // is interned once for all 12 functions)
+        let names: String = (1..12).map(|i| format!("f{i}")).collect();
+        let strings = pad_vec(format!("main{names}i").into_bytes());
+        assert_eq!(extract_section(&data, "strings"), Ok(strings.as_slice()));
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        interp_program(&program, vec![], &mut ctx).expect("program runs");
+        let expected: String = (0..12).map(|i| format!("{i}\n")).collect();
+        assert_eq!(ctx.out, expected.as_bytes());
+
+        // (functions are looked up by name in the program's name table)
+        assert_eq!(program.header().num_funcs, 12);
+        let f7 = program.get("f7").expect("@f7 is defined");
+        assert_eq!(interp::get_func_name(f7), "f7");
+        assert!(std::ptr::eq(f7, &views[7]));
+        assert!(program.get("f12").is_none());
+        assert_eq!(instr_stores_to_fbril_bytes(program.instr_stores()), bytes);
+    }
+
+    /// Only the functions that are called are loaded lazily, so a corrupt
+    /// function which is never called doesn't stop the program from running
+    #[test]
+    fn test_load_program_lazily() {
+        let json = serde_json::json!({ "functions": [
+            {
+                "name": "main",
+                "instrs": [{ "op": "call", "funcs": ["callee"] }]
+            },
+            {
+                "name": "callee",
+                "instrs": [
+                    { "op": "const", "dest": "x", "type": "int", "value": 7 },
+                    { "op": "print", "args": ["x"] }
+                ]
+            },
+            { "name": "unused", "instrs": [{ "op": "nop" }] }
+        ]});
+        let bytes = json_to_fbril_bytes(&json);
+        let mut data = AlignedBytes::new(&bytes);
+        // Claim that `unused` has far more instructions than fit in the file
+        // (its end pc is the 3rd field of its `FuncRecord`)
+        let offset = size_of::<Header>()
+            + size_of::<Toc>()
+            + 2 * size_of::<FuncRecord>()
+            + 16;
+        data[offset..offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(load_program(&data).is_err());
+
+        let Ok(LazyProgram::Narrow(funcs)) = load_program_lazily(&data) else {
+            panic!("the header & function names are valid");
+        };
+        assert_eq!(
+            funcs.names().collect::<Vec<_>>(),
+            ["main", "callee", "unused"]
+        );
+        let mut ctx = InterpContext::new(vec![], Limits::default());
+        interp_func_table(&funcs, vec![], &mut ctx).expect("program runs");
+        assert_eq!(ctx.out, b"7\n");
+        assert!(funcs.is_loaded("callee"));
+        assert!(!funcs.is_loaded("unused"));
+        let err = funcs.views().unwrap_err();
+        assert_eq!(err.code, ErrorCode::MalformedFile);
+        assert!(err.message.starts_with("malformed function @unused"));
+    }
+
+    /// Tuning how a file is mmap-ed doesn't change its contents
+    #[test]
+    fn test_tuned_mmap_matches() {
+        let path = "test/call-with-args.json";
+        let output_file =
+            std::env::temp_dir().join("flat-bril-tuned-mmap-test.fbril");
+        let output_file = output_file.to_str().unwrap();
+        let file = File::open(path).expect("Unable to open file");
+        stream_json_to_fbril(
+            BufReader::new(file),
+            output_file,
+            0,
+            Ingest::Strict,
+        )
+        .expect("streaming should succeed");
+
+        let tuning = MmapTuning {
+            populate: true,
+            advice: Some(MmapAdvice::Sequential),
+            hugepages: false,
         };
+        let mmap = mmap_existing_file_tuned(output_file, tuning)
+            .expect("mmap should succeed");
+        assert_eq!(&mmap[..], std::fs::read(output_file).unwrap());
+        assert!(get_program_views(&mmap).is_ok());
+    }
+
+    /// Streaming a JSON program to a file produces the same bytes
+    /// as flattening the whole program in memory
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let path = "test/call-with-args.json";
+        let output_file =
+            std::env::temp_dir().join("flat-bril-stream-test.fbril");
+        let output_file = output_file.to_str().unwrap();
+
+        let file = File::open(path).expect("Unable to open file");
+        stream_json_to_fbril(
+            BufReader::new(file),
+            output_file,
+            0,
+            Ingest::Strict,
+        )
+        .expect("streaming should succeed");
+        let streamed = std::fs::read(output_file).unwrap();
 
-        let instr_view_bytes = convert_instr_view_to_bytes(&instr_view);
-        buffer.extend_from_slice(&instr_view_bytes);
-        sizes_arr[sizes_idx] = instr_view_bytes.len() as u64;
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap())
+                .unwrap();
+        assert_eq!(streamed, json_to_fbril_bytes(&json));
     }
 
-    let header = Header { sizes: sizes_arr };
+    /// A JSON program can be streamed to a writer instead of a file, and
+    /// the bytes can be read back in from a reader (e.g. in a pipeline)
+    #[test]
+    fn test_stream_to_writer() {
+        let path = "test/call-with-args.json";
+        let file = File::open(path).expect("Unable to open file");
+        let mut written = vec![];
+        stream_json_to_writer(
+            BufReader::new(file),
+            &mut written,
+            0,
+            Ingest::Strict,
+        )
+        .expect("streaming should succeed");
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap())
+                .unwrap();
+        assert_eq!(written, json_to_fbril_bytes(&json));
 
-    // TODO: figure out some appropriate filename + size for the mmapped file
-    let mut mmap = mmap_new_file(&output_file, 100000000, true);
+        let data = AlignedBytes::from_reader(written.as_slice()).unwrap();
+        let program = get_flat_program(&data).expect("valid file should load");
+        let output = interp_program_captured(&program, vec![]).unwrap();
+        assert_eq!(output.lines, ["4", "2", "4"]);
+    }
 
-    // Write the header (containing the offsets) to the file
-    let new_mmap = write_bump(&mut mmap, &header.sizes)
-        .expect("error writing offsets to file");
-    // Then write the contents of the buffer to the file
-    write_bytes(new_mmap, &buffer);
+    /// With one file per function, each file contains just that function,
+    /// and is named after it
+    #[test]
+    fn test_per_function_files() {
+        let out_dir = std::env::temp_dir().join("flat-bril-per-function-test");
+        let out_dir = out_dir.to_str().unwrap();
+        let file = File::open("test/call-with-args.json")
+            .expect("Unable to open file");
+        let outputs = stream_json_to_fbril_per_function(
+            BufReader::new(file),
+            out_dir,
+            0,
+            Ingest::Strict,
+        )
+        .expect("writing per-function files should succeed");
+        assert_eq!(outputs.len(), 2);
+        for (output, func_name) in outputs.iter().zip(["main", "add2"]) {
+            assert!(output.ends_with(&format!("{func_name}.fbril")));
+            let mmap = mmap_existing_file(output).unwrap();
+            let program = load_program(&mmap).expect("valid file should load");
+            assert_eq!(program.func_names(), [func_name]);
+        }
 
-    // Note: we're keeping this around as a sanity check
-    let _temp_instr_view = get_instr_view(&buffer);
+        assert_eq!(per_function_file_name(b"a/../b"), "a_.._b.fbril");
+        assert_eq!(per_function_file_name(b"..main"), "main.fbril");
+    }
+
+    /// Appending functions to a file gives the same bytes as flattening
+    /// all the functions at once, and redefining a function is rejected
+    #[test]
+    fn test_append_functions() {
+        let path = "test/call-with-args.json";
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap())
+                .unwrap();
+        let funcs = json["functions"].as_array().unwrap();
+        let first = serde_json::json!({ "functions": funcs[..1] });
+        let rest = serde_json::json!({ "functions": funcs[1..] });
+
+        let output_file =
+            std::env::temp_dir().join("flat-bril-append-test.fbril");
+        let output_file = output_file.to_str().unwrap();
+        stream_json_to_fbril(
+            first.to_string().as_bytes(),
+            output_file,
+            0,
+            Ingest::Strict,
+        )
+        .expect("streaming should succeed");
+        append_json_to_fbril(
+            rest.to_string().as_bytes(),
+            output_file,
+            Ingest::Strict,
+        )
+        .expect("appending should succeed");
+        let appended = std::fs::read(output_file).unwrap();
+        assert_eq!(appended, json_to_fbril_bytes(&json));
 
-    println!("succesfully wrote to fbril file!");
+        let result = append_json_to_fbril(
+            rest.to_string().as_bytes(),
+            output_file,
+            Ingest::Strict,
+        );
+        assert!(result.unwrap_err().contains("already defined"));
+        assert_eq!(std::fs::read(output_file).unwrap(), appended);
+    }
+
+    /// Merging programs gives the same bytes as flattening all their
+    /// functions at once, and duplicate functions are rejected unless
+    /// overriding is allowed
+    #[test]
+    fn test_merge_programs() {
+        let json: serde_json::Value = serde_json::from_str(
+            &std::fs::read_to_string("test/call-with-args.json").unwrap(),
+        )
+        .unwrap();
+        let funcs = json["functions"].as_array().unwrap();
+        let instr_stores = |json: serde_json::Value| {
+            let bytes = json_to_fbril_bytes(&json);
+            let data = AlignedBytes::new(&bytes);
+            load_program(&data)
+                .expect("valid file should load")
+                .instr_stores()
+        };
+        let first =
+            instr_stores(serde_json::json!({ "functions": funcs[..1] }));
+        let rest = instr_stores(serde_json::json!({ "functions": funcs[1..] }));
+
+        let merged = merge_instr_stores(
+            vec![("first.fbril", first.clone()), ("rest.fbril", rest)],
+            false,
+        )
+        .expect("functions are distinct");
+        assert_eq!(
+            instr_stores_to_fbril_bytes(merged),
+            json_to_fbril_bytes(&json)
+        );
+
+        let programs =
+            vec![("a.fbril", first.clone()), ("b.fbril", first.clone())];
+        assert_eq!(
+            merge_instr_stores(programs.clone(), false).unwrap_err(),
+            "function `@main` is defined in both `a.fbril` and `b.fbril` \
+            (pass `--allow-override` to use the later definition)"
+        );
+        assert_eq!(merge_instr_stores(programs, true), Ok(first));
+    }
+
+    /// Programs in the wide format run the same as in the default format,
+    /// and re-encoding a default-format file in the wide format gives the same
+    /// bytes as writing it in the wide format to begin with
+    #[test]
+    fn test_wide_format() {
+        let path = "test/call-with-args.json";
+        let dir = std::env::temp_dir();
+        let narrow_file = dir.join("flat-bril-narrow-test.fbril");
+        let narrow_file = narrow_file.to_str().unwrap();
+        let wide_file = dir.join("flat-bril-wide-test.fbril");
+        let wide_file = wide_file.to_str().unwrap();
+        for (output_file, format) in
+            [(narrow_file, 0), (wide_file, WIDE_FORMAT_FLAG)]
+        {
+            let file = File::open(path).expect("Unable to open file");
+            stream_json_to_fbril(
+                BufReader::new(file),
+                output_file,
+                format,
+                Ingest::Strict,
+            )
+            .expect("streaming should succeed");
+        }
+
+        let run = |output_file: &str| {
+            let mmap = mmap_existing_file(output_file).unwrap();
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            match load_program(&mmap).expect("file should load") {
+                ProgramViews::Narrow(program) => {
+                    assert!(!output_file.contains("wide"));
+                    interp_program(&program, vec![], &mut ctx)
+                }
+                ProgramViews::Wide(program) => {
+                    assert!(output_file.contains("wide"));
+                    interp_program(&program, vec![], &mut ctx)
+                }
+                ProgramViews::Compact(_) => panic!("file isn't compact"),
+            }
+            .expect("program should run");
+            ctx.out
+        };
+        assert_eq!(run(narrow_file), run(wide_file));
+
+        let mmap = mmap_existing_file(narrow_file).unwrap();
+        let instr_stores = load_program(&mmap).unwrap().instr_stores();
+        assert_eq!(
+            encode_instr_stores(instr_stores, WIDE_FORMAT_FLAG).unwrap(),
+            std::fs::read(wide_file).unwrap()
+        );
+    }
+
+    /// Programs in the compact format (whose `instrs` section needs padding)
+    /// run the same as in the default format & take up less space, and
+    /// programs that are too big for it are written in the default format
+    #[test]
+    fn test_compact_format() {
+        let data = fbril_data("test/fizz-buzz.json");
+        let bytes = &data;
+        let instr_stores = load_program(bytes).unwrap().instr_stores();
+        assert_eq!(
+            choose_format_flags(&instr_stores, COMPACT_FORMAT_FLAG),
+            COMPACT_FORMAT_FLAG
+        );
+        let compact =
+            encode_instr_stores(instr_stores.clone(), COMPACT_FORMAT_FLAG)
+                .unwrap();
+        assert!(compact.len() < bytes.len());
+
+        let compact_words = AlignedBytes::new(&compact);
+        let Ok(ProgramViews::Compact(program)) = load_program(&compact_words)
+        else {
+            panic!("file should load in the compact format");
+        };
+        let num_instrs: usize =
+            program.views().iter().map(|view| view.instrs.len()).sum();
+        assert_eq!(num_instrs % 2, 1);
+        let narrow_program = get_flat_program(bytes).unwrap();
+        assert_eq!(
+            interp_program_captured(&program, vec!["15"]),
+            interp_program_captured(&narrow_program, vec!["15"])
+        );
+        assert_eq!(program.instr_stores(), instr_stores);
+
+        let mut big_store = instr_stores[0].clone();
+        big_store.ext_store = vec![0; I16Pair::MAX_IDX + 1];
+        assert_eq!(choose_format_flags(&[big_store], COMPACT_FORMAT_FLAG), 0);
+    }
+
+    /// A file written with `RESOLVED_FLAG` stores the target pcs of every
+    /// jump & the callee of every call, which are checked when it's loaded
+    #[test]
+    fn test_resolved_operands() {
+        let json = serde_json::json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "n", "type": "int", "value": 3 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "c", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["c"] },
+                { "label": "body" },
+                { "op": "call", "dest": "i", "type": "int",
+                  "funcs": ["inc"], "args": ["i"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "print", "args": ["i"] }
+            ]
+        }, {
+            "name": "inc",
+            "args": [{ "name": "x", "type": "int" }],
+            "type": "int",
+            "instrs": [
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "add", "dest": "y", "type": "int",
+                  "args": ["x", "one"] },
+                { "op": "ret", "args": ["y"] }
+            ]
+        }]});
+        let instr_stores: Vec<InstrStore> = json["functions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(flatten_instrs)
+            .collect();
+        let flags = choose_format_flags(&instr_stores, RESOLVED_FLAG);
+        let mut data = AlignedBytes::new(
+            &encode_instr_stores(instr_stores, flags).unwrap(),
+        );
+        let program = get_flat_program(&data).unwrap();
+        assert_eq!(program.header().flags & RESOLVED_FLAG, RESOLVED_FLAG);
+        let resolved = program.views()[0].resolved;
+        let none = ResolvedOperands {
+            targets: [UNRESOLVED; 2],
+            callee: UNRESOLVED,
+        };
+        assert_eq!(resolved[0], none);
+        assert_eq!(resolved[3].targets, [4, 6]);
+        assert_eq!(resolved[4].callee, 1);
+        assert_eq!(resolved[5].targets, [2, UNRESOLVED]);
+        let output = interp_program_captured(&program, vec![]).unwrap();
+        assert_eq!(output.lines, ["3"]);
+
+        // (the `jmp` is made to jump to the `br`, which has no label)
+        let layouts = section_layouts(&data).unwrap();
+        let section = layouts.iter().find(|s| s.name == "resolved").unwrap();
+        let offset = section.offset + 5 * size_of::<ResolvedOperands>();
+        data[offset..offset + 4].copy_from_slice(&3u32.to_ne_bytes());
+        assert_eq!(
+            get_flat_program(&data).err().unwrap(),
+            "function #0: instr 5's resolved target is pc 3, which has no label"
+        );
+    }
+
+    /// Each extracted section is exactly the bytes the reader loads it from
+    #[test]
+    fn test_extract_section() {
+        let data = fbril_data("test/fizz-buzz.json");
+        let bytes = &data;
+        let views = get_program_views(bytes).unwrap();
+        assert_eq!(extract_section(bytes, "strings"), Ok(views[0].var_store));
+        assert_eq!(
+            extract_section(bytes, "instrs"),
+            Ok(views[0].instrs.as_bytes())
+        );
+        assert_eq!(
+            extract_section(bytes, "arg_idxes_store"),
+            Ok(views[0].arg_idxes_store.as_bytes())
+        );
+        assert_eq!(extract_section(bytes, "header").unwrap().len(), 16);
+        assert!(
+            extract_section(bytes, "vars")
+                .unwrap_err()
+                .contains("no section named `vars`")
+        );
+        assert!(extract_section(&bytes[..bytes.len() - 8], "strings").is_ok());
+        assert!(
+            extract_section(&bytes[..bytes.len() - 8], "arg_slots").is_err()
+        );
+    }
+
+    /// A section whose size doesn't fit in a `usize` is reported as invalid
+    /// (rather than its size overflowing)
+    #[test]
+    fn test_huge_section_is_rejected() {
+        let mut data = fbril_data("test/fizz-buzz.json");
+        // (the `strings` field is the first field of the `Toc`)
+        let offset = size_of::<Header>();
+        let mut set_strings_count = |count: usize| {
+            data[offset..offset + 8].copy_from_slice(&count.to_le_bytes());
+            data.to_vec()
+        };
+
+        let bytes = set_strings_count(usize::MAX - 1);
+        assert_eq!(
+            section_layouts(&bytes).unwrap_err(),
+            format!(
+                "strings section claims {} items of 1 bytes, which is too \
+                big to be valid",
+                usize::MAX - 1
+            )
+        );
+        assert!(extract_section(&bytes, "strings").is_err());
+
+        // (a section which fits in a `usize` but not in the file is only
+        // reported when it's extracted)
+        let bytes = set_strings_count(usize::MAX / 2);
+        assert!(section_layouts(&bytes).is_ok());
+        assert!(
+            extract_section(&bytes, "instrs")
+                .unwrap_err()
+                .contains("but the file is only")
+        );
+    }
 }
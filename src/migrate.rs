@@ -0,0 +1,1046 @@
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
+
+use crate::memfile::{
+    AlignedBytes, encode_instr_stores, read_toc, section_range, slice_prefix,
+    validate_instr_view,
+};
+use crate::slots;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The layouts of `.fbril` files written by earlier versions of flat-bril,
+/// from oldest to newest: every layout before `InlineLabels` is version 0
+/// (i.e. was written before the layout was versioned).
+/// In all of them, index pairs are inclusive `(start, end)` pairs rather than
+/// `(offset, len)` pairs, and in all of them except `SeparateStores` &
+/// `InclusivePairs`, the header records the size of
+/// (at most `MAX_FUNCS`) function sections, each with its own `SectionToc`
+/// & stores, and in all of those except `Sections`, labels are
+/// pseudo-instructions in `instrs` rather than in a label table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyLayout {
+    /// The header has no `flags` (so every file is in the narrow format),
+    /// and each `Toc` ends at `instrs`
+    NoFlags,
+    /// Each `Toc` ends at `instrs` (i.e. there are no slots)
+    NoSlots,
+    /// Each `Toc` ends at `arg_slots` (i.e. there is no `ext_store`)
+    NoExtStore,
+    /// Version 1: each `Toc` has no `label_table`
+    InlineLabels,
+    /// Version 2: the functions don't share a function table & stores
+    Sections,
+    /// Version 3: the layout is the current one, except that the function
+    /// names, variables, labels & callees are in separate stores (described
+    /// by a `SeparateStoresToc`) rather than one string table
+    SeparateStores,
+    /// Version 4: the layout is the current one, except for its index pairs
+    InclusivePairs,
+}
+
+/// The no. of function sections whose sizes are recorded in the header
+/// of a file in a legacy layout
+const MAX_FUNCS: usize = 10;
+
+/// The table of contents at the start of each function section in the
+/// legacy layouts (each field stores the no. of elements in the
+/// corresponding slice of the function's `InstrView`, except for `num_slots`,
+/// which is stored directly). Only `Sections` has all of these fields:
+/// the older layouts' `Toc`s are converted to this by `upgrade_section`.
+#[derive(FromBytes, IntoBytes, Debug, Clone, Copy, Immutable, KnownLayout)]
+#[repr(C, packed)]
+struct SectionToc {
+    func_name: usize,
+    func_args: usize,
+    func_ret_ty: usize,
+    var_store: usize,
+    arg_idxes_store: usize,
+    labels_idxes_store: usize,
+    labels_store: usize,
+    funcs_store: usize,
+    instrs: usize,
+    label_table: usize,
+    num_slots: usize,
+    dest_slots: usize,
+    arg_slots: usize,
+    ext_store: usize,
+}
+
+/// The table of contents of a file in layout version 3 (each field stores
+/// the no. of elements in the corresponding section, as in `Toc`)
+#[derive(FromBytes, IntoBytes, Debug, Clone, Copy, Immutable, KnownLayout)]
+#[repr(C, packed)]
+struct SeparateStoresToc {
+    func_names: usize,
+    func_args: usize,
+    var_store: usize,
+    arg_idxes_store: usize,
+    labels_idxes_store: usize,
+    labels_store: usize,
+    funcs_store: usize,
+    instrs: usize,
+    label_table: usize,
+    dest_slots: usize,
+    arg_slots: usize,
+    ext_store: usize,
+}
+
+/// The sections of an `InstrView` in a legacy layout which contain index
+/// pairs, with those pairs converted to `(offset, len)` pairs
+/// (see `from_inclusive`)
+struct ConvertedPairs<P: IndexPair> {
+    func_args: Vec<FlatFuncArg<P>>,
+    arg_idxes_store: Vec<P>,
+    labels_idxes_store: Vec<P>,
+    instrs: Vec<FlatInstr<P>>,
+    label_table: Vec<FlatLabel<P>>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl LegacyLayout {
+    /// The no. of `usize` fields in each function's `Toc`
+    fn toc_len(self) -> usize {
+        match self {
+            LegacyLayout::NoFlags | LegacyLayout::NoSlots => 9,
+            LegacyLayout::NoExtStore => 12,
+            LegacyLayout::InlineLabels => 13,
+            LegacyLayout::Sections => 14,
+            LegacyLayout::SeparateStores => 12,
+            LegacyLayout::InclusivePairs => 9,
+        }
+    }
+
+    /// The no. of bytes in the header
+    fn header_len(self) -> usize {
+        let sizes_len = MAX_FUNCS * size_of::<u64>();
+        match self {
+            LegacyLayout::NoFlags => sizes_len,
+            LegacyLayout::NoSlots
+            | LegacyLayout::NoExtStore
+            | LegacyLayout::InlineLabels
+            | LegacyLayout::Sections => size_of::<u64>() + sizes_len,
+            LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs => {
+                size_of::<Header>()
+            }
+        }
+    }
+
+    /// A short description of the layout, which is printed by `migrate`
+    pub fn description(self) -> &'static str {
+        match self {
+            LegacyLayout::NoFlags => "no header flags, no slots",
+            LegacyLayout::NoSlots => "no slots",
+            LegacyLayout::NoExtStore => "no extension instructions",
+            LegacyLayout::InlineLabels => "version 1, no label table",
+            LegacyLayout::Sections => "version 2, one section per function",
+            LegacyLayout::SeparateStores => "version 3, no string table",
+            LegacyLayout::InclusivePairs => "version 4, inclusive index pairs",
+        }
+    }
+}
+
+/// Reads the `idx`-th `u64` in `data` (returns `None` if `data` is too short)
+fn read_u64(data: &[u8], idx: usize) -> Option<u64> {
+    let bytes = data.get(idx * 8..idx * 8 + 8)?;
+    Some(u64::from_ne_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads the flags & function sizes from the header of a file in `layout`
+/// (returns `None` for `SeparateStores` & `InclusivePairs`, whose headers
+/// have no sizes)
+fn read_legacy_header(
+    data: &[u8],
+    layout: LegacyLayout,
+) -> Option<(u64, [u64; MAX_FUNCS])> {
+    let (flags, first_size) = match layout {
+        LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs => {
+            return None;
+        }
+        LegacyLayout::NoFlags => (0, 0),
+        LegacyLayout::NoSlots | LegacyLayout::NoExtStore => {
+            (read_u64(data, 0)?, 1)
+        }
+        // (the version is dropped, so that the flags can be used as-is
+        // in the upgraded file's header)
+        LegacyLayout::InlineLabels | LegacyLayout::Sections => {
+            (read_u64(data, 0)? & ((1 << VERSION_SHIFT) - 1), 1)
+        }
+    };
+    let mut sizes = [0; MAX_FUNCS];
+    for (i, size) in sizes.iter_mut().enumerate() {
+        *size = read_u64(data, first_size + i)?;
+    }
+    Some((flags, sizes))
+}
+
+/// The no. of bytes occupied by a function section in `layout` whose `Toc`
+/// is `toc`, with index pairs of type `P`
+/// (returns `None` if the sizes overflow)
+fn section_len<P: IndexPair>(
+    toc: &[usize],
+    layout: LegacyLayout,
+) -> Option<usize> {
+    let element_sizes = [
+        1,                           // func_name
+        size_of::<FlatFuncArg<P>>(), // func_args
+        size_of::<FlatType>(),       // func_ret_ty
+        1,                           // var_store
+        size_of::<P>(),              // arg_idxes_store
+        size_of::<P>(),              // labels_idxes_store
+        1,                           // labels_store
+        1,                           // funcs_store
+        size_of::<FlatInstr<P>>(),   // instrs
+        0,                           // num_slots
+        size_of::<u32>(),            // dest_slots
+        size_of::<u32>(),            // arg_slots
+        1,                           // ext_store
+    ];
+    toc.iter().zip(element_sizes).try_fold(
+        layout.toc_len() * size_of::<usize>(),
+        |len, (count, size)| len.checked_add(count.checked_mul(size)?),
+    )
+}
+
+/// Checks whether the file `data` is consistent with `layout`, i.e. whether
+/// the size of each function recorded in the header matches the size
+/// computed from its `Toc`
+fn matches_layout(data: &[u8], layout: LegacyLayout) -> bool {
+    let Some((flags, sizes)) = read_legacy_header(data, layout) else {
+        return false;
+    };
+    if flags & !WIDE_FORMAT_FLAG != 0 {
+        return false;
+    }
+    let mut offset = layout.header_len();
+    for size in sizes.into_iter().filter(|&size| size != 0) {
+        let toc: Option<Vec<usize>> = (0..layout.toc_len())
+            .map(|i| {
+                let field = read_u64(data.get(offset..)?, i)?;
+                Some(field as usize)
+            })
+            .collect();
+        let Some(toc) = toc else {
+            return false;
+        };
+        let len = if flags & WIDE_FORMAT_FLAG != 0 {
+            section_len::<I64Pair>(&toc, layout)
+        } else {
+            section_len::<I32Pair>(&toc, layout)
+        };
+        if len != Some(size as usize) {
+            return false;
+        }
+        offset += size as usize;
+    }
+    offset <= data.len()
+}
+
+/// Works out which layout the file `data` is in: returns `None` if it's
+/// in the current layout, or an error if it's in a newer layout or
+/// doesn't match any of the legacy layouts
+pub fn detect_layout(data: &[u8]) -> Result<Option<LegacyLayout>, String> {
+    let Some(flags) = read_u64(data, 0) else {
+        return Err(format!(
+            "the file only has {} bytes, which is too short for a header",
+            data.len()
+        ));
+    };
+    let version = flags >> VERSION_SHIFT;
+    if version == FORMAT_VERSION {
+        return Ok(None);
+    } else if version == 1 {
+        return Ok(Some(LegacyLayout::InlineLabels));
+    } else if version == 2 {
+        return Ok(Some(LegacyLayout::Sections));
+    } else if version == 3 {
+        return Ok(Some(LegacyLayout::SeparateStores));
+    } else if version == 4 {
+        return Ok(Some(LegacyLayout::InclusivePairs));
+    } else if version > FORMAT_VERSION {
+        return Err(format!(
+            "file is in layout version {version}, which is newer than the \
+            current version ({FORMAT_VERSION})"
+        ));
+    }
+    // (the newest layouts are tried first, since a file in an older layout
+    // is very unlikely to be consistent with a newer one)
+    [
+        LegacyLayout::NoExtStore,
+        LegacyLayout::NoSlots,
+        LegacyLayout::NoFlags,
+    ]
+    .into_iter()
+    .find(|&layout| matches_layout(data, layout))
+    .map(Some)
+    .ok_or_else(|| {
+        "file isn't in any known layout (is it an `.fbril` file?)".to_string()
+    })
+}
+
+/// Converts the `Toc` at the start of a function section in `layout` into
+/// a `SectionToc`. Since the slots are recomputed afterwards, missing
+/// `dest_slots` & `arg_slots` are filled with zeros by `decode_section`
+/// (& the function is given enough slots for its parameters).
+/// The label table is left empty if the labels are still in `instrs`.
+fn upgrade_section(section: &[u8], layout: LegacyLayout) -> SectionToc {
+    let field = |i: usize| read_u64(section, i).unwrap_or(0) as usize;
+    let (num_slots, dest_slots, arg_slots, ext_store) = match layout {
+        LegacyLayout::NoFlags | LegacyLayout::NoSlots => {
+            (field(1).max(1), field(8), field(4), 0)
+        }
+        LegacyLayout::NoExtStore => (field(9), field(10), field(11), 0),
+        LegacyLayout::InlineLabels => {
+            (field(9), field(10), field(11), field(12))
+        }
+        LegacyLayout::Sections
+        | LegacyLayout::SeparateStores
+        | LegacyLayout::InclusivePairs => {
+            (field(10), field(11), field(12), field(13))
+        }
+    };
+    let label_table = match layout {
+        LegacyLayout::Sections => field(9),
+        _ => 0,
+    };
+    SectionToc {
+        func_name: field(0),
+        func_args: field(1),
+        func_ret_ty: field(2),
+        var_store: field(3),
+        arg_idxes_store: field(4),
+        labels_idxes_store: field(5),
+        labels_store: field(6),
+        funcs_store: field(7),
+        instrs: field(8),
+        label_table,
+        num_slots,
+        dest_slots,
+        arg_slots,
+        ext_store,
+    }
+}
+
+/// Splits a function section (starting with a `SectionToc`) into the slices
+/// of an `InstrView` (without checking that their contents are valid)
+fn parse_section<P: IndexPair>(
+    data: &[u8],
+) -> Result<InstrView<'_, P>, String> {
+    let (toc, buffer) = SectionToc::ref_from_prefix(data).map_err(|_| {
+        format!(
+            "table of contents needs {} bytes but only {} bytes remain",
+            size_of::<SectionToc>(),
+            data.len()
+        )
+    })?;
+
+    let (func_name, new_buffer) =
+        slice_prefix::<u8>(buffer, toc.func_name, "func_name")?;
+    let (func_args, new_buffer) =
+        slice_prefix::<FlatFuncArg<P>>(new_buffer, toc.func_args, "func_args")?;
+    let (func_ret_ty, new_buffer) = <FlatType>::read_from_prefix(new_buffer)
+        .map_err(|_| "func_ret_ty section is truncated".to_string())?;
+    let (var_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.var_store, "var_store")?;
+    let (arg_idxes_store, new_buffer) =
+        slice_prefix::<P>(new_buffer, toc.arg_idxes_store, "arg_idxes_store")?;
+    let (labels_idxes_store, new_buffer) = slice_prefix::<P>(
+        new_buffer,
+        toc.labels_idxes_store,
+        "labels_idxes_store",
+    )?;
+    let (labels_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.labels_store, "labels_store")?;
+    let (funcs_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.funcs_store, "funcs_store")?;
+    let (instrs, new_buffer) =
+        slice_prefix::<FlatInstr<P>>(new_buffer, toc.instrs, "instrs")?;
+    let (label_table, new_buffer) = slice_prefix::<FlatLabel<P>>(
+        new_buffer,
+        toc.label_table,
+        "label_table",
+    )?;
+    let (dest_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.dest_slots, "dest_slots")?;
+    let (arg_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.arg_slots, "arg_slots")?;
+    let (ext_store, _) =
+        slice_prefix::<u8>(new_buffer, toc.ext_store, "ext_store")?;
+
+    Ok(InstrView {
+        func_name,
+        func_args,
+        func_ret_ty,
+        var_store,
+        arg_idxes_store,
+        labels_idxes_store,
+        labels_store,
+        funcs_store,
+        instrs,
+        label_table,
+        num_slots: toc.num_slots,
+        dest_slots,
+        arg_slots,
+        ext_store,
+        resolved: &[],
+    })
+}
+
+/// Converts an inclusive `(start, end)` index pair, as stored in the legacy
+/// layouts, to an `(offset, len)` pair
+fn from_inclusive<P: IndexPair>(pair: P) -> Result<P, String> {
+    match pair.fields() {
+        (-1, -1) => Ok(pair),
+        (start, end) if 0 <= start && start <= end => Ok(P::from_idxes(Some(
+            (start as usize, (end - start + 1) as usize),
+        ))),
+        (start, end) => Err(format!(
+            "index pair ({start}, {end}) isn't a valid (start, end) pair"
+        )),
+    }
+}
+
+impl<P: IndexPair> ConvertedPairs<P> {
+    /// Converts the index pairs in the sections of `view`
+    fn of(view: &InstrView<P>) -> Result<Self, String> {
+        let pairs = |pairs: &[P]| -> Result<Vec<P>, String> {
+            pairs.iter().map(|&pair| from_inclusive(pair)).collect()
+        };
+        let func_args = view
+            .func_args
+            .iter()
+            .map(|&func_arg| {
+                Ok(FlatFuncArg {
+                    arg_name_idxes: from_inclusive(func_arg.arg_name_idxes)?,
+                    ..func_arg
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let instrs = view
+            .instrs
+            .iter()
+            .map(|&instr| {
+                Ok(FlatInstr {
+                    label: from_inclusive(instr.label)?,
+                    dest: from_inclusive(instr.dest)?,
+                    args: from_inclusive(instr.args)?,
+                    instr_labels: from_inclusive(instr.instr_labels)?,
+                    funcs: from_inclusive(instr.funcs)?,
+                    ..instr
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let label_table = view
+            .label_table
+            .iter()
+            .map(|&label| {
+                Ok(FlatLabel {
+                    label_idxes: from_inclusive(label.label_idxes)?,
+                    ..label
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(ConvertedPairs {
+            func_args,
+            arg_idxes_store: pairs(view.arg_idxes_store)?,
+            labels_idxes_store: pairs(view.labels_idxes_store)?,
+            instrs,
+            label_table,
+        })
+    }
+
+    /// `view` with its sections which contain index pairs replaced by
+    /// the converted ones
+    fn view<'a>(&'a self, view: InstrView<'a, P>) -> InstrView<'a, P> {
+        InstrView {
+            func_args: &self.func_args,
+            arg_idxes_store: &self.arg_idxes_store,
+            labels_idxes_store: &self.labels_idxes_store,
+            instrs: &self.instrs,
+            label_table: &self.label_table,
+            ..view
+        }
+    }
+}
+
+/// Decodes a function section in `layout` into an `InstrStore`
+/// (whose slots are recomputed, & whose labels are moved into the
+/// label table when it's encoded again)
+fn decode_section<P: IndexPair>(
+    section: &[u8],
+    layout: LegacyLayout,
+) -> Result<InstrStore, String> {
+    let toc = upgrade_section(section, layout);
+    let mut bytes = toc.as_bytes().to_vec();
+    bytes.extend_from_slice(&section[layout.toc_len() * size_of::<usize>()..]);
+    if matches!(layout, LegacyLayout::NoFlags | LegacyLayout::NoSlots) {
+        bytes.resize(bytes.len() + (toc.dest_slots + toc.arg_slots) * 4, 0);
+    }
+    let data = AlignedBytes::new(&bytes);
+    let instr_view = parse_section::<P>(&data)?;
+    let pairs = ConvertedPairs::of(&instr_view)?;
+    let instr_view = pairs.view(instr_view);
+    validate_instr_view(&instr_view, layout != LegacyLayout::Sections)?;
+    let mut instr_store = InstrStore::from(instr_view);
+    slots::assign_slots(&mut instr_store);
+    Ok(instr_store)
+}
+
+/// Decodes the functions of a file in layout version 3 or 4 (`layout`, where
+/// `data` is the part of the file after its `Header`) into `InstrStore`s,
+/// whose strings are interned into a string table when they're encoded again.
+/// The `resolved` section (if any) isn't read, since it's recomputed.
+fn decode_program<P: IndexPair>(
+    data: &[u8],
+    num_funcs: usize,
+    layout: LegacyLayout,
+) -> Result<Vec<InstrStore>, String> {
+    // (a version 4 file's string table is read as its `func_names`, & the
+    // other string stores are pointed at it below)
+    let (toc, buffer) = if layout == LegacyLayout::InclusivePairs {
+        let (toc, buffer) = read_toc(data)?;
+        let toc = SeparateStoresToc {
+            func_names: toc.strings,
+            func_args: toc.func_args,
+            var_store: 0,
+            arg_idxes_store: toc.arg_idxes_store,
+            labels_idxes_store: toc.labels_idxes_store,
+            labels_store: 0,
+            funcs_store: 0,
+            instrs: toc.instrs,
+            label_table: toc.label_table,
+            dest_slots: toc.dest_slots,
+            arg_slots: toc.arg_slots,
+            ext_store: toc.ext_store,
+        };
+        (toc, buffer)
+    } else {
+        SeparateStoresToc::read_from_prefix(data).map_err(|_| {
+            format!(
+                "table of contents needs {} bytes but only {} bytes remain",
+                size_of::<SeparateStoresToc>(),
+                data.len()
+            )
+        })?
+    };
+    let (func_table, new_buffer) =
+        slice_prefix::<FuncRecord<P>>(buffer, num_funcs, "function table")?;
+    let (func_names, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.func_names, "func_names")?;
+    let (func_args, new_buffer) =
+        slice_prefix::<FlatFuncArg<P>>(new_buffer, toc.func_args, "func_args")?;
+    let (var_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.var_store, "var_store")?;
+    let (arg_idxes_store, new_buffer) =
+        slice_prefix::<P>(new_buffer, toc.arg_idxes_store, "arg_idxes_store")?;
+    let (labels_idxes_store, new_buffer) = slice_prefix::<P>(
+        new_buffer,
+        toc.labels_idxes_store,
+        "labels_idxes_store",
+    )?;
+    let (labels_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.labels_store, "labels_store")?;
+    let (funcs_store, new_buffer) =
+        slice_prefix::<u8>(new_buffer, toc.funcs_store, "funcs_store")?;
+    let (instrs, new_buffer) =
+        slice_prefix::<FlatInstr<P>>(new_buffer, toc.instrs, "instrs")?;
+    let (label_table, new_buffer) = slice_prefix::<FlatLabel<P>>(
+        new_buffer,
+        toc.label_table,
+        "label_table",
+    )?;
+    let (dest_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.dest_slots, "dest_slots")?;
+    let (arg_slots, new_buffer) =
+        slice_prefix::<u32>(new_buffer, toc.arg_slots, "arg_slots")?;
+    let (ext_store, _) =
+        slice_prefix::<u8>(new_buffer, toc.ext_store, "ext_store")?;
+    let (var_store, labels_store, funcs_store) =
+        if layout == LegacyLayout::InclusivePairs {
+            (func_names, func_names, func_names)
+        } else {
+            (var_store, labels_store, funcs_store)
+        };
+
+    // (the index pairs in the shared sections are converted once, rather
+    // than once per function)
+    let sections = InstrView {
+        func_name: func_names,
+        func_args,
+        func_ret_ty: FlatType::NULL,
+        var_store,
+        arg_idxes_store,
+        labels_idxes_store,
+        labels_store,
+        funcs_store,
+        instrs,
+        label_table,
+        num_slots: 0,
+        dest_slots,
+        arg_slots,
+        ext_store,
+        resolved: &[],
+    };
+    let pairs = ConvertedPairs::of(&sections)?;
+    let sections = pairs.view(sections);
+
+    let decode_func = |record: FuncRecord<P>| {
+        let func_name = from_inclusive(record.name)?
+            .get()
+            .and_then(|(offset, len)| func_names.get(offset..offset + len))
+            .ok_or("function's name is out of bounds")?;
+        let num_instrs = record
+            .end_pc
+            .checked_sub(record.start_pc)
+            .ok_or("function ends before it starts".to_string())?;
+        let pcs = section_range(
+            record.start_pc,
+            num_instrs,
+            sections.instrs.len(),
+            "instrs",
+        )?;
+        let args = section_range(
+            record.first_arg,
+            record.num_args,
+            sections.func_args.len(),
+            "func_args",
+        )?;
+        let labels = section_range(
+            record.first_label,
+            record.num_labels,
+            sections.label_table.len(),
+            "label_table",
+        )?;
+        let instr_view = InstrView {
+            func_name,
+            func_args: &sections.func_args[args],
+            func_ret_ty: record.ret_ty,
+            instrs: &sections.instrs[pcs.clone()],
+            label_table: &sections.label_table[labels],
+            num_slots: record.num_slots,
+            dest_slots: &sections.dest_slots[pcs],
+            ..sections
+        };
+        validate_instr_view(&instr_view, false)?;
+        Ok(InstrStore::from(instr_view))
+    };
+    func_table
+        .iter()
+        .enumerate()
+        .map(|(idx, record)| {
+            decode_func(*record)
+                .map_err(|e: String| format!("function #{idx}: {e}"))
+        })
+        .collect()
+}
+
+/// Upgrades a file in layout version 3 or 4 (`layout`, whose contents are
+/// `data`) to the current layout, keeping its flags (i.e. its format &
+/// whether it has a `resolved` section)
+fn migrate_program(
+    data: &[u8],
+    layout: LegacyLayout,
+) -> Result<Vec<u8>, String> {
+    let aligned = AlignedBytes::new(data);
+    let data = &aligned;
+    let (header, rest) = Header::ref_from_prefix(data).map_err(|_| {
+        format!(
+            "header needs {} bytes but the file only has {} bytes",
+            size_of::<Header>(),
+            data.len()
+        )
+    })?;
+    // (the version is dropped, as in `read_legacy_header`)
+    let flags = header.flags & ((1 << VERSION_SHIFT) - 1);
+    let known_flags = WIDE_FORMAT_FLAG | COMPACT_FORMAT_FLAG | RESOLVED_FLAG;
+    if flags & !known_flags != 0 {
+        return Err(format!("header has unknown flags {:#x}", header.flags));
+    }
+    let num_funcs = usize::try_from(header.num_funcs).unwrap_or(usize::MAX);
+    let instr_stores = if flags & WIDE_FORMAT_FLAG != 0 {
+        decode_program::<I64Pair>(rest, num_funcs, layout)
+    } else if flags & COMPACT_FORMAT_FLAG != 0 {
+        decode_program::<I16Pair>(rest, num_funcs, layout)
+    } else {
+        decode_program::<I32Pair>(rest, num_funcs, layout)
+    }?;
+    encode_instr_stores(instr_stores, flags)
+}
+
+/// Upgrades the contents of an `.fbril` file written by an earlier version
+/// of flat-bril to the current layout (keeping its format, i.e. narrow or
+/// wide). Returns the layout that the file was in along with the upgraded
+/// file, or `None` if the file is already in the current layout.
+pub fn migrate_bytes(
+    data: &[u8],
+) -> Result<Option<(LegacyLayout, Vec<u8>)>, String> {
+    let Some(layout) = detect_layout(data)? else {
+        return Ok(None);
+    };
+    if let LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs = layout
+    {
+        let bytes = migrate_program(data, layout)?;
+        return Ok(Some((layout, bytes)));
+    }
+    let (flags, sizes) =
+        read_legacy_header(data, layout).expect("layout was already checked");
+    let mut offset = layout.header_len();
+    let mut instr_stores = vec![];
+    for (i, &size) in sizes.iter().enumerate().filter(|(_, size)| **size != 0) {
+        let section = &data[offset..offset + size as usize];
+        offset += size as usize;
+        let instr_store = if flags & WIDE_FORMAT_FLAG != 0 {
+            decode_section::<I64Pair>(section, layout)
+        } else {
+            decode_section::<I32Pair>(section, layout)
+        }
+        .map_err(|e| format!("function #{i}: {e}"))?;
+        instr_stores.push(instr_store);
+    }
+    let bytes = encode_instr_stores(instr_stores, flags)?;
+    Ok(Some((layout, bytes)))
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod migrate_tests {
+    use std::{fs::File, io::BufReader};
+
+    use zerocopy::{Immutable, IntoBytes, TryFromBytes};
+
+    use crate::memfile::{
+        AlignedBytes, extract_section, get_program_views, json_to_fbril_bytes,
+        pad_vec, read_header, read_toc,
+    };
+    use crate::migrate::{
+        LegacyLayout, MAX_FUNCS, SectionToc, SeparateStoresToc, detect_layout,
+        migrate_bytes,
+    };
+    use crate::types::{
+        FlatFuncArg, FlatInstr, FlatLabel, FlatType, FuncRecord, Header,
+        I32Pair, IndexPair, InstrStore, VERSION_SHIFT,
+    };
+
+    /// Writes a function as a section of a file in layout version 2
+    /// (i.e. its `SectionToc` followed by its stores), with its labels
+    /// left in its `instrs` (& the `label_table` field dropped from its
+    /// `Toc`) if `inline_labels` is true, as in layout version 1
+    fn section_bytes(store: InstrStore, inline_labels: bool) -> Vec<u8> {
+        let mut instrs: Vec<FlatInstr> = vec![];
+        let mut label_table: Vec<FlatLabel> = vec![];
+        let mut dest_slots: Vec<u32> = vec![];
+        for (instr, slot) in store.instrs.into_iter().zip(store.dest_slots) {
+            if instr.op == u32::MAX && !inline_labels {
+                label_table.push(FlatLabel {
+                    label_idxes: I32Pair::from_idxes(instr.label),
+                    pc: instrs.len(),
+                });
+            } else {
+                instrs.push(instr.into());
+                dest_slots.push(slot);
+            }
+        }
+        let func_name = pad_vec(store.func_name);
+        let func_args: Vec<FlatFuncArg> =
+            store.func_args.into_iter().map(FlatFuncArg::from).collect();
+        let var_store = pad_vec(store.var_store);
+        let pairs = |idxes: Vec<(usize, usize)>| -> Vec<I32Pair> {
+            idxes
+                .into_iter()
+                .map(|idx| I32Pair::from_idxes(Some(idx)))
+                .collect()
+        };
+        let arg_idxes_store = pairs(store.args_idxes_store);
+        let labels_idxes_store = pairs(store.labels_idxes_store);
+        let labels_store = pad_vec(store.labels_store);
+        let funcs_store = pad_vec(store.funcs_store);
+        let ext_store = pad_vec(store.ext_store);
+        let toc = SectionToc {
+            func_name: func_name.len(),
+            func_args: func_args.len(),
+            func_ret_ty: 1,
+            var_store: var_store.len(),
+            arg_idxes_store: arg_idxes_store.len(),
+            labels_idxes_store: labels_idxes_store.len(),
+            labels_store: labels_store.len(),
+            funcs_store: funcs_store.len(),
+            instrs: instrs.len(),
+            label_table: label_table.len(),
+            num_slots: store.num_slots,
+            dest_slots: dest_slots.len(),
+            arg_slots: store.arg_slots.len(),
+            ext_store: ext_store.len(),
+        };
+        let mut bytes = toc.as_bytes().to_vec();
+        if inline_labels {
+            bytes.drain(9 * 8..10 * 8);
+        }
+        bytes.extend(func_name);
+        bytes.extend(func_args.as_bytes());
+        bytes.extend(FlatType::from(store.func_ret_ty).as_bytes());
+        bytes.extend(var_store);
+        bytes.extend(arg_idxes_store.as_bytes());
+        bytes.extend(labels_idxes_store.as_bytes());
+        bytes.extend(labels_store);
+        bytes.extend(funcs_store);
+        bytes.extend(instrs.as_bytes());
+        bytes.extend(label_table.as_bytes());
+        bytes.extend(dest_slots.as_bytes());
+        bytes.extend(store.arg_slots.as_bytes());
+        bytes.extend(ext_store);
+        bytes
+    }
+
+    /// `store` with its index pairs converted to the inclusive `(start, end)`
+    /// pairs of the legacy layouts
+    fn inclusive_store(mut store: InstrStore) -> InstrStore {
+        let inclusive =
+            |(offset, len): (usize, usize)| (offset, offset + len - 1);
+        for instr in &mut store.instrs {
+            for idxes in [
+                &mut instr.label,
+                &mut instr.dest,
+                &mut instr.args,
+                &mut instr.instr_labels,
+                &mut instr.funcs,
+            ] {
+                *idxes = idxes.map(inclusive);
+            }
+        }
+        for func_arg in &mut store.func_args {
+            func_arg.arg_name_idxes = inclusive(func_arg.arg_name_idxes);
+        }
+        for idxes in store
+            .args_idxes_store
+            .iter_mut()
+            .chain(&mut store.labels_idxes_store)
+        {
+            *idxes = inclusive(*idxes);
+        }
+        store
+    }
+
+    /// The section called `name` of a (narrow-format) file in the current
+    /// layout, with its index pairs converted to inclusive `(start, end)`
+    /// pairs (& padded, as in the file)
+    fn inclusive_section(data: &[u8], name: &str) -> Vec<u8> {
+        fn convert<T: TryFromBytes + IntoBytes + Immutable>(
+            bytes: &[u8],
+            f: impl Fn(T) -> T,
+        ) -> Vec<u8> {
+            bytes
+                .chunks(size_of::<T>())
+                .flat_map(|chunk| {
+                    f(T::try_read_from_bytes(chunk).unwrap())
+                        .as_bytes()
+                        .to_vec()
+                })
+                .collect()
+        }
+        let inclusive = |pair: I32Pair| {
+            I32Pair::from_idxes(
+                pair.get().map(|(offset, len)| (offset, offset + len - 1)),
+            )
+        };
+        let bytes = extract_section(data, name).unwrap();
+        pad_vec(match name {
+            "func_table" => convert(bytes, |record: FuncRecord| FuncRecord {
+                name: inclusive(record.name),
+                ..record
+            }),
+            "func_args" => {
+                convert(bytes, |func_arg: FlatFuncArg| FlatFuncArg {
+                    arg_name_idxes: inclusive(func_arg.arg_name_idxes),
+                    ..func_arg
+                })
+            }
+            "arg_idxes_store" | "labels_idxes_store" => {
+                convert(bytes, inclusive)
+            }
+            "instrs" => convert(bytes, |instr: FlatInstr| FlatInstr {
+                label: inclusive(instr.label),
+                dest: inclusive(instr.dest),
+                args: inclusive(instr.args),
+                instr_labels: inclusive(instr.instr_labels),
+                funcs: inclusive(instr.funcs),
+                ..instr
+            }),
+            "label_table" => convert(bytes, |label: FlatLabel| FlatLabel {
+                label_idxes: inclusive(label.label_idxes),
+                ..label
+            }),
+            _ => bytes.to_vec(),
+        })
+    }
+
+    /// Rewrites a file in the current layout into layout version 3 (whose
+    /// function names, variables, labels & callees are all stored in a copy
+    /// of the string table) or 4 (`layout`)
+    fn shared_stores_bytes(data: &[u8], layout: LegacyLayout) -> Vec<u8> {
+        let (header, rest) = read_header(data).unwrap();
+        let (toc, _) = read_toc(rest).unwrap();
+        let (version, toc, sections) = match layout {
+            LegacyLayout::SeparateStores => {
+                let toc = SeparateStoresToc {
+                    func_names: toc.strings,
+                    func_args: toc.func_args,
+                    var_store: toc.strings,
+                    arg_idxes_store: toc.arg_idxes_store,
+                    labels_idxes_store: toc.labels_idxes_store,
+                    labels_store: toc.strings,
+                    funcs_store: toc.strings,
+                    instrs: toc.instrs,
+                    label_table: toc.label_table,
+                    dest_slots: toc.dest_slots,
+                    arg_slots: toc.arg_slots,
+                    ext_store: toc.ext_store,
+                };
+                let sections = vec![
+                    "func_table",
+                    "strings",
+                    "func_args",
+                    "strings",
+                    "arg_idxes_store",
+                    "labels_idxes_store",
+                    "strings",
+                    "strings",
+                    "instrs",
+                    "label_table",
+                    "dest_slots",
+                    "arg_slots",
+                    "ext_store",
+                ];
+                (3, toc.as_bytes().to_vec(), sections)
+            }
+            LegacyLayout::InclusivePairs => {
+                let sections = vec![
+                    "func_table",
+                    "strings",
+                    "func_args",
+                    "arg_idxes_store",
+                    "labels_idxes_store",
+                    "instrs",
+                    "label_table",
+                    "dest_slots",
+                    "arg_slots",
+                    "ext_store",
+                ];
+                (4, toc.as_bytes().to_vec(), sections)
+            }
+            _ => unreachable!(),
+        };
+        let current_version = header.flags >> VERSION_SHIFT << VERSION_SHIFT;
+        let header = Header {
+            flags: header.flags - current_version + (version << VERSION_SHIFT),
+            num_funcs: header.num_funcs,
+        };
+        let mut bytes = header.as_bytes().to_vec();
+        bytes.extend(toc);
+        for section in sections {
+            bytes.extend(inclusive_section(data, section));
+        }
+        bytes
+    }
+
+    /// Rewrites a (narrow-format) file in the current layout into `layout`,
+    /// dropping the `Toc` fields & sections that `layout` doesn't have
+    fn downgrade(data: &[u8], layout: LegacyLayout) -> Vec<u8> {
+        let aligned = AlignedBytes::new(data);
+        let data = &aligned;
+        if let LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs =
+            layout
+        {
+            return shared_stores_bytes(data, layout);
+        }
+        let views = get_program_views(data).expect("valid file should load");
+        let word = |data: &[u8], i: usize| {
+            u64::from_ne_bytes(data[i * 8..i * 8 + 8].try_into().unwrap())
+        };
+        let sections = views.iter().map(|view| {
+            let store = inclusive_store(InstrStore::from(view.clone()));
+            let section =
+                section_bytes(store, layout != LegacyLayout::Sections);
+            let (toc_len, slots_len) = match layout {
+                LegacyLayout::Sections | LegacyLayout::InlineLabels => {
+                    return section;
+                }
+                LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs => {
+                    unreachable!()
+                }
+                LegacyLayout::NoExtStore => (12, 0),
+                LegacyLayout::NoSlots | LegacyLayout::NoFlags => {
+                    let (dest_slots, arg_slots) =
+                        (word(&section, 10), word(&section, 11));
+                    (9, (dest_slots + arg_slots) as usize * 4)
+                }
+            };
+            let mut downgraded = section[..toc_len * 8].to_vec();
+            downgraded
+                .extend_from_slice(&section[13 * 8..section.len() - slots_len]);
+            downgraded
+        });
+        let sections: Vec<Vec<u8>> = sections.collect();
+
+        let mut bytes = match layout {
+            LegacyLayout::NoFlags => vec![],
+            LegacyLayout::NoSlots | LegacyLayout::NoExtStore => {
+                0u64.to_ne_bytes().to_vec()
+            }
+            LegacyLayout::InlineLabels => {
+                (1u64 << VERSION_SHIFT).to_ne_bytes().to_vec()
+            }
+            LegacyLayout::Sections => {
+                (2u64 << VERSION_SHIFT).to_ne_bytes().to_vec()
+            }
+            LegacyLayout::SeparateStores | LegacyLayout::InclusivePairs => {
+                unreachable!()
+            }
+        };
+        let mut sizes = [0u64; MAX_FUNCS];
+        for (size, section) in sizes.iter_mut().zip(&sections) {
+            *size = section.len() as u64;
+        }
+        for size in sizes {
+            bytes.extend(size.to_ne_bytes());
+        }
+        bytes.extend(sections.concat());
+        bytes
+    }
+
+    /// Files in each of the legacy layouts are detected & migrated to
+    /// exactly the same bytes as flattening the program afresh
+    #[test]
+    fn test_migrate_legacy_layouts() {
+        let file = File::open("test/check-primes.json").unwrap();
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file)).unwrap();
+        let current = json_to_fbril_bytes(&json);
+        assert_eq!(detect_layout(&current), Ok(None));
+        assert_eq!(migrate_bytes(&current), Ok(None));
+
+        for layout in [
+            LegacyLayout::NoFlags,
+            LegacyLayout::NoSlots,
+            LegacyLayout::NoExtStore,
+            LegacyLayout::InlineLabels,
+            LegacyLayout::Sections,
+            LegacyLayout::SeparateStores,
+            LegacyLayout::InclusivePairs,
+        ] {
+            let legacy = downgrade(&current, layout);
+            assert_eq!(detect_layout(&legacy), Ok(Some(layout)));
+            assert_eq!(
+                migrate_bytes(&legacy),
+                Ok(Some((layout, current.clone())))
+            );
+        }
+    }
+}
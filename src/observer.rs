@@ -0,0 +1,311 @@
+#![allow(unused_variables)]
+use crate::interp::Environment;
+use crate::types::*;
+
+/// Callbacks which the interpreter invokes while running a program,
+/// allowing profilers, tracers, coverage tools, debuggers etc. to be
+/// built outside of the core interpreter loop.
+/// - All methods have empty default implementations, so implementors
+///   only need to override the events they care about
+/// - `instr_view` identifies the function that is currently executing,
+///   and `pc` is an index into `instr_view.instrs`
+/// - Methods are generic over the type of index pairs `P`, so that the same
+///   observer works for programs in the default & wide formats
+pub trait Observer {
+    /// Whether the observer ignores every event, in which case the
+    /// interpreter may run pre-decoded instructions without notifying it
+    /// (see `predecode.rs`)
+    const IGNORES_EVENTS: bool = false;
+
+    /// Called before the instruction at `pc` is executed
+    /// (labels aren't instructions, so this is never called for them)
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+    }
+
+    /// Called when the function `callee` is entered (including `main`),
+    /// where `env` contains the arguments bound to the function's parameters
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        env: &Environment,
+    ) {
+    }
+
+    /// Called when the function `callee` returns (with `value`, if any)
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        value: Option<BrilValue>,
+    ) {
+    }
+
+    /// Called when the `br` instruction at `pc` is executed, where
+    /// `taken` is the value of the branch condition & `target_pc` is the
+    /// PC execution continues from
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+    }
+
+    /// Called when the `jmp` instruction at `pc` is executed
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+    }
+
+    /// Called after a back edge (a `jmp` or `br` to a label at or before it)
+    /// is taken, before the loop header at `header_pc` is executed.
+    /// An observer which can run the loop itself (e.g. by compiling it, see
+    /// `jit.rs`) may execute up to `max_steps` of its instructions, updating
+    /// `env`, & then return where the interpreter should resume.
+    /// By default, the interpreter carries on as normal.
+    fn on_back_edge<'a, P: IndexPair>(
+        &mut self,
+        instr_view: &'a InstrView<P>,
+        header_pc: usize,
+        env: &mut Environment<'a>,
+        max_steps: u64,
+    ) -> Option<LoopExit> {
+        None
+    }
+}
+
+/// Where the interpreter resumes after an observer runs a loop
+/// (see `Observer::on_back_edge`)
+/// - `pc` is the next instruction to execute
+/// - `current_label` & `next_label` are the indexes in the label table of
+///   the label of the block that was executing & of the next label that
+///   will be reached, as in `interp::interp_instrs`
+/// - `steps` is the no. of instructions the observer executed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopExit {
+    pub pc: usize,
+    pub current_label: Option<usize>,
+    pub next_label: usize,
+    pub steps: u64,
+}
+
+/// The default observer, which ignores all events
+/// (since all of its methods are empty, they're optimized away entirely)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoObserver;
+
+impl Observer for NoObserver {
+    const IGNORES_EVENTS: bool = true;
+}
+
+/// Forwarding impl, so that callers can lend an observer to the interpreter
+/// and inspect it after the run
+impl<T: Observer + ?Sized> Observer for &mut T {
+    const IGNORES_EVENTS: bool = T::IGNORES_EVENTS;
+
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        (**self).on_instr(instr_view, pc, instr, env)
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        env: &Environment,
+    ) {
+        (**self).on_call(callee, env)
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        value: Option<BrilValue>,
+    ) {
+        (**self).on_return(callee, value)
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        (**self).on_branch(instr_view, pc, taken, target_pc)
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        (**self).on_jump(instr_view, pc, target_pc)
+    }
+
+    fn on_back_edge<'a, P: IndexPair>(
+        &mut self,
+        instr_view: &'a InstrView<P>,
+        header_pc: usize,
+        env: &mut Environment<'a>,
+        max_steps: u64,
+    ) -> Option<LoopExit> {
+        (**self).on_back_edge(instr_view, header_pc, env, max_steps)
+    }
+}
+
+/// An optional observer forwards events only if it's present, so that
+/// tools which are enabled by command-line flags can be composed
+impl<T: Observer> Observer for Option<T> {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        if let Some(observer) = self {
+            observer.on_instr(instr_view, pc, instr, env)
+        }
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        env: &Environment,
+    ) {
+        if let Some(observer) = self {
+            observer.on_call(callee, env)
+        }
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        value: Option<BrilValue>,
+    ) {
+        if let Some(observer) = self {
+            observer.on_return(callee, value)
+        }
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        if let Some(observer) = self {
+            observer.on_branch(instr_view, pc, taken, target_pc)
+        }
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        if let Some(observer) = self {
+            observer.on_jump(instr_view, pc, target_pc)
+        }
+    }
+
+    fn on_back_edge<'a, P: IndexPair>(
+        &mut self,
+        instr_view: &'a InstrView<P>,
+        header_pc: usize,
+        env: &mut Environment<'a>,
+        max_steps: u64,
+    ) -> Option<LoopExit> {
+        self.as_mut()?
+            .on_back_edge(instr_view, header_pc, env, max_steps)
+    }
+}
+
+/// A pair of observers is an observer which forwards every event to
+/// both components (in order), so several tools can watch the same run
+/// (only one of them can run a loop, so the second is only offered a loop
+/// if the first doesn't run it)
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    const IGNORES_EVENTS: bool = A::IGNORES_EVENTS && B::IGNORES_EVENTS;
+
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        env: &Environment,
+    ) {
+        self.0.on_instr(instr_view, pc, instr, env);
+        self.1.on_instr(instr_view, pc, instr, env);
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        env: &Environment,
+    ) {
+        self.0.on_call(callee, env);
+        self.1.on_call(callee, env);
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        value: Option<BrilValue>,
+    ) {
+        self.0.on_return(callee, value);
+        self.1.on_return(callee, value);
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        self.0.on_branch(instr_view, pc, taken, target_pc);
+        self.1.on_branch(instr_view, pc, taken, target_pc);
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        self.0.on_jump(instr_view, pc, target_pc);
+        self.1.on_jump(instr_view, pc, target_pc);
+    }
+
+    fn on_back_edge<'a, P: IndexPair>(
+        &mut self,
+        instr_view: &'a InstrView<P>,
+        header_pc: usize,
+        env: &mut Environment<'a>,
+        max_steps: u64,
+    ) -> Option<LoopExit> {
+        self.0
+            .on_back_edge(instr_view, header_pc, env, max_steps)
+            .or_else(|| {
+                self.1.on_back_edge(instr_view, header_pc, env, max_steps)
+            })
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::fusion::FusedOp;
+use crate::interp::Environment;
+use crate::observer::Observer;
+use crate::printer::pad_right;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of times instructions with a particular opcode were executed
+/// (`share` is the fraction of all executed instructions)
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpCount {
+    pub op: String,
+    pub count: u64,
+    pub share: f64,
+}
+
+/// A histogram of the opcodes executed by a run (printed by
+/// `--profile-ops`), most frequently executed opcode first
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpHistogram {
+    pub total: u64,
+    pub ops: Vec<OpCount>,
+}
+
+/// An `Observer` which counts how many times each opcode is executed
+/// - `counts` is indexed by opcode (it's grown as new opcodes are seen)
+/// - Superinstructions (see `fusion`) are counted separately in `other`, by
+///   the opcodes of the pair of instructions they fuse (e.g. `lt+br`),
+///   as are extension instructions
+#[derive(Debug, Default)]
+pub struct OpProfiler {
+    counts: Vec<u64>,
+    other: HashMap<String, u64>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl OpProfiler {
+    /// Creates a profiler which hasn't observed anything yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The histogram of everything observed so far
+    pub fn histogram(&self) -> OpHistogram {
+        let mut ops: Vec<(String, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(op, count)| {
+                let name = Opcode::u32_to_opcode(op as u32)
+                    .map_or_else(|| format!("op {op}"), |op| op.to_string());
+                (name, *count)
+            })
+            .chain(
+                self.other
+                    .iter()
+                    .map(|(name, count)| (name.clone(), *count)),
+            )
+            .collect();
+        ops.sort_by(|(op1, count1), (op2, count2)| {
+            (count2, op1).cmp(&(count1, op2))
+        });
+        let total = ops.iter().map(|(_, count)| count).sum();
+        let ops = ops
+            .into_iter()
+            .map(|(op, count)| OpCount {
+                op,
+                count,
+                share: count as f64 / total as f64,
+            })
+            .collect();
+        OpHistogram { total, ops }
+    }
+}
+
+impl Observer for OpProfiler {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        let op = instr.op();
+        if let Some((_, first)) = FusedOp::decode(op) {
+            // (the second instruction of the pair is left in place, after
+            // the superinstruction)
+            let second = instr_view
+                .instrs
+                .get(pc + 1)
+                .and_then(|next| Opcode::u32_to_opcode(next.op()))
+                .map_or("?".to_string(), |op| op.to_string());
+            *self.other.entry(format!("{first}+{second}")).or_default() += 1;
+            return;
+        }
+        if Opcode::u32_to_opcode(op).is_none() {
+            *self.other.entry("extension".to_string()).or_default() += 1;
+            return;
+        }
+        let idx = op as usize;
+        if idx >= self.counts.len() {
+            self.counts.resize(idx + 1, 0);
+        }
+        self.counts[idx] += 1;
+    }
+}
+
+impl OpHistogram {
+    /// Serializes the histogram as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("unable to serialize opcode histogram")
+    }
+
+    /// Renders the histogram as a table, followed by the total
+    pub fn to_text(&self) -> String {
+        let op_width = self
+            .ops
+            .iter()
+            .map(|op| op.op.len())
+            .max()
+            .unwrap_or(0)
+            .max("OPCODE".len());
+        let mut text = format!(
+            "{}  {:>12}  {:>7}\n",
+            pad_right("OPCODE", op_width),
+            "COUNT",
+            "SHARE"
+        );
+        for op in &self.ops {
+            writeln!(
+                text,
+                "{}  {:>12}  {:>6.1}%",
+                pad_right(&op.op, op_width),
+                op.count,
+                op.share * 100.0
+            )
+            .unwrap();
+        }
+        writeln!(text, "{} instructions executed", self.total).unwrap();
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod op_profile_tests {
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+    use crate::op_profile::OpProfiler;
+
+    /// Every executed instruction is counted under its opcode, and the
+    /// most frequent opcodes come first
+    #[test]
+    fn test_op_histogram() {
+        let json_str = std::fs::read_to_string("test/call-with-args.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let mut profiler = OpProfiler::new();
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut profiler);
+        interp_program(&program, vec![], &mut ctx).expect("program should run");
+
+        let histogram = profiler.histogram();
+        assert_eq!(histogram.total, 9);
+        let ops: Vec<(&str, u64)> = histogram
+            .ops
+            .iter()
+            .map(|op| (op.op.as_str(), op.count))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                ("const", 3),
+                ("print", 3),
+                ("add", 1),
+                ("call", 1),
+                ("ret", 1)
+            ]
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&histogram.to_json()).unwrap();
+        assert_eq!(json["ops"][0]["op"], "const");
+        assert!(histogram.to_text().ends_with("9 instructions executed\n"));
+    }
+}
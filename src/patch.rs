@@ -0,0 +1,271 @@
+use zerocopy::IntoBytes;
+
+use crate::flatten::validate_flat_instrs;
+use crate::memfile::{FlatProgram, ProgramViews, load_program};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The contents of a flat Bril file (e.g. a writable mmap, see
+/// `memfile::mmap_existing_file_mut`) whose instructions can be patched
+/// in place, without re-serializing the program
+/// - Instructions are addressed by the name of their function & their pc
+///   within it (as printed by `disasm --pcs`)
+/// - A patch only rewrites the bytes of the instruction it changes. After
+///   each patch, the file is loaded & the patched function is checked again
+///   (see `flatten::validate_flat_instrs`): if it's no longer valid, the
+///   instruction is restored & an error is returned, so the file can always
+///   still be loaded & interpreted
+pub struct PatchView<'a> {
+    data: &'a mut [u8],
+}
+
+/// Where a patched instruction is stored in the file, along with its bytes
+/// before & after the patch
+struct EncodedPatch {
+    offset: usize,
+    old_bytes: Vec<u8>,
+    new_bytes: Vec<u8>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a> PatchView<'a> {
+    /// Wraps the contents of a flat Bril file (in any format), checking that
+    /// it can be loaded first
+    pub fn new(data: &'a mut [u8]) -> Result<Self, String> {
+        load_program(data)?;
+        Ok(Self { data })
+    }
+
+    /// The (patched) contents of the file
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    /// Replaces the opcode of instruction `pc` of `@func` with `op`, keeping
+    /// its other fields (e.g. to swap `add` for `sub`, or `jmp` for `br`)
+    pub fn set_op(
+        &mut self,
+        func: &str,
+        pc: usize,
+        op: Opcode,
+    ) -> Result<(), String> {
+        self.patch_instr(func, pc, |instr| {
+            if instr.op == EXT_OP {
+                return Err("extension instructions have no opcode to replace"
+                    .to_string());
+            }
+            instr.op = op as u32;
+            Ok(())
+        })
+    }
+
+    /// Replaces the value of the `const` at instruction `pc` of `@func` with
+    /// `value`, which must have the same type as the `const`
+    pub fn set_value(
+        &mut self,
+        func: &str,
+        pc: usize,
+        value: BrilValue,
+    ) -> Result<(), String> {
+        self.patch_instr(func, pc, |instr| {
+            if instr.op != Opcode::Const as u32 {
+                return Err("it isn't a `const`".to_string());
+            }
+            if !matches!(value, BrilValue::IntVal(_) | BrilValue::BoolVal(_)) {
+                return Err(format!("{value} can't be stored in a `const`"));
+            }
+            if instr.ty != Some(value.get_type()) {
+                let ty = instr
+                    .ty
+                    .map_or("no type".to_string(), |ty| format!("type {ty}"));
+                return Err(format!(
+                    "it has {ty}, but {value} is of type {}",
+                    value.get_type()
+                ));
+            }
+            instr.value = Some(value);
+            Ok(())
+        })
+    }
+
+    /// Replaces instruction `pc` of `@func` with a `nop` (which has no dest,
+    /// args, labels or funcs)
+    pub fn nop_out(&mut self, func: &str, pc: usize) -> Result<(), String> {
+        self.patch_instr(func, pc, |instr| {
+            *instr = Instr {
+                op: Opcode::Nop as u32,
+                label: None,
+                dest: None,
+                ty: None,
+                value: None,
+                args: None,
+                instr_labels: None,
+                funcs: None,
+            };
+            Ok(())
+        })
+    }
+
+    /// Rewrites instruction `pc` of `@func` using `patch` (which may reject
+    /// the instruction by returning an error), undoing the change if the
+    /// function is no longer valid afterwards
+    fn patch_instr(
+        &mut self,
+        func: &str,
+        pc: usize,
+        patch: impl FnOnce(&mut Instr) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let encoded = match load_program(self.data)? {
+            ProgramViews::Narrow(program) => {
+                encode_patch(self.data, &program, func, pc, patch)
+            }
+            ProgramViews::Wide(program) => {
+                encode_patch(self.data, &program, func, pc, patch)
+            }
+            ProgramViews::Compact(program) => {
+                encode_patch(self.data, &program, func, pc, patch)
+            }
+        }
+        .map_err(|msg| format!("can't patch instr {pc} of @{func}: {msg}"))?;
+
+        let range = encoded.offset..encoded.offset + encoded.new_bytes.len();
+        self.data[range.clone()].copy_from_slice(&encoded.new_bytes);
+        let checked =
+            load_program(self.data).and_then(|program| match program {
+                ProgramViews::Narrow(program) => check_func(&program, func),
+                ProgramViews::Wide(program) => check_func(&program, func),
+                ProgramViews::Compact(program) => check_func(&program, func),
+            });
+        checked.map_err(|msg| {
+            self.data[range].copy_from_slice(&encoded.old_bytes);
+            format!(
+                "patching instr {pc} of @{func} would make it invalid: {msg}"
+            )
+        })
+    }
+}
+
+/// Applies `patch` to (a copy of) instruction `pc` of `@func` in `program`,
+/// which was loaded from `data`, re-encoding it in the file's format
+fn encode_patch<P: IndexPair>(
+    data: &[u8],
+    program: &FlatProgram<P>,
+    func: &str,
+    pc: usize,
+    patch: impl FnOnce(&mut Instr) -> Result<(), String>,
+) -> Result<EncodedPatch, String> {
+    let view = program
+        .get(func)
+        .ok_or_else(|| "there's no such function".to_string())?;
+    let flat_instr = view.instrs.get(pc).ok_or_else(|| {
+        format!("the function only has {} instrs", view.instrs.len())
+    })?;
+    // (the function's instrs are a slice of `data`, so this is where
+    // instruction `pc` is stored in the file)
+    let offset = view.instrs.as_bytes().as_ptr().addr() - data.as_ptr().addr()
+        + pc * size_of::<FlatInstr<P>>();
+
+    let mut instr = Instr::from(*flat_instr);
+    patch(&mut instr)?;
+    Ok(EncodedPatch {
+        offset,
+        old_bytes: flat_instr.as_bytes().to_vec(),
+        new_bytes: FlatInstr::<P>::from(instr).as_bytes().to_vec(),
+    })
+}
+
+/// Checks that the instructions of `@func` are well-formed, e.g. that every
+/// instruction has the right no. of args & labels for its opcode
+/// (see `flatten::validate_flat_instrs`, which identifies instructions by
+/// their PCs, like the patches do)
+fn check_func<P: IndexPair>(
+    program: &FlatProgram<P>,
+    func: &str,
+) -> Result<(), String> {
+    let view = program
+        .get(func)
+        .ok_or_else(|| format!("@{func} is missing"))?;
+    validate_flat_instrs(&InstrStore::from(view.clone()))
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod patch_tests {
+    use serde_json::json;
+
+    use crate::interp::interp_from_bytes;
+    use crate::memfile::{AlignedBytes, json_to_fbril_bytes};
+    use crate::patch::PatchView;
+    use crate::types::{BrilValue, Opcode};
+
+    /// Patched instructions take effect when the file is interpreted, and
+    /// patches which would leave the function invalid are undone
+    #[test]
+    fn test_patch_instrs() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 3 },
+                { "op": "const", "dest": "y", "type": "int", "value": 4 },
+                { "op": "add", "dest": "z", "type": "int", "args": ["x", "y"] },
+                { "op": "print", "args": ["z"] },
+                { "op": "print", "args": ["x"] },
+            ]
+        }]});
+        let mut data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let mut patch = PatchView::new(&mut data).unwrap();
+
+        patch.set_value("main", 0, BrilValue::IntVal(10)).unwrap();
+        patch.set_op("main", 2, Opcode::Sub).unwrap();
+        patch.nop_out("main", 4).unwrap();
+        let output = interp_from_bytes(patch.data(), vec![]).unwrap();
+        assert_eq!(output.lines, vec!["6"]);
+
+        let unpatched = patch.data().to_vec();
+        // (a `const` can't hold a value of another type, `not` only takes
+        // one arg & `main` only has 5 instrs)
+        let bool_val = BrilValue::BoolVal(true.into());
+        assert!(patch.set_value("main", 1, bool_val).is_err());
+        let err = patch.set_op("main", 2, Opcode::Not).unwrap_err();
+        assert!(err.contains("would make it invalid"), "{err}");
+        assert!(patch.nop_out("main", 5).is_err());
+        assert!(patch.nop_out("missing", 0).is_err());
+        assert_eq!(patch.data(), unpatched);
+    }
+
+    /// Errors about patched instructions identify them by their PCs, which
+    /// don't count labels
+    #[test]
+    fn test_patch_error_uses_pc() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "label": "entry" },
+                { "op": "const", "dest": "x", "type": "int", "value": 3 },
+                { "op": "jmp", "labels": ["a"] },
+                { "label": "a" },
+                { "op": "jmp", "labels": ["b"] },
+                { "label": "b" },
+                { "op": "add", "dest": "y", "type": "int", "args": ["x", "x"] },
+                { "op": "print", "args": ["y"] },
+            ]
+        }]});
+        let mut data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let mut patch = PatchView::new(&mut data).unwrap();
+        let err = patch.set_op("main", 3, Opcode::Ret).unwrap_err();
+        assert_eq!(
+            err,
+            "patching instr 3 of @main would make it invalid: @main: \
+            instruction 3 (`ret`) takes at most 1 arg, but has 2"
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use crate::slots;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A peephole rewrite, which looks at a single instruction (along with the
+/// earlier instructions in its basic block, see `Window`) & returns the
+/// instruction to replace it with, if any.
+/// - Replacements may only use index pairs which already exist in the
+///   `InstrStore` (e.g. a sub-range of an instruction's args), which is
+///   what allows the flat instructions to be rewritten in place
+/// - Replacements must define the same `dest` as the original instruction
+pub trait Rewrite {
+    /// A short name for the rewrite
+    fn name(&self) -> &str;
+
+    /// Returns the instruction that `window.instr()` should be replaced with
+    fn rewrite(&self, window: &Window) -> Option<Instr>;
+}
+
+/// The instruction that is being rewritten (at index `pc` in `store.instrs`),
+/// along with the rest of its function
+/// - `block_start` is the index of the first instruction in the basic block
+///   containing `pc`
+pub struct Window<'a> {
+    pub store: &'a InstrStore,
+    pub pc: usize,
+    block_start: usize,
+}
+
+/// A set of peephole rewrites, which are tried in the order they
+/// were registered
+pub struct Peephole {
+    rewrites: Vec<Box<dyn Rewrite>>,
+}
+
+/// `y = id x; z = id y` ~> `z = id x`
+pub struct IdChain;
+
+/// `y = not x; z = not y` ~> `z = id x`
+pub struct DoubleNot;
+
+/// `c = const true; br c .l1 .l2` ~> `jmp .l1` (and similarly for `false`)
+pub struct ConstBranch;
+
+/// `zero = const 0; z = add x zero` ~> `z = id x` (and similarly for `0 + x`)
+pub struct AddZero;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a> Window<'a> {
+    /// The instruction being rewritten
+    pub fn instr(&self) -> &'a Instr {
+        &self.store.instrs[self.pc]
+    }
+
+    /// The opcode of the instruction at index `pc` (`None` for labels)
+    pub fn opcode(&self, pc: usize) -> Option<Opcode> {
+        Opcode::u32_to_opcode(self.store.instrs[pc].op)
+    }
+
+    /// The name of the variable stored at `idxes` in the `var_store`
+    pub fn var(&self, (offset, len): (usize, usize)) -> &'a str {
+        str::from_utf8(&self.store.var_store[offset..offset + len])
+            .expect("variable names are valid UTF-8")
+    }
+
+    /// The names of `instr`'s args
+    pub fn args(&self, instr: &Instr) -> Vec<&'a str> {
+        instr.args.map_or(vec![], |(offset, len)| {
+            self.store.args_idxes_store[offset..offset + len]
+                .iter()
+                .map(|&idxes| self.var(idxes))
+                .collect()
+        })
+    }
+
+    /// The index pair (into `args_idxes_store`) which refers to just
+    /// the `n`th arg of `instr`
+    pub fn nth_arg(&self, instr: &Instr, n: usize) -> Option<(usize, usize)> {
+        let (offset, len) = instr.args?;
+        (n < len).then_some((offset + n, 1))
+    }
+
+    /// The index of the most recent definition of `var` before `pc`
+    /// in the current basic block
+    pub fn local_def(&self, var: &str) -> Option<usize> {
+        (self.block_start..self.pc).rev().find(|&pc| {
+            self.store.instrs[pc]
+                .dest
+                .is_some_and(|dest| self.var(dest) == var)
+        })
+    }
+
+    /// Determines if `var` is (re)defined by the instruction at index `since`,
+    /// or by any instruction after it (& before `pc`)
+    pub fn redefined_since(&self, var: &str, since: usize) -> bool {
+        self.local_def(var).is_some_and(|pc| pc >= since)
+    }
+
+    /// The value of `var`, if it was most recently defined by a `const`
+    /// in the current basic block
+    pub fn local_const(&self, var: &str) -> Option<BrilValue> {
+        let def = self.local_def(var)?;
+        match self.opcode(def) {
+            Some(Opcode::Const) => self.store.instrs[def].value,
+            _ => None,
+        }
+    }
+
+    /// If the `n`th arg of the instruction being rewritten is defined by an
+    /// instruction with opcode `op` earlier in the block, and that
+    /// instruction's args haven't been redefined since, returns the
+    /// instruction
+    fn forwardable_def(&self, n: usize, op: Opcode) -> Option<&'a Instr> {
+        let arg = *self.args(self.instr()).get(n)?;
+        let def = self.local_def(arg)?;
+        let def_instr = &self.store.instrs[def];
+        let forwardable = self.opcode(def) == Some(op)
+            && self
+                .args(def_instr)
+                .iter()
+                .all(|arg| !self.redefined_since(arg, def));
+        forwardable.then_some(def_instr)
+    }
+}
+
+impl Rewrite for IdChain {
+    fn name(&self) -> &str {
+        "id-chain"
+    }
+
+    fn rewrite(&self, window: &Window) -> Option<Instr> {
+        if window.opcode(window.pc) != Some(Opcode::Id) {
+            return None;
+        }
+        let def = window.forwardable_def(0, Opcode::Id)?;
+        Some(Instr {
+            args: def.args,
+            ..window.instr().clone()
+        })
+    }
+}
+
+impl Rewrite for DoubleNot {
+    fn name(&self) -> &str {
+        "double-not"
+    }
+
+    fn rewrite(&self, window: &Window) -> Option<Instr> {
+        if window.opcode(window.pc) != Some(Opcode::Not) {
+            return None;
+        }
+        let def = window.forwardable_def(0, Opcode::Not)?;
+        Some(Instr {
+            op: Opcode::Id as u32,
+            args: def.args,
+            ..window.instr().clone()
+        })
+    }
+}
+
+impl Rewrite for ConstBranch {
+    fn name(&self) -> &str {
+        "const-branch"
+    }
+
+    fn rewrite(&self, window: &Window) -> Option<Instr> {
+        let instr = window.instr();
+        if window.opcode(window.pc) != Some(Opcode::Br) {
+            return None;
+        }
+        let cond = *window.args(instr).first()?;
+        let Some(BrilValue::BoolVal(b)) = window.local_const(cond) else {
+            return None;
+        };
+        let (offset, 2) = instr.instr_labels? else {
+            return None;
+        };
+        let target = if bool::from(b) { offset } else { offset + 1 };
+        Some(Instr {
+            op: Opcode::Jmp as u32,
+            args: None,
+            instr_labels: Some((target, 1)),
+            ..instr.clone()
+        })
+    }
+}
+
+impl Rewrite for AddZero {
+    fn name(&self) -> &str {
+        "add-zero"
+    }
+
+    fn rewrite(&self, window: &Window) -> Option<Instr> {
+        let instr = window.instr();
+        if window.opcode(window.pc) != Some(Opcode::Add) {
+            return None;
+        }
+        let args = window.args(instr);
+        if args.len() != 2 {
+            return None;
+        }
+        let is_zero = |n: usize| {
+            window.local_const(args[n]) == Some(BrilValue::IntVal(0))
+        };
+        let kept = if is_zero(1) {
+            0
+        } else if is_zero(0) {
+            1
+        } else {
+            return None;
+        };
+        Some(Instr {
+            op: Opcode::Id as u32,
+            args: Some(window.nth_arg(instr, kept)?),
+            ..instr.clone()
+        })
+    }
+}
+
+impl Peephole {
+    /// A `Peephole` without any rewrites
+    pub fn empty() -> Self {
+        Self { rewrites: vec![] }
+    }
+
+    /// A `Peephole` with the built-in rewrites
+    /// (`ConstBranch`, `AddZero`, `DoubleNot` & `IdChain`)
+    pub fn new() -> Self {
+        let mut peephole = Self::empty();
+        peephole
+            .register(ConstBranch)
+            .register(AddZero)
+            .register(DoubleNot)
+            .register(IdChain);
+        peephole
+    }
+
+    /// Adds a rewrite, which is tried after the existing ones
+    pub fn register(&mut self, rewrite: impl Rewrite + 'static) -> &mut Self {
+        self.rewrites.push(Box::new(rewrite));
+        self
+    }
+
+    /// The names of the registered rewrites, in the order they're tried
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.rewrites.iter().map(|rewrite| rewrite.name())
+    }
+
+    /// Rewrites the instructions in `store` in place, in a single forward
+    /// pass (so chains of rewrites are collapsed as they're encountered).
+    /// Each rewrite is tried once per instruction, on the result of the
+    /// previous rewrites. Returns the no. of rewrites that were applied.
+    /// - The variables' slots are recomputed afterwards, since rewrites
+    ///   can extend live ranges
+    pub fn run(&self, store: &mut InstrStore) -> usize {
+        let mut num_rewrites = 0;
+        let mut block_start = 0;
+        for pc in 0..store.instrs.len() {
+            let op = Opcode::u32_to_opcode(store.instrs[pc].op);
+            if op.is_none() {
+                // Labels start a new basic block (as do extension
+                // instructions, whose effects are unknown)
+                block_start = pc + 1;
+                continue;
+            }
+            for rewrite in &self.rewrites {
+                let window = Window {
+                    store,
+                    pc,
+                    block_start,
+                };
+                if let Some(instr) = rewrite.rewrite(&window)
+                    && instr != store.instrs[pc]
+                {
+                    store.instrs[pc] = instr;
+                    num_rewrites += 1;
+                }
+            }
+            let op = Opcode::u32_to_opcode(store.instrs[pc].op);
+            if matches!(op, Some(Opcode::Jmp | Opcode::Br | Opcode::Ret)) {
+                block_start = pc + 1;
+            }
+        }
+        if num_rewrites > 0 {
+            slots::assign_slots(store);
+        }
+        num_rewrites
+    }
+}
+
+impl Default for Peephole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod peephole_tests {
+    use serde_json::json;
+
+    use crate::flatten::flatten_instrs;
+    use crate::peephole::{Peephole, Rewrite, Window};
+    use crate::types::{BrilValue, Instr, Opcode};
+
+    /// A user-defined rewrite: `one = const 1; z = mul x one` ~> `z = id x`
+    struct MulOne;
+
+    impl Rewrite for MulOne {
+        fn name(&self) -> &str {
+            "mul-one"
+        }
+
+        fn rewrite(&self, window: &Window) -> Option<Instr> {
+            let instr = window.instr();
+            let args = window.args(instr);
+            let is_one = window.opcode(window.pc) == Some(Opcode::Mul)
+                && window.local_const(args[1]) == Some(BrilValue::IntVal(1));
+            is_one.then(|| Instr {
+                op: Opcode::Id as u32,
+                args: window.nth_arg(instr, 0),
+                ..instr.clone()
+            })
+        }
+    }
+
+    /// Each built-in rewrite fires (but not when an operand is redefined in
+    /// between), and user-defined rewrites can be registered
+    #[test]
+    fn test_rewrites() {
+        let func = json!({
+            "name": "main",
+            "args": [{ "name": "x", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "zero", "type": "int", "value": 0 },
+                { "op": "add", "dest": "a", "type": "int",
+                  "args": ["x", "zero"] },
+                { "op": "id", "dest": "b", "type": "int", "args": ["a"] },
+                { "op": "id", "dest": "c", "type": "int", "args": ["b"] },
+                { "op": "const", "dest": "t", "type": "bool", "value": true },
+                { "op": "not", "dest": "f", "type": "bool", "args": ["t"] },
+                { "op": "const", "dest": "t", "type": "bool", "value": false },
+                { "op": "not", "dest": "g", "type": "bool", "args": ["f"] },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "mul", "dest": "d", "type": "int",
+                  "args": ["c", "one"] },
+                { "op": "br", "args": ["t"], "labels": ["yes", "no"] },
+                { "label": "yes" },
+                { "op": "print", "args": ["d"] },
+                { "label": "no" },
+                { "op": "print", "args": ["g"] }
+            ]
+        });
+        let mut store = flatten_instrs(&func);
+        let mut peephole = Peephole::new();
+        peephole.register(MulOne);
+        assert_eq!(
+            peephole.names().collect::<Vec<_>>(),
+            [
+                "const-branch",
+                "add-zero",
+                "double-not",
+                "id-chain",
+                "mul-one"
+            ]
+        );
+        // `a = id x`, `b = id x` (after `a` is forwarded), `c = id x`,
+        // `d = id c`, & `br` ~> `jmp`. `g = not f` isn't rewritten since `t`
+        // is redefined after `f = not t`.
+        assert_eq!(peephole.run(&mut store), 5);
+
+        let ops: Vec<Option<Opcode>> = store
+            .instrs
+            .iter()
+            .map(|instr| Opcode::u32_to_opcode(instr.op))
+            .collect();
+        assert_eq!(ops[7], Some(Opcode::Not));
+        assert_eq!(ops[9], Some(Opcode::Id));
+        assert_eq!(ops[10], Some(Opcode::Jmp));
+        let window = Window {
+            store: &store,
+            pc: store.instrs.len() - 1,
+            block_start: 0,
+        };
+        for (pc, expected) in [(1, "x"), (2, "x"), (3, "x"), (9, "c")] {
+            assert_eq!(window.args(&store.instrs[pc]), [expected]);
+        }
+        let (offset, len) = store.instrs[10].instr_labels.unwrap();
+        assert_eq!(len, 1);
+        let (label_offset, label_len) = store.labels_idxes_store[offset];
+        assert_eq!(
+            &store.labels_store[label_offset..label_offset + label_len],
+            b"no"
+        );
+    }
+}
@@ -0,0 +1,672 @@
+use std::io::Write;
+
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::fusion::FusedOp;
+use crate::interp::{
+    Environment, FuncTable, InterpContext, call_depth_exceeded,
+    check_call_arity, check_param_type, eval_binop, eval_unop, get_func,
+    get_func_name, get_label_idx, get_labels_vec, get_n_labels,
+    get_table_label_name, get_var, missing_return_value, undefined_label,
+    unsupported_instr,
+};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+// The zero-copy interpreter (`interp::interp_instr_view`) reads each
+// instruction straight out of the file, so every time an instruction is
+// executed, its index pairs are turned back into strings & its variables are
+// looked up in a `HashMap` by name. Instead, each function can be decoded
+// once (the first time it's called) into `DecodedInstr`s, whose variables are
+// the function's slots (see `slots.rs`), whose `jmp` & `br` targets are
+// resolved, & whose callees are indexes in the `FuncTable`. Its environment
+// is then a window of `num_slots` values on a stack shared by all calls.
+// Names are only looked up again to report errors.
+
+/// An instruction whose operands have been resolved by `decode_func`
+/// - `dest`, `arg` & `cond` are slots in the function's environment
+/// - `args` are `(start, end)` ranges (end exclusive) of the instruction's
+///   args in `InstrView::arg_slots` (& `arg_idxes_store`, which holds their
+///   names)
+/// - `target`s are indexes in the function's label table
+/// - `callee` is the index of the called function in the `FuncTable`
+/// - `Invalid` is an instruction which can't be executed (e.g. a `jmp` to an
+///   undefined label), along with the index in `DecodedFunc::errors` of the
+///   error which it raises when it's executed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodedInstr {
+    Const {
+        dest: u32,
+        value: BrilValue,
+    },
+    Unop {
+        op: Opcode,
+        dest: u32,
+        arg: u32,
+    },
+    Binop {
+        op: Opcode,
+        dest: u32,
+        args: [u32; 2],
+    },
+    Print {
+        args: (u32, u32),
+    },
+    Jmp {
+        target: u32,
+    },
+    Br {
+        cond: u32,
+        targets: [u32; 2],
+    },
+    Call {
+        callee: u32,
+        dest: Option<u32>,
+        args: (u32, u32),
+    },
+    Ret {
+        arg: Option<u32>,
+    },
+    Phi {
+        dest: u32,
+    },
+    Nop,
+    Invalid {
+        error: u32,
+    },
+}
+
+/// The pre-decoded instructions of a function (see `decode_func`)
+/// - `label_pcs[i]` is the PC of the `i`th label in the label table
+/// - `has_phis` is whether the function has any `phi`s, which pick their
+///   arg by the label that control came from (the labels that are reached
+///   are only tracked if so)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFunc {
+    pub instrs: Vec<DecodedInstr>,
+    pub label_pcs: Vec<usize>,
+    pub errors: Vec<Diagnostic>,
+    pub num_slots: usize,
+    pub has_phis: bool,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// The error for an instruction whose `dest` is missing
+fn missing_dest(op: Opcode) -> Diagnostic {
+    Diagnostic::new(
+        ErrorCode::MalformedInstr,
+        format!("`{op}` instruction has no dest"),
+    )
+}
+
+/// Decodes the instruction `instr` at `pc` in `instr_view`, whose callees
+/// are looked up in `funcs` (returns the error that the zero-copy
+/// interpreter would raise when executing the instruction, if it can't be
+/// decoded). A superinstruction is decoded as its first instruction (see
+/// `fusion.rs`), since it's executed just as quickly once it's decoded.
+/// The targets & callee in `resolved` (if any) are used instead of looking
+/// them up by name.
+fn decode_instr<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    funcs: &FuncTable<'_, P>,
+    pc: usize,
+    instr: &FlatInstr<P>,
+    resolved: Option<&ResolvedOperands>,
+) -> Result<DecodedInstr, Diagnostic> {
+    let op = match Opcode::try_from(instr.op()) {
+        Ok(op) => op,
+        Err(msg) => {
+            return match FusedOp::decode(instr.op()) {
+                Some((_, first_op)) => {
+                    decode_op(instr_view, funcs, pc, instr, first_op, resolved)
+                }
+                None if instr.op() == EXT_OP => {
+                    Err(unsupported_instr(instr_view, instr))
+                }
+                None => Err(Diagnostic::new(ErrorCode::MalformedInstr, msg)),
+            };
+        }
+    };
+    decode_op(instr_view, funcs, pc, instr, op, resolved)
+}
+
+/// Decodes the instruction `instr` at `pc` in `instr_view`, whose opcode is
+/// `op` (see `decode_instr`)
+fn decode_op<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    funcs: &FuncTable<'_, P>,
+    pc: usize,
+    instr: &FlatInstr<P>,
+    op: Opcode,
+    resolved: Option<&ResolvedOperands>,
+) -> Result<DecodedInstr, Diagnostic> {
+    let dest = instr
+        .dest
+        .get()
+        .map(|_| instr_view.dest_slots[pc])
+        .ok_or_else(|| missing_dest(op));
+    let args = instr.args.get().map_or((0, 0), |(offset, len)| {
+        (offset as u32, (offset + len) as u32)
+    });
+    let arg_slots = &instr_view.arg_slots[args.0 as usize..args.1 as usize];
+    let malformed =
+        |msg: String| Err(Diagnostic::new(ErrorCode::MalformedInstr, msg));
+    let label_idx = |label: &str| {
+        get_label_idx(instr_view, label)
+            .map(|idx| idx as u32)
+            .ok_or_else(|| undefined_label(label))
+    };
+    // (the index of the first label at a resolved target, which is only
+    // used to find the target's pc, since `resolved` isn't used in
+    // functions with `phi`s)
+    let resolved_target = |k: usize| {
+        let target =
+            resolved.map_or(UNRESOLVED, |resolved| resolved.targets[k]);
+        (target != UNRESOLVED).then(|| {
+            let label_table = instr_view.label_table;
+            label_table.partition_point(|label| label.pc < target as usize)
+                as u32
+        })
+    };
+    let decoded = match op {
+        Opcode::Const => {
+            let Ok(value) = instr.value.try_into() else {
+                return malformed(
+                    "`const` instruction has no value".to_string(),
+                );
+            };
+            DecodedInstr::Const { dest: dest?, value }
+        }
+        _ if op.is_unop() => {
+            let &[arg] = arg_slots else {
+                return malformed(
+                    "unary instruction is malformed (no. of args != 1)"
+                        .to_string(),
+                );
+            };
+            DecodedInstr::Unop {
+                op,
+                dest: dest?,
+                arg,
+            }
+        }
+        _ if op.is_binop() => {
+            let &[left, right] = arg_slots else {
+                return malformed(format!("no. of args to `{op}` != 2"));
+            };
+            DecodedInstr::Binop {
+                op,
+                dest: dest?,
+                args: [left, right],
+            }
+        }
+        Opcode::Print => DecodedInstr::Print { args },
+        Opcode::Jmp => {
+            let Some([label]) = get_n_labels(instr_view, instr.instr_labels)
+            else {
+                return malformed(
+                    "no. of labels in jmp instr != 1".to_string(),
+                );
+            };
+            DecodedInstr::Jmp {
+                target: match resolved_target(0) {
+                    Some(target) => target,
+                    None => label_idx(label)?,
+                },
+            }
+        }
+        Opcode::Br => {
+            let &[cond] = arg_slots else {
+                return malformed(
+                    "br instruction must only have 1 arg".to_string(),
+                );
+            };
+            let Some([true_lbl, false_lbl]) =
+                get_n_labels(instr_view, instr.instr_labels)
+            else {
+                return malformed(
+                    "br instruction is malformed (has != 2 labels)".to_string(),
+                );
+            };
+            let targets = match (resolved_target(0), resolved_target(1)) {
+                (Some(true_target), Some(false_target)) => {
+                    [true_target, false_target]
+                }
+                _ => [label_idx(true_lbl)?, label_idx(false_lbl)?],
+            };
+            DecodedInstr::Br { cond, targets }
+        }
+        Opcode::Call
+            if let Some(resolved) = resolved
+                && (resolved.callee as usize) < funcs.len() =>
+        {
+            DecodedInstr::Call {
+                callee: resolved.callee,
+                dest: dest.ok(),
+                args,
+            }
+        }
+        Opcode::Call => {
+            let (funcs_offset, funcs_len) = instr.funcs.idxes();
+            let func_name = get_func(instr_view, funcs_offset, funcs_len);
+            let callee = funcs.index_of(func_name).ok_or_else(|| {
+                Diagnostic::new(
+                    ErrorCode::UndefinedFunction,
+                    format!("call to undefined function @{func_name}"),
+                )
+            })?;
+            DecodedInstr::Call {
+                callee: callee as u32,
+                dest: dest.ok(),
+                args,
+            }
+        }
+        Opcode::Ret => match arg_slots {
+            [] => DecodedInstr::Ret { arg: None },
+            &[arg] => DecodedInstr::Ret { arg: Some(arg) },
+            _ => {
+                return malformed(
+                    "too many args supplied to ret instruction".to_string(),
+                );
+            }
+        },
+        Opcode::Phi => DecodedInstr::Phi { dest: dest? },
+        Opcode::Nop => DecodedInstr::Nop,
+        _ => unreachable!("every opcode is decoded above"),
+    };
+    Ok(decoded)
+}
+
+/// Decodes every instruction of `instr_view`, whose callees are looked up in
+/// `funcs` (instructions which can't be decoded become `Invalid`, so their
+/// errors are only raised if they're executed)
+/// - If the function came from a file with `RESOLVED_FLAG`, its operands
+///   are taken from `instr_view.resolved` rather than looked up by name,
+///   unless it has `phi`s, which need to know which label each jump goes to
+///   (& compare label names when they're executed anyway)
+pub fn decode_func<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    funcs: &FuncTable<'_, P>,
+) -> DecodedFunc {
+    let has_phis = instr_view
+        .instrs
+        .iter()
+        .any(|instr| instr.op() == Opcode::Phi as u32);
+    let resolved = if has_phis { &[] } else { instr_view.resolved };
+    let mut errors = vec![];
+    let instrs = instr_view
+        .instrs
+        .iter()
+        .enumerate()
+        .map(|(pc, instr)| {
+            decode_instr(instr_view, funcs, pc, instr, resolved.get(pc))
+                .unwrap_or_else(|e| {
+                    errors.push(e);
+                    DecodedInstr::Invalid {
+                        error: errors.len() as u32 - 1,
+                    }
+                })
+        })
+        .collect::<Vec<_>>();
+    DecodedFunc {
+        instrs,
+        label_pcs: instr_view
+            .label_table
+            .iter()
+            .map(|label| label.pc)
+            .collect(),
+        errors,
+        num_slots: instr_view.num_slots,
+        has_phis,
+    }
+}
+
+/// The error for reading the `i`th arg in `instr_view.arg_idxes_store`
+/// while it's undefined
+#[cold]
+fn undefined_arg<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    i: usize,
+) -> Diagnostic {
+    let (offset, len) = instr_view.arg_idxes_store[i].idxes();
+    Diagnostic::new(
+        ErrorCode::UndefinedVariable,
+        format!("undefined variable `{}`", get_var(instr_view, offset, len)),
+    )
+}
+
+/// The `k`th arg of the instruction at `pc` in `instr_view`, as an index in
+/// `arg_idxes_store` (for reporting errors)
+fn arg_idx<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    pc: usize,
+    k: usize,
+) -> usize {
+    let args = instr_view.instrs[pc].args;
+    args.get().map_or(0, |(start, _)| start) + k
+}
+
+/// Interprets the function `idx` in `funcs` (e.g. `main`) by its pre-decoded
+/// instructions, where `env` binds its parameters (see
+/// `interp::bind_entry_args`), returning the value that it returns (if any)
+pub fn interp_entry_decoded<P: IndexPair, W: Write, O: Observer>(
+    funcs: &FuncTable<'_, P>,
+    idx: usize,
+    env: &Environment,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    let (view, func) = funcs.decoded(idx)?;
+    // (the `i`th parameter is always in slot `i`)
+    let mut stack: Vec<Option<BrilValue>> = vec![None; func.num_slots];
+    for (slot, flat_arg) in stack.iter_mut().zip(view.func_args) {
+        let (offset, len) = flat_arg.arg_name_idxes.idxes();
+        *slot = env.get(get_var(view, offset, len)).copied();
+    }
+    let result = interp_decoded(view, func, 0, &mut stack, funcs, ctx);
+    ctx.env_bytes = stack.capacity() * size_of::<Option<BrilValue>>();
+    result
+}
+
+/// Interprets the pre-decoded function `func` (whose `InstrView` is
+/// `instr_view`), whose environment is `stack[base..]`. Errors are located
+/// at the PC where they occurred (unless they occurred in a nested call).
+fn interp_decoded<P: IndexPair, W: Write, O: Observer>(
+    instr_view: &InstrView<P>,
+    func: &DecodedFunc,
+    base: usize,
+    stack: &mut Vec<Option<BrilValue>>,
+    funcs: &FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    let mut pc = 0;
+    run_decoded(instr_view, func, base, stack, funcs, ctx, &mut pc)
+        .map_err(|e| e.located(get_func_name(instr_view), pc))
+}
+
+/// The main interpreter loop for `interp_decoded`, which keeps `pc` up to
+/// date so that errors can be located
+fn run_decoded<P: IndexPair, W: Write, O: Observer>(
+    instr_view: &InstrView<P>,
+    func: &DecodedFunc,
+    base: usize,
+    stack: &mut Vec<Option<BrilValue>>,
+    funcs: &FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+    pc: &mut usize,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    // (the labels are only tracked for `phi`s, as in `interp::interp_instrs`)
+    let mut current_label: Option<usize> = None;
+    let mut prev_label: Option<usize> = None;
+    let mut next_label = 0;
+    while let Some(&instr) = func.instrs.get(*pc) {
+        if func.has_phis {
+            while let Some(&label_pc) = func.label_pcs.get(next_label)
+                && label_pc <= *pc
+            {
+                prev_label = current_label;
+                current_label = Some(next_label);
+                next_label += 1;
+            }
+        }
+        ctx.tick()?;
+        let read = |stack: &[Option<BrilValue>], slot: u32, k: usize| {
+            stack[base + slot as usize].ok_or_else(|| {
+                undefined_arg(instr_view, arg_idx(instr_view, *pc, k))
+            })
+        };
+        match instr {
+            DecodedInstr::Const { dest, value } => {
+                stack[base + dest as usize] = Some(value);
+            }
+            DecodedInstr::Unop { op, dest, arg } => {
+                let value = eval_unop(op, read(stack, arg, 0)?)?;
+                stack[base + dest as usize] = Some(value);
+            }
+            DecodedInstr::Binop { op, dest, args } => {
+                let x = read(stack, args[0], 0)?;
+                let y = read(stack, args[1], 1)?;
+                stack[base + dest as usize] = Some(eval_binop(op, x, y)?);
+            }
+            DecodedInstr::Print { args } => {
+                print_args(instr_view, args, &stack[base..], &mut ctx.out)?;
+            }
+            DecodedInstr::Jmp { target } => {
+                *pc = func.label_pcs[target as usize];
+                next_label = target as usize;
+                continue;
+            }
+            DecodedInstr::Br { cond, targets } => {
+                let BrilValue::BoolVal(cond) = read(stack, cond, 0)? else {
+                    return Err(Diagnostic::new(
+                        ErrorCode::TypeError,
+                        "argument to br instruction is ill-typed \
+                        (doesn't have type bool)",
+                    ));
+                };
+                let target = targets[if bool::from(cond) { 0 } else { 1 }];
+                *pc = func.label_pcs[target as usize];
+                next_label = target as usize;
+                continue;
+            }
+            DecodedInstr::Call { callee, dest, args } => {
+                let ret_value = call_decoded(
+                    instr_view,
+                    base,
+                    stack,
+                    funcs,
+                    ctx,
+                    callee as usize,
+                    args,
+                )?;
+                if let Some(dest) = dest {
+                    let (callee_view, _) = funcs.decoded(callee as usize)?;
+                    let ret_value = ret_value.ok_or_else(|| {
+                        missing_return_value(get_func_name(callee_view))
+                    })?;
+                    stack[base + dest as usize] = Some(ret_value);
+                }
+            }
+            DecodedInstr::Ret { arg: None } => return Ok(None),
+            DecodedInstr::Ret { arg: Some(arg) } => {
+                return read(stack, arg, 0).map(Some);
+            }
+            DecodedInstr::Phi { dest } => {
+                let prev_label =
+                    prev_label.map(|idx| get_table_label_name(instr_view, idx));
+                let value =
+                    phi_value(instr_view, *pc, &stack[base..], prev_label)?;
+                stack[base + dest as usize] = value;
+            }
+            DecodedInstr::Nop => {}
+            DecodedInstr::Invalid { error } => {
+                return Err(func.errors[error as usize].clone());
+            }
+        }
+        *pc += 1;
+    }
+    Ok(None)
+}
+
+/// Prints the values of the args `args` of a `print` in `instr_view`, whose
+/// environment is `env` (see `interp::interp_print`)
+fn print_args<P: IndexPair, W: Write>(
+    instr_view: &InstrView<P>,
+    (start, end): (u32, u32),
+    env: &[Option<BrilValue>],
+    out: &mut W,
+) -> Result<(), Diagnostic> {
+    let (start, end) = (start as usize, end as usize);
+    let slots = &instr_view.arg_slots[start..end];
+    // Check that all the args are defined before printing
+    // anything (so that we never print part of a line)
+    if let Some(k) = slots.iter().position(|&slot| env[slot as usize].is_none())
+    {
+        return Err(undefined_arg(instr_view, start + k));
+    }
+    let write_err = |e: std::io::Error| {
+        Diagnostic::new(
+            ErrorCode::Io,
+            format!("unable to write program output: {e}"),
+        )
+    };
+    for (i, &slot) in slots.iter().enumerate() {
+        let sep = if i == 0 { "" } else { " " };
+        out.write_all(sep.as_bytes()).map_err(write_err)?;
+        let value = env[slot as usize].expect("args were checked above");
+        value.write_bril(out).map_err(write_err)?;
+    }
+    writeln!(out).map_err(write_err)?;
+    Ok(())
+}
+
+/// The value which the `phi` at `pc` in `instr_view` assigns to its dest,
+/// where `prev_label` is the label of the block that control came from
+/// (see `interp::interp_phi`)
+fn phi_value<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    pc: usize,
+    env: &[Option<BrilValue>],
+    prev_label: Option<&str>,
+) -> Result<Option<BrilValue>, Diagnostic> {
+    let instr = &instr_view.instrs[pc];
+    let slots = instr.args.get().map_or(&[][..], |(offset, len)| {
+        &instr_view.arg_slots[offset..offset + len]
+    });
+    let labels = instr.instr_labels.get().map_or(vec![], |(offset, len)| {
+        get_labels_vec(instr_view, offset, len)
+    });
+    if slots.len() != labels.len() {
+        return Err(Diagnostic::new(
+            ErrorCode::MalformedInstr,
+            "phi instruction must have as many args as labels",
+        ));
+    }
+    Ok(prev_label
+        .and_then(|prev| labels.iter().position(|label| *label == prev))
+        .and_then(|i| env[slots[i] as usize]))
+}
+
+/// Calls the function `callee` in `funcs` by the `call` in `instr_view`
+/// whose args are `args`, where the caller's environment is `stack[base..]`
+/// & the callee's environment is pushed onto `stack` for the call
+/// (see `interp::interp_call`)
+fn call_decoded<P: IndexPair, W: Write, O: Observer>(
+    instr_view: &InstrView<P>,
+    base: usize,
+    stack: &mut Vec<Option<BrilValue>>,
+    funcs: &FuncTable<'_, P>,
+    ctx: &mut InterpContext<W, O>,
+    callee: usize,
+    (start, end): (u32, u32),
+) -> Result<Option<BrilValue>, Diagnostic> {
+    let (call_view, call_func) = funcs.decoded(callee)?;
+    if let Some(max_call_depth) = ctx.limits.max_call_depth
+        && ctx.call_depth >= max_call_depth
+    {
+        return Err(call_depth_exceeded(
+            max_call_depth,
+            get_func_name(call_view),
+        ));
+    }
+    ctx.call_depth += 1;
+    ctx.peak_call_depth = ctx.peak_call_depth.max(ctx.call_depth);
+
+    // Bind the args to the callee's parameters (the `i`th parameter is
+    // always in slot `i`), then call the function
+    let callee_base = stack.len();
+    stack.resize(callee_base + call_func.num_slots, None);
+    let mut bind_args = || {
+        check_call_arity(call_view, (end - start) as usize)?;
+        let params = call_view.func_args.iter().enumerate();
+        for ((slot, flat_arg), i) in params.zip(start as usize..end as usize) {
+            let value = stack[base + instr_view.arg_slots[i] as usize]
+                .ok_or_else(|| undefined_arg(instr_view, i))?;
+            check_param_type(call_view, flat_arg, &value)?;
+            stack[callee_base + slot] = Some(value);
+        }
+        Ok(())
+    };
+    let ret_value = bind_args().and_then(|()| {
+        interp_decoded(call_view, call_func, callee_base, stack, funcs, ctx)
+    });
+    stack.truncate(callee_base);
+    // (the call is over even if it failed)
+    ctx.call_depth -= 1;
+    ret_value
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod predecode_tests {
+    use serde_json::json;
+
+    use crate::interp::{FuncTable, InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::predecode::DecodedInstr;
+
+    /// Running the pre-decoded instructions gives the same output, steps &
+    /// errors as running the zero-copy instructions, and instructions which
+    /// can't be decoded only fail if they're executed
+    #[test]
+    fn test_predecode() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "op": "const", "dest": "acc", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "done", "type": "bool",
+                  "args": ["n", "one"] },
+                { "op": "br", "labels": ["end", "body"], "args": ["done"] },
+                { "label": "body" },
+                { "op": "call", "dest": "acc", "type": "int",
+                  "funcs": ["mul"], "args": ["acc", "n"] },
+                { "op": "sub", "dest": "n", "type": "int",
+                  "args": ["n", "one"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "end" },
+                { "op": "print", "args": ["acc", "done"] },
+                { "op": "call", "funcs": ["missing"] }
+            ]
+        }, {
+            "name": "mul",
+            "args": [{ "name": "x", "type": "int" },
+                     { "name": "y", "type": "int" }],
+            "type": "int",
+            "instrs": [
+                { "op": "mul", "dest": "z", "type": "int", "args": ["x", "y"] },
+                { "op": "ret", "args": ["z"] }
+            ]
+        }]});
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&data).expect("valid file should load");
+        let run = |predecode: bool| {
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            ctx.predecode = predecode;
+            let result = interp_program(&program, vec!["5"], &mut ctx);
+            (String::from_utf8(ctx.out).unwrap(), ctx.steps, result)
+        };
+
+        let (out, steps, result) = run(true);
+        assert_eq!(out, "120 true\n");
+        let err = result.unwrap_err();
+        assert_eq!(err.message, "call to undefined function @missing");
+        assert_eq!(err.pc, Some(8));
+        assert_eq!((out, steps, Err(err)), run(false));
+
+        let funcs = FuncTable::new(program.views());
+        let (_, main) = funcs.decoded(0).unwrap();
+        assert_eq!(main.instrs[6], DecodedInstr::Jmp { target: 0 });
+        assert!(matches!(main.instrs[8], DecodedInstr::Invalid { .. }));
+    }
+}
@@ -0,0 +1,551 @@
+use std::fmt::Write;
+
+use crate::debugger::format_instr;
+use crate::interp::{get_label_name, get_labels_vec};
+use crate::memfile::{
+    AlignedBytes, FlatProgram, ProgramViews, instr_stores_to_fbril_bytes,
+    load_program,
+};
+use crate::program::Function;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// Options which control how `print_program` lays out the text
+/// - `indent` is the no. of spaces before each instruction
+///   (labels & function headers aren't indented)
+/// - `align_types` pads the destinations & types of the instructions in
+///   each block so that their types & `=` signs line up
+/// - `blank_lines` separates basic blocks with a blank line
+/// - `show_pcs` adds the PC of each instruction as a trailing comment,
+///   i.e. the locations reported by the interpreter & `lint`
+/// - `show_addresses` starts each instruction with its byte offset & PC,
+///   like `objdump -d` (see `disassemble` for what the offsets are relative to)
+/// - `color` highlights opcodes, destinations & labels with ANSI escape codes
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrintOptions {
+    pub indent: usize,
+    pub align_types: bool,
+    pub blank_lines: bool,
+    pub show_pcs: bool,
+    pub show_addresses: bool,
+    pub color: bool,
+}
+
+/// A line of a function body, before it's padded
+/// - `offset` is the byte offset of the instruction (see `disassemble`)
+/// - `dest` & `ty` are only present for instructions with a destination
+/// - `op` is the opcode, `operands` is everything between the opcode & the
+///   labels (including the leading space) & `end` is the trailing `;`
+///   (extension instructions are kept as JSON in `op`, with no `end`)
+enum Row {
+    Label(String),
+    Instr {
+        pc: usize,
+        offset: usize,
+        dest: Option<String>,
+        ty: Option<String>,
+        op: String,
+        operands: String,
+        labels: Vec<String>,
+        end: &'static str,
+    },
+}
+
+/// ANSI escape codes used when `PrintOptions::color` is set
+const OPCODE_COLOR: &str = "1;34";
+const DEST_COLOR: &str = "32";
+const LABEL_COLOR: &str = "33";
+const ADDRESS_COLOR: &str = "2";
+
+/// The width of the `offset  pc  ` prefix added by `show_addresses`
+const ADDRESS_WIDTH: usize = 16;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Default for PrintOptions {
+    fn default() -> Self {
+        Self {
+            indent: 2,
+            align_types: true,
+            blank_lines: true,
+            show_pcs: false,
+            show_addresses: false,
+            color: false,
+        }
+    }
+}
+
+/// Wraps `text` in the ANSI escape code `code` if `color` is set
+fn paint(text: &str, code: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{code}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Pads `text` with spaces on the right to be `width` characters wide
+/// (unlike `{:<width$}`, this works for widths bigger than `u16::MAX`,
+/// which a width measured from a program, e.g. its longest name, can be)
+pub fn pad_right(text: &str, width: usize) -> String {
+    let padding = width.saturating_sub(text.chars().count());
+    format!("{text}{}", " ".repeat(padding))
+}
+
+/// Splits the body of a function into basic blocks of `Row`s: a block
+/// starts at each run of labels & ends after each `jmp`, `br` or `ret`
+/// (`base` is the address which instruction offsets are relative to)
+fn function_blocks<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    base: usize,
+) -> Vec<Vec<Row>> {
+    let mut blocks: Vec<Vec<Row>> = vec![];
+    let mut block = vec![];
+    for pc in 0..=instr_view.instrs.len() {
+        for label in instr_view.labels_at(pc) {
+            if matches!(block.last(), Some(Row::Instr { .. })) {
+                blocks.push(std::mem::take(&mut block));
+            }
+            let (offset, len) = label.label_idxes.idxes();
+            let name = get_label_name(instr_view, offset, len);
+            block.push(Row::Label(name.to_string()));
+        }
+        let Some(instr) = instr_view.instrs.get(pc) else {
+            break;
+        };
+        let offset = std::ptr::from_ref(instr).addr() - base;
+        let text = format_instr(instr_view, instr);
+        if let InstrKind::Extension = instr.get_instr_kind() {
+            block.push(Row::Instr {
+                pc,
+                offset,
+                dest: None,
+                ty: None,
+                op: text,
+                operands: String::new(),
+                labels: vec![],
+                end: "",
+            });
+            continue;
+        }
+        let labels: Vec<String> = match instr.instr_labels.get() {
+            Some((offset, len)) => get_labels_vec(instr_view, offset, len)
+                .into_iter()
+                .map(|label| label.to_string())
+                .collect(),
+            None => vec![],
+        };
+        let label_suffix: String =
+            labels.iter().map(|label| format!(" .{label}")).collect();
+        let text = text.strip_suffix(';').unwrap_or(&text);
+        let text = text.strip_suffix(&label_suffix).unwrap_or(text);
+        let (lhs, rest) = match text.split_once(" = ") {
+            Some((lhs, rest)) if instr.dest.get().is_some() => {
+                (Some(lhs), rest)
+            }
+            _ => (None, text),
+        };
+        let (dest, ty) = match lhs.map(|lhs| lhs.split_once(": ")) {
+            Some(Some((dest, ty))) => (Some(dest), Some(ty.to_string())),
+            Some(None) => (lhs, None),
+            None => (None, None),
+        };
+        let (op, operands) =
+            rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+        block.push(Row::Instr {
+            pc,
+            offset,
+            dest: dest.map(str::to_string),
+            ty,
+            op: op.to_string(),
+            operands: operands.to_string(),
+            labels,
+            end: ";",
+        });
+        if let Ok(Opcode::Jmp | Opcode::Br | Opcode::Ret) =
+            Opcode::try_from(instr.op())
+        {
+            blocks.push(std::mem::take(&mut block));
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Renders a single instruction row, padding its destination & type to the
+/// widths `dest_width` & `lhs_width` (the width of `dest: type`), with
+/// ANSI colors if `color` is set
+#[allow(clippy::too_many_arguments)]
+fn render_instr(
+    dest: Option<&str>,
+    ty: Option<&str>,
+    op: &str,
+    operands: &str,
+    labels: &[String],
+    end: &str,
+    (dest_width, lhs_width): (usize, usize),
+    color: bool,
+) -> String {
+    let mut out = String::new();
+    if let Some(dest) = dest {
+        let lhs_len = match ty {
+            Some(ty) => {
+                let padding = dest_width.saturating_sub(dest.len() + 1);
+                let painted = paint(dest, DEST_COLOR, color);
+                let _ = write!(out, "{painted}:{} {ty}", " ".repeat(padding));
+                dest_width.max(dest.len() + 1) + 1 + ty.len()
+            }
+            None => {
+                out.push_str(&paint(dest, DEST_COLOR, color));
+                dest.len()
+            }
+        };
+        let padding = lhs_width.saturating_sub(lhs_len);
+        let _ = write!(out, "{} = ", " ".repeat(padding));
+    }
+    out.push_str(&paint(op, OPCODE_COLOR, color));
+    out.push_str(operands);
+    for label in labels {
+        let _ =
+            write!(out, " {}", paint(&format!(".{label}"), LABEL_COLOR, color));
+    }
+    out.push_str(end);
+    out
+}
+
+/// Renders a function in the Bril text format, laid out according to `opts`
+/// (instruction offsets are relative to `base`)
+fn render_function<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    opts: &PrintOptions,
+    base: usize,
+) -> String {
+    let function = Function::new(instr_view);
+    let mut out = format!("@{}", function.name());
+    let params: Vec<String> = function
+        .params()
+        .map(|(name, ty)| match ty {
+            Some(ty) => format!("{name}: {ty}"),
+            None => name.to_string(),
+        })
+        .collect();
+    if !params.is_empty() {
+        out.push_str(&format!("({})", params.join(", ")));
+    }
+    if let Some(ty) = function.return_type() {
+        out.push_str(&format!(": {ty}"));
+    }
+    out.push_str(" {\n");
+
+    let indent = " ".repeat(opts.indent);
+    let margin = if opts.show_addresses {
+        ADDRESS_WIDTH
+    } else {
+        0
+    };
+    for (i, block) in function_blocks(instr_view, base).iter().enumerate() {
+        if i > 0 && opts.blank_lines {
+            out.push('\n');
+        }
+        // (the widths stay at 0 without alignment, so nothing is padded)
+        let (mut dest_width, mut lhs_width) = (0, 0);
+        if opts.align_types {
+            for row in block {
+                if let Row::Instr {
+                    dest: Some(dest),
+                    ty,
+                    ..
+                } = row
+                {
+                    dest_width = dest_width.max(dest.len() + 1);
+                    let ty_len = ty.as_ref().map_or(0, |ty| ty.len() + 1);
+                    lhs_width = lhs_width.max(dest.len() + 1 + ty_len);
+                }
+            }
+        }
+        // (each line is rendered without colors too, to measure its width)
+        let lines: Vec<(Option<usize>, String, String)> = block
+            .iter()
+            .map(|row| match row {
+                Row::Label(name) => {
+                    let label = format!(".{name}:");
+                    let painted = paint(&label, LABEL_COLOR, opts.color);
+                    (None, label, format!("{}{painted}", " ".repeat(margin)))
+                }
+                Row::Instr {
+                    pc,
+                    offset,
+                    dest,
+                    ty,
+                    op,
+                    operands,
+                    labels,
+                    end,
+                } => {
+                    let render = |color| {
+                        render_instr(
+                            dest.as_deref(),
+                            ty.as_deref(),
+                            op,
+                            operands,
+                            labels,
+                            end,
+                            (dest_width, lhs_width),
+                            color,
+                        )
+                    };
+                    let mut painted = String::new();
+                    if opts.show_addresses {
+                        let address = format!("{offset:08x}  {pc:>4}  ");
+                        painted.push_str(&paint(
+                            &address,
+                            ADDRESS_COLOR,
+                            opts.color,
+                        ));
+                    }
+                    painted.push_str(&indent);
+                    painted.push_str(&render(opts.color));
+                    let plain = format!("{indent}{}", render(false));
+                    (Some(*pc), plain, painted)
+                }
+            })
+            .collect();
+        let width = lines.iter().map(|(_, plain, _)| plain.len()).max();
+        for (location, plain, painted) in lines {
+            match location {
+                Some(pc) if opts.show_pcs => {
+                    let padding = width.unwrap_or(0) - plain.len();
+                    let padding = " ".repeat(padding);
+                    let _ = writeln!(out, "{painted}{padding}  # pc {pc}");
+                }
+                _ => {
+                    let _ = writeln!(out, "{painted}");
+                }
+            }
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Renders a function in the Bril text format, laid out according to `opts`
+/// (with `show_addresses`, offsets are relative to the function's first
+/// instruction: use `disassemble` for offsets into a file)
+pub fn print_function<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    opts: &PrintOptions,
+) -> String {
+    render_function(instr_view, opts, instr_view.instrs.as_ptr().addr())
+}
+
+/// Renders every function in a program in the Bril text format,
+/// separated by blank lines
+pub fn print_program<P: IndexPair>(
+    instr_views: &[InstrView<P>],
+    opts: &PrintOptions,
+) -> String {
+    instr_views
+        .iter()
+        .map(|instr_view| print_function(instr_view, opts))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders the flat Bril file `data` (in any format) in the Bril text
+/// format, where the offsets shown by `show_addresses` are byte offsets
+/// into the file. Only the function named `func` is rendered if one is
+/// given (`Ok(None)` means there's no such function).
+/// Returns an error if the file is malformed.
+pub fn disassemble(
+    data: &[u8],
+    func: Option<&str>,
+    opts: &PrintOptions,
+) -> Result<Option<String>, String> {
+    fn render<P: IndexPair>(
+        program: &FlatProgram<P>,
+        func: Option<&str>,
+        opts: &PrintOptions,
+        base: usize,
+    ) -> Option<String> {
+        let render_view = |view| render_function(view, opts, base);
+        match func {
+            Some(func) => program.get(func).map(render_view),
+            None => Some(
+                program
+                    .views()
+                    .iter()
+                    .map(render_view)
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+        }
+    }
+
+    let base = data.as_ptr().addr();
+    Ok(match &load_program(data)? {
+        ProgramViews::Narrow(program) => render(program, func, opts, base),
+        ProgramViews::Wide(program) => render(program, func, opts, base),
+        ProgramViews::Compact(program) => render(program, func, opts, base),
+    })
+}
+
+/// Like `print_program`, but for functions which haven't been written to a
+/// flat Bril file yet (e.g. ones built up by a tool in memory)
+pub fn print_instr_stores(
+    instr_stores: Vec<InstrStore>,
+    opts: &PrintOptions,
+) -> String {
+    let bytes = instr_stores_to_fbril_bytes(instr_stores);
+    let data = AlignedBytes::new(&bytes);
+    let program =
+        load_program(&data).expect("freshly encoded file should load");
+    match &program {
+        ProgramViews::Narrow(program) => print_program(program.views(), opts),
+        ProgramViews::Wide(program) => print_program(program.views(), opts),
+        ProgramViews::Compact(program) => print_program(program.views(), opts),
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod printer_tests {
+    use serde_json::json;
+    use zerocopy::IntoBytes;
+
+    use crate::flatten::flatten_instrs;
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+    use crate::printer::{
+        PrintOptions, disassemble, pad_right, print_instr_stores,
+    };
+
+    /// Types & `=` signs are aligned within each block, blocks are
+    /// separated by blank lines & labels aren't indented
+    #[test]
+    fn test_print_instr_stores() {
+        let json = json!({
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "lt", "dest": "small", "type": "bool",
+                  "args": ["n", "x"] },
+                { "op": "br", "args": ["small"], "labels": ["done", "done"] },
+                { "label": "done" },
+                { "op": "print", "args": ["x"] },
+            ]
+        });
+        let store = flatten_instrs(&json);
+        let text = print_instr_stores(vec![store], &PrintOptions::default());
+        assert_eq!(
+            text,
+            "@main(n: int) {\n  \
+            x:     int  = const 1;\n  \
+            small: bool = lt n x;\n  \
+            br small .done .done;\n\
+            \n\
+            .done:\n  \
+            print x;\n\
+            }\n"
+        );
+        let opts = PrintOptions {
+            indent: 4,
+            align_types: false,
+            blank_lines: false,
+            show_pcs: true,
+            ..PrintOptions::default()
+        };
+        let text = print_instr_stores(vec![flatten_instrs(&json)], &opts);
+        assert_eq!(
+            text.lines().nth(1),
+            Some("    x: int = const 1;      # pc 0")
+        );
+        assert!(text.contains("# pc 2\n.done:\n"));
+    }
+
+    /// `show_addresses` gives the offset of each instruction in the file,
+    /// & `color` only adds escape codes around opcodes, dests & labels
+    #[test]
+    fn test_disassemble_addresses_and_color() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": "x", "type": "int", "value": 1 },
+                { "op": "jmp", "labels": ["end"] },
+                { "label": "end" },
+                { "op": "print", "args": ["x"] },
+            ]
+        }]});
+        let bytes = json_to_fbril_bytes(&json);
+        let aligned = AlignedBytes::new(&bytes);
+        let data = &aligned;
+        let views = get_program_views(data).expect("valid file should load");
+        let opts = PrintOptions {
+            show_addresses: true,
+            ..PrintOptions::default()
+        };
+        let text = disassemble(data, Some("main"), &opts)
+            .expect("valid file should load")
+            .expect("main should exist");
+        for (pc, instr) in views[0].instrs.iter().enumerate() {
+            let line = text
+                .lines()
+                .find(|line| line.get(8..14) == Some(&format!("  {pc:>4}")))
+                .expect("every instruction should have an address");
+            let offset = usize::from_str_radix(&line[..8], 16).unwrap();
+            let instr_bytes = instr.as_bytes();
+            assert_eq!(&data[offset..offset + instr_bytes.len()], instr_bytes);
+        }
+        assert_eq!(disassemble(data, Some("f"), &opts), Ok(None));
+
+        let opts = PrintOptions {
+            color: true,
+            ..PrintOptions::default()
+        };
+        let text = disassemble(data, None, &opts).unwrap().unwrap();
+        assert!(
+            text.contains(
+                "  \x1b[32mx\x1b[0m: int = \x1b[1;34mconst\x1b[0m 1;\n"
+            )
+        );
+        assert!(text.contains("\x1b[33m.end:\x1b[0m\n"));
+        assert!(text.contains("\x1b[1;34mjmp\x1b[0m \x1b[33m.end\x1b[0m;"));
+    }
+
+    /// Names longer than `u16::MAX` characters (which are too wide for a
+    /// `{:width$}` format argument) are still padded
+    #[test]
+    fn test_long_names() {
+        let long = "v".repeat(70000);
+        let json = json!({
+            "name": "main",
+            "instrs": [
+                { "op": "const", "dest": long, "type": "int", "value": 1 },
+                { "op": "const", "dest": "x", "type": "int", "value": 2 },
+                { "label": "end" },
+                { "op": "print", "args": [long, "x"] },
+            ]
+        });
+        let opts = PrintOptions {
+            show_pcs: true,
+            show_addresses: true,
+            ..PrintOptions::default()
+        };
+        let text = print_instr_stores(vec![flatten_instrs(&json)], &opts);
+        let x = text.lines().find(|line| line.contains("x:")).unwrap();
+        assert_eq!(x.find(" = "), text.lines().nth(1).unwrap().find(" = "));
+        assert!(x.ends_with("  # pc 1"));
+        assert_eq!(pad_right("x", 70000).len(), 70000);
+    }
+}
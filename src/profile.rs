@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::interp::{Environment, get_func_name};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The execution profile of a single function
+/// - `counts[pc]` is the no. of times the instruction at `pc` was executed
+/// - `edges` holds the jumps taken by `jmp`s & `br`s, as
+///   `(from pc, to pc, no. of times taken)` triples sorted by PC
+/// - `branches` holds the outcomes of each `br` that was executed, as
+///   `(pc, no. of times true, no. of times false)` triples sorted by PC
+///   (profiles written before these were recorded have none)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FuncProfile {
+    pub counts: Vec<u64>,
+    pub edges: Vec<(usize, usize, u64)>,
+    #[serde(default)]
+    pub branches: Vec<(usize, u64, u64)>,
+}
+
+/// The execution profile of a program (serialized as JSON by `--profile`),
+/// which maps the name of each function that was called to its profile
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub functions: BTreeMap<String, FuncProfile>,
+}
+
+/// An `Observer` which records a `Profile` of the program being interpreted
+/// (`edges` maps each function to the no. of times each of its jumps was
+/// taken, and is only turned into a sorted list by `Profiler::profile`,
+/// like `branches`, which maps the PC of each `br` to its `(true, false)`
+/// counts)
+#[derive(Debug, Default)]
+pub struct Profiler {
+    counts: HashMap<String, Vec<u64>>,
+    edges: HashMap<String, HashMap<(usize, usize), u64>>,
+    branches: HashMap<String, HashMap<usize, (u64, u64)>>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Profiler {
+    /// Creates a profiler which hasn't observed anything yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that control jumped from `pc` to `target_pc`
+    fn record_edge(&mut self, func: &str, pc: usize, target_pc: usize) {
+        let edges = match self.edges.get_mut(func) {
+            Some(edges) => edges,
+            None => self.edges.entry(func.to_string()).or_default(),
+        };
+        *edges.entry((pc, target_pc)).or_default() += 1;
+    }
+
+    /// Records that the condition of the `br` at `pc` was `taken`
+    fn record_branch(&mut self, func: &str, pc: usize, taken: bool) {
+        let branches = match self.branches.get_mut(func) {
+            Some(branches) => branches,
+            None => self.branches.entry(func.to_string()).or_default(),
+        };
+        let (num_true, num_false) = branches.entry(pc).or_default();
+        if taken {
+            *num_true += 1;
+        } else {
+            *num_false += 1;
+        }
+    }
+
+    /// The profile of everything observed so far
+    pub fn profile(&self) -> Profile {
+        let functions = self
+            .counts
+            .iter()
+            .map(|(func, counts)| {
+                let mut edges: Vec<(usize, usize, u64)> = self
+                    .edges
+                    .get(func)
+                    .into_iter()
+                    .flatten()
+                    .map(|(&(from, to), &count)| (from, to, count))
+                    .collect();
+                edges.sort_unstable();
+                let mut branches: Vec<(usize, u64, u64)> = self
+                    .branches
+                    .get(func)
+                    .into_iter()
+                    .flatten()
+                    .map(|(&pc, &(num_true, num_false))| {
+                        (pc, num_true, num_false)
+                    })
+                    .collect();
+                branches.sort_unstable();
+                let profile = FuncProfile {
+                    counts: counts.clone(),
+                    edges,
+                    branches,
+                };
+                (func.clone(), profile)
+            })
+            .collect();
+        Profile { functions }
+    }
+}
+
+impl Profile {
+    /// Parses a profile from the JSON produced by `--profile`
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("invalid profile: {e}"))
+    }
+
+    /// Serializes the profile as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("unable to serialize profile")
+    }
+}
+
+impl Observer for Profiler {
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        let func = get_func_name(callee);
+        if !self.counts.contains_key(func) {
+            let num_instrs = callee.instrs.len();
+            self.counts.insert(func.to_string(), vec![0; num_instrs]);
+        }
+    }
+
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        _instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        // (`on_call` has always been called for the function already)
+        if let Some(counts) = self.counts.get_mut(get_func_name(instr_view)) {
+            counts[pc] += 1;
+        }
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        let func = get_func_name(instr_view);
+        self.record_edge(func, pc, target_pc);
+        self.record_branch(func, pc, taken);
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        self.record_edge(get_func_name(instr_view), pc, target_pc);
+    }
+}
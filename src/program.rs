@@ -0,0 +1,254 @@
+use crate::interp::{
+    get_args, get_ext_json, get_func, get_func_name, get_label_name,
+};
+use crate::interp::{get_labels_vec, get_var};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A high-level, read-only view of a flat Bril program, which decodes
+/// instructions on the fly so that API consumers never have to deal with
+/// index pairs (`P` is the type of the index pairs, see `IndexPair`)
+/// - Construct one from the `InstrView`s returned by
+///   `memfile::get_program_views` / `memfile::load_program`
+pub struct Program<'a, P: IndexPair = I32Pair> {
+    views: &'a [InstrView<'a, P>],
+}
+
+/// A single function in a `Program`
+pub struct Function<'a, P: IndexPair = I32Pair> {
+    view: &'a InstrView<'a, P>,
+}
+
+/// A decoded instruction, whose strings borrow from the underlying
+/// flat Bril file
+#[derive(Debug, Clone, PartialEq)]
+pub enum InstructionRef<'a> {
+    /// A label (the name is stored without the leading `.`)
+    Label(&'a str),
+    /// An instruction whose opcode isn't in core Bril, which is kept as
+    /// its JSON (see `EXT_OP`)
+    Extension(&'a str),
+    /// Any other instruction
+    Op(OpRef<'a>),
+}
+
+/// A decoded (non-label) instruction
+/// - `value` is only present for `const` instructions
+/// - `func` is only present for `call` instructions
+///   (the name is stored without the leading `@`)
+/// - `labels` are stored without the leading `.`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpRef<'a> {
+    pub op: Opcode,
+    pub dest: Option<&'a str>,
+    pub ty: Option<Type>,
+    pub value: Option<BrilValue>,
+    pub args: Vec<&'a str>,
+    pub labels: Vec<&'a str>,
+    pub func: Option<&'a str>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a, P: IndexPair> Program<'a, P> {
+    /// Wraps the `InstrView`s of a program's functions
+    pub fn new(views: &'a [InstrView<'a, P>]) -> Self {
+        Self { views }
+    }
+
+    /// The functions in the program, in the order they appear in the file
+    pub fn functions(
+        &self,
+    ) -> impl Iterator<Item = Function<'a, P>> + use<'a, P> {
+        self.views.iter().map(|view| Function { view })
+    }
+
+    /// Looks up a function by name (without the leading `@`)
+    pub fn function(&self, name: &str) -> Option<Function<'a, P>> {
+        self.functions().find(|func| func.name() == name)
+    }
+}
+
+impl<'a, P: IndexPair> Function<'a, P> {
+    /// Wraps the `InstrView` of a single function
+    /// (e.g. one looked up with `memfile::FlatProgram::get`)
+    pub fn new(view: &'a InstrView<'a, P>) -> Self {
+        Self { view }
+    }
+
+    /// The function's name (without the leading `@`)
+    pub fn name(&self) -> &'a str {
+        get_func_name(self.view)
+    }
+
+    /// The function's parameters, as `(name, type)` pairs
+    /// (the type is `None` if the file doesn't record a valid one)
+    pub fn params(
+        &self,
+    ) -> impl Iterator<Item = (&'a str, Option<Type>)> + use<'a, P> {
+        let view = self.view;
+        view.func_args.iter().map(move |func_arg| {
+            let (offset, len) = func_arg.arg_name_idxes.idxes();
+            (get_var(view, offset, len), func_arg.arg_type.into())
+        })
+    }
+
+    /// The function's return type (`None` if the function is void)
+    pub fn return_type(&self) -> Option<Type> {
+        self.view.func_ret_ty.into()
+    }
+
+    /// The function's instructions (including labels), in program order
+    pub fn instructions(
+        &self,
+    ) -> impl Iterator<Item = InstructionRef<'a>> + use<'a, P> {
+        let view = self.view;
+        // (each label comes right before the instruction at its PC)
+        (0..=view.instrs.len()).flat_map(move |pc| {
+            view.labels_at(pc)
+                .iter()
+                .map(move |label| {
+                    let (offset, len) = label.label_idxes.idxes();
+                    InstructionRef::Label(get_label_name(view, offset, len))
+                })
+                .chain(
+                    view.instrs.get(pc).map(|instr| decode_instr(view, instr)),
+                )
+        })
+    }
+
+    /// The underlying `InstrView`, for consumers which need the raw
+    /// index pairs
+    pub fn view(&self) -> &'a InstrView<'a, P> {
+        self.view
+    }
+}
+
+// (`Clone` / `Copy` are implemented manually since deriving them
+// would require `P: Copy`, even though we only store references)
+impl<P: IndexPair> Clone for Program<'_, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P: IndexPair> Copy for Program<'_, P> {}
+
+impl<P: IndexPair> Clone for Function<'_, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P: IndexPair> Copy for Function<'_, P> {}
+
+/// Decodes a single flat instruction belonging to `view`
+fn decode_instr<'a, P: IndexPair>(
+    view: &'a InstrView<'a, P>,
+    instr: &FlatInstr<P>,
+) -> InstructionRef<'a> {
+    if instr.op() == EXT_OP {
+        return InstructionRef::Extension(get_ext_json(view, instr));
+    }
+    let op = Opcode::u32_to_opcode(instr.op())
+        .expect("`memfile::validate_instr_view` checks that every opcode is valid");
+    InstructionRef::Op(OpRef {
+        op,
+        dest: instr
+            .dest
+            .get()
+            .map(|(offset, len)| get_var(view, offset, len)),
+        ty: instr.ty.into(),
+        value: instr.value.into(),
+        args: instr
+            .args
+            .get()
+            .map_or(vec![], |(offset, len)| get_args(view, offset, len)),
+        labels: instr
+            .instr_labels
+            .get()
+            .map_or(vec![], |(offset, len)| get_labels_vec(view, offset, len)),
+        func: instr
+            .funcs
+            .get()
+            .map(|(offset, len)| get_func(view, offset, len)),
+    })
+}
+
+impl OpRef<'_> {
+    /// The instruction's args (variable names)
+    pub fn args(&self) -> &[&str] {
+        &self.args
+    }
+
+    /// The instruction's labels (without the leading `.`)
+    pub fn labels(&self) -> &[&str] {
+        &self.labels
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod program_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+    use crate::program::{InstructionRef, OpRef, Program};
+    use crate::types::{BrilValue, Opcode, Type};
+
+    /// Functions & instructions are decoded without any index arithmetic
+    /// on the caller's side
+    #[test]
+    fn test_decode_program() {
+        let file = File::open("test/call-with-args.json")
+            .expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+        let program = Program::new(&views);
+
+        let names: Vec<&str> = program.functions().map(|f| f.name()).collect();
+        assert_eq!(names, ["main", "add2"]);
+
+        let add2 = program.function("add2").expect("add2 exists");
+        let params: Vec<_> = add2.params().collect();
+        assert_eq!(params, [("x", Some(Type::Int)), ("y", Some(Type::Int))]);
+        assert_eq!(add2.return_type(), Some(Type::Int));
+
+        let main = program.function("main").expect("main exists");
+        let instrs: Vec<InstructionRef> = main.instructions().collect();
+        assert_eq!(instrs.len(), 5);
+        assert_eq!(
+            instrs[0],
+            InstructionRef::Op(OpRef {
+                op: Opcode::Const,
+                dest: Some("x"),
+                ty: Some(Type::Int),
+                value: Some(BrilValue::IntVal(2)),
+                args: vec![],
+                labels: vec![],
+                func: None,
+            })
+        );
+        let InstructionRef::Op(call) = &instrs[2] else {
+            panic!("expected a call");
+        };
+        assert_eq!(call.op, Opcode::Call);
+        assert_eq!(call.func, Some("add2"));
+        assert_eq!(call.args(), ["x", "y"]);
+    }
+}
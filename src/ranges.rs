@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::cfg::{Cfg, Operation};
+use crate::program::Function;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of times a loop header's entry state may grow before it's
+/// widened (so that the analysis of a loop terminates, without losing
+/// precision for loops whose bounds are reached after a few iterations)
+pub const WIDENING_DELAY: usize = 3;
+
+/// A range of ints `lo..=hi` (`i64::MIN` & `i64::MAX` stand for unbounded
+/// ends, since ints are 64-bit)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub lo: i64,
+    pub hi: i64,
+}
+
+/// The abstract value of a variable
+/// - `Bool(None)` is a bool which may be either `true` or `false`
+/// - `Top` is a variable whose type is unknown (e.g. the result of a `call`
+///   without a type), which may hold any value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbsValue {
+    Int(Interval),
+    Bool(Option<bool>),
+    Top,
+}
+
+/// The abstract values of the variables at a program point (a variable which
+/// isn't in the map hasn't been defined along any path to that point)
+pub type AbsEnv = BTreeMap<String, AbsValue>;
+
+/// The abstract states at the entry & exit of a block (both `None` if the
+/// analysis found that the block is unreachable)
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockRanges {
+    pub entry: Option<AbsEnv>,
+    pub exit: Option<AbsEnv>,
+}
+
+/// The result of `analyze_ranges`: `blocks[b]` holds the states at the
+/// entry & exit of `cfg.blocks[b]`, where `names[b]` is its name (as given
+/// by `Cfg::block_name`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeAnalysis {
+    pub func: String,
+    pub names: Vec<String>,
+    pub blocks: Vec<BlockRanges>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Interval {
+    /// All ints
+    pub const FULL: Interval = Interval {
+        lo: i64::MIN,
+        hi: i64::MAX,
+    };
+
+    /// The interval containing just `n`
+    pub fn singleton(n: i64) -> Self {
+        Self { lo: n, hi: n }
+    }
+
+    /// The interval `lo..=hi`, or `None` if it's empty
+    fn new(lo: i64, hi: i64) -> Option<Self> {
+        (lo <= hi).then_some(Self { lo, hi })
+    }
+
+    /// The smallest interval containing both intervals
+    pub fn join(self, other: Interval) -> Interval {
+        Interval {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    /// Extrapolates the growth from `self` to `self.join(other)`: a bound
+    /// which grows is widened to be unbounded
+    pub fn widen(self, other: Interval) -> Interval {
+        Interval {
+            lo: if other.lo < self.lo {
+                i64::MIN
+            } else {
+                self.lo
+            },
+            hi: if other.hi > self.hi {
+                i64::MAX
+            } else {
+                self.hi
+            },
+        }
+    }
+
+    /// The smallest interval containing `f(x, y)` for every pair of bounds,
+    /// or `FULL` if `f` overflows for any of them (since arithmetic wraps
+    /// around, the result could then be anything)
+    fn hull_of_corners(
+        self,
+        other: Interval,
+        f: impl Fn(i64, i64) -> Option<i64>,
+    ) -> Interval {
+        let mut corners = [self.lo, self.hi]
+            .into_iter()
+            .flat_map(|x| [other.lo, other.hi].map(|y| f(x, y)));
+        let Some(Some(first)) = corners.next() else {
+            return Interval::FULL;
+        };
+        corners
+            .try_fold(Interval::singleton(first), |acc, corner| {
+                corner.map(|n| acc.join(Interval::singleton(n)))
+            })
+            .unwrap_or(Interval::FULL)
+    }
+
+    /// The result of the arithmetic operation `op` on ints in the intervals
+    fn arith(self, op: Opcode, other: Interval) -> Interval {
+        match op {
+            Opcode::Add => Interval::new(
+                self.lo.saturating_add(other.lo),
+                self.hi.saturating_add(other.hi),
+            )
+            .filter(|_| {
+                self.lo.checked_add(other.lo).is_some()
+                    && self.hi.checked_add(other.hi).is_some()
+            })
+            .unwrap_or(Interval::FULL),
+            Opcode::Sub => Interval::new(
+                self.lo.saturating_sub(other.hi),
+                self.hi.saturating_sub(other.lo),
+            )
+            .filter(|_| {
+                self.lo.checked_sub(other.hi).is_some()
+                    && self.hi.checked_sub(other.lo).is_some()
+            })
+            .unwrap_or(Interval::FULL),
+            Opcode::Mul => self.hull_of_corners(other, i64::checked_mul),
+            // (if execution continues after a `div`, the divisor wasn't 0, so
+            // the quotients are computed separately for the negative &
+            // positive divisors)
+            Opcode::Div => [
+                Interval::new(other.lo, other.hi.min(-1)),
+                Interval::new(other.lo.max(1), other.hi),
+            ]
+            .into_iter()
+            .flatten()
+            .map(|divisor| self.hull_of_corners(divisor, i64::checked_div))
+            .reduce(Interval::join)
+            .unwrap_or(Interval::FULL),
+            _ => Interval::FULL,
+        }
+    }
+
+    /// Whether `x op y` holds for all / no `x` in `self` & `y` in `other`
+    /// (`None` if it holds for some but not others)
+    fn compare(self, op: Opcode, other: Interval) -> Option<bool> {
+        let (always, never) = match op {
+            Opcode::Eq => (
+                self.lo == self.hi && self == other,
+                self.hi < other.lo || other.hi < self.lo,
+            ),
+            Opcode::Lt => (self.hi < other.lo, self.lo >= other.hi),
+            Opcode::Le => (self.hi <= other.lo, self.lo > other.hi),
+            Opcode::Gt => (self.lo > other.hi, self.hi <= other.lo),
+            Opcode::Ge => (self.lo >= other.hi, self.hi < other.lo),
+            _ => (false, false),
+        };
+        if always {
+            Some(true)
+        } else if never {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Narrows `self` & `other` to the values for which `x op y` holds
+    /// (`None` if there aren't any)
+    fn refine(
+        self,
+        op: Opcode,
+        other: Interval,
+    ) -> Option<(Interval, Interval)> {
+        match op {
+            Opcode::Lt => Some((
+                Interval::new(
+                    self.lo,
+                    self.hi.min(other.hi.saturating_sub(1)),
+                )?,
+                Interval::new(
+                    other.lo.max(self.lo.saturating_add(1)),
+                    other.hi,
+                )?,
+            )),
+            Opcode::Le => Some((
+                Interval::new(self.lo, self.hi.min(other.hi))?,
+                Interval::new(other.lo.max(self.lo), other.hi)?,
+            )),
+            Opcode::Gt | Opcode::Ge => {
+                let op = if op == Opcode::Gt {
+                    Opcode::Lt
+                } else {
+                    Opcode::Le
+                };
+                let (other, this) = other.refine(op, self)?;
+                Some((this, other))
+            }
+            Opcode::Eq => {
+                let both = Interval::new(
+                    self.lo.max(other.lo),
+                    self.hi.min(other.hi),
+                )?;
+                Some((both, both))
+            }
+            _ => Some((self, other)),
+        }
+    }
+}
+
+/// The comparison which gives the opposite result (`x op y` is false
+/// exactly when `x (negate op) y` is true), if there is one
+fn negate(op: Opcode) -> Option<Opcode> {
+    match op {
+        Opcode::Lt => Some(Opcode::Ge),
+        Opcode::Le => Some(Opcode::Gt),
+        Opcode::Gt => Some(Opcode::Le),
+        Opcode::Ge => Some(Opcode::Lt),
+        _ => None,
+    }
+}
+
+impl AbsValue {
+    /// The value of a variable of type `ty` which could be anything
+    fn unknown(ty: Option<Type>) -> AbsValue {
+        match ty {
+            Some(Type::Int) => AbsValue::Int(Interval::FULL),
+            Some(Type::Bool) => AbsValue::Bool(None),
+            _ => AbsValue::Top,
+        }
+    }
+
+    /// The least upper bound of the two values
+    fn join(self, other: AbsValue) -> AbsValue {
+        match (self, other) {
+            (AbsValue::Int(x), AbsValue::Int(y)) => AbsValue::Int(x.join(y)),
+            (AbsValue::Bool(x), AbsValue::Bool(y)) => {
+                AbsValue::Bool(if x == y { x } else { None })
+            }
+            _ => AbsValue::Top,
+        }
+    }
+
+    /// `self.join(other)`, with growing int bounds widened
+    fn widen(self, other: AbsValue) -> AbsValue {
+        match (self, other) {
+            (AbsValue::Int(x), AbsValue::Int(y)) => AbsValue::Int(x.widen(y)),
+            _ => self.join(other),
+        }
+    }
+
+    /// Renders the value as JSON: an int is `{"lo": .., "hi": ..}` (with
+    /// `null` for an unbounded end), & a bool is `true`, `false` or `null`
+    /// if it could be either (as is a value of unknown type)
+    pub fn to_json(self) -> serde_json::Value {
+        let bound = |n: i64| {
+            if n == i64::MIN || n == i64::MAX {
+                serde_json::Value::Null
+            } else {
+                n.into()
+            }
+        };
+        match self {
+            AbsValue::Int(Interval { lo, hi }) => {
+                serde_json::json!({ "lo": bound(lo), "hi": bound(hi) })
+            }
+            AbsValue::Bool(b) => b.into(),
+            AbsValue::Top => serde_json::Value::Null,
+        }
+    }
+}
+
+/// Merges `incoming` into `env`, widening int bounds if `widen` is set
+/// (returns whether `env` changed)
+/// - A variable that's only defined along some paths keeps the value it
+///   has along those paths, since reading it along the other paths would
+///   be an error at run time
+fn merge(env: &mut AbsEnv, incoming: &AbsEnv, widen: bool) -> bool {
+    let mut changed = false;
+    for (var, &value) in incoming {
+        let merged = match env.get(var) {
+            Some(&old) if widen => old.widen(value),
+            Some(&old) => old.join(value),
+            None => value,
+        };
+        if env.insert(var.clone(), merged) != Some(merged) {
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// The abstract value of the result of `instr` in the state `env`
+fn eval(instr: &Operation, env: &AbsEnv) -> AbsValue {
+    let int = |n: usize| match instr.args.get(n).and_then(|arg| env.get(arg)) {
+        Some(AbsValue::Int(interval)) => *interval,
+        _ => Interval::FULL,
+    };
+    let bool = |n: usize| match instr.args.get(n).and_then(|arg| env.get(arg)) {
+        Some(AbsValue::Bool(b)) => *b,
+        _ => None,
+    };
+    match instr.op {
+        Opcode::Const => match instr.value {
+            Some(BrilValue::IntVal(n)) => AbsValue::Int(Interval::singleton(n)),
+            Some(BrilValue::BoolVal(b)) => AbsValue::Bool(Some(b.into())),
+            #[cfg(feature = "ext-values")]
+            Some(BrilValue::Extern(_)) => AbsValue::unknown(instr.ty),
+            None => AbsValue::unknown(instr.ty),
+        },
+        Opcode::Id => instr
+            .args
+            .first()
+            .and_then(|arg| env.get(arg))
+            .copied()
+            .unwrap_or(AbsValue::unknown(instr.ty)),
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div => {
+            AbsValue::Int(int(0).arith(instr.op, int(1)))
+        }
+        Opcode::Eq | Opcode::Lt | Opcode::Gt | Opcode::Le | Opcode::Ge => {
+            AbsValue::Bool(int(0).compare(instr.op, int(1)))
+        }
+        Opcode::Not => AbsValue::Bool(bool(0).map(|b| !b)),
+        Opcode::And => AbsValue::Bool(match (bool(0), bool(1)) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        }),
+        Opcode::Or => AbsValue::Bool(match (bool(0), bool(1)) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        }),
+        _ => AbsValue::unknown(instr.ty),
+    }
+}
+
+/// Applies the instructions of `block` to `env`, returning the state at the
+/// exit of the block, along with the comparison which defined the
+/// condition of the block's `br` (if the block ends in one, and the
+/// comparison's args aren't redefined in between), as `(op, x, y)`
+fn transfer(
+    block: &[Operation],
+    mut env: AbsEnv,
+) -> (AbsEnv, Option<(Opcode, &str, &str)>) {
+    let mut comparisons: BTreeMap<&str, (Opcode, &str, &str)> = BTreeMap::new();
+    for instr in block {
+        let Some(dest) = &instr.dest else {
+            continue;
+        };
+        env.insert(dest.clone(), eval(instr, &env));
+        comparisons.retain(|cond, (_, x, y)| {
+            ![*cond, *x, *y].contains(&dest.as_str())
+        });
+        if let (
+            Opcode::Eq | Opcode::Lt | Opcode::Gt | Opcode::Le | Opcode::Ge,
+            [x, y],
+        ) = (instr.op, instr.args.as_slice())
+            && x != dest
+            && y != dest
+        {
+            comparisons.insert(dest, (instr.op, x, y));
+        }
+    }
+    let comparison = match block.last() {
+        Some(instr) if instr.op == Opcode::Br => instr
+            .args
+            .first()
+            .and_then(|cond| comparisons.get(cond.as_str()))
+            .copied(),
+        _ => None,
+    };
+    (env, comparison)
+}
+
+/// The states along the outgoing edges of block `b`, whose exit state is
+/// `exit`, as `(successor, state)` pairs
+/// - If the block ends in a `br` whose condition is known, only the edge
+///   that's taken is returned
+/// - Otherwise, each edge of a `br` on a comparison of ints narrows their
+///   intervals to the values for which the comparison gives the edge's
+///   result (& an edge along which no values do is dropped)
+fn out_edges(
+    cfg: &Cfg,
+    b: usize,
+    exit: &AbsEnv,
+    comparison: Option<(Opcode, &str, &str)>,
+) -> Vec<(usize, AbsEnv)> {
+    let block = &cfg.blocks[b];
+    let Some(br) = block.instrs.last().filter(|instr| instr.op == Opcode::Br)
+    else {
+        return cfg
+            .successors(b)
+            .into_iter()
+            .map(|s| (s, exit.clone()))
+            .collect();
+    };
+    let (Some(cond), [true_label, false_label]) =
+        (br.args.first(), br.labels.as_slice())
+    else {
+        return vec![];
+    };
+    let mut edges = vec![];
+    for (label, taken) in [(true_label, true), (false_label, false)] {
+        let Some(succ) = cfg.block_index(label) else {
+            continue;
+        };
+        if let Some(AbsValue::Bool(Some(b))) = exit.get(cond)
+            && *b != taken
+        {
+            continue;
+        }
+        let mut env = exit.clone();
+        env.insert(cond.clone(), AbsValue::Bool(Some(taken)));
+        if let Some((op, x, y)) = comparison
+            && let (Some(AbsValue::Int(x_range)), Some(AbsValue::Int(y_range))) =
+                (exit.get(x), exit.get(y))
+        {
+            let op = if taken { Some(op) } else { negate(op) };
+            if let Some(op) = op {
+                let Some((x_range, y_range)) = x_range.refine(op, *y_range)
+                else {
+                    continue;
+                };
+                env.insert(x.to_string(), AbsValue::Int(x_range));
+                env.insert(y.to_string(), AbsValue::Int(y_range));
+            }
+        }
+        edges.push((succ, env));
+    }
+    edges
+}
+
+/// Computes the range of each int variable & whether each bool variable is
+/// constant, at the entry & exit of every block of `cfg`, by abstract
+/// interpretation over intervals
+/// - The entry state of a block that's the target of a back edge (i.e. a
+///   loop header) is widened once it has grown `WIDENING_DELAY` times, so
+///   that loops are analyzed in finite time
+pub fn analyze_ranges(cfg: &Cfg) -> RangeAnalysis {
+    let num_blocks = cfg.blocks.len();
+    let mut entries: Vec<Option<AbsEnv>> = vec![None; num_blocks];
+    let mut exits: Vec<Option<AbsEnv>> = vec![None; num_blocks];
+    let mut updates = vec![0; num_blocks];
+    // (blocks are processed in reverse postorder, so that a block's
+    // predecessors are usually processed before it)
+    let mut rpo_idx = vec![usize::MAX; num_blocks];
+    for (i, b) in cfg.reverse_postorder().into_iter().enumerate() {
+        rpo_idx[b] = i;
+    }
+    let mut worklist = BTreeSet::new();
+    if num_blocks > 0 {
+        entries[0] = Some(
+            cfg.args
+                .iter()
+                .map(|(name, ty)| (name.clone(), AbsValue::unknown(Some(*ty))))
+                .collect(),
+        );
+        worklist.insert((rpo_idx[0], 0));
+    }
+
+    while let Some((_, b)) = worklist.pop_first() {
+        let entry = entries[b].clone().expect("queued blocks are reachable");
+        let (exit, comparison) = transfer(&cfg.blocks[b].instrs, entry);
+        for (succ, env) in out_edges(cfg, b, &exit, comparison) {
+            // (an edge to a block that's earlier in reverse postorder is a
+            // back edge)
+            let widen =
+                rpo_idx[succ] <= rpo_idx[b] && updates[succ] >= WIDENING_DELAY;
+            let changed = match &mut entries[succ] {
+                Some(old) => merge(old, &env, widen),
+                None => {
+                    entries[succ] = Some(env);
+                    true
+                }
+            };
+            if changed {
+                updates[succ] += 1;
+                worklist.insert((rpo_idx[succ], succ));
+            }
+        }
+        exits[b] = Some(exit);
+    }
+
+    RangeAnalysis {
+        func: cfg.name.clone(),
+        names: (0..num_blocks).map(|b| cfg.block_name(b)).collect(),
+        blocks: entries
+            .into_iter()
+            .zip(exits)
+            .map(|(entry, exit)| BlockRanges { entry, exit })
+            .collect(),
+    }
+}
+
+/// Replaces each `br` in `cfg` whose condition `analyze_ranges` finds to be
+/// constant with a `jmp` to the label that's always taken, & removes the
+/// blocks which are then unreachable (returns the no. of `br`s replaced)
+pub fn simplify_branches(cfg: &mut Cfg) -> usize {
+    let analysis = analyze_ranges(cfg);
+    let mut simplified = 0;
+    for (block, ranges) in cfg.blocks.iter_mut().zip(&analysis.blocks) {
+        let (Some(exit), Some(br)) = (&ranges.exit, block.instrs.last_mut())
+        else {
+            continue;
+        };
+        if br.op != Opcode::Br || br.labels.len() != 2 {
+            continue;
+        }
+        let Some(AbsValue::Bool(Some(cond))) =
+            br.args.first().and_then(|cond| exit.get(cond))
+        else {
+            continue;
+        };
+        let target = br.labels[if *cond { 0 } else { 1 }].clone();
+        *br = Operation {
+            op: Opcode::Jmp,
+            dest: None,
+            ty: None,
+            value: None,
+            args: vec![],
+            labels: vec![target],
+            func: None,
+        };
+        simplified += 1;
+    }
+    cfg.remove_unreachable_blocks();
+    simplified
+}
+
+/// Simplifies the branches of a function (see `simplify_branches`),
+/// producing a new flat function
+pub fn simplify<P: IndexPair>(func: Function<'_, P>) -> InstrStore {
+    let mut cfg = Cfg::new(func);
+    simplify_branches(&mut cfg);
+    cfg.to_instr_store()
+}
+
+impl RangeAnalysis {
+    /// Renders the analysis as JSON: for each block, its name, whether it's
+    /// reachable, & the value of each variable at its entry & exit (see
+    /// `AbsValue::to_json`)
+    pub fn to_json(&self) -> serde_json::Value {
+        let env_json = |env: &Option<AbsEnv>| match env {
+            Some(env) => env
+                .iter()
+                .map(|(var, value)| (var.clone(), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            None => serde_json::Value::Null,
+        };
+        let blocks: Vec<serde_json::Value> = self
+            .names
+            .iter()
+            .zip(&self.blocks)
+            .map(|(name, ranges)| {
+                serde_json::json!({
+                    "block": name,
+                    "reachable": ranges.entry.is_some(),
+                    "entry": env_json(&ranges.entry),
+                    "exit": env_json(&ranges.exit),
+                })
+            })
+            .collect();
+        serde_json::json!({ "function": self.func, "blocks": blocks })
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod ranges_tests {
+    use serde_json::json;
+
+    use crate::cfg::Cfg;
+    use crate::interp::interp_program_captured;
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::program::Program;
+    use crate::ranges::{
+        AbsValue, Interval, analyze_ranges, simplify, simplify_branches,
+    };
+
+    /// The loop counter's range is bounded by the loop condition, so the
+    /// check after the loop is always false & its `br` is replaced by a
+    /// `jmp` (without changing the program's output)
+    #[test]
+    fn test_ranges() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "ten", "type": "int", "value": 10 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "ten"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["cond"] },
+                { "label": "body" },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "lt", "dest": "neg", "type": "bool",
+                  "args": ["i", "one"] },
+                { "op": "br", "labels": ["never", "end"], "args": ["neg"] },
+                { "label": "never" },
+                { "op": "print", "args": ["n"] },
+                { "label": "end" },
+                { "op": "print", "args": ["i"] }
+            ]
+        }]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let main = Program::new(program.views()).function("main").unwrap();
+
+        let mut cfg = Cfg::new(main);
+        let analysis = analyze_ranges(&cfg);
+        let body = cfg.block_index("body").unwrap();
+        let done = cfg.block_index("done").unwrap();
+        let value = |b: usize, var: &str| {
+            analysis.blocks[b].entry.as_ref().unwrap()[var]
+        };
+        assert_eq!(value(body, "i"), AbsValue::Int(Interval { lo: 0, hi: 9 }));
+        assert_eq!(value(done, "i").to_json(), json!({ "lo": 10, "hi": null }));
+        assert_eq!(value(done, "n"), AbsValue::Int(Interval::FULL));
+        assert_eq!(
+            analysis.to_json()["blocks"][done]["exit"]["neg"],
+            json!(false)
+        );
+
+        assert_eq!(simplify_branches(&mut cfg), 1);
+        assert!(cfg.block_index("never").is_none());
+
+        let expected = interp_program_captured(&program, vec!["3"]).unwrap();
+        let stores = Program::new(program.views()).functions().map(simplify);
+        let bytes =
+            AlignedBytes::new(&instr_stores_to_fbril_bytes(stores.collect()));
+        let simplified = get_flat_program(&bytes).expect("should load");
+        let actual = interp_program_captured(&simplified, vec!["3"]).unwrap();
+        assert_eq!(actual.lines, expected.lines);
+        assert!(actual.steps <= expected.steps);
+    }
+}
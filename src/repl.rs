@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+use serde_json::json;
+
+use crate::debugger::format_instr;
+use crate::fusion::with_instrs;
+use crate::interp::{
+    BranchTargets, EnvPool, Environment, FuncTable, InterpContext, Limits,
+    interp_instr_view,
+};
+use crate::memfile::{
+    AlignedBytes, get_program_views, instr_stores_to_fbril_bytes,
+};
+use crate::types::*;
+use crate::{flatten, slots};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The name of the function which holds the instructions typed at the prompt
+pub const REPL_FUNC: &str = "repl";
+
+/// An interactive session, where each line of Bril (in the text format) is
+/// flattened, appended to an in-memory function & executed straight away
+/// - `store` holds the instructions that have been executed so far
+///   (instructions which fail aren't kept)
+/// - `functions` are the functions loaded with `:load`, which the
+///   instructions can `call`
+/// - `env` is the environment which every instruction is executed in
+pub struct Repl {
+    store: InstrStore,
+    functions: Vec<InstrStore>,
+    env: HashMap<String, BrilValue>,
+}
+
+/// Help text displayed by the `:help` command
+const HELP: &str = "\
+Type Bril instructions in the text format (eg. `x: int = const 4;`) to run
+them. Control flow (labels, `jmp`, `br` & `ret`) isn't supported at the
+prompt, but functions loaded with `:load` can use it.
+commands:
+  :env           print every variable & its value
+  :list          list the instructions executed so far
+  :load FILE     load the functions in a JSON Bril program, so that they
+                 can be called (eg. `y: int = call @f x;`)
+  :help          print this message
+  :quit          exit (as does Ctrl-D)";
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Parses a single (non-label) instruction in Bril's text format
+/// (eg. `v: int = add a b;` or `print v;`) into Bril's JSON representation.
+/// Returns `Ok(None)` for a line which is blank or only has a comment.
+pub fn parse_instr(line: &str) -> Result<Option<serde_json::Value>, String> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    let line = line.strip_suffix(';').unwrap_or(line).trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.starts_with('.') {
+        return Err("labels aren't supported at the prompt".to_string());
+    }
+
+    // Split off the `dest: type =` part (if any)
+    let (dest, rest) = match line.split_once('=') {
+        Some((lhs, rhs)) => {
+            let Some((dest, ty)) = lhs.split_once(':') else {
+                return Err("expected `dest: type = ...`".to_string());
+            };
+            let (dest, ty) = (dest.trim(), ty.trim());
+            if dest.is_empty() || dest.contains(char::is_whitespace) {
+                return Err(format!("invalid destination `{dest}`"));
+            }
+            let ty: Type = serde_json::from_value(json!(ty))
+                .map_err(|_| format!("unknown type `{ty}`"))?;
+            (Some((dest, ty)), rhs)
+        }
+        None => (None, line),
+    };
+
+    let mut words = rest.split_whitespace();
+    let op_str = words.next().ok_or("missing opcode")?;
+    let op: Opcode = serde_json::from_value(json!(op_str))
+        .map_err(|_| format!("unknown opcode `{op_str}`"))?;
+    let mut instr = json!({ "op": op_str });
+    if let Some((dest, ty)) = dest {
+        instr["dest"] = dest.into();
+        instr["type"] = ty.to_json();
+    }
+    let words: Vec<&str> = words.collect();
+    if op == Opcode::Const {
+        let (Some((_, ty)), [value]) = (dest, words.as_slice()) else {
+            return Err("expected `dest: type = const value`".to_string());
+        };
+        instr["value"] = match (ty, *value) {
+            (Type::Bool, "true") => true.into(),
+            (Type::Bool, "false") => false.into(),
+            (Type::Int, value) => value
+                .parse::<i64>()
+                .map_err(|_| format!("invalid int `{value}`"))?
+                .into(),
+            (_, value) => return Err(format!("invalid bool `{value}`")),
+        };
+        return Ok(Some(instr));
+    }
+
+    let (mut args, mut labels, mut funcs) = (vec![], vec![], vec![]);
+    for word in words {
+        if let Some(func) = word.strip_prefix('@') {
+            funcs.push(func);
+        } else if let Some(label) = word.strip_prefix('.') {
+            labels.push(label);
+        } else {
+            args.push(word);
+        }
+    }
+    if labels.iter().chain(&funcs).any(|name| name.is_empty()) {
+        return Err("missing label / function name".to_string());
+    }
+    if funcs.len() > 1 {
+        return Err("at most one function can be called".to_string());
+    }
+    // (empty lists are omitted, as in `Operation::to_json`)
+    for (key, values) in [("args", args), ("labels", labels), ("funcs", funcs)]
+    {
+        if !values.is_empty() {
+            instr[key] = values.into();
+        }
+    }
+    Ok(Some(instr))
+}
+
+/// Appends the instructions in `other` to `store`, shifting the indexes
+/// in `other`'s instructions so that they refer to the end of `store`'s
+/// buffers (the slots of the variables in `store` are then recomputed)
+fn append_store(store: &mut InstrStore, other: InstrStore) {
+    store.append(other);
+    slots::assign_slots(store);
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    /// Creates a session with no instructions, functions or variables
+    pub fn new() -> Self {
+        Self {
+            store: flatten::flatten_instrs(
+                &json!({ "name": REPL_FUNC, "instrs": [] }),
+            ),
+            functions: vec![],
+            env: HashMap::new(),
+        }
+    }
+
+    /// Executes the last instruction in `self.store`
+    /// (the output of `print`s is written to `out`)
+    fn run_last_instr<W: Write>(&mut self, out: W) -> Result<(), String> {
+        let mut stores = self.functions.clone();
+        stores.push(self.store.clone());
+        let bytes = instr_stores_to_fbril_bytes(stores);
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data)?;
+        let (repl_view, func_views) = views
+            .split_last()
+            .expect("the REPL function is always present");
+
+        // Only the last instruction is executed
+        let last = repl_view.instrs.len() - 1;
+        let instr_view = with_instrs(repl_view, &repl_view.instrs[last..]);
+        let funcs = FuncTable::new(func_views);
+        let mut env: Environment = self
+            .env
+            .iter()
+            .map(|(var, value)| (var.as_str(), *value))
+            .collect();
+        let mut ctx = InterpContext::new(out, Limits::default());
+        interp_instr_view(
+            &instr_view,
+            &BranchTargets::new(1),
+            &mut env,
+            &funcs,
+            &mut ctx,
+            &mut EnvPool::default(),
+        )
+        .map_err(|diagnostic| diagnostic.to_string())?;
+        self.env = env
+            .into_iter()
+            .map(|(var, value)| (var.to_string(), value))
+            .collect();
+        Ok(())
+    }
+
+    /// Loads the functions in the JSON Bril program at `path`
+    fn load(&mut self, path: &str) -> Result<usize, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("unable to open `{path}`: {e}"))?;
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("invalid JSON in `{path}`: {e}"))?;
+        let functions = json["functions"]
+            .as_array()
+            .ok_or_else(|| format!("`{path}` has no `functions` array"))?;
+        let mut loaded = vec![];
+        for (func_idx, func) in functions.iter().enumerate() {
+            let name = func["name"].as_str().unwrap_or_default();
+            let defined = self
+                .functions
+                .iter()
+                .any(|store| store.func_name == name.as_bytes());
+            if name == REPL_FUNC || defined {
+                return Err(format!("function `@{name}` is already defined"));
+            }
+            loaded.push(flatten::try_flatten_instrs(func).map_err(|e| {
+                format!("malformed `{path}`: {}", e.in_program(func_idx))
+            })?);
+        }
+        let num_loaded = loaded.len();
+        self.functions.extend(loaded);
+        Ok(num_loaded)
+    }
+
+    /// Evaluates a line typed at the prompt (either a command or an
+    /// instruction), writing any output to `out`. Returns `Ok(false)` if
+    /// the session should end.
+    pub fn eval_line<W: Write>(
+        &mut self,
+        line: &str,
+        mut out: W,
+    ) -> Result<bool, String> {
+        let write_err = |e: io::Error| format!("unable to write output: {e}");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [":quit" | ":q"] => return Ok(false),
+            [":help" | ":h"] => writeln!(out, "{HELP}").map_err(write_err)?,
+            [":env" | ":e"] => {
+                let mut vars: Vec<(&String, &BrilValue)> =
+                    self.env.iter().collect();
+                vars.sort_by_key(|(var, _)| *var);
+                for (var, value) in vars {
+                    writeln!(out, "{var}: {} = {value}", value.get_type())
+                        .map_err(write_err)?;
+                }
+            }
+            [":list" | ":l"] => {
+                let bytes =
+                    instr_stores_to_fbril_bytes(vec![self.store.clone()]);
+                let data = AlignedBytes::new(&bytes);
+                let views = get_program_views(&data)?;
+                for (pc, instr) in views[0].instrs.iter().enumerate() {
+                    writeln!(
+                        out,
+                        "{pc:>4}  {}",
+                        format_instr(&views[0], instr)
+                    )
+                    .map_err(write_err)?;
+                }
+            }
+            [":load", path] => {
+                let num_loaded = self.load(path)?;
+                writeln!(out, "loaded {num_loaded} function(s) from `{path}`")
+                    .map_err(write_err)?;
+            }
+            [cmd, ..] if cmd.starts_with(':') => {
+                return Err(format!("unknown command `{cmd}` (try `:help`)"));
+            }
+            _ => {
+                let Some(instr) = parse_instr(line)? else {
+                    return Ok(true);
+                };
+                if matches!(
+                    instr["op"].as_str(),
+                    Some("jmp" | "br" | "ret" | "phi")
+                ) {
+                    return Err(format!(
+                        "`{}` isn't supported at the prompt",
+                        instr["op"].as_str().unwrap_or_default()
+                    ));
+                }
+                let func = json!({ "name": REPL_FUNC, "instrs": [instr] });
+                let old_store = self.store.clone();
+                let store = flatten::try_flatten_instrs(&func)
+                    .map_err(|e| e.to_string())?;
+                append_store(&mut self.store, store);
+                if let Err(msg) = self.run_last_instr(&mut out) {
+                    // Instructions which fail aren't kept
+                    self.store = old_store;
+                    return Err(msg);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Runs the read-eval-print loop, reading lines from `input` until EOF
+    /// or `:quit` (the prompt & errors are written to stderr, everything
+    /// else is written to `out`)
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        mut input: R,
+        mut out: W,
+    ) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            out.flush()?;
+            eprint!(">> ");
+            io::stderr().flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                eprintln!();
+                return Ok(());
+            }
+            match self.eval_line(&line, &mut out) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(msg) => eprintln!("error: {msg}"),
+            }
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod repl_tests {
+    use crate::repl::Repl;
+
+    /// Variables persist across lines, calls to loaded functions work, and
+    /// instructions which fail aren't kept
+    #[test]
+    fn test_repl_session() {
+        let mut repl = Repl::new();
+        let mut out = vec![];
+        for line in [
+            "a: int = const 6;  # a comment",
+            "b: int = const 7",
+            "c: int = mul a b;",
+            "print c;",
+            ":load test/fact.json",
+            "d: int = call @fact b;",
+            "print d;",
+        ] {
+            assert_eq!(repl.eval_line(line, &mut out), Ok(true), "{line}");
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "42\nloaded 2 function(s) \
+            from `test/fact.json`\n5040\n"
+        );
+
+        assert!(repl.eval_line("e: int = add c nope;", &mut out).is_err());
+        assert!(repl.eval_line("jmp .somewhere;", &mut out).is_err());
+        assert!(repl.eval_line("f: int = const true;", &mut out).is_err());
+        out.clear();
+        repl.eval_line(":list", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "   0  a: int = const 6;\n   1  b: int = const 7;\n   \
+             2  c: int = mul a b;\n   3  print c;\n   \
+             4  d: int = call @fact b;\n   5  print d;\n"
+        );
+        out.clear();
+        repl.eval_line(":env", &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "a: int = 6\nb: int = 7\nc: int = 42\nd: int = 5040\n"
+        );
+        assert_eq!(repl.eval_line(":quit", &mut out), Ok(false));
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::interp::{Environment, get_func_name};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The first 8 bytes of every execution trace file
+pub const TRACE_MAGIC: [u8; 8] = *b"FBRTRACE";
+
+/// The version of the trace format written by `ExecTrace::to_bytes`
+pub const TRACE_VERSION: u32 = 1;
+
+/// The sequence of (non-label) instructions executed by a run, along with
+/// the inputs needed to reproduce it
+/// - Core Bril programs are deterministic, so their only inputs are the
+///   function called (`entry`) & its command-line `args`
+/// - Each step is a (function, PC) pair, where the function is an index
+///   into `funcs` (so that each name is only stored once)
+///
+/// The binary format is (all integers are little-endian):
+/// `TRACE_MAGIC`, the version (`u32`), `entry`, the no. of `args` (`u32`) &
+/// then each arg, the no. of `funcs` (`u32`) & then each function name,
+/// & finally the no. of steps (`u64`) & then each step as two `u32`s,
+/// where strings are stored as their length (`u32`) followed by their bytes
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExecTrace {
+    pub entry: String,
+    pub args: Vec<String>,
+    pub funcs: Vec<String>,
+    pub steps: Vec<(u32, u32)>,
+}
+
+/// An `Observer` which records an `ExecTrace` of a run
+#[derive(Debug, Default)]
+pub struct ExecRecorder {
+    pub trace: ExecTrace,
+}
+
+/// An `Observer` which checks that a run executes exactly the
+/// instructions in `trace`, recording the first step at which it doesn't
+/// - `steps` is the no. of instructions executed so far
+/// - `mismatch` holds the step & the (function, PC) that was executed
+///   instead of the one in the trace
+pub struct Replayer<'t> {
+    trace: &'t ExecTrace,
+    steps: usize,
+    mismatch: Option<(usize, String, usize)>,
+}
+
+/// Reads the fields of a trace file in order (see `ExecTrace`)
+struct TraceReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a> TraceReader<'a> {
+    /// Reads the next `len` bytes of the trace
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len))
+            .ok_or_else(|| "trace file is truncated".to_string())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| "trace file contains invalid UTF-8".to_string())
+    }
+
+    /// Reads a `u32` count followed by that many strings
+    fn strings(&mut self) -> Result<Vec<String>, String> {
+        (0..self.u32()?).map(|_| self.string()).collect()
+    }
+}
+
+impl ExecTrace {
+    /// Serializes the trace in the binary format described in `ExecTrace`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = TRACE_MAGIC.to_vec();
+        bytes.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        let push_str = |bytes: &mut Vec<u8>, s: &str| {
+            bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(s.as_bytes());
+        };
+        push_str(&mut bytes, &self.entry);
+        for strings in [&self.args, &self.funcs] {
+            bytes.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+            for s in strings {
+                push_str(&mut bytes, s);
+            }
+        }
+        bytes.extend_from_slice(&(self.steps.len() as u64).to_le_bytes());
+        for (func, pc) in &self.steps {
+            bytes.extend_from_slice(&func.to_le_bytes());
+            bytes.extend_from_slice(&pc.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Deserializes a trace written by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(&TRACE_MAGIC) {
+            return Err("not an execution trace (bad magic number)".into());
+        }
+        let mut reader = TraceReader {
+            data,
+            pos: TRACE_MAGIC.len(),
+        };
+        let version = reader.u32()?;
+        if version != TRACE_VERSION {
+            return Err(format!(
+                "unsupported trace version {version} (expected \
+                {TRACE_VERSION})"
+            ));
+        }
+        let entry = reader.string()?;
+        let args = reader.strings()?;
+        let funcs = reader.strings()?;
+        let num_steps = reader.u64()?;
+        // (checked before allocating, since the count may be corrupt)
+        if num_steps > (data.len() / 8) as u64 {
+            return Err("trace file is truncated".into());
+        }
+        let steps = (0..num_steps)
+            .map(|_| {
+                let (func, pc) = (reader.u32()?, reader.u32()?);
+                if func as usize >= funcs.len() {
+                    return Err(format!(
+                        "step refers to unknown function {func}"
+                    ));
+                }
+                Ok((func, pc))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if reader.pos != data.len() {
+            return Err("trailing bytes after the last step".into());
+        }
+        Ok(Self {
+            entry,
+            args,
+            funcs,
+            steps,
+        })
+    }
+
+    /// Renders the step at `idx` as `@func:pc`
+    pub fn format_step(&self, idx: usize) -> String {
+        let (func, pc) = self.steps[idx];
+        format!("@{}:{pc}", self.funcs[func as usize])
+    }
+
+    /// Summarizes the trace for offline analysis: how the run was invoked,
+    /// how many steps each function executed, & the `n` instructions which
+    /// were executed most often
+    pub fn summary(&self, n: usize) -> String {
+        let mut text = format!(
+            "trace of @{}({}): {} step(s)\n",
+            self.entry,
+            self.args.join(", "),
+            self.steps.len()
+        );
+        let mut func_counts = vec![0u64; self.funcs.len()];
+        let mut pc_counts: HashMap<(u32, u32), u64> = HashMap::new();
+        for &(func, pc) in &self.steps {
+            func_counts[func as usize] += 1;
+            *pc_counts.entry((func, pc)).or_default() += 1;
+        }
+        let percent =
+            |count: u64| 100.0 * count as f64 / self.steps.len().max(1) as f64;
+        text.push_str("steps per function:\n");
+        for (func, count) in self.funcs.iter().zip(&func_counts) {
+            writeln!(
+                text,
+                "  @{func:<16} {count:>10} ({:.1}%)",
+                percent(*count)
+            )
+            .unwrap();
+        }
+
+        // Hottest first (ties are broken by function & PC, so that the
+        // summary is deterministic)
+        let mut hottest: Vec<((u32, u32), u64)> =
+            pc_counts.into_iter().collect();
+        hottest.sort_by(|(a, a_count), (b, b_count)| {
+            b_count.cmp(a_count).then(a.cmp(b))
+        });
+        writeln!(text, "hottest instructions:").unwrap();
+        for ((func, pc), count) in hottest.into_iter().take(n) {
+            let location = format!("@{}:{pc}", self.funcs[func as usize]);
+            writeln!(
+                text,
+                "  {location:<16} {count:>10} ({:.1}%)",
+                percent(count)
+            )
+            .unwrap();
+        }
+        text
+    }
+}
+
+impl ExecRecorder {
+    /// Creates a recorder for a run which calls `entry` with `args`
+    pub fn new(entry: &str, args: &[&str]) -> Self {
+        Self {
+            trace: ExecTrace {
+                entry: entry.to_string(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                ..ExecTrace::default()
+            },
+        }
+    }
+}
+
+impl Observer for ExecRecorder {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        _instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        let name = get_func_name(instr_view);
+        let funcs = &mut self.trace.funcs;
+        let func = match funcs.iter().position(|func| func == name) {
+            Some(func) => func,
+            None => {
+                funcs.push(name.to_string());
+                funcs.len() - 1
+            }
+        };
+        self.trace.steps.push((func as u32, pc as u32));
+    }
+}
+
+impl<'t> Replayer<'t> {
+    /// Creates a replayer which checks a run against `trace`
+    pub fn new(trace: &'t ExecTrace) -> Self {
+        Self {
+            trace,
+            steps: 0,
+            mismatch: None,
+        }
+    }
+
+    /// Checks that the run executed exactly the steps in the trace,
+    /// returning the no. of steps if so, or a `ReplayMismatch` error
+    /// describing the first step at which the run & the trace differ
+    pub fn finish(&self) -> Result<usize, Diagnostic> {
+        let message = match &self.mismatch {
+            Some((step, func, pc)) if *step < self.trace.steps.len() => {
+                format!(
+                    "replay diverged from the trace at step {step}: expected \
+                    {}, but executed @{func}:{pc}",
+                    self.trace.format_step(*step)
+                )
+            }
+            Some((step, func, pc)) => format!(
+                "replay diverged from the trace at step {step}: the trace \
+                ends there, but the run executed @{func}:{pc}"
+            ),
+            None if self.steps < self.trace.steps.len() => format!(
+                "replay stopped after {} step(s), but the trace continues \
+                with {}",
+                self.steps,
+                self.trace.format_step(self.steps)
+            ),
+            None => return Ok(self.steps),
+        };
+        Err(Diagnostic::new(ErrorCode::ReplayMismatch, message))
+    }
+}
+
+impl Observer for Replayer<'_> {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        _instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        let step = self.steps;
+        self.steps += 1;
+        if self.mismatch.is_some() {
+            return;
+        }
+        let name = get_func_name(instr_view);
+        let matches =
+            self.trace.steps.get(step).is_some_and(|&(func, t_pc)| {
+                self.trace.funcs[func as usize] == name && t_pc as usize == pc
+            });
+        if !matches {
+            self.mismatch = Some((step, name.to_string(), pc));
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod replay_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::diagnostic::ErrorCode;
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::replay::{ExecRecorder, ExecTrace, Replayer};
+
+    /// A recorded trace survives a round trip through the binary format,
+    /// replays cleanly with the same args, & reports a mismatch with others
+    #[test]
+    fn test_record_and_replay() {
+        let file = File::open("test/fib_recursive.json").unwrap();
+        let json = serde_json::from_reader(BufReader::new(file)).unwrap();
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("well-formed program");
+
+        let mut recorder = ExecRecorder::new("main", &["5"]);
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut recorder);
+        interp_program(&program, vec!["5"], &mut ctx).unwrap();
+        let steps = ctx.steps as usize;
+        let trace = recorder.trace;
+        assert_eq!(trace.steps.len(), steps);
+        assert_eq!(trace.funcs, ["main", "fib"]);
+
+        let decoded = ExecTrace::from_bytes(&trace.to_bytes()).unwrap();
+        assert_eq!(decoded, trace);
+        assert!(ExecTrace::from_bytes(&trace.to_bytes()[..20]).is_err());
+        assert!(decoded.summary(3).starts_with("trace of @main(5): "));
+
+        let replay = |arg: &str| {
+            let mut replayer = Replayer::new(&decoded);
+            let mut ctx = InterpContext::new(vec![], Limits::default())
+                .with_observer(&mut replayer);
+            interp_program(&program, vec![arg], &mut ctx).unwrap();
+            replayer.finish()
+        };
+        assert_eq!(replay("5"), Ok(steps));
+        for arg in ["4", "6"] {
+            let diagnostic = replay(arg).unwrap_err();
+            assert_eq!(diagnostic.code, ErrorCode::ReplayMismatch);
+            assert!(diagnostic.message.starts_with("replay diverged"));
+        }
+    }
+}
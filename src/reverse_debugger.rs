@@ -0,0 +1,493 @@
+use std::io::{self, BufRead, Write};
+
+use crate::debugger::{
+    Breakpoint, Breakpoints, format_instr, parse_breakpoint, write_listing,
+};
+use crate::diagnostic::Diagnostic;
+use crate::interp::{FuncTable, Limits, get_func_name};
+use crate::scheduler::{Task, TaskStatus};
+use crate::snapshot::Snapshot;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The default no. of instructions between the checkpoints of a
+/// `ReverseDebugger`
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 1000;
+
+/// An interactive debugger which can step backwards as well as forwards.
+/// Bril programs are deterministic, so rather than recording every state,
+/// it takes a snapshot of the task every `interval` instructions, & moves
+/// back to step N by restoring the last checkpoint at or before N &
+/// re-executing the instructions after it.
+/// - `checkpoints[k]` is the snapshot after `k * interval` instructions,
+///   along with the no. of bytes the program had printed by then
+/// - `base_out` is the no. of bytes printed before `task` was restored
+///   (`task.out` only holds what it printed after that)
+/// - `output` is everything the program has printed up to the furthest
+///   step reached so far, so that output is only shown the first time it's
+///   printed, & not again when the instructions are re-executed
+pub struct ReverseDebugger<'a, P: IndexPair = I32Pair> {
+    funcs: &'a FuncTable<'a, P>,
+    entry: String,
+    limits: Limits,
+    interval: u64,
+    checkpoints: Vec<(Snapshot, usize)>,
+    task: Task<'a, P>,
+    base_out: usize,
+    output: Vec<u8>,
+    breakpoints: Breakpoints,
+}
+
+/// Help text displayed by the `help` command
+const HELP: &str = "\
+commands:
+  s, step [N]             execute N instructions (default 1)
+  rs, reverse-step [N]    go back N instructions (default 1)
+  c, continue             run until the next breakpoint
+  rc, reverse-continue    go back to the previous breakpoint
+  goto N                  go to the state after N instructions
+  b, break [@f] TGT       set a breakpoint, where TGT is a PC or a `.label`
+                          (in function `@f`, or the current function)
+  d, delete N             delete breakpoint no. N
+  i, info                 list all breakpoints
+  p, print VAR            print the value of a variable
+  e, env                  print the entire environment
+  l, list [N]             list the N instructions around the current PC
+  bt, backtrace           print the call stack
+  h, help                 print this message
+  q, quit                 stop debugging";
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a, P: IndexPair> ReverseDebugger<'a, P> {
+    /// Creates a debugger for a run which calls the function `entry` in
+    /// `funcs` with the arguments `cmd_line_args` under `limits`, taking a
+    /// checkpoint every `interval` instructions (returns an error if there's
+    /// no such function, or if the arguments are wrong)
+    pub fn new(
+        funcs: &'a FuncTable<'a, P>,
+        entry: &str,
+        cmd_line_args: &[&str],
+        limits: Limits,
+        interval: u64,
+    ) -> Result<Self, Diagnostic> {
+        let task = Task::new(funcs, entry, cmd_line_args, limits)?;
+        let start = task.snapshot().expect("a new task is runnable");
+        Ok(Self {
+            funcs,
+            entry: entry.to_string(),
+            limits,
+            interval: interval.max(1),
+            checkpoints: vec![(start, 0)],
+            task,
+            base_out: 0,
+            output: vec![],
+            breakpoints: Breakpoints::new(funcs),
+        })
+    }
+
+    /// The no. of instructions executed to reach the current state
+    pub fn steps(&self) -> u64 {
+        self.task.steps
+    }
+
+    /// Everything the program has printed up to the furthest step reached
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// The task which is being debugged, in its current state
+    pub fn task(&self) -> &Task<'a, P> {
+        &self.task
+    }
+
+    /// Replaces all of the breakpoints
+    /// (which must be at existing instructions, see `Breakpoints::replace`)
+    pub fn set_breakpoints(&mut self, breakpoints: Vec<Breakpoint>) {
+        self.breakpoints.replace(breakpoints);
+    }
+
+    /// Executes one instruction, recording any new output & taking a
+    /// checkpoint if one is due
+    pub fn step(&mut self) {
+        self.task.run(1);
+        let printed = self.base_out + self.task.out.len();
+        if printed > self.output.len() {
+            let new_out = &self.task.out[self.output.len() - self.base_out..];
+            self.output.extend_from_slice(new_out);
+        }
+        let steps = self.task.steps;
+        if steps.is_multiple_of(self.interval)
+            && steps / self.interval == self.checkpoints.len() as u64
+            && let Some(snapshot) = self.task.snapshot()
+        {
+            self.checkpoints.push((snapshot, printed));
+        }
+    }
+
+    /// Moves to the state after `target` instructions (or to the end of the
+    /// run, if it finishes before then), restoring the nearest checkpoint
+    /// if `target` is before the current state
+    pub fn goto(&mut self, target: u64) {
+        if target < self.task.steps {
+            let idx = ((target / self.interval) as usize)
+                .min(self.checkpoints.len() - 1);
+            let (snapshot, printed) = &self.checkpoints[idx];
+            self.task = Task::restore(self.funcs, snapshot, self.limits)
+                .expect("checkpoints are taken from the same program");
+            self.base_out = *printed;
+        }
+        while self.task.steps < target && !self.task.is_finished() {
+            self.step();
+        }
+    }
+
+    /// Whether the next instruction to execute is at a breakpoint
+    pub fn at_breakpoint(&self) -> bool {
+        match self.task.location() {
+            Some((view, pc)) if !self.task.is_finished() => {
+                self.breakpoints.any_matches(view, pc)
+            }
+            _ => false,
+        }
+    }
+
+    /// Executes at least one instruction, stopping before the next
+    /// instruction which is at a breakpoint (or when the program finishes)
+    pub fn continue_to_breakpoint(&mut self) {
+        self.step();
+        while !self.task.is_finished() && !self.at_breakpoint() {
+            self.step();
+        }
+    }
+
+    /// Moves back to the last earlier state which is at a breakpoint
+    /// (returns false & moves back to the start of the run if there's none)
+    pub fn reverse_to_breakpoint(&mut self) -> bool {
+        match self.last_breakpoint_before(self.task.steps) {
+            Some(steps) => {
+                self.goto(steps);
+                true
+            }
+            None => {
+                self.goto(0);
+                false
+            }
+        }
+    }
+
+    /// Finds the last state before step `end` which is at a breakpoint, by
+    /// re-executing the checkpointed segments before `end`, latest first
+    /// (this leaves the debugger in an arbitrary earlier state)
+    fn last_breakpoint_before(&mut self, mut end: u64) -> Option<u64> {
+        while end > 0 {
+            let start = (end - 1) / self.interval * self.interval;
+            self.goto(start);
+            let mut found = None;
+            while self.task.steps < end && !self.task.is_finished() {
+                if self.at_breakpoint() {
+                    found = Some(self.task.steps);
+                }
+                self.step();
+            }
+            if found.is_some() {
+                return found;
+            }
+            end = start;
+        }
+        None
+    }
+
+    /// Describes the current state, i.e. the next instruction to execute
+    /// (or how the program finished)
+    fn describe(&self) -> String {
+        let steps = self.task.steps;
+        match &self.task.status {
+            TaskStatus::Returned(Some(value)) => {
+                format!("[step {steps}] @{} returned {value}", self.entry)
+            }
+            TaskStatus::Returned(None) => {
+                format!("[step {steps}] @{} returned", self.entry)
+            }
+            TaskStatus::Failed(e) => format!("[step {steps}] error: {e}"),
+            TaskStatus::Runnable => {
+                let (view, pc) =
+                    self.task.location().expect("a runnable task has a frame");
+                let instr = match view.instrs.get(pc) {
+                    Some(instr) => format_instr(view, instr),
+                    None => "(end of function)".to_string(),
+                };
+                format!("[step {steps}] @{}:{pc}  {instr}", get_func_name(view))
+            }
+        }
+    }
+
+    /// The state of the call stack, which is only available while the
+    /// program is still running
+    fn current_snapshot(&self) -> Result<Snapshot, String> {
+        self.task.snapshot().ok_or_else(|| {
+            "the program has finished (use `rs` to step back)".to_string()
+        })
+    }
+
+    /// Executes the command in `line`, writing any output the program prints
+    /// for the first time to `out` & the debugger's own output to `log`.
+    /// Returns `Ok(false)` if the user asked to quit.
+    pub fn eval_line(
+        &mut self,
+        line: &str,
+        out: &mut impl Write,
+        log: &mut impl Write,
+    ) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((cmd, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let count = || match args.first() {
+            Some(n) => n.parse::<u64>().map_err(|_| format!("invalid N `{n}`")),
+            None => Ok(1),
+        };
+        let io_err = |e: io::Error| format!("unable to write output: {e}");
+        let printed = self.output.len();
+        let mut moved = true;
+        match *cmd {
+            "s" | "step" => {
+                if self.task.is_finished() {
+                    return Err(self.current_snapshot().unwrap_err());
+                }
+                let target = self.task.steps.saturating_add(count()?);
+                self.goto(target);
+            }
+            "rs" | "reverse-step" => {
+                if self.task.steps == 0 {
+                    return Err("already at the start of the run".to_string());
+                }
+                self.goto(self.task.steps.saturating_sub(count()?));
+            }
+            "c" | "continue" => {
+                if self.task.is_finished() {
+                    return Err(self.current_snapshot().unwrap_err());
+                }
+                self.continue_to_breakpoint();
+            }
+            "rc" | "reverse-continue" => {
+                if !self.reverse_to_breakpoint() {
+                    writeln!(log, "no earlier breakpoint").map_err(io_err)?;
+                }
+            }
+            "goto" => match args.first().map(|n| n.parse::<u64>()) {
+                Some(Ok(target)) => self.goto(target),
+                _ => return Err("usage: goto N".to_string()),
+            },
+            _ => moved = false,
+        }
+        out.write_all(&self.output[printed..]).map_err(io_err)?;
+        out.flush().map_err(io_err)?;
+        if moved {
+            writeln!(log, "{}", self.describe()).map_err(io_err)?;
+            return Ok(true);
+        }
+
+        match *cmd {
+            "b" | "break" => {
+                let func = match self.task.location() {
+                    Some((view, _)) => get_func_name(view),
+                    None => &self.entry,
+                };
+                let id = self.breakpoints.add(parse_breakpoint(args, func)?)?;
+                writeln!(log, "breakpoint {id} set").map_err(io_err)?;
+            }
+            "d" | "delete" => {
+                match args.first().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) if self.breakpoints.delete(n) => (),
+                    _ => return Err("usage: delete N (see `info`)".to_string()),
+                }
+            }
+            "i" | "info" => {
+                for (id, bp) in self.breakpoints.iter() {
+                    writeln!(log, "{id}: {bp}").map_err(io_err)?;
+                }
+            }
+            "p" | "print" => {
+                let snapshot = self.current_snapshot()?;
+                let frame = snapshot.frames.last().expect("task has a frame");
+                for var in args {
+                    match frame.env.get(*var) {
+                        Some(value) => writeln!(log, "{var} = {value}"),
+                        None => writeln!(log, "{var} is undefined"),
+                    }
+                    .map_err(io_err)?;
+                }
+            }
+            "e" | "env" => {
+                let snapshot = self.current_snapshot()?;
+                let frame = snapshot.frames.last().expect("task has a frame");
+                for (var, value) in &frame.env {
+                    writeln!(log, "{var}: {} = {value}", value.get_type())
+                        .map_err(io_err)?;
+                }
+            }
+            "l" | "list" => {
+                let (view, pc) = self.task.location().ok_or(
+                    "the program has finished (use `rs` to step back)",
+                )?;
+                let radius = args
+                    .first()
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .unwrap_or(5);
+                write_listing(log, view, pc, radius).map_err(io_err)?;
+            }
+            "bt" | "backtrace" => {
+                let snapshot = self.current_snapshot()?;
+                for (depth, frame) in snapshot.frames.iter().enumerate().rev() {
+                    writeln!(log, "#{depth} @{}:{}", frame.func, frame.pc)
+                        .map_err(io_err)?;
+                }
+            }
+            "h" | "help" => writeln!(log, "{HELP}").map_err(io_err)?,
+            "q" | "quit" => return Ok(false),
+            _ => return Err(format!("unknown command `{cmd}` (try `help`)")),
+        }
+        Ok(true)
+    }
+
+    /// Reads commands from `input` until the user quits (or `input` ends),
+    /// writing the program's output to `out` & prompts, the current state &
+    /// errors to stderr
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        mut input: R,
+        mut out: W,
+    ) -> io::Result<()> {
+        let mut log = io::stderr();
+        writeln!(log, "{}", self.describe())?;
+        let mut line = String::new();
+        loop {
+            write!(log, "(fbril-rdb) ")?;
+            log.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(log)?;
+                return Ok(());
+            }
+            match self.eval_line(&line, &mut out, &mut log) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(msg) => writeln!(log, "{msg}")?,
+            }
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod reverse_debugger_tests {
+    use crate::interp::{FuncTable, Limits, interp_program_captured};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+    use crate::reverse_debugger::ReverseDebugger;
+
+    /// Stepping back reconstructs the same state that was reached going
+    /// forwards, & the program's output is only shown once
+    #[test]
+    fn test_reverse_debugger() {
+        let json_str = std::fs::read_to_string("test/fib_recursive.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let expected = interp_program_captured(&program, vec!["10"]).unwrap();
+        let funcs = FuncTable::new(program.views());
+        let mut dbg =
+            ReverseDebugger::new(&funcs, "main", &["10"], Limits::default(), 7)
+                .unwrap();
+        let (mut out, mut log) = (vec![], vec![]);
+        let mut eval = |dbg: &mut ReverseDebugger, line: &str| {
+            log.clear();
+            assert_eq!(dbg.eval_line(line, &mut out, &mut log), Ok(true));
+            String::from_utf8_lossy(&log).to_string()
+        };
+
+        eval(&mut dbg, "s 100");
+        let forward = eval(&mut dbg, "bt") + &eval(&mut dbg, "e");
+        eval(&mut dbg, "s 23");
+        assert!(eval(&mut dbg, "rs 23").starts_with("[step 100] @"));
+        assert_eq!(eval(&mut dbg, "bt") + &eval(&mut dbg, "e"), forward);
+
+        // (running to the end & back again doesn't print anything twice)
+        eval(&mut dbg, "c");
+        assert_eq!(dbg.steps(), expected.steps);
+        eval(&mut dbg, "goto 3");
+        eval(&mut dbg, "c");
+        assert_eq!(String::from_utf8_lossy(dbg.output()), "55\n");
+
+        // Reverse-continue stops at the last time a breakpoint was reached
+        eval(&mut dbg, "b @fib 0");
+        let before = dbg.steps();
+        assert!(eval(&mut dbg, "rc").contains("@fib:0"));
+        let last_call = dbg.steps();
+        assert!(last_call < before);
+        eval(&mut dbg, "s");
+        assert!(eval(&mut dbg, "c").contains("returned"));
+        assert_eq!(dbg.steps(), before);
+        eval(&mut dbg, "goto 0");
+        assert!(eval(&mut dbg, "rc").starts_with("no earlier breakpoint"));
+        assert_eq!(dbg.eval_line("q", &mut out, &mut log), Ok(false));
+        assert_eq!(String::from_utf8_lossy(&out), "55\n");
+    }
+
+    /// Breakpoints on functions, PCs or labels which don't exist are
+    /// rejected, & deleting a breakpoint doesn't renumber the others
+    #[test]
+    fn test_breakpoints() {
+        let json_str = std::fs::read_to_string("test/fib_recursive.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let funcs = FuncTable::new(program.views());
+        let mut dbg =
+            ReverseDebugger::new(&funcs, "main", &["10"], Limits::default(), 7)
+                .unwrap();
+        let mut log = vec![];
+        let mut eval = |line: &str| {
+            log.clear();
+            dbg.eval_line(line, &mut vec![], &mut log)
+                .map(|_| String::from_utf8_lossy(&log).to_string())
+        };
+
+        assert_eq!(
+            eval("b 99"),
+            Err("@main has no PC 99 (it has 3 instructions)".to_string())
+        );
+        assert_eq!(
+            eval("b @nosuch 1"),
+            Err("no function named @nosuch".to_string())
+        );
+        assert_eq!(
+            eval("b @fib .nosuch"),
+            Err("@fib has no label .nosuch before an instruction".to_string())
+        );
+
+        assert_eq!(eval("b 2"), Ok("breakpoint 0 set\n".to_string()));
+        assert_eq!(
+            eval("b @fib .else.0"),
+            Ok("breakpoint 1 set\n".to_string())
+        );
+        assert_eq!(eval("b @fib 0"), Ok("breakpoint 2 set\n".to_string()));
+        assert_eq!(eval("d 1"), Ok(String::new()));
+        assert!(eval("d 1").is_err());
+        assert_eq!(eval("i"), Ok("0: @main 2\n2: @fib 0\n".to_string()));
+        assert_eq!(
+            eval("b @fib .then.0"),
+            Ok("breakpoint 3 set\n".to_string())
+        );
+    }
+}
@@ -0,0 +1,587 @@
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::fusion::FusedOp;
+use crate::interp::{
+    BranchTargets, EnvPool, Environment, FuncTable, Limits, bind_call_args,
+    bind_entry_args, branch_target, call_depth_exceeded, get_arg_pairs,
+    get_func_name, get_n_args, get_table_label_name, get_value, get_var,
+    interp_binop, interp_const, interp_phi, interp_print, interp_unop,
+    jump_target, lookup_callee, missing_return_value, unsupported_instr,
+};
+use crate::snapshot::{FrameSnapshot, SNAPSHOT_VERSION, Snapshot, find_var};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A call which is currently on a task's stack
+/// - `pc` is the PC of the next instruction to execute (while the function
+///   is calling another one, it's the PC of the `call`)
+/// - `current_label` & `prev_label` are the indexes in the label table of
+///   the most recently reached label & the one before it (which `phi`s pick
+///   their arg by), and `next_label` is the next label that will be reached
+struct Frame<'a, P: IndexPair> {
+    view: &'a InstrView<'a, P>,
+    targets: &'a BranchTargets,
+    env: Environment<'a>,
+    pc: usize,
+    current_label: Option<usize>,
+    prev_label: Option<usize>,
+    next_label: usize,
+}
+
+/// Whether a task can still be run, or how it finished
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskStatus {
+    Runnable,
+    /// The entry function returned (with the value, if any)
+    Returned(Option<BrilValue>),
+    /// Interpretation failed (the error is located at the PC where it
+    /// occurred)
+    Failed(Diagnostic),
+}
+
+/// A program which is being interpreted a slice at a time: unlike
+/// `interp::interp_entry`, which runs a program to completion, a task keeps
+/// its call stack on the heap, so that it can stop after any instruction &
+/// be resumed later
+/// - `out` holds everything the program has printed so far
+/// - `steps` is the no. of (non-label) instructions executed so far, &
+///   `turns` is the no. of slices it's been given by a `Scheduler`
+/// - Like the interpreter, a task enforces the step & call depth limits in
+///   `limits` (the timeout is ignored, since a task's wall-clock time also
+///   includes the turns of all the other tasks). Observers & runtime type
+///   checks aren't supported, and superinstructions are executed as the
+///   pairs of instructions that they replaced.
+pub struct Task<'a, P: IndexPair = I32Pair> {
+    funcs: &'a FuncTable<'a, P>,
+    limits: Limits,
+    stack: Vec<Frame<'a, P>>,
+    pool: EnvPool<'a>,
+    pub out: Vec<u8>,
+    pub steps: u64,
+    pub turns: u64,
+    pub status: TaskStatus,
+}
+
+/// Interleaves the execution of several tasks on a single thread, by giving
+/// each runnable task in turn (round-robin) a slice of `fuel` instructions,
+/// so that a task which never finishes (or blocks for a long time) can't
+/// starve the others
+pub struct Scheduler<'a, P: IndexPair = I32Pair> {
+    fuel: u64,
+    tasks: Vec<Task<'a, P>>,
+    /// The index of the task which is considered first for the next turn
+    next: usize,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<'a, P: IndexPair> Frame<'a, P> {
+    fn new(
+        view: &'a InstrView<'a, P>,
+        targets: &'a BranchTargets,
+        env: Environment<'a>,
+    ) -> Self {
+        Self {
+            view,
+            targets,
+            env,
+            pc: 0,
+            current_label: None,
+            prev_label: None,
+            next_label: 0,
+        }
+    }
+
+    /// Moves control to the label at index `target` in the label table
+    fn jump_to(&mut self, target: usize) {
+        self.pc = self.view.label_table[target].pc;
+        self.next_label = target;
+    }
+}
+
+impl<'a, P: IndexPair> Task<'a, P> {
+    /// Creates a task which calls the function `entry` in `funcs` with the
+    /// arguments `cmd_line_args` (which are parsed according to their types)
+    /// under `limits`, without executing any instructions yet
+    /// (returns an error if there's no such function, or if the arguments
+    /// are wrong)
+    pub fn new(
+        funcs: &'a FuncTable<'a, P>,
+        entry: &str,
+        cmd_line_args: &[&str],
+        limits: Limits,
+    ) -> Result<Self, Diagnostic> {
+        let (view, targets) = funcs.get(entry)?.ok_or_else(|| {
+            Diagnostic::new(
+                ErrorCode::UndefinedFunction,
+                format!("program has no @{entry} function"),
+            )
+        })?;
+        let env = bind_entry_args(view, cmd_line_args)?;
+        Ok(Self {
+            funcs,
+            limits,
+            stack: vec![Frame::new(view, targets, env)],
+            pool: EnvPool::default(),
+            out: vec![],
+            steps: 0,
+            turns: 0,
+            status: TaskStatus::Runnable,
+        })
+    }
+
+    /// Restores a task which was suspended while running a program in
+    /// `funcs` from `snapshot`, which continues to run under `limits`
+    /// (returns an error if the snapshot doesn't match the program, i.e. if
+    /// it refers to a function, variable, label or PC that doesn't exist, or
+    /// a frame other than the innermost one isn't at a `call`)
+    pub fn restore(
+        funcs: &'a FuncTable<'a, P>,
+        snapshot: &Snapshot,
+        limits: Limits,
+    ) -> Result<Self, Diagnostic> {
+        let mismatch = |msg: String| {
+            Diagnostic::new(
+                ErrorCode::MalformedFile,
+                format!("snapshot doesn't match the program: {msg}"),
+            )
+        };
+        if snapshot.frames.is_empty() {
+            return Err(mismatch("it has no frames".to_string()));
+        }
+        let mut stack = vec![];
+        for (idx, saved) in snapshot.frames.iter().enumerate() {
+            let func = &saved.func;
+            let (view, targets) = funcs
+                .get(func)?
+                .ok_or_else(|| mismatch(format!("no function @{func}")))?;
+            let is_innermost = idx + 1 == snapshot.frames.len();
+            let at_call = view.instrs.get(saved.pc).is_some_and(|instr| {
+                Opcode::u32_to_opcode(instr.op()) == Some(Opcode::Call)
+            });
+            if saved.pc > view.instrs.len() || !(is_innermost || at_call) {
+                return Err(mismatch(format!(
+                    "@{func} can't be suspended at pc {}",
+                    saved.pc
+                )));
+            }
+            let num_labels = view.label_table.len();
+            if saved.next_label > num_labels
+                || [saved.current_label, saved.prev_label]
+                    .iter()
+                    .flatten()
+                    .any(|&label| label >= num_labels)
+            {
+                return Err(mismatch(format!("@{func} has no such label")));
+            }
+            let mut env = Environment::new();
+            for (name, value) in &saved.env {
+                let var = find_var(view, name).ok_or_else(|| {
+                    mismatch(format!("@{func} has no variable `{name}`"))
+                })?;
+                env.insert(var, *value);
+            }
+            stack.push(Frame {
+                view,
+                targets,
+                env,
+                pc: saved.pc,
+                current_label: saved.current_label,
+                prev_label: saved.prev_label,
+                next_label: saved.next_label,
+            });
+        }
+        Ok(Self {
+            funcs,
+            limits,
+            stack,
+            pool: EnvPool::default(),
+            out: vec![],
+            steps: snapshot.steps,
+            turns: 0,
+            status: TaskStatus::Runnable,
+        })
+    }
+
+    /// Captures the task's state, so that it can be restored later with
+    /// `restore` (returns `None` if the task has already finished)
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        if self.is_finished() {
+            return None;
+        }
+        let frames = self
+            .stack
+            .iter()
+            .map(|frame| FrameSnapshot {
+                func: get_func_name(frame.view).to_string(),
+                pc: frame.pc,
+                current_label: frame.current_label,
+                prev_label: frame.prev_label,
+                next_label: frame.next_label,
+                env: frame
+                    .env
+                    .iter()
+                    .map(|(var, value)| (var.to_string(), *value))
+                    .collect(),
+            })
+            .collect();
+        Some(Snapshot {
+            version: SNAPSHOT_VERSION,
+            steps: self.steps,
+            frames,
+        })
+    }
+
+    /// Whether the task has finished (successfully or not)
+    pub fn is_finished(&self) -> bool {
+        self.status != TaskStatus::Runnable
+    }
+
+    /// The innermost call's function & the PC of the next instruction it
+    /// will execute (`None` once the entry function has returned)
+    pub fn location(&self) -> Option<(&'a InstrView<'a, P>, usize)> {
+        self.stack.last().map(|frame| (frame.view, frame.pc))
+    }
+
+    /// The no. of calls on the stack
+    pub fn depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Executes at most `fuel` instructions, stopping early if the task
+    /// finishes, & returns the no. of instructions executed
+    pub fn run(&mut self, fuel: u64) -> u64 {
+        let start = self.steps;
+        while !self.is_finished() && self.steps - start < fuel {
+            if let Err(e) = self.step() {
+                let located = match self.stack.last() {
+                    Some(frame) => {
+                        e.located(get_func_name(frame.view), frame.pc)
+                    }
+                    None => e,
+                };
+                self.status = TaskStatus::Failed(located);
+            }
+        }
+        self.steps - start
+    }
+
+    /// Runs the task until it finishes
+    pub fn run_to_completion(&mut self) -> &TaskStatus {
+        while !self.is_finished() {
+            self.run(u64::MAX);
+        }
+        &self.status
+    }
+
+    /// Returns from the innermost call with `value`, storing it in the
+    /// caller's dest (or finishing the task if it was the entry function)
+    fn ret(&mut self, value: Option<BrilValue>) -> Result<(), Diagnostic> {
+        let callee = self.stack.pop().expect("a runnable task has a frame");
+        self.pool.give(callee.env);
+        let Some(caller) = self.stack.last_mut() else {
+            self.status = TaskStatus::Returned(value);
+            return Ok(());
+        };
+        let instr = &caller.view.instrs[caller.pc];
+        if let InstrKind::ValueOp = instr.get_instr_kind() {
+            let value = value.ok_or_else(|| {
+                missing_return_value(get_func_name(callee.view))
+            })?;
+            let (dest_offset, dest_len) = instr.dest.idxes();
+            let dest = get_var(caller.view, dest_offset, dest_len);
+            caller.env.insert(dest, value);
+        }
+        caller.pc += 1;
+        Ok(())
+    }
+
+    /// Executes one instruction of the innermost call (returning from it if
+    /// control has reached the end of the function)
+    fn step(&mut self) -> Result<(), Diagnostic> {
+        let depth = self.stack.len();
+        let frame = self.stack.last_mut().expect("a runnable task has a frame");
+        let view = frame.view;
+        if frame.pc >= view.instrs.len() {
+            return self.ret(None);
+        }
+        while let Some(label) = view.label_table.get(frame.next_label)
+            && label.pc <= frame.pc
+        {
+            frame.prev_label = frame.current_label;
+            frame.current_label = Some(frame.next_label);
+            frame.next_label += 1;
+        }
+
+        // (like `InterpContext::tick`, a refused instruction isn't counted)
+        if let Some(max_steps) = self.limits.max_steps
+            && self.steps >= max_steps
+        {
+            return Err(Diagnostic::new(
+                ErrorCode::StepLimit,
+                format!("exceeded the maximum of {max_steps} steps"),
+            ));
+        }
+        self.steps += 1;
+
+        let instr = &view.instrs[frame.pc];
+        // (the first instruction of a superinstruction keeps its operands,
+        // & the second one is left in place after it)
+        let op = match FusedOp::decode(instr.op()) {
+            Some((_, first)) => first,
+            None => Opcode::try_from(instr.op()).map_err(|msg| {
+                if instr.op() == EXT_OP {
+                    unsupported_instr(view, instr)
+                } else {
+                    Diagnostic::new(ErrorCode::MalformedInstr, msg)
+                }
+            })?,
+        };
+        match op {
+            Opcode::Const => interp_const(view, instr, &mut frame.env)?,
+            Opcode::Print => {
+                interp_print(view, instr, &frame.env, &mut self.out)?
+            }
+            Opcode::Nop => {}
+            Opcode::Phi => {
+                let prev_label =
+                    frame.prev_label.map(|idx| get_table_label_name(view, idx));
+                interp_phi(view, instr, &mut frame.env, prev_label)?;
+            }
+            Opcode::Jmp => {
+                let target = jump_target(view, frame.targets, frame.pc, instr)?;
+                frame.jump_to(target);
+                return Ok(());
+            }
+            Opcode::Br => {
+                let Some([arg]) = get_n_args(view, instr.args) else {
+                    return Err(Diagnostic::new(
+                        ErrorCode::MalformedInstr,
+                        "br instruction must only have 1 arg",
+                    ));
+                };
+                let BrilValue::BoolVal(condition) =
+                    *get_value(&frame.env, arg)?
+                else {
+                    return Err(Diagnostic::new(
+                        ErrorCode::TypeError,
+                        "argument to br instruction is ill-typed \
+                        (doesn't have type bool)",
+                    ));
+                };
+                let target = branch_target(
+                    view,
+                    frame.targets,
+                    frame.pc,
+                    instr,
+                    condition.into(),
+                )?;
+                frame.jump_to(target);
+                return Ok(());
+            }
+            Opcode::Call => {
+                let (call_view, call_targets) =
+                    lookup_callee(view, instr, self.funcs)?;
+                // (the entry function doesn't count towards the call depth)
+                if let Some(max_call_depth) = self.limits.max_call_depth
+                    && depth > max_call_depth
+                {
+                    return Err(call_depth_exceeded(
+                        max_call_depth,
+                        get_func_name(call_view),
+                    ));
+                }
+                let mut callee_env = self.pool.take();
+                bind_call_args(
+                    view,
+                    &frame.env,
+                    instr,
+                    call_view,
+                    &mut callee_env,
+                )?;
+                self.stack.push(Frame::new(
+                    call_view,
+                    call_targets,
+                    callee_env,
+                ));
+                return Ok(());
+            }
+            Opcode::Ret => {
+                let value = match get_arg_pairs(view, instr.args) {
+                    [] => None,
+                    _ => {
+                        let Some([arg]) = get_n_args(view, instr.args) else {
+                            return Err(Diagnostic::new(
+                                ErrorCode::MalformedInstr,
+                                "too many args supplied to ret instruction",
+                            ));
+                        };
+                        Some(*get_value(&frame.env, arg)?)
+                    }
+                };
+                return self.ret(value);
+            }
+            op if op.is_binop() => {
+                interp_binop(view, op, instr, &mut frame.env)?
+            }
+            op if op.is_unop() => interp_unop(view, op, instr, &mut frame.env)?,
+            op => {
+                return Err(Diagnostic::new(
+                    ErrorCode::MalformedInstr,
+                    format!("unexpected opcode `{op}`"),
+                ));
+            }
+        }
+        frame.pc += 1;
+        Ok(())
+    }
+}
+
+impl<'a, P: IndexPair> Scheduler<'a, P> {
+    /// Creates a scheduler which runs each task for at most `fuel`
+    /// instructions per turn (at least 1)
+    pub fn new(fuel: u64) -> Self {
+        Self {
+            fuel: fuel.max(1),
+            tasks: vec![],
+            next: 0,
+        }
+    }
+
+    /// Adds `task` to the scheduler, returning its index in `tasks`
+    pub fn spawn(&mut self, task: Task<'a, P>) -> usize {
+        self.tasks.push(task);
+        self.tasks.len() - 1
+    }
+
+    /// The tasks that have been spawned, in the order they were spawned
+    pub fn tasks(&self) -> &[Task<'a, P>] {
+        &self.tasks
+    }
+
+    /// Mutable access to the tasks (e.g. so that the host can drain their
+    /// output between turns)
+    pub fn tasks_mut(&mut self) -> &mut [Task<'a, P>] {
+        &mut self.tasks
+    }
+
+    /// Gives the next runnable task (in round-robin order) a slice of
+    /// `fuel` instructions, returning its index (or `None` if every task
+    /// has finished)
+    pub fn run_turn(&mut self) -> Option<usize> {
+        let num_tasks = self.tasks.len();
+        let idx = (0..num_tasks)
+            .map(|offset| (self.next + offset) % num_tasks)
+            .find(|&idx| !self.tasks[idx].is_finished())?;
+        let task = &mut self.tasks[idx];
+        task.run(self.fuel);
+        task.turns += 1;
+        self.next = (idx + 1) % num_tasks;
+        Some(idx)
+    }
+
+    /// Runs turns until every task has finished
+    pub fn run_until_done(&mut self) {
+        while self.run_turn().is_some() {}
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod scheduler_tests {
+    use serde_json::json;
+
+    use crate::diagnostic::ErrorCode;
+    use crate::interp::{FuncTable, Limits, interp_program_captured};
+    use crate::memfile::{
+        AlignedBytes, flatten_to_bytes, get_flat_program, json_to_fbril_bytes,
+    };
+    use crate::scheduler::{Scheduler, Task, TaskStatus};
+    use crate::types::BrilValue;
+
+    /// Tasks are interleaved a slice at a time, so a task which never
+    /// finishes doesn't starve the others, & each task's output & result are
+    /// the same as when it's run on its own
+    #[test]
+    fn test_scheduler() {
+        let json_str = std::fs::read_to_string("test/fib_recursive.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let fib = get_flat_program(&data).expect("valid file should load");
+        let expected = interp_program_captured(&fib, vec!["10"]).unwrap();
+
+        let json = json!({ "functions": [{
+            "name": "main",
+            "instrs": [
+                { "label": "loop" },
+                { "op": "jmp", "labels": ["loop"] }
+            ]
+        }]});
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let spin = get_flat_program(&data).expect("valid file should load");
+
+        let fib_funcs = FuncTable::new(fib.views());
+        let spin_funcs = FuncTable::new(spin.views());
+        let limits = Limits {
+            max_steps: Some(expected.steps * 2),
+            ..Limits::default()
+        };
+        let mut scheduler = Scheduler::new(7);
+        let spin_idx = scheduler
+            .spawn(Task::new(&spin_funcs, "main", &[], limits).unwrap());
+        let fib_idx = scheduler
+            .spawn(Task::new(&fib_funcs, "main", &["10"], limits).unwrap());
+        assert_eq!(scheduler.run_turn(), Some(spin_idx));
+        assert_eq!(scheduler.run_turn(), Some(fib_idx));
+        assert_eq!(scheduler.tasks()[spin_idx].steps, 7);
+        scheduler.run_until_done();
+
+        let fib_task = &scheduler.tasks()[fib_idx];
+        assert_eq!(fib_task.status, TaskStatus::Returned(None));
+        assert_eq!(fib_task.steps, expected.steps);
+        assert_eq!(fib_task.turns, expected.steps.div_ceil(7));
+        let lines: Vec<String> = String::from_utf8_lossy(&fib_task.out)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        assert_eq!(lines, expected.lines);
+
+        // (the spinning task is only stopped by the step limit)
+        let spin_task = &scheduler.tasks()[spin_idx];
+        let TaskStatus::Failed(e) = &spin_task.status else {
+            panic!("expected the step limit to be exceeded");
+        };
+        assert_eq!(e.code, ErrorCode::StepLimit);
+        assert_eq!(spin_task.steps, expected.steps * 2);
+        assert!(spin_task.turns > fib_task.turns);
+
+        // Return values are recorded in the task's status
+        let json = json!({ "functions": [{
+            "name": "double",
+            "args": [{ "name": "x", "type": "int" }],
+            "type": "int",
+            "instrs": [
+                { "op": "add", "dest": "y", "type": "int",
+                  "args": ["x", "x"] },
+                { "op": "ret", "args": ["y"] }
+            ]
+        }]});
+        let data = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let double = get_flat_program(&data).expect("valid file should load");
+        let funcs = FuncTable::new(double.views());
+        let mut task =
+            Task::new(&funcs, "double", &["21"], Limits::default()).unwrap();
+        assert_eq!(
+            task.run_to_completion(),
+            &TaskStatus::Returned(Some(BrilValue::IntVal(42)))
+        );
+    }
+}
@@ -0,0 +1,257 @@
+use std::fmt::Write;
+use std::io::{self, Read};
+
+use serde::Serialize;
+
+use crate::memfile;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The size of one part of an `.fbril` file (see `memfile::section_sizes`)
+/// - `share` is the fraction of the file that it takes up
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SectionSize {
+    pub name: String,
+    pub bytes: usize,
+    pub share: f64,
+}
+
+/// A comparison of the size of a JSON Bril program with the size of the
+/// `.fbril` file it was flattened to, broken down by section
+/// - `ratio` is `fbril_bytes / json_bytes`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SizeReport {
+    pub json_bytes: usize,
+    pub fbril_bytes: usize,
+    pub ratio: f64,
+    pub sections: Vec<SectionSize>,
+}
+
+/// A reader which counts the no. of bytes read from `inner`
+/// (e.g. to find out the size of a JSON program read from `stdin`)
+pub struct CountingReader<R: Read> {
+    inner: R,
+    bytes_read: usize,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+        }
+    }
+
+    /// The no. of bytes read so far
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n;
+        Ok(n)
+    }
+}
+
+impl SizeReport {
+    /// Compares the size of a JSON program (`json_bytes` long) with the
+    /// contents of the `.fbril` file it was flattened to (`fbril`)
+    /// (returns an error if the file is malformed)
+    pub fn new(json_bytes: usize, fbril: &[u8]) -> Result<Self, String> {
+        let fraction = |bytes: usize, total: usize| {
+            if total == 0 {
+                0.0
+            } else {
+                bytes as f64 / total as f64
+            }
+        };
+        let sections = memfile::section_sizes(fbril)?
+            .into_iter()
+            .map(|(name, bytes)| SectionSize {
+                name: name.to_string(),
+                bytes,
+                share: fraction(bytes, fbril.len()),
+            })
+            .collect();
+        Ok(Self {
+            json_bytes,
+            fbril_bytes: fbril.len(),
+            ratio: fraction(fbril.len(), json_bytes),
+            sections,
+        })
+    }
+
+    /// Serializes the report as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self)
+            .expect("unable to serialize size report")
+    }
+
+    /// Renders the report as a table of sections, followed by the sizes
+    /// of the two encodings
+    pub fn to_text(&self) -> String {
+        let mut text =
+            format!("{:<20} {:>10} {:>7}\n", "section", "bytes", "share");
+        for section in &self.sections {
+            writeln!(
+                text,
+                "{:<20} {:>10} {:>6.1}%",
+                section.name,
+                section.bytes,
+                section.share * 100.0
+            )
+            .unwrap();
+        }
+        writeln!(
+            text,
+            "fbril: {} bytes, json: {} bytes (fbril/json = {:.2})",
+            self.fbril_bytes, self.json_bytes, self.ratio
+        )
+        .unwrap();
+        text
+    }
+}
+
+/// Renders the layout of an `.fbril` file (see `memfile::section_layouts`)
+/// as a table of sections, with one row per section. Sections which aren't
+/// aligned to their entries' alignment, or which extend past the end of
+/// the file (which is `file_bytes` long), are flagged.
+pub fn layouts_to_text(
+    layouts: &[memfile::SectionLayout],
+    file_bytes: usize,
+) -> String {
+    let mut text = format!(
+        "{:<20} {:>10} {:>10} {:>8} {:>6} {:>6}\n",
+        "section", "offset", "bytes", "entries", "size", "align"
+    );
+    for section in layouts {
+        let mut problems = vec![];
+        if section.offset % section.align != 0 {
+            problems.push("misaligned");
+        }
+        if section.offset.saturating_add(section.bytes) > file_bytes {
+            problems.push("past the end of the file");
+        }
+        let line = format!(
+            "{:<20} {:>#10x} {:>10} {:>8} {:>6} {:>6}  {}",
+            section.name,
+            section.offset,
+            section.bytes,
+            section.count,
+            section.item_size,
+            section.align,
+            problems.join(", ")
+        );
+        writeln!(text, "{}", line.trim_end()).unwrap();
+    }
+    let total: usize = layouts.iter().map(|section| section.bytes).sum();
+    writeln!(
+        text,
+        "total: {total} bytes (the file is {file_bytes} bytes)"
+    )
+    .unwrap();
+    text
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod size_report_tests {
+    use std::io::Read;
+
+    use crate::memfile::{
+        AlignedBytes, encode_instr_stores, get_program_views,
+        json_to_fbril_bytes, load_program, section_layouts, section_sizes,
+    };
+    use crate::size_report::{CountingReader, SizeReport, layouts_to_text};
+    use crate::types::COMPACT_FORMAT_FLAG;
+
+    /// The sections add up to the whole file, and the ratio compares the
+    /// size of the file with the size of the JSON it was flattened from
+    #[test]
+    fn test_size_report() {
+        let json_str = std::fs::read_to_string("test/fizz-buzz.json")
+            .expect("Unable to read file");
+        let mut reader = CountingReader::new(json_str.as_bytes());
+        let mut json_bytes = vec![];
+        reader.read_to_end(&mut json_bytes).unwrap();
+        assert_eq!(reader.bytes_read(), json_str.len());
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&json_bytes).expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let fbril = &data;
+        let report = SizeReport::new(reader.bytes_read(), fbril).unwrap();
+        let total: usize =
+            report.sections.iter().map(|section| section.bytes).sum();
+        assert_eq!(total, fbril.len());
+        assert_eq!(report.sections[0].name, "header");
+        assert!(report.ratio > 0.0 && report.ratio < 1.0);
+        assert!(report.to_text().contains("\ninstrs "));
+
+        // (including in the compact format, whose `instrs` are padded)
+        let program = load_program(fbril).unwrap();
+        let compact =
+            encode_instr_stores(program.instr_stores(), COMPACT_FORMAT_FLAG)
+                .unwrap();
+        let sizes = section_sizes(&AlignedBytes::new(&compact)).unwrap();
+        let total: usize = sizes.iter().map(|(_, bytes)| bytes).sum();
+        assert_eq!(total, compact.len());
+
+        let json_report: serde_json::Value =
+            serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json_report["fbril_bytes"], fbril.len());
+        assert!(SizeReport::new(0, &fbril[..8]).is_err());
+    }
+
+    /// The offsets in the table of contents are the ones the sections are
+    /// loaded from, & sections past the end of a truncated file are flagged
+    #[test]
+    fn test_section_layouts() {
+        let json_str = std::fs::read_to_string("test/fizz-buzz.json")
+            .expect("Unable to read file");
+        let json: serde_json::Value =
+            serde_json::from_str(&json_str).expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let fbril = &data;
+
+        let layouts = section_layouts(fbril).unwrap();
+        for pair in layouts.windows(2) {
+            assert_eq!(pair[0].offset + pair[0].bytes, pair[1].offset);
+        }
+        let views = get_program_views(fbril).unwrap();
+        let instrs = layouts
+            .iter()
+            .find(|section| section.name == "instrs")
+            .unwrap();
+        let instrs_offset =
+            views[0].instrs.as_ptr().addr() - fbril.as_ptr().addr();
+        assert_eq!(instrs.offset, instrs_offset);
+        assert_eq!(
+            instrs.count,
+            views.iter().map(|view| view.instrs.len()).sum::<usize>()
+        );
+
+        let table = layouts_to_text(&layouts, fbril.len());
+        assert!(!table.contains("past the end of the file"));
+        assert!(table.ends_with(&format!(
+            "total: {0} bytes (the file is {0} bytes)\n",
+            fbril.len()
+        )));
+        let table = layouts_to_text(&layouts, fbril.len() - 1);
+        assert!(table.contains("past the end of the file"));
+    }
+}
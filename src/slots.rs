@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+// Every variable in a flattened function is numbered into a dense slot
+// (stored in `InstrStore::dest_slots` / `arg_slots`), so that an interpreter
+// can keep a function's environment in an array of `num_slots` values.
+// Two variables share a slot if their live ranges don't overlap, which keeps
+// `num_slots` small. Slots are assigned by greedily coloring the interference
+// graph (computed with a standard liveness analysis), where:
+// - The `i`th parameter is always in slot `i`
+// - Variables which may be used before being defined get a slot of their own,
+//   so reading them never sees another variable's value
+
+/// The successors of the instruction at index `pc`
+/// (`label_pcs` maps each label's name to its index in `instrs`)
+fn successors(
+    store: &InstrStore,
+    label_pcs: &HashMap<&[u8], usize>,
+    pc: usize,
+) -> Vec<usize> {
+    let instr = &store.instrs[pc];
+    match Opcode::u32_to_opcode(instr.op) {
+        Some(Opcode::Jmp | Opcode::Br) => {
+            instr.instr_labels.map_or(vec![], |(offset, len)| {
+                store.labels_idxes_store[offset..offset + len]
+                    .iter()
+                    .filter_map(|&(label_offset, label_len)| {
+                        let label = &store.labels_store
+                            [label_offset..label_offset + label_len];
+                        label_pcs.get(label).copied()
+                    })
+                    .collect()
+            })
+        }
+        Some(Opcode::Ret) => vec![],
+        _ if pc + 1 < store.instrs.len() => vec![pc + 1],
+        _ => vec![],
+    }
+}
+
+/// Computes the slots of the variables in `store`, overwriting its
+/// `num_slots`, `dest_slots` & `arg_slots` fields
+/// (this must be re-run after changing a function's instructions,
+/// since that may change the variables' live ranges)
+pub fn assign_slots(store: &mut InstrStore) {
+    // Number the variables by name, with the parameters first
+    let mut var_ids: HashMap<&[u8], usize> = HashMap::new();
+    let name =
+        |(offset, len): (usize, usize)| &store.var_store[offset..offset + len];
+    for func_arg in &store.func_args {
+        let num_vars = var_ids.len();
+        var_ids
+            .entry(name(func_arg.arg_name_idxes))
+            .or_insert(num_vars);
+    }
+    let num_params = var_ids.len();
+    let mut dests: Vec<Option<usize>> = vec![None; store.instrs.len()];
+    let mut args: Vec<Vec<usize>> = vec![vec![]; store.instrs.len()];
+    let mut label_pcs: HashMap<&[u8], usize> = HashMap::new();
+    for (pc, instr) in store.instrs.iter().enumerate() {
+        if instr.op == u32::MAX
+            && let Some((offset, len)) = instr.label
+        {
+            label_pcs.insert(&store.labels_store[offset..offset + len], pc);
+        }
+        if let Some((offset, len)) = instr.args {
+            for &idxes in &store.args_idxes_store[offset..offset + len] {
+                let num_vars = var_ids.len();
+                args[pc].push(*var_ids.entry(name(idxes)).or_insert(num_vars));
+            }
+        }
+        if let Some(idxes) = instr.dest {
+            let num_vars = var_ids.len();
+            dests[pc] = Some(*var_ids.entry(name(idxes)).or_insert(num_vars));
+        }
+    }
+    // (entries in `args_idxes_store` which no instruction refers to, e.g.
+    // after a pass has rewritten an instruction in place, also need slots)
+    for &idxes in &store.args_idxes_store {
+        let num_vars = var_ids.len();
+        var_ids.entry(name(idxes)).or_insert(num_vars);
+    }
+    let num_vars = var_ids.len();
+
+    // Liveness analysis (at the granularity of instructions)
+    let succs: Vec<Vec<usize>> = (0..store.instrs.len())
+        .map(|pc| successors(store, &label_pcs, pc))
+        .collect();
+    let mut live_in: Vec<HashSet<usize>> =
+        vec![HashSet::new(); store.instrs.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..store.instrs.len()).rev() {
+            let mut live: HashSet<usize> = succs[pc]
+                .iter()
+                .flat_map(|&succ| live_in[succ].iter().copied())
+                .collect();
+            if let Some(dest) = dests[pc] {
+                live.remove(&dest);
+            }
+            live.extend(&args[pc]);
+            if live.len() != live_in[pc].len() {
+                live_in[pc] = live;
+                changed = true;
+            }
+        }
+    }
+
+    // A definition interferes with every other variable that's live after it
+    let mut interference: Vec<HashSet<usize>> = vec![HashSet::new(); num_vars];
+    let mut add_edge = |a: usize, b: usize| {
+        if a != b {
+            interference[a].insert(b);
+            interference[b].insert(a);
+        }
+    };
+    for (pc, dest) in dests.iter().enumerate() {
+        if let Some(dest) = *dest {
+            for &succ in &succs[pc] {
+                for &live in &live_in[succ] {
+                    add_edge(dest, live);
+                }
+            }
+        }
+    }
+    // The parameters are all defined on entry, and variables which may be
+    // used before being defined interfere with everything
+    let live_on_entry = live_in.first().cloned().unwrap_or_default();
+    for var in 0..num_vars {
+        if var < num_params {
+            for param in 0..num_params {
+                add_edge(var, param);
+            }
+        } else if live_on_entry.contains(&var) {
+            for other in 0..num_vars {
+                add_edge(var, other);
+            }
+        }
+    }
+
+    // Greedily color the variables, in the order they first appear
+    let mut slots: Vec<usize> = (0..num_params).collect();
+    for (var, neighbors) in interference.iter().enumerate().skip(num_params) {
+        let taken: HashSet<usize> = neighbors
+            .iter()
+            .filter(|&&other| other < var)
+            .map(|&other| slots[other])
+            .collect();
+        slots.push((0..).find(|slot| !taken.contains(slot)).unwrap());
+    }
+
+    let arg_slots: Vec<u32> = store
+        .args_idxes_store
+        .iter()
+        .map(|&idxes| slots[var_ids[name(idxes)]] as u32)
+        .collect();
+    store.num_slots = slots.iter().map(|slot| slot + 1).max().unwrap_or(0);
+    store.dest_slots = dests
+        .iter()
+        .map(|dest| dest.map_or(NO_SLOT, |var| slots[var] as u32))
+        .collect();
+    store.arg_slots = arg_slots;
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod slots_tests {
+    use serde_json::json;
+
+    use crate::flatten::flatten_instrs;
+    use crate::memfile::{
+        AlignedBytes, get_program_views, json_to_fbril_bytes,
+    };
+    use crate::types::NO_SLOT;
+
+    /// Variables whose live ranges don't overlap share slots (including
+    /// parameters which are dead), and the slots are stored in `.fbril` files
+    #[test]
+    fn test_slot_reuse() {
+        let func = json!({
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "a", "type": "int", "value": 1 },
+                { "op": "add", "dest": "b", "type": "int",
+                  "args": ["a", "n"] },
+                { "op": "print", "args": ["b"] },
+                { "op": "const", "dest": "c", "type": "int", "value": 2 },
+                { "op": "print", "args": ["c"] }
+            ]
+        });
+        let store = flatten_instrs(&func);
+        // `a` interferes with `n`, but `b` & `c` are only defined after
+        // `n` & `a` are dead
+        assert_eq!(store.num_slots, 2);
+        assert_eq!(store.dest_slots, [1, 0, NO_SLOT, 0, NO_SLOT]);
+        assert_eq!(store.arg_slots, [1, 0, 0, 0]);
+
+        let bytes = json_to_fbril_bytes(&json!({ "functions": [func] }));
+        let data = AlignedBytes::new(&bytes);
+        let views = get_program_views(&data).expect("valid file should load");
+        assert_eq!({ views[0].num_slots }, 2);
+        assert_eq!({ views[0].dest_slots }, store.dest_slots);
+        assert_eq!({ views[0].arg_slots }, store.arg_slots);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::interp::get_var;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The version of the snapshot format (snapshots with a different version
+/// are rejected)
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A call on the stack of a suspended task (see `scheduler::Task`)
+/// - `func` is the name of the function & `pc` is the PC of the next
+///   instruction to execute in it (for every call but the innermost one,
+///   this is the `call` that's waiting for its callee to return)
+/// - `current_label`, `prev_label` & `next_label` are indexes into the
+///   function's label table (`phi`s pick their arg by `prev_label`)
+/// - `env` holds the values of the variables which are defined
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FrameSnapshot {
+    pub func: String,
+    pub pc: usize,
+    pub current_label: Option<usize>,
+    pub prev_label: Option<usize>,
+    pub next_label: usize,
+    pub env: BTreeMap<String, BrilValue>,
+}
+
+/// The state of a suspended task, which can be saved to a file (as JSON) &
+/// restored later to continue running the program where it left off
+/// - `steps` is the no. of instructions executed before it was suspended
+///   (so that the step limit still applies to the whole run)
+/// - `frames` is the call stack, outermost call (the entry function) first
+/// - Core Bril has no heap, so the call stack is the entire state of a run.
+///   The program's output isn't included, since it's already been written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub steps: u64,
+    pub frames: Vec<FrameSnapshot>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Snapshot {
+    /// Parses a snapshot which was serialized with `to_json`
+    /// (returns an error if it's malformed or has a different version)
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let snapshot: Snapshot = serde_json::from_str(json)
+            .map_err(|e| format!("invalid snapshot: {e}"))?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot has version {}, expected {SNAPSHOT_VERSION}",
+                snapshot.version
+            ));
+        }
+        Ok(snapshot)
+    }
+
+    /// Serializes the snapshot as JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("unable to serialize snapshot")
+    }
+}
+
+/// Finds the variable called `name` in `instr_view`, i.e. one of its
+/// parameters or the dest of one of its instructions (environments borrow
+/// their variable names from the function, so a restored environment has to
+/// use the function's copy of each name)
+pub fn find_var<'a, P: IndexPair>(
+    instr_view: &'a InstrView<P>,
+    name: &str,
+) -> Option<&'a str> {
+    let params = instr_view.func_args.iter().map(|arg| arg.arg_name_idxes);
+    let dests = instr_view.instrs.iter().map(|instr| instr.dest);
+    params
+        .chain(dests)
+        .filter_map(|pair| pair.get())
+        .map(|(offset, len)| get_var(instr_view, offset, len))
+        .find(|var| *var == name)
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod snapshot_tests {
+    use crate::interp::{FuncTable, Limits, interp_program_captured};
+    use crate::memfile::{AlignedBytes, flatten_to_bytes, get_flat_program};
+    use crate::scheduler::{Task, TaskStatus};
+    use crate::snapshot::Snapshot;
+    use crate::types::BrilValue;
+
+    /// A run which is suspended in a nested call, saved, & restored
+    /// produces the same output as an uninterrupted run
+    #[test]
+    fn test_snapshot() {
+        let json_str = std::fs::read_to_string("test/fib_recursive.json")
+            .expect("Unable to read file");
+        let bytes = flatten_to_bytes(&json_str).expect("valid program");
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+        let expected = interp_program_captured(&program, vec!["10"]).unwrap();
+        let funcs = FuncTable::new(program.views());
+
+        let mut task =
+            Task::new(&funcs, "main", &["10"], Limits::default()).unwrap();
+        assert_eq!(task.run(100), 100);
+        let snapshot = task.snapshot().expect("task is still running");
+        assert!(snapshot.frames.len() > 2);
+        assert_eq!(snapshot.frames[0].func, "main");
+
+        let json = snapshot.to_json();
+        let restored = Snapshot::from_json(&json).unwrap();
+        assert_eq!(restored, snapshot);
+        let mut task =
+            Task::restore(&funcs, &restored, Limits::default()).unwrap();
+        assert_eq!(task.run_to_completion(), &TaskStatus::Returned(None));
+        assert_eq!(task.steps, expected.steps);
+        assert_eq!(String::from_utf8_lossy(&task.out), "55\n");
+
+        // (snapshots of other programs are rejected)
+        let mut bad = snapshot.clone();
+        bad.frames[0].func = "nope".to_string();
+        assert!(Task::restore(&funcs, &bad, Limits::default()).is_err());
+        let mut bad = snapshot;
+        bad.frames[1]
+            .env
+            .insert("nope".to_string(), BrilValue::IntVal(0));
+        assert!(Task::restore(&funcs, &bad, Limits::default()).is_err());
+        let json = json.replace("\"version\":1", "\"version\":99");
+        assert!(Snapshot::from_json(&json).unwrap_err().contains("version"));
+    }
+}
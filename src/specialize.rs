@@ -0,0 +1,622 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::cfg::{BasicBlock, Cfg, Operation};
+use crate::program::{Function, InstructionRef, Program};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The default maximum no. of specialized copies of each block of `main`
+/// (see `specialize --max-versions`): once a block has this many copies,
+/// it's only specialized for the values it's entered with if they match an
+/// existing copy (otherwise, the values are materialized & control jumps to
+/// a copy where they're unknown), which bounds how far loops are unrolled
+pub const MAX_VERSIONS: usize = 32;
+
+/// A value which is known when the program is specialized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Known {
+    Int(i64),
+    Bool(bool),
+}
+
+/// The variables whose values are known at a program point (every other
+/// variable holds its value at run time, as in the original program)
+type KnownEnv = BTreeMap<String, Known>;
+
+/// What `specialize_program` did to `main`
+/// - `blocks_before` & `blocks_after` are the no. of blocks in the original
+///   & specialized `main`
+/// - `folded` is the no. of instructions which were evaluated away
+/// - `pruned_branches` is the no. of `br`s whose conditions were known,
+///   which were replaced by jumps to the label that's taken
+/// - `generalized` is the no. of jumps to a block which already had
+///   `max_versions` copies (see `MAX_VERSIONS`)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SpecializeStats {
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+    pub folded: usize,
+    pub pruned_branches: usize,
+    pub generalized: usize,
+}
+
+/// The state of the specialization of a single function
+/// - `versions` maps each block & the (live) known values it's entered with
+///   to the label of the copy of the block specialized for them
+/// - `pending` are the copies which have been labelled but not generated
+/// - `bases[b]` is the prefix of the labels of the copies of block `b`
+///   (copy `n` is labelled `{bases[b]}.{n}`, and the trampolines which
+///   materialize values before a `br` jumps to a block are labelled
+///   `{edge_base}.{n}`, so that labels never clash)
+struct Specializer<'c> {
+    cfg: &'c Cfg,
+    live_in: Vec<HashSet<String>>,
+    max_versions: usize,
+    bases: Vec<String>,
+    edge_base: String,
+    versions: HashMap<(usize, KnownEnv), String>,
+    num_versions: Vec<usize>,
+    pending: Vec<(usize, KnownEnv, String)>,
+    trampolines: Vec<BasicBlock>,
+    stats: SpecializeStats,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Known {
+    /// The known value of a `const`, if it's an int or a bool
+    fn of(value: BrilValue) -> Option<Known> {
+        match value {
+            BrilValue::IntVal(i) => Some(Known::Int(i)),
+            BrilValue::BoolVal(b) => Some(Known::Bool(b.into())),
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(_) => None,
+        }
+    }
+
+    /// The `const` instruction which stores this value in `var`
+    fn materialize(self, var: &str) -> Operation {
+        let (ty, value) = match self {
+            Known::Int(i) => (Type::Int, BrilValue::IntVal(i)),
+            Known::Bool(b) => (Type::Bool, BrilValue::BoolVal(b.into())),
+        };
+        Operation {
+            op: Opcode::Const,
+            dest: Some(var.to_string()),
+            ty: Some(ty),
+            value: Some(value),
+            args: vec![],
+            labels: vec![],
+            func: None,
+        }
+    }
+}
+
+/// Evaluates the value operation `op` on the known values `args`, with the
+/// same semantics as the interpreter (returns `None` if it can't be
+/// evaluated, e.g. a division by zero, which is left to fail at run time)
+fn fold(op: Opcode, args: &[Known]) -> Option<Known> {
+    use Known::{Bool, Int};
+    use Opcode::*;
+    match (op, args) {
+        (Id, [x]) => Some(*x),
+        (Not, [Bool(b)]) => Some(Bool(!b)),
+        (And, [Bool(x), Bool(y)]) => Some(Bool(*x && *y)),
+        (Or, [Bool(x), Bool(y)]) => Some(Bool(*x || *y)),
+        (Div, [Int(_), Int(0)]) => None,
+        (_, [Int(x), Int(y)]) => match op {
+            Add => Some(Int(x.wrapping_add(*y))),
+            Sub => Some(Int(x.wrapping_sub(*y))),
+            Mul => Some(Int(x.wrapping_mul(*y))),
+            Div => Some(Int(x.wrapping_div(*y))),
+            Eq => Some(Bool(x == y)),
+            Lt => Some(Bool(x < y)),
+            Gt => Some(Bool(x > y)),
+            Le => Some(Bool(x <= y)),
+            Ge => Some(Bool(x >= y)),
+            #[cfg(feature = "bitwise")]
+            Band => Some(Int(x & y)),
+            #[cfg(feature = "bitwise")]
+            Bor => Some(Int(x | y)),
+            #[cfg(feature = "bitwise")]
+            Bxor => Some(Int(x ^ y)),
+            #[cfg(feature = "bitwise")]
+            Shl => Some(Int(x.wrapping_shl(*y as u32))),
+            #[cfg(feature = "bitwise")]
+            Shr => Some(Int(x.wrapping_shr(*y as u32))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A `jmp` to `label`
+fn jmp(label: String) -> Operation {
+    Operation {
+        op: Opcode::Jmp,
+        dest: None,
+        ty: None,
+        value: None,
+        args: vec![],
+        labels: vec![label],
+        func: None,
+    }
+}
+
+impl<'c> Specializer<'c> {
+    fn new(cfg: &'c Cfg, max_versions: usize) -> Self {
+        let bases = (0..cfg.blocks.len())
+            .map(|b| match &cfg.blocks[b].label {
+                Some(label) => label.clone(),
+                None => cfg.fresh_label(&format!("b{b}")),
+            })
+            .collect();
+        Self {
+            cfg,
+            live_in: cfg.live_in(),
+            max_versions,
+            bases,
+            edge_base: cfg.fresh_label("edge"),
+            versions: HashMap::new(),
+            num_versions: vec![0; cfg.blocks.len()],
+            pending: vec![],
+            trampolines: vec![],
+            stats: SpecializeStats {
+                blocks_before: cfg.blocks.len(),
+                ..SpecializeStats::default()
+            },
+        }
+    }
+
+    /// The label of the copy of block `b` which is entered with the known
+    /// values `env` (only the variables which are live on entry to `b`
+    /// matter), along with the `const`s which must be executed before
+    /// jumping to it, if it's a copy where some of them aren't known
+    fn target(&mut self, b: usize, env: &KnownEnv) -> (String, Vec<Operation>) {
+        let live: KnownEnv = env
+            .iter()
+            .filter(|(var, _)| self.live_in[b].contains(*var))
+            .map(|(var, value)| (var.clone(), *value))
+            .collect();
+        let mut key = (b, live.clone());
+        if !self.versions.contains_key(&key)
+            && self.num_versions[b] >= self.max_versions
+        {
+            key.1.clear();
+            self.stats.generalized += 1;
+        }
+        let consts = live
+            .iter()
+            .filter(|(var, _)| !key.1.contains_key(*var))
+            .map(|(var, value)| value.materialize(var))
+            .collect();
+        let label = match self.versions.get(&key) {
+            Some(label) => label.clone(),
+            None => {
+                let label =
+                    format!("{}.{}", self.bases[b], self.num_versions[b]);
+                self.num_versions[b] += 1;
+                self.pending.push((b, key.1.clone(), label.clone()));
+                self.versions.insert(key, label.clone());
+                label
+            }
+        };
+        (label, consts)
+    }
+
+    /// Like `target`, but the `const`s are moved into a trampoline block
+    /// (for the targets of a `br`, which can't execute them beforehand)
+    fn branch_target(&mut self, b: usize, env: &KnownEnv) -> String {
+        let (label, consts) = self.target(b, env);
+        if consts.is_empty() {
+            return label;
+        }
+        let trampoline =
+            format!("{}.{}", self.edge_base, self.trampolines.len());
+        let mut instrs = consts;
+        instrs.push(jmp(label));
+        self.trampolines.push(BasicBlock {
+            label: Some(trampoline.clone()),
+            instrs,
+        });
+        trampoline
+    }
+
+    /// Generates the copy of block `b` labelled `label`, which is entered
+    /// with the known values `env`
+    fn specialize_block(
+        &mut self,
+        b: usize,
+        mut env: KnownEnv,
+        label: String,
+    ) -> BasicBlock {
+        let cfg = self.cfg;
+        let block_of = |label: &str| {
+            cfg.block_index(label)
+                .expect("jump to an undefined label in a checked function")
+        };
+        let mut instrs = vec![];
+        // (the known variables which have been stored at run time,
+        // since the start of this copy or since they were last assigned)
+        let mut materialized: HashSet<String> = HashSet::new();
+        for instr in &cfg.blocks[b].instrs {
+            let known: Option<Vec<Known>> =
+                instr.args.iter().map(|arg| env.get(arg).copied()).collect();
+            match (instr.op, &instr.dest, known.as_deref()) {
+                (Opcode::Nop, _, _) => continue,
+                (Opcode::Jmp, _, _) => {
+                    let (target, consts) =
+                        self.target(block_of(&instr.labels[0]), &env);
+                    instrs.extend(consts);
+                    instrs.push(jmp(target));
+                    return BasicBlock {
+                        label: Some(label),
+                        instrs,
+                    };
+                }
+                (Opcode::Br, _, Some([Known::Bool(cond)])) => {
+                    self.stats.pruned_branches += 1;
+                    let taken = &instr.labels[if *cond { 0 } else { 1 }];
+                    let (target, consts) = self.target(block_of(taken), &env);
+                    instrs.extend(consts);
+                    instrs.push(jmp(target));
+                    return BasicBlock {
+                        label: Some(label),
+                        instrs,
+                    };
+                }
+                (Opcode::Br, _, _) => {
+                    let labels = instr
+                        .labels
+                        .iter()
+                        .map(|label| self.branch_target(block_of(label), &env))
+                        .collect();
+                    instrs.push(Operation {
+                        labels,
+                        ..instr.clone()
+                    });
+                    return BasicBlock {
+                        label: Some(label),
+                        instrs,
+                    };
+                }
+                _ => (),
+            }
+            if let Some(dest) = &instr.dest {
+                let value = match (instr.op, known.as_deref()) {
+                    (Opcode::Const, _) => instr.value.and_then(Known::of),
+                    (op, Some(args)) => fold(op, args).inspect(|_| {
+                        self.stats.folded += 1;
+                    }),
+                    _ => None,
+                };
+                if let Some(value) = value {
+                    env.insert(dest.clone(), value);
+                    materialized.remove(dest);
+                    continue;
+                }
+            }
+
+            // The instruction is left for run time, so the known values of
+            // its args must be stored first
+            for arg in &instr.args {
+                if let Some(value) = env.get(arg)
+                    && materialized.insert(arg.clone())
+                {
+                    instrs.push(value.materialize(arg));
+                }
+            }
+            instrs.push(instr.clone());
+            if let Some(dest) = &instr.dest {
+                env.remove(dest);
+                materialized.remove(dest);
+            }
+            if instr.op == Opcode::Ret {
+                return BasicBlock {
+                    label: Some(label),
+                    instrs,
+                };
+            }
+        }
+
+        if b + 1 < cfg.blocks.len() {
+            let (target, consts) = self.target(b + 1, &env);
+            instrs.extend(consts);
+            instrs.push(jmp(target));
+        } else {
+            // (copies of the last block aren't necessarily generated last,
+            // so they can't fall off the end of the function)
+            instrs.push(Operation {
+                op: Opcode::Ret,
+                dest: None,
+                ty: None,
+                value: None,
+                args: vec![],
+                labels: vec![],
+                func: None,
+            });
+        }
+        BasicBlock {
+            label: Some(label),
+            instrs,
+        }
+    }
+}
+
+/// Checks that the partial evaluator supports every instruction in `func`
+fn check_supported<P: IndexPair>(func: Function<'_, P>) -> Result<(), String> {
+    for instr in func.instructions() {
+        match instr {
+            InstructionRef::Extension(json) => {
+                return Err(format!(
+                    "@{}: can't specialize a function with the extension \
+                    instruction {json}",
+                    func.name()
+                ));
+            }
+            InstructionRef::Op(op_ref) if op_ref.op == Opcode::Phi => {
+                return Err(format!(
+                    "@{}: can't specialize a function in SSA form \
+                    (it contains a `phi`)",
+                    func.name()
+                ));
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Parses the command-line args of `main` according to the types of its
+/// parameters (in the same way as the interpreter)
+fn parse_args(cfg: &Cfg, args: &[&str]) -> Result<KnownEnv, String> {
+    if args.len() != cfg.args.len() {
+        return Err(format!(
+            "@{} takes {} args, but {} were given",
+            cfg.name,
+            cfg.args.len(),
+            args.len()
+        ));
+    }
+    cfg.args
+        .iter()
+        .zip(args)
+        .map(|((name, ty), arg)| {
+            let value = match ty {
+                Type::Int => arg.parse().ok().map(Known::Int),
+                Type::Bool => arg.parse().ok().map(Known::Bool),
+                _ => None,
+            };
+            value.map(|value| (name.clone(), value)).ok_or_else(|| {
+                format!("can't use `{arg}` as the value of {name}: {ty}")
+            })
+        })
+        .collect()
+}
+
+/// Partially evaluates `func` for the values `args` of its parameters:
+/// instructions whose args are known are evaluated away, `br`s whose
+/// conditions are known become jumps, and each block is copied for every
+/// combination of known values it's entered with, so that loops whose
+/// bounds are known are unrolled (up to `max_versions` times, see
+/// `MAX_VERSIONS`). The resulting function has no parameters, and behaves
+/// like `func` called with `args`.
+pub fn specialize<P: IndexPair>(
+    func: Function<'_, P>,
+    args: &[&str],
+    max_versions: usize,
+) -> Result<(Cfg, SpecializeStats), String> {
+    check_supported(func)?;
+    let cfg = Cfg::new(func);
+    let env = parse_args(&cfg, args)?;
+    let mut specializer = Specializer::new(&cfg, max_versions);
+    let mut blocks = vec![];
+    if !cfg.blocks.is_empty() {
+        let (_, consts) = specializer.target(0, &env);
+        debug_assert!(consts.is_empty());
+        // (the copy that's generated next is the target of the jump at the
+        // end of the previous one, if it had one, so that jump can usually
+        // be removed below)
+        while let Some((b, env, label)) = specializer.pending.pop() {
+            blocks.push(specializer.specialize_block(b, env, label));
+        }
+    }
+    blocks.append(&mut specializer.trampolines);
+    // Jumps to the next block & a `ret` (without a value) at the very end
+    // are redundant, and so are the labels which are no longer jumped to
+    for b in 0..blocks.len().saturating_sub(1) {
+        let next = blocks[b + 1].label.clone();
+        if let Some(last) = blocks[b].instrs.last()
+            && last.op == Opcode::Jmp
+            && Some(&last.labels[0]) == next.as_ref()
+        {
+            blocks[b].instrs.pop();
+        }
+    }
+    if let Some(last_block) = blocks.last_mut()
+        && let Some(last) = last_block.instrs.last()
+        && last.op == Opcode::Ret
+        && last.args.is_empty()
+    {
+        last_block.instrs.pop();
+    }
+    let targets: HashSet<String> = blocks
+        .iter()
+        .flat_map(|block| &block.instrs)
+        .flat_map(|instr| instr.labels.iter().cloned())
+        .collect();
+    for block in &mut blocks {
+        block.label.take_if(|label| !targets.contains(label));
+    }
+    // (a block without a label is only entered by falling through from the
+    // previous one, so it's part of the same block)
+    let mut merged: Vec<BasicBlock> = vec![];
+    for block in blocks {
+        match merged.last_mut() {
+            Some(prev) if block.label.is_none() => {
+                prev.instrs.extend(block.instrs)
+            }
+            _ => merged.push(block),
+        }
+    }
+
+    let mut stats = specializer.stats;
+    stats.blocks_after = merged.len();
+    let specialized = Cfg {
+        name: cfg.name.clone(),
+        args: vec![],
+        ret_ty: cfg.ret_ty,
+        blocks: merged,
+    };
+    Ok((specialized, stats))
+}
+
+/// Whether `func` calls `@callee`
+fn calls<P: IndexPair>(func: Function<'_, P>, callee: &str) -> bool {
+    func.instructions().any(|instr| {
+        matches!(instr, InstructionRef::Op(op_ref) if op_ref.func == Some(callee))
+    })
+}
+
+/// Replaces every call to `@from` in `cfg` with a call to `@to`
+fn rename_calls(cfg: &mut Cfg, from: &str, to: &str) {
+    for instr in cfg.blocks.iter_mut().flat_map(|block| &mut block.instrs) {
+        if instr.func.as_deref() == Some(from) {
+            instr.func = Some(to.to_string());
+        }
+    }
+}
+
+/// Specializes the `main` function of a program for the command-line args
+/// `args` (see `specialize`), copying the other functions unchanged
+/// - If `main` is called (e.g. recursively), the calls are to the original
+///   `main`, which is kept (under a fresh name, e.g. `main.0`) alongside
+///   the specialized one
+pub fn specialize_program<P: IndexPair>(
+    views: &[InstrView<P>],
+    args: &[&str],
+    max_versions: usize,
+) -> Result<(Vec<InstrStore>, SpecializeStats), String> {
+    let program = Program::new(views);
+    let main = program
+        .function("main")
+        .ok_or_else(|| "the program has no `main` function".to_string())?;
+    let (mut specialized, stats) = specialize(main, args, max_versions)?;
+    if !program.functions().any(|func| calls(func, "main")) {
+        let mut main_store = Some(specialized.to_instr_store());
+        let instr_stores = program
+            .functions()
+            .map(|func| match func.name() {
+                "main" => main_store
+                    .take()
+                    .unwrap_or_else(|| InstrStore::from(func.view().clone())),
+                _ => InstrStore::from(func.view().clone()),
+            })
+            .collect();
+        return Ok((instr_stores, stats));
+    }
+
+    let generic = (0..)
+        .map(|i| format!("main.{i}"))
+        .find(|name| program.function(name).is_none())
+        .expect("ran out of function names");
+    rename_calls(&mut specialized, "main", &generic);
+    let mut main_store = Some(specialized.to_instr_store());
+    let mut instr_stores = vec![];
+    let mut generic_store = None;
+    for func in program.functions() {
+        if func.name() != "main" && !calls(func, "main") {
+            instr_stores.push(InstrStore::from(func.view().clone()));
+            continue;
+        }
+        check_supported(func)?;
+        let mut cfg = Cfg::new(func);
+        rename_calls(&mut cfg, "main", &generic);
+        match main_store.take_if(|_| func.name() == "main") {
+            Some(main_store) => {
+                cfg.name = generic.clone();
+                generic_store = Some(cfg.to_instr_store());
+                instr_stores.push(main_store);
+            }
+            None => instr_stores.push(cfg.to_instr_store()),
+        }
+    }
+    instr_stores.extend(generic_store);
+    Ok((instr_stores, stats))
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod specialize_tests {
+    use serde_json::json;
+
+    use crate::interp::interp_program_captured;
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::specialize::{MAX_VERSIONS, specialize_program};
+
+    /// A loop whose bound is an arg of `main` is unrolled completely (so the
+    /// specialized program has no branches left & only prints), while a
+    /// loop whose bound isn't known stays a loop, and both programs print
+    /// the same thing
+    #[test]
+    fn test_specialize() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "n", "type": "int" }],
+            "instrs": [
+                { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                { "op": "const", "dest": "one", "type": "int", "value": 1 },
+                { "label": "loop" },
+                { "op": "lt", "dest": "cond", "type": "bool",
+                  "args": ["i", "n"] },
+                { "op": "br", "labels": ["body", "done"], "args": ["cond"] },
+                { "label": "body" },
+                { "op": "mul", "dest": "sq", "type": "int",
+                  "args": ["i", "i"] },
+                { "op": "print", "args": ["sq"] },
+                { "op": "add", "dest": "i", "type": "int",
+                  "args": ["i", "one"] },
+                { "op": "jmp", "labels": ["loop"] },
+                { "label": "done" },
+                { "op": "print", "args": ["i"] }
+            ]
+        }]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let expected = interp_program_captured(&program, vec!["4"]).unwrap();
+
+        let (stores, stats) =
+            specialize_program(program.views(), &["4"], MAX_VERSIONS).unwrap();
+        assert_eq!(stats.pruned_branches, 5);
+        assert_eq!(stats.generalized, 0);
+        let bytes = AlignedBytes::new(&instr_stores_to_fbril_bytes(stores));
+        let specialized = get_flat_program(&bytes).expect("should load");
+        let actual = interp_program_captured(&specialized, vec![]).unwrap();
+        assert_eq!(actual.lines, expected.lines);
+        // (a `const` & a `print` for each iteration, & for the final `print`)
+        assert_eq!(actual.steps, 5 * 2);
+
+        // With only 2 copies of each block, the loop can't be fully unrolled
+        let (stores, stats) =
+            specialize_program(program.views(), &["4"], 2).unwrap();
+        assert!(stats.generalized > 0);
+        let bytes = AlignedBytes::new(&instr_stores_to_fbril_bytes(stores));
+        let specialized = get_flat_program(&bytes).expect("should load");
+        let actual = interp_program_captured(&specialized, vec![]).unwrap();
+        assert_eq!(actual.lines, expected.lines);
+
+        assert!(specialize_program(program.views(), &[], 2).is_err());
+        assert!(specialize_program(program.views(), &["x"], 2).is_err());
+    }
+}
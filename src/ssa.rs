@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::cfg::{BasicBlock, Cfg, Operation};
+use crate::program::Function;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The arg used by a `phi` when its variable is undefined along the
+/// corresponding edge (this variable is never defined, so the `phi`'s dest
+/// becomes undefined too, as in the reference interpreter)
+pub const UNDEFINED_VAR: &str = "__undefined";
+
+/// State used while renaming variables
+/// - `stacks` maps each (original) variable to the stack of names it has
+///   been renamed to along the current path in the dominator tree
+/// - `counters` & `used` are used to generate fresh names
+struct Renamer {
+    stacks: HashMap<String, Vec<String>>,
+    counters: HashMap<String, usize>,
+    used: HashSet<String>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl Renamer {
+    /// Generates a fresh name for `var` (of the form `var.N`)
+    fn fresh(&mut self, var: &str) -> String {
+        let counter = self.counters.entry(var.to_string()).or_default();
+        loop {
+            let name = format!("{var}.{counter}");
+            *counter += 1;
+            if self.used.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+
+    /// The name that `var` currently refers to (if it has been defined)
+    fn current(&self, var: &str) -> Option<&String> {
+        self.stacks.get(var).and_then(|stack| stack.last())
+    }
+
+    /// Renames `var` to a fresh name, returning the new name
+    fn define(&mut self, var: &str) -> String {
+        let name = self.fresh(var);
+        self.stacks
+            .entry(var.to_string())
+            .or_default()
+            .push(name.clone());
+        name
+    }
+}
+
+/// Renames the variables in block `b` & (recursively) the blocks it
+/// dominates, filling in the args of the `phi`s in its successors
+/// (`phis[b]` holds the `phi`s of block `b` along with their original
+/// variables)
+fn rename(
+    b: usize,
+    cfg: &mut Cfg,
+    phis: &mut [Vec<(String, Operation)>],
+    succs: &[Vec<usize>],
+    children: &[Vec<usize>],
+    renamer: &mut Renamer,
+) {
+    let mut defined = vec![];
+    for (var, phi) in &mut phis[b] {
+        phi.dest = Some(renamer.define(var));
+        defined.push(var.clone());
+    }
+    for instr in &mut cfg.blocks[b].instrs {
+        for arg in &mut instr.args {
+            if let Some(name) = renamer.current(arg) {
+                *arg = name.clone();
+            }
+        }
+        if let Some(dest) = &mut instr.dest {
+            defined.push(dest.clone());
+            *dest = renamer.define(dest);
+        }
+    }
+
+    let label = cfg.blocks[b].label.clone().expect("every block is labeled");
+    for &succ in &succs[b] {
+        for (var, phi) in &mut phis[succ] {
+            let name = renamer.current(var).map_or(UNDEFINED_VAR, |s| s);
+            phi.args.push(name.to_string());
+            phi.labels.push(label.clone());
+        }
+    }
+
+    for &child in &children[b] {
+        rename(child, cfg, phis, succs, children, renamer);
+    }
+    for var in defined {
+        renamer.stacks.get_mut(&var).map(Vec::pop);
+    }
+}
+
+/// Converts a CFG to SSA form, using the classic algorithm of Cytron et al.:
+/// `phi`s are inserted at the dominance frontiers of each variable's
+/// definitions, and then variables are renamed by walking the dominator tree.
+/// - Unreachable blocks are removed, every block is given a label
+///   (since `phi`s refer to their predecessors by label), and a new entry
+///   block is added if the original one is the target of a jump
+/// - Function arguments keep their names
+/// - The `phi`s aren't pruned, so a `phi` may refer to `UNDEFINED_VAR`
+pub fn cfg_to_ssa(cfg: &mut Cfg) {
+    if cfg.blocks.is_empty() {
+        return;
+    }
+    cfg.remove_unreachable_blocks();
+    if !cfg.predecessors()[0].is_empty() {
+        let label = cfg.fresh_label("entry");
+        cfg.blocks.insert(
+            0,
+            BasicBlock {
+                label: Some(label),
+                instrs: vec![],
+            },
+        );
+    }
+    for b in 0..cfg.blocks.len() {
+        if cfg.blocks[b].label.is_none() {
+            cfg.blocks[b].label = Some(cfg.fresh_label("b"));
+        }
+    }
+
+    let types = cfg.var_types();
+    let dominators = cfg.dominators();
+    let frontiers = dominators.frontiers(cfg);
+    let succs: Vec<Vec<usize>> =
+        (0..cfg.blocks.len()).map(|b| cfg.successors(b)).collect();
+
+    // Find the blocks where each variable is defined
+    // (`BTreeMap`s are used so that the output is deterministic)
+    let mut defs: BTreeMap<&str, BTreeSet<usize>> = BTreeMap::new();
+    for (name, _) in &cfg.args {
+        defs.entry(name).or_default().insert(0);
+    }
+    for (b, block) in cfg.blocks.iter().enumerate() {
+        for dest in block.instrs.iter().filter_map(|i| i.dest.as_deref()) {
+            defs.entry(dest).or_default().insert(b);
+        }
+    }
+
+    // Insert `phi`s at the (iterated) dominance frontiers of the definitions
+    let mut phis: Vec<Vec<(String, Operation)>> =
+        vec![vec![]; cfg.blocks.len()];
+    for (var, def_blocks) in &defs {
+        let mut has_phi = HashSet::new();
+        let mut visited: HashSet<usize> = def_blocks.iter().copied().collect();
+        let mut worklist: Vec<usize> = def_blocks.iter().copied().collect();
+        while let Some(b) = worklist.pop() {
+            let mut frontier: Vec<usize> =
+                frontiers[b].iter().copied().collect();
+            frontier.sort_unstable();
+            for d in frontier {
+                if has_phi.insert(d) {
+                    let phi = Operation {
+                        op: Opcode::Phi,
+                        dest: Some(var.to_string()),
+                        ty: types.get(*var).copied(),
+                        value: None,
+                        args: vec![],
+                        labels: vec![],
+                        func: None,
+                    };
+                    phis[d].push((var.to_string(), phi));
+                    if visited.insert(d) {
+                        worklist.push(d);
+                    }
+                }
+            }
+        }
+    }
+
+    // Rename variables, starting with the function arguments
+    let mut used: HashSet<String> =
+        defs.keys().map(|s| s.to_string()).collect();
+    for block in &cfg.blocks {
+        for instr in &block.instrs {
+            used.extend(instr.args.iter().cloned());
+        }
+    }
+    used.insert(UNDEFINED_VAR.to_string());
+    let mut renamer = Renamer {
+        stacks: cfg
+            .args
+            .iter()
+            .map(|(name, _)| (name.clone(), vec![name.clone()]))
+            .collect(),
+        counters: HashMap::new(),
+        used,
+    };
+    let children = dominators.children();
+    rename(0, cfg, &mut phis, &succs, &children, &mut renamer);
+
+    // Put the `phi`s at the start of their blocks
+    for (block, block_phis) in cfg.blocks.iter_mut().zip(phis) {
+        let mut instrs: Vec<Operation> =
+            block_phis.into_iter().map(|(_, phi)| phi).collect();
+        instrs.append(&mut block.instrs);
+        block.instrs = instrs;
+    }
+}
+
+/// Converts a function to SSA form (see `cfg_to_ssa`),
+/// producing a new flat function
+pub fn to_ssa<P: IndexPair>(func: Function<'_, P>) -> InstrStore {
+    let mut cfg = Cfg::new(func);
+    cfg_to_ssa(&mut cfg);
+    cfg.to_instr_store()
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod ssa_tests {
+    use std::collections::HashSet;
+    use std::fs::{self, File};
+    use std::io::BufReader;
+
+    use zerocopy::IntoBytes;
+
+    use crate::interp::interp_program_captured;
+    use crate::memfile::{
+        AlignedBytes, get_flat_program, instr_stores_to_fbril_bytes,
+        json_to_fbril_bytes,
+    };
+    use crate::program::{InstructionRef, Program};
+    use crate::ssa::to_ssa;
+    use crate::types::InstrStore;
+
+    /// Every variable is defined at most once after converting to SSA,
+    /// and the program's output is unchanged (for a few benchmarks with
+    /// loops & non-trivial control flow, run with the args in their
+    /// `# ARGS:` comments)
+    #[test]
+    fn test_ssa_preserves_behavior() {
+        for name in [
+            "gcd",
+            "lcm",
+            "collatz",
+            "dead_block",
+            "fizz-buzz",
+            "non_linear_control_flow",
+            "pascals-row",
+            "sum-divisors",
+        ] {
+            let path = format!("test/{name}.json");
+            let bril = fs::read_to_string(format!("test/{name}.bril"))
+                .expect("Unable to read .bril file");
+            let args: Vec<&str> = bril
+                .lines()
+                .find_map(|line| {
+                    line.trim_start_matches('#').trim().strip_prefix("ARGS:")
+                })
+                .map_or(vec![], |args| args.split_whitespace().collect());
+
+            let file = File::open(&path).expect("Unable to open file");
+            let json: serde_json::Value =
+                serde_json::from_reader(BufReader::new(file))
+                    .expect("Unable to parse JSON");
+            let bytes = json_to_fbril_bytes(&json);
+            let data = AlignedBytes::new(&bytes);
+            let program =
+                get_flat_program(&data).expect("valid file should load");
+            let expected = interp_program_captured(&program, args.clone());
+
+            let ssa_stores: Vec<InstrStore> = Program::new(program.views())
+                .functions()
+                .map(to_ssa)
+                .collect();
+            let ssa_bytes = instr_stores_to_fbril_bytes(ssa_stores);
+            let ssa_words = AlignedBytes::new(&ssa_bytes);
+            let ssa_program =
+                get_flat_program(&ssa_words.as_bytes()[..ssa_bytes.len()])
+                    .expect("SSA program should load");
+            for func in Program::new(ssa_program.views()).functions() {
+                let mut dests = HashSet::new();
+                for instr in func.instructions() {
+                    if let InstructionRef::Op(op) = instr
+                        && let Some(dest) = op.dest
+                    {
+                        assert!(dests.insert(dest), "{path}: {dest}");
+                    }
+                }
+            }
+            // (errors are compared by code, since the SSA program's
+            // instructions are at different pcs)
+            let actual = interp_program_captured(&ssa_program, args);
+            assert_eq!(
+                actual.map(|output| output.lines).map_err(|d| d.code),
+                expected.map(|output| output.lines).map_err(|d| d.code),
+                "{path}"
+            );
+        }
+    }
+}
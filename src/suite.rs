@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostic::panic_message;
+use crate::interp::{InterpContext, Limits, interp_program};
+use crate::memfile::{self, FlatProgram, ProgramViews};
+use crate::printer::pad_right;
+use crate::types::IndexPair;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A suite manifest, i.e. a JSON file of the form
+/// `{ "programs": [ { "file": "fact.fbril", "args": [5], "expected_file": "fact.out" }, ... ] }`
+/// - Relative paths in the manifest are resolved w.r.t. the directory
+///   containing the manifest
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    pub programs: Vec<SuiteEntry>,
+}
+
+/// A single program to run as part of a suite
+/// - `name` is used in the report (defaults to `file`)
+/// - `args` are the command-line arguments passed to `main`
+///   (JSON numbers & booleans are accepted as well as strings)
+/// - The expected output is either supplied inline via `expected`,
+///   or read from the file `expected_file`. If neither is present,
+///   the program passes as long as it runs to completion.
+#[derive(Debug, Deserialize)]
+pub struct SuiteEntry {
+    pub name: Option<String>,
+    pub file: String,
+    #[serde(default)]
+    pub args: Vec<serde_json::Value>,
+    pub expected: Option<String>,
+    pub expected_file: Option<String>,
+}
+
+/// Outcome of running a single program in the suite
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Error,
+}
+
+/// The result of running a single `SuiteEntry`
+/// (`message` explains why a program failed / errored)
+#[derive(Debug, Serialize)]
+pub struct SuiteResult {
+    pub name: String,
+    pub file: String,
+    pub status: Status,
+    pub time_ms: f64,
+    pub message: Option<String>,
+}
+
+/// Aggregated report for an entire suite
+#[derive(Debug, Serialize)]
+pub struct SuiteReport {
+    pub results: Vec<SuiteResult>,
+    pub passed: usize,
+    pub total: usize,
+    pub total_time_ms: f64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Converts a JSON argument from the manifest to the string form
+/// expected by `interp_program`
+fn arg_to_string(arg: &serde_json::Value) -> String {
+    match arg {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Runs a single entry of the suite against the (already loaded) program
+fn run_entry<P: IndexPair>(
+    entry: &SuiteEntry,
+    program: &FlatProgram<P>,
+    base_dir: &Path,
+    limits: Limits,
+) -> SuiteResult {
+    let name = entry.name.clone().unwrap_or_else(|| entry.file.clone());
+    let args: Vec<String> = entry.args.iter().map(arg_to_string).collect();
+    let arg_strs: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+
+    let expected = match (&entry.expected, &entry.expected_file) {
+        (Some(expected), _) => Ok(Some(expected.clone())),
+        (None, Some(path)) => fs::read_to_string(base_dir.join(path))
+            .map(Some)
+            .map_err(|e| format!("unable to read `{path}`: {e}")),
+        (None, None) => Ok(None),
+    };
+
+    // Interpret the program, capturing its output in a buffer
+    // (panics in the interpreter are reported as errors for this entry
+    // rather than aborting the whole suite)
+    let mut ctx = InterpContext::new(vec![], limits);
+    let start = Instant::now();
+    let run_result = panic::catch_unwind(AssertUnwindSafe(|| {
+        interp_program(program, arg_strs, &mut ctx)
+    }));
+    let time_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let (status, message) = match (run_result, expected) {
+        (Err(payload), _) => (Status::Error, Some(panic_message(payload))),
+        (Ok(Err(diagnostic)), _) => {
+            (Status::Error, Some(diagnostic.to_string()))
+        }
+        (Ok(Ok(())), Err(msg)) => (Status::Error, Some(msg)),
+        (Ok(Ok(())), Ok(None)) => (Status::Pass, None),
+        (Ok(Ok(())), Ok(Some(expected))) => {
+            let actual = String::from_utf8_lossy(&ctx.out);
+            if actual == expected {
+                (Status::Pass, None)
+            } else {
+                (
+                    Status::Fail,
+                    Some(format!(
+                        "expected {:?}, got {:?}",
+                        expected.as_str(),
+                        actual
+                    )),
+                )
+            }
+        }
+    };
+
+    SuiteResult {
+        name,
+        file: entry.file.clone(),
+        status,
+        time_ms,
+        message,
+    }
+}
+
+/// Runs every program listed in the manifest at `manifest_path`,
+/// subject to the execution `limits`.
+/// Each distinct `.fbril` file is only mmap-ed once, even if the manifest
+/// runs it several times (e.g. with different arguments).
+pub fn run_suite(manifest_path: &str, limits: Limits) -> SuiteReport {
+    let manifest_str = fs::read_to_string(manifest_path)
+        .expect("unable to read suite manifest");
+    let manifest: Manifest =
+        serde_json::from_str(&manifest_str).expect("malformed suite manifest");
+    let base_dir: PathBuf = Path::new(manifest_path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    // Load each distinct fbril file once
+    // (files that can't be loaded are recorded along with the reason why)
+    let mut mmaps: HashMap<&str, Result<Mmap, String>> = HashMap::new();
+    for entry in &manifest.programs {
+        let path = base_dir.join(&entry.file);
+        if !mmaps.contains_key(entry.file.as_str()) {
+            let mmap = memfile::mmap_existing_file(
+                path.to_str().expect("invalid path"),
+            );
+            mmaps.insert(entry.file.as_str(), mmap);
+        }
+    }
+    let programs: HashMap<&str, Result<ProgramViews, String>> = mmaps
+        .iter()
+        .map(|(file, mmap)| {
+            let program = match mmap {
+                Ok(mmap) => memfile::load_program(mmap),
+                Err(msg) => Err(msg.clone()),
+            };
+            (*file, program)
+        })
+        .collect();
+
+    // Silence the default panic hook while running the suite
+    // (panics are reported in the table instead)
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let start = Instant::now();
+    let mut results = vec![];
+    for entry in &manifest.programs {
+        let result = match &programs[entry.file.as_str()] {
+            Ok(ProgramViews::Narrow(program)) => {
+                run_entry(entry, program, &base_dir, limits)
+            }
+            Ok(ProgramViews::Wide(program)) => {
+                run_entry(entry, program, &base_dir, limits)
+            }
+            Ok(ProgramViews::Compact(program)) => {
+                run_entry(entry, program, &base_dir, limits)
+            }
+            Err(msg) => SuiteResult {
+                name: entry.name.clone().unwrap_or_else(|| entry.file.clone()),
+                file: entry.file.clone(),
+                status: Status::Error,
+                time_ms: 0.0,
+                message: Some(msg.clone()),
+            },
+        };
+        results.push(result);
+    }
+    let total_time_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    panic::set_hook(default_hook);
+
+    let passed = results.iter().filter(|r| r.status == Status::Pass).count();
+    let total = results.len();
+    SuiteReport {
+        results,
+        passed,
+        total,
+        total_time_ms,
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl SuiteReport {
+    /// Renders the report as a human-readable table
+    pub fn to_table(&self) -> String {
+        let name_width = self
+            .results
+            .iter()
+            .map(|r| r.name.len())
+            .max()
+            .unwrap_or(0)
+            .max("NAME".len());
+
+        let mut table = format!(
+            "{}  {:<6}  {:>10}\n",
+            pad_right("NAME", name_width),
+            "STATUS",
+            "TIME (ms)"
+        );
+        for result in &self.results {
+            let status = match result.status {
+                Status::Pass => "pass",
+                Status::Fail => "FAIL",
+                Status::Error => "ERROR",
+            };
+            table.push_str(&format!(
+                "{}  {:<6}  {:>10.3}\n",
+                pad_right(&result.name, name_width),
+                status,
+                result.time_ms
+            ));
+            if let Some(message) = &result.message {
+                table.push_str(&format!("    {message}\n"));
+            }
+        }
+        table.push_str(&format!(
+            "{}/{} passed ({:.3} ms total)",
+            self.passed, self.total, self.total_time_ms
+        ));
+        table
+    }
+}
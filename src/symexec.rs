@@ -0,0 +1,529 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use crate::interp::get_label_name;
+use crate::program::{Function, InstructionRef, OpRef};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The maximum no. of instructions executed along a single path, which stops
+/// loops whose conditions are concrete from running forever
+pub const MAX_PATH_STEPS: u64 = 100_000;
+
+/// A symbolic value: either a concrete int/bool, or an expression over the
+/// function's parameters
+/// - `Call` is the value returned by a call, which isn't executed (so it's
+///   an uninterpreted function of its args)
+/// - `Unary` is only used for `not`
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymExpr {
+    Int(i64),
+    Bool(bool),
+    Param(String),
+    Call(String, Vec<SymExpr>),
+    Unary(Opcode, Box<SymExpr>),
+    Binary(Opcode, Box<SymExpr>, Box<SymExpr>),
+}
+
+/// What a path does at a `print` or `ret` instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymEventKind {
+    Print(Vec<SymExpr>),
+    Ret(Option<SymExpr>),
+}
+
+/// A `print` or `ret` reached by a path, at the instruction with index `pc`
+/// (`condition` is the path condition at that point, i.e. the branch
+/// conditions which must all hold for the path to reach it)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymEvent {
+    pub pc: usize,
+    pub condition: Vec<SymExpr>,
+    pub kind: SymEventKind,
+}
+
+/// Why a path stopped being explored
+/// - `DepthBound`: it reached a symbolic branch after forking
+///   `max_depth` times already
+/// - `StepBound`: it executed `MAX_PATH_STEPS` instructions
+/// - `Error`: it would fail at run time (e.g. it divides by zero), or
+///   reached an instruction that can't be executed symbolically
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathEnd {
+    Returned,
+    DepthBound,
+    StepBound,
+    Error(String),
+}
+
+/// A single path through a function, with the `print`s & `ret`s along it
+/// (`condition` is the final path condition)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymPath {
+    pub condition: Vec<SymExpr>,
+    pub events: Vec<SymEvent>,
+    pub end: PathEnd,
+}
+
+/// The paths explored by `symexec` through the function `func`, in the order
+/// they were explored (the branch that's taken comes before the fall-through
+/// branch)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymReport {
+    pub func: String,
+    pub paths: Vec<SymPath>,
+}
+
+/// A path which is still being explored
+/// - `depth` is the no. of symbolic branches it has forked at
+/// - `steps` is the no. of instructions it has executed
+#[derive(Debug, Clone)]
+struct State<'a> {
+    pc: usize,
+    env: HashMap<&'a str, SymExpr>,
+    condition: Vec<SymExpr>,
+    events: Vec<SymEvent>,
+    depth: usize,
+    steps: u64,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl SymExpr {
+    /// The negation of a boolean expression
+    fn not(self) -> SymExpr {
+        match self {
+            SymExpr::Bool(b) => SymExpr::Bool(!b),
+            SymExpr::Unary(Opcode::Not, expr) => *expr,
+            expr => SymExpr::Unary(Opcode::Not, Box::new(expr)),
+        }
+    }
+
+    /// Applies the binary operation `op` to `self` & `other`, folding it
+    /// into a constant if both are concrete
+    /// (returns `None` for a division by a concrete zero)
+    fn binary(self, op: Opcode, other: SymExpr) -> Option<SymExpr> {
+        use Opcode::*;
+        use SymExpr::{Bool, Int};
+        let folded = match (&self, &other) {
+            (_, Int(0)) if op == Div => return None,
+            (Int(x), Int(y)) => match op {
+                Add => Some(Int(x.wrapping_add(*y))),
+                Sub => Some(Int(x.wrapping_sub(*y))),
+                Mul => Some(Int(x.wrapping_mul(*y))),
+                Div => Some(Int(x.wrapping_div(*y))),
+                Eq => Some(Bool(x == y)),
+                Lt => Some(Bool(x < y)),
+                Gt => Some(Bool(x > y)),
+                Le => Some(Bool(x <= y)),
+                Ge => Some(Bool(x >= y)),
+                #[cfg(feature = "bitwise")]
+                Band => Some(Int(x & y)),
+                #[cfg(feature = "bitwise")]
+                Bor => Some(Int(x | y)),
+                #[cfg(feature = "bitwise")]
+                Bxor => Some(Int(x ^ y)),
+                #[cfg(feature = "bitwise")]
+                Shl => Some(Int(x.wrapping_shl(*y as u32))),
+                #[cfg(feature = "bitwise")]
+                Shr => Some(Int(x.wrapping_shr(*y as u32))),
+                _ => None,
+            },
+            // (`and` & `or` short-circuit if either side is concrete)
+            (Bool(b), _) | (_, Bool(b)) if op == And || op == Or => {
+                let other = if matches!(self, Bool(_)) {
+                    &other
+                } else {
+                    &self
+                };
+                match (op, b) {
+                    (And, false) => Some(Bool(false)),
+                    (Or, true) => Some(Bool(true)),
+                    _ => Some(other.clone()),
+                }
+            }
+            _ => None,
+        };
+        Some(folded.unwrap_or_else(|| {
+            SymExpr::Binary(op, Box::new(self), Box::new(other))
+        }))
+    }
+}
+
+impl<'a> State<'a> {
+    /// The value of the variable `var`
+    fn get(&self, var: &str) -> Result<SymExpr, PathEnd> {
+        self.env.get(var).cloned().ok_or_else(|| {
+            PathEnd::Error(format!("undefined variable `{var}`"))
+        })
+    }
+
+    /// The values of the args of `instr`
+    fn get_args(&self, instr: &OpRef<'a>) -> Result<Vec<SymExpr>, PathEnd> {
+        instr.args().iter().map(|arg| self.get(arg)).collect()
+    }
+
+    /// The value of the first arg of `instr`
+    fn get_arg(&self, instr: &OpRef<'a>) -> Result<SymExpr, PathEnd> {
+        let arg = instr.args().first().ok_or_else(|| {
+            PathEnd::Error(format!("`{}` has no args", instr.op))
+        })?;
+        self.get(arg)
+    }
+
+    fn end(self, end: PathEnd) -> SymPath {
+        SymPath {
+            condition: self.condition,
+            events: self.events,
+            end,
+        }
+    }
+}
+
+/// Executes `instr` (the instruction at `state.pc`) symbolically, updating
+/// `state` & pushing the state of the branch that isn't followed onto
+/// `pending` if `instr` forks the path
+/// (returns how the path ended, if it did)
+fn step<'a>(
+    instr: &OpRef<'a>,
+    labels: &HashMap<&str, usize>,
+    max_depth: usize,
+    state: &mut State<'a>,
+    pending: &mut Vec<State<'a>>,
+) -> Result<(), PathEnd> {
+    use Opcode::*;
+    let mut next_pc = state.pc + 1;
+    let label_pc = |idx: usize| {
+        let label = instr.labels().get(idx).ok_or_else(|| {
+            PathEnd::Error(format!("`{}` has too few labels", instr.op))
+        })?;
+        labels.get(label).copied().ok_or_else(|| {
+            PathEnd::Error(format!("undefined label `.{label}`"))
+        })
+    };
+    let value = match instr.op {
+        Const => match instr.value {
+            Some(BrilValue::IntVal(v)) => Some(SymExpr::Int(v)),
+            Some(BrilValue::BoolVal(b)) => Some(SymExpr::Bool(b.into())),
+            #[cfg(feature = "ext-values")]
+            Some(BrilValue::Extern(_)) => {
+                return Err(PathEnd::Error(
+                    "`const` can't hold an extension value".to_string(),
+                ));
+            }
+            None => {
+                return Err(PathEnd::Error("`const` has no value".to_string()));
+            }
+        },
+        Id => Some(state.get_arg(instr)?),
+        Not => Some(state.get_arg(instr)?.not()),
+        Call => {
+            let args = state.get_args(instr)?;
+            let func = instr.func.unwrap_or_default().to_string();
+            instr.dest.map(|_| SymExpr::Call(func, args))
+        }
+        Jmp => {
+            next_pc = label_pc(0)?;
+            None
+        }
+        Br => {
+            let cond = state.get_arg(instr)?;
+            let (true_pc, false_pc) = (label_pc(0)?, label_pc(1)?);
+            match cond {
+                SymExpr::Bool(b) => {
+                    next_pc = if b { true_pc } else { false_pc }
+                }
+                // (a condition the path has already branched on needn't be
+                // forked at again)
+                cond if state.condition.contains(&cond) => next_pc = true_pc,
+                cond if state.condition.contains(&cond.clone().not()) => {
+                    next_pc = false_pc
+                }
+                _ if state.depth >= max_depth => {
+                    return Err(PathEnd::DepthBound);
+                }
+                cond => {
+                    state.depth += 1;
+                    let mut other = state.clone();
+                    other.pc = false_pc;
+                    other.condition.push(cond.clone().not());
+                    pending.push(other);
+                    state.condition.push(cond);
+                    next_pc = true_pc;
+                }
+            }
+            None
+        }
+        Ret | Print => {
+            let mut args = state.get_args(instr)?;
+            let kind = if instr.op == Print {
+                SymEventKind::Print(args)
+            } else {
+                SymEventKind::Ret(args.pop())
+            };
+            state.events.push(SymEvent {
+                pc: state.pc,
+                condition: state.condition.clone(),
+                kind,
+            });
+            if instr.op == Ret {
+                return Err(PathEnd::Returned);
+            }
+            None
+        }
+        Nop => None,
+        Phi => {
+            return Err(PathEnd::Error(
+                "`phi` can't be executed symbolically".to_string(),
+            ));
+        }
+        op => {
+            let mut args = state.get_args(instr)?.into_iter();
+            let (Some(x), Some(y)) = (args.next(), args.next()) else {
+                return Err(PathEnd::Error(format!(
+                    "no. of args to `{op}` != 2"
+                )));
+            };
+            let Some(value) = x.binary(op, y) else {
+                return Err(PathEnd::Error("division by zero".to_string()));
+            };
+            Some(value)
+        }
+    };
+    if let (Some(dest), Some(value)) = (instr.dest, value) {
+        state.env.insert(dest, value);
+    }
+    state.pc = next_pc;
+    Ok(())
+}
+
+/// Executes `func` with symbolic values for its parameters, forking at each
+/// `br` whose condition is symbolic (up to `max_depth` times along each
+/// path, so at most 2^`max_depth` paths are explored), & reports the path
+/// conditions under which each `print` & `ret` is reached
+/// - Calls aren't followed: their results are left symbolic
+///   (see `SymExpr::Call`), and their side effects are ignored
+/// - Path conditions aren't checked for satisfiability (other than
+///   branching the same way on a condition that's already in the path
+///   condition), so a path may be infeasible (e.g. if it takes a branch on
+///   `x < 0` after one on `x > 0`)
+pub fn symexec<P: IndexPair>(
+    func: Function<'_, P>,
+    max_depth: usize,
+) -> SymReport {
+    let view = func.view();
+    let instrs: Vec<InstructionRef> = func
+        .instructions()
+        .filter(|instr| !matches!(instr, InstructionRef::Label(_)))
+        .collect();
+    let labels: HashMap<&str, usize> = view
+        .label_table
+        .iter()
+        .map(|label| {
+            let (offset, len) = label.label_idxes.idxes();
+            (get_label_name(view, offset, len), label.pc)
+        })
+        .collect();
+
+    let env = func
+        .params()
+        .map(|(name, _)| (name, SymExpr::Param(name.to_string())))
+        .collect();
+    let mut pending = vec![State {
+        pc: 0,
+        env,
+        condition: vec![],
+        events: vec![],
+        depth: 0,
+        steps: 0,
+    }];
+    let mut paths = vec![];
+    while let Some(mut state) = pending.pop() {
+        let end = loop {
+            if state.steps >= MAX_PATH_STEPS {
+                break PathEnd::StepBound;
+            }
+            state.steps += 1;
+            let result = match instrs.get(state.pc) {
+                // (falling off the end of the function returns from it)
+                None => Err(PathEnd::Returned),
+                Some(InstructionRef::Op(instr)) => {
+                    step(instr, &labels, max_depth, &mut state, &mut pending)
+                }
+                Some(_) => Err(PathEnd::Error(
+                    "extension instructions can't be executed symbolically"
+                        .to_string(),
+                )),
+            };
+            if let Err(end) = result {
+                break end;
+            }
+        };
+        paths.push(state.end(end));
+    }
+    SymReport {
+        func: func.name().to_string(),
+        paths,
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl fmt::Display for SymExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymExpr::Int(v) => write!(f, "{v}"),
+            SymExpr::Bool(b) => write!(f, "{b}"),
+            SymExpr::Param(name) => write!(f, "{name}"),
+            SymExpr::Call(func, args) => {
+                let args: Vec<String> =
+                    args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "@{func}({})", args.join(", "))
+            }
+            SymExpr::Unary(_, expr) => write!(f, "!{expr}"),
+            SymExpr::Binary(op, x, y) => {
+                let op = match op {
+                    Opcode::Add => "+".to_string(),
+                    Opcode::Sub => "-".to_string(),
+                    Opcode::Mul => "*".to_string(),
+                    Opcode::Div => "/".to_string(),
+                    Opcode::Eq => "==".to_string(),
+                    Opcode::Lt => "<".to_string(),
+                    Opcode::Gt => ">".to_string(),
+                    Opcode::Le => "<=".to_string(),
+                    Opcode::Ge => ">=".to_string(),
+                    Opcode::And => "&&".to_string(),
+                    Opcode::Or => "||".to_string(),
+                    op => op.to_string(),
+                };
+                write!(f, "({x} {op} {y})")
+            }
+        }
+    }
+}
+
+/// Renders a path condition as a conjunction (`true` if it's empty)
+fn format_condition(condition: &[SymExpr]) -> String {
+    if condition.is_empty() {
+        return "true".to_string();
+    }
+    let conds: Vec<String> =
+        condition.iter().map(|cond| cond.to_string()).collect();
+    conds.join(" && ")
+}
+
+impl SymReport {
+    /// Renders each path as its path condition, followed by the `print`s &
+    /// `ret`s along it (& why it stopped being explored, unless it returned)
+    pub fn to_text(&self) -> String {
+        let mut text =
+            format!("@{}: {} paths explored\n", self.func, self.paths.len());
+        for (idx, path) in self.paths.iter().enumerate() {
+            writeln!(
+                text,
+                "path {}: {}",
+                idx + 1,
+                format_condition(&path.condition)
+            )
+            .unwrap();
+            for event in &path.events {
+                let instr = match &event.kind {
+                    SymEventKind::Print(args) => {
+                        let args: Vec<String> =
+                            args.iter().map(|arg| arg.to_string()).collect();
+                        format!("print {}", args.join(" "))
+                    }
+                    SymEventKind::Ret(Some(value)) => format!("ret {value}"),
+                    SymEventKind::Ret(None) => "ret".to_string(),
+                };
+                writeln!(text, "  {:>4}  {instr}", event.pc).unwrap();
+            }
+            match &path.end {
+                PathEnd::Returned => {}
+                PathEnd::DepthBound => {
+                    text.push_str("  cut off at the depth bound\n");
+                }
+                PathEnd::StepBound => {
+                    text.push_str("  cut off at the step bound\n");
+                }
+                PathEnd::Error(msg) => {
+                    writeln!(text, "  error: {msg}").unwrap()
+                }
+            }
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod symexec_tests {
+    use serde_json::json;
+
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::program::Function;
+    use crate::symexec::{PathEnd, symexec};
+
+    /// Symbolic branches fork the path, concrete ones don't, and the
+    /// values printed & returned are expressions over the parameters
+    #[test]
+    fn test_symexec() {
+        let json = json!({ "functions": [{
+            "name": "main",
+            "args": [{ "name": "x", "type": "int" }],
+            "type": "int",
+            "instrs": [
+                { "op": "const", "dest": "zero", "type": "int", "value": 0 },
+                { "op": "const", "dest": "t", "type": "bool", "value": true },
+                { "op": "br", "labels": ["check", "never"], "args": ["t"] },
+                { "label": "never" },
+                { "op": "div", "dest": "y", "type": "int",
+                  "args": ["x", "zero"] },
+                { "label": "check" },
+                { "op": "lt", "dest": "neg", "type": "bool",
+                  "args": ["x", "zero"] },
+                { "op": "br", "labels": ["negative", "positive"],
+                  "args": ["neg"] },
+                { "label": "negative" },
+                { "op": "sub", "dest": "x", "type": "int",
+                  "args": ["zero", "x"] },
+                { "op": "print", "args": ["x"] },
+                { "label": "positive" },
+                { "op": "add", "dest": "y", "type": "int",
+                  "args": ["x", "x"] },
+                { "op": "ret", "args": ["y"] }
+            ]
+        }]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let main = Function::new(program.get("main").unwrap());
+
+        let report = symexec(main, 8);
+        assert_eq!(
+            report.to_text(),
+            "@main: 2 paths explored\n\
+            path 1: (x < 0)\n\
+            \x20    7  print (0 - x)\n\
+            \x20    9  ret ((0 - x) + (0 - x))\n\
+            path 2: !(x < 0)\n\
+            \x20    9  ret (x + x)\n"
+        );
+        assert!(report.paths.iter().all(|p| p.end == PathEnd::Returned));
+
+        // (with no forks allowed, the only path is cut off at the first
+        // symbolic branch)
+        let report = symexec(main, 0);
+        assert_eq!(report.paths.len(), 1);
+        assert_eq!(report.paths[0].end, PathEnd::DepthBound);
+    }
+}
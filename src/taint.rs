@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+use crate::debugger::format_instr;
+use crate::fusion::FusedOp;
+use crate::interp::{Environment, get_args, get_func_name, get_var};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// A `print` or `br` which was executed with at least one tainted operand
+/// - `instr` is the instruction, as it's printed by the debugger
+/// - `count` is the no. of times it was executed with tainted operands
+/// - `vars` are the operands which were tainted (at least once)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaintedSite {
+    pub func: String,
+    pub pc: usize,
+    pub instr: String,
+    pub count: u64,
+    pub vars: Vec<String>,
+}
+
+/// The `print`s & `br`s that were influenced by tainted data during a run
+/// (each ordered by function & PC)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaintReport {
+    pub prints: Vec<TaintedSite>,
+    pub branches: Vec<TaintedSite>,
+}
+
+/// The taint of the variables in a call which is currently on the stack
+/// - `call` is the call instruction this function is currently executing
+///   (if any): whether each of its args is tainted, & the dest that the
+///   callee's return value is assigned to
+/// - `ret` is whether the value returned by this function is tainted
+#[derive(Debug, Default)]
+struct Frame {
+    tainted: HashSet<String>,
+    call: Option<(Vec<bool>, Option<String>)>,
+    ret: bool,
+}
+
+/// An `Observer` which tracks which variables hold tainted data during a
+/// run, & records which `print`s & `br`s are influenced by it
+/// - Data is tainted at its sources: the arguments of the entry function
+///   (if `taint_args` is true), & every variable named in `sources`, which
+///   is tainted whenever it's assigned (or bound as a parameter) in any
+///   function
+/// - Taint propagates through value operations: an instruction's dest is
+///   tainted if any of its args are (so `const` clears the taint of its
+///   dest), & through the args & return values of calls. A `phi` is tainted
+///   if any of its args are, whichever one is actually chosen.
+/// - Only explicit flows are tracked: a variable assigned inside a branch on
+///   tainted data isn't tainted unless what's assigned to it is
+pub struct TaintTracker {
+    taint_args: bool,
+    sources: HashSet<String>,
+    stack: Vec<Frame>,
+    prints: BTreeMap<(String, usize), TaintedSite>,
+    branches: BTreeMap<(String, usize), TaintedSite>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl TaintTracker {
+    /// Creates a tracker which taints the variables named in `sources`
+    /// (& the entry function's arguments, if `taint_args` is true)
+    pub fn new(sources: &[&str], taint_args: bool) -> Self {
+        Self {
+            taint_args,
+            sources: sources.iter().map(|var| var.to_string()).collect(),
+            stack: vec![],
+            prints: BTreeMap::new(),
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Marks `var` as tainted in `frame` if `tainted` is true (or if `var`
+    /// is a source), & as untainted otherwise
+    fn assign(&self, frame: &mut Frame, var: &str, tainted: bool) {
+        if tainted || self.sources.contains(var) {
+            frame.tainted.insert(var.to_string());
+        } else {
+            frame.tainted.remove(var);
+        }
+    }
+
+    /// The `print`s & `br`s that have been influenced by tainted data
+    pub fn report(&self) -> TaintReport {
+        TaintReport {
+            prints: self.prints.values().cloned().collect(),
+            branches: self.branches.values().cloned().collect(),
+        }
+    }
+}
+
+/// Records that the instruction at `pc` was executed with the tainted
+/// operands `vars` in `sites`
+fn record_site<P: IndexPair>(
+    sites: &mut BTreeMap<(String, usize), TaintedSite>,
+    instr_view: &InstrView<P>,
+    pc: usize,
+    instr: &FlatInstr<P>,
+    vars: Vec<&str>,
+) {
+    let func = get_func_name(instr_view);
+    let site =
+        sites
+            .entry((func.to_string(), pc))
+            .or_insert_with(|| TaintedSite {
+                func: func.to_string(),
+                pc,
+                instr: format_instr(instr_view, instr),
+                count: 0,
+                vars: vec![],
+            });
+    site.count += 1;
+    for var in vars {
+        if !site.vars.iter().any(|v| v == var) {
+            site.vars.push(var.to_string());
+        }
+    }
+}
+
+impl Observer for TaintTracker {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        let Some(mut frame) = self.stack.pop() else {
+            return;
+        };
+        // (the first instruction of a superinstruction keeps its operands,
+        // so it's treated like the original instruction)
+        let op = match FusedOp::decode(instr.op()) {
+            Some((_, first)) => Some(first),
+            None => Opcode::u32_to_opcode(instr.op()),
+        };
+        let args = instr
+            .args
+            .get()
+            .map_or(vec![], |(offset, len)| get_args(instr_view, offset, len));
+        let dest = instr
+            .dest
+            .get()
+            .map(|(offset, len)| get_var(instr_view, offset, len));
+        let tainted_args: Vec<&str> = args
+            .iter()
+            .copied()
+            .filter(|arg| frame.tainted.contains(*arg))
+            .collect();
+
+        match op {
+            Some(Opcode::Call) => {
+                let taints = args
+                    .iter()
+                    .map(|arg| frame.tainted.contains(*arg))
+                    .collect();
+                frame.call = Some((taints, dest.map(str::to_string)));
+            }
+            Some(Opcode::Ret) => frame.ret = !tainted_args.is_empty(),
+            Some(Opcode::Print) if !tainted_args.is_empty() => {
+                record_site(
+                    &mut self.prints,
+                    instr_view,
+                    pc,
+                    instr,
+                    tainted_args,
+                );
+            }
+            Some(Opcode::Br) if !tainted_args.is_empty() => {
+                record_site(
+                    &mut self.branches,
+                    instr_view,
+                    pc,
+                    instr,
+                    tainted_args,
+                );
+            }
+            _ => {
+                if let Some(dest) = dest {
+                    self.assign(&mut frame, dest, !tainted_args.is_empty());
+                }
+            }
+        }
+        self.stack.push(frame);
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        // (the entry function isn't called by an instruction, so its
+        // arguments are tainted iff `taint_args` is set)
+        let taints = match self.stack.last() {
+            Some(caller) => caller
+                .call
+                .as_ref()
+                .map_or(vec![], |(taints, _)| taints.clone()),
+            None => vec![self.taint_args; callee.func_args.len()],
+        };
+        let mut frame = Frame::default();
+        for (idx, arg) in callee.func_args.iter().enumerate() {
+            let (offset, len) = arg.arg_name_idxes.idxes();
+            let name = get_var(callee, offset, len);
+            let tainted = taints.get(idx).copied().unwrap_or(false);
+            self.assign(&mut frame, name, tainted);
+        }
+        self.stack.push(frame);
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        _callee: &InstrView<P>,
+        _value: Option<BrilValue>,
+    ) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        let Some(mut caller) = self.stack.pop() else {
+            return;
+        };
+        if let Some((_, Some(dest))) = caller.call.take() {
+            self.assign(&mut caller, &dest, frame.ret);
+        }
+        self.stack.push(caller);
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                               Pretty-Printing                              */
+/* -------------------------------------------------------------------------- */
+
+impl TaintReport {
+    /// Renders the report as plain text, listing each tainted `print` &
+    /// `br` along with the no. of times it was executed with tainted
+    /// operands & which of its operands were tainted
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if self.prints.is_empty() && self.branches.is_empty() {
+            text.push_str(
+                "no prints or branches were influenced by tainted data\n",
+            );
+            return text;
+        }
+        for (title, sites) in [
+            ("tainted prints", &self.prints),
+            ("tainted branches", &self.branches),
+        ] {
+            writeln!(text, "{title}: {}", sites.len()).unwrap();
+            for site in sites {
+                writeln!(
+                    text,
+                    "  @{} pc {}: {}  ({}x, via {})",
+                    site.func,
+                    site.pc,
+                    site.instr,
+                    site.count,
+                    site.vars.join(", ")
+                )
+                .unwrap();
+            }
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod taint_tests {
+    use serde_json::json;
+
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::taint::TaintTracker;
+
+    /// Taint flows through calls & value operations, but not through
+    /// `const`s, & only the `print`s & `br`s that use tainted data are
+    /// reported
+    #[test]
+    fn test_taint() {
+        let json = json!({ "functions": [
+            {
+                "name": "main",
+                "args": [
+                    { "name": "a", "type": "int" },
+                    { "name": "b", "type": "int" }
+                ],
+                "instrs": [
+                    { "op": "call", "dest": "c", "type": "int",
+                      "funcs": ["double"], "args": ["a"] },
+                    { "op": "print", "args": ["c"] },
+                    { "op": "print", "args": ["b"] },
+                    { "op": "const", "dest": "a", "type": "int", "value": 1 },
+                    { "op": "lt", "dest": "cond", "type": "bool",
+                      "args": ["a", "c"] },
+                    { "op": "br", "labels": ["then", "end"],
+                      "args": ["cond"] },
+                    { "label": "then" },
+                    { "op": "print", "args": ["a"] },
+                    { "label": "end" }
+                ]
+            },
+            {
+                "name": "double",
+                "args": [{ "name": "x", "type": "int" }],
+                "type": "int",
+                "instrs": [
+                    { "op": "add", "dest": "y", "type": "int",
+                      "args": ["x", "x"] },
+                    { "op": "ret", "args": ["y"] }
+                ]
+            }
+        ]});
+        let bytes = AlignedBytes::new(&json_to_fbril_bytes(&json));
+        let program = get_flat_program(&bytes).expect("valid file should load");
+        let run = |tracker: &mut TaintTracker| {
+            let mut ctx = InterpContext::new(vec![], Limits::default())
+                .with_observer(tracker);
+            interp_program(&program, vec!["3", "4"], &mut ctx)
+                .expect("program should run");
+        };
+
+        // (`a` is a source, so it's tainted again when the `const` assigns
+        // to it)
+        let mut tracker = TaintTracker::new(&["a"], false);
+        run(&mut tracker);
+        let report = tracker.report();
+        let prints: Vec<&str> = report
+            .prints
+            .iter()
+            .map(|site| site.instr.as_str())
+            .collect();
+        assert_eq!(prints, vec!["print c;", "print a;"]);
+        assert_eq!(report.branches.len(), 1);
+        assert_eq!(report.branches[0].vars, vec!["cond"]);
+        assert!(
+            report
+                .to_text()
+                .contains("@main pc 1: print c;  (1x, via c)")
+        );
+
+        // (the entry function's args are only tainted when they're bound,
+        // so the `const` clears the taint of `a`)
+        let mut tracker = TaintTracker::new(&[], true);
+        run(&mut tracker);
+        let report = tracker.report();
+        let prints: Vec<&str> = report
+            .prints
+            .iter()
+            .map(|site| site.instr.as_str())
+            .collect();
+        assert_eq!(prints, vec!["print c;", "print b;"]);
+        assert_eq!(report.branches.len(), 1);
+    }
+}
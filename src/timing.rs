@@ -0,0 +1,250 @@
+#[cfg(feature = "timing")]
+use std::cell::RefCell;
+use std::time::Duration;
+#[cfg(feature = "timing")]
+use std::time::Instant;
+
+use crate::types::{NUM_OPCODES, Opcode};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// Whether the interpreter was built with the `timing` feature, i.e. whether
+/// it records `Timings` at all (without it, `Timer`s do nothing & compile
+/// away, so the interpreter loop isn't slowed down)
+pub const ENABLED: bool = cfg!(feature = "timing");
+
+/// What the time measured by a `Timer` is charged to. Timers can be
+/// nested, and time is only ever charged to the innermost running timer,
+/// so the categories are disjoint. Once an instruction has been executed,
+/// the time until the next timer starts (i.e. moving on to the next
+/// instruction, or returning from a call) is charged to `Dispatch`.
+/// - `Dispatch`: fetching & decoding an instruction (including tracking the
+///   labels reached, notifying the observer & checking the execution limits)
+/// - `EnvLookup`: looking up the value of a variable
+/// - `BranchResolution`: finding the PC that a `jmp` / `br` jumps to
+/// - `Op`: the rest of the time spent executing an instruction with the
+///   given opcode (for a `call`, this doesn't include the instructions
+///   executed by the callee)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    Dispatch,
+    EnvLookup,
+    BranchResolution,
+    Op(Opcode),
+}
+
+/// The no. of times each category was timed & the total time charged to it
+/// - `opcodes[op as usize]` is the entry for `Category::Op(op)`
+/// - `dispatch`, `env_lookup` & `branch_resolution` are the entries for
+///   the other categories
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timings {
+    pub dispatch: (u64, Duration),
+    pub env_lookup: (u64, Duration),
+    pub branch_resolution: (u64, Duration),
+    pub opcodes: [(u64, Duration); NUM_OPCODES],
+}
+
+/// Charges the time from when it's started until it's dropped to its
+/// category in the current thread's `Timings` (a no-op without the `timing`
+/// feature)
+#[must_use]
+pub struct Timer {
+    _private: (),
+}
+
+/// The state of the running timers on the current thread
+/// - `running` holds the categories of the running timers (innermost last)
+/// - `current` is the category which time is currently being charged to,
+///   & `since` is when time was last charged to a category
+#[cfg(feature = "timing")]
+#[derive(Default)]
+struct Clock {
+    timings: Timings,
+    running: Vec<Category>,
+    current: Option<Category>,
+    since: Option<Instant>,
+}
+
+#[cfg(feature = "timing")]
+thread_local! {
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::default());
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(feature = "timing")]
+impl Clock {
+    /// Charges the time since the last call to the current category
+    fn charge(&mut self) {
+        let now = Instant::now();
+        if let (Some(category), Some(since)) = (self.current, self.since) {
+            self.timings.entry(category).1 += now - since;
+        }
+        self.since = Some(now);
+    }
+}
+
+impl Timer {
+    /// Starts timing something which is charged to `category`
+    #[inline(always)]
+    pub fn start(category: Category) -> Self {
+        #[cfg(feature = "timing")]
+        CLOCK.with_borrow_mut(|clock| {
+            clock.charge();
+            clock.timings.entry(category).0 += 1;
+            clock.running.push(category);
+            clock.current = Some(category);
+        });
+        #[cfg(not(feature = "timing"))]
+        let _ = category;
+        Self { _private: () }
+    }
+}
+
+#[cfg(feature = "timing")]
+impl Drop for Timer {
+    fn drop(&mut self) {
+        CLOCK.with_borrow_mut(|clock| {
+            clock.charge();
+            clock.current = match clock.running.pop() {
+                // (back to the instruction that did the lookup)
+                Some(Category::EnvLookup | Category::BranchResolution) => {
+                    clock.running.last().copied()
+                }
+                _ if clock.running.is_empty() => None,
+                _ => Some(Category::Dispatch),
+            };
+        });
+    }
+}
+
+/// Returns the `Timings` recorded by the current thread so far,
+/// & resets them (always empty without the `timing` feature)
+pub fn take_timings() -> Timings {
+    #[cfg(feature = "timing")]
+    {
+        CLOCK.with_borrow_mut(|clock| std::mem::take(&mut clock.timings))
+    }
+    #[cfg(not(feature = "timing"))]
+    {
+        Timings::default()
+    }
+}
+
+impl Timings {
+    /// The entry for `category`
+    pub fn entry(&mut self, category: Category) -> &mut (u64, Duration) {
+        match category {
+            Category::Dispatch => &mut self.dispatch,
+            Category::EnvLookup => &mut self.env_lookup,
+            Category::BranchResolution => &mut self.branch_resolution,
+            Category::Op(op) => &mut self.opcodes[op as usize],
+        }
+    }
+
+    /// Renders a breakdown of where the time went (the opcodes are sorted by
+    /// the total time spent executing them). Timing each instruction is much
+    /// slower than executing most of them, so the absolute times are inflated
+    /// & only the relative times are meaningful.
+    pub fn to_text(&self) -> String {
+        let ops: Duration = self.opcodes.iter().map(|(_, time)| *time).sum();
+        let total = self.dispatch.1
+            + self.env_lookup.1
+            + self.branch_resolution.1
+            + ops;
+        let mut text = format!(
+            "time per category ({:.3}ms in total):\n",
+            total.as_secs_f64() * 1000.0
+        );
+        let row = |name: &str, (count, time): (u64, Duration)| {
+            let share = if total.is_zero() {
+                0.0
+            } else {
+                time.as_secs_f64() / total.as_secs_f64() * 100.0
+            };
+            let per_call = if count == 0 {
+                0.0
+            } else {
+                time.as_secs_f64() * 1e9 / count as f64
+            };
+            format!(
+                "  {name:<19} {share:>5.1}%  {:>10.3}ms  {count:>10}  \
+                 {per_call:>8.1}ns each\n",
+                time.as_secs_f64() * 1000.0,
+            )
+        };
+        let num_ops = self.opcodes.iter().map(|(count, _)| count).sum();
+        text.push_str(&row("dispatch", self.dispatch));
+        text.push_str(&row("env lookup", self.env_lookup));
+        text.push_str(&row("branch resolution", self.branch_resolution));
+        text.push_str(&row("executing ops", (num_ops, ops)));
+
+        let mut opcodes: Vec<(Opcode, (u64, Duration))> = self
+            .opcodes
+            .iter()
+            .enumerate()
+            .filter(|(_, (count, _))| *count > 0)
+            .filter_map(|(op, &entry)| {
+                Some((Opcode::u32_to_opcode(op as u32)?, entry))
+            })
+            .collect();
+        opcodes.sort_by_key(|(_, (_, time))| std::cmp::Reverse(*time));
+        text.push_str("time per opcode (excluding env lookups & branches):\n");
+        for (op, entry) in opcodes {
+            text.push_str(&row(op.as_str(), entry));
+        }
+        text
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod timing_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::interp::interp_program_captured;
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::timing::{ENABLED, take_timings};
+    use crate::types::Opcode;
+
+    /// Every instruction is timed once (when the `timing` feature is enabled)
+    #[test]
+    fn test_timings() {
+        let file = File::open("test/gcd.json").expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        take_timings();
+        interp_program_captured(&program, vec!["4", "6"])
+            .expect("program should run");
+        let timings = take_timings();
+        let num_ops: u64 = timings.opcodes.iter().map(|e| e.0).sum();
+        if ENABLED {
+            assert!(num_ops > 0);
+            assert_eq!(timings.opcodes[Opcode::Print as usize].0, 1);
+            // (labels aren't instructions, so only ops are dispatched)
+            assert_eq!(timings.dispatch.0, num_ops);
+            assert!(timings.env_lookup.0 > 0);
+            assert_eq!(
+                timings.branch_resolution.0,
+                timings.opcodes[Opcode::Jmp as usize].0
+                    + timings.opcodes[Opcode::Br as usize].0
+            );
+        } else {
+            assert_eq!(num_ops, 0);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use crate::interp::{Environment, get_func_name};
+use crate::observer::Observer;
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The maximum no. of instructions in a trace (longer traces are abandoned)
+pub const MAX_TRACE_LEN: usize = 10_000;
+
+/// One iteration of a hot loop, as recorded by a `TraceRecorder`
+/// - `func` & `header_pc` identify the loop, where `header_pc` is the
+///   target of the loop's back edge
+/// - `pcs` are the PCs of the (non-label) instructions executed during the
+///   iteration, in order, ending with the back edge. Instructions executed
+///   by functions called from the loop aren't included.
+/// - `guards` are the `br`s executed during the iteration, along with the
+///   direction each one went: code compiled from the trace is only valid
+///   while every `br` goes the same way, and must return to the interpreter
+///   (at the `br`'s target) as soon as one doesn't
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub func: String,
+    pub header_pc: usize,
+    pub pcs: Vec<usize>,
+    pub guards: Vec<(usize, bool)>,
+}
+
+/// A trace which is being recorded
+/// - `back_edge_pc` is the PC of the jump/branch which ends the trace
+/// - `depth` is the no. of calls made from the trace that haven't returned
+struct Recording {
+    trace: Trace,
+    back_edge_pc: usize,
+    depth: usize,
+}
+
+/// An `Observer` which detects hot loops & records a trace of one iteration
+/// of each of them (which `jit.rs` then compiles).
+/// - Like other tracing JITs, loops are found by looking for back edges,
+///   i.e. `jmp`s & `br`s to an earlier PC. Since PCs in the flat format are
+///   stable, a back edge is identified by its function & PC.
+/// - Once a back edge has been executed more than `threshold` times,
+///   the next iteration of its loop is recorded. A recording is abandoned
+///   if it takes a different back edge (e.g. the loop exits, or an inner
+///   loop is entered), if its function returns, or if it gets longer than
+///   `MAX_TRACE_LEN`, in which case it is retried the next time the
+///   back edge is taken.
+/// - `traces` holds the completed traces, in the order they were recorded
+///   (at most one per loop header)
+pub struct TraceRecorder {
+    threshold: u64,
+    back_edges: HashMap<String, HashMap<usize, u64>>,
+    recording: Option<Recording>,
+    pub traces: Vec<Trace>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+impl TraceRecorder {
+    /// Creates a recorder which traces loops whose back edges are executed
+    /// more than `threshold` times
+    pub fn new(threshold: u64) -> Self {
+        Self {
+            threshold,
+            back_edges: HashMap::new(),
+            recording: None,
+            traces: vec![],
+        }
+    }
+
+    /// The no. of times the back edge at `pc` in `func` has been executed
+    pub fn back_edge_count(&self, func: &str, pc: usize) -> u64 {
+        self.back_edges
+            .get(func)
+            .and_then(|counts| counts.get(&pc))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Determines if the loop with the header at `header_pc` in `func` has
+    /// already been traced
+    fn is_traced(&self, func: &str, header_pc: usize) -> bool {
+        self.traces
+            .iter()
+            .any(|trace| trace.func == func && trace.header_pc == header_pc)
+    }
+
+    /// Called when control flows from `pc` to `target_pc`, which is a back
+    /// edge if `target_pc` is at or before `pc`
+    fn on_edge<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        if target_pc > pc {
+            return;
+        }
+        let func = get_func_name(instr_view);
+
+        // Any back edge taken by the trace ends it (successfully or not)
+        if self.recording.as_ref().is_some_and(|r| r.depth == 0) {
+            let recording = self.recording.take().unwrap();
+            if recording.trace.func == func && recording.back_edge_pc == pc {
+                self.traces.push(recording.trace);
+            }
+        }
+
+        let count = match self.back_edges.get_mut(func) {
+            Some(counts) => counts.entry(pc).or_default(),
+            None => self
+                .back_edges
+                .entry(func.to_string())
+                .or_default()
+                .entry(pc)
+                .or_default(),
+        };
+        *count += 1;
+        if *count > self.threshold
+            && self.recording.is_none()
+            && !self.is_traced(func, target_pc)
+        {
+            self.recording = Some(Recording {
+                trace: Trace {
+                    func: func.to_string(),
+                    header_pc: target_pc,
+                    pcs: vec![],
+                    guards: vec![],
+                },
+                back_edge_pc: pc,
+                depth: 0,
+            });
+        }
+    }
+}
+
+impl Observer for TraceRecorder {
+    fn on_instr<P: IndexPair>(
+        &mut self,
+        _instr_view: &InstrView<P>,
+        pc: usize,
+        _instr: &FlatInstr<P>,
+        _env: &Environment,
+    ) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        if recording.depth > 0 {
+            return;
+        }
+        recording.trace.pcs.push(pc);
+        if recording.trace.pcs.len() > MAX_TRACE_LEN {
+            self.recording = None;
+        }
+    }
+
+    fn on_call<P: IndexPair>(
+        &mut self,
+        _callee: &InstrView<P>,
+        _env: &Environment,
+    ) {
+        if let Some(recording) = &mut self.recording {
+            recording.depth += 1;
+        }
+    }
+
+    fn on_return<P: IndexPair>(
+        &mut self,
+        _callee: &InstrView<P>,
+        _value: Option<BrilValue>,
+    ) {
+        if let Some(recording) = &mut self.recording {
+            if recording.depth == 0 {
+                self.recording = None;
+            } else {
+                recording.depth -= 1;
+            }
+        }
+    }
+
+    fn on_branch<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        taken: bool,
+        target_pc: usize,
+    ) {
+        if let Some(recording) = &mut self.recording
+            && recording.depth == 0
+        {
+            recording.trace.guards.push((pc, taken));
+        }
+        self.on_edge(instr_view, pc, target_pc);
+    }
+
+    fn on_jump<P: IndexPair>(
+        &mut self,
+        instr_view: &InstrView<P>,
+        pc: usize,
+        target_pc: usize,
+    ) {
+        self.on_edge(instr_view, pc, target_pc);
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod trace_tests {
+    use serde_json::json;
+
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+    use crate::trace::{Trace, TraceRecorder};
+
+    /// A loop is traced once its back edge has been executed more than
+    /// `threshold` times, & calls made from the loop aren't part of the trace
+    #[test]
+    fn test_record_hot_loop() {
+        let json = json!({ "functions": [
+            {
+                "name": "main",
+                "args": [{ "name": "n", "type": "int" }],
+                "instrs": [
+                    { "op": "const", "dest": "i", "type": "int", "value": 0 },
+                    { "label": "loop" },
+                    { "op": "lt", "dest": "cond", "type": "bool",
+                      "args": ["i", "n"] },
+                    { "op": "br", "labels": ["body", "done"],
+                      "args": ["cond"] },
+                    { "label": "body" },
+                    { "op": "call", "funcs": ["show"], "args": ["i"] },
+                    { "op": "const", "dest": "one", "type": "int",
+                      "value": 1 },
+                    { "op": "add", "dest": "i", "type": "int",
+                      "args": ["i", "one"] },
+                    { "op": "jmp", "labels": ["loop"] },
+                    { "label": "done" }
+                ]
+            },
+            {
+                "name": "show",
+                "args": [{ "name": "x", "type": "int" }],
+                "instrs": [{ "op": "print", "args": ["x"] }]
+            }
+        ]});
+        let bytes = json_to_fbril_bytes(&json);
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("valid file should load");
+
+        // The loop only runs 3 times, so the trace which starts after the
+        // 3rd iteration is abandoned when the loop exits
+        let mut recorder = TraceRecorder::new(2);
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut recorder);
+        interp_program(&program, vec!["3"], &mut ctx).expect("program runs");
+        assert!(recorder.traces.is_empty());
+        assert_eq!(recorder.back_edge_count("main", 6), 3);
+
+        let mut recorder = TraceRecorder::new(2);
+        let mut ctx = InterpContext::new(vec![], Limits::default())
+            .with_observer(&mut recorder);
+        interp_program(&program, vec!["10"], &mut ctx).expect("program runs");
+        assert_eq!(
+            recorder.traces,
+            [Trace {
+                func: "main".to_string(),
+                header_pc: 1,
+                pcs: vec![1, 2, 3, 4, 5, 6],
+                guards: vec![(2, true)],
+            }]
+        );
+    }
+}
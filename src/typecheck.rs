@@ -0,0 +1,250 @@
+use crate::diagnostic::{Diagnostic, ErrorCode};
+use crate::interp::{
+    Environment, FuncTable, get_arg_pairs, get_func, get_func_name, get_var,
+};
+use crate::types::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Renders a type with its indefinite article (e.g. "an int")
+fn with_article(ty: Type) -> String {
+    match ty {
+        Type::Int => "an int".to_string(),
+        #[cfg(feature = "ext-values")]
+        Type::Extern => "an extern".to_string(),
+        Type::Bool | Type::Ptr { .. } => format!("a {ty}"),
+    }
+}
+
+/// A type error (which is located by the interpreter)
+fn type_error(message: String) -> Diagnostic {
+    Diagnostic::new(ErrorCode::TypeError, message)
+}
+
+/// Checks the runtime types of the operands of the instruction `instr`
+/// (whose opcode is `op`) against the types that `op` expects, & the type
+/// of the value it will produce against its declared dest type, before
+/// the instruction is executed (this is what `InterpContext::check_types`
+/// enables). Returns a `TypeError` naming the offending variable if any
+/// of them don't match.
+/// - Undefined operands & undefined callees are left for the interpreter
+///   to report
+/// - `call`s are checked against the callee's signature: the types of the
+///   arguments against its parameters, & its return type against the dest
+/// - `ret`s are checked against the return type of the current function
+/// - `phi`s aren't checked, since their value depends on the path taken
+/// - Every other opcode is checked against its `OpSpec`
+pub fn check_instr_types<P: IndexPair>(
+    instr_view: &InstrView<P>,
+    op: Opcode,
+    instr: &FlatInstr<P>,
+    env: &Environment,
+    funcs: &FuncTable<'_, P>,
+) -> Result<(), Diagnostic> {
+    let args: Vec<&str> = get_arg_pairs(instr_view, instr.args)
+        .iter()
+        .map(|pair| {
+            let (offset, len) = pair.idxes();
+            get_var(instr_view, offset, len)
+        })
+        .collect();
+    let arg_type = |arg: &str| env.get(arg).map(BrilValue::get_type);
+    let expect_operands = |expected: Type| {
+        for arg in &args {
+            if let Some(actual) = arg_type(arg)
+                && actual != expected
+            {
+                return Err(type_error(format!(
+                    "`{op}` expects {expected} operands, but `{arg}` is {}",
+                    with_article(actual)
+                )));
+            }
+        }
+        Ok(())
+    };
+    let dest = instr
+        .dest
+        .get()
+        .map(|(offset, len)| get_var(instr_view, offset, len));
+    let declared: Option<Type> = instr.ty.into();
+    let expect_dest = |produced: Type, producer: &str| match (dest, declared) {
+        (Some(dest), Some(declared)) if declared != produced => {
+            Err(type_error(format!(
+                "`{dest}` is declared as {declared}, but {producer} \
+                produces {}",
+                with_article(produced)
+            )))
+        }
+        _ => Ok(()),
+    };
+    let op_name = format!("`{op}`");
+
+    match op {
+        Opcode::Id => match args.first().and_then(|arg| arg_type(arg)) {
+            Some(ty) => expect_dest(ty, &format!("`id {}`", args[0])),
+            None => Ok(()),
+        },
+        Opcode::Const => match Option::<BrilValue>::from(instr.value) {
+            Some(value) => {
+                expect_dest(value.get_type(), &format!("`const {value}`"))
+            }
+            None => Ok(()),
+        },
+        Opcode::Call => {
+            let Some((offset, len)) = instr.funcs.get() else {
+                return Ok(());
+            };
+            let callee_name = get_func(instr_view, offset, len);
+            let Some((callee, _)) = funcs.get(callee_name)? else {
+                return Ok(());
+            };
+            if callee.func_args.len() != args.len() {
+                return Err(type_error(format!(
+                    "@{callee_name} expects {} argument(s), but {} were \
+                    supplied",
+                    callee.func_args.len(),
+                    args.len()
+                )));
+            }
+            for (param, arg) in callee.func_args.iter().zip(&args) {
+                let (offset, len) = param.arg_name_idxes.idxes();
+                let param_name = get_var(callee, offset, len);
+                let expected: Option<Type> = param.arg_type.into();
+                if let (Some(expected), Some(actual)) =
+                    (expected, arg_type(arg))
+                    && expected != actual
+                {
+                    return Err(type_error(format!(
+                        "`{arg}` is {}, but the parameter `{param_name}` \
+                        of @{callee_name} is declared as {expected}",
+                        with_article(actual)
+                    )));
+                }
+            }
+            let ret_ty: Option<Type> = { callee.func_ret_ty }.into();
+            match (dest, ret_ty) {
+                (Some(dest), None) => Err(type_error(format!(
+                    "@{callee_name} doesn't return a value, but its result \
+                    is assigned to `{dest}`"
+                ))),
+                (Some(_), Some(ret_ty)) => {
+                    expect_dest(ret_ty, &format!("@{callee_name}"))
+                }
+                (None, _) => Ok(()),
+            }
+        }
+        Opcode::Ret => {
+            let func_name = get_func_name(instr_view);
+            let ret_ty: Option<Type> = { instr_view.func_ret_ty }.into();
+            match (args.first(), ret_ty) {
+                (Some(arg), None) => Err(type_error(format!(
+                    "`ret {arg}` returns a value, but @{func_name} has no \
+                    return type"
+                ))),
+                (None, Some(ret_ty)) => Err(type_error(format!(
+                    "`ret` has no value, but @{func_name} returns {}",
+                    with_article(ret_ty)
+                ))),
+                (Some(arg), Some(ret_ty)) => match arg_type(arg) {
+                    Some(actual) if actual != ret_ty => {
+                        Err(type_error(format!(
+                            "`{arg}` is {}, but @{func_name} returns {}",
+                            with_article(actual),
+                            with_article(ret_ty)
+                        )))
+                    }
+                    _ => Ok(()),
+                },
+                (None, None) => Ok(()),
+            }
+        }
+        // Every other opcode is checked against its row in `OP_SPECS`
+        _ => {
+            let spec = op.spec();
+            if let Some(operand_ty) = spec.operand_ty {
+                expect_operands(operand_ty)?;
+            }
+            match spec.result_ty {
+                Some(result_ty) => expect_dest(result_ty, &op_name),
+                None => Ok(()),
+            }
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod typecheck_tests {
+    use serde_json::{Value, json};
+
+    use crate::diagnostic::{Diagnostic, ErrorCode};
+    use crate::interp::{InterpContext, Limits, interp_program};
+    use crate::memfile::{AlignedBytes, get_flat_program, json_to_fbril_bytes};
+
+    /// Runs the `functions` (with & without type checks), returning the
+    /// diagnostics produced by each run
+    fn run_checked(
+        functions: Value,
+    ) -> (Result<(), Diagnostic>, Result<(), Diagnostic>) {
+        let bytes = json_to_fbril_bytes(&json!({ "functions": functions }));
+        let data = AlignedBytes::new(&bytes);
+        let program = get_flat_program(&data).expect("well-formed program");
+        let run = |check_types: bool| {
+            let mut ctx = InterpContext::new(vec![], Limits::default());
+            ctx.check_types = check_types;
+            interp_program(&program, vec![], &mut ctx)
+        };
+        (run(false), run(true))
+    }
+
+    /// Mismatched dest types, which the interpreter doesn't notice by
+    /// itself, are reported with the variable & the instruction's location
+    #[test]
+    fn test_check_types() {
+        let main =
+            |instrs: Value| json!([{ "name": "main", "instrs": instrs }]);
+        let (unchecked, checked) = run_checked(main(json!([
+            { "op": "const", "dest": "a", "type": "int", "value": 1 },
+            { "op": "add", "dest": "b", "type": "bool", "args": ["a", "a"] },
+        ])));
+        assert_eq!(unchecked, Ok(()));
+        let diagnostic = checked.unwrap_err();
+        assert_eq!(diagnostic.code, ErrorCode::TypeError);
+        assert_eq!(
+            diagnostic.to_string(),
+            "`b` is declared as bool, but `add` produces an int (at @main:1)"
+        );
+
+        let (unchecked, checked) = run_checked(main(json!([
+            { "op": "const", "dest": "a", "type": "int", "value": 1 },
+            { "op": "const", "dest": "t", "type": "bool", "value": true },
+            { "op": "add", "dest": "b", "type": "int", "args": ["a", "t"] },
+        ])));
+        assert!(unchecked.is_err());
+        assert_eq!(
+            checked.unwrap_err().message,
+            "`add` expects int operands, but `t` is a bool"
+        );
+
+        let (unchecked, checked) = run_checked(json!([
+            { "name": "main", "instrs": [
+                { "op": "call", "dest": "x", "type": "bool",
+                  "funcs": ["f"] },
+            ] },
+            { "name": "f", "type": "int", "instrs": [
+                { "op": "const", "dest": "r", "type": "int", "value": 7 },
+                { "op": "ret", "args": ["r"] },
+            ] },
+        ]));
+        assert_eq!(unchecked, Ok(()));
+        assert_eq!(
+            checked.unwrap_err().message,
+            "`x` is declared as bool, but @f produces an int"
+        );
+    }
+}
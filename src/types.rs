@@ -2,11 +2,16 @@
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::io;
+use std::ops::{Range, RangeInclusive};
 use strum_macros::EnumIter;
 use zerocopy::{
     FromBytes, FromZeros, Immutable, IntoBytes, KnownLayout, TryFromBytes,
 };
 
+#[cfg(feature = "ext-values")]
+use crate::ext_value::ExternRef;
+
 /* -------------------------------------------------------------------------- */
 /*                                    Types                                   */
 /* -------------------------------------------------------------------------- */
@@ -18,45 +23,182 @@ use zerocopy::{
 /// - We can store the actual `type` and `value` inline in the `Instr` struct
 ///   (since they're either an int or a bool,
 ///   i.e. they don't need to be heap-allocated)
-/// - `dest` stores the start & end indices (inclusive) of the byte representation
+/// - `dest` stores the offset & length of the byte representation
 ///   of the string in the `all_vars` byte vector (see `flatten.rs`)
-/// - `args` and `labels` contains the start & end indices (inclusive)
-///   in their index vectors (see `all_args_idxes` & `all_labels_idxes` in `flatten.rs`)
+/// - `args` and `labels` contains the offset & length of the instruction's
+///   entries in their index vectors (see `all_args_idxes` &
+///   `all_labels_idxes` in `flatten.rs`), so an empty list is `Some((_, 0))`
 /// - For `args` and `labels` we have 2 layers of indirection since
 ///   an instruction can have multiple args/labels, so
-///   `(start, end) = instr.arg ==> all_args_idxes[start..=end] ==> all_vars[...]`
-/// - (Well-formedness condition: we must have end_idx >= start_idx always)
-#[derive(Debug, PartialEq, Clone)]
+///   `(offset, len) = instr.arg ==> all_args_idxes[offset..offset + len]
+///   ==> all_vars[...]`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Instr {
     pub op: u32,
-    pub label: Option<(u32, u32)>,
-    pub dest: Option<(u32, u32)>,
+    pub label: Option<(usize, usize)>,
+    pub dest: Option<(usize, usize)>,
     pub ty: Option<Type>,
     pub value: Option<BrilValue>,
-    pub args: Option<(u32, u32)>,
-    pub instr_labels: Option<(u32, u32)>,
-    pub funcs: Option<(u32, u32)>,
+    pub args: Option<(usize, usize)>,
+    pub instr_labels: Option<(usize, usize)>,
+    pub funcs: Option<(usize, usize)>,
 }
 
 /// Struct representation of the pair `(i32, i32)`
 /// (we need this b/c `zerocopy` doesn't work for tuples)
 #[repr(C)]
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, FromBytes)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromBytes,
+    Serialize,
+    Deserialize,
+)]
 pub struct I32Pair {
     pub first: i32,
     pub second: i32,
 }
 
+/// Struct representation of the pair `(i16, i16)`, which is used instead of
+/// `I32Pair` in compact-format files (see `COMPACT_FORMAT_FLAG`)
+#[repr(C)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromBytes,
+    Serialize,
+    Deserialize,
+)]
+pub struct I16Pair {
+    pub first: i16,
+    pub second: i16,
+}
+
+/// Struct representation of the pair `(i64, i64)`, which is used instead of
+/// `I32Pair` in wide-format files (see `WIDE_FORMAT_FLAG`)
+/// - This is packed since sections are only padded to 4 bytes
+#[repr(C, packed)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromBytes,
+    Serialize,
+    Deserialize,
+)]
+pub struct I64Pair {
+    pub first: i64,
+    pub second: i64,
+}
+
+/// The offset & length of a (half-open) range of a store, as stored in a
+/// flat instruction, where `(-1, -1)` represents `None`
+/// - The width of the indexes depends on the format of the `.fbril` file:
+///   `I32Pair`s are used by default, `I64Pair`s are used by the wide
+///   format (for functions whose stores are too big for `i32` indexes),
+///   and `I16Pair`s are used by the compact format (for small programs)
+pub trait IndexPair:
+    fmt::Debug + PartialEq + Copy + IntoBytes + FromBytes + Immutable + 'static
+{
+    /// The `Header::flags` of files which use this type of index pair
+    const FORMAT_FLAGS: u64;
+
+    /// The type of the `op` field of a `FlatInstr` in files which use this
+    /// type of index pair
+    type Op: OpWord;
+
+    /// The largest index that can be stored in this type of index pair
+    const MAX_IDX: usize;
+
+    /// Converts `Some((offset, len))` / `None` to an index pair
+    /// (`offset` & `len` must be at most `MAX_IDX`)
+    fn from_idxes(idxes: Option<(usize, usize)>) -> Self;
+
+    /// The raw (signed) contents of the index pair
+    fn fields(self) -> (i64, i64);
+
+    /// Converts an index pair back to `Some((offset, len))`
+    /// (or `None` if both fields are -1)
+    fn get(self) -> Option<(usize, usize)> {
+        match self.fields() {
+            (-1, -1) => None,
+            (first, second) => Some((first as usize, second as usize)),
+        }
+    }
+
+    /// The `(offset, len)` of an index pair that is known to be present
+    /// (for `None`, these are out of bounds for every store)
+    fn idxes(self) -> (usize, usize) {
+        let (first, second) = self.fields();
+        (first as usize, second as usize)
+    }
+
+    /// The range of the store that an index pair which is known to be
+    /// present refers to
+    fn range(self) -> Range<usize> {
+        let (offset, len) = self.idxes();
+        offset..offset.wrapping_add(len)
+    }
+}
+
+/// The opcode stored in a `FlatInstr`, which is a `u32` unless the file is in
+/// the compact format, where it's a `u16` (in which case the opcodes of
+/// labels & extension instructions are `u16::MAX` & `u16::MAX - 1`)
+pub trait OpWord:
+    fmt::Debug
+    + PartialEq
+    + Copy
+    + IntoBytes
+    + FromBytes
+    + Immutable
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + 'static
+{
+    /// Converts an opcode (as stored in an `Instr`) to this type
+    fn from_u32(op: u32) -> Self;
+
+    /// Converts this opcode back to the `u32` stored in an `Instr`
+    fn to_u32(self) -> u32;
+}
+
 /// Flattened representation of an instruction, amenable to `zerocopy`
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, TryFromBytes)]
+/// (`P` is the type of the index pairs, see `IndexPair`, and also determines
+/// the width of the opcode, see `IndexPair::Op`)
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    TryFromBytes,
+    Serialize,
+    Deserialize,
+)]
 #[repr(packed)]
-pub struct FlatInstr {
-    pub op: u32,
-    pub label: I32Pair,
-    pub dest: I32Pair,
-    pub args: I32Pair,
-    pub instr_labels: I32Pair,
-    pub funcs: I32Pair,
+#[serde(bound(
+    serialize = "P: Serialize + Copy",
+    deserialize = "P: Deserialize<'de>"
+))]
+pub struct FlatInstr<P: IndexPair = I32Pair> {
+    pub op: P::Op,
+    pub label: P,
+    pub dest: P,
+    pub args: P,
+    pub instr_labels: P,
+    pub funcs: P,
     pub ty: FlatType,
     pub value: FlatBrilValue,
 }
@@ -69,45 +211,93 @@ pub enum InstrKind {
     ValueOp,
     EffectOp,
     Nop,
+    /// An instruction whose opcode isn't in core Bril (see `EXT_OP`)
+    Extension,
 }
 
-/// Primitive types in core Bril are either `int` or `bool`
-#[repr(C)]
-#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
-#[serde(rename_all = "lowercase")]
+/// Primitive types in core Bril are either `int` or `bool`, and the memory
+/// extension adds pointers to them, which may be nested
+/// (e.g. `ptr<ptr<int>>` is `Ptr { pointee: BaseType::Int, depth: 2 }`)
+/// - In JSON, pointer types are written as `{"ptr": <type>}`
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "JsonType", into = "JsonType")]
 pub enum Type {
-    Int = 0,
-    Bool = 1,
+    Int,
+    Bool,
+    /// `depth` (at least 1) nested `ptr<..>`s around `pointee`
+    Ptr {
+        pointee: BaseType,
+        depth: u32,
+    },
+    /// A value of an extension's type (see `ext_value::ExternRef`)
+    #[cfg(feature = "ext-values")]
+    Extern,
+}
+
+/// The types that a (possibly nested) pointer type can ultimately point to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BaseType {
+    Int,
+    Bool,
+}
+
+/// The JSON representation of a `Type` (which `serde` converts to & from)
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JsonType {
+    Int,
+    Bool,
+    Ptr(Box<JsonType>),
+    #[cfg(feature = "ext-values")]
+    Extern,
 }
 
-#[repr(usize)]
+/// Flattened representation of an optional `Type`: the low byte is
+/// `FlatType::INT`, `FlatType::BOOL` or `FlatType::NULL` (no type),
+/// and the remaining bytes are the pointer depth (see `PTR_DEPTH_SHIFT`)
+/// - Since any `usize` can be read as a `FlatType`, files are checked
+///   for invalid types when they're loaded (see `FlatType::is_valid`)
+#[repr(transparent)]
 #[derive(
     Debug,
     PartialEq,
+    Eq,
     Clone,
     Copy,
+    Serialize,
     Deserialize,
     IntoBytes,
-    FromZeros,
+    FromBytes,
     Immutable,
     KnownLayout,
 )]
-#[serde(rename_all = "lowercase")]
-pub enum FlatType {
-    Int = 0,
-    Bool = 1,
-    Null = 2,
-}
+pub struct FlatType(usize);
 
 /// The type of primitive values in Bril.    
 /// - Note: We call this enum `BrilValue` to avoid namespace clashes
 ///   with `serde_json::Value`
 /// - `SurrogateBool` is needed for padding reasons (to make zerocopy happy)
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 #[repr(u64)]
 pub enum BrilValue {
     IntVal(i64),
     BoolVal(SurrogateBool),
+    #[cfg(feature = "ext-values")]
+    Extern(ExternRef),
+}
+
+impl FlatType {
+    pub const INT: FlatType = FlatType(0);
+    pub const BOOL: FlatType = FlatType(1);
+    pub const NULL: FlatType = FlatType(2);
+    #[cfg(feature = "ext-values")]
+    pub const EXTERN: FlatType = FlatType(3);
+
+    /// Checks whether this is the flattened representation of a `Type`
+    /// (or of no type at all)
+    pub fn is_valid(self) -> bool {
+        self == FlatType::NULL || Type::try_from(self).is_ok()
+    }
 }
 
 impl BrilValue {
@@ -116,11 +306,23 @@ impl BrilValue {
         match self {
             BrilValue::IntVal(_) => Type::Int,
             BrilValue::BoolVal(_) => Type::Bool,
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(_) => Type::Extern,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, FromZeros)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromZeros,
+    Serialize,
+    Deserialize,
+)]
 #[repr(u64)]
 pub enum FlatBrilValue {
     IntVal(i64),
@@ -129,18 +331,40 @@ pub enum FlatBrilValue {
 }
 
 /// A null which is represented as a u64 to make zerocopy happy
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, FromBytes)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromBytes,
+    Serialize,
+    Deserialize,
+)]
 pub struct SurrogateNull(u64);
 
 /// A type isomorphic to `bool`, which is represented as a u64
 /// (so that it has the same representation as `BrilValue::IntVal`'s)
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, FromBytes)]
+/// - This is serialized as a `bool`
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    FromBytes,
+    Serialize,
+    Deserialize,
+)]
+#[serde(from = "bool", into = "bool")]
 pub struct SurrogateBool(u64);
 
 impl Instr {
     /// Represents a label as an `Instr` where
     /// all other fields of the struct are none
-    pub fn make_label(label_idxes: (u32, u32)) -> Self {
+    pub fn make_label(label_idxes: (usize, usize)) -> Self {
         Self {
             op: u32::MAX,
             label: Some(label_idxes),
@@ -153,57 +377,57 @@ impl Instr {
         }
     }
 
+    /// Creates an extension instruction, whose JSON is stored at
+    /// `json_idxes` in `ext_store` (see `EXT_OP`)
+    pub fn make_extension(json_idxes: (usize, usize)) -> Self {
+        Self {
+            op: EXT_OP,
+            ..Self::make_label(json_idxes)
+        }
+    }
+
     /// Retrieves the kind of an instruction (`Nop, Const, EffectOp, ValueOp`)
     pub fn get_instr_kind(&self) -> InstrKind {
-        use Opcode::*;
-        let possible_op = Opcode::u32_to_opcode(self.op);
-        if let Some(op) = possible_op {
-            match op {
-                Nop => InstrKind::Nop,
-                Const => InstrKind::Const,
-                Print | Jmp | Br | Ret => InstrKind::EffectOp,
-                Call => {
-                    // Function calls can be both value op and effect op
-                    // depending on whether the `dest` field of the instr
-                    // is present
-                    if let Some((_, _)) = self.dest {
-                        InstrKind::ValueOp
-                    } else {
-                        InstrKind::EffectOp
-                    }
-                }
-                _ => InstrKind::ValueOp,
-            }
-        } else {
-            InstrKind::Label
-        }
+        InstrKind::of(self.op, self.dest.is_some())
     }
 }
 
-impl FlatInstr {
+impl<P: IndexPair> FlatInstr<P> {
+    /// The instruction's opcode (as stored in an `Instr`, whatever the
+    /// width of the `op` field)
+    pub fn op(&self) -> u32 {
+        self.op.to_u32()
+    }
+
+    /// Sets the instruction's opcode (see `op`)
+    pub fn set_op(&mut self, op: u32) {
+        self.op = P::Op::from_u32(op);
+    }
+
     /// Retrieves the kind of an instruction (`Nop, Const, EffectOp, ValueOp`)
     pub fn get_instr_kind(&self) -> InstrKind {
-        use Opcode::*;
-        let possible_op = Opcode::u32_to_opcode(self.op);
-        if let Some(op) = possible_op {
-            match op {
-                Nop => InstrKind::Nop,
-                Const => InstrKind::Const,
-                Print | Jmp | Br | Ret => InstrKind::EffectOp,
-                Call => {
-                    // Function calls can be both value op and effect op
-                    // depending on whether the `dest` field of the instr
-                    // is present
-                    if self.dest.first == -1 && self.dest.second == -1 {
-                        InstrKind::EffectOp
-                    } else {
-                        InstrKind::ValueOp
-                    }
-                }
-                _ => InstrKind::ValueOp,
-            }
-        } else {
-            InstrKind::Label
+        InstrKind::of(self.op(), self.dest.get().is_some())
+    }
+}
+
+impl InstrKind {
+    /// The kind of an instruction whose `op` field is `op`, and which has
+    /// a dest iff `has_dest` (see `OpSpec::role`)
+    fn of(op: u32, has_dest: bool) -> Self {
+        match Opcode::u32_to_opcode(op) {
+            Some(op) => match op.spec().role {
+                OpRole::Nop => InstrKind::Nop,
+                OpRole::Const => InstrKind::Const,
+                OpRole::Value => InstrKind::ValueOp,
+                OpRole::Effect => InstrKind::EffectOp,
+                // Function calls can be both value op and effect op
+                // depending on whether the `dest` field of the instr
+                // is present
+                OpRole::ValueOrEffect if has_dest => InstrKind::ValueOp,
+                OpRole::ValueOrEffect => InstrKind::EffectOp,
+            },
+            None if op == EXT_OP => InstrKind::Extension,
+            None => InstrKind::Label,
         }
     }
 }
@@ -251,32 +475,54 @@ pub enum Opcode {
     Print = 17,
     Nop = 18,
     Const = 19,
+
+    // SSA extension (produced by `ssa::to_ssa`)
+    Phi = 20,
+
+    // Bitwise extension (on ints: `shl` & `shr` only use the lowest 6 bits
+    // of the shift amount, and `shr` is an arithmetic shift)
+    #[cfg(feature = "bitwise")]
+    Band = 21,
+    #[cfg(feature = "bitwise")]
+    Bor = 22,
+    #[cfg(feature = "bitwise")]
+    Bxor = 23,
+    #[cfg(feature = "bitwise")]
+    Shl = 24,
+    #[cfg(feature = "bitwise")]
+    Shr = 25,
 }
 
 impl Opcode {
+    /// The specification of this opcode (its row in `OP_SPECS`)
+    #[inline]
+    pub fn spec(self) -> &'static OpSpec {
+        &OP_SPECS[self as usize]
+    }
+
     /// Determines if an opcode is a binary (value) operation
+    #[inline]
     pub fn is_binop(self) -> bool {
-        !matches!(
-            self,
-            Opcode::Not
-                | Opcode::Jmp
-                | Opcode::Br
-                | Opcode::Call
-                | Opcode::Ret
-                | Opcode::Id
-                | Opcode::Print
-                | Opcode::Nop
-                | Opcode::Const
-        )
+        let spec = self.spec();
+        spec.role == OpRole::Value && spec.operand_counts[0] == (2..=2)
     }
 
     /// Determines if an opcode is a unary (value) operation (i.e. `not`, `id`)
+    #[inline]
     pub fn is_unop(self) -> bool {
-        matches!(self, Opcode::Not | Opcode::Id)
+        let spec = self.spec();
+        spec.role == OpRole::Value && spec.operand_counts[0] == (1..=1)
+    }
+
+    /// The number of args, labels & funcs (in that order) that an
+    /// instruction with this opcode can have (`phi`s additionally need
+    /// exactly one arg per label)
+    pub fn operand_counts(self) -> [RangeInclusive<usize>; 3] {
+        self.spec().operand_counts.clone()
     }
 
     /// Converts a `u32` value to the corresponding `Opcode`
-    /// - Panics if the `u32` value can't be converted
+    /// (returns `None` if the `u32` value doesn't correspond to any opcode)
     pub fn u32_to_opcode(v: u32) -> Option<Self> {
         num_traits::FromPrimitive::from_u32(v)
     }
@@ -313,24 +559,209 @@ impl Opcode {
     }
 }
 
+/// Fallible conversion from the `u32` opcodes stored in `FlatInstr`s
+/// (which may come from an untrusted `.fbril` file)
+impl TryFrom<u32> for Opcode {
+    type Error = String;
+
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
+        Opcode::u32_to_opcode(v).ok_or_else(|| format!("invalid opcode {v}"))
+    }
+}
+
+/// Whether an instruction with some opcode produces a value or performs an
+/// effect (see `InstrKind`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpRole {
+    Nop,
+    Const,
+    /// Produces a value, which is stored in its dest
+    Value,
+    /// Only performs an effect (it has no dest)
+    Effect,
+    /// Produces a value if it has a dest, & only performs an effect
+    /// otherwise (i.e. `call`)
+    ValueOrEffect,
+}
+
+/// The specification of an opcode, which the flattener, validator, type
+/// checker & interpreter all consult instead of matching on opcodes
+/// - `operand_counts` is the number of args, labels & funcs (in that order)
+///   that an instruction with this opcode can have
+/// - `operand_ty` is the type that all of its args must have (`None` if
+///   they can have any type, or are checked against a function signature)
+/// - `result_ty` is the type of the value it produces (`None` if it doesn't
+///   produce one, or if the type depends on its operands / callee)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpSpec {
+    pub operand_counts: [RangeInclusive<usize>; 3],
+    pub operand_ty: Option<Type>,
+    pub result_ty: Option<Type>,
+    pub role: OpRole,
+}
+
+impl OpSpec {
+    /// A value operation taking two args of type `operand_ty`
+    const fn binop(operand_ty: Type, result_ty: Type) -> Self {
+        OpSpec {
+            operand_counts: [2..=2, 0..=0, 0..=0],
+            operand_ty: Some(operand_ty),
+            result_ty: Some(result_ty),
+            role: OpRole::Value,
+        }
+    }
+
+    /// An operation whose operands aren't typed by its opcode alone
+    const fn untyped(
+        operand_counts: [RangeInclusive<usize>; 3],
+        role: OpRole,
+    ) -> Self {
+        OpSpec {
+            operand_counts,
+            operand_ty: None,
+            result_ty: None,
+            role,
+        }
+    }
+}
+
+/// The specification of every opcode, indexed by opcode (so adding an
+/// opcode only requires adding its row here)
+/// - `phi`s additionally need exactly one arg per label
+static OP_SPECS: [OpSpec; NUM_OPCODES] = {
+    use OpRole::*;
+    use Type::{Bool, Int};
+    const ANY: usize = usize::MAX;
+    [
+        OpSpec::binop(Int, Int),  // add
+        OpSpec::binop(Int, Int),  // mul
+        OpSpec::binop(Int, Int),  // sub
+        OpSpec::binop(Int, Int),  // div
+        OpSpec::binop(Int, Bool), // eq
+        OpSpec::binop(Int, Bool), // lt
+        OpSpec::binop(Int, Bool), // gt
+        OpSpec::binop(Int, Bool), // le
+        OpSpec::binop(Int, Bool), // ge
+        // not
+        OpSpec {
+            operand_counts: [1..=1, 0..=0, 0..=0],
+            operand_ty: Some(Bool),
+            result_ty: Some(Bool),
+            role: Value,
+        },
+        OpSpec::binop(Bool, Bool), // and
+        OpSpec::binop(Bool, Bool), // or
+        OpSpec::untyped([0..=0, 1..=1, 0..=0], Effect), // jmp
+        // br (whose arg is the condition)
+        OpSpec {
+            operand_counts: [1..=1, 2..=2, 0..=0],
+            operand_ty: Some(Bool),
+            result_ty: None,
+            role: Effect,
+        },
+        OpSpec::untyped([0..=ANY, 0..=0, 1..=1], ValueOrEffect), // call
+        OpSpec::untyped([0..=1, 0..=0, 0..=0], Effect),          // ret
+        OpSpec::untyped([1..=1, 0..=0, 0..=0], Value),           // id
+        OpSpec::untyped([0..=ANY, 0..=0, 0..=0], Effect),        // print
+        OpSpec::untyped([0..=0, 0..=0, 0..=0], Nop),             // nop
+        OpSpec::untyped([0..=0, 0..=0, 0..=0], Const),           // const
+        OpSpec::untyped([0..=ANY, 0..=ANY, 0..=0], Value),       // phi
+        #[cfg(feature = "bitwise")]
+        OpSpec::binop(Int, Int), // band
+        #[cfg(feature = "bitwise")]
+        OpSpec::binop(Int, Int), // bor
+        #[cfg(feature = "bitwise")]
+        OpSpec::binop(Int, Int), // bxor
+        #[cfg(feature = "bitwise")]
+        OpSpec::binop(Int, Int), // shl
+        #[cfg(feature = "bitwise")]
+        OpSpec::binop(Int, Int), // shr
+    ]
+};
+
 /// Struct representing the two components of an argument to a Bril function:
-/// - The argument name, represented by the start & end indexes in the
+/// - The argument name, represented by its offset & length in the
 ///   `var_store` vector of `InstrStore`
 /// - The type of the argument
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub struct FuncArg {
-    pub arg_name_idxes: (u32, u32),
+    pub arg_name_idxes: (usize, usize),
     pub arg_type: Type,
 }
 
 /// Flat version of a `FuncArg`
 #[repr(packed)]
-#[derive(Debug, PartialEq, Clone, Copy, IntoBytes, Immutable, TryFromBytes)]
-pub struct FlatFuncArg {
-    pub arg_name_idxes: I32Pair,
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    TryFromBytes,
+    Serialize,
+    Deserialize,
+)]
+#[serde(bound(
+    serialize = "P: Serialize + Copy",
+    deserialize = "P: Deserialize<'de>"
+))]
+pub struct FlatFuncArg<P = I32Pair> {
+    pub arg_name_idxes: P,
     pub arg_type: FlatType,
 }
 
+/// Entry in a function's label table: `label_idxes` is the index pair of the
+/// label's name in `labels_store`, and `pc` is the index (in `instrs`) of the
+/// first instruction after the label (`instrs.len()` for a trailing label)
+#[repr(packed)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    TryFromBytes,
+    Serialize,
+    Deserialize,
+)]
+#[serde(bound(
+    serialize = "P: Serialize + Copy",
+    deserialize = "P: Deserialize<'de>"
+))]
+pub struct FlatLabel<P = I32Pair> {
+    pub label_idxes: P,
+    pub pc: usize,
+}
+
+/// Entry in the `resolved` section of a file with `RESOLVED_FLAG`, which
+/// holds the operands of the instruction at the same position in `instrs`,
+/// resolved when the file was written (so that they don't have to be looked
+/// up by name when it's run)
+/// - `targets` are the pcs (relative to the start of the function) of a
+///   `jmp`'s target (`targets[0]`), or of a `br`'s true & false targets
+/// - `callee` is the index in the function table of the function that a
+///   `call` calls
+/// - Operands which the instruction doesn't have are `UNRESOLVED`
+#[repr(C)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    FromBytes,
+    IntoBytes,
+    Immutable,
+    KnownLayout,
+    Serialize,
+    Deserialize,
+)]
+pub struct ResolvedOperands {
+    pub targets: [u32; 2],
+    pub callee: u32,
+}
+
 /// Struct that stores all the instrs and the args/dest/labels/funcs arrays
 /// in the same place (note: we create one `InstrStore` per Bril function)
 /// - The `func_name` field stores the name of the Bril function
@@ -342,36 +773,68 @@ pub struct FlatFuncArg {
 /// - args_idxes_stores |-> var_store
 /// - labels_idxes_store |-> labels_store
 /// - there's only one function so `funcs_store` can just be Vec<u8>
+/// - `ext_store` holds the JSON of each extension instruction (see `EXT_OP`)
 /// - `instrs_and_labels` is a vector containing the instructions/labels in
 ///   the order they appear in the source Bril file
-#[derive(Debug, Clone, PartialEq)]
+/// - `num_slots`, `dest_slots` & `arg_slots` number the function's variables
+///   into dense slots (see `slots.rs`): `dest_slots[pc]` is the slot of
+///   `instrs[pc]`'s dest (`NO_SLOT` if it has none), `arg_slots[i]` is the
+///   slot of the arg at `args_idxes_store[i]`, and the `i`th parameter is
+///   always in slot `i`
+/// - This (along with the flat types) implements serde's traits, so
+///   flattened functions can also be stored in other formats (e.g. CBOR),
+///   or dumped as JSON for debugging
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct InstrStore {
     pub func_name: Vec<u8>,
     pub func_args: Vec<FuncArg>,
     pub func_ret_ty: Option<Type>,
     pub var_store: Vec<u8>,
-    pub args_idxes_store: Vec<(u32, u32)>,
-    pub labels_idxes_store: Vec<(u32, u32)>,
+    pub args_idxes_store: Vec<(usize, usize)>,
+    pub labels_idxes_store: Vec<(usize, usize)>,
     pub labels_store: Vec<u8>,
     pub funcs_store: Vec<u8>,
     pub instrs: Vec<Instr>,
+    pub num_slots: usize,
+    pub dest_slots: Vec<u32>,
+    pub arg_slots: Vec<u32>,
+    #[serde(default)]
+    pub ext_store: Vec<u8>,
 }
 
 /// `InstrView` is the same as `InstrStore`:
-/// all the slices in `InstrView` are references to the `Vec`s in `InstrStore`
-/// All [u8]s are padded to 4 bytes
+/// all the slices in `InstrView` are references to the `Vec`s in `InstrStore`,
+/// except that labels aren't stored in `instrs`: they're in `label_table`
+/// (sorted by pc), so `instrs`, `dest_slots` & the pcs only count real
+/// instructions
+/// (`P` is the type of the index pairs, see `IndexPair`)
+/// - In a flat Bril file, the stores (`var_store`, `arg_idxes_store`, ...) are
+///   shared by every function, so a function's `InstrView` refers to the
+///   whole of each store, while `func_args`, `instrs`, `label_table` &
+///   `dest_slots` are just the function's part of the corresponding
+///   sections (see `FuncRecord`). `var_store`, `labels_store` &
+///   `funcs_store` are all the file's string table (see `Toc::strings`).
+/// - `resolved` is the function's part of the `resolved` section of a file
+///   with `RESOLVED_FLAG` (one entry per instruction), and is empty if the
+///   file doesn't have one
 #[repr(packed)]
 #[derive(Debug, PartialEq, Clone, Immutable, IntoBytes)]
-pub struct InstrView<'a> {
+pub struct InstrView<'a, P: IndexPair = I32Pair> {
     pub func_name: &'a [u8],
-    pub func_args: &'a [FlatFuncArg],
+    pub func_args: &'a [FlatFuncArg<P>],
     pub func_ret_ty: FlatType,
     pub var_store: &'a [u8],
-    pub arg_idxes_store: &'a [I32Pair],
-    pub labels_idxes_store: &'a [I32Pair],
+    pub arg_idxes_store: &'a [P],
+    pub labels_idxes_store: &'a [P],
     pub labels_store: &'a [u8],
     pub funcs_store: &'a [u8],
-    pub instrs: &'a [FlatInstr],
+    pub instrs: &'a [FlatInstr<P>],
+    pub label_table: &'a [FlatLabel<P>],
+    pub num_slots: usize,
+    pub dest_slots: &'a [u32],
+    pub arg_slots: &'a [u32],
+    pub ext_store: &'a [u8],
+    pub resolved: &'a [ResolvedOperands],
 }
 
 #[repr(packed)]
@@ -380,87 +843,191 @@ pub struct InstrViewFamily<'a> {
     pub instr_views: &'a [InstrView<'a>],
 }
 
-/// Top-level metadata in the mmap-ed file, appears before all the `Toc`/`InstrView`s
-/// The `sizes` fields contains a list of sizes (no. of bytes) for each
-/// of the functions in the Bril program.
-#[derive(FromBytes, IntoBytes, Debug, Clone, Copy, Immutable, KnownLayout)]
+/// Top-level metadata in the mmap-ed file, appears before the `Toc`
+/// The `flags` field describes the format of the file (see `WIDE_FORMAT_FLAG`),
+/// and its upper 32 bits are the version of the layout (see `FORMAT_VERSION`).
+/// The `num_funcs` field is the no. of functions in the Bril program,
+/// i.e. the no. of `FuncRecord`s in the function table.
+#[derive(
+    FromBytes,
+    IntoBytes,
+    Debug,
+    Clone,
+    Copy,
+    Immutable,
+    KnownLayout,
+    Serialize,
+    Deserialize,
+)]
 #[repr(C)]
 pub struct Header {
-    // TODO: change this in the future? right now we only allow at most 10 functions
-    pub sizes: [u64; 10],
+    pub flags: u64,
+    pub num_funcs: u64,
 }
 
 /// Table of contents for the flat Bril file
-/// (each field stores the no. of elements in the corresponding slice
-/// in the `InstrView`)
-#[derive(FromBytes, IntoBytes, Debug, Clone, Copy, Immutable, KnownLayout)]
+/// (each field stores the no. of elements in the corresponding section,
+/// which is shared by all the functions in the program)
+/// - `strings` is the program's string table, in which every distinct
+///   function name, variable, label & callee is interned once (so that
+///   e.g. `i` or `main` is stored once, however many functions use it):
+///   the function names (see `FuncRecord::name`) & every index pair which
+///   refers to the `var_store`, `labels_store` or `funcs_store` of an
+///   `InstrView` index into it
+/// - The other sections are the same as the fields of an `InstrView`,
+///   except that they contain the data of every function
+#[derive(
+    FromBytes,
+    IntoBytes,
+    Debug,
+    Clone,
+    Copy,
+    Immutable,
+    KnownLayout,
+    Serialize,
+    Deserialize,
+)]
 #[repr(packed)]
 pub struct Toc {
-    pub func_name: usize,
+    pub strings: usize,
     pub func_args: usize,
-    pub func_ret_ty: usize,
-    pub var_store: usize,
     pub arg_idxes_store: usize,
     pub labels_idxes_store: usize,
-    pub labels_store: usize,
-    pub funcs_store: usize,
     pub instrs: usize,
+    pub label_table: usize,
+    pub dest_slots: usize,
+    pub arg_slots: usize,
+    pub ext_store: usize,
 }
 
-impl InstrView<'_> {
-    /// Returns a `Toc` containing the no. of elements of each field
-    /// in the `InstrView` struct
-    pub fn get_sizes(&self) -> Toc {
-        let func_name = self.func_name.len();
-        let func_args = self.func_args.len();
-        let func_ret_ty = 1; // Each function only has one return type
-        let var_store = self.var_store.len();
-        let arg_idxes_store = self.arg_idxes_store.len();
-        let labels_idxes_store = self.labels_idxes_store.len();
-        let labels_store = self.labels_store.len();
-        let funcs_store = self.funcs_store.len();
-        let instrs = self.instrs.len();
-
-        Toc {
-            func_name,
-            func_args,
-            func_ret_ty,
-            var_store,
-            arg_idxes_store,
-            labels_idxes_store,
-            labels_store,
-            funcs_store,
-            instrs,
+/// Entry in the function table of a flat Bril file, which comes right after
+/// the `Toc` (one per function, in the order they appear in the program)
+/// - `name` is the index pair of the function's name in `strings`
+/// - The function's instructions are `instrs[start_pc..end_pc]`
+///   (& its dest slots are `dest_slots[start_pc..end_pc]`)
+/// - Its parameters are the `num_args` entries of `func_args` starting at
+///   `first_arg`, and `ret_ty` is its return type
+/// - Its labels are the `num_labels` entries of `label_table` starting at
+///   `first_label`, whose pcs are relative to `start_pc`
+/// - All the other index pairs in the function refer directly to the
+///   program's (shared) stores & string table
+#[repr(packed)]
+#[derive(
+    Debug,
+    PartialEq,
+    Clone,
+    Copy,
+    IntoBytes,
+    Immutable,
+    TryFromBytes,
+    Serialize,
+    Deserialize,
+)]
+#[serde(bound(
+    serialize = "P: Serialize + Copy",
+    deserialize = "P: Deserialize<'de>"
+))]
+pub struct FuncRecord<P = I32Pair> {
+    pub name: P,
+    pub start_pc: usize,
+    pub end_pc: usize,
+    pub first_arg: usize,
+    pub num_args: usize,
+    pub ret_ty: FlatType,
+    pub num_slots: usize,
+    pub first_label: usize,
+    pub num_labels: usize,
+}
+
+impl Header {
+    /// Creates a header for a file in the current layout (`FORMAT_VERSION`)
+    /// with the given flags & no. of functions
+    pub fn new(flags: u64, num_funcs: u64) -> Self {
+        Self {
+            flags: FORMAT_VERSION << VERSION_SHIFT | flags,
+            num_funcs,
         }
     }
 
-    /// Computes the total no. of bytes occupied by the Toc +
-    /// the contents of the `InstrView`
-    /// NOTE: This function is broken! don't use it for now
-    pub fn total_size_in_bytes(&self) -> u64 {
-        let toc_num_bytes = size_of::<Toc>();
-        let func_name_num_bytes = std::mem::size_of_val(self.func_name);
-        let func_args_num_bytes = std::mem::size_of_val(self.func_args);
-        let func_ret_ty_num_bytes = size_of::<FlatType>();
-        let var_store_num_bytes = std::mem::size_of_val(self.var_store);
-        let arg_idxes_store_num_bytes =
-            std::mem::size_of_val(self.arg_idxes_store);
-        let labels_idxes_store_num_bytes =
-            std::mem::size_of_val(self.labels_idxes_store);
-        let labels_store_num_bytes = std::mem::size_of_val(self.labels_store);
-        let funcs_store_num_bytes = std::mem::size_of_val(self.funcs_store);
-        let instrs_num_bytes = std::mem::size_of_val(self.instrs);
-
-        (toc_num_bytes
-            + func_name_num_bytes
-            + func_args_num_bytes
-            + func_ret_ty_num_bytes
-            + var_store_num_bytes
-            + arg_idxes_store_num_bytes
-            + labels_idxes_store_num_bytes
-            + labels_store_num_bytes
-            + funcs_store_num_bytes
-            + instrs_num_bytes) as u64
+    /// The version of the layout that the file is in
+    pub fn version(&self) -> u64 {
+        self.flags >> VERSION_SHIFT
+    }
+}
+
+impl InstrStore {
+    /// The length of the longest store in this function
+    /// (every index pair refers to one of the stores, so all the indexes
+    /// in the function are smaller than this)
+    pub fn max_store_len(&self) -> usize {
+        [
+            self.var_store.len(),
+            self.args_idxes_store.len(),
+            self.labels_idxes_store.len(),
+            self.labels_store.len(),
+            self.funcs_store.len(),
+            self.ext_store.len(),
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or(0)
+    }
+
+    /// Appends the stores, instructions & slots of `other` to `self`,
+    /// shifting the indexes in `other`'s instructions so that they refer to
+    /// the end of `self`'s stores
+    /// (`other`'s name, parameters, return type & `num_slots` are dropped)
+    pub fn append(&mut self, other: InstrStore) {
+        let shift = |idxes: Option<(usize, usize)>, by: usize| {
+            idxes.map(|(offset, len)| (offset + by, len))
+        };
+        let var_offset = self.var_store.len();
+        let args_offset = self.args_idxes_store.len();
+        let labels_idxes_offset = self.labels_idxes_store.len();
+        let labels_offset = self.labels_store.len();
+        let funcs_offset = self.funcs_store.len();
+        let ext_offset = self.ext_store.len();
+        self.var_store.extend(other.var_store);
+        self.args_idxes_store.extend(
+            other
+                .args_idxes_store
+                .into_iter()
+                .map(|(offset, len)| (offset + var_offset, len)),
+        );
+        self.labels_idxes_store.extend(
+            other
+                .labels_idxes_store
+                .into_iter()
+                .map(|(offset, len)| (offset + labels_offset, len)),
+        );
+        self.labels_store.extend(other.labels_store);
+        self.funcs_store.extend(other.funcs_store);
+        self.ext_store.extend(other.ext_store);
+        self.instrs
+            .extend(other.instrs.into_iter().map(|instr| Instr {
+                // (the `label` of an extension instruction indexes its JSON)
+                label: if instr.op == EXT_OP {
+                    shift(instr.label, ext_offset)
+                } else {
+                    shift(instr.label, labels_offset)
+                },
+                dest: shift(instr.dest, var_offset),
+                args: shift(instr.args, args_offset),
+                instr_labels: shift(instr.instr_labels, labels_idxes_offset),
+                funcs: shift(instr.funcs, funcs_offset),
+                ..instr
+            }));
+        self.dest_slots.extend(other.dest_slots);
+        self.arg_slots.extend(other.arg_slots);
+    }
+}
+
+impl<P: IndexPair> InstrView<'_, P> {
+    /// The entries of `label_table` for the labels right before `instrs[pc]`
+    pub fn labels_at(&self, pc: usize) -> &[FlatLabel<P>] {
+        let start = self.label_table.partition_point(|label| label.pc < pc);
+        let end = self.label_table.partition_point(|label| label.pc <= pc);
+        &self.label_table[start..end]
     }
 }
 
@@ -468,12 +1035,71 @@ impl InstrView<'_> {
 /*                                  Constants                                 */
 /* -------------------------------------------------------------------------- */
 
+/// `Header::flags` bit which indicates that the file is in the wide format,
+/// i.e. all its index pairs are `I64Pair`s rather than `I32Pair`s
+pub const WIDE_FORMAT_FLAG: u64 = 1;
+
+/// `Header::flags` bit which indicates that the file is in the compact
+/// format, i.e. all its index pairs are `I16Pair`s & its opcodes are `u16`s
+/// (this can't be combined with `WIDE_FORMAT_FLAG`)
+pub const COMPACT_FORMAT_FLAG: u64 = 2;
+
+/// `Header::flags` bit which indicates that the file has a `resolved`
+/// section after `ext_store`, with a `ResolvedOperands` for every entry of
+/// `instrs` (this can be combined with either format)
+pub const RESOLVED_FLAG: u64 = 4;
+
+/// Entry in `ResolvedOperands` for operands which an instruction doesn't
+/// have
+pub const UNRESOLVED: u32 = u32::MAX;
+
+/// The pointer depth of a `FlatType` is stored above this many bits
+/// (e.g. `ptr<ptr<bool>>` is `FlatType::BOOL | 2 << PTR_DEPTH_SHIFT`)
+pub const PTR_DEPTH_SHIFT: u32 = 8;
+
+/// The deepest pointer type a `FlatType` may have (deeper than `bril2json`
+/// output can nest), so that a corrupt depth is rejected at load time
+/// rather than e.g. printing billions of `ptr<`s
+pub const MAX_PTR_DEPTH: u32 = 255;
+
+/// The version of the `.fbril` layout written by this version of flat-bril,
+/// which is stored in the upper 32 bits of `Header::flags`. Files written
+/// before the layout was versioned have version 0, version 1 files store
+/// labels inline in `instrs`, version 2 files have a separate section
+/// (with its own stores) for each function, version 3 files have
+/// separate stores for function names, variables, labels & callees rather
+/// than one string table, and version 4 files store index pairs as inclusive
+/// `(start, end)` pairs rather than `(offset, len)` (`migrate.rs` upgrades
+/// them all).
+pub const FORMAT_VERSION: u64 = 5;
+
+/// `Header::flags` is shifted right by this to get the layout version
+pub const VERSION_SHIFT: u32 = 32;
+
+/// Entry in `dest_slots` for instructions (and labels) without a dest
+pub const NO_SLOT: u32 = u32::MAX;
+
+/// The `op` of an extension instruction, i.e. one whose opcode isn't in core
+/// Bril (labels have `op == u32::MAX`). Its `label` is the index pair of the
+/// instruction's JSON in `ext_store`, so that it can be unflattened exactly;
+/// the interpreter reports an error if it's ever reached.
+pub const EXT_OP: u32 = u32::MAX - 1;
+
 /// A string literal storing all distinct opcodes in core Bril
+/// (followed by `phi` from the SSA extension, and the bitwise extension's
+/// opcodes with the `bitwise` feature)
+#[cfg(not(feature = "bitwise"))]
 pub const OPCODE_BUFFER: &str =
-    "addmulsubdiveqltgtlegenotandorjmpbrcallretidprintnopconst";
+    "addmulsubdiveqltgtlegenotandorjmpbrcallretidprintnopconstphi";
+#[cfg(feature = "bitwise")]
+pub const OPCODE_BUFFER: &str = concat!(
+    "addmulsubdiveqltgtlegenotandorjmpbrcallretidprintnopconstphi",
+    "bandborbxorshlshr"
+);
 
-/// There are 20 distinct opcodes in core Bril
-pub const NUM_OPCODES: usize = 20;
+/// There are 20 distinct opcodes in core Bril, plus `phi`
+/// (and the 5 opcodes of the bitwise extension, with the `bitwise` feature)
+pub const NUM_OPCODES: usize = if cfg!(feature = "bitwise") { 26 } else { 21 };
 
 /// Default length of the args array
 /// (Rust `Vec`s are initialized with a capacity that is a power of 2,
@@ -516,6 +1142,17 @@ pub const OPCODE_IDX: [(usize, usize); NUM_OPCODES] = [
     (44, 48), // Print
     (49, 51), // Nop
     (52, 56), // Const
+    (57, 59), // Phi
+    #[cfg(feature = "bitwise")]
+    (60, 63), // Band
+    #[cfg(feature = "bitwise")]
+    (64, 66), // Bor
+    #[cfg(feature = "bitwise")]
+    (67, 70), // Bxor
+    #[cfg(feature = "bitwise")]
+    (71, 73), // Shl
+    #[cfg(feature = "bitwise")]
+    (74, 76), // Shr
 ];
 
 /* -------------------------------------------------------------------------- */
@@ -525,9 +1162,17 @@ pub const OPCODE_IDX: [(usize, usize); NUM_OPCODES] = [
 impl From<Option<Type>> for FlatType {
     fn from(ty_opt: Option<Type>) -> Self {
         match ty_opt {
-            Some(Type::Bool) => FlatType::Bool,
-            Some(Type::Int) => FlatType::Int,
-            None => FlatType::Null,
+            Some(ty) => ty.into(),
+            None => FlatType::NULL,
+        }
+    }
+}
+
+impl From<BaseType> for Type {
+    fn from(base: BaseType) -> Self {
+        match base {
+            BaseType::Int => Type::Int,
+            BaseType::Bool => Type::Bool,
         }
     }
 }
@@ -535,8 +1180,14 @@ impl From<Option<Type>> for FlatType {
 impl From<Type> for FlatType {
     fn from(ty: Type) -> Self {
         match ty {
-            Type::Bool => FlatType::Bool,
-            Type::Int => FlatType::Int,
+            Type::Bool => FlatType::BOOL,
+            Type::Int => FlatType::INT,
+            Type::Ptr { pointee, depth } => {
+                let FlatType(base) = Type::from(pointee).into();
+                FlatType(base | (depth as usize) << PTR_DEPTH_SHIFT)
+            }
+            #[cfg(feature = "ext-values")]
+            Type::Extern => FlatType::EXTERN,
         }
     }
 }
@@ -545,10 +1196,65 @@ impl TryFrom<FlatType> for Type {
     type Error = ();
 
     fn try_from(flat_ty: FlatType) -> Result<Self, Self::Error> {
-        match flat_ty {
-            FlatType::Bool => Ok(Type::Bool),
-            FlatType::Int => Ok(Type::Int),
-            FlatType::Null => Err(()),
+        #[cfg(feature = "ext-values")]
+        if flat_ty == FlatType::EXTERN {
+            return Ok(Type::Extern);
+        }
+        let pointee = match FlatType(flat_ty.0 & ((1 << PTR_DEPTH_SHIFT) - 1)) {
+            FlatType::BOOL => BaseType::Bool,
+            FlatType::INT => BaseType::Int,
+            _ => return Err(()),
+        };
+        match u32::try_from(flat_ty.0 >> PTR_DEPTH_SHIFT) {
+            Ok(0) => Ok(pointee.into()),
+            Ok(depth) if depth <= MAX_PTR_DEPTH => {
+                Ok(Type::Ptr { pointee, depth })
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<JsonType> for Type {
+    fn from(json_ty: JsonType) -> Self {
+        match json_ty {
+            JsonType::Int => Type::Int,
+            JsonType::Bool => Type::Bool,
+            #[cfg(feature = "ext-values")]
+            JsonType::Extern => Type::Extern,
+            JsonType::Ptr(inner) => match Type::from(*inner) {
+                Type::Int => Type::Ptr {
+                    pointee: BaseType::Int,
+                    depth: 1,
+                },
+                Type::Bool => Type::Ptr {
+                    pointee: BaseType::Bool,
+                    depth: 1,
+                },
+                Type::Ptr { pointee, depth } => Type::Ptr {
+                    pointee,
+                    depth: depth + 1,
+                },
+                // (pointers to extension values aren't supported, so
+                // `ptr<extern>` is treated as `extern`)
+                #[cfg(feature = "ext-values")]
+                Type::Extern => Type::Extern,
+            },
+        }
+    }
+}
+
+impl From<Type> for JsonType {
+    fn from(ty: Type) -> Self {
+        match ty {
+            Type::Int => JsonType::Int,
+            Type::Bool => JsonType::Bool,
+            Type::Ptr { pointee, depth } => (0..depth)
+                .fold(Type::from(pointee).into(), |inner, _| {
+                    JsonType::Ptr(Box::new(inner))
+                }),
+            #[cfg(feature = "ext-values")]
+            Type::Extern => JsonType::Extern,
         }
     }
 }
@@ -567,6 +1273,10 @@ impl From<Option<BrilValue>> for FlatBrilValue {
                 FlatBrilValue::BoolVal(surrogate_bool)
             }
             None => FlatBrilValue::Null(SurrogateNull(0)),
+            // (extension values only exist at runtime, so they're never
+            // the value of a `const`)
+            #[cfg(feature = "ext-values")]
+            Some(BrilValue::Extern(_)) => FlatBrilValue::Null(SurrogateNull(0)),
         }
     }
 }
@@ -610,19 +1320,66 @@ impl From<bool> for SurrogateBool {
     }
 }
 
-impl From<(u32, u32)> for I32Pair {
-    fn from(pair: (u32, u32)) -> Self {
-        Self {
-            first: pair.0 as i32,
-            second: pair.1 as i32,
+impl OpWord for u32 {
+    fn from_u32(op: u32) -> Self {
+        op
+    }
+
+    fn to_u32(self) -> u32 {
+        self
+    }
+}
+
+impl OpWord for u16 {
+    fn from_u32(op: u32) -> Self {
+        match op {
+            u32::MAX => u16::MAX,
+            EXT_OP => u16::MAX - 1,
+            op => op as u16,
+        }
+    }
+
+    fn to_u32(self) -> u32 {
+        match self {
+            u16::MAX => u32::MAX,
+            op if op == u16::MAX - 1 => EXT_OP,
+            op => op as u32,
         }
     }
 }
 
-// Convention: None |-> an `I32Pair` where both fields are -1
-impl From<Option<(u32, u32)>> for I32Pair {
-    fn from(pair_opt: Option<(u32, u32)>) -> Self {
-        match pair_opt {
+impl IndexPair for I16Pair {
+    const FORMAT_FLAGS: u64 = COMPACT_FORMAT_FLAG;
+    const MAX_IDX: usize = i16::MAX as usize;
+    type Op = u16;
+
+    // Convention: None |-> an `I16Pair` where both fields are -1
+    fn from_idxes(idxes: Option<(usize, usize)>) -> Self {
+        match idxes {
+            None => I16Pair {
+                first: -1,
+                second: -1,
+            },
+            Some((i, j)) => I16Pair {
+                first: i as i16,
+                second: j as i16,
+            },
+        }
+    }
+
+    fn fields(self) -> (i64, i64) {
+        (self.first as i64, self.second as i64)
+    }
+}
+
+impl IndexPair for I32Pair {
+    const FORMAT_FLAGS: u64 = 0;
+    const MAX_IDX: usize = i32::MAX as usize;
+    type Op = u32;
+
+    // Convention: None |-> an `I32Pair` where both fields are -1
+    fn from_idxes(idxes: Option<(usize, usize)>) -> Self {
+        match idxes {
             None => I32Pair {
                 first: -1,
                 second: -1,
@@ -633,111 +1390,232 @@ impl From<Option<(u32, u32)>> for I32Pair {
             },
         }
     }
+
+    fn fields(self) -> (i64, i64) {
+        (self.first as i64, self.second as i64)
+    }
 }
 
-// Convention: `I32Pair {first: -1, second: -1} |-> None`
-impl From<I32Pair> for Option<(u32, u32)> {
-    fn from(i32pair: I32Pair) -> Self {
-        let I32Pair { first, second } = i32pair;
-        if first == -1 && second == -1 {
-            None
-        } else {
-            let first = first as u32;
-            let second = second as u32;
-            Some((first, second))
+impl IndexPair for I64Pair {
+    const FORMAT_FLAGS: u64 = WIDE_FORMAT_FLAG;
+    const MAX_IDX: usize = i64::MAX as usize;
+    type Op = u32;
+
+    // Convention: None |-> an `I64Pair` where both fields are -1
+    fn from_idxes(idxes: Option<(usize, usize)>) -> Self {
+        match idxes {
+            None => I64Pair {
+                first: -1,
+                second: -1,
+            },
+            Some((i, j)) => I64Pair {
+                first: i as i64,
+                second: j as i64,
+            },
         }
     }
-}
 
-impl From<I32Pair> for (u32, u32) {
-    fn from(i32pair: I32Pair) -> Self {
-        let I32Pair { first, second } = i32pair;
-        (first as u32, second as u32)
+    fn fields(self) -> (i64, i64) {
+        (self.first, self.second)
     }
 }
 
 // Converting `Instr` to `FlatInstr`
-impl From<Instr> for FlatInstr {
+impl<P: IndexPair> From<Instr> for FlatInstr<P> {
     fn from(instr: Instr) -> Self {
         FlatInstr {
-            op: instr.op,
-            label: instr.label.into(),
-            dest: instr.dest.into(),
-            args: instr.args.into(),
-            instr_labels: instr.instr_labels.into(),
-            funcs: instr.funcs.into(),
+            op: P::Op::from_u32(instr.op),
+            label: P::from_idxes(instr.label),
+            dest: P::from_idxes(instr.dest),
+            args: P::from_idxes(instr.args),
+            instr_labels: P::from_idxes(instr.instr_labels),
+            funcs: P::from_idxes(instr.funcs),
             ty: instr.ty.into(),
             value: instr.value.into(),
         }
     }
 }
 
-impl From<FlatInstr> for Instr {
-    fn from(flat_instr: FlatInstr) -> Self {
+impl<P: IndexPair> From<FlatInstr<P>> for Instr {
+    fn from(flat_instr: FlatInstr<P>) -> Self {
         Instr {
-            op: flat_instr.op,
-            label: flat_instr.label.into(),
-            dest: flat_instr.dest.into(),
+            op: flat_instr.op(),
+            label: flat_instr.label.get(),
+            dest: flat_instr.dest.get(),
             ty: flat_instr.ty.into(),
             value: flat_instr.value.into(),
-            args: flat_instr.args.into(),
-            instr_labels: flat_instr.instr_labels.into(),
-            funcs: flat_instr.funcs.into(),
+            args: flat_instr.args.get(),
+            instr_labels: flat_instr.instr_labels.get(),
+            funcs: flat_instr.funcs.get(),
         }
     }
 }
 
-impl From<FuncArg> for FlatFuncArg {
+impl<P: IndexPair> From<FuncArg> for FlatFuncArg<P> {
     fn from(func_arg: FuncArg) -> Self {
         Self {
-            arg_name_idxes: func_arg.arg_name_idxes.into(),
+            arg_name_idxes: P::from_idxes(Some(func_arg.arg_name_idxes)),
             arg_type: func_arg.arg_type.into(),
         }
     }
 }
 
-impl From<FlatFuncArg> for FuncArg {
-    fn from(flat_func_arg: FlatFuncArg) -> Self {
+impl<P: IndexPair> From<FlatFuncArg<P>> for FuncArg {
+    fn from(flat_func_arg: FlatFuncArg<P>) -> Self {
         Self {
-            arg_name_idxes: flat_func_arg.arg_name_idxes.into(),
+            arg_name_idxes: flat_func_arg.arg_name_idxes.idxes(),
             arg_type: flat_func_arg
                 .arg_type
                 .try_into()
-                .expect("Can't convert `FlatType::Null` into a `Type`"),
+                .expect("Can't convert `FlatType::NULL` into a `Type`"),
         }
     }
 }
 
-impl From<InstrView<'_>> for InstrStore {
-    fn from(instr_view: InstrView) -> Self {
+/// The smallest range of a store containing every index pair it's been
+/// extended with (see `From<InstrView> for InstrStore`)
+#[derive(Clone, Copy)]
+struct StoreSpan {
+    start: usize,
+    end: usize,
+}
+
+impl StoreSpan {
+    fn new() -> Self {
+        Self {
+            start: usize::MAX,
+            end: 0,
+        }
+    }
+
+    fn extend(&mut self, idxes: Option<(usize, usize)>) {
+        if let Some((offset, len)) = idxes {
+            self.start = self.start.min(offset);
+            self.end = self.end.max(offset + len);
+        }
+    }
+
+    /// The (half-open) range of the store which is spanned
+    fn range(self) -> std::ops::Range<usize> {
+        self.start.min(self.end)..self.end
+    }
+
+    /// Shifts `idxes` so that they're relative to the start of the span
+    fn shift(self, idxes: Option<(usize, usize)>) -> Option<(usize, usize)> {
+        idxes.map(|(offset, len)| (offset - self.start, len))
+    }
+}
+
+impl<P: IndexPair> From<InstrView<'_, P>> for InstrStore {
+    /// Only the parts of the stores that the function refers to are copied,
+    /// since the stores in a flat Bril file are shared by all its functions
+    /// (the index pairs are shifted accordingly)
+    fn from(instr_view: InstrView<P>) -> Self {
+        let mut var_span = StoreSpan::new();
+        let mut args_span = StoreSpan::new();
+        let mut labels_idxes_span = StoreSpan::new();
+        let mut labels_span = StoreSpan::new();
+        let mut funcs_span = StoreSpan::new();
+        let mut ext_span = StoreSpan::new();
+        for func_arg in instr_view.func_args {
+            var_span.extend(Some(func_arg.arg_name_idxes.idxes()));
+        }
+        for instr in instr_view.instrs {
+            if instr.op() == EXT_OP {
+                ext_span.extend(instr.label.get());
+            } else {
+                labels_span.extend(instr.label.get());
+            }
+            var_span.extend(instr.dest.get());
+            args_span.extend(instr.args.get());
+            labels_idxes_span.extend(instr.instr_labels.get());
+            funcs_span.extend(instr.funcs.get());
+        }
+        for label in instr_view.label_table {
+            labels_span.extend(Some(label.label_idxes.idxes()));
+        }
+        let arg_idxes_store = &instr_view.arg_idxes_store[args_span.range()];
+        for arg_idxes in arg_idxes_store {
+            var_span.extend(Some(arg_idxes.idxes()));
+        }
+        let labels_idxes_store =
+            &instr_view.labels_idxes_store[labels_idxes_span.range()];
+        for label_idxes in labels_idxes_store {
+            labels_span.extend(Some(label_idxes.idxes()));
+        }
+
         let func_name = instr_view.func_name.into();
         let func_args: Vec<FuncArg> = instr_view
             .func_args
             .iter()
-            .map(|func_arg| FuncArg::from(*func_arg))
+            .map(|func_arg| FuncArg {
+                arg_name_idxes: var_span
+                    .shift(Some(func_arg.arg_name_idxes.idxes()))
+                    .unwrap(),
+                ..FuncArg::from(*func_arg)
+            })
             .collect();
 
         let func_ret_ty = instr_view.func_ret_ty.into();
 
-        let var_store = instr_view.var_store.into();
-        let args_idxes_store: Vec<(u32, u32)> = instr_view
-            .arg_idxes_store
+        let var_store = instr_view.var_store[var_span.range()].into();
+        let args_idxes_store: Vec<(usize, usize)> = arg_idxes_store
             .iter()
-            .map(|arg_idxes| <(u32, u32)>::from(*arg_idxes))
+            .map(|arg_idxes| var_span.shift(Some(arg_idxes.idxes())).unwrap())
             .collect();
-        let labels_idxes_store: Vec<(u32, u32)> = instr_view
-            .labels_idxes_store
+        let labels_idxes_store: Vec<(usize, usize)> = labels_idxes_store
             .iter()
-            .map(|label_idxes| <(u32, u32)>::from(*label_idxes))
+            .map(|label_idxes| {
+                labels_span.shift(Some(label_idxes.idxes())).unwrap()
+            })
             .collect();
 
-        let labels_store = instr_view.labels_store.into();
-        let funcs_store = instr_view.funcs_store.into();
-        let instrs: Vec<Instr> = instr_view
-            .instrs
-            .iter()
-            .map(|flat_instr| Instr::from(*flat_instr))
-            .collect();
+        let labels_store = instr_view.labels_store[labels_span.range()].into();
+        let funcs_store = instr_view.funcs_store[funcs_span.range()].into();
+        let shift_instr = |flat_instr: FlatInstr<P>| {
+            let instr = Instr::from(flat_instr);
+            Instr {
+                label: if instr.op == EXT_OP {
+                    ext_span.shift(instr.label)
+                } else {
+                    labels_span.shift(instr.label)
+                },
+                dest: var_span.shift(instr.dest),
+                args: args_span.shift(instr.args),
+                instr_labels: labels_idxes_span.shift(instr.instr_labels),
+                funcs: funcs_span.shift(instr.funcs),
+                ..instr
+            }
+        };
+        // Labels go back in front of the instruction they label
+        // (with `NO_SLOT` as their entry in `dest_slots`, if there is one)
+        let has_slots = !instr_view.dest_slots.is_empty();
+        let mut instrs: Vec<Instr> = vec![];
+        let mut dest_slots: Vec<u32> = vec![];
+        let mut labels = instr_view.label_table.iter().peekable();
+        for pc in 0..=instr_view.instrs.len() {
+            while let Some(label) = labels.next_if(|label| label.pc <= pc) {
+                instrs.push(Instr::make_label(
+                    labels_span.shift(Some(label.label_idxes.idxes())).unwrap(),
+                ));
+                if has_slots {
+                    dest_slots.push(NO_SLOT);
+                }
+            }
+            if let Some(flat_instr) = instr_view.instrs.get(pc) {
+                instrs.push(shift_instr(*flat_instr));
+            }
+            if let Some(slot) = instr_view.dest_slots.get(pc) {
+                dest_slots.push(*slot);
+            }
+        }
+        let num_slots = instr_view.num_slots;
+        let arg_slots = instr_view
+            .arg_slots
+            .get(args_span.range())
+            .unwrap_or_default()
+            .into();
+        let ext_store = instr_view.ext_store[ext_span.range()].into();
 
         InstrStore {
             func_name,
@@ -749,6 +1627,10 @@ impl From<InstrView<'_>> for InstrStore {
             labels_store,
             funcs_store,
             instrs,
+            num_slots,
+            dest_slots,
+            arg_slots,
+            ext_store,
         }
     }
 }
@@ -762,20 +1644,74 @@ impl fmt::Display for Type {
         match self {
             Type::Int => write!(f, "int"),
             Type::Bool => write!(f, "bool"),
+            Type::Ptr { pointee, depth } => {
+                let depth = *depth as usize;
+                let pointee = Type::from(*pointee);
+                write!(
+                    f,
+                    "{}{pointee}{}",
+                    "ptr<".repeat(depth),
+                    ">".repeat(depth)
+                )
+            }
+            #[cfg(feature = "ext-values")]
+            Type::Extern => write!(f, "extern"),
         }
     }
 }
 
 impl Type {
-    /// Converts a `Type` to its string representation
-    pub fn as_str(&self) -> &str {
+    /// Converts a `Type` to its JSON representation
+    /// (a string for `int` / `bool`, or `{"ptr": ..}` for pointers)
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::to_value(self).expect("types can always be serialized")
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl BrilValue {
+    /// Writes this value to `out` exactly as the reference interpreter
+    /// (`brili`) prints it: ints in decimal & booleans as `true` / `false`
+    /// (`print` uses this rather than `Display`, so that no formatting
+    /// flags ever leak into the program's output)
+    pub fn write_bril<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
         match self {
-            Type::Int => "int",
-            Type::Bool => "bool",
+            BrilValue::IntVal(n) => write!(out, "{n}"),
+            BrilValue::BoolVal(b) => {
+                let b = bool::from(*b);
+                out.write_all(if b { b"true" } else { b"false" })
+            }
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(ext) => write!(out, "{ext}"),
         }
     }
 }
 
+/// Formats a float the way `brili` prints it, for when floats are supported:
+/// - `-0` is printed as `-0`, and the non-finite values as `NaN`,
+///   `Infinity` & `-Infinity` (JavaScript's spellings)
+/// - Otherwise, floats are printed with 17 digits after the decimal point
+///   (like JavaScript's `toFixed(17)`), except that magnitudes of at least
+///   `1e21` use exponential notation with an explicit sign (eg. `1e+21`)
+pub fn format_bril_float(f: f64) -> String {
+    if f == 0.0 && f.is_sign_negative() {
+        "-0".to_string()
+    } else if f.is_nan() {
+        "NaN".to_string()
+    } else if f.is_infinite() {
+        if f > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+    } else if f.abs() >= 1e21 {
+        format!("{f:e}").replacen('e', "e+", 1)
+    } else {
+        format!("{f:.17}")
+    }
+}
+
 impl fmt::Display for BrilValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -783,6 +1719,134 @@ impl fmt::Display for BrilValue {
             BrilValue::BoolVal(b) => {
                 write!(f, "{}", bool::from(*b))
             }
+            #[cfg(feature = "ext-values")]
+            BrilValue::Extern(ext) => write!(f, "{ext}"),
         }
     }
 }
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod types_tests {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    use crate::flatten::flatten_instrs;
+    use crate::types::{
+        BrilValue, FlatInstr, I64Pair, Instr, InstrKind, InstrStore, OP_SPECS,
+        OpRole, Opcode, Type, format_bril_float,
+    };
+
+    /// Flattened functions (and flat instructions) survive a round trip
+    /// through serde
+    #[test]
+    fn test_serde_roundtrip() {
+        let file = File::open("test/call-with-args.json")
+            .expect("Unable to open file");
+        let json: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file))
+                .expect("Unable to parse JSON");
+        for func in json["functions"].as_array().unwrap() {
+            let instr_store = flatten_instrs(func);
+            let serialized = serde_json::to_string(&instr_store).unwrap();
+            let deserialized: InstrStore =
+                serde_json::from_str(&serialized).unwrap();
+            assert_eq!(deserialized, instr_store);
+
+            for instr in &instr_store.instrs {
+                let flat_instr = FlatInstr::<I64Pair>::from(instr.clone());
+                let serialized = serde_json::to_string(&flat_instr).unwrap();
+                let deserialized: FlatInstr<I64Pair> =
+                    serde_json::from_str(&serialized).unwrap();
+                assert_eq!(deserialized, flat_instr);
+            }
+        }
+    }
+
+    /// Values are printed exactly as `brili` prints them
+    /// (the expected strings are `brili`'s own output)
+    #[test]
+    fn test_bril_value_printing() {
+        let print = |value: BrilValue| {
+            let mut out = vec![];
+            value.write_bril(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(print(BrilValue::IntVal(-42)), "-42");
+        assert_eq!(print(BrilValue::IntVal(i64::MIN)), "-9223372036854775808");
+        assert_eq!(print(BrilValue::BoolVal(true.into())), "true");
+        assert_eq!(print(BrilValue::BoolVal(false.into())), "false");
+
+        assert_eq!(format_bril_float(0.5), "0.50000000000000000");
+        assert_eq!(format_bril_float(0.1), "0.10000000000000001");
+        assert_eq!(format_bril_float(-3.0), "-3.00000000000000000");
+        assert_eq!(format_bril_float(0.0), "0.00000000000000000");
+        assert_eq!(format_bril_float(-0.0), "-0");
+        assert_eq!(format_bril_float(f64::NAN), "NaN");
+        assert_eq!(format_bril_float(f64::INFINITY), "Infinity");
+        assert_eq!(format_bril_float(f64::NEG_INFINITY), "-Infinity");
+        assert_eq!(format_bril_float(1e21), "1e+21");
+        assert_eq!(format_bril_float(-1.5e300), "-1.5e+300");
+    }
+
+    /// Every opcode has a row in `OP_SPECS`, from which the classification
+    /// of instructions is derived
+    #[test]
+    fn test_op_specs() {
+        use strum::IntoEnumIterator;
+
+        assert_eq!(OP_SPECS.len(), Opcode::iter().count());
+        let binops: Vec<Opcode> =
+            Opcode::iter().filter(|op| op.is_binop()).collect();
+        assert_eq!(
+            binops,
+            [
+                Opcode::Add,
+                Opcode::Mul,
+                Opcode::Sub,
+                Opcode::Div,
+                Opcode::Eq,
+                Opcode::Lt,
+                Opcode::Gt,
+                Opcode::Le,
+                Opcode::Ge,
+                Opcode::And,
+                Opcode::Or,
+                #[cfg(feature = "bitwise")]
+                Opcode::Band,
+                #[cfg(feature = "bitwise")]
+                Opcode::Bor,
+                #[cfg(feature = "bitwise")]
+                Opcode::Bxor,
+                #[cfg(feature = "bitwise")]
+                Opcode::Shl,
+                #[cfg(feature = "bitwise")]
+                Opcode::Shr,
+            ]
+        );
+        let unops: Vec<Opcode> =
+            Opcode::iter().filter(|op| op.is_unop()).collect();
+        assert_eq!(unops, [Opcode::Not, Opcode::Id]);
+
+        assert_eq!(Opcode::Lt.spec().operand_ty, Some(Type::Int));
+        assert_eq!(Opcode::Lt.spec().result_ty, Some(Type::Bool));
+        assert_eq!(Opcode::Br.spec().operand_ty, Some(Type::Bool));
+        assert_eq!(Opcode::Call.spec().role, OpRole::ValueOrEffect);
+
+        // `call`s are value ops iff they have a dest
+        let call = Instr {
+            op: Opcode::Call as u32,
+            dest: None,
+            ..Instr::make_label((0, 0))
+        };
+        assert!(matches!(call.get_instr_kind(), InstrKind::EffectOp));
+        let call = Instr {
+            dest: Some((0, 0)),
+            ..call
+        };
+        assert!(matches!(call.get_instr_kind(), InstrKind::ValueOp));
+    }
+}
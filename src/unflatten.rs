@@ -5,14 +5,21 @@ use std::str;
 
 /// Takes an `InstrStore` (flattened instrs + arrays storing args/dests etc.)
 /// corresponding to a Bril function and returns its JSON representation
+/// (an instruction's `args`, `labels` & `funcs` are only written if it has
+/// them, so an empty `args` list is written as `[]` but a missing one is
+/// left out)
 pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
     let mut instr_json_vec = vec![];
 
     for instr in &instr_store.instrs {
-        if let Some((start_idx, end_idx)) = instr.label {
-            let start_idx = start_idx as usize;
-            let end_idx = end_idx as usize;
-            let label = &instr_store.labels_store[start_idx..=end_idx];
+        if instr.op == EXT_OP {
+            // Extension instructions are stored as JSON already
+            let (offset, len) = instr.label.expect("missing extension JSON");
+            let json = &instr_store.ext_store[offset..offset + len];
+            instr_json_vec
+                .push(serde_json::from_slice(json).expect("invalid JSON"));
+        } else if let Some((offset, len)) = instr.label {
+            let label = &instr_store.labels_store[offset..offset + len];
             let label_for_json = str::from_utf8(label).expect("invalid utf-8");
             let json = serde_json::json!({
                 "label": label_for_json
@@ -21,78 +28,65 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
         } else {
             let op_str = Opcode::op_idx_to_op_str(instr.op as usize);
 
-            // Extract the `ty` field of the instr as a string
-            let mut ty_str = None;
+            // Extract the `ty` field of the instr as JSON
+            let mut ty_json = None;
             if let Some(ty) = &instr.ty {
-                ty_str = Some(ty.as_str());
+                ty_json = Some(ty.to_json());
             }
 
             // Convert the `dest` index of the instr to an actual string
             // containing the dest
             let mut dest: Option<&[u8]> = None;
-            if let Some((start_idx, end_idx)) = instr.dest {
-                let start_idx = start_idx as usize;
-                let end_idx = end_idx as usize;
-                dest = Some(&instr_store.var_store[start_idx..=end_idx]);
+            if let Some((offset, len)) = instr.dest {
+                dest = Some(&instr_store.var_store[offset..offset + len]);
             }
 
-            // Convert the (start_idx, end_idx) for args in the instr to
-            // an actual list of strings (by doing `args_store[start_idx..=end_idx]`)
-            let mut args: Vec<&[u8]> = vec![];
-            if let Some((start_idx, end_idx)) = instr.args {
-                let start_idx = start_idx as usize;
-                let end_idx = end_idx as usize;
-                let arg_idxes: Vec<(u32, u32)> =
-                    instr_store.args_idxes_store[start_idx..=end_idx].to_vec();
-                for (start, end) in arg_idxes {
-                    let start = start as usize;
-                    let end = end as usize;
-                    let arg: &[u8] = &instr_store.var_store[start..=end];
+            // Convert the (offset, len) for args in the instr to an actual
+            // list of strings (by doing `args_store[offset..offset + len]`)
+            let mut args: Option<Vec<&[u8]>> = None;
+            if let Some((offset, len)) = instr.args {
+                let args = args.insert(vec![]);
+                let arg_idxes: Vec<(usize, usize)> =
+                    instr_store.args_idxes_store[offset..offset + len].to_vec();
+                for (arg_offset, arg_len) in arg_idxes {
+                    let arg: &[u8] = &instr_store.var_store
+                        [arg_offset..arg_offset + arg_len];
                     args.push(arg);
                 }
             }
 
-            // Convert the (start_idx, end_idx) for labels in the instr to
+            // Convert the (offset, len) for labels in the instr to
             // an actual list of strings
-            let mut labels: Vec<&[u8]> = vec![];
-            if let Some((start_idx, end_idx)) = instr.instr_labels {
-                let start_idx = start_idx as usize;
-                let end_idx = end_idx as usize;
-                let labels_idxes: Vec<(u32, u32)> = instr_store
-                    .labels_idxes_store[start_idx..=end_idx]
+            let mut labels: Option<Vec<&[u8]>> = None;
+            if let Some((offset, len)) = instr.instr_labels {
+                let labels = labels.insert(vec![]);
+                let labels_idxes: Vec<(usize, usize)> = instr_store
+                    .labels_idxes_store[offset..offset + len]
                     .to_vec();
-                for (start, end) in labels_idxes {
-                    let start = start as usize;
-                    let end = end as usize;
-                    let label: &[u8] = &instr_store.labels_store[start..=end];
+                for (label_offset, label_len) in labels_idxes {
+                    let label: &[u8] = &instr_store.labels_store
+                        [label_offset..label_offset + label_len];
                     labels.push(label);
                 }
             }
 
-            // Convert the (start_idx, end_idx) for funcs in the instr to
+            // Convert the (offset, len) for funcs in the instr to
             // an actual list of strings
             let mut funcs: Option<&[u8]> = None;
-            if let Some((start_idx, end_idx)) = instr.funcs {
-                let start_idx = start_idx as usize;
-                let end_idx = end_idx as usize;
-                funcs = Some(&instr_store.funcs_store[start_idx..=end_idx]);
+            if let Some((offset, len)) = instr.funcs {
+                funcs = Some(&instr_store.funcs_store[offset..offset + len]);
             }
 
-            let args_for_json: Vec<&str> = args
-                .iter()
-                .map(|arg| str::from_utf8(arg).expect("invalid utf-8"))
-                .collect();
-            let labels_for_json: Vec<&str> = labels
-                .iter()
-                .map(|label| str::from_utf8(label).expect("invalid utf-8"))
-                .collect();
-
-            let mut funcs_for_json = vec![];
-            if Option::is_some(&funcs) {
-                let func_str = str::from_utf8(funcs.expect("missing funcs"))
-                    .expect("invalid utf-8");
-                funcs_for_json.push(func_str)
-            }
+            let to_json = |strs: Vec<&[u8]>| {
+                let strs: Vec<&str> = strs
+                    .iter()
+                    .map(|s| str::from_utf8(s).expect("invalid utf-8"))
+                    .collect();
+                serde_json::json!(strs)
+            };
+            let args_for_json = args.map(to_json);
+            let labels_for_json = labels.map(to_json);
+            let funcs_for_json = funcs.map(|func| to_json(vec![func]));
 
             // Convert the `BrilValue` to an `serde_json::Value`
             let mut value_for_json: Option<serde_json::Value> = None;
@@ -107,14 +101,18 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
                                 .unwrap(),
                         );
                     }
+                    // (a `const` never holds an extension value)
+                    #[cfg(feature = "ext-values")]
+                    BrilValue::Extern(_) => (),
                 }
             }
 
             // Build a JSON object corresponding to the right instr kind
             let instr_kind = instr.get_instr_kind();
             let instr_json = match instr_kind {
-                InstrKind::Label => {
-                    // labels are already handled at the beginning of this function
+                InstrKind::Label | InstrKind::Extension => {
+                    // labels & extension instructions are already handled
+                    // at the beginning of this function
                     unreachable!();
                 }
                 InstrKind::Nop => {
@@ -129,7 +127,7 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
                     serde_json::json!({
                       "op": op_str,
                       "dest": dest_for_json,
-                      "type": ty_str.expect("Expected string representing a type"),
+                      "type": ty_json.expect("Expected a type"),
                       "value": value_for_json.expect("Missing value"),
                     })
                 }
@@ -140,21 +138,27 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
                     serde_json::json!({
                       "op": op_str,
                       "dest": dest_for_json,
-                      "type": ty_str.expect("Expected string representing a type"),
-                      "args": args_for_json,
-                      "labels": labels_for_json,
-                      "funcs": funcs_for_json
+                      "type": ty_json.expect("Expected a type"),
                     })
                 }
                 InstrKind::EffectOp => {
                     serde_json::json!({
                       "op": op_str,
-                      "args": args_for_json,
-                      "labels": labels_for_json,
-                      "funcs": funcs_for_json
                     })
                 }
             };
+            // (every kind of instruction keeps the lists it was written
+            // with, even if they're empty, e.g. a `nop` with `"args": []`)
+            let mut instr_json = instr_json;
+            for (field, json) in [
+                ("args", args_for_json),
+                ("labels", labels_for_json),
+                ("funcs", funcs_for_json),
+            ] {
+                if let Some(json) = json {
+                    instr_json[field] = json;
+                }
+            }
 
             instr_json_vec.push(instr_json);
         }
@@ -167,20 +171,18 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
     // Recover the arguments to the function (if any exist)
     let mut func_args_for_json = vec![];
     for func_arg in &instr_store.func_args {
-        // For each arg, use its start & end index to index into the `var_store`
+        // For each arg, use its offset & length to index into the `var_store`
         // buffer, then convert those bytes back to a valid string
-        let (start_idx, end_idx) = func_arg.arg_name_idxes;
-        let start_idx = start_idx as usize;
-        let end_idx = end_idx as usize;
+        let (offset, len) = func_arg.arg_name_idxes;
         let func_arg_str =
-            str::from_utf8(&instr_store.var_store[start_idx..=end_idx])
+            str::from_utf8(&instr_store.var_store[offset..offset + len])
                 .expect("invalid utf-8");
 
         // Extract the type of the function argument
-        let arg_type_str = func_arg.arg_type.as_str();
+        let arg_type_json = func_arg.arg_type.to_json();
         let func_arg_json = serde_json::json!({
             "name": func_arg_str,
-            "type": arg_type_str
+            "type": arg_type_json
         });
         func_args_for_json.push(func_arg_json);
     }
@@ -193,7 +195,7 @@ pub fn unflatten_instrs(instr_store: &InstrStore) -> serde_json::Value {
         func_json = serde_json::json!({
             "name": func_name,
             "args": func_args_for_json,
-            "type": ret_ty.as_str(),
+            "type": ret_ty.to_json(),
             "instrs": instr_json_vec
         });
     } else {
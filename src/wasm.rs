@@ -0,0 +1,62 @@
+use wasm_bindgen::prelude::*;
+
+use crate::interp::{InterpContext, Limits, interp_program};
+use crate::memfile::{self, AlignedBytes, ProgramViews};
+
+/* -------------------------------------------------------------------------- */
+/*                                 JS bindings                                */
+/* -------------------------------------------------------------------------- */
+
+// These are the entry points used by the browser playground, which runs
+// flat-bril compiled to `wasm32-unknown-unknown`. Errors are thrown on the
+// JS side as strings.
+
+/// Flattens a JSON Bril program (given as a string) to the contents of
+/// an `.fbril` file
+#[wasm_bindgen]
+pub fn flatten(json: &str) -> Result<Vec<u8>, String> {
+    memfile::flatten_to_bytes(json)
+}
+
+/// Interprets the `.fbril` file whose contents are `bytes`, passing `args`
+/// to `main`, and returns everything the program printed
+/// - `bytes` is copied into a buffer of `u64`s first, since the buffer
+///   handed to us by JS isn't necessarily suitably aligned
+#[wasm_bindgen]
+pub fn run(bytes: &[u8], args: Vec<String>) -> Result<String, String> {
+    let data = AlignedBytes::new(bytes);
+    let program = memfile::load_program(&data)
+        .map_err(|msg| format!("malformed file: {msg}"))?;
+    let arg_strs: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+    let mut ctx = InterpContext::new(vec![], Limits::default());
+    let result = match &program {
+        ProgramViews::Narrow(program) => {
+            interp_program(program, arg_strs, &mut ctx)
+        }
+        ProgramViews::Wide(program) => {
+            interp_program(program, arg_strs, &mut ctx)
+        }
+        ProgramViews::Compact(program) => {
+            interp_program(program, arg_strs, &mut ctx)
+        }
+    };
+    result.map_err(|diagnostic| diagnostic.to_string())?;
+    Ok(String::from_utf8_lossy(&ctx.out).into_owned())
+}
+
+#[cfg(test)]
+mod wasm_tests {
+    use super::*;
+
+    /// A program flattened by `flatten` can be interpreted by `run`
+    #[test]
+    fn test_flatten_then_run() {
+        let json = std::fs::read_to_string("test/call-with-args.json")
+            .expect("Unable to read file");
+        let bytes = flatten(&json).expect("valid program should flatten");
+        let output = run(&bytes, vec![]).expect("program should run");
+        let expected = std::fs::read_to_string("test/call-with-args.out")
+            .expect("Unable to read file");
+        assert_eq!(output, expected);
+    }
+}
@@ -0,0 +1,311 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::flatten::read_bril_source;
+use crate::interp::{InterpContext, Limits, interp_program};
+use crate::memfile::{
+    AlignedBytes, FlatProgram, get_program_views, try_json_to_fbril_bytes,
+};
+
+/* -------------------------------------------------------------------------- */
+/*                                    Types                                   */
+/* -------------------------------------------------------------------------- */
+
+/// The no. of unchanged lines shown on either side of a change in a diff
+const DIFF_CONTEXT: usize = 1;
+
+/// A line of the diff between two outputs (see `diff_lines`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffLine {
+    Same(String),
+    Removed(String),
+    Added(String),
+}
+
+/// Watches a Bril source file, re-flattening it (& optionally re-running
+/// it) every time it changes
+/// - `args` are the command-line arguments passed to `main` when `run` is set
+/// - If `output` is set, each successfully flattened program is also
+///   written there as an `.fbril` file
+/// - `last_output` holds the lines printed by the previous run (including
+///   a final `error: ...` line if it failed), which the next run's output
+///   is diffed against
+#[derive(Debug)]
+pub struct Watcher {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+    pub run: bool,
+    pub output: Option<PathBuf>,
+    pub limits: Limits,
+    last_output: Option<Vec<String>>,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                Actual logic                                */
+/* -------------------------------------------------------------------------- */
+
+/// Computes a line-by-line diff from `old` to `new` which keeps as many
+/// lines as possible (i.e. a longest common subsequence), listing the
+/// removed lines of each change before the added ones
+pub fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // (lines common to both ends are kept as-is, so typical edits don't
+    // need the quadratic LCS table)
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    // `lcs[i][j]` is the length of the LCS of `old_mid[i..]` & `new_mid[j..]`
+    let (n, m) = (old_mid.len(), new_mid.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_mid[i] == new_mid[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff: Vec<DiffLine> =
+        old[..prefix].iter().cloned().map(DiffLine::Same).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_mid[i] == new_mid[j] {
+            diff.push(DiffLine::Same(old_mid[i].clone()));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(DiffLine::Removed(old_mid[i].clone()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new_mid[j].clone()));
+            j += 1;
+        }
+    }
+    diff.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .cloned()
+            .map(DiffLine::Same),
+    );
+    diff
+}
+
+/// Renders a diff with `-` / `+` in front of the removed / added lines,
+/// showing only the unchanged lines within `DIFF_CONTEXT` lines of a
+/// change (the others are replaced by `...`)
+pub fn format_diff(diff: &[DiffLine]) -> String {
+    let changed: Vec<usize> = (0..diff.len())
+        .filter(|&i| !matches!(diff[i], DiffLine::Same(_)))
+        .collect();
+    let near_change =
+        |i: usize| changed.iter().any(|&c| c.abs_diff(i) <= DIFF_CONTEXT);
+    let mut text = String::new();
+    let mut skipped = false;
+    for (i, line) in diff.iter().enumerate() {
+        let (marker, line) = match line {
+            DiffLine::Same(line) if !near_change(i) => {
+                if !skipped {
+                    text.push_str("  ...\n");
+                    skipped = true;
+                }
+                continue;
+            }
+            DiffLine::Same(line) => (' ', line),
+            DiffLine::Removed(line) => ('-', line),
+            DiffLine::Added(line) => ('+', line),
+        };
+        skipped = false;
+        text.push_str(&format!("{marker} {line}\n"));
+    }
+    text
+}
+
+impl Watcher {
+    /// Creates a watcher for the Bril program at `path` (`.json` or `.bril`,
+    /// see `read_bril_source`) which only re-flattens it
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            args: vec![],
+            run: false,
+            output: None,
+            limits: Limits::default(),
+            last_output: None,
+        }
+    }
+
+    /// Flattens the program (writing it to `self.output`, if set) & runs it
+    /// if `self.run` is set, returning the lines it printed (followed by
+    /// an `error: ...` line if it failed)
+    fn flatten_and_run(&self) -> Result<Vec<String>, String> {
+        let json = read_bril_source(&self.path)?;
+        let bytes = try_json_to_fbril_bytes(&json)
+            .map_err(|msg| format!("unable to flatten program: {msg}"))?;
+        if let Some(output) = &self.output {
+            fs::write(output, &bytes).map_err(|e| {
+                format!("unable to write `{}`: {e}", output.display())
+            })?;
+        }
+        let data = AlignedBytes::new(&bytes);
+        let program = FlatProgram::new(get_program_views(&data)?);
+        if !self.run {
+            let num_instrs: usize =
+                program.views().iter().map(|view| view.instrs.len()).sum();
+            return Ok(vec![format!(
+                "flattened {} function(s), {num_instrs} instruction(s)",
+                program.views().len()
+            )]);
+        }
+
+        let args: Vec<&str> = self.args.iter().map(String::as_str).collect();
+        let mut ctx = InterpContext::new(vec![], self.limits);
+        let result = interp_program(&program, args, &mut ctx);
+        let mut lines: Vec<String> = String::from_utf8_lossy(&ctx.out)
+            .lines()
+            .map(str::to_string)
+            .collect();
+        if let Err(diagnostic) = result {
+            lines.push(format!("error: {diagnostic}"));
+        }
+        Ok(lines)
+    }
+
+    /// Re-flattens (& re-runs) the program, writing a report to `out`:
+    /// the whole output the first time, & then a diff against the
+    /// previous output
+    pub fn rebuild<W: Write>(&mut self, mut out: W) -> io::Result<()> {
+        let lines = match self.flatten_and_run() {
+            Ok(lines) => lines,
+            Err(msg) => {
+                // (the next successful run is diffed against the last one)
+                return writeln!(out, "error: {msg}");
+            }
+        };
+        match &self.last_output {
+            None => {
+                for line in &lines {
+                    writeln!(out, "{line}")?;
+                }
+            }
+            Some(last) if *last == lines => {
+                writeln!(out, "(output unchanged, {} line(s))", lines.len())?;
+            }
+            Some(last) => {
+                write!(out, "{}", format_diff(&diff_lines(last, &lines)))?
+            }
+        }
+        self.last_output = Some(lines);
+        Ok(())
+    }
+
+    /// Rebuilds the program & then polls its modification time every
+    /// `interval`, rebuilding it whenever it changes (never returns unless
+    /// writing to `out` fails)
+    pub fn watch<W: Write>(
+        &mut self,
+        interval: Duration,
+        mut out: W,
+    ) -> io::Result<()> {
+        let modified =
+            |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified: Option<SystemTime> = modified(&self.path);
+        let mut num_builds = 1;
+        writeln!(out, "[watch] {}", self.path.display())?;
+        self.rebuild(&mut out)?;
+        out.flush()?;
+        loop {
+            thread::sleep(interval);
+            let now_modified = modified(&self.path);
+            // (the file may be missing for a moment while an editor saves it)
+            if now_modified.is_none() || now_modified == last_modified {
+                continue;
+            }
+            last_modified = now_modified;
+            num_builds += 1;
+            writeln!(
+                out,
+                "[watch] {} changed (build #{num_builds})",
+                self.path.display()
+            )?;
+            self.rebuild(&mut out)?;
+            out.flush()?;
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod watch_tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use crate::watch::Watcher;
+
+    /// Each rebuild after the first prints the diff against the previous
+    /// run's output, & errors are reported without losing that output
+    #[test]
+    fn test_watcher_diffs_output() {
+        let path = std::env::temp_dir()
+            .join(format!("flat-bril-watch-{}.json", std::process::id()));
+        let write_program = |values: &[i64]| {
+            let instrs: Vec<_> = values
+                .iter()
+                .flat_map(|value| {
+                    [
+                        json!({ "op": "const", "dest": "v", "type": "int",
+                                "value": value }),
+                        json!({ "op": "print", "args": ["v"] }),
+                    ]
+                })
+                .collect();
+            let program =
+                json!({ "functions": [{ "name": "main", "instrs": instrs }] });
+            fs::write(&path, program.to_string()).unwrap();
+        };
+        let mut watcher = Watcher::new(&path);
+        watcher.run = true;
+        let mut rebuild = || {
+            let mut out = vec![];
+            watcher.rebuild(&mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        write_program(&[1, 2, 3, 4, 5]);
+        assert_eq!(rebuild(), "1\n2\n3\n4\n5\n");
+        write_program(&[1, 2, 3, 4, 5]);
+        assert_eq!(rebuild(), "(output unchanged, 5 line(s))\n");
+        write_program(&[1, 2, 7, 4, 5, 6]);
+        assert_eq!(rebuild(), "  ...\n  2\n- 3\n+ 7\n  4\n  5\n+ 6\n");
+        fs::write(&path, "{ not json").unwrap();
+        assert!(rebuild().starts_with("error: invalid JSON"));
+        fs::write(
+            &path,
+            json!({ "functions": [{ "name": "main", "instrs": [
+                { "op": "call", "args": ["v"] }
+            ] }] })
+            .to_string(),
+        )
+        .unwrap();
+        assert!(rebuild().starts_with("error: unable to flatten program: "));
+        write_program(&[2, 7, 4, 5, 6]);
+        assert_eq!(rebuild(), "- 1\n  2\n  ...\n");
+        fs::remove_file(&path).unwrap();
+    }
+}